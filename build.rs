@@ -27,14 +27,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "proto/data-plane-api/envoy/service/discovery/v3/discovery.proto",
         "proto/data-plane-api/envoy/type/metadata/v3/metadata.proto",
         "proto/data-plane-api/envoy/type/tracing/v3/custom_tag.proto",
+        "proto/udpa/udpa/type/v1/typed_struct.proto",
         "proto/udpa/xds/core/v3/resource_name.proto",
-        "proto/quilkin/extensions/filters/debug/v1alpha1/debug.proto",
-        "proto/quilkin/extensions/filters/capture_bytes/v1alpha1/capture_bytes.proto",
-        "proto/quilkin/extensions/filters/compress/v1alpha1/compress.proto",
-        "proto/quilkin/extensions/filters/concatenate_bytes/v1alpha1/concatenate_bytes.proto",
-        "proto/quilkin/extensions/filters/load_balancer/v1alpha1/load_balancer.proto",
-        "proto/quilkin/extensions/filters/local_rate_limit/v1alpha1/local_rate_limit.proto",
-        "proto/quilkin/extensions/filters/token_router/v1alpha1/token_router.proto",
+        "proto/quilkin/extensions/filters/debug/v1beta1/debug.proto",
+        "proto/quilkin/extensions/filters/capture_bytes/v1beta1/capture_bytes.proto",
+        "proto/quilkin/extensions/filters/compress/v1beta1/compress.proto",
+        "proto/quilkin/extensions/filters/concatenate_bytes/v1beta1/concatenate_bytes.proto",
+        "proto/quilkin/extensions/filters/fragment/v1beta1/fragment.proto",
+        "proto/quilkin/extensions/filters/handoff/v1beta1/handoff.proto",
+        "proto/quilkin/extensions/filters/jitter_buffer/v1beta1/jitter_buffer.proto",
+        "proto/quilkin/extensions/filters/load_balancer/v1beta1/load_balancer.proto",
+        "proto/quilkin/extensions/filters/local_rate_limit/v1beta1/local_rate_limit.proto",
+        "proto/quilkin/extensions/filters/network_simulation/v1beta1/network_simulation.proto",
+        "proto/quilkin/extensions/filters/prioritize/v1beta1/prioritize.proto",
+        "proto/quilkin/extensions/filters/shadow/v1beta1/shadow.proto",
+        "proto/quilkin/extensions/filters/static_metadata/v1beta1/static_metadata.proto",
+        "proto/quilkin/extensions/filters/token_quota/v1beta1/token_quota.proto",
+        "proto/quilkin/extensions/filters/token_router/v1beta1/token_router.proto",
+        "proto/quilkin/extensions/filters/transform/v1beta1/transform.proto",
+        "proto/quilkin/proxy/connection_tracker/v1alpha1/connection_tracker.proto",
+        "proto/quilkin/proxy/session_stats/v1alpha1/session_stats.proto",
+        "proto/quilkin/proxy/tap/v1alpha1/tap.proto",
+        "proto/quilkin/proxy/telemetry/v1alpha1/telemetry.proto",
     ]
     .iter()
     .map(|name| std::env::current_dir().unwrap().join(name))
@@ -76,5 +90,42 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("cargo:rerun-if-changed={}", path.to_str().unwrap());
     }
 
+    // Build information reported by the `quilkin_build_info` metric and the
+    // admin `/info` endpoint.
+    println!(
+        "cargo:rustc-env=QUILKIN_GIT_SHA={}",
+        command_output("git", &["rev-parse", "HEAD"])
+    );
+    println!(
+        "cargo:rustc-env=QUILKIN_RUSTC_VERSION={}",
+        command_output(
+            &std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into()),
+            &["--version"]
+        )
+    );
+    let mut features = std::env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect::<Vec<_>>();
+    features.sort();
+    println!("cargo:rustc-env=QUILKIN_FEATURES={}", features.join(","));
+    if std::path::Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+    }
+
     Ok(())
 }
+
+/// Returns the trimmed output of running `program`, or `unknown` if it fails.
+fn command_output(program: &str, args: &[&str]) -> String {
+    std::process::Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+        .unwrap_or_else(|| "unknown".into())
+}
@@ -51,7 +51,7 @@ impl ToTokens for IncludeProto {
 
         let module = id.split('.').rev().fold::<Vec<_>, _>(items, |acc, module| {
             let module = syn::Ident::new(module, Span::mixed_site());
-            let result: syn::ItemMod = syn::parse_quote!(pub(crate) mod #module { #(#acc)* });
+            let result: syn::ItemMod = syn::parse_quote!(pub mod #module { #(#acc)* });
 
             vec![syn::Item::Mod(result)]
         });
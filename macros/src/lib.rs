@@ -30,18 +30,18 @@ use include::IncludeProto;
 ///
 /// ### Input
 /// ```
-/// quilkin::include_proto!("quilkin.extensions.filters.debug.v1alpha1");
+/// quilkin::include_proto!("quilkin.extensions.filters.debug.v1beta1");
 /// ```
 ///
 /// ### Output
 /// ```
-/// mod quilkin {
-///     pub(crate) mod extensions {
-///         pub(crate) mod filters {
-///             pub(crate) mod debug {
-///                 pub(crate) mod v1alpha1 {
+/// pub mod quilkin {
+///     pub mod extensions {
+///         pub mod filters {
+///             pub mod debug {
+///                 pub mod v1beta1 {
 ///                     #![doc(hidden)]
-///                     tonic::include_proto!("quilkin.extensions.filters.debug.v1alpha1");
+///                     tonic::include_proto!("quilkin.extensions.filters.debug.v1beta1");
 ///                 }
 ///             }
 ///         }
@@ -76,14 +76,14 @@ pub fn include_proto(input: proc_macro::TokenStream) -> proc_macro::TokenStream
 ///
 /// ### Input
 /// ```
-/// #[quilkin::filter("quilkin.extensions.filters.debug.v1alpha1.Debug")]
+/// #[quilkin::filter("quilkin.extensions.filters.debug.v1beta1.Debug")]
 /// pub struct Debug;
 /// ```
 ///
 /// ### Output
 /// ```
 /// impl Debug {
-///     pub (crate) const FILTER_NAME: &str = "quilkin.extensions.filters.debug.v1alpha1.Debug";
+///     pub (crate) const FILTER_NAME: &str = "quilkin.extensions.filters.debug.v1beta1.Debug";
 /// }
 /// ```
 #[proc_macro_attribute]
@@ -0,0 +1,270 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use slog::{warn, Logger};
+
+use crate::config::AuditLog as AuditLogConfig;
+
+/// The syslog priority of audit records: the authpriv facility, at notice
+/// severity.
+#[cfg_attr(not(unix), allow(dead_code))]
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 5;
+
+/// The kind of action that an audit record is about.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub(crate) enum Action {
+    /// The proxy loaded its configuration.
+    ConfigLoad,
+    /// A client made a request to the admin server.
+    AdminRequest,
+    /// A management server sent an update, which the proxy accepted or
+    /// rejected.
+    XdsUpdate,
+}
+
+/// Whether an action succeeded.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub(crate) enum Outcome {
+    Succeeded,
+    Failed,
+}
+
+/// A record of who did what, and with which outcome.
+#[derive(Debug, Serialize)]
+pub(crate) struct Record<'a> {
+    /// Who performed the action, e.g the address of an admin client or of a
+    /// management server.
+    pub actor: &'a str,
+    pub action: Action,
+    /// What the action applied to, e.g the method and path of an admin
+    /// request.
+    pub target: &'a str,
+    pub outcome: Outcome,
+    /// More about the outcome, e.g why an update was rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<&'a str>,
+}
+
+/// A record as it is written, along with when it was written.
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    record: &'a Record<'a>,
+}
+
+/// An append-only log of admin and control plane actions, for compliance
+/// processes that audit changes to how traffic is routed. Clones write to the
+/// same destinations.
+#[derive(Clone)]
+pub(crate) struct AuditLog {
+    log: Logger,
+    file: Option<Arc<Mutex<File>>>,
+    syslog: Option<Arc<Syslog>>,
+}
+
+impl AuditLog {
+    /// Opens the destinations in `config`.
+    pub(crate) fn new(log: Logger, config: &AuditLogConfig) -> io::Result<Self> {
+        let file = config
+            .file
+            .as_ref()
+            .map(|path| OpenOptions::new().create(true).append(true).open(path))
+            .transpose()?
+            .map(|file| Arc::new(Mutex::new(file)));
+        let syslog = config
+            .syslog
+            .as_ref()
+            .map(|config| Syslog::connect(&config.socket))
+            .transpose()?
+            .map(Arc::new);
+        Ok(Self { log, file, syslog })
+    }
+
+    /// Writes `record` to every destination. Failures are logged rather
+    /// than returned, so that they don't fail the action being recorded.
+    pub(crate) fn record(&self, record: Record) {
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            record: &record,
+        };
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(err) => {
+                warn!(self.log, "Failed to serialize audit record"; "error" => %err);
+                return;
+            }
+        };
+
+        if let Some(file) = &self.file {
+            // Each record is written at once, so that records written
+            // concurrently aren't interleaved.
+            if let Err(err) = file.lock().write_all(format!("{}\n", line).as_bytes()) {
+                warn!(self.log, "Failed to write audit record to file"; "error" => %err);
+            }
+        }
+        if let Some(syslog) = &self.syslog {
+            if let Err(err) = syslog.send(&line) {
+                warn!(self.log, "Failed to send audit record to syslog"; "error" => %err);
+            }
+        }
+    }
+}
+
+/// Sends records to the local syslog daemon.
+#[cfg(unix)]
+struct Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    fn connect(path: &Path) -> io::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        // A syslog daemon that can't keep up shouldn't block the proxy.
+        socket.set_nonblocking(true)?;
+        socket.connect(path)?;
+        Ok(Self {
+            socket,
+            path: path.into(),
+        })
+    }
+
+    fn send(&self, line: &str) -> io::Result<()> {
+        // The syslog daemon adds the timestamp and hostname.
+        let message = format!(
+            "<{}>quilkin[{}]: {}",
+            SYSLOG_PRIORITY,
+            std::process::id(),
+            line
+        );
+        if self.socket.send(message.as_bytes()).is_ok() {
+            return Ok(());
+        }
+        // The daemon may have been restarted, which breaks the connection.
+        self.socket.connect(&self.path)?;
+        self.socket.send(message.as_bytes()).map(|_| ())
+    }
+}
+
+#[cfg(not(unix))]
+struct Syslog;
+
+#[cfg(not(unix))]
+impl Syslog {
+    fn connect(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "syslog is only supported on unix",
+        ))
+    }
+
+    fn send(&self, _line: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::{Action, AuditLog, Outcome, Record};
+    use crate::config::AuditLog as AuditLogConfig;
+    use crate::test_utils::logger;
+
+    fn record(target: &str) -> Record {
+        Record {
+            actor: "127.0.0.1:1234",
+            action: Action::AdminRequest,
+            target,
+            outcome: Outcome::Succeeded,
+            detail: Some("200"),
+        }
+    }
+
+    #[test]
+    fn append_to_file() {
+        let path = std::env::temp_dir().join(format!("quilkin-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = AuditLogConfig {
+            file: Some(path.clone()),
+            syslog: None,
+        };
+
+        AuditLog::new(logger(), &config)
+            .unwrap()
+            .record(record("GET /config_dump"));
+        // Records are appended to an existing log.
+        AuditLog::new(logger(), &config)
+            .unwrap()
+            .record(record("POST /state"));
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records = contents
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(2, records.len());
+        assert_eq!("127.0.0.1:1234", records[0]["actor"]);
+        assert_eq!("AdminRequest", records[0]["action"]);
+        assert_eq!("GET /config_dump", records[0]["target"]);
+        assert_eq!("Succeeded", records[0]["outcome"]);
+        assert_eq!("200", records[0]["detail"]);
+        assert!(records[0]["timestamp"].is_string());
+        assert_eq!("POST /state", records[1]["target"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn send_to_syslog() {
+        use std::os::unix::net::UnixDatagram;
+
+        use crate::config::Syslog;
+
+        let path = std::env::temp_dir().join(format!("quilkin-syslog-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let config = AuditLogConfig {
+            file: None,
+            syslog: Some(Syslog {
+                socket: path.clone(),
+            }),
+        };
+
+        AuditLog::new(logger(), &config)
+            .unwrap()
+            .record(record("GET /config_dump"));
+
+        let mut buf = vec![0; 1024];
+        let size = daemon.recv(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let message = String::from_utf8(buf[..size].to_vec()).unwrap();
+        let prefix = format!("<85>quilkin[{}]: ", std::process::id());
+        assert!(message.starts_with(&prefix), "{}", message);
+        let record = serde_json::from_str::<Value>(&message[prefix.len()..]).unwrap();
+        assert_eq!("GET /config_dump", record["target"]);
+    }
+}
@@ -20,13 +20,29 @@ use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 
 pub(crate) mod cluster_manager;
+mod health;
+pub(crate) mod mdns;
 mod metrics;
+mod slow_start;
+
+pub use health::{EndpointHealth, Latency};
+pub use slow_start::SlowStart;
+
+/// The weight of an endpoint that doesn't have one configured.
+pub const DEFAULT_WEIGHT: u32 = 1;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Endpoint {
     pub address: SocketAddr,
     pub tokens: HashSet<Vec<u8>>,
     pub metadata: Option<Value>,
+    /// The endpoint's share of traffic relative to other endpoints, when
+    /// load balancing by weight.
+    pub weight: u32,
+    /// If the endpoint is itself a proxy (a relay), the token that packets
+    /// sent to it are enveloped with, which tells the relay which of its own
+    /// endpoints to send them to.
+    pub relay_token: Option<Vec<u8>>,
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
@@ -54,6 +70,8 @@ impl Endpoint {
             address,
             tokens,
             metadata,
+            weight: DEFAULT_WEIGHT,
+            relay_token: None,
         }
     }
 
@@ -63,13 +81,17 @@ impl Endpoint {
 
     /// Converts an endpoint config into an internal endpoint representation.
     pub fn from_config(config: &EndPoint) -> Result<Endpoint, String> {
-        let (metadata, tokens) = if let Some(metadata) = config.metadata.clone() {
-            let (metadata, tokens) = parse_endpoint_metadata_from_yaml(metadata)?;
-            (Some(metadata), tokens)
+        let (metadata, tokens, relay_token) = if let Some(metadata) = config.metadata.clone() {
+            let (metadata, tokens, relay_token) = parse_endpoint_metadata_from_yaml(metadata)?;
+            (Some(metadata), tokens, relay_token)
         } else {
-            (None, Default::default())
+            (None, Default::default(), None)
         };
 
-        Ok(Endpoint::new(config.address, tokens, metadata))
+        Ok(Endpoint {
+            weight: config.weight.unwrap_or(DEFAULT_WEIGHT),
+            relay_token,
+            ..Endpoint::new(config.address, tokens, metadata)
+        })
     }
 }
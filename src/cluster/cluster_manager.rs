@@ -20,13 +20,16 @@ use std::sync::Arc;
 // and we will need to acquire a read lock with every packet that is processed
 // to be able to capture the current endpoint state and pass it to Filters.
 use parking_lot::RwLock;
-use slog::{debug, o, warn, Logger};
+use slog::{debug, info, o, warn, Logger};
 
 use prometheus::{Registry, Result as MetricsResult};
 use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Instant};
 
-use crate::cluster::Endpoint;
-use crate::config::{Endpoints, UpstreamEndpoints};
+use crate::cluster::{Cluster, Endpoint};
+use crate::config::{
+    EndpointUpdateGuard, EndpointUpdateGuardPolicy, Endpoints, Failover, UpstreamEndpoints,
+};
 use crate::xds::ads_client::ClusterUpdate;
 
 use super::metrics::Metrics;
@@ -37,6 +40,37 @@ pub(crate) type SharedClusterManager = Arc<RwLock<ClusterManager>>;
 pub(crate) struct ClusterManager {
     metrics: Metrics,
     endpoints: Option<Endpoints>,
+    failover: Option<FailoverState>,
+    update_guard: Option<UpdateGuardState>,
+}
+
+/// Tracks whether a [`ClusterManager`] configured with a failover cluster
+/// sends traffic to it.
+struct FailoverState {
+    config: Failover,
+    /// The endpoints of every cluster other than the failover cluster.
+    local: Option<Endpoints>,
+    /// The endpoints of the failover cluster.
+    remote: Option<Endpoints>,
+    /// Set while traffic is sent to the failover cluster.
+    active: bool,
+    /// When traffic is sent back to the local endpoints, if they have
+    /// recovered while failed over.
+    failback_at: Option<Instant>,
+}
+
+/// Tracks the endpoint updates held back by a [`ClusterManager`] configured
+/// with an [`EndpointUpdateGuard`].
+struct UpdateGuardState {
+    config: EndpointUpdateGuard,
+    /// Set once the first update has been applied. The first update is never
+    /// guarded, as it replaces either nothing or the fallback endpoints.
+    initialized: bool,
+    /// The endpoints being phased in to, under
+    /// [`EndpointUpdateGuardPolicy::PhaseIn`].
+    target: Option<Vec<Endpoint>>,
+    /// When the next step of the phase in is applied.
+    next_step_at: Option<Instant>,
 }
 
 /// InitializeError is returned with an error message if the
@@ -48,34 +82,252 @@ pub enum InitializeError {
 }
 
 impl ClusterManager {
-    fn new(metrics_registry: &Registry, endpoints: Option<Endpoints>) -> MetricsResult<Self> {
-        let metrics = Metrics::new(metrics_registry)?;
-        Ok(Self { metrics, endpoints })
+    fn new(
+        metrics_registry: &Registry,
+        endpoints: Option<Endpoints>,
+        failover: Option<Failover>,
+        update_guard: Option<EndpointUpdateGuard>,
+    ) -> MetricsResult<Self> {
+        let mut cm = Self {
+            metrics: Metrics::new(metrics_registry)?,
+            endpoints: None,
+            failover: failover.map(|config| FailoverState {
+                config,
+                local: None,
+                remote: None,
+                active: false,
+                failback_at: None,
+            }),
+            update_guard: update_guard.map(|config| UpdateGuardState {
+                config,
+                initialized: false,
+                target: None,
+                next_step_at: None,
+            }),
+        };
+        cm.set_endpoints(endpoints);
+        Ok(cm)
     }
 
-    fn update(&mut self, endpoints: Option<Endpoints>) {
+    pub(crate) fn set_endpoints(&mut self, endpoints: Option<Endpoints>) {
+        self.metrics.active_endpoints.set(
+            endpoints
+                .as_ref()
+                .map(|ep| ep.as_ref().len())
+                .unwrap_or_default() as i64,
+        );
         self.endpoints = endpoints;
     }
 
+    fn update(&mut self, log: &Logger, update: &ClusterUpdate) {
+        self.metrics.active_clusters.set(update.len() as i64);
+        let local = match self.failover.as_mut() {
+            Some(failover) => {
+                let failover_cluster = &failover.config.cluster;
+                failover.remote =
+                    Self::create_endpoints_from_clusters(update.get(failover_cluster));
+                Self::create_endpoints_from_clusters(
+                    update
+                        .iter()
+                        .filter(|(name, _)| *name != failover_cluster)
+                        .map(|(_, cluster)| cluster),
+                )
+            }
+            None => Self::create_endpoints_from_update(update),
+        };
+        self.guard_update(log, local, Instant::now());
+    }
+
+    /// Returns the endpoints of every cluster other than the failover
+    /// cluster.
+    fn local_endpoints(&self) -> Option<&Endpoints> {
+        match self.failover.as_ref() {
+            Some(failover) => failover.local.as_ref(),
+            None => self.endpoints.as_ref(),
+        }
+    }
+
+    fn set_local_endpoints(&mut self, log: &Logger, endpoints: Option<Endpoints>, now: Instant) {
+        match self.failover.as_mut() {
+            Some(failover) => {
+                failover.local = endpoints;
+                self.apply_failover(log, now);
+            }
+            None => self.set_endpoints(endpoints),
+        }
+    }
+
+    /// Applies an update to the local endpoints, unless it removes more of
+    /// them than the update guard allows, in which case it is either
+    /// rejected or phased in.
+    fn guard_update(&mut self, log: &Logger, endpoints: Option<Endpoints>, now: Instant) {
+        let guard = match self.update_guard.as_mut() {
+            Some(guard) => guard,
+            None => return self.set_local_endpoints(log, endpoints, now),
+        };
+        // An update always replaces any phase in still in progress.
+        guard.target = None;
+        guard.next_step_at = None;
+        if !guard.initialized {
+            guard.initialized = true;
+            return self.set_local_endpoints(log, endpoints, now);
+        }
+        let config = guard.config;
+
+        let current = self.local_endpoints().cloned();
+        let current_endpoints = current.as_ref().map(|ep| ep.as_ref().as_slice());
+        let target = endpoints
+            .as_ref()
+            .map(|ep| ep.as_ref().clone())
+            .unwrap_or_default();
+        let removals = removed(current_endpoints.unwrap_or_default(), &target).len();
+        let max_removals = max_removals(
+            current_endpoints.map(|ep| ep.len()).unwrap_or_default(),
+            config.max_removal_percent,
+        );
+        if removals <= max_removals {
+            return self.set_local_endpoints(log, endpoints, now);
+        }
+
+        self.metrics.endpoint_updates_guarded_total.inc();
+        match config.policy {
+            EndpointUpdateGuardPolicy::Reject => {
+                warn!(
+                    log,
+                    "Rejecting an endpoint update that removes too many endpoints.";
+                    "removals" => removals, "max_removals" => max_removals
+                );
+                // Still apply the update's changes to the failover cluster.
+                self.set_local_endpoints(log, current, now);
+            }
+            EndpointUpdateGuardPolicy::PhaseIn => {
+                warn!(
+                    log,
+                    "Phasing in an endpoint update that removes too many endpoints.";
+                    "removals" => removals, "max_removals" => max_removals
+                );
+                if let Some(guard) = self.update_guard.as_mut() {
+                    guard.target = Some(target);
+                }
+                self.phase_in(log, now);
+            }
+        }
+    }
+
+    /// Applies the next step of the endpoint update being phased in, which
+    /// adds all of its new endpoints but only removes up to the maximum
+    /// percentage of the current ones.
+    fn phase_in(&mut self, log: &Logger, now: Instant) {
+        let (target, config) = match self.update_guard.as_mut() {
+            Some(guard) => match guard.target.take() {
+                Some(target) => (target, guard.config),
+                None => return,
+            },
+            None => return,
+        };
+
+        let current = self
+            .local_endpoints()
+            .map(|ep| ep.as_ref().clone())
+            .unwrap_or_default();
+        // Always remove at least one endpoint, so that the phase in finishes
+        // even if the percentage rounds down to none.
+        let max_removals = max_removals(current.len(), config.max_removal_percent).max(1);
+        let removed = removed(&current, &target);
+        let done = removed.len() <= max_removals;
+        let mut endpoints = target.clone();
+        endpoints.extend(removed.into_iter().skip(max_removals).cloned());
+        self.set_local_endpoints(log, Endpoints::new(endpoints).ok(), now);
+
+        if let Some(guard) = self.update_guard.as_mut() {
+            if done {
+                guard.next_step_at = None;
+                info!(log, "Finished phasing in an endpoint update.");
+            } else {
+                guard.target = Some(target);
+                guard.next_step_at = Some(now + config.phase_in_interval);
+            }
+        }
+    }
+
+    /// Returns when the next step of an endpoint update being phased in is
+    /// applied, if any.
+    fn next_step_at(&self) -> Option<Instant> {
+        self.update_guard
+            .as_ref()
+            .and_then(|guard| guard.next_step_at)
+    }
+
+    /// Chooses between the local and failover endpoints. Traffic fails over
+    /// as soon as the local clusters have no healthy endpoints, and only
+    /// fails back once they have had some for the failback delay, so that it
+    /// doesn't flap between the two.
+    fn apply_failover(&mut self, log: &Logger, now: Instant) {
+        let failover = match self.failover.as_mut() {
+            Some(failover) => failover,
+            None => return,
+        };
+
+        if failover.local.is_none() {
+            failover.failback_at = None;
+            if !failover.active {
+                failover.active = true;
+                self.metrics.failovers_total.inc();
+                warn!(
+                    log,
+                    "Local clusters have no healthy endpoints, failing over.";
+                    "cluster" => &failover.config.cluster
+                );
+            }
+        } else if failover.active {
+            let failback_at = *failover
+                .failback_at
+                .get_or_insert(now + failover.config.failback_delay);
+            if failback_at <= now {
+                failover.active = false;
+                failover.failback_at = None;
+                info!(
+                    log,
+                    "Local clusters have recovered, failing back.";
+                    "cluster" => &failover.config.cluster
+                );
+            }
+        }
+
+        self.metrics.failover_active.set(failover.active as i64);
+        let endpoints = if failover.active {
+            failover.remote.clone().or_else(|| failover.local.clone())
+        } else {
+            failover.local.clone()
+        };
+        self.set_endpoints(endpoints);
+    }
+
+    /// Returns when traffic should be failed back to the local clusters, if
+    /// it is currently failed over and they have recovered.
+    fn failback_at(&self) -> Option<Instant> {
+        self.failover
+            .as_ref()
+            .and_then(|failover| failover.failback_at)
+    }
+
     /// Returns all endpoints known at the time of invocation.
     /// Returns `None` if there are no endpoints.
     pub fn get_all_endpoints(&self) -> Option<UpstreamEndpoints> {
         self.endpoints.clone().map(|ep| ep.into())
     }
 
+    /// Returns the endpoints that packets are currently sent to, if any.
+    pub(crate) fn endpoints(&self) -> Option<&Endpoints> {
+        self.endpoints.as_ref()
+    }
+
     /// Returns a ClusterManager backed by the fixed set of clusters provided in the config.
     pub fn fixed(
         metrics_registry: &Registry,
         endpoints: Endpoints,
     ) -> MetricsResult<SharedClusterManager> {
-        let cm = Self::new(metrics_registry, Some(endpoints))?;
-        // Set the endpoints count metrics.
-        cm.metrics.active_endpoints.set(
-            cm.endpoints
-                .as_ref()
-                .map(|ep| ep.as_ref().len())
-                .unwrap_or_default() as i64,
-        );
+        let cm = Self::new(metrics_registry, Some(endpoints), None, None)?;
         Ok(Arc::new(RwLock::new(cm)))
     }
 
@@ -87,67 +339,95 @@ impl ClusterManager {
     /// The set of clusters is continuously updated based on responses
     /// from the XDS server.
     /// The returned contains the XDS client's execution result after termination.
+    /// If `failover` is set, its cluster only receives traffic while the
+    /// other clusters have no endpoints. If `update_guard` is set, updates
+    /// that remove too many endpoints at once are rejected or phased in.
+    #[allow(clippy::too_many_arguments)]
     pub fn dynamic(
         base_logger: Logger,
         metrics_registry: &Registry,
         cluster_update: ClusterUpdate,
+        failover: Option<Failover>,
+        update_guard: Option<EndpointUpdateGuard>,
         cluster_updates_rx: mpsc::Receiver<ClusterUpdate>,
         shutdown_rx: watch::Receiver<()>,
     ) -> MetricsResult<SharedClusterManager> {
+        let mut cluster_manager = Self::new(metrics_registry, None, failover, update_guard)?;
+        cluster_manager.update(&base_logger, &cluster_update);
+
+        Ok(Self::spawn_dynamic(
+            base_logger,
+            cluster_manager,
+            cluster_updates_rx,
+            shutdown_rx,
+        ))
+    }
+
+    /// Returns a ClusterManager like [`ClusterManager::dynamic`] but that
+    /// starts out with the provided fallback endpoints until the first
+    /// cluster update is received.
+    pub fn dynamic_with_fallback(
+        base_logger: Logger,
+        metrics_registry: &Registry,
+        endpoints: Endpoints,
+        failover: Option<Failover>,
+        update_guard: Option<EndpointUpdateGuard>,
+        cluster_updates_rx: mpsc::Receiver<ClusterUpdate>,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> MetricsResult<SharedClusterManager> {
+        let cluster_manager =
+            Self::new(metrics_registry, Some(endpoints), failover, update_guard)?;
+
+        Ok(Self::spawn_dynamic(
+            base_logger,
+            cluster_manager,
+            cluster_updates_rx,
+            shutdown_rx,
+        ))
+    }
+
+    fn spawn_dynamic(
+        base_logger: Logger,
+        cluster_manager: ClusterManager,
+        cluster_updates_rx: mpsc::Receiver<ClusterUpdate>,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> SharedClusterManager {
         let log = base_logger.new(o!("source" => "cluster::ClusterManager"));
 
-        let cluster_manager = Self::new(
-            metrics_registry,
-            Self::create_endpoints_from_update(&cluster_update),
-        )?;
-        let metrics = cluster_manager.metrics.clone();
         let cluster_manager = Arc::new(RwLock::new(cluster_manager));
 
-        Self::update_cluster_update_metrics(&metrics, &cluster_update);
-
         // Start a task in the background to receive cluster updates
         // and update the cluster manager's cluster set in turn.
         Self::spawn_updater(
-            log.clone(),
-            metrics,
+            log,
             cluster_manager.clone(),
             cluster_updates_rx,
             shutdown_rx,
         );
 
-        Ok(cluster_manager)
+        cluster_manager
     }
 
-    fn update_cluster_update_metrics(metrics: &Metrics, update: &ClusterUpdate) {
-        metrics.active_clusters.set(update.len() as i64);
-        metrics.active_endpoints.set(
-            Self::create_endpoints_from_update(update)
-                .map(|ep| ep.as_ref().len() as i64)
-                .unwrap_or_default(),
-        )
+    fn create_endpoints_from_update(update: &ClusterUpdate) -> Option<Endpoints> {
+        Self::create_endpoints_from_clusters(update.values())
     }
 
-    fn create_endpoints_from_update(update: &ClusterUpdate) -> Option<Endpoints> {
+    fn create_endpoints_from_clusters<'a>(
+        clusters: impl IntoIterator<Item = &'a Cluster>,
+    ) -> Option<Endpoints> {
         // NOTE: We don't currently have support for consuming multiple clusters
         // so here gather all endpoints into the same set, ignoring what cluster they
         // belong to.
-        let endpoints = update
-            .iter()
-            .fold(vec![], |mut endpoints, (_name, cluster)| {
-                let cluster_endpoints = cluster
-                    .localities
-                    .iter()
-                    .map(|(_, endpoints)| {
-                        endpoints
-                            .endpoints
-                            .iter()
-                            .map(|ep| Endpoint::from_address(ep.address))
-                    })
-                    .flatten();
-                endpoints.extend(cluster_endpoints);
-
-                endpoints
-            });
+        let endpoints = clusters.into_iter().fold(vec![], |mut endpoints, cluster| {
+            let cluster_endpoints = cluster
+                .localities
+                .iter()
+                .map(|(_, endpoints)| endpoints.endpoints.iter().cloned())
+                .flatten();
+            endpoints.extend(cluster_endpoints);
+
+            endpoints
+        });
 
         match Endpoints::new(endpoints) {
             Ok(endpoints) => Some(endpoints),
@@ -159,21 +439,22 @@ impl ClusterManager {
     /// and updates the ClusterManager's state in turn.
     fn spawn_updater(
         log: Logger,
-        metrics: Metrics,
         cluster_manager: Arc<RwLock<ClusterManager>>,
         mut cluster_updates_rx: mpsc::Receiver<ClusterUpdate>,
         mut shutdown_rx: watch::Receiver<()>,
     ) {
         tokio::spawn(async move {
             loop {
+                let (failback_at, next_step_at) = {
+                    let cluster_manager = cluster_manager.read();
+                    (cluster_manager.failback_at(), cluster_manager.next_step_at())
+                };
                 tokio::select! {
                     update = cluster_updates_rx.recv() => {
                         match update {
                             Some(update) => {
-                                Self::update_cluster_update_metrics(&metrics, &update);
-                                let update = Self::create_endpoints_from_update(&update);
                                 debug!(log, "Received a cluster update.");
-                                cluster_manager.write().update(update);
+                                cluster_manager.write().update(&log, &update);
                             }
                             None => {
                                 warn!(log, "Exiting cluster update receive loop because the sender dropped the channel.");
@@ -181,6 +462,12 @@ impl ClusterManager {
                             }
                         }
                     }
+                    _ = Self::wait_until(failback_at) => {
+                        cluster_manager.write().apply_failover(&log, Instant::now());
+                    }
+                    _ = Self::wait_until(next_step_at) => {
+                        cluster_manager.write().phase_in(&log, Instant::now());
+                    }
                     _ = shutdown_rx.changed() => {
                         debug!(log, "Exiting cluster update receive loop because a shutdown signal was received.");
                         return;
@@ -189,17 +476,98 @@ impl ClusterManager {
             }
         });
     }
+
+    /// Waits until `deadline` if set, otherwise forever.
+    async fn wait_until(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Returns the endpoints of `current` that aren't in `target`.
+fn removed<'a>(current: &'a [Endpoint], target: &[Endpoint]) -> Vec<&'a Endpoint> {
+    current
+        .iter()
+        .filter(|ep| !target.iter().any(|target| target.address == ep.address))
+        .collect()
+}
+
+/// Returns how many of `count` endpoints may be removed at once.
+fn max_removals(count: usize, max_removal_percent: f64) -> usize {
+    (count as f64 * max_removal_percent / 100.0).floor() as usize
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ClusterManager;
+    use super::{max_removals, ClusterManager};
     use crate::cluster::{Cluster, Endpoint, LocalityEndpoints};
-    use crate::config::Endpoints;
+    use crate::config::{EndpointUpdateGuard, EndpointUpdateGuardPolicy, Endpoints, Failover};
     use crate::test_utils::logger;
+    use crate::xds::ads_client::ClusterUpdate;
     use prometheus::Registry;
+    use std::net::SocketAddr;
+    use std::time::Duration;
     use tokio::sync::{mpsc, watch};
 
+    fn cluster(addresses: &[&str]) -> Cluster {
+        Cluster {
+            localities: vec![(
+                None,
+                LocalityEndpoints {
+                    endpoints: addresses
+                        .iter()
+                        .map(|addr| Endpoint::from_address(addr.parse().unwrap()))
+                        .collect(),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    // Returns an update with a local cluster with the given endpoints,
+    // and a remote cluster.
+    fn failover_update(local: &[&str]) -> ClusterUpdate {
+        vec![
+            ("local".into(), cluster(local)),
+            ("remote".into(), cluster(&["127.0.0.1:90"])),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn update(addresses: &[&str]) -> ClusterUpdate {
+        vec![("cluster".into(), cluster(addresses))]
+            .into_iter()
+            .collect()
+    }
+
+    fn guarded(policy: EndpointUpdateGuardPolicy) -> ClusterManager {
+        ClusterManager::new(
+            &Registry::default(),
+            None,
+            None,
+            Some(EndpointUpdateGuard {
+                max_removal_percent: 50.0,
+                policy,
+                phase_in_interval: Duration::from_secs(10),
+            }),
+        )
+        .unwrap()
+    }
+
+    fn addresses(cm: &ClusterManager) -> Vec<SocketAddr> {
+        cm.get_all_endpoints()
+            .map(|endpoints| endpoints.iter().map(|ep| ep.address).collect())
+            .unwrap_or_default()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
     #[test]
     fn static_cluster_manager_metrics() {
         let cm = ClusterManager::fixed(
@@ -241,6 +609,8 @@ mod tests {
             )]
             .into_iter()
             .collect(),
+            None,
+            None,
             update_rx,
             shutdown_rx,
         )
@@ -314,4 +684,256 @@ mod tests {
         .await
         .unwrap();
     }
+
+    #[tokio::test]
+    async fn dynamic_cluster_manager_with_fallback() {
+        let (update_tx, update_rx) = mpsc::channel(3);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let cm = ClusterManager::dynamic_with_fallback(
+            logger(),
+            &Registry::default(),
+            Endpoints::new(vec![Endpoint::from_address(
+                "127.0.0.1:80".parse().unwrap(),
+            )])
+            .unwrap(),
+            None,
+            None,
+            update_rx,
+            shutdown_rx,
+        )
+        .unwrap();
+
+        // The fallback endpoints are served until an update is received.
+        {
+            let cm = cm.read();
+            let endpoints = cm.get_all_endpoints().unwrap();
+            assert_eq!(
+                vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()],
+                endpoints.iter().map(|ep| ep.address).collect::<Vec<_>>()
+            );
+            assert_eq!(1, cm.metrics.active_endpoints.get());
+            assert_eq!(0, cm.metrics.active_clusters.get());
+        }
+
+        let update = vec![(
+            "cluster-1".into(),
+            Cluster {
+                localities: vec![(
+                    None,
+                    LocalityEndpoints {
+                        endpoints: vec![
+                            Endpoint::from_address("127.0.0.1:82".parse().unwrap()),
+                            Endpoint::from_address("127.0.0.1:83".parse().unwrap()),
+                        ],
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            },
+        )]
+        .into_iter()
+        .collect();
+        update_tx.send(update).await.unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(3), async move {
+            loop {
+                {
+                    let metrics = &cm.read().metrics;
+                    if metrics.active_endpoints.get() == 2 {
+                        break;
+                    }
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(3)).await;
+            }
+
+            let metrics = &cm.read().metrics;
+            assert_eq!(1, metrics.active_clusters.get());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn failover() {
+        let log = logger();
+        let mut cm = ClusterManager::new(
+            &Registry::default(),
+            None,
+            Some(Failover {
+                cluster: "remote".into(),
+                failback_delay: Duration::from_secs(30),
+            }),
+            None,
+        )
+        .unwrap();
+
+        // The failover cluster isn't used while the local cluster has endpoints.
+        cm.update(&log, &failover_update(&["127.0.0.1:80"]));
+        assert_eq!(vec![addr("127.0.0.1:80")], addresses(&cm));
+        assert_eq!(0, cm.metrics.failover_active.get());
+
+        // Fail over as soon as the local cluster has no endpoints.
+        cm.update(&log, &failover_update(&[]));
+        assert_eq!(vec![addr("127.0.0.1:90")], addresses(&cm));
+        assert_eq!(1, cm.metrics.failover_active.get());
+        assert_eq!(1, cm.metrics.failovers_total.get());
+        assert_eq!(1, cm.metrics.active_endpoints.get());
+        assert!(cm.failback_at().is_none());
+
+        // Fail back only once the local cluster has had endpoints for the
+        // failback delay, even if it changes in the meantime.
+        cm.update(&log, &failover_update(&["127.0.0.1:81"]));
+        assert_eq!(vec![addr("127.0.0.1:90")], addresses(&cm));
+        let failback_at = cm.failback_at().unwrap();
+        cm.apply_failover(&log, failback_at - Duration::from_secs(1));
+        assert_eq!(vec![addr("127.0.0.1:90")], addresses(&cm));
+        cm.update(&log, &failover_update(&["127.0.0.1:82"]));
+        assert_eq!(Some(failback_at), cm.failback_at());
+
+        cm.apply_failover(&log, failback_at);
+        assert_eq!(vec![addr("127.0.0.1:82")], addresses(&cm));
+        assert_eq!(0, cm.metrics.failover_active.get());
+        assert!(cm.failback_at().is_none());
+
+        // Losing the local endpoints again while waiting to fail back
+        // restarts the delay.
+        cm.update(&log, &failover_update(&[]));
+        cm.update(&log, &failover_update(&["127.0.0.1:80"]));
+        assert!(cm.failback_at().is_some());
+        cm.update(&log, &failover_update(&[]));
+        assert!(cm.failback_at().is_none());
+        assert_eq!(vec![addr("127.0.0.1:90")], addresses(&cm));
+        assert_eq!(2, cm.metrics.failovers_total.get());
+    }
+
+    #[tokio::test]
+    async fn dynamic_cluster_manager_failback() {
+        let (update_tx, update_rx) = mpsc::channel(3);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let cm = ClusterManager::dynamic(
+            logger(),
+            &Registry::default(),
+            failover_update(&[]),
+            Some(Failover {
+                cluster: "remote".into(),
+                failback_delay: Duration::from_millis(50),
+            }),
+            None,
+            update_rx,
+            shutdown_rx,
+        )
+        .unwrap();
+        assert_eq!(vec![addr("127.0.0.1:90")], addresses(&cm.read()));
+
+        // Traffic fails back once the delay passes, without another update.
+        update_tx
+            .send(failover_update(&["127.0.0.1:80"]))
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(3), async move {
+            loop {
+                if addresses(&cm.read()) == vec![addr("127.0.0.1:80")] {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(3)).await;
+            }
+            assert_eq!(0, cm.read().metrics.failover_active.get());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn max_removals_rounds_down() {
+        assert_eq!(0, max_removals(0, 50.0));
+        assert_eq!(1, max_removals(3, 50.0));
+        assert_eq!(2, max_removals(4, 50.0));
+        assert_eq!(0, max_removals(4, 0.0));
+        assert_eq!(4, max_removals(4, 100.0));
+    }
+
+    #[test]
+    fn update_guard_reject() {
+        let log = logger();
+        let mut cm = guarded(EndpointUpdateGuardPolicy::Reject);
+        let all = ["127.0.0.1:80", "127.0.0.1:81", "127.0.0.1:82", "127.0.0.1:83"];
+
+        // The first update is never guarded.
+        cm.update(&log, &update(&all));
+        assert_eq!(4, addresses(&cm).len());
+
+        // Updates within the limit are applied, including additions.
+        cm.update(&log, &update(&all[..2]));
+        assert_eq!(vec![addr(all[0]), addr(all[1])], addresses(&cm));
+        cm.update(&log, &update(&all));
+        assert_eq!(4, addresses(&cm).len());
+
+        // An update that removes too many endpoints is rejected.
+        cm.update(&log, &update(&all[..1]));
+        assert_eq!(4, addresses(&cm).len());
+        cm.update(&log, &update(&[]));
+        assert_eq!(4, addresses(&cm).len());
+        assert_eq!(2, cm.metrics.endpoint_updates_guarded_total.get());
+        assert!(cm.next_step_at().is_none());
+    }
+
+    #[test]
+    fn update_guard_phase_in() {
+        let log = logger();
+        let mut cm = guarded(EndpointUpdateGuardPolicy::PhaseIn);
+        let all = ["127.0.0.1:80", "127.0.0.1:81", "127.0.0.1:82", "127.0.0.1:83"];
+        cm.update(&log, &update(&all));
+
+        // New endpoints are added straight away, but only half of the
+        // current endpoints are removed every step.
+        cm.update(&log, &update(&["127.0.0.1:90"]));
+        assert_eq!(
+            vec![addr("127.0.0.1:90"), addr(all[2]), addr(all[3])],
+            addresses(&cm)
+        );
+        assert_eq!(1, cm.metrics.endpoint_updates_guarded_total.get());
+
+        let next_step_at = cm.next_step_at().unwrap();
+        cm.phase_in(&log, next_step_at);
+        assert_eq!(vec![addr("127.0.0.1:90"), addr(all[3])], addresses(&cm));
+
+        // At least one endpoint is removed every step.
+        let next_step_at = cm.next_step_at().unwrap();
+        cm.phase_in(&log, next_step_at);
+        assert_eq!(vec![addr("127.0.0.1:90")], addresses(&cm));
+        assert!(cm.next_step_at().is_none());
+
+        // A new update replaces the update being phased in.
+        cm.update(&log, &update(&all));
+        cm.update(&log, &update(&[]));
+        assert_eq!(vec![addr(all[2]), addr(all[3])], addresses(&cm));
+        cm.update(&log, &update(&all[2..]));
+        assert_eq!(vec![addr(all[2]), addr(all[3])], addresses(&cm));
+        assert!(cm.next_step_at().is_none());
+    }
+
+    #[test]
+    fn update_guard_with_failover() {
+        let log = logger();
+        let mut cm = ClusterManager::new(
+            &Registry::default(),
+            None,
+            Some(Failover {
+                cluster: "remote".into(),
+                failback_delay: Duration::from_secs(30),
+            }),
+            Some(EndpointUpdateGuard {
+                max_removal_percent: 50.0,
+                policy: EndpointUpdateGuardPolicy::Reject,
+                phase_in_interval: Duration::from_secs(10),
+            }),
+        )
+        .unwrap();
+
+        // A local cluster briefly published as empty doesn't fail over.
+        cm.update(&log, &failover_update(&["127.0.0.1:80"]));
+        cm.update(&log, &failover_update(&[]));
+        assert_eq!(vec![addr("127.0.0.1:80")], addresses(&cm));
+        assert_eq!(0, cm.metrics.failovers_total.get());
+    }
 }
@@ -0,0 +1,370 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use prometheus::{IntCounter, IntGaugeVec, Registry, Result as MetricsResult};
+use slog::{info, o, warn, Logger};
+use tokio::time::Instant;
+
+use crate::cluster::SlowStart;
+use crate::config::EndpointHealthCheck;
+use crate::metrics::{opts, CollectorExt};
+
+/// Tracks the health of endpoints passively, from the traffic sessions send
+/// to and receive from them. An endpoint is marked unhealthy after repeated
+/// failures to send to it, or if it stops responding, and is re-admitted
+/// once a cooldown has passed. The latency to each endpoint is measured
+/// from the same traffic.
+///
+/// **Note:** Cloning [`EndpointHealth`] is shallow, clones share the same
+/// state.
+#[derive(Clone)]
+pub struct EndpointHealth {
+    log: Logger,
+    config: EndpointHealthCheck,
+    endpoints: Arc<Mutex<HashMap<SocketAddr, State>>>,
+    metrics: Metrics,
+    /// Warms up endpoints once they are re-admitted, if enabled.
+    slow_start: Option<SlowStart>,
+}
+
+/// The health of a single endpoint.
+#[derive(Default)]
+struct State {
+    /// The number of failures to send to the endpoint since a packet was
+    /// last sent to it successfully.
+    consecutive_failures: u32,
+    /// When a packet was first sent to the endpoint since it last responded,
+    /// if one has been.
+    awaiting_response_since: Option<Instant>,
+    /// When the endpoint is re-admitted, while it's unhealthy.
+    unhealthy_until: Option<Instant>,
+    /// The latency measured to the endpoint, once it has responded.
+    latency: Option<Latency>,
+}
+
+/// The round-trip time to an endpoint, measured as the time from a packet
+/// being sent to it to the next packet received from it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Latency {
+    /// The round-trip time, smoothed over recent round trips.
+    pub smoothed: Duration,
+    /// The lowest round-trip time measured.
+    pub min: Duration,
+    /// The number of round trips measured.
+    pub samples: u64,
+}
+
+impl Latency {
+    fn new(sample: Duration) -> Self {
+        Self {
+            smoothed: sample,
+            min: sample,
+            samples: 1,
+        }
+    }
+
+    /// Adds a round trip of `sample`, which is smoothed the way TCP smooths
+    /// its round-trip time (RFC 6298).
+    fn record(&mut self, sample: Duration) {
+        self.smoothed = self.smoothed * 7 / 8 + sample / 8;
+        self.min = self.min.min(sample);
+        self.samples += 1;
+    }
+}
+
+#[derive(Clone)]
+struct Metrics {
+    endpoint_healthy: IntGaugeVec,
+    endpoints_ejected_total: IntCounter,
+}
+
+impl Metrics {
+    fn new(registry: &Registry) -> MetricsResult<Self> {
+        let subsystem = "cluster";
+        Ok(Self {
+            endpoint_healthy: IntGaugeVec::new(
+                opts(
+                    "endpoint_healthy",
+                    subsystem,
+                    "Whether an endpoint is healthy (1) or unhealthy (0).",
+                ),
+                &["address"],
+            )?
+            .register_if_not_exists(registry)?,
+            endpoints_ejected_total: IntCounter::with_opts(opts(
+                "endpoints_ejected_total",
+                subsystem,
+                "Total number of times an endpoint was marked unhealthy.",
+            ))?
+            .register_if_not_exists(registry)?,
+        })
+    }
+}
+
+impl EndpointHealth {
+    pub fn new(
+        base: &Logger,
+        config: EndpointHealthCheck,
+        registry: &Registry,
+    ) -> MetricsResult<Self> {
+        Ok(Self {
+            log: base.new(o!("source" => "cluster::EndpointHealth")),
+            config,
+            endpoints: Default::default(),
+            metrics: Metrics::new(registry)?,
+            slow_start: None,
+        })
+    }
+
+    /// Warms up endpoints with `slow_start` once they are re-admitted.
+    pub fn with_slow_start(self, slow_start: SlowStart) -> Self {
+        Self {
+            slow_start: Some(slow_start),
+            ..self
+        }
+    }
+
+    /// Returns whether packets should be sent to the endpoint at `address`.
+    /// An unhealthy endpoint whose cooldown has passed is re-admitted.
+    pub fn is_healthy(&self, address: SocketAddr) -> bool {
+        let mut endpoints = self.endpoints.lock();
+        let state = match endpoints.get_mut(&address) {
+            Some(state) => state,
+            None => return true,
+        };
+        match state.unhealthy_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                *state = State {
+                    latency: state.latency,
+                    ..State::default()
+                };
+                self.set_healthy(address, true);
+                info!(self.log, "Endpoint re-admitted after cooldown"; "address" => %address);
+                if let Some(slow_start) = &self.slow_start {
+                    slow_start.restart(address);
+                }
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Records the outcome of sending a packet to the endpoint at `address`.
+    pub fn record_send(&self, address: SocketAddr, sent: bool) {
+        let mut endpoints = self.endpoints.lock();
+        let state = endpoints.entry(address).or_insert_with(|| {
+            self.set_healthy(address, true);
+            State::default()
+        });
+        if state.unhealthy_until.is_some() {
+            return;
+        }
+
+        let now = Instant::now();
+        if !sent {
+            state.consecutive_failures += 1;
+            if state.consecutive_failures >= self.config.max_send_failures {
+                self.eject(address, state, "send failures");
+            }
+            return;
+        }
+
+        state.consecutive_failures = 0;
+        let awaiting_response_since = *state.awaiting_response_since.get_or_insert(now);
+        let unresponsive = self
+            .config
+            .response_timeout
+            .map_or(false, |timeout| now - awaiting_response_since > timeout);
+        if unresponsive {
+            self.eject(address, state, "no response");
+        }
+    }
+
+    /// Records that a packet was received from the endpoint at `address`.
+    pub fn record_response(&self, address: SocketAddr) {
+        if let Some(state) = self.endpoints.lock().get_mut(&address) {
+            state.consecutive_failures = 0;
+            if let Some(since) = state.awaiting_response_since.take() {
+                let sample = Instant::now() - since;
+                match &mut state.latency {
+                    Some(latency) => latency.record(sample),
+                    None => state.latency = Some(Latency::new(sample)),
+                }
+            }
+        }
+    }
+
+    /// Returns the latency measured to each endpoint that has responded to
+    /// a packet, ordered by address.
+    pub fn latencies(&self) -> Vec<(SocketAddr, Latency)> {
+        let mut latencies = self
+            .endpoints
+            .lock()
+            .iter()
+            .filter_map(|(address, state)| state.latency.map(|latency| (*address, latency)))
+            .collect::<Vec<_>>();
+        latencies.sort_by_key(|(address, _)| *address);
+        latencies
+    }
+
+    /// Marks the endpoint at `address` unhealthy until the cooldown passes.
+    fn eject(&self, address: SocketAddr, state: &mut State, reason: &'static str) {
+        *state = State {
+            unhealthy_until: Some(Instant::now() + self.config.cooldown),
+            latency: state.latency,
+            ..State::default()
+        };
+        self.set_healthy(address, false);
+        self.metrics.endpoints_ejected_total.inc();
+        warn!(self.log, "Endpoint marked unhealthy";
+            "address" => %address, "reason" => reason, "cooldown" => ?self.config.cooldown);
+    }
+
+    fn set_healthy(&self, address: SocketAddr, healthy: bool) {
+        self.metrics
+            .endpoint_healthy
+            .with_label_values(&[&address.to_string()])
+            .set(healthy as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use prometheus::Registry;
+    use tokio::time::{self, Duration};
+
+    use super::{EndpointHealth, Latency};
+    use crate::config::EndpointHealthCheck;
+    use crate::test_utils::logger;
+
+    fn health(response_timeout: Option<Duration>) -> EndpointHealth {
+        EndpointHealth::new(
+            &logger(),
+            EndpointHealthCheck {
+                max_send_failures: 2,
+                response_timeout,
+                cooldown: Duration::from_secs(30),
+            },
+            &Registry::default(),
+        )
+        .unwrap()
+    }
+
+    fn healthy_gauge(health: &EndpointHealth, address: SocketAddr) -> i64 {
+        health
+            .metrics
+            .endpoint_healthy
+            .with_label_values(&[&address.to_string()])
+            .get()
+    }
+
+    #[tokio::test]
+    async fn send_failures() {
+        time::pause();
+        let health = health(None);
+        let address: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        assert!(health.is_healthy(address));
+
+        // A successful send resets the count of failures.
+        health.record_send(address, false);
+        health.record_send(address, true);
+        health.record_send(address, false);
+        assert!(health.is_healthy(address));
+        assert_eq!(1, healthy_gauge(&health, address));
+
+        health.record_send(address, false);
+        assert!(!health.is_healthy(address));
+        assert_eq!(0, healthy_gauge(&health, address));
+        assert_eq!(1, health.metrics.endpoints_ejected_total.get());
+
+        // The endpoint is re-admitted after the cooldown.
+        time::advance(Duration::from_secs(29)).await;
+        assert!(!health.is_healthy(address));
+        time::advance(Duration::from_secs(1)).await;
+        assert!(health.is_healthy(address));
+        assert_eq!(1, healthy_gauge(&health, address));
+    }
+
+    #[tokio::test]
+    async fn response_timeout() {
+        time::pause();
+        let health = health(Some(Duration::from_secs(5)));
+        let responsive: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let unresponsive: SocketAddr = "127.0.0.1:7002".parse().unwrap();
+
+        for _ in 0..3 {
+            health.record_send(responsive, true);
+            health.record_send(unresponsive, true);
+            health.record_response(responsive);
+            time::advance(Duration::from_secs(3)).await;
+        }
+        assert!(health.is_healthy(responsive));
+        assert!(!health.is_healthy(unresponsive));
+        assert_eq!(1, health.metrics.endpoints_ejected_total.get());
+    }
+
+    #[tokio::test]
+    async fn latencies() {
+        time::pause();
+        let health = health(None);
+        let fast: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let slow: SocketAddr = "127.0.0.1:7002".parse().unwrap();
+        let silent: SocketAddr = "127.0.0.1:7003".parse().unwrap();
+
+        health.record_send(silent, true);
+        for &rtt in &[40, 20] {
+            health.record_send(slow, true);
+            health.record_send(fast, true);
+            // Only the first packet sent since the last response is timed.
+            time::advance(Duration::from_millis(5)).await;
+            health.record_send(fast, true);
+            health.record_response(fast);
+            time::advance(Duration::from_millis(rtt - 5)).await;
+            health.record_response(slow);
+        }
+
+        assert_eq!(
+            vec![
+                (
+                    fast,
+                    Latency {
+                        smoothed: Duration::from_millis(5),
+                        min: Duration::from_millis(5),
+                        samples: 2,
+                    }
+                ),
+                (
+                    slow,
+                    Latency {
+                        smoothed: Duration::from_micros(37500),
+                        min: Duration::from_millis(20),
+                        samples: 2,
+                    }
+                ),
+            ],
+            health.latencies()
+        );
+    }
+}
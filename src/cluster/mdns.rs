@@ -0,0 +1,561 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Discovers endpoints by browsing for the instances of a service that are
+//! advertised with multicast DNS service discovery ([RFC 6762], [RFC 6763]).
+//! Queries are sent from an ephemeral port, which makes them one-shot
+//! queries that responders answer by unicast, so the browser doesn't need
+//! to share the mDNS port with a responder running on the same host.
+//!
+//! [RFC 6762]: https://tools.ietf.org/html/rfc6762
+//! [RFC 6763]: https://tools.ietf.org/html/rfc6763
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use prometheus::{IntCounter, IntGauge, Registry, Result as MetricsResult};
+use slog::{debug, info, o, warn, Logger};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Duration, Instant};
+
+use crate::cluster::{Cluster, Endpoint, LocalityEndpoints};
+use crate::config::{Endpoints, Mdns};
+use crate::metrics::{opts, CollectorExt};
+use crate::xds::ads_client::ClusterUpdate;
+
+/// The multicast group and port that mDNS queries are sent to.
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// The name of the cluster holding both the static and the discovered
+/// endpoints, in a stable order.
+const CLUSTER_NAME: &str = "mdns";
+/// The largest response that is read.
+const MAX_RESPONSE_SIZE: usize = 9000;
+/// The most compression pointers followed while reading a name, which stops
+/// pointer loops.
+const MAX_POINTERS: usize = 16;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// The top bit of a record's class is mDNS' cache-flush bit.
+const CLASS_MASK: u16 = 0x7fff;
+const FLAG_RESPONSE: u16 = 0x8000;
+
+/// Returns whether `service_type` is a DNS-SD service type: a service name
+/// and the protocol it's served over, e.g `_game._udp`.
+pub(crate) fn is_service_type(service_type: &str) -> bool {
+    let mut labels = service_type.split('.');
+    match (labels.next(), labels.next(), labels.next()) {
+        (Some(service), Some(protocol), None) => {
+            // Service names are at most 15 characters long (RFC 6335).
+            (2..=16).contains(&service.len())
+                && service.starts_with('_')
+                && service[1..]
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && (protocol == "_udp" || protocol == "_tcp")
+        }
+        _ => false,
+    }
+}
+
+/// Browses for instances of a service, publishing the static endpoints
+/// along with an endpoint for every instance as cluster updates. Instances
+/// are removed once their records expire without being refreshed, or the
+/// responder withdraws them.
+pub(crate) struct MdnsBrowser {
+    log: Logger,
+    /// The name that instances are browsed for, e.g `_game._udp.local`.
+    service: String,
+    query_interval: Duration,
+    group: SocketAddr,
+    static_endpoints: Vec<Endpoint>,
+    /// The discovered instances, by their name.
+    instances: HashMap<String, Instance>,
+    metrics: Metrics,
+}
+
+/// A discovered instance of the service.
+struct Instance {
+    address: SocketAddr,
+    expires_at: Instant,
+}
+
+struct Metrics {
+    endpoints: IntGauge,
+    responses_invalid_total: IntCounter,
+}
+
+impl Metrics {
+    fn new(registry: &Registry) -> MetricsResult<Self> {
+        Ok(Self {
+            endpoints: IntGauge::with_opts(opts(
+                "endpoints",
+                "mdns",
+                "Number of endpoints currently discovered with mDNS",
+            ))?
+            .register_if_not_exists(registry)?,
+            responses_invalid_total: IntCounter::with_opts(opts(
+                "responses_invalid_total",
+                "mdns",
+                "Total number of mDNS responses that couldn't be parsed",
+            ))?
+            .register_if_not_exists(registry)?,
+        })
+    }
+}
+
+/// The records of an mDNS response about instances of the browsed service.
+#[derive(Debug, Default, PartialEq)]
+struct Response {
+    /// The instances announced by the response, and for how many seconds.
+    /// Instances announced for zero seconds are withdrawn.
+    instances: Vec<(String, u32)>,
+    /// The host and port of each instance.
+    services: HashMap<String, (String, u16)>,
+    /// The addresses of each host.
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Response {
+    /// Returns the address of `instance`, preferring IPv4 if its host has
+    /// both, or `None` if the response doesn't include it.
+    fn address(&self, instance: &str) -> Option<SocketAddr> {
+        let (host, port) = self.services.get(instance)?;
+        let addresses = self.hosts.get(host)?;
+        let ip = addresses
+            .iter()
+            .find(|ip| ip.is_ipv4())
+            .or_else(|| addresses.first())?;
+        Some(SocketAddr::new(*ip, *port))
+    }
+}
+
+impl MdnsBrowser {
+    pub(crate) fn new(
+        base: &Logger,
+        registry: &Registry,
+        config: &Mdns,
+        static_endpoints: Option<Endpoints>,
+    ) -> MetricsResult<Self> {
+        Ok(Self {
+            log: base.new(o!("source" => "cluster::MdnsBrowser")),
+            service: format!("{}.local", config.service_type.to_lowercase()),
+            query_interval: config.query_interval,
+            group: SocketAddr::from((MDNS_GROUP, MDNS_PORT)),
+            static_endpoints: static_endpoints
+                .map(|endpoints| endpoints.as_ref().to_vec())
+                .unwrap_or_default(),
+            instances: HashMap::new(),
+            metrics: Metrics::new(registry)?,
+        })
+    }
+
+    /// Returns an update holding the static endpoints, followed by the
+    /// endpoints of the discovered instances that aren't also static.
+    pub(crate) fn cluster_update(&self) -> ClusterUpdate {
+        let mut addresses = self
+            .instances
+            .values()
+            .map(|instance| instance.address)
+            .filter(|address| {
+                !self
+                    .static_endpoints
+                    .iter()
+                    .any(|endpoint| endpoint.address == *address)
+            })
+            .collect::<Vec<_>>();
+        addresses.sort();
+        addresses.dedup();
+        self.metrics.endpoints.set(addresses.len() as i64);
+
+        let mut endpoints = self.static_endpoints.clone();
+        endpoints.extend(addresses.into_iter().map(Endpoint::from_address));
+        let mut update = ClusterUpdate::new();
+        update.insert(
+            CLUSTER_NAME.into(),
+            Cluster {
+                localities: vec![(None, LocalityEndpoints { endpoints })]
+                    .into_iter()
+                    .collect(),
+            },
+        );
+        update
+    }
+
+    /// Queries for instances every query interval, sending an update to
+    /// `updates_tx` whenever the discovered endpoints change, until a
+    /// shutdown signal is received.
+    pub(crate) async fn run(
+        mut self,
+        updates_tx: mpsc::Sender<ClusterUpdate>,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) {
+        let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                warn!(self.log, "Failed to bind the mDNS socket, no endpoints will be discovered";
+                    "error" => %err);
+                return;
+            }
+        };
+        info!(self.log, "Browsing for endpoints with mDNS"; "service" => &self.service);
+
+        let query = query(&self.service);
+        let mut ticker = time::interval(self.query_interval);
+        let mut buf = vec![0; MAX_RESPONSE_SIZE];
+        loop {
+            let changed = tokio::select! {
+                _ = ticker.tick() => {
+                    if let Err(err) = socket.send_to(&query, self.group).await {
+                        warn!(self.log, "Failed to send an mDNS query"; "error" => %err);
+                    }
+                    self.expire(Instant::now())
+                }
+                result = socket.recv_from(&mut buf) => match result {
+                    Ok((size, from)) => match parse(&buf[..size], &self.service) {
+                        Some(response) => self.apply(response, Instant::now()),
+                        None => {
+                            debug!(self.log, "Ignoring an invalid mDNS response"; "from" => %from);
+                            self.metrics.responses_invalid_total.inc();
+                            false
+                        }
+                    },
+                    Err(err) => {
+                        warn!(self.log, "Failed to receive an mDNS response"; "error" => %err);
+                        false
+                    }
+                },
+                _ = shutdown_rx.changed() => return,
+            };
+
+            if changed {
+                debug!(self.log, "Discovered endpoints changed";
+                    "instances" => self.instances.len());
+                if updates_tx.send(self.cluster_update()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Adds, refreshes or withdraws the instances announced by `response`,
+    /// returning whether the discovered endpoints changed.
+    fn apply(&mut self, response: Response, now: Instant) -> bool {
+        let mut changed = false;
+        for (name, ttl) in &response.instances {
+            if *ttl == 0 {
+                changed |= self.instances.remove(name).is_some();
+                continue;
+            }
+            let address = match response.address(name) {
+                Some(address) => address,
+                None => {
+                    debug!(self.log, "Ignoring an instance without an address"; "instance" => name);
+                    continue;
+                }
+            };
+            let instance = Instance {
+                address,
+                expires_at: now + Duration::from_secs(u64::from(*ttl)),
+            };
+            match self.instances.insert(name.clone(), instance) {
+                Some(previous) => changed |= previous.address != address,
+                None => changed = true,
+            }
+        }
+        changed
+    }
+
+    /// Removes the instances whose records expired, returning whether any
+    /// were.
+    fn expire(&mut self, now: Instant) -> bool {
+        let count = self.instances.len();
+        self.instances
+            .retain(|_, instance| instance.expires_at > now);
+        self.instances.len() != count
+    }
+}
+
+/// Returns a query for the instances of `service`.
+fn query(service: &str) -> Vec<u8> {
+    // A header without an id or flags, and with a single question.
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in service.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+/// Parses the records of `packet` about instances of `service`, returning
+/// `None` if it isn't a valid response.
+fn parse(packet: &[u8], service: &str) -> Option<Response> {
+    if read_u16(packet, 2)? & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    let questions = read_u16(packet, 4)?;
+    let records = (6..12)
+        .step_by(2)
+        .map(|offset| read_u16(packet, offset).map(usize::from))
+        .sum::<Option<usize>>()?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+
+    let mut response = Response::default();
+    for _ in 0..records {
+        let (name, next) = read_name(packet, offset)?;
+        let kind = read_u16(packet, next)?;
+        let class = read_u16(packet, next + 2)? & CLASS_MASK;
+        let ttl = read_u32(packet, next + 4)?;
+        let start = next + 10;
+        let data = packet.get(start..start + usize::from(read_u16(packet, next + 8)?))?;
+        offset = start + data.len();
+        if class != CLASS_IN {
+            continue;
+        }
+
+        match kind {
+            TYPE_PTR if name == service => {
+                response.instances.push((read_name(packet, start)?.0, ttl));
+            }
+            TYPE_SRV => {
+                let port = read_u16(packet, start + 4)?;
+                let (host, _) = read_name(packet, start + 6)?;
+                response.services.insert(name, (host, port));
+            }
+            TYPE_A => {
+                let ip = Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?);
+                response.hosts.entry(name).or_default().push(ip.into());
+            }
+            TYPE_AAAA => {
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?);
+                response.hosts.entry(name).or_default().push(ip.into());
+            }
+            _ => {}
+        }
+    }
+    Some(response)
+}
+
+/// Reads the name at `offset` in `packet`, following any compression
+/// pointers. Returns the name in lowercase, and the offset after it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *packet.get(offset)?;
+        match len & 0xc0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                let label = packet.get(offset + 1..offset + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).to_lowercase());
+                offset += 1 + usize::from(len);
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                offset = usize::from(read_u16(packet, offset)? & 0x3fff);
+            }
+            _ => return None,
+        }
+    }
+    Some((labels.join("."), end.unwrap_or(offset + 1)))
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    let bytes = packet.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(packet: &[u8], offset: usize) -> Option<u32> {
+    let bytes = packet.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use prometheus::Registry;
+    use tokio::net::UdpSocket;
+    use tokio::sync::{mpsc, watch};
+    use tokio::time::{timeout, Duration};
+
+    use super::{
+        is_service_type, parse, query, read_name, MdnsBrowser, CLUSTER_NAME, TYPE_A, TYPE_PTR,
+        TYPE_SRV,
+    };
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, Mdns};
+    use crate::test_utils::logger;
+    use crate::xds::ads_client::ClusterUpdate;
+
+    /// Returns the uncompressed encoding of `name`.
+    fn name(name: &str) -> Vec<u8> {
+        let mut encoded = query(name);
+        encoded.drain(..12);
+        encoded.truncate(encoded.len() - 4);
+        encoded
+    }
+
+    fn record(owner: &str, kind: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut record = name(owner);
+        record.extend_from_slice(&kind.to_be_bytes());
+        // The cache-flush bit is set.
+        record.extend_from_slice(&0x8001u16.to_be_bytes());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    /// Returns a response announcing the instance `instance` at `address`
+    /// for `ttl` seconds.
+    fn announcement(instance: &str, address: SocketAddr, ttl: u32) -> Vec<u8> {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+        let instance = format!("{}._game._udp.local", instance);
+        packet.extend(record("_game._udp.local", TYPE_PTR, ttl, &name(&instance)));
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&address.port().to_be_bytes());
+        srv.extend(name("host.local"));
+        packet.extend(record(&instance, TYPE_SRV, 120, &srv));
+        let ip = match address.ip() {
+            std::net::IpAddr::V4(ip) => ip.octets(),
+            _ => unreachable!("expected an IPv4 address"),
+        };
+        packet.extend(record("host.local", TYPE_A, 120, &ip));
+        packet
+    }
+
+    fn addresses(update: &ClusterUpdate) -> Vec<SocketAddr> {
+        update[CLUSTER_NAME]
+            .localities
+            .values()
+            .flat_map(|locality| locality.endpoints.iter().map(|endpoint| endpoint.address))
+            .collect()
+    }
+
+    #[test]
+    fn service_type() {
+        assert!(is_service_type("_game._udp"));
+        assert!(is_service_type("_quilkin-1._tcp"));
+        assert!(!is_service_type("game._udp"));
+        assert!(!is_service_type("_game"));
+        assert!(!is_service_type("_game._sctp"));
+        assert!(!is_service_type("_game._udp.local"));
+        assert!(!is_service_type("_a-very-long-service._udp"));
+    }
+
+    #[test]
+    fn parse_response() {
+        let address = "10.0.0.2:7777".parse().unwrap();
+        let response = parse(&announcement("One", address, 60), "_game._udp.local").unwrap();
+        assert_eq!(
+            vec![("one._game._udp.local".to_string(), 60)],
+            response.instances
+        );
+        assert_eq!(Some(address), response.address("one._game._udp.local"));
+
+        // Records of other services are ignored.
+        let response = parse(&announcement("One", address, 60), "_other._udp.local").unwrap();
+        assert!(response.instances.is_empty());
+
+        // Queries and truncated responses are invalid.
+        assert_eq!(None, parse(&query("_game._udp.local"), "_game._udp.local"));
+        let mut truncated = announcement("One", address, 60);
+        truncated.pop();
+        assert_eq!(None, parse(&truncated, "_game._udp.local"));
+    }
+
+    #[test]
+    fn read_compressed_name() {
+        let mut packet = name("host.local");
+        // `game` followed by a pointer to `local`.
+        packet.extend_from_slice(&[4, b'g', b'a', b'm', b'e', 0xc0, 5]);
+        assert_eq!(Some(("game.local".into(), 19)), read_name(&packet, 12));
+
+        // A pointer to itself.
+        packet.extend_from_slice(&[0xc0, 19]);
+        assert_eq!(None, read_name(&packet, 19));
+    }
+
+    #[tokio::test]
+    async fn browse() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let static_address = "127.0.0.1:7000".parse().unwrap();
+        let mut browser = MdnsBrowser::new(
+            &logger(),
+            &Registry::default(),
+            &Mdns {
+                service_type: "_game._udp".into(),
+                query_interval: Duration::from_secs(60),
+            },
+            Some(Endpoints::new(vec![Endpoint::from_address(static_address)]).unwrap()),
+        )
+        .unwrap();
+        browser.group = responder.local_addr().unwrap();
+        assert_eq!(vec![static_address], addresses(&browser.cluster_update()));
+
+        let (updates_tx, mut updates_rx) = mpsc::channel(1);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(browser.run(updates_tx, shutdown_rx));
+
+        let mut buf = vec![0; 1500];
+        let (size, from) = timeout(Duration::from_secs(5), responder.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(query("_game._udp.local"), &buf[..size]);
+
+        let discovered = "127.0.0.1:7001".parse().unwrap();
+        responder
+            .send_to(&announcement("one", discovered, 60), from)
+            .await
+            .unwrap();
+        let update = timeout(Duration::from_secs(5), updates_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(vec![static_address, discovered], addresses(&update));
+
+        // The instance is withdrawn.
+        responder
+            .send_to(&announcement("one", discovered, 0), from)
+            .await
+            .unwrap();
+        let update = timeout(Duration::from_secs(5), updates_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(vec![static_address], addresses(&update));
+    }
+}
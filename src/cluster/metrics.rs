@@ -17,12 +17,15 @@
 use crate::metrics::{opts, CollectorExt};
 use prometheus::core::{AtomicI64, GenericGauge};
 use prometheus::Result as MetricsResult;
-use prometheus::{IntGauge, Registry};
+use prometheus::{IntCounter, IntGauge, Registry};
 
 #[derive(Clone)]
 pub(super) struct Metrics {
     pub active_clusters: GenericGauge<AtomicI64>,
     pub active_endpoints: GenericGauge<AtomicI64>,
+    pub failover_active: IntGauge,
+    pub failovers_total: IntCounter,
+    pub endpoint_updates_guarded_total: IntCounter,
 }
 
 impl Metrics {
@@ -41,6 +44,25 @@ impl Metrics {
                 "Number of currently active endpoints.",
             ))?
             .register_if_not_exists(registry)?,
+            failover_active: IntGauge::with_opts(opts(
+                "failover_active",
+                subsystem,
+                "Whether traffic is currently sent to the failover cluster.",
+            ))?
+            .register_if_not_exists(registry)?,
+            failovers_total: IntCounter::with_opts(opts(
+                "failovers_total",
+                subsystem,
+                "Total number of times traffic was failed over to the failover cluster.",
+            ))?
+            .register_if_not_exists(registry)?,
+            endpoint_updates_guarded_total: IntCounter::with_opts(opts(
+                "endpoint_updates_guarded_total",
+                subsystem,
+                "Total number of endpoint updates that were rejected or phased in as they \
+                 removed too many endpoints at once.",
+            ))?
+            .register_if_not_exists(registry)?,
         })
     }
 }
@@ -0,0 +1,221 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use slog::{info, o, Logger};
+use tokio::time::Instant;
+
+use crate::cluster::Endpoint;
+use crate::config::{EndpointSlowStart, Endpoints};
+
+/// How much the weights of all endpoints are scaled up by while any endpoint
+/// is warming up, so that the share of an endpoint with the default weight
+/// can still be ramped up gradually.
+const WEIGHT_SCALE: u32 = 100;
+
+/// Ramps up the share of traffic of endpoints that were just added, or
+/// re-admitted after being marked unhealthy, over a window. An endpoint's
+/// share is ramped up by scaling down its weight in proportion to how far
+/// into the window it is, so it applies wherever endpoints are chosen by
+/// weight.
+///
+/// The endpoints known when the proxy first receives endpoints are treated
+/// as already warm.
+///
+/// **Note:** Cloning [`SlowStart`] is shallow, clones share the same state.
+#[derive(Clone)]
+pub struct SlowStart {
+    log: Logger,
+    window: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The endpoints that were last weighed, to tell when they change.
+    endpoints: Option<Endpoints>,
+    /// When each known endpoint started warming up, or `None` if it was
+    /// known before any others.
+    started_at: HashMap<SocketAddr, Option<Instant>>,
+}
+
+impl SlowStart {
+    pub fn new(base: &Logger, config: EndpointSlowStart) -> Self {
+        Self {
+            log: base.new(o!("source" => "cluster::SlowStart")),
+            window: config.window,
+            state: Default::default(),
+        }
+    }
+
+    /// Restarts the warm-up of the endpoint at `address`, e.g once it's
+    /// re-admitted after being marked unhealthy.
+    pub fn restart(&self, address: SocketAddr) {
+        self.state
+            .lock()
+            .started_at
+            .insert(address, Some(Instant::now()));
+        info!(self.log, "Endpoint warming up"; "address" => %address, "window" => ?self.window);
+    }
+
+    /// Returns `endpoints` with the weights of the endpoints that are warming
+    /// up scaled down by how far into the window they are, or `None` if none
+    /// of them are warming up.
+    pub fn weigh(&self, endpoints: &Endpoints) -> Option<Endpoints> {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        if state.endpoints.as_ref() != Some(endpoints) {
+            self.track(&mut state, endpoints, now);
+        }
+
+        // How long the endpoint at `address` has been warming up for, if
+        // it still is.
+        let warming_for = |address: &SocketAddr| match state.started_at.get(address) {
+            Some(Some(started_at)) => {
+                let elapsed = now.saturating_duration_since(*started_at);
+                (elapsed < self.window).then(|| elapsed)
+            }
+            _ => None,
+        };
+        if !endpoints
+            .as_ref()
+            .iter()
+            .any(|endpoint| warming_for(&endpoint.address).is_some())
+        {
+            return None;
+        }
+
+        let weighed = endpoints
+            .as_ref()
+            .iter()
+            .map(|endpoint| {
+                let weight = endpoint.weight.saturating_mul(WEIGHT_SCALE);
+                let weight = match warming_for(&endpoint.address) {
+                    // Weights are at least 1, so an endpoint that just
+                    // started warming up still receives some traffic.
+                    Some(elapsed) => {
+                        let scaled =
+                            u128::from(weight) * elapsed.as_nanos() / self.window.as_nanos();
+                        (scaled as u32).max(1)
+                    }
+                    None => weight,
+                };
+                Endpoint {
+                    weight,
+                    ..endpoint.clone()
+                }
+            })
+            .collect();
+        Endpoints::new(weighed).ok()
+    }
+
+    /// Starts warming up the endpoints in `endpoints` that weren't known,
+    /// and forgets the ones that were removed, so that they warm up again if
+    /// they are added back.
+    fn track(&self, state: &mut State, endpoints: &Endpoints, now: Instant) {
+        let first = state.endpoints.is_none();
+        let addresses = endpoints
+            .as_ref()
+            .iter()
+            .map(|endpoint| endpoint.address)
+            .collect::<HashSet<_>>();
+        state
+            .started_at
+            .retain(|address, _| addresses.contains(address));
+        for address in addresses {
+            state.started_at.entry(address).or_insert_with(|| {
+                if first {
+                    None
+                } else {
+                    info!(self.log, "Endpoint warming up";
+                        "address" => %address, "window" => ?self.window);
+                    Some(now)
+                }
+            });
+        }
+        state.endpoints = Some(endpoints.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::time::{self, Duration};
+
+    use super::SlowStart;
+    use crate::cluster::Endpoint;
+    use crate::config::{EndpointSlowStart, Endpoints};
+    use crate::test_utils::logger;
+
+    fn endpoints(addresses: &[&str]) -> Endpoints {
+        Endpoints::new(
+            addresses
+                .iter()
+                .map(|address| Endpoint::from_address(address.parse().unwrap()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    fn weights(endpoints: Option<Endpoints>) -> Option<Vec<u32>> {
+        endpoints.map(|endpoints| {
+            endpoints
+                .as_ref()
+                .iter()
+                .map(|endpoint| endpoint.weight)
+                .collect()
+        })
+    }
+
+    #[tokio::test]
+    async fn weigh() {
+        time::pause();
+        let slow_start = SlowStart::new(
+            &logger(),
+            EndpointSlowStart {
+                window: Duration::from_secs(10),
+            },
+        );
+
+        // The first endpoints are already warm.
+        let initial = endpoints(&["127.0.0.1:7001"]);
+        assert_eq!(None, weights(slow_start.weigh(&initial)));
+
+        let added = endpoints(&["127.0.0.1:7001", "127.0.0.1:7002"]);
+        assert_eq!(Some(vec![100, 1]), weights(slow_start.weigh(&added)));
+        time::advance(Duration::from_secs(4)).await;
+        assert_eq!(Some(vec![100, 40]), weights(slow_start.weigh(&added)));
+        time::advance(Duration::from_secs(6)).await;
+        assert_eq!(None, weights(slow_start.weigh(&added)));
+
+        // An endpoint warms up again once it's re-admitted.
+        let address: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        slow_start.restart(address);
+        time::advance(Duration::from_secs(5)).await;
+        assert_eq!(Some(vec![50, 100]), weights(slow_start.weigh(&added)));
+
+        // Or removed and added back.
+        time::advance(Duration::from_secs(5)).await;
+        assert_eq!(None, weights(slow_start.weigh(&initial)));
+        assert_eq!(Some(vec![100, 1]), weights(slow_start.weigh(&added)));
+    }
+}
@@ -17,23 +17,37 @@
 use std::io;
 use std::marker::PhantomData;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use base64_serde::base64_serde_type;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::secret::SecretRef;
+
 mod builder;
+mod cidr;
+mod encryption;
 mod endpoints;
 mod error;
 mod metadata;
+mod profile;
+mod schedule;
 
 pub use crate::config::endpoints::{
     EmptyListError, Endpoints, RetainedItems, UpstreamEndpoints, UpstreamEndpointsIter,
 };
 pub(crate) use crate::config::error::ValueInvalidArgs;
 pub use builder::Builder;
+pub use cidr::Cidr;
+pub use encryption::{ConfigKey, EncryptionError, KEY_ENV, KEY_FILE_ENV};
 pub use error::ValidationError;
-pub(crate) use metadata::{extract_endpoint_tokens, parse_endpoint_metadata_from_yaml};
+pub use profile::ProfileError;
+pub use schedule::{ActivationWindow, EndpointSchedule, FilterSchedule, Schedule, TimeOfDay};
+pub(crate) use metadata::{
+    extract_endpoint_relay_token, extract_endpoint_tokens, parse_endpoint_metadata_from_yaml,
+};
 
 base64_serde_type!(Base64Standard, base64::STANDARD);
 
@@ -54,201 +68,2931 @@ pub struct Proxy {
     pub id: String,
     #[serde(default = "default_proxy_port")]
     pub port: u16,
+    /// What to do if another proxy is already listening on `port` when the
+    /// proxy starts.
+    #[serde(default)]
+    pub port_conflict_policy: PortConflictPolicy,
+    /// The secret that requests between proxies on the same port are signed
+    /// with, which they must share. Required to take over the port.
+    #[serde(with = "Base64Standard", default)]
+    pub port_conflict_secret: Vec<u8>,
+    /// The maximum size in bytes of a packet, both as it is received and
+    /// as it is produced by the filter chain. Receive buffers are sized to
+    /// hold packets of this size.
+    #[serde(default = "default_max_packet_size")]
+    pub max_packet_size: usize,
+    /// What to do with packets that exceed `max_packet_size`, whether when
+    /// received or after filtering.
+    #[serde(default)]
+    pub oversized_packet_policy: OversizedPacketPolicy,
+    /// If set, packets received while there are no endpoints to forward
+    /// them to, or all of them are unhealthy, are held for a while rather
+    /// than dropped.
+    #[serde(default)]
+    pub failover_buffer: Option<FailoverBuffer>,
+    /// If set, an external service is consulted before a session is created
+    /// for a client, and can reject it.
+    #[serde(default)]
+    pub connection_tracker: Option<ConnectionTracker>,
+    /// If set, a client must echo a cookie sent by the proxy before a
+    /// session is created for it.
+    #[serde(default)]
+    pub handshake: Option<Handshake>,
+    /// If set, filter chains containing heavy filters are run on a
+    /// dedicated pool of threads.
+    #[serde(default)]
+    pub compute_pool: Option<ComputePool>,
+    /// Options for the sockets that sessions send packets to endpoints from.
+    #[serde(default)]
+    pub upstream_socket: UpstreamSocket,
+    /// If set, packets that haven't been processed by the filter chain
+    /// within this long of being received are dropped, as are packets
+    /// received while all workers are busy.
+    #[serde(default, with = "humantime_serde")]
+    pub packet_deadline: Option<Duration>,
+    /// If set, received packets wait in a queue that workers drain one
+    /// client at a time, so that a client flooding the proxy can't starve
+    /// other clients while it is overloaded.
+    #[serde(default)]
+    pub fair_queue: Option<FairQueue>,
+    /// If set, a session is only created for a client if its first packet
+    /// passes these checks.
+    #[serde(default)]
+    pub first_packet: Option<FirstPacket>,
+    /// If set, the version of a client's build is read from the first packet
+    /// of its sessions, and added to the sessions' logs and metrics.
+    #[serde(default)]
+    pub client_version: Option<ClientVersion>,
+    /// If set, a session is torn down if its endpoint hasn't sent a packet
+    /// within this long of the session being created.
+    #[serde(default, with = "humantime_serde")]
+    pub first_response_timeout: Option<Duration>,
+    /// Limits on how long filters can take to process a packet.
+    #[serde(default)]
+    pub filter_timeouts: Vec<FilterTimeout>,
+    /// Limits on the processing time and memory that filters can use.
+    #[serde(default)]
+    pub filter_budgets: Vec<FilterBudget>,
+    /// If set, the endpoints that routing filters route each client to are
+    /// cached, so that the client's packets skip those filters.
+    #[serde(default)]
+    pub routing_cache: Option<RoutingCache>,
+    /// If set, the packets of trusted clients, such as game servers relaying
+    /// traffic to each other, skip the expensive filters meant for untrusted
+    /// players.
+    #[serde(default)]
+    pub trusted_clients: Option<TrustedClients>,
+    /// Configures the metrics that the proxy reports.
+    #[serde(default)]
+    pub metrics: Metrics,
+    /// If set, metrics are pushed to a Prometheus push gateway.
+    #[serde(default)]
+    pub metrics_push: Option<MetricsPush>,
+    /// If set, sessions send packets to endpoints through a tunnel to a
+    /// peer proxy rather than over UDP.
+    #[serde(default)]
+    pub tunnel_peer: Option<TunnelPeer>,
+    /// If set, the proxy accepts tunnels from peer proxies and forwards
+    /// their packets to endpoints over UDP.
+    #[serde(default)]
+    pub tunnel_listener: Option<TunnelListener>,
+    /// If set, the proxy accepts UDP proxying requests (CONNECT-UDP) over
+    /// HTTP/1.1, and handles their packets as if they were received over UDP.
+    #[serde(default)]
+    pub connect_udp: Option<ConnectUdp>,
+    /// If set, the proxy accepts SOCKS5 UDP associations, and handles their
+    /// packets as if they were received over UDP.
+    #[serde(default)]
+    pub socks5: Option<Socks5>,
+    /// If set, admin requests, the loading of the configuration and updates
+    /// from management servers are recorded in an audit log.
+    #[serde(default)]
+    pub audit_log: Option<AuditLog>,
+    /// Addresses that packets are accepted from on behalf of clients, but
+    /// never sent to, such as a spectator or replay broadcaster.
+    #[serde(default)]
+    pub response_only_endpoints: Vec<EndPoint>,
+    /// Windows of time during which filters and endpoints are active.
+    #[serde(default)]
+    pub schedule: Schedule,
+    /// If set, sessions are shed while the proxy uses more memory or file
+    /// descriptors than these limits.
+    #[serde(default)]
+    pub resource_limits: Option<ResourceLimits>,
+    /// If set, endpoints are excluded from load balancing while the traffic
+    /// sent to them shows they are unhealthy.
+    #[serde(default)]
+    pub endpoint_health_check: Option<EndpointHealthCheck>,
+    /// If set, the share of traffic of endpoints that were just added, or
+    /// re-admitted after being unhealthy, is ramped up over a window.
+    #[serde(default)]
+    pub endpoint_slow_start: Option<EndpointSlowStart>,
+    /// If set, faults are injected into the proxy to test how it recovers
+    /// from them.
+    #[serde(default)]
+    pub faults: Option<Faults>,
+    /// If set, sessions are keyed by a token or connection id found in the
+    /// client's packets rather than by its address, so that clients keep
+    /// their sessions when their address changes.
+    #[serde(default)]
+    pub session_key: Option<SessionKeySource>,
+    /// If set, the proxy assigns each client a connection id, which the
+    /// client can send at the start of its packets so that it keeps its
+    /// session when its address changes.
+    #[serde(default)]
+    pub connection_id: Option<ConnectionId>,
+    /// If set, the proxy answers ICE connectivity checks as an ICE-lite
+    /// agent, so that clients using ICE can check their paths to it.
+    #[serde(default)]
+    pub ice: Option<Ice>,
+    /// If set, clients banned by this proxy are banned by its peers too, and
+    /// the other way around, so that a client moving to another proxy is
+    /// dropped there straight away.
+    #[serde(default)]
+    pub ban_gossip: Option<BanGossip>,
+    /// If set, the proxy acts as a relay for other proxies, sending the
+    /// packets they wrap in a relay envelope to the endpoints with the
+    /// envelope's token.
+    #[serde(default)]
+    pub relay: Option<Relay>,
+    /// If set, the packets of each session are sent to its endpoint in the
+    /// order they were received, even though the proxy processes packets on
+    /// several workers.
+    #[serde(default)]
+    pub ordered_sends: Option<OrderedSends>,
+    /// If set, the proxy keeps statistics on the packets it receives, which
+    /// are served by the admin server.
+    #[serde(default)]
+    pub analyzer: Option<Analyzer>,
+    /// If set, the proxy is one of an active/standby pair, only processing
+    /// packets while it's the active proxy of the pair, and taking over the
+    /// sessions of the active proxy if it fails.
+    #[serde(default)]
+    pub standby: Option<Standby>,
+    /// If set, the proxy flags clients that send bursts of packets, e.g
+    /// because of a speed hack or a flood.
+    #[serde(default)]
+    pub burst_detection: Option<BurstDetection>,
+    /// If set, the proxy logs a sample of the decisions of which endpoints
+    /// packets are sent to, and keeps the latest ones for the admin server.
+    #[serde(default)]
+    pub decision_log: Option<DecisionLog>,
+    /// If set, a summary of the proxy's metrics is reported to the
+    /// management server it's connected to, e.g so that client side proxies
+    /// don't each have to be scraped.
+    #[serde(default)]
+    pub telemetry: Option<Telemetry>,
 }
 
-fn default_proxy_id() -> String {
-    Uuid::new_v4().to_hyphenated().to_string()
+/// Configures how packets received while there are no endpoints to forward
+/// them to (e.g while endpoints are being replaced, or have all been marked
+/// unhealthy) are held until endpoints become available.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FailoverBuffer {
+    /// How long a packet is held before it is dropped.
+    #[serde(with = "humantime_serde", default = "default_failover_buffer_max_delay")]
+    pub max_delay: Duration,
+    /// The maximum number of packets held for each client. Once reached,
+    /// the client's oldest packet is dropped to make room for a new one.
+    #[serde(default = "default_failover_buffer_max_packets")]
+    pub max_packets: usize,
 }
 
-fn default_proxy_port() -> u16 {
-    7000
+fn default_failover_buffer_max_delay() -> Duration {
+    Duration::from_millis(100)
 }
 
-impl Default for Proxy {
-    fn default() -> Self {
-        Proxy {
-            id: default_proxy_id(),
-            port: default_proxy_port(),
-        }
-    }
+fn default_failover_buffer_max_packets() -> usize {
+    32
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// Configures the external service that admits or rejects new sessions.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct Admin {
-    pub address: SocketAddr,
+pub struct ConnectionTracker {
+    /// The address of the connection tracker's gRPC server.
+    pub address: String,
+    /// The dynamic metadata key holding the token sent along with the
+    /// client's address, e.g as captured by the CaptureBytes filter.
+    #[serde(default = "default_connection_tracker_metadata_key")]
+    pub metadata_key: String,
+    /// How long to wait for a decision before applying `failure_policy`.
+    #[serde(with = "humantime_serde", default = "default_connection_tracker_timeout")]
+    pub timeout: Duration,
+    /// How long a decision is reused for the same client and token, unless
+    /// the connection tracker specifies otherwise.
+    #[serde(with = "humantime_serde", default = "default_connection_tracker_cache_ttl")]
+    pub cache_ttl: Duration,
+    /// Whether sessions are admitted if no decision can be made.
+    #[serde(default)]
+    pub failure_policy: FailurePolicy,
 }
 
-impl Default for Admin {
-    fn default() -> Self {
-        Admin {
-            address: "[::]:9091".parse().unwrap(),
-        }
-    }
+fn default_connection_tracker_metadata_key() -> String {
+    "quilkin.dev/captured_bytes".into()
+}
+
+fn default_connection_tracker_timeout() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_connection_tracker_cache_ttl() -> Duration {
+    Duration::from_secs(60)
 }
 
+/// Configures what a session's client is identified by, other than its
+/// address.
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct ManagementServer {
-    pub address: String,
+pub struct SessionKeySource {
+    pub kind: SessionKeyKind,
+    /// The dynamic metadata key holding the token or connection id, e.g as
+    /// captured by the CaptureBytes filter. Packets without it are keyed by
+    /// the client's address.
+    #[serde(default = "default_session_key_metadata_key")]
+    pub metadata_key: String,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub enum Source {
-    #[serde(rename = "static")]
-    Static {
-        #[serde(default)]
-        filters: Vec<Filter>,
+fn default_session_key_metadata_key() -> String {
+    "quilkin.dev/captured_bytes".into()
+}
 
-        endpoints: Vec<EndPoint>,
-    },
-    #[serde(rename = "dynamic")]
-    Dynamic {
-        management_servers: Vec<ManagementServer>,
-    },
+/// What a session's client is identified by.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum SessionKeyKind {
+    /// The client's IP address and a token in its packets, so the client
+    /// keeps its session if its port changes, and clients sharing an address
+    /// have separate sessions.
+    #[serde(rename = "IP_AND_TOKEN")]
+    IpAndToken,
+    /// A connection id in the client's packets, so the client keeps its
+    /// session whatever its address changes to.
+    #[serde(rename = "CONNECTION_ID")]
+    ConnectionId,
 }
 
-/// Config is the configuration of a proxy
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// Configures the stateless handshake that a client must complete before a
+/// session is created for it, which stops clients spoofing their address
+/// from creating sessions.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct Config {
-    pub version: Version,
+pub struct Handshake {
+    /// The secret that cookies are signed with. If empty, a random secret
+    /// is generated when the proxy starts.
+    #[serde(with = "Base64Standard", default)]
+    pub secret: Vec<u8>,
+    /// A reference to the secret that cookies are signed with, as an
+    /// alternative to setting `secret`.
+    #[serde(default)]
+    pub secret_ref: Option<SecretRef>,
+    /// The bytes that a packet containing a cookie starts with.
+    #[serde(with = "Base64Standard", default = "default_handshake_prefix")]
+    pub prefix: Vec<u8>,
+    /// How long a cookie is valid for after it has been sent to the client.
+    #[serde(with = "humantime_serde", default = "default_handshake_cookie_lifetime")]
+    pub cookie_lifetime: Duration,
+}
+
+fn default_handshake_prefix() -> Vec<u8> {
+    b"QUILKIN_COOKIE".to_vec()
+}
+
+fn default_handshake_cookie_lifetime() -> Duration {
+    Duration::from_secs(30)
+}
 
+/// Configures the connection ids that the proxy assigns to clients. A
+/// client's id is sent at the start of the packets sent to it until the
+/// client sends it back, and packets starting with it are matched to the
+/// client's session whatever address they are received from.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionId {
+    /// The secret that connection ids are signed with. If empty, a random
+    /// secret is generated when the proxy starts.
+    #[serde(with = "Base64Standard", default)]
+    pub secret: Vec<u8>,
+    /// A reference to the secret that connection ids are signed with, as an
+    /// alternative to setting `secret`.
     #[serde(default)]
-    pub proxy: Proxy,
+    pub secret_ref: Option<SecretRef>,
+    /// The bytes that a packet containing a connection id starts with.
+    #[serde(with = "Base64Standard", default = "default_connection_id_prefix")]
+    pub prefix: Vec<u8>,
+}
+
+fn default_connection_id_prefix() -> Vec<u8> {
+    b"QUILKIN_CID".to_vec()
+}
 
+/// Configures the proxy as an ICE-lite agent, which answers the STUN
+/// connectivity checks that clients send to its candidates, so that a client
+/// can choose the best path to a session while keeping the proxy in the
+/// middle. The proxy's ICE parameters and candidates are served on the
+/// `/ice` admin endpoint, for exchanging with clients.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Ice {
+    /// The username fragment that checks sent to the proxy start their
+    /// username with. If empty, one is generated when the proxy starts.
     #[serde(default)]
-    pub admin: Admin,
+    pub ufrag: String,
+    /// The password that checks are authenticated with. If empty, one is
+    /// generated when the proxy starts.
+    #[serde(default)]
+    pub password: String,
+    /// The addresses that clients can reach the proxy at, which are
+    /// advertised as its host candidates in order of preference.
+    pub candidates: Vec<SocketAddr>,
+}
 
-    #[serde(flatten)]
-    pub source: Source,
+/// Configures the bans that proxies share with each other. A client's IP
+/// address is banned once filters drop enough of its packets for one of the
+/// configured reasons, e.g once it's rate limited. Packets from banned
+/// clients are dropped before any other processing, and each new ban is sent
+/// to the proxy's peers, signed with a secret that all of them share.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BanGossip {
+    /// The UDP port that bans are received from peers on.
+    pub port: u16,
+    /// The addresses of the peers that bans are sent to.
+    #[serde(default)]
+    pub peers: Vec<SocketAddr>,
+    /// The secret that bans are signed with, which all peers must share.
+    #[serde(with = "Base64Standard")]
+    pub secret: Vec<u8>,
+    /// How long a client is banned for.
+    #[serde(with = "humantime_serde", default = "default_ban_gossip_duration")]
+    pub duration: Duration,
+    /// The reasons for dropping a packet that the client is banned for, as
+    /// given by the filter that dropped it.
+    #[serde(default)]
+    pub drop_reasons: Vec<String>,
+    /// How many of a client's packets must be dropped for one of
+    /// `drop_reasons` within a `sweep_interval` for it to be banned. Only
+    /// clients that have completed the handshake or have a session are.
+    #[serde(default = "default_ban_gossip_min_drops")]
+    pub min_drops: u32,
+    /// Whether clients are banned once the connection tracker rejects them.
+    #[serde(default)]
+    pub ban_rejected: bool,
+    /// The maximum number of clients banned at once. Once reached, expired
+    /// bans are removed before a new one is added.
+    #[serde(default = "default_ban_gossip_max_bans")]
+    pub max_bans: usize,
+    /// If set, bans are saved to this file and loaded from it when the proxy
+    /// starts, so that they survive restarts.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    /// How often expired bans are removed, and changed bans are saved to
+    /// `file`.
+    #[serde(with = "humantime_serde", default = "default_ban_gossip_sweep_interval")]
+    pub sweep_interval: Duration,
+    /// The ranges of the addresses of clients that are never banned, along
+    /// with loopback addresses and those of trusted clients.
+    #[serde(default)]
+    pub never_ban: Vec<Cidr>,
+}
 
-    // Limit struct creation to the builder. We use an Optional<Phantom>
-    // so that we can create instances though deserialization.
-    #[serde(skip_serializing)]
-    pub(super) phantom: Option<PhantomData<()>>,
+fn default_ban_gossip_duration() -> Duration {
+    Duration::from_secs(60)
 }
 
-impl Source {
-    /// Returns the list of filters if the config is a static config and None otherwise.
-    /// This is a convenience function and should only be used for doc tests and tests.
-    pub fn get_static_filters(&self) -> Option<&[Filter]> {
-        match self {
-            Source::Static {
-                filters,
-                endpoints: _,
-            } => Some(filters),
-            Source::Dynamic {
-                management_servers: _,
-            } => None,
-        }
-    }
+fn default_ban_gossip_min_drops() -> u32 {
+    10
 }
 
-/// Filter is the configuration for a single filter
-#[derive(Debug, Deserialize, Serialize, Clone)]
+fn default_ban_gossip_max_bans() -> usize {
+    100_000
+}
+
+fn default_ban_gossip_sweep_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// Configures the proxy as a relay, i.e as an endpoint of other proxies.
+/// Packets sent to an endpoint with a relay token are wrapped in an envelope
+/// holding the token, which the relay removes before processing the packet
+/// as usual, with only its endpoints that have the token.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct Filter {
-    pub name: String,
-    pub config: Option<serde_yaml::Value>,
+pub struct Relay {
+    /// Whether packets without an envelope are dropped, rather than sent to
+    /// any of the endpoints.
+    #[serde(default)]
+    pub require_envelope: bool,
 }
 
-/// A singular endpoint, to pass on UDP packets to.
-#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+/// Configures the ordering of the packets sent to endpoints. Each client's
+/// packets are processed by the same worker, and each session sends its
+/// packets from a queue drained by a single task, so that the proxy never
+/// reorders the packets of a session. Packets delayed by filters are still
+/// sent once their delay has elapsed.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct EndPoint {
-    pub address: SocketAddr,
-    pub metadata: Option<serde_yaml::Value>,
+pub struct OrderedSends {
+    /// The maximum number of packets waiting to be sent by a session. Once
+    /// reached, the session's packets are dropped until it catches up.
+    #[serde(default = "default_ordered_sends_queue_size")]
+    pub queue_size: usize,
 }
 
-impl EndPoint {
-    pub fn new(address: SocketAddr) -> Self {
-        EndPoint::with_metadata(address, None)
+fn default_ordered_sends_queue_size() -> usize {
+    256
+}
+
+/// Configures the analyzer, which keeps statistics on the packets the proxy
+/// receives from clients: the addresses sending the most packets, the
+/// distribution of packet sizes and the time between packets. Statistics
+/// cover the current and the previous window.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Analyzer {
+    /// The number of addresses sending the most packets that are reported.
+    #[serde(default = "default_analyzer_top_talkers")]
+    pub top_talkers: usize,
+    /// How long each window of statistics lasts.
+    #[serde(with = "humantime_serde", default = "default_analyzer_window")]
+    pub window: Duration,
+    /// The maximum number of addresses counted in a window. Packets from
+    /// other addresses are only counted in the totals, so that a flood from
+    /// many addresses can't exhaust the proxy's memory.
+    #[serde(default = "default_analyzer_max_sources")]
+    pub max_sources: usize,
+}
+
+fn default_analyzer_top_talkers() -> usize {
+    10
+}
+
+fn default_analyzer_window() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_analyzer_max_sources() -> usize {
+    10_000
+}
+
+/// Configures a proxy as one of an active/standby pair. The proxies send
+/// each other heartbeats, and the active proxy replicates the state of its
+/// sessions to the standby. The standby becomes the active proxy once it
+/// hasn't received a heartbeat from the active proxy for the failover
+/// timeout, restoring the replicated sessions. Heartbeats and state are
+/// signed with a secret that both proxies share.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Standby {
+    /// The port that heartbeats (UDP) and state (TCP) are received from the
+    /// peer on.
+    pub port: u16,
+    /// The address that heartbeats and state are sent to the peer at.
+    pub peer: SocketAddr,
+    /// The secret that heartbeats and state are signed with, which both
+    /// proxies must share.
+    #[serde(with = "Base64Standard")]
+    pub secret: Vec<u8>,
+    /// The proxy's priority. If both proxies are active at once, the one with
+    /// the lower priority becomes the standby.
+    #[serde(default = "default_standby_priority")]
+    pub priority: u8,
+    /// Whether the proxy takes over from an active peer with a lower
+    /// priority.
+    #[serde(default = "default_standby_preempt")]
+    pub preempt: bool,
+    /// How often heartbeats are sent to the peer.
+    #[serde(with = "humantime_serde", default = "default_standby_heartbeat_interval")]
+    pub heartbeat_interval: Duration,
+    /// How long the standby goes without a heartbeat from the active proxy
+    /// before it becomes the active proxy.
+    #[serde(with = "humantime_serde", default = "default_standby_failover_timeout")]
+    pub failover_timeout: Duration,
+    /// How often the active proxy replicates its state to the standby.
+    #[serde(with = "humantime_serde", default = "default_standby_sync_interval")]
+    pub sync_interval: Duration,
+}
+
+fn default_standby_priority() -> u8 {
+    100
+}
+
+fn default_standby_preempt() -> bool {
+    true
+}
+
+fn default_standby_heartbeat_interval() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_standby_failover_timeout() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_standby_sync_interval() -> Duration {
+    Duration::from_millis(200)
+}
+
+/// Configures the detection of bursts of packets from clients. The packets
+/// received from each client are counted over consecutive windows, and the
+/// client is flagged once it sends more than `max_packets` within a window.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BurstDetection {
+    /// How long each window that packets are counted over lasts.
+    #[serde(with = "humantime_serde", default = "default_burst_detection_window")]
+    pub window: Duration,
+    /// The number of packets a client can send within a window without
+    /// being flagged.
+    pub max_packets: u64,
+    /// How long after an event about a client is emitted before another one
+    /// is emitted about it, while it keeps bursting.
+    #[serde(with = "humantime_serde", default = "default_burst_detection_cooldown")]
+    pub cooldown: Duration,
+    /// Whether events are logged.
+    #[serde(default = "default_burst_detection_log")]
+    pub log: bool,
+    /// If set, events are sent to this http URL as JSON in a POST request.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// The maximum number of clients whose packets are counted at once, so
+    /// that a flood from many addresses can't exhaust the proxy's memory.
+    #[serde(default = "default_burst_detection_max_clients")]
+    pub max_clients: usize,
+}
+
+fn default_burst_detection_window() -> Duration {
+    Duration::from_millis(10)
+}
+
+fn default_burst_detection_cooldown() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_burst_detection_log() -> bool {
+    true
+}
+
+fn default_burst_detection_max_clients() -> usize {
+    100_000
+}
+
+/// Configures the decision log, which records why sampled packets were sent
+/// to the endpoints they were: how many endpoints were candidates, how each
+/// filter narrowed them down, and which endpoints were chosen in the end.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DecisionLog {
+    /// The fraction of received packets whose decisions are recorded,
+    /// between 0 and 1.
+    #[serde(default = "default_decision_log_sample_rate")]
+    pub sample_rate: f64,
+    /// Whether decisions are logged.
+    #[serde(default = "default_decision_log_log")]
+    pub log: bool,
+    /// The number of the latest decisions served by the admin server.
+    #[serde(default = "default_decision_log_max_decisions")]
+    pub max_decisions: usize,
+}
+
+fn default_decision_log_sample_rate() -> f64 {
+    0.001
+}
+
+fn default_decision_log_log() -> bool {
+    true
+}
+
+fn default_decision_log_max_decisions() -> usize {
+    100
+}
+
+/// Configures reporting a summary of the proxy's metrics to the management
+/// server it's connected to, over its xDS connection. Counters are reported
+/// as their increase since the previous report, and histograms as the count,
+/// sum and quantiles of the values recorded since then, so that a collector
+/// can add up the reports of many proxies.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Telemetry {
+    /// How often a report is sent.
+    #[serde(with = "humantime_serde", default = "default_telemetry_interval")]
+    pub interval: Duration,
+    /// If not empty, only the metric families whose name matches one of
+    /// these patterns are reported. Patterns match names as those of
+    /// [`Metrics::allow`] do, after the families have been renamed.
+    #[serde(default)]
+    pub metrics: Vec<String>,
+}
+
+fn default_telemetry_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Configures the checks that the packet creating a session must pass, so
+/// that obviously bogus traffic is rejected before any sockets or state are
+/// allocated for it. Packets from clients that already have a session aren't
+/// checked.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FirstPacket {
+    /// The bytes that the packet must start with, such as the magic bytes
+    /// of the game's protocol. Any are accepted if empty.
+    #[serde(with = "Base64Standard", default)]
+    pub prefix: Vec<u8>,
+    /// The minimum size of the packet in bytes.
+    #[serde(default)]
+    pub min_size: usize,
+}
+
+impl FirstPacket {
+    /// Returns whether `packet` passes the checks.
+    pub fn accepts(&self, packet: &[u8]) -> bool {
+        packet.len() >= self.min_size && packet.starts_with(&self.prefix)
     }
+}
 
-    pub fn with_metadata(address: SocketAddr, metadata: Option<serde_yaml::Value>) -> Self {
-        EndPoint { address, metadata }
+/// Configures reading the version (or build id) of a client's build from the
+/// packet creating a session, so that network issues can be correlated with
+/// specific client builds. The version is added to the session's logs, and
+/// sessions are counted by version in the `client_version` label of the
+/// `quilkin_proxy_client_version_sessions_total` metric.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClientVersion {
+    /// The offset in bytes of the version from the start of the packet.
+    #[serde(default)]
+    pub offset: usize,
+    /// The length in bytes of the version.
+    pub length: usize,
+    /// How the version's bytes are turned into text.
+    #[serde(default)]
+    pub encoding: ClientVersionEncoding,
+    /// The maximum number of distinct versions that are counted under their
+    /// own label. Sessions of any further versions are counted as `other`,
+    /// so that clients can't grow the number of metrics without bound.
+    #[serde(default = "default_client_version_max_versions")]
+    pub max_versions: usize,
+}
+
+impl ClientVersion {
+    /// Returns the version in `packet`, or `None` if the packet is too short
+    /// to hold it, or it isn't printable text when encoded as text.
+    pub fn parse(&self, packet: &[u8]) -> Option<String> {
+        let end = self.offset.checked_add(self.length)?;
+        let bytes = packet.get(self.offset..end)?;
+        match self.encoding {
+            ClientVersionEncoding::Text => {
+                // Versions shorter than `length` are padded with NUL bytes.
+                let len = bytes.iter().rposition(|byte| *byte != 0)? + 1;
+                let bytes = &bytes[..len];
+                if !bytes.iter().all(u8::is_ascii_graphic) {
+                    return None;
+                }
+                String::from_utf8(bytes.to_vec()).ok()
+            }
+            ClientVersionEncoding::Hex => {
+                Some(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+            }
+        }
     }
 }
 
-impl Config {
-    /// from_reader returns a config from a given Reader
-    pub fn from_reader<R: io::Read>(input: R) -> Result<Config, serde_yaml::Error> {
-        serde_yaml::from_reader(input)
+fn default_client_version_max_versions() -> usize {
+    50
+}
+
+/// How the bytes of a client's version are turned into text.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum ClientVersionEncoding {
+    /// The bytes are printable ASCII text, such as `1.4.2`.
+    #[serde(rename = "TEXT")]
+    Text,
+    /// The bytes are binary, such as a build number, and are hex encoded.
+    #[serde(rename = "HEX")]
+    Hex,
+}
+
+impl Default for ClientVersionEncoding {
+    fn default() -> Self {
+        ClientVersionEncoding::Text
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use serde_yaml::Value;
+/// Configures the sockets that sessions send packets to endpoints from.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamSocket {
+    /// The IP time to live (hop limit) of packets sent to endpoints. If not
+    /// set, the system default is used.
+    #[serde(default)]
+    pub ttl: Option<u32>,
+    /// Whether packets sent to endpoints have the don't fragment bit set. If
+    /// not set, the system default is used. Only supported on Linux.
+    #[serde(default)]
+    pub dont_fragment: Option<bool>,
+}
+
+/// Configures the pool of threads that filter chains containing heavy
+/// filters (e.g filters doing expensive cryptography) run on, so that they
+/// don't delay packets going through other filter chains.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ComputePool {
+    /// The names of the filters that are heavy.
+    pub heavy_filters: Vec<String>,
+    /// The number of threads in the pool.
+    #[serde(default = "default_compute_pool_threads")]
+    pub threads: usize,
+    /// The maximum number of packets waiting for a thread. Once reached,
+    /// packets going through heavy filters are dropped.
+    #[serde(default = "default_compute_pool_queue_size")]
+    pub queue_size: usize,
+}
+
+fn default_compute_pool_threads() -> usize {
+    num_cpus::get()
+}
+
+fn default_compute_pool_queue_size() -> usize {
+    1024
+}
+
+/// Configures the queue that received packets wait in for a worker, which
+/// is drained by taking a packet from each client with queued packets in
+/// turn.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FairQueue {
+    /// The maximum number of packets in the queue. Once reached, a packet
+    /// from the client with the most queued packets is dropped to make room.
+    #[serde(default = "default_fair_queue_size")]
+    pub queue_size: usize,
+}
+
+fn default_fair_queue_size() -> usize {
+    1024
+}
+
+/// Soft limits on the proxy's own use of resources. Sessions are shed while
+/// a limit is exceeded, so that the proxy frees resources before the
+/// operating system kills it, or it can't open sockets for new sessions.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// The resident memory, in bytes, past which sessions are shed.
+    #[serde(default)]
+    pub max_memory_bytes: Option<u64>,
+    /// The number of open file descriptors past which sessions are shed.
+    #[serde(default)]
+    pub max_open_fds: Option<u64>,
+    /// How often the proxy's resource usage is checked against the limits.
+    #[serde(with = "humantime_serde", default = "default_resource_check_interval")]
+    pub check_interval: Duration,
+}
+
+pub(crate) fn default_resource_check_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Passive health checking of endpoints, based on the traffic that sessions
+/// send to and receive from them. Unhealthy endpoints are excluded from the
+/// endpoints that packets are sent to until a cooldown has passed.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointHealthCheck {
+    /// The number of consecutive failures to send a packet to an endpoint
+    /// after which it is marked unhealthy.
+    #[serde(default = "default_max_send_failures")]
+    pub max_send_failures: u32,
+    /// If set, an endpoint is marked unhealthy if it doesn't send a packet
+    /// within this long of a packet being sent to it.
+    #[serde(default, with = "humantime_serde")]
+    pub response_timeout: Option<Duration>,
+    /// How long an unhealthy endpoint is excluded before it is re-admitted.
+    #[serde(with = "humantime_serde", default = "default_health_check_cooldown")]
+    pub cooldown: Duration,
+}
+
+fn default_max_send_failures() -> u32 {
+    3
+}
+
+fn default_health_check_cooldown() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Slow start of endpoints, so that game servers that were just started
+/// have time to finish loading before they receive a full share of new
+/// sessions.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointSlowStart {
+    /// How long it takes for the share of traffic of an endpoint that was
+    /// just added, or re-admitted, to ramp up to its full share.
+    #[serde(with = "humantime_serde", default = "default_slow_start_window")]
+    pub window: Duration,
+}
+
+fn default_slow_start_window() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Faults injected into the proxy, to test how it recovers from them, e.g
+/// by retrying, failing over or draining. Only available in debug builds,
+/// or builds with the `testing` feature.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Faults {
+    /// The fraction of packets, from 0 to 1, that fail to be sent to
+    /// endpoints and clients, as if their socket returned an error.
+    #[serde(default)]
+    pub send_failure_rate: f64,
+    /// If set, the connection to the management server is dropped this long
+    /// after it is established, every time it is.
+    #[serde(default, with = "humantime_serde")]
+    pub xds_disconnect_after: Option<Duration>,
+    /// If set, the filter chain takes this much longer to process each
+    /// packet, as if one of its filters was slow.
+    #[serde(default, with = "humantime_serde")]
+    pub filter_delay: Option<Duration>,
+}
+
+/// Limits how long a filter can take to process a packet, so that a filter
+/// that is stuck (e.g waiting on an external service) doesn't stall every
+/// packet behind it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilterTimeout {
+    /// The name of the filter.
+    pub filter: String,
+    /// How long the filter can take to process a packet.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// What happens to a packet that the filter doesn't process in time.
+    #[serde(default)]
+    pub on_timeout: FilterTimeoutPolicy,
+}
+
+/// Determines what happens to a packet that a filter doesn't process within
+/// its timeout.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum FilterTimeoutPolicy {
+    /// Drop the packet.
+    #[serde(rename = "DROP")]
+    Drop,
+    /// Pass the packet on to the next filter as if the filter hadn't
+    /// changed it.
+    #[serde(rename = "PASS_THROUGH")]
+    PassThrough,
+}
+
+impl Default for FilterTimeoutPolicy {
+    fn default() -> Self {
+        FilterTimeoutPolicy::Drop
+    }
+}
+
+/// Limits the processing time and memory that a filter can use, so that a
+/// filter that misbehaves, e.g by tracking too many clients, degrades on its
+/// own rather than taking the proxy down with it. A filter with a budget
+/// also reports how much of it the filter uses.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilterBudget {
+    /// The name of the filter.
+    pub filter: String,
+    /// If set, how long the filter can spend processing packets in each
+    /// `period`.
+    #[serde(default, with = "humantime_serde")]
+    pub cpu_time: Option<Duration>,
+    /// If set, how many bytes of state the filter can hold. Only filters
+    /// that report their memory usage are limited.
+    #[serde(default)]
+    pub max_memory_bytes: Option<usize>,
+    /// How often the filter's budget is renewed and its memory usage
+    /// checked.
+    #[serde(default = "default_filter_budget_period", with = "humantime_serde")]
+    pub period: Duration,
+    /// What happens to packets while the filter is over its budget.
+    #[serde(default)]
+    pub on_exceeded: FilterBudgetPolicy,
+}
+
+fn default_filter_budget_period() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// Determines what happens to packets while a filter is over its budget.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum FilterBudgetPolicy {
+    /// Drop the packets.
+    #[serde(rename = "DROP")]
+    Drop,
+    /// Pass the packets on to the next filter without processing them.
+    #[serde(rename = "PASS_THROUGH")]
+    PassThrough,
+}
+
+impl Default for FilterBudgetPolicy {
+    fn default() -> Self {
+        FilterBudgetPolicy::Drop
+    }
+}
+
+/// Caching of the endpoints that routing filters route each client to, so
+/// that a client's packets only go through those filters when the endpoints
+/// they're given change, rather than for every packet.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingCache {
+    /// The names of the filters whose routing is cached. Each must only
+    /// route packets, and route every packet from a client to the same
+    /// endpoints for as long as it's given the same endpoints.
+    pub filters: Vec<String>,
+    /// How long a client's route is cached for, which bounds how long a
+    /// client keeps being routed the same way if it should be routed
+    /// differently, e.g. because it sends a different token.
+    #[serde(with = "humantime_serde", default = "default_routing_cache_ttl")]
+    pub ttl: Duration,
+    /// The maximum number of clients whose routes each filter caches.
+    #[serde(default = "default_routing_cache_max_clients")]
+    pub max_clients: usize,
+}
+
+fn default_routing_cache_ttl() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_routing_cache_max_clients() -> usize {
+    10_000
+}
+
+/// Clients that are trusted, such as other game servers within the same
+/// network, whose sessions skip filters that only guard against untrusted
+/// players (e.g. rate limiting or decryption), so that they don't pay the
+/// full cost of each packet.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TrustedClients {
+    /// The ranges of the addresses of trusted clients.
+    pub addresses: Vec<Cidr>,
+    /// The names of the filters that the packets read from and written to
+    /// trusted clients skip.
+    pub filters: Vec<String>,
+}
+
+/// Configures the metrics that the proxy reports.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Metrics {
+    #[serde(default)]
+    pub histogram_buckets: HistogramBuckets,
+    /// If not empty, only the metric families whose name matches one of
+    /// these patterns are exposed. A pattern ending in `*` matches any name
+    /// starting with the rest of the pattern.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// The metric families whose name matches one of these patterns aren't
+    /// exposed, even if allowed.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Renames metric families before they are exposed. The first rule
+    /// whose prefix a family's name starts with applies.
+    #[serde(default)]
+    pub relabel: Vec<MetricRelabel>,
+}
+
+/// Renames the metric families whose name starts with `prefix`, by
+/// replacing the prefix with `replacement`, e.g to match the names that
+/// existing dashboards expect.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricRelabel {
+    pub prefix: String,
+    pub replacement: String,
+}
+
+/// Overrides the bucket boundaries of histograms, by the kind of value they
+/// record. Histograms of a kind that isn't set keep their default buckets.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HistogramBuckets {
+    /// The buckets of histograms of how long packets take to process, in
+    /// seconds.
+    #[serde(default)]
+    pub latency: Option<Vec<f64>>,
+    /// The buckets of histograms of packet sizes, in bytes.
+    #[serde(default)]
+    pub packet_size: Option<Vec<f64>>,
+    /// The buckets of histograms of compression ratios, the size of a
+    /// compressed packet divided by its original size.
+    #[serde(default)]
+    pub compression_ratio: Option<Vec<f64>>,
+}
+
+/// Configures pushing metrics to a Prometheus push gateway, for proxies that
+/// can't be scraped, e.g because they are short lived or behind a NAT.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsPush {
+    /// The URL of the push gateway, e.g `http://pushgateway:9091`.
+    pub url: String,
+    /// How often metrics are pushed.
+    #[serde(with = "humantime_serde", default = "default_metrics_push_interval")]
+    pub interval: Duration,
+    /// The job that metrics are grouped under. They are also grouped by the
+    /// proxy's id as the instance.
+    #[serde(default = "default_metrics_push_job")]
+    pub job: String,
+}
+
+fn default_metrics_push_interval() -> Duration {
+    Duration::from_secs(15)
+}
+
+fn default_metrics_push_job() -> String {
+    "quilkin".into()
+}
+
+/// Configures where audit records are written. Records are written to every
+/// destination that is set.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuditLog {
+    /// The path of a file that records are appended to, one JSON object per
+    /// line.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    /// If set, records are sent to syslog.
+    #[serde(default)]
+    pub syslog: Option<Syslog>,
+}
+
+/// Configures sending audit records to the local syslog daemon.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Syslog {
+    /// The path of the unix domain socket that the syslog daemon listens on.
+    #[serde(default = "default_syslog_socket")]
+    pub socket: PathBuf,
+}
+
+fn default_syslog_socket() -> PathBuf {
+    "/dev/log".into()
+}
+
+/// Configures the peer proxy that sessions tunnel packets to over TCP,
+/// optionally secured with TLS, for clients on networks that block UDP. The
+/// peer forwards the packets to endpoints over UDP.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelPeer {
+    /// The address of the peer's tunnel listener, e.g `relay.example.com:443`.
+    pub address: String,
+    /// If set, tunnels are secured with TLS.
+    #[serde(default)]
+    pub tls: Option<TunnelPeerTls>,
+    /// How long to wait for a tunnel to be established before the session
+    /// is torn down.
+    #[serde(with = "humantime_serde", default = "default_tunnel_connect_timeout")]
+    pub connect_timeout: Duration,
+}
+
+fn default_tunnel_connect_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Configures how the peer proxy's certificate is verified.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelPeerTls {
+    /// The DNS name that the peer's certificate must be valid for. Defaults
+    /// to the host of the peer's address.
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// The path of a PEM file containing the certificate authorities that
+    /// the peer's certificate must be issued by. If not set, the Mozilla
+    /// root certificates are used.
+    #[serde(default)]
+    pub ca_certificate: Option<PathBuf>,
+}
+
+/// Configures accepting tunnels from peer proxies.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelListener {
+    /// The TCP port that tunnels are accepted on.
+    pub port: u16,
+    /// If set, tunnels must be secured with TLS.
+    #[serde(default)]
+    pub tls: Option<ListenerTls>,
+}
+
+/// Configures accepting UDP proxying requests, as specified by RFC 9298, so
+/// that clients behind an HTTP proxy or using a platform's MASQUE support
+/// can reach the proxy without a custom SDK.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectUdp {
+    /// The TCP port that requests are accepted on.
+    pub port: u16,
+    /// If set, connections must be secured with TLS.
+    #[serde(default)]
+    pub tls: Option<ListenerTls>,
+}
+
+/// Configures accepting UDP associations (the UDP ASSOCIATE command of
+/// SOCKS5, as specified by RFC 1928), so that tooling and network stacks
+/// that send UDP through a SOCKS5 proxy can reach the proxy.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Socks5 {
+    /// The TCP port that SOCKS5 connections are accepted on.
+    pub port: u16,
+    /// The maximum number of connections open at once, including those
+    /// still negotiating an association. Connections beyond it are closed
+    /// straight away.
+    #[serde(default = "default_socks5_max_associations")]
+    pub max_associations: usize,
+}
+
+fn default_socks5_max_associations() -> usize {
+    1024
+}
+
+/// Configures the certificate that the proxy secures the connections it
+/// accepts with.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerTls {
+    /// The path of a PEM file containing the certificate chain.
+    pub certificate: PathBuf,
+    /// The path of a PEM file containing the certificate's private key.
+    pub private_key: PathBuf,
+}
+
+/// Determines whether a session is admitted if the connection tracker
+/// cannot be reached or does not respond in time.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum FailurePolicy {
+    /// Admit the session.
+    #[serde(rename = "OPEN")]
+    Open,
+    /// Reject the session.
+    #[serde(rename = "CLOSED")]
+    Closed,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        FailurePolicy::Open
+    }
+}
+
+/// Determines how the proxy handles a packet that exceeds the maximum
+/// packet size after being processed by the filter chain.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum OversizedPacketPolicy {
+    /// Drop the packet.
+    #[serde(rename = "DROP")]
+    Drop,
+    /// Truncate the packet to the maximum packet size.
+    #[serde(rename = "TRUNCATE")]
+    Truncate,
+    /// Send the packet regardless of its size.
+    #[serde(rename = "SEND")]
+    Send,
+}
+
+impl Default for OversizedPacketPolicy {
+    fn default() -> Self {
+        OversizedPacketPolicy::Drop
+    }
+}
+
+/// Determines what the proxy does if another proxy is already listening on
+/// its port when it starts.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum PortConflictPolicy {
+    /// Fail to start.
+    #[serde(rename = "FAIL")]
+    Fail,
+    /// Listen on the port alongside the other proxy using `SO_REUSEPORT`,
+    /// then ask it to stop, so that the port's traffic moves over without
+    /// packets being dropped. The other proxy must use this policy as well.
+    #[serde(rename = "TAKE_OVER")]
+    TakeOver,
+}
+
+impl Default for PortConflictPolicy {
+    fn default() -> Self {
+        PortConflictPolicy::Fail
+    }
+}
+
+fn default_proxy_id() -> String {
+    Uuid::new_v4().to_hyphenated().to_string()
+}
+
+fn default_proxy_port() -> u16 {
+    7000
+}
+
+/// The largest UDP payload that can be sent over IPv4.
+pub(crate) const DEFAULT_MAX_PACKET_SIZE: usize = 65507;
+
+/// The largest size of a UDP datagram, as limited by its 16 bit length field.
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65535;
+
+fn default_max_packet_size() -> usize {
+    DEFAULT_MAX_PACKET_SIZE
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Proxy {
+            id: default_proxy_id(),
+            port: default_proxy_port(),
+            port_conflict_policy: PortConflictPolicy::default(),
+            port_conflict_secret: vec![],
+            max_packet_size: default_max_packet_size(),
+            oversized_packet_policy: OversizedPacketPolicy::default(),
+            failover_buffer: None,
+            connection_tracker: None,
+            handshake: None,
+            compute_pool: None,
+            upstream_socket: UpstreamSocket::default(),
+            packet_deadline: None,
+            fair_queue: None,
+            first_packet: None,
+            client_version: None,
+            first_response_timeout: None,
+            filter_timeouts: vec![],
+            filter_budgets: vec![],
+            routing_cache: None,
+            trusted_clients: None,
+            metrics: Metrics::default(),
+            metrics_push: None,
+            tunnel_peer: None,
+            tunnel_listener: None,
+            connect_udp: None,
+            socks5: None,
+            audit_log: None,
+            response_only_endpoints: vec![],
+            schedule: Schedule::default(),
+            resource_limits: None,
+            endpoint_health_check: None,
+            endpoint_slow_start: None,
+            faults: None,
+            session_key: None,
+            connection_id: None,
+            ice: None,
+            ban_gossip: None,
+            relay: None,
+            ordered_sends: None,
+            analyzer: None,
+            standby: None,
+            burst_detection: None,
+            decision_log: None,
+            telemetry: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Admin {
+    /// The TCP address to serve the admin interface on, if any.
+    #[serde(default)]
+    pub address: Option<SocketAddr>,
+    /// The path of a local control socket to serve the admin interface on,
+    /// if any. This is a unix domain socket, or a named pipe on Windows.
+    #[serde(default)]
+    pub local_socket: Option<PathBuf>,
+    /// The TCP address to serve the tap gRPC service on, which streams
+    /// copies of the packets passing through the proxy, if any.
+    #[serde(default)]
+    pub tap_address: Option<SocketAddr>,
+    /// The TCP address to serve the session stats gRPC service on, which
+    /// streams summaries of the rates and sizes of the packets of each
+    /// session, if any.
+    #[serde(default)]
+    pub session_stats_address: Option<SocketAddr>,
+    /// Whether the endpoints that change the proxy's state or export it,
+    /// such as `POST /bans` or `GET /state`, are served.
+    #[serde(default)]
+    pub privileged_endpoints: bool,
+    /// If set, the bearer token that requests to privileged endpoints must
+    /// send over TCP. Without one, they are only served on `local_socket`.
+    #[serde(default)]
+    pub token: Option<SecretRef>,
+}
+
+impl Default for Admin {
+    fn default() -> Self {
+        Admin {
+            address: Some("[::]:9091".parse().unwrap()),
+            local_socket: None,
+            tap_address: None,
+            session_stats_address: None,
+            privileged_endpoints: false,
+            token: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ManagementServer {
+    pub address: String,
+    /// If set, the bearer token that the proxy authenticates to the server
+    /// with, sent in the `authorization` metadata of its requests.
+    #[serde(default)]
+    pub token: Option<SecretRef>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Source {
+    #[serde(rename = "static")]
+    Static {
+        #[serde(default)]
+        filters: Vec<Filter>,
+
+        #[serde(default)]
+        endpoints: Vec<EndPoint>,
+
+        mdns: Option<Mdns>,
+
+        /// If set, the filter chains of the experiment's variants are used
+        /// instead of `filters`.
+        experiment: Option<Experiment>,
+    },
+    #[serde(rename = "dynamic")]
+    Dynamic {
+        management_servers: Vec<ManagementServer>,
+
+        #[serde(default)]
+        startup: Startup,
+
+        failover: Option<Failover>,
+
+        endpoint_update_guard: Option<EndpointUpdateGuard>,
+
+        #[serde(default)]
+        filter_update_failure: FilterUpdateFailurePolicy,
+    },
+}
+
+/// Determines how a proxy with a dynamic source starts up if it cannot
+/// receive its initial configuration from a management server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Startup {
+    #[serde(default)]
+    pub policy: StartupPolicy,
+    /// How long to wait for the initial configuration before applying the
+    /// policy. Ignored by [`StartupPolicy::Wait`].
+    #[serde(with = "humantime_serde", default = "default_startup_timeout")]
+    pub timeout: Duration,
+    /// The filters and endpoints to serve with under [`StartupPolicy::Fallback`].
+    pub fallback: Option<Fallback>,
+}
+
+/// What a proxy does when it cannot receive its initial configuration.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum StartupPolicy {
+    /// Wait until the initial configuration is received before serving traffic.
+    #[serde(rename = "WAIT")]
+    Wait,
+    /// Serve traffic using the fallback configuration until the initial
+    /// configuration is received.
+    #[serde(rename = "FALLBACK")]
+    Fallback,
+    /// Exit with an error.
+    #[serde(rename = "EXIT")]
+    Exit,
+}
+
+impl Default for StartupPolicy {
+    fn default() -> Self {
+        StartupPolicy::Wait
+    }
+}
+
+/// A static configuration used until a dynamic configuration is received.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fallback {
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+
+    pub endpoints: Vec<EndPoint>,
+}
+
+fn default_startup_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        Startup {
+            policy: StartupPolicy::default(),
+            timeout: default_startup_timeout(),
+            fallback: None,
+        }
+    }
+}
+
+/// A remote cluster that a proxy with a dynamic source sends traffic to
+/// while its other clusters have no healthy endpoints.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Failover {
+    /// The name of the cluster to fail over to. Its endpoints only receive
+    /// traffic while failed over.
+    pub cluster: String,
+    /// How long the other clusters must have healthy endpoints again before
+    /// traffic is sent back to them.
+    #[serde(with = "humantime_serde", default = "default_failback_delay")]
+    pub failback_delay: Duration,
+}
+
+fn default_failback_delay() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Guards a proxy with a dynamic source against endpoint updates that would
+/// remove too many of its current endpoints at once, such as a management
+/// server briefly publishing an empty set.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointUpdateGuard {
+    /// The percentage of the current endpoints that an update may remove
+    /// before the policy is applied.
+    pub max_removal_percent: f64,
+    #[serde(default)]
+    pub policy: EndpointUpdateGuardPolicy,
+    /// How often another step of the removals is applied under
+    /// [`EndpointUpdateGuardPolicy::PhaseIn`].
+    #[serde(with = "humantime_serde", default = "default_phase_in_interval")]
+    pub phase_in_interval: Duration,
+}
+
+/// What a proxy does with an endpoint update that removes too many endpoints.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum EndpointUpdateGuardPolicy {
+    /// Keep the current endpoints, ignoring the update's removals until the
+    /// next update.
+    #[serde(rename = "REJECT")]
+    Reject,
+    /// Apply the update, but only remove up to the maximum percentage of
+    /// endpoints every phase in interval.
+    #[serde(rename = "PHASE_IN")]
+    PhaseIn,
+}
+
+impl Default for EndpointUpdateGuardPolicy {
+    fn default() -> Self {
+        EndpointUpdateGuardPolicy::Reject
+    }
+}
+
+fn default_phase_in_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// What a proxy with a dynamic source does with a filter chain update in
+/// which a filter fails to be created, once it has applied a filter chain
+/// from the management server. Until then, such updates are always rejected,
+/// leaving the [`StartupPolicy`] to decide how the proxy starts.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum FilterUpdateFailurePolicy {
+    /// Reject the update, keeping the current filter chain.
+    #[serde(rename = "REJECT")]
+    Reject,
+    /// Apply the update without the filters that failed to be created, so
+    /// that packets bypass them.
+    #[serde(rename = "BYPASS")]
+    Bypass,
+}
+
+impl Default for FilterUpdateFailurePolicy {
+    fn default() -> Self {
+        FilterUpdateFailurePolicy::Reject
+    }
+}
+
+/// Discovers endpoints for a proxy with a static source by browsing for
+/// instances of a service advertised with mDNS (DNS-SD), in addition to its
+/// static endpoints. Meant for local development and LAN setups.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Mdns {
+    /// The DNS-SD service type that game servers advertise, e.g `_game._udp`.
+    pub service_type: String,
+    /// How often to query for instances of the service.
+    #[serde(with = "humantime_serde", default = "default_mdns_query_interval")]
+    pub query_interval: Duration,
+}
+
+fn default_mdns_query_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// An A/B experiment between two filter chains, e.g to measure the impact of
+/// adding a filter before rolling it out. Clients are split between the
+/// variants by a hash of their address, so that all of the packets of a
+/// session, in both directions, pass through the same variant's chain.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Experiment {
+    pub variants: Vec<ExperimentVariant>,
+}
+
+/// A variant of an [`Experiment`]. The metrics of its filter chain, and of
+/// the filters in it, are labelled with its name.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExperimentVariant {
+    pub name: String,
+    /// The share of clients in the variant, relative to the weights of the
+    /// other variants.
+    #[serde(default = "default_variant_weight")]
+    pub weight: u32,
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+}
+
+fn default_variant_weight() -> u32 {
+    1
+}
+
+/// Config is the configuration of a proxy
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub version: Version,
+
+    #[serde(default)]
+    pub proxy: Proxy,
+
+    #[serde(default)]
+    pub admin: Admin,
+
+    #[serde(flatten)]
+    pub source: Source,
+
+    // Limit struct creation to the builder. We use an Optional<Phantom>
+    // so that we can create instances though deserialization.
+    #[serde(skip_serializing)]
+    pub(super) phantom: Option<PhantomData<()>>,
+}
+
+impl Source {
+    /// Returns the list of filters if the config is a static config and None otherwise.
+    /// This is a convenience function and should only be used for doc tests and tests.
+    pub fn get_static_filters(&self) -> Option<&[Filter]> {
+        match self {
+            Source::Static { filters, .. } => Some(filters),
+            Source::Dynamic { .. } => None,
+        }
+    }
+}
+
+/// Filter is the configuration for a single filter
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    pub name: String,
+    pub config: Option<serde_yaml::Value>,
+}
+
+/// A singular endpoint, to pass on UDP packets to.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EndPoint {
+    pub address: SocketAddr,
+    pub metadata: Option<serde_yaml::Value>,
+    /// The endpoint's share of traffic relative to other endpoints, when
+    /// load balancing by weight. Defaults to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+}
+
+impl EndPoint {
+    pub fn new(address: SocketAddr) -> Self {
+        EndPoint::with_metadata(address, None)
+    }
+
+    pub fn with_metadata(address: SocketAddr, metadata: Option<serde_yaml::Value>) -> Self {
+        EndPoint {
+            address,
+            metadata,
+            weight: None,
+        }
+    }
+}
+
+impl Config {
+    /// from_reader returns a config from a given Reader
+    pub fn from_reader<R: io::Read>(input: R) -> Result<Config, serde_yaml::Error> {
+        serde_yaml::from_reader(input)
+    }
+
+    /// from_file returns the config in the file at `path`, merged on top of
+    /// the base profiles that it extends. Encrypted files are decrypted with
+    /// the key given by the environment, see [`ConfigKey::from_env`].
+    pub fn from_file(path: &Path) -> Result<Config, ProfileError> {
+        let key = ConfigKey::from_env().map_err(ProfileError::Key)?;
+        Self::resolve(path, key.as_ref())
+    }
+
+    /// from_file_with_key returns the config in the file at `path` like
+    /// [`Config::from_file`], decrypting encrypted files with `key`.
+    pub fn from_file_with_key(path: &Path, key: &ConfigKey) -> Result<Config, ProfileError> {
+        Self::resolve(path, Some(key))
+    }
+
+    fn resolve(path: &Path, key: Option<&ConfigKey>) -> Result<Config, ProfileError> {
+        serde_yaml::from_value(profile::resolve(path, key)?).map_err(|source| ProfileError::Parse {
+            path: path.into(),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use crate::config::{
+        ActivationWindow, Analyzer, AuditLog, BanGossip, Builder, BurstDetection, ClientVersion,
+        ClientVersionEncoding, ComputePool, Config, ConnectUdp, ConnectionId, ConnectionTracker,
+        DecisionLog, EndPoint, EndpointHealthCheck, EndpointSchedule, EndpointSlowStart,
+        EndpointUpdateGuard, EndpointUpdateGuardPolicy, Failover, FailoverBuffer, FailurePolicy,
+        FairQueue, Faults, FilterBudget, FilterBudgetPolicy, FilterSchedule, FilterTimeout,
+        FilterTimeoutPolicy, FilterUpdateFailurePolicy, FirstPacket, Handshake, HistogramBuckets,
+        Ice, ListenerTls, ManagementServer, Mdns, MetricRelabel, Metrics, MetricsPush,
+        OrderedSends, OversizedPacketPolicy, PortConflictPolicy, Relay, ResourceLimits,
+        RoutingCache, Schedule, SessionKeyKind, SessionKeySource, Socks5, Source, Standby,
+        StartupPolicy, Syslog, Telemetry, TimeOfDay, TrustedClients, TunnelListener, TunnelPeer,
+        TunnelPeerTls, UpstreamSocket,
+    };
+    use crate::secret::SecretRef;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn parse_config(yaml: &str) -> Config {
+        Config::from_reader(yaml.as_bytes()).unwrap()
+    }
+
+    fn assert_static_endpoints(source: &Source, expected_endpoints: Vec<EndPoint>) {
+        match source {
+            Source::Static { endpoints, .. } => {
+                assert_eq!(&expected_endpoints, endpoints,);
+            }
+            _ => unreachable!("expected static config source"),
+        }
+    }
+
+    fn assert_management_servers(source: &Source, expected: Vec<ManagementServer>) {
+        match source {
+            Source::Dynamic {
+                management_servers, ..
+            } => {
+                assert_eq!(&expected, management_servers,);
+            }
+            _ => unreachable!("expected dynamic config source"),
+        }
+    }
+
+    #[test]
+    fn deserialise_client() {
+        let config = Builder::empty()
+            .with_port(7000)
+            .with_static(
+                vec![],
+                vec![EndPoint::new("127.0.0.1:25999".parse().unwrap())],
+            )
+            .build();
+        let _ = serde_yaml::to_string(&config).unwrap();
+    }
+
+    #[test]
+    fn deserialise_server() {
+        let config = Builder::empty()
+            .with_port(7000)
+            .with_static(
+                vec![],
+                vec![
+                    EndPoint::new("127.0.0.1:26000".parse().unwrap()),
+                    EndPoint::new("127.0.0.1:26001".parse().unwrap()),
+                ],
+            )
+            .build();
+        let _ = serde_yaml::to_string(&config).unwrap();
+    }
+
+    #[test]
+    fn parse_default_values() {
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(config.proxy.port, 7000);
+        assert_eq!(config.proxy.id.len(), 36);
+        assert_eq!(config.proxy.max_packet_size, 65507);
+        assert_eq!(
+            config.proxy.oversized_packet_policy,
+            OversizedPacketPolicy::Drop
+        );
+        assert_eq!(config.proxy.port_conflict_policy, PortConflictPolicy::Fail);
+        assert_eq!(config.proxy.failover_buffer, None);
+        assert_eq!(config.proxy.connection_tracker, None);
+        assert_eq!(config.proxy.first_response_timeout, None);
+    }
+
+    #[test]
+    fn parse_oversized_packet_policy() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  max_packet_size: 1200
+  oversized_packet_policy: TRUNCATE
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(config.proxy.max_packet_size, 1200);
+        assert_eq!(
+            config.proxy.oversized_packet_policy,
+            OversizedPacketPolicy::Truncate
+        );
+    }
+
+    #[test]
+    fn parse_port_conflict_policy() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  port_conflict_policy: TAKE_OVER
+  port_conflict_secret: c2VjcmV0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.port_conflict_policy,
+            PortConflictPolicy::TakeOver
+        );
+        assert_eq!(config.proxy.port_conflict_secret, b"secret".to_vec());
+    }
+
+    #[test]
+    fn parse_failover_buffer() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  failover_buffer:
+    max_delay: 250ms
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.failover_buffer,
+            Some(FailoverBuffer {
+                max_delay: Duration::from_millis(250),
+                max_packets: 32,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_connection_tracker() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  connection_tracker:
+    address: http://127.0.0.1:9000
+    failure_policy: CLOSED
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.connection_tracker,
+            Some(ConnectionTracker {
+                address: "http://127.0.0.1:9000".into(),
+                metadata_key: "quilkin.dev/captured_bytes".into(),
+                timeout: Duration::from_millis(100),
+                cache_ttl: Duration::from_secs(60),
+                failure_policy: FailurePolicy::Closed,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_filter_timeouts() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  filter_timeouts:
+    - filter: quilkin.extensions.filters.debug.v1beta1.Debug
+      timeout: 2ms
+    - filter: quilkin.extensions.filters.compress.v1beta1.Compress
+      timeout: 1s
+      on_timeout: PASS_THROUGH
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.filter_timeouts,
+            vec![
+                FilterTimeout {
+                    filter: "quilkin.extensions.filters.debug.v1beta1.Debug".into(),
+                    timeout: Duration::from_millis(2),
+                    on_timeout: FilterTimeoutPolicy::Drop,
+                },
+                FilterTimeout {
+                    filter: "quilkin.extensions.filters.compress.v1beta1.Compress".into(),
+                    timeout: Duration::from_secs(1),
+                    on_timeout: FilterTimeoutPolicy::PassThrough,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_filter_budgets() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  filter_budgets:
+    - filter: quilkin.extensions.filters.debug.v1beta1.Debug
+    - filter: quilkin.extensions.filters.handoff.v1beta1.Handoff
+      cpu_time: 100ms
+      max_memory_bytes: 1048576
+      period: 10s
+      on_exceeded: PASS_THROUGH
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.filter_budgets,
+            vec![
+                FilterBudget {
+                    filter: "quilkin.extensions.filters.debug.v1beta1.Debug".into(),
+                    cpu_time: None,
+                    max_memory_bytes: None,
+                    period: Duration::from_secs(1),
+                    on_exceeded: FilterBudgetPolicy::Drop,
+                },
+                FilterBudget {
+                    filter: "quilkin.extensions.filters.handoff.v1beta1.Handoff".into(),
+                    cpu_time: Some(Duration::from_millis(100)),
+                    max_memory_bytes: Some(1048576),
+                    period: Duration::from_secs(10),
+                    on_exceeded: FilterBudgetPolicy::PassThrough,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_routing_cache() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  routing_cache:
+    filters:
+      - quilkin.extensions.filters.token_router.v1beta1.TokenRouter
+    ttl: 30s
+    max_clients: 100
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.routing_cache,
+            Some(RoutingCache {
+                filters: vec![
+                    "quilkin.extensions.filters.token_router.v1beta1.TokenRouter".into()
+                ],
+                ttl: Duration::from_secs(30),
+                max_clients: 100,
+            })
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  routing_cache:
+    filters:
+      - quilkin.extensions.filters.token_router.v1beta1.TokenRouter
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        let routing_cache = config.proxy.routing_cache.unwrap();
+        assert_eq!(routing_cache.ttl, Duration::from_secs(10));
+        assert_eq!(routing_cache.max_clients, 10_000);
+    }
+
+    #[test]
+    fn parse_trusted_clients() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  trusted_clients:
+    addresses:
+      - 10.0.0.0/8
+      - fd00::/8
+    filters:
+      - quilkin.extensions.filters.local_rate_limit.v1beta1.LocalRateLimit
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.trusted_clients,
+            Some(TrustedClients {
+                addresses: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+                filters: vec![
+                    "quilkin.extensions.filters.local_rate_limit.v1beta1.LocalRateLimit".into()
+                ],
+            })
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  trusted_clients:
+    addresses:
+      - 10.0.0.0/33
+    filters: []
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        assert!(Config::from_reader(yaml.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parse_metrics() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  metrics:
+    histogram_buckets:
+      latency: [0.00001, 0.0001, 0.001]
+      packet_size: [64, 512, 1500]
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.metrics,
+            Metrics {
+                histogram_buckets: HistogramBuckets {
+                    latency: Some(vec![0.000_01, 0.000_1, 0.001]),
+                    packet_size: Some(vec![64.0, 512.0, 1500.0]),
+                    compression_ratio: None,
+                },
+                ..Metrics::default()
+            }
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  metrics:
+    allow:
+      - quilkin_proxy_*
+    deny:
+      - quilkin_proxy_packets_shed_total
+    relabel:
+      - prefix: quilkin_
+        replacement: mygame_
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.metrics,
+            Metrics {
+                histogram_buckets: HistogramBuckets::default(),
+                allow: vec!["quilkin_proxy_*".into()],
+                deny: vec!["quilkin_proxy_packets_shed_total".into()],
+                relabel: vec![MetricRelabel {
+                    prefix: "quilkin_".into(),
+                    replacement: "mygame_".into(),
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_metrics_push() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  metrics_push:
+    url: http://pushgateway:9091
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.metrics_push,
+            Some(MetricsPush {
+                url: "http://pushgateway:9091".into(),
+                interval: Duration::from_secs(15),
+                job: "quilkin".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_tunnel() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  tunnel_peer:
+    address: relay.example.com:443
+    tls:
+      ca_certificate: /etc/quilkin/ca.pem
+  tunnel_listener:
+    port: 7443
+    tls:
+      certificate: /etc/quilkin/cert.pem
+      private_key: /etc/quilkin/key.pem
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.tunnel_peer,
+            Some(TunnelPeer {
+                address: "relay.example.com:443".into(),
+                tls: Some(TunnelPeerTls {
+                    server_name: None,
+                    ca_certificate: Some("/etc/quilkin/ca.pem".into()),
+                }),
+                connect_timeout: Duration::from_secs(5),
+            })
+        );
+        assert_eq!(
+            config.proxy.tunnel_listener,
+            Some(TunnelListener {
+                port: 7443,
+                tls: Some(ListenerTls {
+                    certificate: "/etc/quilkin/cert.pem".into(),
+                    private_key: "/etc/quilkin/key.pem".into(),
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_connect_udp() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  connect_udp:
+    port: 8443
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.connect_udp,
+            Some(ConnectUdp {
+                port: 8443,
+                tls: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_socks5() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  socks5:
+    port: 1080
+    max_associations: 64
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.socks5,
+            Some(Socks5 {
+                port: 1080,
+                max_associations: 64,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_audit_log() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  audit_log:
+    file: /var/log/quilkin/audit.log
+    syslog: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.audit_log,
+            Some(AuditLog {
+                file: Some("/var/log/quilkin/audit.log".into()),
+                syslog: Some(Syslog {
+                    socket: "/dev/log".into(),
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_response_only_endpoints() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  response_only_endpoints:
+    - address: 127.0.0.1:26000
+      metadata:
+        role: spectator
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        let mut metadata = serde_yaml::Mapping::new();
+        metadata.insert("role".into(), "spectator".into());
+        assert_eq!(
+            config.proxy.response_only_endpoints,
+            vec![EndPoint::with_metadata(
+                "127.0.0.1:26000".parse().unwrap(),
+                Some(Value::Mapping(metadata))
+            )]
+        );
+    }
+
+    #[test]
+    fn parse_schedule() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  schedule:
+    filters:
+      - filter: quilkin.extensions.filters.debug.v1alpha1.Debug
+        active:
+          - start: 02:00
+            end: 03:00
+    endpoints:
+      - addresses: [127.0.0.1:26000, 127.0.0.1:26001]
+        active:
+          - start: 23:00
+            end: 01:00:30
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.schedule,
+            Schedule {
+                filters: vec![FilterSchedule {
+                    filter: "quilkin.extensions.filters.debug.v1alpha1.Debug".into(),
+                    active: vec![ActivationWindow {
+                        start: TimeOfDay::new(2, 0, 0).unwrap(),
+                        end: TimeOfDay::new(3, 0, 0).unwrap(),
+                    }],
+                }],
+                endpoints: vec![EndpointSchedule {
+                    addresses: vec![
+                        "127.0.0.1:26000".parse().unwrap(),
+                        "127.0.0.1:26001".parse().unwrap(),
+                    ],
+                    active: vec![ActivationWindow {
+                        start: TimeOfDay::new(23, 0, 0).unwrap(),
+                        end: TimeOfDay::new(1, 0, 30).unwrap(),
+                    }],
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_resource_limits() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  resource_limits:
+    max_memory_bytes: 1073741824
+    max_open_fds: 60000
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.resource_limits,
+            Some(ResourceLimits {
+                max_memory_bytes: Some(1_073_741_824),
+                max_open_fds: Some(60_000),
+                check_interval: Duration::from_secs(5),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_faults() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  faults:
+    send_failure_rate: 0.5
+    xds_disconnect_after: 10s
+    filter_delay: 5ms
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.faults,
+            Some(Faults {
+                send_failure_rate: 0.5,
+                xds_disconnect_after: Some(Duration::from_secs(10)),
+                filter_delay: Some(Duration::from_millis(5)),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_session_key() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  session_key:
+    kind: CONNECTION_ID
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.session_key,
+            Some(SessionKeySource {
+                kind: SessionKeyKind::ConnectionId,
+                metadata_key: "quilkin.dev/captured_bytes".into(),
+            })
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  session_key:
+    kind: IP_AND_TOKEN
+    metadata_key: myapp.com/token
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.session_key,
+            Some(SessionKeySource {
+                kind: SessionKeyKind::IpAndToken,
+                metadata_key: "myapp.com/token".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_connection_id() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  connection_id:
+    secret: c2VjcmV0
+    prefix: Q0lE
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.connection_id,
+            Some(ConnectionId {
+                secret: b"secret".to_vec(),
+                secret_ref: None,
+                prefix: b"CID".to_vec(),
+            })
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  connection_id: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let config = parse_config(yaml);
+        let connection_id = config.proxy.connection_id.unwrap();
+        assert_eq!(connection_id.secret, Vec::<u8>::new());
+        assert_eq!(connection_id.prefix, b"QUILKIN_CID".to_vec());
+    }
+
+    #[test]
+    fn parse_endpoint_health_check() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  endpoint_health_check:
+    response_timeout: 10s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+      weight: 3
+    - address: 127.0.0.1:26000
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.endpoint_health_check,
+            Some(EndpointHealthCheck {
+                max_send_failures: 3,
+                response_timeout: Some(Duration::from_secs(10)),
+                cooldown: Duration::from_secs(30),
+            })
+        );
+        assert_static_endpoints(
+            &config.source,
+            vec![
+                EndPoint {
+                    weight: Some(3),
+                    ..EndPoint::new("127.0.0.1:25999".parse().unwrap())
+                },
+                EndPoint::new("127.0.0.1:26000".parse().unwrap()),
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_endpoint_slow_start() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  endpoint_slow_start:
+    window: 2m
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.endpoint_slow_start,
+            Some(EndpointSlowStart {
+                window: Duration::from_secs(120),
+            })
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  endpoint_slow_start: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.endpoint_slow_start,
+            Some(EndpointSlowStart {
+                window: Duration::from_secs(60),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_ice() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  ice:
+    ufrag: quilkin
+    candidates:
+      - 203.0.113.1:7000
+      - '[2001:db8::1]:7000'
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.ice,
+            Some(Ice {
+                ufrag: "quilkin".into(),
+                password: "".into(),
+                candidates: vec![
+                    "203.0.113.1:7000".parse().unwrap(),
+                    "[2001:db8::1]:7000".parse().unwrap(),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn parse_ban_gossip() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  ban_gossip:
+    port: 7100
+    peers:
+      - 10.0.0.2:7100
+    secret: c2VjcmV0
+    duration: 5m
+    file: /var/lib/quilkin/bans.json
+    never_ban:
+      - 10.0.0.0/8
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.ban_gossip,
+            Some(BanGossip {
+                port: 7100,
+                peers: vec!["10.0.0.2:7100".parse().unwrap()],
+                secret: b"secret".to_vec(),
+                duration: Duration::from_secs(300),
+                drop_reasons: vec![],
+                min_drops: 10,
+                ban_rejected: false,
+                max_bans: 100_000,
+                file: Some("/var/lib/quilkin/bans.json".into()),
+                sweep_interval: Duration::from_secs(1),
+                never_ban: vec!["10.0.0.0/8".parse().unwrap()],
+            })
+        );
+    }
+
+    #[test]
+    fn parse_relay() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  relay:
+    require_envelope: true
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+      metadata:
+        quilkin.dev:
+          relay:
+            token: YWJj
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.relay,
+            Some(Relay {
+                require_envelope: true,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_ordered_sends() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  ordered_sends: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.ordered_sends,
+            Some(OrderedSends { queue_size: 256 })
+        );
+    }
+
+    #[test]
+    fn parse_analyzer() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  analyzer:
+    top_talkers: 5
+    window: 30s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.analyzer,
+            Some(Analyzer {
+                top_talkers: 5,
+                window: Duration::from_secs(30),
+                max_sources: 10_000,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_standby() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  standby:
+    port: 7200
+    peer: 10.0.0.2:7200
+    secret: c2VjcmV0
+    priority: 200
+    failover_timeout: 300ms
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.standby,
+            Some(Standby {
+                port: 7200,
+                peer: "10.0.0.2:7200".parse().unwrap(),
+                secret: b"secret".to_vec(),
+                priority: 200,
+                preempt: true,
+                heartbeat_interval: Duration::from_millis(100),
+                failover_timeout: Duration::from_millis(300),
+                sync_interval: Duration::from_millis(200),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_burst_detection() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  burst_detection:
+    max_packets: 20
+    webhook: http://alerts:8080/bursts
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.burst_detection,
+            Some(BurstDetection {
+                window: Duration::from_millis(10),
+                max_packets: 20,
+                cooldown: Duration::from_secs(10),
+                log: true,
+                webhook: Some("http://alerts:8080/bursts".into()),
+                max_clients: 100_000,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_decision_log() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  decision_log:
+    sample_rate: 0.5
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.decision_log,
+            Some(DecisionLog {
+                sample_rate: 0.5,
+                log: true,
+                max_decisions: 100,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_telemetry() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  telemetry:
+    metrics:
+      - quilkin_session_*
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.telemetry,
+            Some(Telemetry {
+                interval: Duration::from_secs(60),
+                metrics: vec!["quilkin_session_*".into()],
+            })
+        );
+    }
+
+    #[test]
+    fn parse_compute_pool() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  compute_pool:
+    heavy_filters:
+      - quilkin.extensions.filters.compress.v1beta1.Compress
+    threads: 2
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.compute_pool,
+            Some(ComputePool {
+                heavy_filters: vec!["quilkin.extensions.filters.compress.v1beta1.Compress".into()],
+                threads: 2,
+                queue_size: 1024,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_fair_queue() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  fair_queue: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(config.proxy.fair_queue, Some(FairQueue { queue_size: 1024 }));
+    }
+
+    #[test]
+    fn parse_upstream_socket() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  upstream_socket:
+    ttl: 32
+    dont_fragment: false
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.upstream_socket,
+            UpstreamSocket {
+                ttl: Some(32),
+                dont_fragment: Some(false),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_admin() {
+        let config = parse_config(
+            "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+        );
+        assert_eq!(config.admin.address, Some("[::]:9091".parse().unwrap()));
+        assert_eq!(config.admin.local_socket, None);
+
+        let config = parse_config(
+            "
+version: v1alpha1
+admin:
+  local_socket: /run/quilkin/admin.sock
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+        );
+        assert_eq!(config.admin.address, None);
+        assert_eq!(
+            config.admin.local_socket,
+            Some("/run/quilkin/admin.sock".into())
+        );
+        assert_eq!(config.admin.tap_address, None);
 
-    use crate::config::{Builder, Config, EndPoint, ManagementServer, Source};
-    use std::collections::HashMap;
+        let config = parse_config(
+            "
+version: v1alpha1
+admin:
+  tap_address: 127.0.0.1:9092
+  session_stats_address: 127.0.0.1:9093
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+        );
+        assert_eq!(
+            config.admin.tap_address,
+            Some("127.0.0.1:9092".parse().unwrap())
+        );
+        assert_eq!(
+            config.admin.session_stats_address,
+            Some("127.0.0.1:9093".parse().unwrap())
+        );
+        assert!(!config.admin.privileged_endpoints);
 
-    fn parse_config(yaml: &str) -> Config {
-        Config::from_reader(yaml.as_bytes()).unwrap()
+        let config = parse_config(
+            "
+version: v1alpha1
+admin:
+  privileged_endpoints: true
+  token:
+    provider: file
+    key: /var/run/secrets/quilkin/admin-token
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+        );
+        assert!(config.admin.privileged_endpoints);
+        assert_eq!(
+            config.admin.token,
+            Some(SecretRef {
+                provider: "file".into(),
+                key: "/var/run/secrets/quilkin/admin-token".into(),
+            })
+        );
     }
 
-    fn assert_static_endpoints(source: &Source, expected_endpoints: Vec<EndPoint>) {
-        match source {
-            Source::Static {
-                filters: _,
-                endpoints,
-            } => {
-                assert_eq!(&expected_endpoints, endpoints,);
-            }
-            _ => unreachable!("expected static config source"),
-        }
-    }
+    #[test]
+    fn parse_handshake() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  handshake:
+    secret: c2VjcmV0
+    cookie_lifetime: 10s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
 
-    fn assert_management_servers(source: &Source, expected: Vec<ManagementServer>) {
-        match source {
-            Source::Dynamic { management_servers } => {
-                assert_eq!(&expected, management_servers,);
-            }
-            _ => unreachable!("expected dynamic config source"),
-        }
-    }
+        assert_eq!(
+            config.proxy.handshake,
+            Some(Handshake {
+                secret: b"secret".to_vec(),
+                secret_ref: None,
+                prefix: b"QUILKIN_COOKIE".to_vec(),
+                cookie_lifetime: Duration::from_secs(10),
+            })
+        );
 
-    #[test]
-    fn deserialise_client() {
-        let config = Builder::empty()
-            .with_port(7000)
-            .with_static(
-                vec![],
-                vec![EndPoint::new("127.0.0.1:25999".parse().unwrap())],
-            )
-            .build();
-        let _ = serde_yaml::to_string(&config).unwrap();
+        let yaml = "
+version: v1alpha1
+proxy:
+  handshake: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(config.proxy.handshake.unwrap().secret, Vec::<u8>::new());
     }
 
     #[test]
-    fn deserialise_server() {
-        let config = Builder::empty()
-            .with_port(7000)
-            .with_static(
-                vec![],
-                vec![
-                    EndPoint::new("127.0.0.1:26000".parse().unwrap()),
-                    EndPoint::new("127.0.0.1:26001".parse().unwrap()),
-                ],
-            )
-            .build();
-        let _ = serde_yaml::to_string(&config).unwrap();
+    fn parse_first_packet() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  first_packet:
+    prefix: UVVJTA==
+    min_size: 8
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        let first_packet = config.proxy.first_packet.unwrap();
+        assert_eq!(
+            first_packet,
+            FirstPacket {
+                prefix: b"QUIL".to_vec(),
+                min_size: 8,
+            }
+        );
+
+        assert!(first_packet.accepts(b"QUILKIN!"));
+        assert!(!first_packet.accepts(b"QUIL"));
+        assert!(!first_packet.accepts(b"NOTQUILKIN"));
+        assert!(FirstPacket::default().accepts(b""));
     }
 
     #[test]
-    fn parse_default_values() {
+    fn parse_client_version() {
         let yaml = "
 version: v1alpha1
+proxy:
+  client_version:
+    offset: 4
+    length: 8
 static:
   endpoints:
     - address: 127.0.0.1:25999
   ";
         let config = parse_config(yaml);
+        let client_version = config.proxy.client_version.unwrap();
+        assert_eq!(
+            client_version,
+            ClientVersion {
+                offset: 4,
+                length: 8,
+                encoding: ClientVersionEncoding::Text,
+                max_versions: 50,
+            }
+        );
 
-        assert_eq!(config.proxy.port, 7000);
-        assert_eq!(config.proxy.id.len(), 36);
+        assert_eq!(
+            Some("1.4.2".to_string()),
+            client_version.parse(b"QUIL1.4.2\0\0\0hello")
+        );
+        assert_eq!(None, client_version.parse(b"QUIL1.4.2"));
+        assert_eq!(None, client_version.parse(b"QUIL1.4\n2\0\0\0"));
+        assert_eq!(None, client_version.parse(b"QUIL\0\0\0\0\0\0\0\0"));
+
+        let client_version = ClientVersion {
+            length: 2,
+            encoding: ClientVersionEncoding::Hex,
+            ..client_version
+        };
+        assert_eq!(
+            Some("0a1b".to_string()),
+            client_version.parse(b"QUIL\x0a\x1b")
+        );
     }
 
     #[test]
@@ -299,6 +3043,7 @@ version: v1alpha1
 proxy:
   id: server-proxy
   port: 7000
+  first_response_timeout: 5s
 static:
   endpoints:
     - address: 127.0.0.1:25999
@@ -307,6 +3052,10 @@ static:
 
         assert_eq!(config.proxy.port, 7000);
         assert_eq!(config.proxy.id.as_str(), "server-proxy");
+        assert_eq!(
+            config.proxy.first_response_timeout,
+            Some(Duration::from_secs(5))
+        );
     }
 
     #[test]
@@ -408,14 +3157,277 @@ dynamic:
             vec![
                 ManagementServer {
                     address: "127.0.0.1:25999".into(),
+                    token: None,
                 },
                 ManagementServer {
                     address: "127.0.0.1:30000".into(),
+                    token: None,
                 },
             ],
         );
     }
 
+    #[test]
+    fn parse_management_server_token() {
+        let yaml = "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+      token:
+        provider: file
+        key: /var/run/secrets/xds/token
+  ";
+        let config = parse_config(yaml);
+
+        assert_management_servers(
+            &config.source,
+            vec![ManagementServer {
+                address: "127.0.0.1:25999".into(),
+                token: Some(SecretRef {
+                    provider: "file".into(),
+                    key: "/var/run/secrets/xds/token".into(),
+                }),
+            }],
+        );
+    }
+
+    #[test]
+    fn parse_dynamic_source_startup() {
+        let yaml = "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  startup:
+    policy: FALLBACK
+    timeout: 5s
+    fallback:
+      endpoints:
+        - address: 127.0.0.1:26000
+  ";
+        let config = parse_config(yaml);
+
+        match config.source {
+            Source::Dynamic { startup, .. } => {
+                assert_eq!(StartupPolicy::Fallback, startup.policy);
+                assert_eq!(Duration::from_secs(5), startup.timeout);
+                let fallback = startup.fallback.unwrap();
+                assert!(fallback.filters.is_empty());
+                assert_eq!(
+                    vec![EndPoint::new("127.0.0.1:26000".parse().unwrap())],
+                    fallback.endpoints
+                );
+            }
+            _ => unreachable!("expected dynamic config source"),
+        }
+    }
+
+    #[test]
+    fn parse_dynamic_source_startup_default() {
+        let yaml = "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        match config.source {
+            Source::Dynamic { startup, .. } => {
+                assert_eq!(StartupPolicy::Wait, startup.policy);
+                assert_eq!(Duration::from_secs(30), startup.timeout);
+                assert!(startup.fallback.is_none());
+            }
+            _ => unreachable!("expected dynamic config source"),
+        }
+    }
+
+    #[test]
+    fn parse_dynamic_source_failover() {
+        let yaml = "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  failover:
+    cluster: us-west1
+  ";
+        let config = parse_config(yaml);
+
+        match config.source {
+            Source::Dynamic { failover, .. } => assert_eq!(
+                Some(Failover {
+                    cluster: "us-west1".into(),
+                    failback_delay: Duration::from_secs(30),
+                }),
+                failover
+            ),
+            _ => unreachable!("expected dynamic config source"),
+        }
+    }
+
+    #[test]
+    fn parse_dynamic_source_endpoint_update_guard() {
+        let yaml = "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  endpoint_update_guard:
+    max_removal_percent: 50
+  ";
+        match parse_config(yaml).source {
+            Source::Dynamic {
+                endpoint_update_guard,
+                ..
+            } => assert_eq!(
+                Some(EndpointUpdateGuard {
+                    max_removal_percent: 50.0,
+                    policy: EndpointUpdateGuardPolicy::Reject,
+                    phase_in_interval: Duration::from_secs(10),
+                }),
+                endpoint_update_guard
+            ),
+            _ => unreachable!("expected dynamic config source"),
+        }
+
+        let yaml = "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  endpoint_update_guard:
+    max_removal_percent: 12.5
+    policy: PHASE_IN
+    phase_in_interval: 1m
+  ";
+        match parse_config(yaml).source {
+            Source::Dynamic {
+                endpoint_update_guard,
+                ..
+            } => assert_eq!(
+                Some(EndpointUpdateGuard {
+                    max_removal_percent: 12.5,
+                    policy: EndpointUpdateGuardPolicy::PhaseIn,
+                    phase_in_interval: Duration::from_secs(60),
+                }),
+                endpoint_update_guard
+            ),
+            _ => unreachable!("expected dynamic config source"),
+        }
+    }
+
+    #[test]
+    fn parse_dynamic_source_filter_update_failure() {
+        let yaml = "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  ";
+        match parse_config(yaml).source {
+            Source::Dynamic {
+                filter_update_failure,
+                ..
+            } => assert_eq!(FilterUpdateFailurePolicy::Reject, filter_update_failure),
+            _ => unreachable!("expected dynamic config source"),
+        }
+
+        let yaml = "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  filter_update_failure: BYPASS
+  ";
+        match parse_config(yaml).source {
+            Source::Dynamic {
+                filter_update_failure,
+                ..
+            } => assert_eq!(FilterUpdateFailurePolicy::Bypass, filter_update_failure),
+            _ => unreachable!("expected dynamic config source"),
+        }
+    }
+
+    #[test]
+    fn parse_static_source_mdns() {
+        let yaml = "
+version: v1alpha1
+static:
+  mdns:
+    service_type: _game._udp
+  ";
+        match parse_config(yaml).source {
+            Source::Static {
+                endpoints, mdns, ..
+            } => {
+                assert!(endpoints.is_empty());
+                assert_eq!(
+                    Some(Mdns {
+                        service_type: "_game._udp".into(),
+                        query_interval: Duration::from_secs(10),
+                    }),
+                    mdns
+                );
+            }
+            _ => unreachable!("expected static config source"),
+        }
+
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  mdns:
+    service_type: _game._udp
+    query_interval: 2s
+  ";
+        match parse_config(yaml).source {
+            Source::Static { mdns, .. } => assert_eq!(
+                Some(Mdns {
+                    service_type: "_game._udp".into(),
+                    query_interval: Duration::from_secs(2),
+                }),
+                mdns
+            ),
+            _ => unreachable!("expected static config source"),
+        }
+    }
+
+    #[test]
+    fn parse_static_source_experiment() {
+        let yaml = "
+version: v1alpha1
+static:
+  experiment:
+    variants:
+      - name: control
+        weight: 9
+      - name: compressed
+        filters:
+          - name: quilkin.extensions.filters.compress.v1beta1.Compress
+            config:
+              on_read: COMPRESS
+              on_write: DECOMPRESS
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        match parse_config(yaml).source {
+            Source::Static { experiment, .. } => {
+                let variants = experiment.unwrap().variants;
+                assert_eq!(2, variants.len());
+                assert_eq!("control", variants[0].name);
+                assert_eq!(9, variants[0].weight);
+                assert!(variants[0].filters.is_empty());
+                assert_eq!("compressed", variants[1].name);
+                assert_eq!(1, variants[1].weight);
+                assert_eq!(1, variants[1].filters.len());
+            }
+            _ => unreachable!("expected static config source"),
+        }
+    }
+
     #[test]
     fn deny_unused_fields() {
         let configs = vec![
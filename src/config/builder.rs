@@ -34,6 +34,8 @@ impl Builder {
             source: Source::Static {
                 filters: vec![],
                 endpoints: vec![],
+                mdns: None,
+                experiment: None,
             },
         }
     }
@@ -43,7 +45,12 @@ impl Builder {
     }
 
     pub fn with_static(self, filters: Vec<Filter>, endpoints: Vec<EndPoint>) -> Self {
-        let source = Source::Static { filters, endpoints };
+        let source = Source::Static {
+            filters,
+            endpoints,
+            mdns: None,
+            experiment: None,
+        };
         Builder { source, ..self }
     }
 
@@ -57,6 +64,7 @@ impl Builder {
             proxy: Proxy {
                 id: "test".into(),
                 port: self.port,
+                ..Proxy::default()
             },
             admin: self.admin,
             source: self.source,
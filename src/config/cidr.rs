@@ -0,0 +1,170 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// A range of IP addresses in CIDR notation, e.g `10.0.0.0/8`. A single
+/// address can be written without a prefix length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns the range of the addresses starting with the first
+    /// `prefix_len` bits of `address`, or `None` if `prefix_len` is longer
+    /// than the address.
+    pub fn new(address: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len <= max_len {
+            Some(Self {
+                address,
+                prefix_len,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether `ip` is in the range. IPv4 addresses mapped to IPv6,
+    /// as those of clients of a dual-stack socket are, match the IPv4
+    /// address they map.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, unmapped(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether any of `ranges` contains `ip`.
+    pub fn any_contains(ranges: &[Self], ip: IpAddr) -> bool {
+        ranges.iter().any(|range| range.contains(ip))
+    }
+}
+
+/// Returns the IPv4 address that `ip` maps, if it's an IPv4-mapped IPv6
+/// address, or else `ip`.
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::from([a, b, c, d]),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Returns whether the first `prefix_len` bits of `network` and `ip` match.
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let (bytes, bits) = (usize::from(prefix_len / 8), prefix_len % 8);
+    network[..bytes] == ip[..bytes]
+        && (bits == 0 || (network[bytes] ^ ip[bytes]) >> (8 - bits) == 0)
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid CIDR `{}`, expected e.g `10.0.0.0/8`", s);
+        let mut parts = s.splitn(2, '/');
+        let address = parts
+            .next()
+            .and_then(|address| address.parse::<IpAddr>().ok())
+            .ok_or_else(invalid)?;
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None if address.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(address, prefix_len).ok_or_else(invalid)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cidr;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_cidr() {
+        assert_eq!("10.0.0.0/8", cidr("10.0.0.0/8").to_string());
+        assert_eq!("10.0.0.1/32", cidr("10.0.0.1").to_string());
+        assert_eq!("fd00::/8", cidr("fd00::/8").to_string());
+        assert_eq!("::1/128", cidr("::1").to_string());
+
+        for invalid in &["", "10.0.0.0/", "10.0.0.0/33", "fd00::/129", "10.0.0/8"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let range = cidr("10.1.0.0/17");
+        assert!(range.contains("10.1.0.1".parse().unwrap()));
+        assert!(range.contains("10.1.127.255".parse().unwrap()));
+        assert!(!range.contains("10.1.128.0".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        // IPv4 clients of a dual-stack socket.
+        assert!(range.contains("::ffff:10.1.0.1".parse().unwrap()));
+
+        let range = cidr("fd00::/8");
+        assert!(range.contains("fd12::1".parse().unwrap()));
+        assert!(!range.contains("fe80::1".parse().unwrap()));
+        assert!(!range.contains("10.1.0.1".parse().unwrap()));
+
+        assert!(cidr("0.0.0.0/0").contains("192.0.2.1".parse().unwrap()));
+        assert!(cidr("192.0.2.1").contains("192.0.2.1".parse().unwrap()));
+        assert!(!cidr("192.0.2.1").contains("192.0.2.2".parse().unwrap()));
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Encryption of configuration files at rest, so that configurations holding
+//! tokens and keys can be stored and shipped as opaque blobs.
+//!
+//! Encrypted files are [age](https://age-encryption.org/v1) files, in either
+//! its binary format or its ASCII armor, encrypted to the X25519 recipient of
+//! the config key. This way they can be encrypted and inspected with the
+//! standard `age` tools as well as with `quilkin encrypt-config`, and no
+//! cryptographic format is specific to Quilkin.
+
+use std::io::{self, Read, Write};
+use std::iter;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::x25519::Identity;
+
+/// The first line of an encrypted configuration file in age's ASCII armor.
+const ARMOR_HEADER: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// The first line of an encrypted configuration file in age's binary format.
+const BINARY_HEADER: &str = "age-encryption.org/v1";
+
+/// The environment variable holding the age identity that is the key.
+pub const KEY_ENV: &str = "QUILKIN_CONFIG_KEY";
+/// The environment variable holding the path of an age identity file that
+/// contains the key.
+pub const KEY_FILE_ENV: &str = "QUILKIN_CONFIG_KEY_FILE";
+
+/// An error while encrypting or decrypting a configuration file.
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptionError {
+    #[error("no config key, set {} or {}", KEY_ENV, KEY_FILE_ENV)]
+    MissingKey,
+    #[error("invalid config key: {}", .0)]
+    InvalidKey(String),
+    #[error("failed to read config key file {}: {}", path.display(), source)]
+    ReadKey {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid encrypted config: {}", .0)]
+    Malformed(String),
+    #[error("failed to decrypt config: the key is wrong or the file is corrupted")]
+    Decrypt,
+    #[error("failed to encrypt config")]
+    Encrypt,
+}
+
+/// The key that configuration files are encrypted with, an age X25519
+/// identity.
+pub struct ConfigKey {
+    identity: Identity,
+}
+
+impl ConfigKey {
+    /// Returns the key in `identity`, which holds an age identity such as
+    /// `AGE-SECRET-KEY-1...`, as written by `age-keygen`. Empty lines and
+    /// comments are skipped.
+    pub fn from_identity(identity: &str) -> Result<Self, EncryptionError> {
+        let identity = identity
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or_else(|| EncryptionError::InvalidKey("missing identity".into()))?;
+        let identity = Identity::from_str(identity)
+            .map_err(|err| EncryptionError::InvalidKey(err.to_string()))?;
+        Ok(Self { identity })
+    }
+
+    /// Returns the key in the [`KEY_ENV`] environment variable, or else in
+    /// the file named by [`KEY_FILE_ENV`], or `None` if neither is set.
+    pub fn from_env() -> Result<Option<Self>, EncryptionError> {
+        if let Ok(identity) = std::env::var(KEY_ENV) {
+            return Self::from_identity(&identity).map(Some);
+        }
+        match std::env::var_os(KEY_FILE_ENV) {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                let identity = std::fs::read_to_string(&path)
+                    .map_err(|source| EncryptionError::ReadKey { path, source })?;
+                Self::from_identity(&identity).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the contents of an encrypted configuration file holding
+    /// `config`, in age's ASCII armor.
+    pub fn encrypt(&self, config: &[u8]) -> Result<String, EncryptionError> {
+        let encrypted = self.seal(config).map_err(|_| EncryptionError::Encrypt)?;
+        String::from_utf8(encrypted).map_err(|_| EncryptionError::Encrypt)
+    }
+
+    fn seal(&self, config: &[u8]) -> io::Result<Vec<u8>> {
+        let recipient: Box<dyn age::Recipient> = Box::new(self.identity.to_public());
+        let armored = ArmoredWriter::wrap_output(vec![], Format::AsciiArmor)?;
+        let mut writer = age::Encryptor::with_recipients(vec![recipient]).wrap_output(armored)?;
+        writer.write_all(config)?;
+        writer.finish()?.finish()
+    }
+
+    /// Returns the configuration held by the encrypted configuration file
+    /// `contents`.
+    pub fn decrypt(&self, contents: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let decryptor = match age::Decryptor::new(ArmoredReader::new(contents)) {
+            Ok(age::Decryptor::Recipients(decryptor)) => decryptor,
+            Ok(_) => {
+                return Err(EncryptionError::Malformed(
+                    "encrypted with a passphrase rather than to the config key".into(),
+                ))
+            }
+            Err(err) => return Err(EncryptionError::Malformed(err.to_string())),
+        };
+        let mut reader = decryptor
+            .decrypt(iter::once(&self.identity as &dyn age::Identity))
+            .map_err(|_| EncryptionError::Decrypt)?;
+
+        let mut config = vec![];
+        reader
+            .read_to_end(&mut config)
+            .map_err(|_| EncryptionError::Decrypt)?;
+        Ok(config)
+    }
+}
+
+/// Returns whether `contents` are those of an encrypted configuration file.
+pub(crate) fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(ARMOR_HEADER.as_bytes()) || contents.starts_with(BINARY_HEADER.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_encrypted, ConfigKey, EncryptionError};
+
+    const KEY: &str = "AGE-SECRET-KEY-1QURSWPC8QURSWPC8QURSWPC8QURSWPC8QURSWPC8QURSWPC8QURSKMP32K";
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let key = ConfigKey::from_identity(KEY).unwrap();
+        let config = b"version: v1alpha1\n";
+
+        let encrypted = key.encrypt(config).unwrap();
+        assert!(is_encrypted(encrypted.as_bytes()));
+        assert!(encrypted.starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+        assert!(!encrypted.contains("v1alpha1"));
+        assert_eq!(config.to_vec(), key.decrypt(encrypted.as_bytes()).unwrap());
+        // Each encryption uses a new file key.
+        assert_ne!(encrypted, key.encrypt(config).unwrap());
+    }
+
+    #[test]
+    fn decrypt_errors() {
+        let key = ConfigKey::from_identity(KEY).unwrap();
+        let encrypted = key.encrypt(b"version: v1alpha1\n").unwrap();
+
+        let other = ConfigKey::from_identity(
+            "AGE-SECRET-KEY-1PYYSJZGFPYYSJZGFPYYSJZGFPYYSJZGFPYYSJZGFPYYSJZGFPYYSTFVYXW",
+        )
+        .unwrap();
+        assert!(matches!(
+            other.decrypt(encrypted.as_bytes()),
+            Err(EncryptionError::Decrypt)
+        ));
+
+        // Change a character of the payload, just before the armor's footer.
+        let mut tampered = encrypted.into_bytes();
+        let footer = tampered.len() - "\n-----END AGE ENCRYPTED FILE-----\n".len();
+        tampered[footer - 4] ^= 1;
+        assert!(key.decrypt(&tampered).is_err());
+
+        assert!(matches!(
+            key.decrypt(b"version: v1alpha1"),
+            Err(EncryptionError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn key_file() {
+        let key = ConfigKey::from_identity(&format!(
+            "# created: 2021-10-01T00:00:00Z\n# public key: age1...\n{}\n",
+            KEY
+        ))
+        .unwrap();
+        let encrypted = ConfigKey::from_identity(KEY)
+            .unwrap()
+            .encrypt(b"version: v1alpha1\n")
+            .unwrap();
+        assert!(key.decrypt(encrypted.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn invalid_key() {
+        assert!(matches!(
+            ConfigKey::from_identity("not an identity"),
+            Err(EncryptionError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            ConfigKey::from_identity("# only a comment\n"),
+            Err(EncryptionError::InvalidKey(_))
+        ));
+    }
+}
@@ -15,7 +15,7 @@
  */
 
 // TODO Move endpoint.rs out of config/ into cluster/
-use crate::cluster::Endpoint;
+use crate::cluster::{Endpoint, EndpointHealth, SlowStart};
 use std::sync::Arc;
 
 #[derive(Debug)]
@@ -134,6 +134,26 @@ impl UpstreamEndpoints {
         }
     }
 
+    /// Updates the current subset of endpoints to exclude the endpoints that
+    /// `health` has marked unhealthy. If all of them are unhealthy, the
+    /// subset is left as is, so that packets are still sent somewhere.
+    pub fn retain_healthy(&mut self, health: &EndpointHealth) -> RetainedItems {
+        self.retain(|endpoint| health.is_healthy(endpoint.address))
+    }
+
+    /// Scales down the weights of the endpoints that `slow_start` is warming
+    /// up. The current subset is left as is.
+    pub fn apply_slow_start(&mut self, slow_start: &SlowStart) {
+        if let Some(endpoints) = slow_start.weigh(&self.endpoints) {
+            self.endpoints = endpoints;
+        }
+    }
+
+    /// Returns the sum of the weights of the endpoints in the current subset.
+    pub fn total_weight(&self) -> u64 {
+        self.iter().map(|endpoint| u64::from(endpoint.weight)).sum()
+    }
+
     /// Iterate over the endpoints in the current subset.
     pub fn iter(&self) -> UpstreamEndpointsIter {
         UpstreamEndpointsIter {
@@ -198,9 +218,14 @@ impl<'a> Iterator for UpstreamEndpointsIter<'a> {
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
+    use prometheus::Registry;
+
     use super::Endpoints;
-    use crate::cluster::Endpoint;
-    use crate::config::{RetainedItems, UpstreamEndpoints};
+    use crate::cluster::{Endpoint, EndpointHealth, SlowStart};
+    use crate::config::{EndpointHealthCheck, EndpointSlowStart, RetainedItems, UpstreamEndpoints};
+    use crate::test_utils::logger;
 
     fn ep(id: usize) -> Endpoint {
         Endpoint::from_address(format!("127.0.0.{}:8080", id).parse().unwrap())
@@ -261,6 +286,66 @@ mod tests {
         assert!(result.is_none());
     }
 
+    #[tokio::test]
+    async fn retain_healthy() {
+        let health = EndpointHealth::new(
+            &logger(),
+            EndpointHealthCheck {
+                max_send_failures: 1,
+                response_timeout: None,
+                cooldown: Duration::from_secs(30),
+            },
+            &Registry::default(),
+        )
+        .unwrap();
+        let mut up: UpstreamEndpoints = Endpoints::new(vec![ep(1), ep(2), ep(3)]).unwrap().into();
+        health.record_send(ep(2).address, false);
+
+        assert!(matches!(up.retain_healthy(&health), RetainedItems::Some(2)));
+        assert_eq!(vec![ep(1), ep(3)], up.iter().cloned().collect::<Vec<_>>());
+
+        // The subset is kept if all of its endpoints are unhealthy.
+        health.record_send(ep(1).address, false);
+        health.record_send(ep(3).address, false);
+        assert!(up.retain_healthy(&health).is_none());
+        assert_eq!(2, up.size());
+    }
+
+    #[tokio::test]
+    async fn apply_slow_start() {
+        let slow_start = SlowStart::new(
+            &logger(),
+            EndpointSlowStart {
+                window: Duration::from_secs(30),
+            },
+        );
+        let mut up: UpstreamEndpoints = Endpoints::new(vec![ep(1), ep(2)]).unwrap().into();
+        up.apply_slow_start(&slow_start);
+        assert_eq!(2, up.total_weight());
+
+        slow_start.restart(ep(2).address);
+        up.keep(1).unwrap();
+        up.apply_slow_start(&slow_start);
+        assert_eq!(vec![ep(2).address], up.iter().map(|ep| ep.address).collect::<Vec<_>>());
+        assert_eq!(1, up.total_weight());
+    }
+
+    #[test]
+    fn total_weight() {
+        let mut up: UpstreamEndpoints = Endpoints::new(vec![
+            ep(1),
+            Endpoint {
+                weight: 3,
+                ..ep(2)
+            },
+        ])
+        .unwrap()
+        .into();
+        assert_eq!(4, up.total_weight());
+        up.keep(1).unwrap();
+        assert_eq!(3, up.total_weight());
+    }
+
     #[test]
     fn upstream_len() {
         let mut up: UpstreamEndpoints = Endpoints::new(vec![ep(1), ep(2), ep(3)]).unwrap().into();
@@ -28,6 +28,15 @@ pub(crate) const METADATA_KEY: &str = "quilkin.dev";
 /// exist in an endpoint metadata.
 pub const ENDPOINT_METADATA_TOKENS: &str = "tokens";
 
+/// ENDPOINT_METADATA_RELAY is the key under which the relay configuration of
+/// an endpoint exists in an endpoint metadata.
+pub const ENDPOINT_METADATA_RELAY: &str = "relay";
+
+/// ENDPOINT_METADATA_RELAY_TOKEN is the key under which the token, that
+/// packets sent to a relay endpoint are enveloped with, exists in its relay
+/// configuration.
+pub const ENDPOINT_METADATA_RELAY_TOKEN: &str = "token";
+
 // Returns an empty map if no tokens exist.
 pub fn extract_endpoint_tokens(
     metadata: &mut JsonMap<String, JSONValue>,
@@ -76,9 +85,58 @@ pub fn extract_endpoint_tokens(
     Ok(tokens.unwrap_or_default())
 }
 
+/// Returns the token that packets sent to the endpoint are enveloped with, if
+/// the endpoint is a relay. Returns `None` if the endpoint isn't a relay.
+pub fn extract_endpoint_relay_token(
+    metadata: &mut JsonMap<String, JSONValue>,
+) -> Result<Option<Vec<u8>>, String> {
+    // Any other type of value is reported by `extract_endpoint_tokens`.
+    let relay = match metadata.get_mut(METADATA_KEY) {
+        Some(JSONValue::Object(object)) => object.remove(ENDPOINT_METADATA_RELAY),
+        _ => None,
+    };
+    let token = match relay {
+        Some(JSONValue::Object(mut relay)) => relay.remove(ENDPOINT_METADATA_RELAY_TOKEN),
+        Some(_) => {
+            return Err(format!(
+                "invalid data type for key `{}.{}`: value must be an object",
+                METADATA_KEY, ENDPOINT_METADATA_RELAY
+            ))
+        }
+        None => return Ok(None),
+    };
+
+    let token = match token {
+        Some(JSONValue::String(token)) => base64::decode(token).map_err(|err| {
+            format!(
+                "key {}.{}.{}: failed to decode token as a base64 string:{}",
+                METADATA_KEY, ENDPOINT_METADATA_RELAY, ENDPOINT_METADATA_RELAY_TOKEN, err
+            )
+        })?,
+        _ => {
+            return Err(format!(
+                "invalid value for key `{}.{}.{}`: value must be a base64 string",
+                METADATA_KEY, ENDPOINT_METADATA_RELAY, ENDPOINT_METADATA_RELAY_TOKEN
+            ))
+        }
+    };
+    if token.is_empty() || token.len() > usize::from(u8::MAX) {
+        return Err(format!(
+            "invalid value for key `{}.{}.{}`: token must be between 1 and {} bytes long",
+            METADATA_KEY,
+            ENDPOINT_METADATA_RELAY,
+            ENDPOINT_METADATA_RELAY_TOKEN,
+            u8::MAX
+        ));
+    }
+    Ok(Some(token))
+}
+
+/// Converts an endpoint's YAML metadata into its JSON metadata, tokens and
+/// relay token.
 pub fn parse_endpoint_metadata_from_yaml(
     yaml: YamlValue,
-) -> Result<(JSONValue, HashSet<Vec<u8>>), String> {
+) -> Result<(JSONValue, HashSet<Vec<u8>>, Option<Vec<u8>>), String> {
     let mapping = if let YamlValue::Mapping(mapping) = yaml {
         mapping
     } else {
@@ -92,9 +150,10 @@ pub fn parse_endpoint_metadata_from_yaml(
         map.insert(key, value);
     }
 
+    let relay_token = extract_endpoint_relay_token(&mut map)?;
     let tokens = extract_endpoint_tokens(&mut map)?;
 
-    Ok((JSONValue::Object(map), tokens))
+    Ok((JSONValue::Object(map), tokens, relay_token))
 }
 
 fn yaml_to_json_value(key: &str, yaml: YamlValue) -> Result<JSONValue, String> {
@@ -189,7 +248,8 @@ quilkin.dev:
             }
         });
 
-        let (user_metadata, tokens) = parse_endpoint_metadata_from_yaml(yaml_value).unwrap();
+        let (user_metadata, tokens, relay_token) =
+            parse_endpoint_metadata_from_yaml(yaml_value).unwrap();
         assert_eq!(user_metadata, expected_user_metadata);
         assert_eq!(
             tokens,
@@ -197,6 +257,41 @@ quilkin.dev:
                 .into_iter()
                 .collect::<HashSet<_>>()
         );
+        assert_eq!(relay_token, None);
+    }
+
+    #[test]
+    fn yaml_parse_relay_endpoint_metadata() {
+        let yaml = "
+quilkin.dev:
+    tokens:
+        - MXg3aWp5Ng== #1x7ijy6
+    relay:
+        token: OGdqM3YyaQ== #8gj3v2i
+";
+        let yaml_value = serde_yaml::from_str(yaml).unwrap();
+        let (user_metadata, tokens, relay_token) =
+            parse_endpoint_metadata_from_yaml(yaml_value).unwrap();
+        assert_eq!(user_metadata, serde_json::json!({}));
+        assert_eq!(
+            tokens,
+            vec!["1x7ijy6".into()].into_iter().collect::<HashSet<_>>()
+        );
+        assert_eq!(relay_token, Some(b"8gj3v2i".to_vec()));
+
+        let not_an_object = "
+quilkin.dev:
+    relay: OGdqM3YyaQ==
+";
+        let empty_token = "
+quilkin.dev:
+    relay:
+        token: ''
+";
+        for yaml in &[not_an_object, empty_token] {
+            let yaml_value = serde_yaml::from_str(yaml).unwrap();
+            assert!(parse_endpoint_metadata_from_yaml(yaml_value).is_err());
+        }
     }
 
     #[test]
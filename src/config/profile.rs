@@ -0,0 +1,439 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Resolves configuration files that inherit from a base profile.
+//!
+//! A configuration file can name another configuration file in its top level
+//! `extends` field. The file's values are merged on top of the base profile's:
+//! - Mappings are merged key by key.
+//! - A `filters` list overrides base filters with the same name in place, and
+//!   appends filters that the base does not have.
+//! - Any other value, including other lists, replaces the base value.
+//! - Specifying `static` or `dynamic` replaces the base profile's source.
+//!
+//! Both the file and its base profiles may be
+//! [encrypted](super::encryption).
+
+use std::path::{Path, PathBuf};
+
+use serde_yaml::Value;
+
+use super::encryption::{is_encrypted, ConfigKey, EncryptionError};
+
+/// The top level field naming the base profile of a configuration file.
+const EXTENDS: &str = "extends";
+
+/// The fields that hold a source, of which a config can only have one.
+const SOURCES: [&str; 2] = ["static", "dynamic"];
+
+/// An error while resolving a configuration file and its base profiles.
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("failed to parse config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+    #[error("config file {0} has an invalid `extends` value: expected a file path")]
+    InvalidExtends(PathBuf),
+    #[error("config file {0} extends itself")]
+    Cycle(PathBuf),
+    #[error("failed to decrypt config file {path}: {source}")]
+    Decrypt {
+        path: PathBuf,
+        source: EncryptionError,
+    },
+    #[error("{0}")]
+    Key(EncryptionError),
+}
+
+/// Returns the contents of the config file at `path` merged on top of the
+/// base profiles it extends. Relative `extends` paths are resolved against
+/// the directory of the file that contains them. Encrypted files are
+/// decrypted with `key`.
+pub(super) fn resolve(path: &Path, key: Option<&ConfigKey>) -> Result<Value, ProfileError> {
+    resolve_with_visited(path, key, &mut vec![])
+}
+
+fn resolve_with_visited(
+    path: &Path,
+    key: Option<&ConfigKey>,
+    visited: &mut Vec<PathBuf>,
+) -> Result<Value, ProfileError> {
+    let path = path.canonicalize().map_err(|source| ProfileError::Read {
+        path: path.into(),
+        source,
+    })?;
+    if visited.contains(&path) {
+        return Err(ProfileError::Cycle(path));
+    }
+    visited.push(path.clone());
+
+    let contents = std::fs::read(&path).map_err(|source| ProfileError::Read {
+        path: path.clone(),
+        source,
+    })?;
+    let contents = if is_encrypted(&contents) {
+        key.ok_or(EncryptionError::MissingKey)
+            .and_then(|key| key.decrypt(&contents))
+            .map_err(|source| ProfileError::Decrypt {
+                path: path.clone(),
+                source,
+            })?
+    } else {
+        contents
+    };
+    let mut value: Value =
+        serde_yaml::from_slice(&contents).map_err(|source| ProfileError::Parse {
+            path: path.clone(),
+            source,
+        })?;
+
+    let extends = match value.as_mapping_mut() {
+        Some(mapping) => mapping.remove(&Value::from(EXTENDS)),
+        None => None,
+    };
+    match extends {
+        None => Ok(value),
+        Some(Value::String(base)) => {
+            let base = path
+                .parent()
+                .map(|dir| dir.join(&base))
+                .unwrap_or_else(|| base.into());
+            let base = resolve_with_visited(&base, key, visited)?;
+            Ok(merge_config(base, value))
+        }
+        Some(_) => Err(ProfileError::InvalidExtends(path)),
+    }
+}
+
+/// Merges a configuration on top of its base profile.
+fn merge_config(mut base: Value, overlay: Value) -> Value {
+    if let (Some(base), Some(overlay)) = (base.as_mapping_mut(), overlay.as_mapping()) {
+        if SOURCES
+            .iter()
+            .any(|source| overlay.contains_key(&Value::from(*source)))
+        {
+            for source in SOURCES.iter() {
+                base.remove(&Value::from(*source));
+            }
+        }
+    }
+    merge(base, overlay)
+}
+
+/// Merges `overlay` on top of `base`.
+fn merge(base: Value, overlay: Value) -> Value {
+    match (base, overlay) {
+        (Value::Mapping(mut base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                // Update existing values in place to preserve the order of the base's keys.
+                match base.get_mut(&key) {
+                    Some(base_value) => {
+                        let previous = std::mem::replace(base_value, Value::Null);
+                        *base_value = match (previous, value) {
+                            (Value::Sequence(base_filters), Value::Sequence(filters))
+                                if key.as_str() == Some("filters") =>
+                            {
+                                Value::Sequence(merge_filters(base_filters, filters))
+                            }
+                            (previous, value) => merge(previous, value),
+                        };
+                    }
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+            Value::Mapping(base)
+        }
+        (_, overlay) => overlay,
+    }
+}
+
+/// Replaces each filter in `base` with the filter of the same name in
+/// `overlay`, and appends the remaining filters in `overlay`.
+fn merge_filters(mut base: Vec<Value>, overlay: Vec<Value>) -> Vec<Value> {
+    fn name(filter: &Value) -> Option<&Value> {
+        filter.as_mapping().and_then(|f| f.get(&Value::from("name")))
+    }
+
+    for filter in overlay {
+        let position = name(&filter).and_then(|overlay_name| {
+            base.iter()
+                .position(|base_filter| name(base_filter) == Some(overlay_name))
+        });
+        match position {
+            Some(position) => base[position] = filter,
+            None => base.push(filter),
+        }
+    }
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_yaml::Value;
+
+    use super::{merge_config, resolve, ProfileError};
+    use crate::config::encryption::{ConfigKey, EncryptionError};
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    /// Writes each of `files` into a new temporary directory, returning its path.
+    fn write_files(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("quilkin-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn merge_fields() {
+        let base = yaml(
+            "
+version: v1alpha1
+proxy:
+  port: 7000
+  max_packet_size: 1200
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+    - address: 127.0.0.1:26001
+",
+        );
+        let overlay = yaml(
+            "
+proxy:
+  port: 7001
+static:
+  endpoints:
+    - address: 127.0.0.1:26002
+",
+        );
+
+        assert_eq!(
+            yaml(
+                "
+version: v1alpha1
+proxy:
+  port: 7001
+  max_packet_size: 1200
+static:
+  endpoints:
+    - address: 127.0.0.1:26002
+"
+            ),
+            merge_config(base, overlay)
+        );
+    }
+
+    #[test]
+    fn merge_filters() {
+        let base = yaml(
+            "
+static:
+  filters:
+    - name: quilkin.extensions.filters.debug.v1beta1.Debug
+      config:
+        id: base
+    - name: quilkin.extensions.filters.compress.v1beta1.Compress
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        let overlay = yaml(
+            "
+static:
+  filters:
+    - name: quilkin.extensions.filters.debug.v1beta1.Debug
+      config:
+        id: title
+    - name: quilkin.extensions.filters.capture_bytes.v1beta1.CaptureBytes
+",
+        );
+
+        assert_eq!(
+            yaml(
+                "
+static:
+  filters:
+    - name: quilkin.extensions.filters.debug.v1beta1.Debug
+      config:
+        id: title
+    - name: quilkin.extensions.filters.compress.v1beta1.Compress
+    - name: quilkin.extensions.filters.capture_bytes.v1beta1.CaptureBytes
+  endpoints:
+    - address: 127.0.0.1:26000
+"
+            ),
+            merge_config(base, overlay)
+        );
+    }
+
+    #[test]
+    fn merge_source() {
+        let base = yaml(
+            "
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        let overlay = yaml(
+            "
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+",
+        );
+
+        assert_eq!(overlay.clone(), merge_config(base, overlay));
+    }
+
+    #[test]
+    fn resolve_extends() {
+        let dir = write_files(&[
+            (
+                "base.yaml",
+                "
+version: v1alpha1
+proxy:
+  port: 7000
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+            ),
+            (
+                "region.yaml",
+                "
+extends: base.yaml
+proxy:
+  port: 7001
+",
+            ),
+            (
+                "title.yaml",
+                "
+extends: region.yaml
+proxy:
+  id: title
+",
+            ),
+        ]);
+
+        assert_eq!(
+            yaml(
+                "
+version: v1alpha1
+proxy:
+  port: 7001
+  id: title
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+"
+            ),
+            resolve(&dir.join("title.yaml"), None).unwrap()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_cycle() {
+        let dir = write_files(&[
+            ("a.yaml", "extends: b.yaml"),
+            ("b.yaml", "extends: a.yaml"),
+        ]);
+
+        match resolve(&dir.join("a.yaml"), None).unwrap_err() {
+            ProfileError::Cycle(path) => assert!(path.ends_with("a.yaml")),
+            err => unreachable!("expected cycle error: got {}", err),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_invalid_extends() {
+        let dir = write_files(&[("a.yaml", "extends: [b.yaml]")]);
+
+        match resolve(&dir.join("a.yaml"), None).unwrap_err() {
+            ProfileError::InvalidExtends(_) => {}
+            err => unreachable!("expected invalid extends error: got {}", err),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_encrypted() {
+        let key = ConfigKey::from_identity(
+            "AGE-SECRET-KEY-1QURSWPC8QURSWPC8QURSWPC8QURSWPC8QURSWPC8QURSWPC8QURSKMP32K",
+        )
+        .unwrap();
+        let base = key
+            .encrypt(
+                b"
+version: v1alpha1
+proxy:
+  port: 7000
+",
+            )
+            .unwrap();
+        let dir = write_files(&[
+            ("base.yaml", base.as_str()),
+            (
+                "title.yaml",
+                "
+extends: base.yaml
+proxy:
+  id: title
+",
+            ),
+        ]);
+
+        assert_eq!(
+            yaml(
+                "
+version: v1alpha1
+proxy:
+  port: 7000
+  id: title
+"
+            ),
+            resolve(&dir.join("title.yaml"), Some(&key)).unwrap()
+        );
+        match resolve(&dir.join("title.yaml"), None).unwrap_err() {
+            ProfileError::Decrypt {
+                path,
+                source: EncryptionError::MissingKey,
+            } => assert!(path.ends_with("base.yaml")),
+            err => unreachable!("expected decrypt error: got {}", err),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Windows of time during which filters and endpoints are active, e.g a
+/// filter that rejects packets during a nightly maintenance window.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    /// Filters that are only active during their windows. Outside of them,
+    /// packets pass through the filter unchanged.
+    #[serde(default)]
+    pub filters: Vec<FilterSchedule>,
+    /// Groups of endpoints that are only active during their windows.
+    /// Outside of them, packets aren't sent to the endpoints.
+    #[serde(default)]
+    pub endpoints: Vec<EndpointSchedule>,
+}
+
+/// The windows during which a filter is active.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilterSchedule {
+    /// The name of the filter.
+    pub filter: String,
+    pub active: Vec<ActivationWindow>,
+}
+
+/// The windows during which a group of endpoints is active.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointSchedule {
+    /// The addresses of the endpoints in the group.
+    pub addresses: Vec<SocketAddr>,
+    pub active: Vec<ActivationWindow>,
+}
+
+/// A daily window of time, in UTC. A window that ends before it starts
+/// spans midnight, e.g from `23:00` to `01:00`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ActivationWindow {
+    /// When the window starts, inclusive.
+    pub start: TimeOfDay,
+    /// When the window ends, exclusive.
+    pub end: TimeOfDay,
+}
+
+impl ActivationWindow {
+    /// Returns whether `time`, as read from the proxy's clock, falls within
+    /// the window.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let now = TimeOfDay::at(time);
+        if self.start <= self.end {
+            self.start <= now && now < self.end
+        } else {
+            self.start <= now || now < self.end
+        }
+    }
+
+    /// Returns whether `time` falls within any of `windows`.
+    pub fn any_contains(windows: &[Self], time: SystemTime) -> bool {
+        windows.iter().any(|window| window.contains(time))
+    }
+}
+
+/// A time of day in UTC, written as `HH:MM` or `HH:MM:SS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    /// The number of seconds since midnight.
+    seconds: u32,
+}
+
+impl TimeOfDay {
+    /// Returns the time of day, or `None` if any of the components are out
+    /// of range.
+    pub fn new(hour: u32, minute: u32, second: u32) -> Option<Self> {
+        if hour < 24 && minute < 60 && second < 60 {
+            Some(Self {
+                seconds: (hour * 60 + minute) * 60 + second,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the time of day of `time`. Times before the epoch are treated
+    /// as midnight.
+    fn at(time: SystemTime) -> Self {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| (since_epoch.as_secs() % u64::from(SECONDS_PER_DAY)) as u32)
+            .unwrap_or(0);
+        Self { seconds }
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time of day `{}`, expected `HH:MM` or `HH:MM:SS`", s);
+        let components = s
+            .split(':')
+            .map(|component| match component.len() {
+                2 => component.parse::<u32>().ok(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        match components.as_slice() {
+            [hour, minute] => Self::new(*hour, *minute, 0),
+            [hour, minute, second] => Self::new(*hour, *minute, *second),
+            _ => None,
+        }
+        .ok_or_else(invalid)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (hour, minute, second) = (
+            self.seconds / 3600,
+            self.seconds / 60 % 60,
+            self.seconds % 60,
+        );
+        if second == 0 {
+            write!(f, "{:02}:{:02}", hour, minute)
+        } else {
+            write!(f, "{:02}:{:02}:{:02}", hour, minute, second)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{ActivationWindow, TimeOfDay};
+
+    fn time(s: &str) -> TimeOfDay {
+        s.parse().unwrap()
+    }
+
+    /// Returns a time on the second day after the epoch, at `time_of_day`.
+    fn at(time_of_day: &str) -> SystemTime {
+        let time_of_day = time(time_of_day);
+        UNIX_EPOCH + Duration::from_secs(86400 + u64::from(time_of_day.seconds))
+    }
+
+    #[test]
+    fn parse_time_of_day() {
+        assert_eq!(TimeOfDay::new(2, 0, 0), Some(time("02:00")));
+        assert_eq!(TimeOfDay::new(23, 59, 30), Some(time("23:59:30")));
+        assert_eq!("02:00", time("02:00").to_string());
+        assert_eq!("23:59:30", time("23:59:30").to_string());
+
+        for invalid in &["", "2:00", "24:00", "02:60", "02:00:60", "02:00:00:00", "ab:cd"] {
+            assert!(invalid.parse::<TimeOfDay>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn window_contains() {
+        let window = ActivationWindow {
+            start: time("02:00"),
+            end: time("03:00"),
+        };
+        assert!(!window.contains(at("01:59:59")));
+        assert!(window.contains(at("02:00")));
+        assert!(window.contains(at("02:59:59")));
+        assert!(!window.contains(at("03:00")));
+    }
+
+    #[test]
+    fn window_spans_midnight() {
+        let window = ActivationWindow {
+            start: time("23:00"),
+            end: time("01:00"),
+        };
+        assert!(!window.contains(at("22:59")));
+        assert!(window.contains(at("23:00")));
+        assert!(window.contains(at("00:00")));
+        assert!(window.contains(at("00:59")));
+        assert!(!window.contains(at("01:00")));
+        assert!(!window.contains(at("12:00")));
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+use std::time::Duration;
+
+use prometheus::{IntCounter, IntCounterVec, Registry, Result as MetricsResult};
+use slog::{warn, Logger};
+
+use crate::config::Faults;
+use crate::metrics::{opts, CollectorExt};
+
+/// Injects faults into the proxy, so that tests can check how it recovers
+/// from them. Each fault injected is counted by the
+/// `quilkin_proxy_faults_injected_total` metric.
+pub(crate) struct FaultInjector {
+    config: Faults,
+    send_failures: IntCounter,
+    xds_disconnects: IntCounter,
+    filter_delays: IntCounter,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(log: &Logger, config: Faults, registry: &Registry) -> MetricsResult<Self> {
+        warn!(log, "Injecting faults into the proxy"; "faults" => ?config);
+        let faults_injected_total = IntCounterVec::new(
+            opts(
+                "faults_injected_total",
+                "proxy",
+                "Total number of faults injected into the proxy",
+            ),
+            &["fault"],
+        )?
+        .register_if_not_exists(registry)?;
+        Ok(Self {
+            config,
+            send_failures: faults_injected_total.with_label_values(&["SendFailure"]),
+            xds_disconnects: faults_injected_total.with_label_values(&["XdsDisconnect"]),
+            filter_delays: faults_injected_total.with_label_values(&["FilterDelay"]),
+        })
+    }
+
+    /// Returns an error in place of sending a packet, for the configured
+    /// fraction of packets.
+    pub(crate) fn send(&self) -> io::Result<()> {
+        let rate = self.config.send_failure_rate;
+        if rate > 0.0 && rand::random::<f64>() < rate {
+            self.send_failures.inc();
+            return Err(io::Error::new(io::ErrorKind::Other, "injected fault: send failure"));
+        }
+        Ok(())
+    }
+
+    /// Blocks the current thread for the configured filter delay, as a slow
+    /// filter would.
+    pub(crate) fn delay_filters(&self) {
+        if let Some(delay) = self.config.filter_delay {
+            self.filter_delays.inc();
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Waits until the connection to a management server, established when
+    /// this is called, should be dropped. Never returns if connections
+    /// aren't dropped.
+    pub(crate) async fn xds_disconnect(&self) {
+        match self.config.xds_disconnect_after {
+            Some(after) => {
+                tokio::time::sleep(after).await;
+                self.xds_disconnects.inc();
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use prometheus::Registry;
+
+    use super::FaultInjector;
+    use crate::config::Faults;
+    use crate::test_utils::logger;
+
+    fn fault_injector(config: Faults) -> FaultInjector {
+        FaultInjector::new(&logger(), config, &Registry::default()).unwrap()
+    }
+
+    #[test]
+    fn send() {
+        assert!(fault_injector(Faults::default()).send().is_ok());
+
+        let faults = fault_injector(Faults {
+            send_failure_rate: 1.0,
+            ..Faults::default()
+        });
+        for _ in 0..10 {
+            assert!(faults.send().is_err());
+        }
+        assert_eq!(10, faults.send_failures.get());
+    }
+
+    #[test]
+    fn delay_filters() {
+        let faults = fault_injector(Faults {
+            filter_delay: Some(Duration::from_millis(10)),
+            ..Faults::default()
+        });
+        let start = Instant::now();
+        faults.delay_filters();
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert_eq!(1, faults.filter_delays.get());
+    }
+
+    #[tokio::test]
+    async fn xds_disconnect() {
+        let faults = fault_injector(Faults::default());
+        let disconnect = tokio::time::timeout(Duration::from_millis(10), faults.xds_disconnect());
+        assert!(disconnect.await.is_err());
+
+        let faults = fault_injector(Faults {
+            xds_disconnect_after: Some(Duration::from_millis(10)),
+            ..Faults::default()
+        });
+        let disconnect = tokio::time::timeout(Duration::from_secs(1), faults.xds_disconnect());
+        assert!(disconnect.await.is_ok());
+        assert_eq!(1, faults.xds_disconnects.get());
+    }
+}
@@ -16,12 +16,21 @@
 
 //! Filters for processing packets.
 
+mod budget;
+mod bypass;
 mod config;
+mod drop_reason;
 mod error;
 mod factory;
+mod metrics;
 mod read;
 mod registry;
+mod routing_cache;
+mod sample;
+mod schedule;
 mod set;
+mod static_filter;
+mod timeout;
 mod write;
 
 pub(crate) mod chain;
@@ -33,22 +42,29 @@ pub mod extensions;
 /// [`FilterFactory`].
 pub mod prelude {
     pub use super::{
-        ConvertProtoConfigError, CreateFilterArgs, Error, Filter, FilterFactory, ReadContext,
-        ReadResponse, WriteContext, WriteResponse,
+        drop_packet, ConvertProtoConfigError, CreateFilterArgs, Error, Filter, FilterFactory,
+        FilterMetrics, ReadContext, ReadResponse, Sample, WriteContext, WriteResponse,
     };
 }
 
 pub use self::{
     config::ConfigType,
+    drop_reason::drop_packet,
     error::{ConvertProtoConfigError, Error},
     factory::{CreateFilterArgs, DynFilterFactory, FilterFactory},
+    metrics::{FilterMetrics, RoutingMetrics},
     read::{ReadContext, ReadResponse},
-    registry::FilterRegistry,
+    registry::{FilterDescription, FilterRegistry},
+    sample::Sample,
     set::{FilterMap, FilterSet},
-    write::{WriteContext, WriteResponse},
+    static_filter::StaticFilter,
+    write::{Priority, WriteContext, WriteResponse},
 };
 
-pub(crate) use self::chain::FilterChain;
+pub use self::chain::FilterChain;
+
+pub(crate) use self::chain::FilterStep;
+pub(crate) use self::drop_reason::DropReason;
 
 /// Filter is a trait for routing and manipulating packets.
 pub trait Filter: Send + Sync {
@@ -57,7 +73,8 @@ pub trait Filter: Send + Sync {
     /// This function should return a [`ReadResponse`] containing the array of
     /// endpoints that the packet should be sent to and the packet that should be
     /// sent (which may be manipulated) as well.
-    /// If the packet should be rejected, return None.
+    /// If the packet should be rejected, return None, or [`drop_packet`] to
+    /// also report why it was rejected.
     /// By default, passes the context through unchanged
     fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
         Some(ctx.into())
@@ -67,9 +84,44 @@ pub trait Filter: Send + Sync {
     /// via the listening port after receiving it via one of the upstream Endpoints.
     /// This function should return an [`WriteResponse`] containing the packet to
     /// be sent (which may be manipulated).
-    /// If the packet should be rejected, return None.
+    /// If the packet should be rejected, return None, or [`drop_packet`] to
+    /// also report why it was rejected.
     /// By default, passes the context through unchanged
     fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
         Some(ctx.into())
     }
+
+    /// Returns whether the filter only observes packets, i.e. it never
+    /// changes, drops or adds packets and doesn't use metadata set by other
+    /// filters. Consecutive read-only filters in a chain are run
+    /// concurrently, each with its own copy of the packet without metadata,
+    /// and their responses are discarded.
+    /// By default, filters are not read-only
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Returns the state of the filter that should be carried over to a new
+    /// instance of the proxy that replaces this one, such as the endpoints
+    /// that clients are routed to.
+    /// By default, the filter has no such state and None is returned
+    fn export_state(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Restores the `state` exported by the same filter in another instance
+    /// of the proxy.
+    /// By default, the state is ignored
+    fn import_state(&self, state: serde_json::Value) -> Result<(), Error> {
+        let _ = state;
+        Ok(())
+    }
+
+    /// Returns the approximate number of bytes held by the filter's state,
+    /// such as the clients that it tracks, which counts towards the
+    /// filter's memory budget.
+    /// By default, the filter's state isn't accounted for and None is returned
+    fn memory_usage(&self) -> Option<usize> {
+        None
+    }
 }
@@ -0,0 +1,362 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use prometheus::{Counter, IntCounterVec, IntGauge, Opts, Registry};
+
+use crate::config::{FilterBudget, FilterBudgetPolicy};
+use crate::filters::prelude::*;
+use crate::metrics::CollectorExt;
+
+/// The reason code reported for packets dropped as their filter is over its
+/// budget.
+const BUDGET_EXCEEDED_REASON: &str = "BudgetExceeded";
+
+/// The `resource` label value of packets the filter was over its CPU time
+/// budget for.
+const CPU: &str = "cpu";
+/// The `resource` label value of packets the filter was over its memory
+/// budget for.
+const MEMORY: &str = "memory";
+
+/// The budgets of filters, by filter name.
+pub(crate) type FilterBudgets = HashMap<String, FilterBudget>;
+
+/// Wraps a filter to account for the time it spends processing packets and
+/// the memory it holds. While the filter is over its budget, packets are
+/// dropped or passed through without being processed by it, until the
+/// budget is renewed.
+pub(crate) struct BudgetFilter {
+    filter: Box<dyn Filter>,
+    budget: FilterBudget,
+    period: Mutex<Period>,
+    cpu_seconds_total: Counter,
+    memory_bytes: IntGauge,
+    packets_over_budget_total: IntCounterVec,
+}
+
+/// The filter's use of its budget in the current period.
+struct Period {
+    /// When the period started.
+    started: Instant,
+    /// The time the filter has spent processing packets in the period.
+    cpu_time: Duration,
+    /// Whether the filter held more memory than its budget when the period
+    /// started.
+    memory_exceeded: bool,
+}
+
+impl BudgetFilter {
+    /// Returns a new BudgetFilter. The filter's memory usage is first
+    /// checked once its first period has elapsed.
+    pub(crate) fn new(
+        name: &str,
+        filter: Box<dyn Filter>,
+        budget: &FilterBudget,
+        metrics_registry: &Registry,
+    ) -> Result<Self, Error> {
+        let cpu_seconds_total = Counter::with_opts(
+            Opts::new(
+                "filter_cpu_seconds_total",
+                "Total time that a given filter with a budget spent processing packets.",
+            )
+            .const_label("filter", name),
+        )?
+        .register_if_not_exists(metrics_registry)?;
+        let memory_bytes = IntGauge::with_opts(
+            Opts::new(
+                "filter_memory_bytes",
+                "Approximate number of bytes held by the state of a given filter with a budget.",
+            )
+            .const_label("filter", name),
+        )?
+        .register_if_not_exists(metrics_registry)?;
+        let packets_over_budget_total = IntCounterVec::new(
+            Opts::new(
+                "filter_packets_over_budget_total",
+                "Total number of packets that a given filter didn't process as it was over its \
+                 budget.",
+            )
+            .const_label("filter", name),
+            &["resource"],
+        )?
+        .register_if_not_exists(metrics_registry)?;
+
+        Ok(Self {
+            filter,
+            budget: budget.clone(),
+            period: Mutex::new(Period {
+                started: Instant::now(),
+                cpu_time: Duration::from_secs(0),
+                memory_exceeded: false,
+            }),
+            cpu_seconds_total,
+            memory_bytes,
+            packets_over_budget_total,
+        })
+    }
+
+    /// Returns a period starting at `started`, checking the filter's memory
+    /// usage against its budget.
+    fn start_period(&self, started: Instant) -> Period {
+        let memory_usage = self.filter.memory_usage();
+        if let Some(usage) = memory_usage {
+            self.memory_bytes.set(usage as i64);
+        }
+        Period {
+            started,
+            cpu_time: Duration::from_secs(0),
+            memory_exceeded: matches!(
+                (memory_usage, self.budget.max_memory_bytes),
+                (Some(usage), Some(max_memory_bytes)) if usage > max_memory_bytes
+            ),
+        }
+    }
+
+    /// Returns the resource that the filter is over its budget for, if any,
+    /// starting a new period first if the current one has elapsed.
+    fn over_budget(&self) -> Option<&'static str> {
+        let now = Instant::now();
+        let mut period = self.period.lock();
+        if now.duration_since(period.started) >= self.budget.period {
+            *period = self.start_period(now);
+        }
+
+        if period.memory_exceeded {
+            return Some(MEMORY);
+        }
+        match self.budget.cpu_time {
+            Some(cpu_time) if period.cpu_time >= cpu_time => Some(CPU),
+            _ => None,
+        }
+    }
+
+    /// Runs `f` with the filter, counting the time it takes towards the
+    /// filter's budget.
+    fn run<T>(&self, f: impl FnOnce(&dyn Filter) -> T) -> T {
+        let started = Instant::now();
+        let result = f(self.filter.as_ref());
+        let elapsed = started.elapsed();
+        self.cpu_seconds_total.inc_by(elapsed.as_secs_f64());
+        self.period.lock().cpu_time += elapsed;
+        result
+    }
+
+    /// Returns the response for a packet that the filter is over its budget
+    /// for, given the packet as it was passed to the filter.
+    fn exceeded<T>(&self, resource: &str, unchanged: T) -> Option<T> {
+        self.packets_over_budget_total
+            .with_label_values(&[resource])
+            .inc();
+        match self.budget.on_exceeded {
+            FilterBudgetPolicy::Drop => drop_packet(BUDGET_EXCEEDED_REASON),
+            FilterBudgetPolicy::PassThrough => Some(unchanged),
+        }
+    }
+}
+
+impl Filter for BudgetFilter {
+    fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+        match self.over_budget() {
+            Some(resource) => self.exceeded(resource, ctx.into()),
+            None => self.run(|filter| filter.read(ctx)),
+        }
+    }
+
+    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        match self.over_budget() {
+            Some(resource) => self.exceeded(resource, ctx.into()),
+            None => self.run(|filter| filter.write(ctx)),
+        }
+    }
+
+    /// Packets over the budget are dropped unless they are passed through.
+    fn is_read_only(&self) -> bool {
+        self.budget.on_exceeded == FilterBudgetPolicy::PassThrough && self.filter.is_read_only()
+    }
+
+    fn export_state(&self) -> Option<serde_json::Value> {
+        self.filter.export_state()
+    }
+
+    fn import_state(&self, state: serde_json::Value) -> Result<(), Error> {
+        self.filter.import_state(state)
+    }
+
+    fn memory_usage(&self) -> Option<usize> {
+        self.filter.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+
+    use super::{BudgetFilter, BUDGET_EXCEEDED_REASON, CPU, MEMORY};
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, FilterBudget, FilterBudgetPolicy};
+    use crate::filters::{drop_reason, prelude::*};
+
+    /// Takes `delay` to process a packet, appending `!` to it, and reports
+    /// holding `memory_usage` bytes.
+    struct HungryFilter {
+        delay: Duration,
+        memory_usage: Arc<AtomicUsize>,
+    }
+
+    impl Filter for HungryFilter {
+        fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+            std::thread::sleep(self.delay);
+            ctx.contents.push(b'!');
+            Some(ctx.into())
+        }
+
+        fn memory_usage(&self) -> Option<usize> {
+            Some(self.memory_usage.load(Ordering::SeqCst))
+        }
+    }
+
+    fn budget_filter(
+        delay: Duration,
+        budget: FilterBudget,
+    ) -> (BudgetFilter, Arc<AtomicUsize>) {
+        let memory_usage = Arc::new(AtomicUsize::new(0));
+        let filter = BudgetFilter::new(
+            "HungryFilter",
+            Box::new(HungryFilter {
+                delay,
+                memory_usage: memory_usage.clone(),
+            }),
+            &budget,
+            &Registry::default(),
+        )
+        .unwrap();
+        (filter, memory_usage)
+    }
+
+    fn read(filter: &BudgetFilter, contents: &[u8]) -> Option<Vec<u8>> {
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:80".parse().unwrap(),
+        )])
+        .unwrap();
+        filter
+            .read(ReadContext::new(
+                endpoints.into(),
+                "127.0.0.1:70".parse().unwrap(),
+                contents.to_vec(),
+            ))
+            .map(|response| response.contents)
+    }
+
+    #[test]
+    fn drop_over_cpu_budget() {
+        let (filter, _) = budget_filter(
+            Duration::from_millis(5),
+            FilterBudget {
+                filter: "HungryFilter".into(),
+                cpu_time: Some(Duration::from_millis(1)),
+                max_memory_bytes: None,
+                period: Duration::from_secs(3600),
+                on_exceeded: FilterBudgetPolicy::Drop,
+            },
+        );
+
+        assert_eq!(Some(b"hello!".to_vec()), read(&filter, b"hello"));
+        assert!(filter.cpu_seconds_total.get() >= 0.005);
+
+        assert_eq!(None, read(&filter, b"hello"));
+        assert_eq!(BUDGET_EXCEEDED_REASON, drop_reason::take());
+        assert_eq!(
+            1,
+            filter
+                .packets_over_budget_total
+                .with_label_values(&[CPU])
+                .get()
+        );
+    }
+
+    #[test]
+    fn pass_through_over_memory_budget() {
+        let (filter, memory_usage) = budget_filter(
+            Duration::from_secs(0),
+            FilterBudget {
+                filter: "HungryFilter".into(),
+                cpu_time: None,
+                max_memory_bytes: Some(100),
+                period: Duration::from_millis(10),
+                on_exceeded: FilterBudgetPolicy::PassThrough,
+            },
+        );
+
+        assert_eq!(Some(b"hello!".to_vec()), read(&filter, b"hello"));
+
+        // Memory usage is only checked once the period has elapsed.
+        memory_usage.store(200, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(Some(b"hello".to_vec()), read(&filter, b"hello"));
+        assert_eq!(200, filter.memory_bytes.get());
+        assert_eq!(
+            1,
+            filter
+                .packets_over_budget_total
+                .with_label_values(&[MEMORY])
+                .get()
+        );
+
+        memory_usage.store(50, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(Some(b"hello!".to_vec()), read(&filter, b"hello"));
+        assert_eq!(50, filter.memory_bytes.get());
+    }
+
+    #[test]
+    fn read_only() {
+        /// Only observes packets.
+        struct ReadOnlyFilter;
+
+        impl Filter for ReadOnlyFilter {
+            fn is_read_only(&self) -> bool {
+                true
+            }
+        }
+
+        let budget_filter = |on_exceeded| {
+            BudgetFilter::new(
+                "ReadOnlyFilter",
+                Box::new(ReadOnlyFilter),
+                &FilterBudget {
+                    filter: "ReadOnlyFilter".into(),
+                    cpu_time: Some(Duration::from_millis(1)),
+                    max_memory_bytes: None,
+                    period: Duration::from_secs(1),
+                    on_exceeded,
+                },
+                &Registry::default(),
+            )
+            .unwrap()
+        };
+        // Packets over the budget would be dropped.
+        assert!(!budget_filter(FilterBudgetPolicy::Drop).is_read_only());
+        assert!(budget_filter(FilterBudgetPolicy::PassThrough).is_read_only());
+    }
+}
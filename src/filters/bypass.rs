@@ -0,0 +1,164 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::SocketAddr;
+
+use prometheus::{IntCounter, Opts, Registry};
+
+use crate::config::Cidr;
+use crate::filters::prelude::*;
+use crate::metrics::CollectorExt;
+
+/// Wraps a filter so that the packets read from and written to trusted
+/// clients pass through unchanged, without being processed by it.
+pub(crate) struct BypassFilter {
+    filter: Box<dyn Filter>,
+    /// The ranges of the addresses of trusted clients.
+    trusted: Vec<Cidr>,
+    packets_bypassed_total: IntCounter,
+}
+
+impl BypassFilter {
+    pub(crate) fn new(
+        name: &str,
+        filter: Box<dyn Filter>,
+        trusted: Vec<Cidr>,
+        metrics_registry: &Registry,
+    ) -> Result<Self, Error> {
+        let packets_bypassed_total = IntCounter::with_opts(
+            Opts::new(
+                "filter_packets_bypassed_total",
+                "Total number of packets of trusted clients that skipped a given filter.",
+            )
+            .const_label("filter", name),
+        )?
+        .register_if_not_exists(metrics_registry)?;
+        Ok(Self {
+            filter,
+            trusted,
+            packets_bypassed_total,
+        })
+    }
+
+    /// Returns whether the packets of the client at `client` skip the
+    /// filter, counting the packet if so.
+    fn bypassed(&self, client: SocketAddr) -> bool {
+        let trusted = Cidr::any_contains(&self.trusted, client.ip());
+        if trusted {
+            self.packets_bypassed_total.inc();
+        }
+        trusted
+    }
+}
+
+impl Filter for BypassFilter {
+    fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+        if self.bypassed(ctx.from) {
+            Some(ctx.into())
+        } else {
+            self.filter.read(ctx)
+        }
+    }
+
+    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        if self.bypassed(ctx.to) {
+            Some(ctx.into())
+        } else {
+            self.filter.write(ctx)
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.filter.is_read_only()
+    }
+
+    fn export_state(&self) -> Option<serde_json::Value> {
+        self.filter.export_state()
+    }
+
+    fn import_state(&self, state: serde_json::Value) -> Result<(), Error> {
+        self.filter.import_state(state)
+    }
+
+    fn memory_usage(&self) -> Option<usize> {
+        self.filter.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::Registry;
+
+    use super::BypassFilter;
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::prelude::*;
+
+    /// Drops every packet.
+    struct Reject;
+
+    impl Filter for Reject {
+        fn read(&self, _: ReadContext) -> Option<ReadResponse> {
+            drop_packet("RateLimited")
+        }
+
+        fn write(&self, _: WriteContext) -> Option<WriteResponse> {
+            drop_packet("RateLimited")
+        }
+    }
+
+    fn read(filter: &BypassFilter, from: &str) -> Option<ReadResponse> {
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:80".parse().unwrap(),
+        )])
+        .unwrap();
+        filter.read(ReadContext::new(
+            endpoints.into(),
+            from.parse().unwrap(),
+            b"hello".to_vec(),
+        ))
+    }
+
+    fn write(filter: &BypassFilter, to: &str) -> Option<WriteResponse> {
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        filter.write(WriteContext::new(
+            &endpoint,
+            endpoint.address,
+            to.parse().unwrap(),
+            b"hello".to_vec(),
+        ))
+    }
+
+    #[test]
+    fn bypass_trusted_clients() {
+        let filter = BypassFilter::new(
+            "Reject",
+            Box::new(Reject),
+            vec!["10.0.0.0/8".parse().unwrap()],
+            &Registry::default(),
+        )
+        .unwrap();
+
+        let (trusted, untrusted) = ("10.1.2.3:7000", "192.0.2.1:7000");
+        assert_eq!(b"hello".to_vec(), read(&filter, trusted).unwrap().contents);
+        assert_eq!(b"hello".to_vec(), write(&filter, trusted).unwrap().contents);
+        assert_eq!(2, filter.packets_bypassed_total.get());
+
+        assert!(read(&filter, untrusted).is_none());
+        assert!(write(&filter, untrusted).is_none());
+        assert_eq!(2, filter.packets_bypassed_total.get());
+    }
+}
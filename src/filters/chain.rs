@@ -14,13 +14,50 @@
  * limitations under the License.
  */
 
-use prometheus::{Error as PrometheusError, Histogram, HistogramOpts, Registry};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hasher;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
 
-use crate::config::{Filter as FilterConfig, ValidationError};
-use crate::filters::{prelude::*, FilterRegistry};
-use crate::metrics::CollectorExt;
+use fnv::FnvHasher;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Error as PrometheusError, Histogram, HistogramOpts, HistogramVec, IntCounter, Opts, Registry,
+    DEFAULT_BUCKETS,
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Endpoint;
+use crate::config::{
+    Endpoints, Experiment, Filter as FilterConfig, UpstreamEndpoints, ValidationError,
+};
+use crate::filters::{
+    drop_reason, prelude::*, DropReason, Error as FilterError, FilterRegistry, StaticFilter,
+};
+use crate::metrics::{latency_buckets, CollectorExt};
+use crate::proxy::contain_panics;
 
 const FILTER_LABEL: &str = "filter";
+/// The label of the metrics of an experiment's variant.
+const VARIANT_LABEL: &str = "variant";
+
+/// The buckets of the histograms of how many bytes filters add to packets,
+/// negative deltas being bytes removed.
+const BYTES_DELTA_BUCKETS: &[f64] = &[
+    -1024.0, -256.0, -64.0, -16.0, -1.0, 0.0, 1.0, 16.0, 64.0, 256.0, 1024.0,
+];
+
+/// The buckets of the histograms of how many filters packets pass through
+/// before they're dropped.
+const DROP_POSITION_BUCKETS: &[f64] = &[
+    1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0, 12.0, 16.0, 24.0, 32.0,
+];
+
+/// The contents of the packet passed through filters by
+/// [`FilterChain::self_test`].
+const SELF_TEST_CONTENTS: &[u8] = b"quilkin-self-test";
 
 /// A chain of [`Filter`]s to be executed in order.
 ///
@@ -28,10 +65,137 @@ const FILTER_LABEL: &str = "filter";
 /// between each filter's execution, returning the result of data that has gone
 /// through all of the filters in the chain. If any of the filters in the chain
 /// return `None`, then the chain is broken, and `None` is returned.
+///
+/// If a filter returns additional packets, each packet is passed through the
+/// rest of the chain on its own, and the packets that make it through the
+/// whole chain are returned together.
+///
+/// Consecutive filters that are [read-only](Filter::is_read_only) are run
+/// concurrently rather than one after another.
 pub struct FilterChain {
     filters: Vec<(String, Box<dyn Filter>)>,
+    /// The ranges of filters that are run together, in order.
+    stages: Vec<Range<usize>>,
     filter_read_duration_seconds: Vec<Histogram>,
     filter_write_duration_seconds: Vec<Histogram>,
+    filter_read_bytes: Vec<BytesDeltaMetrics>,
+    filter_write_bytes: Vec<BytesDeltaMetrics>,
+    /// The bytes that the whole chain adds to and removes from packets.
+    read_bytes: BytesDeltaMetrics,
+    write_bytes: BytesDeltaMetrics,
+    /// The position in the chain, in the order that packets pass through it,
+    /// of the filters that dropped packets.
+    read_drop_position: Histogram,
+    write_drop_position: Histogram,
+    /// The variants of the experiment that the chain was created for, if
+    /// any, whose chains packets pass through instead of `filters`.
+    variants: Vec<Variant>,
+}
+
+/// A variant of an experiment, with the chain that the packets of its share
+/// of clients pass through.
+struct Variant {
+    name: String,
+    /// The upper bound of the hashes of the clients in the variant. Clients
+    /// with lower hashes are in the variants before it.
+    until: f64,
+    chain: FilterChain,
+}
+
+/// Exposes the metrics registered in the registry of an experiment's
+/// variant, which are labelled with its name, as part of another registry.
+#[derive(Clone)]
+struct VariantCollector {
+    desc: Desc,
+    registry: Registry,
+}
+
+impl Collector for VariantCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.registry.gather()
+    }
+}
+
+/// A filter that a packet was read by, as recorded by
+/// [`FilterChain::try_read_traced`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct FilterStep {
+    pub filter: String,
+    /// The number of endpoints the packet was to be sent to once the filter
+    /// read it.
+    pub endpoints: usize,
+}
+
+/// Counts the bytes that a filter, or the whole chain if no filter is given,
+/// adds to and removes from the packets it processes in one direction, so
+/// that operators can tell how much of the MTU their filters take up.
+/// Read-only filters can't change packets, so nothing is recorded for them.
+struct BytesDeltaMetrics {
+    added: IntCounter,
+    removed: IntCounter,
+    delta: Histogram,
+}
+
+impl BytesDeltaMetrics {
+    /// Registers the metrics of `direction` (`read` or `write`) for the
+    /// filter named `filter`, or for the whole chain.
+    fn new(
+        registry: &Registry,
+        direction: &str,
+        filter: Option<&str>,
+    ) -> Result<Self, PrometheusError> {
+        let (prefix, subject) = match filter {
+            Some(_) => ("filter", "a given filter"),
+            None => ("filter_chain", "the filter chain"),
+        };
+        let with_label = |opts: Opts| match filter {
+            Some(name) => opts.const_label(FILTER_LABEL, name),
+            None => opts,
+        };
+        Ok(Self {
+            added: IntCounter::with_opts(with_label(Opts::new(
+                format!("{}_{}_bytes_added_total", prefix, direction),
+                format!("Total number of bytes added to packets by {}'s `{}`.", subject, direction),
+            )))?
+            .register_if_not_exists(registry)?,
+            removed: IntCounter::with_opts(with_label(Opts::new(
+                format!("{}_{}_bytes_removed_total", prefix, direction),
+                format!(
+                    "Total number of bytes removed from packets by {}'s `{}`.",
+                    subject, direction
+                ),
+            )))?
+            .register_if_not_exists(registry)?,
+            delta: Histogram::with_opts(HistogramOpts {
+                common_opts: with_label(Opts::new(
+                    format!("{}_{}_bytes_delta", prefix, direction),
+                    format!(
+                        "Bytes added to each packet by {}'s `{}`, negative if removed.",
+                        subject, direction
+                    ),
+                )),
+                buckets: BYTES_DELTA_BUCKETS.into(),
+            })?
+            .register_if_not_exists(registry)?,
+        })
+    }
+
+    /// Records that a packet of `before` bytes became packets of `after`
+    /// bytes each.
+    fn record(&self, before: usize, after: impl Iterator<Item = usize>) {
+        for after in after {
+            if after > before {
+                self.added.inc_by((after - before) as u64);
+            } else {
+                self.removed.inc_by((before - after) as u64);
+            }
+            self.delta.observe(after as f64 - before as f64);
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -43,6 +207,11 @@ pub enum Error {
         filter_name: String,
         error: ValidationError,
     },
+    #[error("filter {} panicked on a test packet: {}", filter_name, message)]
+    SelfTest {
+        filter_name: String,
+        message: String,
+    },
 }
 
 impl From<PrometheusError> for Error {
@@ -56,6 +225,16 @@ impl FilterChain {
         filters: Vec<(String, Box<dyn Filter>)>,
         registry: &Registry,
     ) -> Result<Self, Error> {
+        let drop_position = HistogramVec::new(
+            HistogramOpts::new(
+                "filter_chain_drop_position",
+                "Position in the filter chain, starting from 1 in the order that packets pass \
+                 through it, of the filter that dropped a packet.",
+            )
+            .buckets(DROP_POSITION_BUCKETS.into()),
+            &["direction"],
+        )?
+        .register_if_not_exists(registry)?;
         Ok(Self {
             filter_read_duration_seconds: filters
                 .iter()
@@ -65,7 +244,8 @@ impl FilterChain {
                             "filter_read_duration_seconds",
                             "Seconds taken to execute a given filter's `read`.",
                         )
-                        .const_label(FILTER_LABEL, name),
+                        .const_label(FILTER_LABEL, name)
+                        .buckets(latency_buckets(DEFAULT_BUCKETS)),
                     )
                     .and_then(|histogram| histogram.register_if_not_exists(&registry))
                 })
@@ -78,12 +258,27 @@ impl FilterChain {
                             "filter_write_duration_seconds",
                             "Seconds taken to execute a given filter's `write`.",
                         )
-                        .const_label(FILTER_LABEL, name),
+                        .const_label(FILTER_LABEL, name)
+                        .buckets(latency_buckets(DEFAULT_BUCKETS)),
                     )
                     .and_then(|histogram| histogram.register_if_not_exists(&registry))
                 })
                 .collect::<Result<_, prometheus::Error>>()?,
+            filter_read_bytes: filters
+                .iter()
+                .map(|(name, _)| BytesDeltaMetrics::new(registry, "read", Some(name)))
+                .collect::<Result<_, _>>()?,
+            filter_write_bytes: filters
+                .iter()
+                .map(|(name, _)| BytesDeltaMetrics::new(registry, "write", Some(name)))
+                .collect::<Result<_, _>>()?,
+            read_bytes: BytesDeltaMetrics::new(registry, "read", None)?,
+            write_bytes: BytesDeltaMetrics::new(registry, "write", None)?,
+            read_drop_position: drop_position.get_metric_with_label_values(&["read"])?,
+            write_drop_position: drop_position.get_metric_with_label_values(&["write"])?,
+            stages: stages(&filters),
             filters,
+            variants: vec![],
         })
     }
 
@@ -102,7 +297,15 @@ impl FilterChain {
                 CreateFilterArgs::fixed(metrics_registry.clone(), filter_config.config.as_ref())
                     .with_metrics_registry(metrics_registry.clone()),
             ) {
-                Ok(filter) => filters.push((filter_config.name, filter)),
+                Ok(filter) => {
+                    // Filters referenced by a deprecated name are tracked
+                    // under their current name.
+                    let name = filter_registry
+                        .replacement_for(&filter_config.name)
+                        .map(String::from)
+                        .unwrap_or(filter_config.name);
+                    filters.push((name, filter))
+                }
                 Err(err) => {
                     return Err(Error::Filter {
                         filter_name: filter_config.name.clone(),
@@ -114,47 +317,538 @@ impl FilterChain {
 
         FilterChain::new(filters, &metrics_registry)
     }
+
+    /// Validates the filter configurations of each variant of `experiment`
+    /// and constructs a FilterChain that passes the packets of each client
+    /// through the chain of one of the variants, picked by a hash of the
+    /// client's address. The metrics of each variant's chain, and of the
+    /// filters in it, are labelled with the variant's name.
+    pub fn try_create_experiment(
+        experiment: &Experiment,
+        filter_registry: &FilterRegistry,
+        metrics_registry: &Registry,
+    ) -> Result<Self, Error> {
+        let total_weight = experiment
+            .variants
+            .iter()
+            .map(|variant| u64::from(variant.weight))
+            .sum::<u64>();
+        let mut weight = 0;
+        let mut variants = vec![];
+        for variant in &experiment.variants {
+            weight += u64::from(variant.weight);
+            let registry = variant_registry(&variant.name, metrics_registry)?;
+            variants.push(Variant {
+                name: variant.name.clone(),
+                until: weight as f64 / total_weight as f64,
+                chain: Self::try_create(variant.filters.clone(), filter_registry, &registry)?,
+            });
+        }
+
+        // Packets never pass through the experiment's own chain, so its
+        // metrics aren't exposed.
+        let mut chain = FilterChain::new(vec![], &Registry::default())?;
+        chain.variants = variants;
+        Ok(chain)
+    }
+
+    /// Constructs a FilterChain from filters that are created directly by
+    /// their factories.
+    pub fn from_static(
+        filters: Vec<StaticFilter>,
+        metrics_registry: &Registry,
+    ) -> Result<Self, Error> {
+        let filters = filters
+            .into_iter()
+            .map(|filter| {
+                let name = filter.name();
+                filter
+                    .create(metrics_registry)
+                    .map(|filter| (name.to_string(), filter))
+                    .map_err(|err| Error::Filter {
+                        filter_name: name.into(),
+                        error: err.into(),
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        FilterChain::new(filters, metrics_registry)
+    }
+
+    /// Returns whether the chain contains any of the filters in `names`.
+    pub fn contains_any(&self, names: &[String]) -> bool {
+        self.filters.iter().any(|(name, _)| names.contains(name))
+            || self
+                .variants
+                .iter()
+                .any(|variant| variant.chain.contains_any(names))
+    }
+
+    /// Returns the names of the filters in the chain, in the order that
+    /// packets read from clients pass through them. The filters of an
+    /// experiment's variants are returned one variant after another.
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.filters
+            .iter()
+            .chain(
+                self.variants
+                    .iter()
+                    .flat_map(|variant| variant.chain.filters.iter()),
+            )
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Returns the name of the variant of the experiment that the chain was
+    /// created for whose chain the packets of `client` pass through, or
+    /// `None` if it wasn't created for one.
+    pub fn variant(&self, client: SocketAddr) -> Option<&str> {
+        self.variant_of(client).map(|variant| variant.name.as_str())
+    }
+
+    /// Returns the variant of the experiment that the chain was created for
+    /// whose chain the packets of `client` pass through, or `None` if it
+    /// wasn't created for one.
+    fn variant_of(&self, client: SocketAddr) -> Option<&Variant> {
+        let last = self.variants.last()?;
+        // Maps the hash evenly onto [0, 1), as an f64 has 53 bits of
+        // precision.
+        let hash = (client_hash(client) >> 11) as f64 / (1u64 << 53) as f64;
+        self.variants
+            .iter()
+            .find(|variant| hash < variant.until)
+            .or(Some(last))
+    }
+
+    /// Passes a test packet through each filter on its own, in both
+    /// directions, so that a filter which panics on packets is caught before
+    /// the chain is used. Filters are free to drop the test packet, and
+    /// count it in their metrics like any other.
+    pub(crate) fn self_test(&self) -> Result<(), Error> {
+        for variant in &self.variants {
+            variant.chain.self_test()?;
+        }
+        // Addresses reserved for documentation, which no client or endpoint
+        // can have.
+        let client = SocketAddr::from(([192, 0, 2, 1], 7777));
+        let endpoint = Endpoint::from_address(SocketAddr::from(([192, 0, 2, 2], 7777)));
+        let endpoints = Endpoints::new(vec![endpoint.clone()]).expect("endpoints are not empty");
+        for (name, filter) in &self.filters {
+            contain_panics(|| {
+                filter.read(ReadContext::new(
+                    UpstreamEndpoints::from(endpoints.clone()),
+                    client,
+                    SELF_TEST_CONTENTS.to_vec(),
+                ));
+                filter.write(WriteContext::new(
+                    &endpoint,
+                    endpoint.address,
+                    client,
+                    SELF_TEST_CONTENTS.to_vec(),
+                ));
+                drop_reason::clear();
+            })
+            .map_err(|message| Error::SelfTest {
+                filter_name: name.clone(),
+                message,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl FilterChain {
+    /// Like [`Filter::read`], but returns why the packet was dropped if it
+    /// was.
+    pub fn try_read(&self, ctx: ReadContext) -> Result<ReadResponse, DropReason> {
+        self.try_read_traced(ctx, None)
+    }
+
+    /// Like [`FilterChain::try_read`], pushing each filter that read the
+    /// packet to `trace`, if set. Filters that only observe packets, and
+    /// those that read the packets split off by a filter, aren't recorded.
+    pub(crate) fn try_read_traced(
+        &self,
+        ctx: ReadContext,
+        trace: Option<&mut Vec<FilterStep>>,
+    ) -> Result<ReadResponse, DropReason> {
+        if let Some(variant) = self.variant_of(ctx.from) {
+            return variant.chain.try_read_traced(ctx, trace);
+        }
+        let before = ctx.contents.len();
+        let response = self.read_from(0, ctx, trace)?;
+        self.read_bytes.record(before, read_lens(&response));
+        Ok(response)
+    }
+
+    /// Like [`Filter::write`], but returns why the packet was dropped if it
+    /// was.
+    pub fn try_write(&self, ctx: WriteContext) -> Result<WriteResponse, DropReason> {
+        if let Some(variant) = self.variant_of(ctx.to) {
+            return variant.chain.try_write(ctx);
+        }
+        let before = ctx.contents.len();
+        let response = self.write_until(self.filters.len(), ctx)?;
+        self.write_bytes.record(before, write_lens(&response));
+        Ok(response)
+    }
+
+    /// Returns the reason the filter at `index` gave for dropping a packet.
+    fn dropped_by(&self, index: usize) -> DropReason {
+        DropReason {
+            filter: self.filters[index].0.clone(),
+            code: drop_reason::take(),
+        }
+    }
+
+    /// Like [`FilterChain::dropped_by`], recording that the packet was read
+    /// by the filters up to and including the one at `index`.
+    fn read_dropped_by(&self, index: usize) -> DropReason {
+        self.read_drop_position.observe((index + 1) as f64);
+        self.dropped_by(index)
+    }
+
+    /// Like [`FilterChain::dropped_by`], recording that the packet was
+    /// written by the filters from the last one down to the one at `index`.
+    fn write_dropped_by(&self, index: usize) -> DropReason {
+        self.write_drop_position
+            .observe((self.filters.len() - index) as f64);
+        self.dropped_by(index)
+    }
+
+    /// Passes `ctx` through the filters starting at index `start`, pushing
+    /// each filter that read it to `trace`, if set.
+    fn read_from(
+        &self,
+        start: usize,
+        mut ctx: ReadContext,
+        mut trace: Option<&mut Vec<FilterStep>>,
+    ) -> Result<ReadResponse, DropReason> {
+        for stage in self.stages.iter().filter(|stage| stage.start >= start) {
+            if stage.len() > 1 {
+                self.observe_read(stage.clone(), &ctx);
+                continue;
+            }
+
+            let index = stage.start;
+            let (filter, histogram) = (
+                &self.filters[index].1,
+                &self.filter_read_duration_seconds[index],
+            );
+            let (from, received_at, sample) = (ctx.from, ctx.received_at, ctx.sample);
+            let next_ctx = |response| {
+                let mut ctx = ReadContext::with_response(from, response);
+                ctx.received_at = received_at;
+                ctx.sample = sample;
+                ctx
+            };
+            drop_reason::clear();
+            let before = ctx.contents.len();
+            let response = histogram
+                .observe_closure_duration(|| filter.read(ctx))
+                .ok_or_else(|| self.read_dropped_by(index))?;
+            self.filter_read_bytes[index].record(before, read_lens(&response));
+            if let Some(trace) = trace.as_mut() {
+                trace.push(FilterStep {
+                    filter: self.filters[index].0.clone(),
+                    endpoints: response.endpoints.size(),
+                });
+            }
+
+            if !response.additional.is_empty() {
+                // Pass each packet through the rest of the chain on its own,
+                // reporting the last reason one was dropped for if they all
+                // were.
+                let mut dropped = None;
+                let packets = response
+                    .into_packets()
+                    .into_iter()
+                    .filter_map(|response| {
+                        self.read_from(index + 1, next_ctx(response), None)
+                            .map_err(|reason| dropped = Some(reason))
+                            .ok()
+                    })
+                    .flat_map(ReadResponse::into_packets)
+                    .collect();
+                return ReadResponse::from_packets(packets)
+                    .ok_or_else(|| dropped.unwrap_or_else(|| self.read_dropped_by(index)));
+            }
+
+            ctx = next_ctx(response);
+        }
+
+        Ok(ctx.into())
+    }
+
+    /// Passes `ctx` through the first `end` filters in reverse order.
+    fn write_until(&self, end: usize, mut ctx: WriteContext) -> Result<WriteResponse, DropReason> {
+        for stage in self.stages.iter().filter(|stage| stage.end <= end).rev() {
+            if stage.len() > 1 {
+                self.observe_write(stage.clone(), &ctx);
+                continue;
+            }
+
+            let index = stage.start;
+            let (filter, histogram) = (
+                &self.filters[index].1,
+                &self.filter_write_duration_seconds[index],
+            );
+            let (endpoint, from, downstreams, sample) =
+                (ctx.endpoint, ctx.from, ctx.downstreams, ctx.sample);
+            let next_ctx = |response| {
+                let mut ctx = WriteContext::with_response(endpoint, from, response);
+                ctx.downstreams = downstreams;
+                ctx.sample = sample;
+                ctx
+            };
+            drop_reason::clear();
+            let before = ctx.contents.len();
+            let response = histogram
+                .observe_closure_duration(|| filter.write(ctx))
+                .ok_or_else(|| self.write_dropped_by(index))?;
+            self.filter_write_bytes[index].record(before, write_lens(&response));
+
+            if !response.additional.is_empty() {
+                // Pass each packet through the rest of the chain on its own,
+                // reporting the last reason one was dropped for if they all
+                // were.
+                let mut dropped = None;
+                let packets = response
+                    .into_packets()
+                    .into_iter()
+                    .filter_map(|response| {
+                        self.write_until(index, next_ctx(response))
+                            .map_err(|reason| dropped = Some(reason))
+                            .ok()
+                    })
+                    .flat_map(WriteResponse::into_packets)
+                    .collect();
+                return WriteResponse::from_packets(packets)
+                    .ok_or_else(|| dropped.unwrap_or_else(|| self.write_dropped_by(index)));
+            }
+
+            ctx = next_ctx(response);
+        }
+
+        Ok(ctx.into())
+    }
+
+    /// Runs the read-only filters in `stage` concurrently, each with its own
+    /// copy of `ctx`.
+    fn observe_read(&self, stage: Range<usize>, ctx: &ReadContext) {
+        let copies = stage
+            .clone()
+            .map(|_| ReadContext {
+                endpoints: ctx.endpoints.clone(),
+                from: ctx.from,
+                contents: ctx.contents.clone(),
+                metadata: HashMap::new(),
+                received_at: ctx.received_at,
+                delay: ctx.delay,
+                sample: ctx.sample,
+            })
+            .collect::<Vec<_>>();
+        self.filters[stage.clone()]
+            .par_iter()
+            .zip(&self.filter_read_duration_seconds[stage])
+            .zip(copies)
+            .for_each(|(((_, filter), histogram), ctx)| {
+                histogram.observe_closure_duration(|| filter.read(ctx));
+            });
+    }
+
+    /// Runs the read-only filters in `stage` concurrently, each with its own
+    /// copy of `ctx`.
+    fn observe_write(&self, stage: Range<usize>, ctx: &WriteContext) {
+        let copies = stage
+            .clone()
+            .map(|_| WriteContext {
+                endpoint: ctx.endpoint,
+                from: ctx.from,
+                to: ctx.to,
+                downstreams: ctx.downstreams,
+                contents: ctx.contents.clone(),
+                metadata: HashMap::new(),
+                delay: ctx.delay,
+                priority: ctx.priority,
+                sample: ctx.sample,
+            })
+            .collect::<Vec<_>>();
+        self.filters[stage.clone()]
+            .par_iter()
+            .zip(&self.filter_write_duration_seconds[stage])
+            .zip(copies)
+            .for_each(|(((_, filter), histogram), ctx)| {
+                histogram.observe_closure_duration(|| filter.write(ctx));
+            });
+    }
+}
+
+/// Returns the sizes of the packets in `response`.
+fn read_lens(response: &ReadResponse) -> impl Iterator<Item = usize> + '_ {
+    std::iter::once(response.contents.len())
+        .chain(response.additional.iter().map(|packet| packet.contents.len()))
+}
+
+/// Returns the sizes of the packets in `response`.
+fn write_lens(response: &WriteResponse) -> impl Iterator<Item = usize> + '_ {
+    std::iter::once(response.contents.len())
+        .chain(response.additional.iter().map(|packet| packet.contents.len()))
+}
+
+/// Groups consecutive read-only filters into a single stage so that they can
+/// be run concurrently. Every other filter is a stage of its own.
+fn stages(filters: &[(String, Box<dyn Filter>)]) -> Vec<Range<usize>> {
+    let mut stages: Vec<Range<usize>> = Vec::new();
+    for (index, (_, filter)) in filters.iter().enumerate() {
+        match stages.last_mut() {
+            Some(stage) if filter.is_read_only() && filters[stage.start].1.is_read_only() => {
+                stage.end = index + 1;
+            }
+            _ => stages.push(index..index + 1),
+        }
+    }
+    stages
 }
 
 impl Filter for FilterChain {
     fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
-        self.filters
+        self.try_read(ctx).ok()
+    }
+
+    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        self.try_write(ctx).ok()
+    }
+
+    /// Returns the state of each filter in the chain that has any, along
+    /// with the filter's name, or that of each variant's chain by the
+    /// variant's name.
+    fn export_state(&self) -> Option<serde_json::Value> {
+        if !self.variants.is_empty() {
+            let states = self
+                .variants
+                .iter()
+                .filter_map(|variant| {
+                    let state = variant.chain.export_state()?;
+                    Some((variant.name.clone(), state))
+                })
+                .collect::<serde_json::Map<String, serde_json::Value>>();
+            if states.is_empty() {
+                return None;
+            }
+            return Some(states.into());
+        }
+
+        let states = self
+            .filters
             .iter()
-            .zip(self.filter_read_duration_seconds.iter())
-            .try_fold(ctx, |ctx, ((_, filter), histogram)| {
-                Some(ReadContext::with_response(
-                    ctx.from,
-                    histogram.observe_closure_duration(|| filter.read(ctx))?,
-                ))
+            .filter_map(|(name, filter)| {
+                filter.export_state().map(|state| FilterState {
+                    name: name.clone(),
+                    state,
+                })
             })
-            .map(ReadResponse::from)
+            .collect::<Vec<_>>();
+        if states.is_empty() {
+            return None;
+        }
+        serde_json::to_value(states).ok()
     }
 
-    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+    /// Restores the state of each filter exported by [`Self::export_state`].
+    /// If the chain contains a filter more than once, the state of each is
+    /// restored in the order they appear in.
+    fn import_state(&self, state: serde_json::Value) -> Result<(), FilterError> {
+        if !self.variants.is_empty() {
+            let mut states =
+                serde_json::from_value::<serde_json::Map<String, serde_json::Value>>(state)
+                    .map_err(|err| FilterError::DeserializeFailed(err.to_string()))?;
+            for variant in &self.variants {
+                if let Some(state) = states.remove(&variant.name) {
+                    variant.chain.import_state(state)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let mut states = HashMap::<_, VecDeque<_>>::new();
+        for FilterState { name, state } in serde_json::from_value::<Vec<FilterState>>(state)
+            .map_err(|err| FilterError::DeserializeFailed(err.to_string()))?
+        {
+            states.entry(name).or_default().push_back(state);
+        }
+
+        for (name, filter) in &self.filters {
+            if let Some(state) = states.get_mut(name).and_then(VecDeque::pop_front) {
+                filter.import_state(state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the total memory usage of the filters in the chain that
+    /// report theirs.
+    fn memory_usage(&self) -> Option<usize> {
         self.filters
             .iter()
-            .rev()
-            .zip(self.filter_write_duration_seconds.iter().rev())
-            .try_fold(ctx, |ctx, ((_, filter), histogram)| {
-                Some(WriteContext::with_response(
-                    ctx.endpoint,
-                    ctx.from,
-                    ctx.to,
-                    histogram.observe_closure_duration(|| filter.write(ctx))?,
-                ))
-            })
-            .map(WriteResponse::from)
+            .filter_map(|(_, filter)| filter.memory_usage())
+            .chain(
+                self.variants
+                    .iter()
+                    .filter_map(|variant| variant.chain.memory_usage()),
+            )
+            .fold(None, |total, usage| Some(total.unwrap_or(0) + usage))
+    }
+}
+
+/// Returns the FNV-1a hash of `client`'s address, which unlike that of
+/// `DefaultHasher` is the same for every build of every proxy, so that a
+/// client is in the same variant whichever proxy it reaches.
+fn client_hash(client: SocketAddr) -> u64 {
+    let mut hasher = FnvHasher::default();
+    match client.ip() {
+        IpAddr::V4(ip) => hasher.write(&ip.octets()),
+        IpAddr::V6(ip) => hasher.write(&ip.octets()),
     }
+    hasher.write(&client.port().to_be_bytes());
+    hasher.finish()
+}
+
+/// Returns a registry whose metrics are labelled with the name of the
+/// experiment's variant `variant`, and exposed as part of `registry`. The
+/// metrics of the variant's previous chain, if any, stop being exposed.
+fn variant_registry(variant: &str, registry: &Registry) -> Result<Registry, PrometheusError> {
+    let mut labels = HashMap::new();
+    labels.insert(VARIANT_LABEL.to_string(), variant.to_string());
+    let collector = VariantCollector {
+        desc: Desc::new(
+            "filter_chain_variant".into(),
+            "Metrics of the filter chain of an experiment's variant.".into(),
+            vec![],
+            labels.clone(),
+        )?,
+        registry: Registry::new_custom(None, Some(labels))?,
+    };
+    let _ = registry.unregister(Box::new(collector.clone()));
+    registry.register(Box::new(collector.clone()))?;
+    Ok(collector.registry)
+}
+
+/// The state exported by a filter in a [`FilterChain`].
+#[derive(Deserialize, Serialize)]
+struct FilterState {
+    name: String,
+    state: serde_json::Value,
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::SocketAddr;
     use std::str::from_utf8;
 
     use crate::config;
     use crate::config::{Endpoints, UpstreamEndpoints};
     use crate::filters::{extensions::DebugFactory, FilterFactory, FilterRegistry, FilterSet};
-    use crate::test_utils::{logger, new_test_chain, TestFilter};
+    use crate::test_utils::{logger, new_registry, new_test_chain, TestFilter};
 
     use super::*;
     use crate::cluster::Endpoint;
@@ -184,6 +878,23 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn contains_any() {
+        let log = logger();
+        let provider = DebugFactory::new(&log);
+        let filter_configs = vec![config::Filter {
+            name: provider.name().into(),
+            config: Default::default(),
+        }];
+        let registry = FilterRegistry::new(FilterSet::default(&log));
+        let chain =
+            FilterChain::try_create(filter_configs, &registry, &Registry::default()).unwrap();
+
+        assert!(chain.contains_any(&[provider.name().into()]));
+        assert!(!chain.contains_any(&["quilkin.extensions.filters.compress.v1beta1.Compress".into()]));
+        assert!(!chain.contains_any(&[]));
+    }
+
     fn endpoints() -> Vec<Endpoint> {
         vec![
             Endpoint::from_address("127.0.0.1:80".parse().unwrap()),
@@ -246,6 +957,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn chain_bytes_delta() {
+        let registry = prometheus::Registry::default();
+        let chain = new_test_chain(&registry);
+        let endpoints_fixture = endpoints();
+
+        chain
+            .read(ReadContext::new(
+                upstream_endpoints(endpoints_fixture.clone()),
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+        chain
+            .write(WriteContext::new(
+                &endpoints_fixture[0],
+                endpoints_fixture[0].address,
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+
+        // The test filter appends ":odr:127.0.0.1:70" on read, and
+        // ":our:127.0.0.1:80:127.0.0.1:70" on write.
+        assert_eq!(17, chain.filter_read_bytes[0].added.get());
+        assert_eq!(0, chain.filter_read_bytes[0].removed.get());
+        assert_eq!(30, chain.filter_write_bytes[0].added.get());
+        assert_eq!(17, chain.read_bytes.added.get());
+        assert_eq!(1, chain.read_bytes.delta.get_sample_count());
+        assert_eq!(30, chain.write_bytes.delta.get_sample_sum() as u64);
+    }
+
     #[test]
     fn chain_double_test_filter() {
         let registry = prometheus::Registry::default();
@@ -303,4 +1046,601 @@ mod tests {
                 .unwrap()
         );
     }
+
+    /// Splits each packet into one packet per byte. Packets containing
+    /// `drop` are dropped.
+    struct SplitFilter;
+
+    impl Filter for SplitFilter {
+        fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+            if ctx.contents == b"drop" {
+                return None;
+            }
+            let mut packets = ctx
+                .contents
+                .iter()
+                .map(|b| ReadContext::new(ctx.endpoints.clone(), ctx.from, vec![*b]).into());
+            let mut response: ReadResponse = packets.next()?;
+            response.additional = packets.collect();
+            Some(response)
+        }
+
+        fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+            if ctx.contents == b"drop" {
+                return None;
+            }
+            let mut packets = ctx
+                .contents
+                .iter()
+                .map(|b| WriteContext::new(ctx.endpoint, ctx.from, ctx.to, vec![*b]).into());
+            let mut response: WriteResponse = packets.next()?;
+            response.additional = packets.collect();
+            Some(response)
+        }
+    }
+
+    /// Drops packets containing `b`, giving a reason on read.
+    struct DropFilter;
+
+    impl Filter for DropFilter {
+        fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+            if ctx.contents == b"b" {
+                return drop_packet("ContainsB");
+            }
+            Some(ctx.into())
+        }
+
+        fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+            if ctx.contents == b"b" {
+                return None;
+            }
+            Some(ctx.into())
+        }
+    }
+
+    #[test]
+    fn chain_multiple_packets() {
+        let registry = prometheus::Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("SplitFilter".into(), Box::new(SplitFilter)),
+            ("DropFilter".into(), Box::new(DropFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+        let endpoints_fixture = endpoints();
+
+        let response = chain
+            .read(ReadContext::new(
+                upstream_endpoints(endpoints_fixture.clone()),
+                "127.0.0.1:70".parse().unwrap(),
+                b"abc".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(
+            vec![b"a".to_vec(), b"c".to_vec()],
+            response
+                .into_packets()
+                .into_iter()
+                .map(|response| response.contents)
+                .collect::<Vec<_>>()
+        );
+
+        // The filters are run in reverse order on write.
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("DropFilter".into(), Box::new(DropFilter)),
+            ("SplitFilter".into(), Box::new(SplitFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+
+        let response = chain
+            .write(WriteContext::new(
+                &endpoints_fixture[0],
+                endpoints_fixture[0].address,
+                "127.0.0.1:70".parse().unwrap(),
+                b"abc".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(
+            vec![b"a".to_vec(), b"c".to_vec()],
+            response
+                .into_packets()
+                .into_iter()
+                .map(|response| response.contents)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// Duplicates each packet to every other downstream of its session on
+    /// write.
+    struct FanOutFilter;
+
+    impl Filter for FanOutFilter {
+        fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+            let additional = ctx
+                .downstreams
+                .iter()
+                .map(|to| WriteContext::new(ctx.endpoint, ctx.from, *to, ctx.contents.clone()))
+                .map(WriteResponse::from)
+                .collect();
+            let mut response: WriteResponse = ctx.into();
+            response.additional = additional;
+            Some(response)
+        }
+    }
+
+    #[test]
+    fn chain_write_downstreams() {
+        let registry = prometheus::Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("SplitFilter".into(), Box::new(SplitFilter)),
+            ("FanOutFilter".into(), Box::new(FanOutFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+        let endpoints_fixture = endpoints();
+        let client: SocketAddr = "127.0.0.1:70".parse().unwrap();
+        let spectator: SocketAddr = "127.0.0.1:71".parse().unwrap();
+
+        let mut ctx = WriteContext::new(
+            &endpoints_fixture[0],
+            endpoints_fixture[0].address,
+            client,
+            b"ab".to_vec(),
+        );
+        let downstreams = [spectator];
+        ctx.downstreams = &downstreams;
+
+        // Each copy keeps its destination through the rest of the chain.
+        let response = chain.write(ctx).unwrap();
+        assert_eq!(
+            vec![
+                (client, b"a".to_vec()),
+                (client, b"b".to_vec()),
+                (spectator, b"a".to_vec()),
+                (spectator, b"b".to_vec()),
+            ],
+            response
+                .into_packets()
+                .into_iter()
+                .map(|response| (response.to, response.contents))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// Asserts that every packet it sees has the provided sample.
+    struct AssertSampleFilter(Sample);
+
+    impl Filter for AssertSampleFilter {
+        fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+            assert_eq!(self.0, ctx.sample);
+            Some(ctx.into())
+        }
+
+        fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+            assert_eq!(self.0, ctx.sample);
+            Some(ctx.into())
+        }
+    }
+
+    #[test]
+    fn chain_sample_is_shared() {
+        let registry = prometheus::Registry::default();
+        let from: SocketAddr = "127.0.0.1:70".parse().unwrap();
+        let read_sample = Sample::new(from, b"abc");
+        let endpoints_fixture = endpoints();
+        let write_sample = Sample::new(from, b"xyz");
+
+        // Filters after one that changes or splits the packet see the
+        // packet's original sample.
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("SplitFilter".into(), Box::new(SplitFilter)),
+            (
+                "AssertSampleFilter".into(),
+                Box::new(AssertSampleFilter(read_sample)),
+            ),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+        let response = chain
+            .read(ReadContext::new(
+                upstream_endpoints(endpoints_fixture.clone()),
+                from,
+                b"abc".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(3, response.into_packets().len());
+
+        // Write filters run in reverse order.
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            (
+                "AssertSampleFilter".into(),
+                Box::new(AssertSampleFilter(write_sample)),
+            ),
+            ("SplitFilter".into(), Box::new(SplitFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+        let response = chain
+            .write(WriteContext::new(
+                &endpoints_fixture[0],
+                endpoints_fixture[0].address,
+                from,
+                b"xyz".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(3, response.into_packets().len());
+    }
+
+    #[test]
+    fn chain_multiple_packets_all_dropped() {
+        let registry = prometheus::Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("SplitFilter".into(), Box::new(SplitFilter)),
+            ("DropFilter".into(), Box::new(DropFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+
+        let response = chain.read(ReadContext::new(
+            upstream_endpoints(endpoints()),
+            "127.0.0.1:70".parse().unwrap(),
+            b"bb".to_vec(),
+        ));
+        assert!(response.is_none());
+
+        let response = chain.read(ReadContext::new(
+            upstream_endpoints(endpoints()),
+            "127.0.0.1:70".parse().unwrap(),
+            b"drop".to_vec(),
+        ));
+        assert!(response.is_none());
+    }
+
+    #[test]
+    fn chain_drop_reason() {
+        let registry = prometheus::Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("SplitFilter".into(), Box::new(SplitFilter)),
+            ("DropFilter".into(), Box::new(DropFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+        let endpoints_fixture = endpoints();
+
+        let reason = chain
+            .try_read(ReadContext::new(
+                upstream_endpoints(endpoints_fixture.clone()),
+                "127.0.0.1:70".parse().unwrap(),
+                b"bb".to_vec(),
+            ))
+            .unwrap_err();
+        assert_eq!("DropFilter", reason.filter);
+        assert_eq!("ContainsB", reason.code);
+
+        // A reason left over from an earlier packet isn't reported for a
+        // filter that doesn't give one.
+        drop_packet::<()>("Stale");
+        let reason = chain
+            .try_write(WriteContext::new(
+                &endpoints_fixture[0],
+                endpoints_fixture[0].address,
+                "127.0.0.1:70".parse().unwrap(),
+                b"drop".to_vec(),
+            ))
+            .unwrap_err();
+        assert_eq!("SplitFilter", reason.filter);
+        assert_eq!(drop_reason::UNSPECIFIED, reason.code);
+
+        // Both halves of the read packet were dropped by the second filter,
+        // while the written packet was dropped by the last filter it reached,
+        // which is also the second as packets are written in reverse order.
+        assert_eq!(2, chain.read_drop_position.get_sample_count());
+        assert_eq!(4, chain.read_drop_position.get_sample_sum() as u64);
+        assert_eq!(1, chain.write_drop_position.get_sample_count());
+        assert_eq!(2, chain.write_drop_position.get_sample_sum() as u64);
+    }
+
+    /// Sends packets to the first endpoint only.
+    struct FirstEndpointFilter;
+
+    impl Filter for FirstEndpointFilter {
+        fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+            ctx.endpoints.keep(0).ok()?;
+            Some(ctx.into())
+        }
+    }
+
+    #[test]
+    fn chain_read_traced() {
+        let registry = prometheus::Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("FirstEndpointFilter".into(), Box::new(FirstEndpointFilter)),
+            ("DropFilter".into(), Box::new(DropFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+        let ctx = |contents: &[u8]| {
+            ReadContext::new(
+                upstream_endpoints(endpoints()),
+                "127.0.0.1:70".parse().unwrap(),
+                contents.to_vec(),
+            )
+        };
+
+        let mut trace = vec![];
+        let response = chain.try_read_traced(ctx(b"a"), Some(&mut trace)).unwrap();
+        assert_eq!(1, response.endpoints.size());
+        assert_eq!(
+            vec![
+                FilterStep {
+                    filter: "FirstEndpointFilter".into(),
+                    endpoints: 1,
+                },
+                FilterStep {
+                    filter: "DropFilter".into(),
+                    endpoints: 1,
+                },
+            ],
+            trace
+        );
+
+        // The filter that dropped the packet isn't recorded, as its reason
+        // is returned instead.
+        let mut trace = vec![];
+        let reason = chain
+            .try_read_traced(ctx(b"b"), Some(&mut trace))
+            .unwrap_err();
+        assert_eq!("DropFilter", reason.filter);
+        assert_eq!(1, trace.len());
+    }
+
+    /// Exports and imports a number as its state.
+    struct StateFilter(std::sync::atomic::AtomicU64);
+
+    impl Filter for StateFilter {
+        fn export_state(&self) -> Option<serde_json::Value> {
+            Some(self.0.load(std::sync::atomic::Ordering::SeqCst).into())
+        }
+
+        fn import_state(&self, state: serde_json::Value) -> Result<(), FilterError> {
+            let state = state.as_u64().ok_or_else(|| {
+                FilterError::DeserializeFailed("state must be a number".into())
+            })?;
+            self.0.store(state, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chain_export_import_state() {
+        let registry = prometheus::Registry::default();
+        let chain = |states: [u64; 2]| {
+            let filters: Vec<(String, Box<dyn Filter>)> = vec![
+                ("StateFilter".into(), Box::new(StateFilter(states[0].into()))),
+                ("TestFilter".into(), Box::new(TestFilter {})),
+                ("StateFilter".into(), Box::new(StateFilter(states[1].into()))),
+            ];
+            FilterChain::new(filters, &registry).unwrap()
+        };
+
+        let state = chain([1, 2]).export_state().unwrap();
+        let imported = chain([0, 0]);
+        imported.import_state(state.clone()).unwrap();
+        assert_eq!(Some(state), imported.export_state());
+
+        let chain = FilterChain::new(vec![], &registry).unwrap();
+        assert!(chain.export_state().is_none());
+        assert!(chain.import_state(serde_json::json!({})).is_err());
+    }
+
+    /// Drops packets that weren't received at the Unix epoch.
+    struct EpochFilter;
+
+    impl Filter for EpochFilter {
+        fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+            if ctx.received_at != std::time::UNIX_EPOCH {
+                return None;
+            }
+            Some(ctx.into())
+        }
+    }
+
+    #[test]
+    fn chain_keeps_received_at() {
+        let registry = prometheus::Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("SplitFilter".into(), Box::new(SplitFilter)),
+            ("EpochFilter".into(), Box::new(EpochFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+
+        let mut ctx = ReadContext::new(
+            upstream_endpoints(endpoints()),
+            "127.0.0.1:70".parse().unwrap(),
+            b"ab".to_vec(),
+        );
+        ctx.received_at = std::time::UNIX_EPOCH;
+        let response = chain.read(ctx).unwrap();
+        assert_eq!(2, response.into_packets().len());
+    }
+
+    /// Delays each packet by 10ms.
+    struct DelayFilter;
+
+    impl Filter for DelayFilter {
+        fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+            ctx.delay += std::time::Duration::from_millis(10);
+            Some(ctx.into())
+        }
+
+        fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+            ctx.delay += std::time::Duration::from_millis(10);
+            Some(ctx.into())
+        }
+    }
+
+    #[test]
+    fn chain_adds_delays() {
+        let registry = prometheus::Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("DelayFilter".into(), Box::new(DelayFilter)),
+            ("DelayFilter".into(), Box::new(DelayFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+        let endpoints_fixture = endpoints();
+
+        let response = chain
+            .read(ReadContext::new(
+                upstream_endpoints(endpoints_fixture.clone()),
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(std::time::Duration::from_millis(20), response.delay);
+
+        let response = chain
+            .write(WriteContext::new(
+                &endpoints_fixture[0],
+                endpoints_fixture[0].address,
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(std::time::Duration::from_millis(20), response.delay);
+    }
+
+    /// Counts the packets it sees without changing them.
+    struct CountFilter(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Filter for CountFilter {
+        fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Some(ctx.into())
+        }
+
+        fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Some(ctx.into())
+        }
+
+        fn is_read_only(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn chain_read_only_filters() {
+        let registry = prometheus::Registry::default();
+        let count = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![
+            ("CountFilter".into(), Box::new(CountFilter(count.clone()))),
+            ("CountFilter".into(), Box::new(CountFilter(count.clone()))),
+            ("SplitFilter".into(), Box::new(SplitFilter)),
+            ("CountFilter".into(), Box::new(CountFilter(count.clone()))),
+            ("CountFilter".into(), Box::new(CountFilter(count.clone()))),
+            ("DelayFilter".into(), Box::new(DelayFilter)),
+        ];
+        let chain = FilterChain::new(filters, &registry).unwrap();
+        assert_eq!(vec![0..2, 2..3, 3..5, 5..6], chain.stages);
+        let endpoints_fixture = endpoints();
+
+        // Each packet split from the original is seen by the filters after
+        // the split, and the read-only filters leave the packets unchanged.
+        let response = chain
+            .read(ReadContext::new(
+                upstream_endpoints(endpoints_fixture.clone()),
+                "127.0.0.1:70".parse().unwrap(),
+                b"abc".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()],
+            response
+                .into_packets()
+                .into_iter()
+                .map(|response| response.contents)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(2 + 2 * 3, count.load(std::sync::atomic::Ordering::SeqCst));
+
+        count.store(0, std::sync::atomic::Ordering::SeqCst);
+        let response = chain
+            .write(WriteContext::new(
+                &endpoints_fixture[0],
+                endpoints_fixture[0].address,
+                "127.0.0.1:70".parse().unwrap(),
+                b"abc".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(3, response.into_packets().len());
+        assert_eq!(2 + 2 * 3, count.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn chain_experiment() {
+        let registry = prometheus::Registry::default();
+        let experiment = config::Experiment {
+            variants: vec![
+                config::ExperimentVariant {
+                    name: "control".into(),
+                    weight: 1,
+                    filters: vec![],
+                },
+                config::ExperimentVariant {
+                    name: "test".into(),
+                    weight: 1,
+                    filters: vec![config::Filter {
+                        name: "TestFilter".into(),
+                        config: None,
+                    }],
+                },
+            ],
+        };
+        let chain =
+            FilterChain::try_create_experiment(&experiment, &new_registry(&logger()), &registry)
+                .unwrap();
+        assert!(chain.contains_any(&["TestFilter".into()]));
+        assert_eq!(vec!["TestFilter"], chain.names().collect::<Vec<_>>());
+        let endpoints_fixture = endpoints();
+
+        // Each client's packets pass through the same variant's chain in
+        // both directions.
+        let mut variants = vec![];
+        for port in 7000..7100 {
+            let client: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            let read = chain
+                .read(ReadContext::new(
+                    upstream_endpoints(endpoints_fixture.clone()),
+                    client,
+                    b"hello".to_vec(),
+                ))
+                .unwrap();
+            let write = chain
+                .write(WriteContext::new(
+                    &endpoints_fixture[0],
+                    endpoints_fixture[0].address,
+                    client,
+                    b"hello".to_vec(),
+                ))
+                .unwrap();
+            let in_test = read.contents != b"hello";
+            assert_eq!(in_test, write.contents != b"hello");
+            assert_eq!(in_test, chain.variant(client) == Some("test"));
+            variants.push(in_test);
+        }
+        assert!(variants.iter().any(|in_test| *in_test));
+        assert!(variants.iter().any(|in_test| !*in_test));
+        // Every build of every proxy puts a client in the same variant.
+        assert_eq!(
+            0x7fda_253d_4658_eeed,
+            client_hash("192.0.2.1:7777".parse().unwrap())
+        );
+
+        // The metrics of each variant's chain are labelled with its name.
+        let in_test = variants.iter().filter(|in_test| **in_test).count() as u64;
+        let family = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "filter_read_duration_seconds")
+            .unwrap();
+        let metric = &family.get_metric()[0];
+        assert!(metric
+            .get_label()
+            .iter()
+            .any(|label| label.get_name() == "variant" && label.get_value() == "test"));
+        assert_eq!(in_test, metric.get_histogram().get_sample_count());
+    }
 }
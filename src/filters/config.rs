@@ -17,8 +17,15 @@
 use std::convert::TryFrom;
 
 use bytes::Bytes;
+use prost_types::value::Kind;
 
 use crate::filters::error::{ConvertProtoConfigError, Error};
+use crate::xds::udpa::r#type::v1::TypedStruct;
+
+/// Type URL of a dynamic configuration provided as a `google.protobuf.Struct`.
+const STRUCT_TYPE_URL: &str = "type.googleapis.com/google.protobuf.Struct";
+/// Type URL of a dynamic configuration provided as a `udpa.type.v1.TypedStruct`.
+const TYPED_STRUCT_TYPE_URL: &str = "type.googleapis.com/udpa.type.v1.TypedStruct";
 
 /// The configuration of a [`Filter`][crate::filters::Filter] from either a
 /// static or dynamic source.
@@ -32,24 +39,214 @@ pub enum ConfigType<'a> {
 impl ConfigType<'_> {
     /// Deserializes the configuration to `T` based on the input type. Errors if
     /// the data produces an invalid config.
+    ///
+    /// A dynamic configuration is interpreted based on its type URL:
+    /// a `google.protobuf.Struct` or `udpa.type.v1.TypedStruct` is
+    /// deserialized the same way as a static configuration, while any other
+    /// type URL is decoded as the filter's protobuf config `P`.
     pub fn deserialize<T, P>(self, filter_name: &str) -> Result<T, Error>
     where
         P: prost::Message + Default,
         T: for<'de> serde::Deserialize<'de> + TryFrom<P, Error = ConvertProtoConfigError>,
     {
         match self {
-            ConfigType::Static(config) => serde_yaml::to_string(config)
-                .and_then(|raw_config| serde_yaml::from_str(raw_config.as_str()))
+            ConfigType::Static(config) => serde_yaml::from_value(config.clone())
                 .map_err(|err| Error::DeserializeFailed(err.to_string())),
-            ConfigType::Dynamic(config) => prost::Message::decode(Bytes::from(config.value))
-                .map_err(|err| {
-                    Error::DeserializeFailed(format!(
-                        "filter `{}`: config decode error: {}",
-                        filter_name,
-                        err.to_string()
-                    ))
-                })
-                .and_then(|config| T::try_from(config).map_err(Error::ConvertProtoConfig)),
+            ConfigType::Dynamic(config) => match config.type_url.as_str() {
+                STRUCT_TYPE_URL => decode::<prost_types::Struct>(filter_name, config.value)
+                    .and_then(|config| deserialize_struct(filter_name, config)),
+                TYPED_STRUCT_TYPE_URL => decode::<TypedStruct>(filter_name, config.value)
+                    .and_then(|config| {
+                        deserialize_struct(filter_name, config.value.unwrap_or_default())
+                    }),
+                _ => decode::<P>(filter_name, config.value)
+                    .and_then(|config| T::try_from(config).map_err(Error::ConvertProtoConfig)),
+            },
+        }
+    }
+}
+
+/// Decodes a protobuf message `M` from the bytes of a dynamic configuration.
+fn decode<M>(filter_name: &str, value: Vec<u8>) -> Result<M, Error>
+where
+    M: prost::Message + Default,
+{
+    M::decode(Bytes::from(value)).map_err(|err| {
+        Error::DeserializeFailed(format!(
+            "filter `{}`: config decode error: {}",
+            filter_name,
+            err.to_string()
+        ))
+    })
+}
+
+/// Deserializes `T` from a `google.protobuf.Struct`, using the same field
+/// names as the filter's static configuration.
+fn deserialize_struct<T>(filter_name: &str, config: prost_types::Struct) -> Result<T, Error>
+where
+    T: for<'de> serde::Deserialize<'de>,
+{
+    serde_json::from_value(struct_to_json(config)).map_err(|err| {
+        Error::DeserializeFailed(format!(
+            "filter `{}`: config decode error: {}",
+            filter_name,
+            err.to_string()
+        ))
+    })
+}
+
+fn struct_to_json(config: prost_types::Struct) -> serde_json::Value {
+    serde_json::Value::Object(
+        config
+            .fields
+            .into_iter()
+            .map(|(key, value)| (key, value_to_json(value)))
+            .collect(),
+    )
+}
+
+fn value_to_json(value: prost_types::Value) -> serde_json::Value {
+    match value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        // Struct only supports doubles, so whole numbers are converted to
+        // integers in order to deserialize into integer fields.
+        Some(Kind::NumberValue(number))
+            if number.fract() == 0.0
+                && number >= i64::MIN as f64
+                && number <= i64::MAX as f64 =>
+        {
+            serde_json::Value::from(number as i64)
+        }
+        Some(Kind::NumberValue(number)) => serde_json::Number::from_f64(number)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        Some(Kind::StringValue(value)) => serde_json::Value::String(value),
+        Some(Kind::BoolValue(value)) => serde_json::Value::Bool(value),
+        Some(Kind::StructValue(value)) => struct_to_json(value),
+        Some(Kind::ListValue(list)) => {
+            serde_json::Value::Array(list.values.into_iter().map(value_to_json).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+
+    use prost::Message;
+    use prost_types::value::Kind;
+    use serde::Deserialize;
+
+    use super::{ConfigType, STRUCT_TYPE_URL, TYPED_STRUCT_TYPE_URL};
+    use crate::filters::error::ConvertProtoConfigError;
+    use crate::xds::udpa::r#type::v1::TypedStruct;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct TestConfig {
+        id: u32,
+        name: Option<String>,
+    }
+
+    impl TryFrom<prost_types::Duration> for TestConfig {
+        type Error = ConvertProtoConfigError;
+
+        fn try_from(p: prost_types::Duration) -> Result<Self, Self::Error> {
+            Ok(TestConfig {
+                id: p.seconds as u32,
+                name: None,
+            })
         }
     }
+
+    fn encode(message: impl Message) -> Vec<u8> {
+        let mut buf = vec![];
+        message.encode(&mut buf).unwrap();
+        buf
+    }
+
+    fn test_struct() -> prost_types::Struct {
+        let mut fields = BTreeMap::new();
+        fields.insert(
+            "id".into(),
+            prost_types::Value {
+                kind: Some(Kind::NumberValue(12.0)),
+            },
+        );
+        fields.insert(
+            "name".into(),
+            prost_types::Value {
+                kind: Some(Kind::StringValue("abc".into())),
+            },
+        );
+        prost_types::Struct { fields }
+    }
+
+    fn deserialize(type_url: &str, value: Vec<u8>) -> TestConfig {
+        ConfigType::Dynamic(prost_types::Any {
+            type_url: type_url.into(),
+            value,
+        })
+        .deserialize::<TestConfig, prost_types::Duration>("test")
+        .unwrap()
+    }
+
+    #[test]
+    fn dynamic_typed_config() {
+        let config = deserialize(
+            "type.googleapis.com/google.protobuf.Duration",
+            encode(prost_types::Duration {
+                seconds: 7,
+                nanos: 0,
+            }),
+        );
+        assert_eq!(config, TestConfig { id: 7, name: None });
+    }
+
+    #[test]
+    fn dynamic_struct_config() {
+        let config = deserialize(STRUCT_TYPE_URL, encode(test_struct()));
+        assert_eq!(
+            config,
+            TestConfig {
+                id: 12,
+                name: Some("abc".into())
+            }
+        );
+    }
+
+    #[test]
+    fn dynamic_typed_struct_config() {
+        let config = deserialize(
+            TYPED_STRUCT_TYPE_URL,
+            encode(TypedStruct {
+                type_url: "type.googleapis.com/google.protobuf.Duration".into(),
+                value: Some(test_struct()),
+            }),
+        );
+        assert_eq!(
+            config,
+            TestConfig {
+                id: 12,
+                name: Some("abc".into())
+            }
+        );
+    }
+
+    #[test]
+    fn dynamic_struct_config_invalid() {
+        let mut config = test_struct();
+        config.fields.insert(
+            "id".into(),
+            prost_types::Value {
+                kind: Some(Kind::NumberValue(1.5)),
+            },
+        );
+        assert!(ConfigType::Dynamic(prost_types::Any {
+            type_url: STRUCT_TYPE_URL.into(),
+            value: encode(config),
+        })
+        .deserialize::<TestConfig, prost_types::Duration>("test")
+        .is_err());
+    }
 }
@@ -0,0 +1,99 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::Cell;
+use std::fmt;
+
+/// The code reported for packets dropped by a filter that didn't give a
+/// reason for dropping them.
+pub const UNSPECIFIED: &str = "Unspecified";
+
+thread_local! {
+    /// The reason given by the filter currently processing a packet on this
+    /// thread for dropping it, if any.
+    static REASON: Cell<Option<&'static str>> = Cell::new(None);
+}
+
+/// Drops the packet being processed, reporting `reason` as the code for
+/// why it was dropped. Filters can return this from [`Filter::read`] or
+/// [`Filter::write`] in place of `None`, so that the reason is included in
+/// the proxy's per-session diagnostics.
+///
+/// ```rust
+/// # use quilkin::filters::{drop_packet, prelude::*};
+/// struct DropEmpty;
+///
+/// impl Filter for DropEmpty {
+///     fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+///         if ctx.contents.is_empty() {
+///             return drop_packet("EmptyPacket");
+///         }
+///         Some(ctx.into())
+///     }
+/// }
+/// ```
+///
+/// [`Filter::read`]: crate::filters::Filter::read
+/// [`Filter::write`]: crate::filters::Filter::write
+pub fn drop_packet<T>(reason: &'static str) -> Option<T> {
+    REASON.with(|current| current.set(Some(reason)));
+    None
+}
+
+/// Clears any reason given for dropping a packet on this thread.
+pub(crate) fn clear() {
+    REASON.with(|current| current.set(None));
+}
+
+/// Returns the reason given for dropping a packet on this thread since it
+/// was last cleared, or [`UNSPECIFIED`] if none was.
+pub(crate) fn take() -> &'static str {
+    REASON.with(Cell::take).unwrap_or(UNSPECIFIED)
+}
+
+/// Why a packet was dropped by a filter chain.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DropReason {
+    /// The name of the filter that dropped the packet.
+    pub filter: String,
+    /// The code the filter gave for dropping the packet.
+    pub code: &'static str,
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.filter, self.code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clear, drop_packet, take, UNSPECIFIED};
+
+    #[test]
+    fn take_reason() {
+        clear();
+        assert_eq!(UNSPECIFIED, take());
+
+        assert!(drop_packet::<()>("Test").is_none());
+        assert_eq!("Test", take());
+        assert_eq!(UNSPECIFIED, take());
+
+        drop_packet::<()>("Test");
+        clear();
+        assert_eq!(UNSPECIFIED, take());
+    }
+}
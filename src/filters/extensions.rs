@@ -15,21 +15,42 @@
  */
 
 //! Useful filters for common operations.
+//!
+//! Each filter's module exports its `Config`, which serializes to the filter's
+//! static configuration and converts to and from its protobuf config.
 
 pub use capture_bytes::CaptureBytesFactory;
 pub use compress::CompressFactory;
 pub use concatenate_bytes::ConcatBytesFactory;
 pub use debug::DebugFactory;
+pub use fragment::FragmentFactory;
+pub use handoff::HandoffFactory;
+pub use jitter_buffer::JitterBufferFactory;
 pub use load_balancer::LoadBalancerFilterFactory;
 pub use local_rate_limit::RateLimitFilterFactory;
+pub use network_simulation::NetworkSimulationFactory;
+pub use prioritize::PrioritizeFactory;
+pub use shadow::ShadowFactory;
+pub use static_metadata::StaticMetadataFactory;
+pub use token_quota::TokenQuotaFactory;
 pub use token_router::TokenRouterFactory;
+pub use transform::TransformFactory;
 
-mod capture_bytes;
-mod compress;
-mod concatenate_bytes;
-mod debug;
-mod load_balancer;
-mod local_rate_limit;
-mod token_router;
+pub mod capture_bytes;
+pub mod compress;
+pub mod concatenate_bytes;
+pub mod debug;
+pub mod fragment;
+pub mod handoff;
+pub mod jitter_buffer;
+pub mod load_balancer;
+pub mod local_rate_limit;
+pub mod network_simulation;
+pub mod prioritize;
+pub mod shadow;
+pub mod static_metadata;
+pub mod token_quota;
+pub mod token_router;
+pub mod transform;
 
 pub const CAPTURED_BYTES: &str = "quilkin.dev/captured_bytes";
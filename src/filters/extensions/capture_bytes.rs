@@ -24,16 +24,22 @@ use metrics::Metrics;
 
 use crate::filters::{extensions::CAPTURED_BYTES, prelude::*};
 use crate::map_proto_enum;
-use proto::quilkin::extensions::filters::capture_bytes::v1alpha1::{
-    capture_bytes::Strategy as ProtoStrategy, CaptureBytes as ProtoConfig,
+
+use self::quilkin::extensions::filters::capture_bytes::v1beta1::{
+    capture_bytes::Strategy as ProtoStrategy, capture_bytes::StrategyValue,
+    CaptureBytes as ProtoConfig,
 };
 
 mod metrics;
-mod proto;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+crate::include_proto!("quilkin.extensions.filters.capture_bytes.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::capture_bytes::v1beta1 as proto;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 /// Strategy to apply for acquiring a set of bytes in the UDP packet
-enum Strategy {
+pub enum Strategy {
     #[serde(rename = "PREFIX")]
     /// Looks for the set of bytes at the beginning of the packet
     Prefix,
@@ -42,20 +48,22 @@ enum Strategy {
     Suffix,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-struct Config {
+/// Config represents the configuration of the `CaptureBytes` filter.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Config {
+    /// where in the packet to capture the bytes from
     #[serde(default)]
-    strategy: Strategy,
+    pub strategy: Strategy,
     /// the number of bytes to capture
     #[serde(rename = "size")]
-    size: usize,
+    pub size: usize,
     /// the key to use when storing the captured bytes in the filter context
     #[serde(rename = "metadataKey")]
     #[serde(default = "default_metadata_key")]
-    metadata_key: String,
+    pub metadata_key: String,
     /// whether or not to remove the set of the bytes from the packet once captured
     #[serde(default = "default_remove")]
-    remove: bool,
+    pub remove: bool,
 }
 
 /// default value for [`Config::remove`].
@@ -101,6 +109,23 @@ impl TryFrom<ProtoConfig> for Config {
     }
 }
 
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        let strategy = match config.strategy {
+            Strategy::Prefix => ProtoStrategy::Prefix,
+            Strategy::Suffix => ProtoStrategy::Suffix,
+        };
+        Self {
+            strategy: Some(StrategyValue {
+                value: strategy as i32,
+            }),
+            size: config.size as u32,
+            metadata_key: Some(config.metadata_key),
+            remove: Some(config.remove),
+        }
+    }
+}
+
 pub struct CaptureBytesFactory {
     log: Logger,
 }
@@ -116,6 +141,14 @@ impl FilterFactory for CaptureBytesFactory {
         CaptureBytes::FILTER_NAME
     }
 
+    fn deprecated_names(&self) -> &'static [&'static str] {
+        &["quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes"]
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("capture_bytes/schema.yaml"))
+    }
+
     fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
         Ok(Box::new(CaptureBytes::new(
             &self.log,
@@ -126,7 +159,7 @@ impl FilterFactory for CaptureBytesFactory {
     }
 }
 
-#[crate::filter("quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes")]
+#[crate::filter("quilkin.extensions.filters.capture_bytes.v1beta1.CaptureBytes")]
 struct CaptureBytes {
     log: Logger,
     capture: Box<dyn Capture + Sync + Send>,
@@ -168,7 +201,7 @@ impl Filter for CaptureBytes {
                 );
             }
             self.metrics.packets_dropped_total.inc();
-            return None;
+            return drop_packet("PacketTooShort");
         }
         let token = self
             .capture
@@ -231,7 +264,7 @@ mod tests {
         Metrics, Prefix, Strategy, Suffix,
     };
 
-    use super::proto::quilkin::extensions::filters::capture_bytes::v1alpha1::{
+    use super::proto::{
         capture_bytes::{Strategy as ProtoStrategy, StrategyValue},
         CaptureBytes as ProtoConfig,
     };
@@ -310,6 +343,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            strategy: Strategy::Prefix,
+            size: 3,
+            metadata_key: TOKEN_KEY.into(),
+            remove: true,
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
     #[test]
     fn factory_valid_config_all() {
         let factory = CaptureBytesFactory::new(&logger());
@@ -13,11 +13,13 @@
  *  See the License for the specific language governing permissions and
  *  limitations under the License.
  */
+
 use prometheus::core::{AtomicU64, GenericCounter};
-use prometheus::Result as MetricsResult;
-use prometheus::{IntCounter, Registry};
+use prometheus::{Registry, Result as MetricsResult};
+
+use crate::filters::FilterMetrics;
 
-use crate::metrics::{filter_opts, CollectorExt};
+use super::CaptureBytes;
 
 /// Register and manage metrics for this filter
 pub(super) struct Metrics {
@@ -26,13 +28,12 @@ pub(super) struct Metrics {
 
 impl Metrics {
     pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let metrics = FilterMetrics::new(registry, CaptureBytes::FILTER_NAME);
         Ok(Metrics {
-            packets_dropped_total: IntCounter::with_opts(filter_opts(
+            packets_dropped_total: metrics.counter(
                 "packets_dropped",
-                "CaptureBytes",
                 "Total number of packets dropped due capture size being larger than the received packet",
-            ))?
-            .register(registry)?,
+            )?,
         })
     }
 }
@@ -14,35 +14,58 @@
  *  limitations under the License.
  */
 
-use std::convert::TryFrom;
+use std::convert::{TryFrom, TryInto};
 use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
+use base64_serde::base64_serde_type;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use slog::{o, warn, Logger};
 use snap::read::FrameDecoder;
 use snap::write::FrameEncoder;
 
-use self::quilkin::extensions::filters::compress::v1alpha1::{
-    compress::Action as ProtoAction, compress::Mode as ProtoMode, Compress as ProtoConfig,
+use self::quilkin::extensions::filters::compress::v1beta1::{
+    compress::Action as ProtoAction, compress::ActionValue, compress::Adaptive as ProtoAdaptive,
+    compress::Mode as ProtoMode, compress::ModeValue, compress::ZstdSettings as ProtoZstd,
+    Compress as ProtoConfig,
 };
 
 use crate::map_proto_enum;
 use crate::{
-    config::LOG_SAMPLING_RATE,
+    config::{LOG_SAMPLING_RATE, MAX_DATAGRAM_SIZE},
     filters::{extensions::compress::metrics::Metrics, prelude::*},
 };
+use adaptive::{
+    default_evaluation_interval, default_max_duration_per_packet, default_min_ratio,
+    default_sample_packets, Flows, COMPRESSED, UNCOMPRESSED,
+};
+
+pub use adaptive::AdaptiveConfig;
 
+mod adaptive;
 mod metrics;
 
-crate::include_proto!("quilkin.extensions.filters.compress.v1alpha1");
+crate::include_proto!("quilkin.extensions.filters.compress.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::compress::v1beta1 as proto;
+
+base64_serde_type!(Base64Standard, base64::STANDARD);
 
 /// The library to use when compressing
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 pub enum Mode {
-    // we only support one mode for now, but adding in the config option to provide the
-    // option to expand for later.
+    /// Compress with the Snappy frame format.
     #[serde(rename = "SNAPPY")]
     Snappy,
+    /// Compress with LZ4 blocks.
+    #[serde(rename = "LZ4")]
+    Lz4,
+    /// Compress with Zstandard.
+    #[serde(rename = "ZSTD")]
+    Zstd,
 }
 
 impl Default for Mode {
@@ -52,12 +75,15 @@ impl Default for Mode {
 }
 
 /// Whether to do nothing, compress or decompress the packet.
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-enum Action {
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Action {
+    /// Pass the packet through unchanged.
     #[serde(rename = "DO_NOTHING")]
     DoNothing,
+    /// Compress the packet.
     #[serde(rename = "COMPRESS")]
     Compress,
+    /// Decompress the packet.
     #[serde(rename = "DECOMPRESS")]
     Decompress,
 }
@@ -68,12 +94,97 @@ impl Default for Action {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-struct Config {
+/// Config represents the configuration of the `Compress` filter.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Config {
+    /// mode is the library used to compress packets.
     #[serde(default)]
-    mode: Mode,
-    on_read: Action,
-    on_write: Action,
+    pub mode: Mode,
+    /// on_read is what to do with packets received from clients.
+    pub on_read: Action,
+    /// on_write is what to do with packets received from endpoints.
+    pub on_write: Action,
+    /// adaptive, if provided, bypasses compression for flows whose packets
+    /// do not compress well.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<AdaptiveConfig>,
+    /// zstd tunes compression in `ZSTD` mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zstd: Option<ZstdConfig>,
+    /// skip_if_not_smaller, if true, sends packets uncompressed when
+    /// compressing them would not reduce their size.
+    #[serde(default)]
+    pub skip_if_not_smaller: bool,
+}
+
+/// ZstdConfig represents the settings of `ZSTD` mode.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ZstdConfig {
+    /// level is the compression level, trading speed for size.
+    #[serde(default = "default_zstd_level")]
+    pub level: i32,
+    /// dictionary, if not empty, is a dictionary shared by both ends of the
+    /// connection that small packets are compressed with.
+    #[serde(with = "Base64Standard", default, skip_serializing_if = "Vec::is_empty")]
+    pub dictionary: Vec<u8>,
+}
+
+/// default value for [`ZstdConfig::level`]
+fn default_zstd_level() -> i32 {
+    zstd::DEFAULT_COMPRESSION_LEVEL
+}
+
+impl From<ProtoZstd> for ZstdConfig {
+    fn from(p: ProtoZstd) -> Self {
+        Self {
+            level: p.level.unwrap_or_else(default_zstd_level),
+            dictionary: p.dictionary,
+        }
+    }
+}
+
+impl From<ZstdConfig> for ProtoZstd {
+    fn from(config: ZstdConfig) -> Self {
+        Self {
+            level: Some(config.level),
+            dictionary: config.dictionary,
+        }
+    }
+}
+
+impl TryFrom<ProtoAdaptive> for AdaptiveConfig {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoAdaptive) -> std::result::Result<Self, Self::Error> {
+        let duration = |value: Option<prost_types::Duration>, field: &str, default: Duration| {
+            value
+                .map(|value| {
+                    value.try_into().map_err(|err| {
+                        ConvertProtoConfigError::new(
+                            format!("invalid duration: {:?}", err),
+                            Some(format!("adaptive.{}", field)),
+                        )
+                    })
+                })
+                .transpose()
+                .map(|value| value.unwrap_or(default))
+        };
+
+        Ok(Self {
+            min_ratio: p.min_ratio.unwrap_or_else(default_min_ratio),
+            sample_packets: p.sample_packets.unwrap_or_else(default_sample_packets),
+            evaluation_interval: duration(
+                p.evaluation_interval,
+                "evaluation_interval",
+                default_evaluation_interval(),
+            )?,
+            max_duration_per_packet: duration(
+                p.max_duration_per_packet,
+                "max_duration_per_packet",
+                default_max_duration_per_packet(),
+            )?,
+        })
+    }
 }
 
 impl TryFrom<ProtoConfig> for Config {
@@ -88,7 +199,7 @@ impl TryFrom<ProtoConfig> for Config {
                     field = "mode",
                     proto_enum_type = ProtoMode,
                     target_enum_type = Mode,
-                    variants = [Snappy]
+                    variants = [Snappy, Lz4, Zstd]
                 )
             })
             .transpose()?
@@ -126,10 +237,63 @@ impl TryFrom<ProtoConfig> for Config {
             mode,
             on_read,
             on_write,
+            adaptive: p.adaptive.map(AdaptiveConfig::try_from).transpose()?,
+            zstd: p.zstd.map(ZstdConfig::from),
+            skip_if_not_smaller: p.skip_if_not_smaller.unwrap_or(false),
         })
     }
 }
 
+impl From<AdaptiveConfig> for ProtoAdaptive {
+    fn from(config: AdaptiveConfig) -> Self {
+        Self {
+            min_ratio: Some(config.min_ratio),
+            sample_packets: Some(config.sample_packets),
+            evaluation_interval: Some(config.evaluation_interval.into()),
+            max_duration_per_packet: Some(config.max_duration_per_packet.into()),
+        }
+    }
+}
+
+impl From<Mode> for ModeValue {
+    fn from(mode: Mode) -> Self {
+        let value = match mode {
+            Mode::Snappy => ProtoMode::Snappy,
+            Mode::Lz4 => ProtoMode::Lz4,
+            Mode::Zstd => ProtoMode::Zstd,
+        };
+        Self {
+            value: value as i32,
+        }
+    }
+}
+
+impl From<Action> for ActionValue {
+    fn from(action: Action) -> Self {
+        let value = match action {
+            Action::DoNothing => ProtoAction::DoNothing,
+            Action::Compress => ProtoAction::Compress,
+            Action::Decompress => ProtoAction::Decompress,
+        };
+        Self {
+            value: value as i32,
+        }
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            mode: Some(config.mode.into()),
+            on_read: Some(config.on_read.into()),
+            on_write: Some(config.on_write.into()),
+            adaptive: config.adaptive.map(ProtoAdaptive::from),
+            zstd: config.zstd.map(ProtoZstd::from),
+            skip_if_not_smaller: Some(config.skip_if_not_smaller),
+        }
+    }
+}
+
 pub struct CompressFactory {
     log: Logger,
 }
@@ -145,18 +309,62 @@ impl FilterFactory for CompressFactory {
         Compress::FILTER_NAME
     }
 
+    fn deprecated_names(&self) -> &'static [&'static str] {
+        &["quilkin.extensions.filters.compress.v1alpha1.Compress"]
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("compress/schema.yaml"))
+    }
+
     fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config: Config = self
+            .require_config(args.config)?
+            .deserialize::<Config, ProtoConfig>(self.name())?;
+
+        if let Some(adaptive) = &config.adaptive {
+            if adaptive.min_ratio.is_nan() || adaptive.min_ratio <= 0.0 {
+                return Err(Error::FieldInvalid {
+                    field: "adaptive.min_ratio".into(),
+                    reason: "value must be greater than 0".into(),
+                });
+            }
+            if adaptive.sample_packets == 0 {
+                return Err(Error::FieldInvalid {
+                    field: "adaptive.sample_packets".into(),
+                    reason: "value must be greater than 0".into(),
+                });
+            }
+        }
+
+        if let Some(settings) = &config.zstd {
+            if config.mode != Mode::Zstd {
+                return Err(Error::FieldInvalid {
+                    field: "zstd".into(),
+                    reason: "value can only be set in ZSTD mode".into(),
+                });
+            }
+            if !zstd::compression_level_range().contains(&settings.level) {
+                return Err(Error::FieldInvalid {
+                    field: "zstd.level".into(),
+                    reason: format!(
+                        "value must be within {:?}",
+                        zstd::compression_level_range()
+                    ),
+                });
+            }
+        }
+
         Ok(Box::new(Compress::new(
             &self.log,
-            self.require_config(args.config)?
-                .deserialize::<Config, ProtoConfig>(self.name())?,
+            config,
             Metrics::new(&args.metrics_registry)?,
-        )))
+        )?))
     }
 }
 
 /// Filter for compressing and decompressing packet data
-#[crate::filter("quilkin.extensions.filters.compress.v1alpha1.Compress")]
+#[crate::filter("quilkin.extensions.filters.compress.v1beta1.Compress")]
 struct Compress {
     log: Logger,
     metrics: Metrics,
@@ -164,21 +372,46 @@ struct Compress {
     on_read: Action,
     on_write: Action,
     compressor: Box<dyn Compressor + Sync + Send>,
+    /// In adaptive mode, decides which flows to compress for packets
+    /// that are read and written respectively.
+    adaptive_flows: Option<(Flows, Flows)>,
+    skip_if_not_smaller: bool,
 }
 
 impl Compress {
-    pub fn new(base: &Logger, config: Config, metrics: Metrics) -> Self {
-        let compressor = match config.mode {
+    pub fn new(base: &Logger, config: Config, metrics: Metrics) -> Result<Self, Error> {
+        let compressor: Box<dyn Compressor + Sync + Send> = match config.mode {
             Mode::Snappy => Box::new(Snappy {}),
+            Mode::Lz4 => Box::new(Lz4 {}),
+            Mode::Zstd => {
+                let zstd = config.zstd.unwrap_or_else(|| ZstdConfig {
+                    level: default_zstd_level(),
+                    dictionary: vec![],
+                });
+                Box::new(Zstd::new(zstd).map_err(|err| Error::FieldInvalid {
+                    field: "zstd.dictionary".into(),
+                    reason: err.to_string(),
+                })?)
+            }
         };
-        Compress {
+        Ok(Compress {
             log: base.new(o!("source" => "extensions::Compress")),
             metrics,
             compression_mode: config.mode,
             on_read: config.on_read,
             on_write: config.on_write,
             compressor,
-        }
+            adaptive_flows: config
+                .adaptive
+                .map(|adaptive| (Flows::new(adaptive), Flows::new(adaptive))),
+            skip_if_not_smaller: config.skip_if_not_smaller,
+        })
+    }
+
+    /// Returns whether a header is prepended to compressed packets, recording
+    /// whether their contents are compressed.
+    fn has_header(&self) -> bool {
+        self.adaptive_flows.is_some() || self.skip_if_not_smaller
     }
 
     /// Track a failed attempt at compression
@@ -189,7 +422,7 @@ impl Compress {
                             "count" => self.metrics.packets_dropped_compress.get());
         }
         self.metrics.packets_dropped_compress.inc();
-        None
+        drop_packet("Compress")
     }
 
     /// Track a failed attempt at decompression
@@ -200,72 +433,148 @@ impl Compress {
                             "count" => self.metrics.packets_dropped_decompress.get());
         }
         self.metrics.packets_dropped_decompress.inc();
-        None
+        drop_packet("Decompress")
     }
 }
 
 impl Filter for Compress {
     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
-        let original_size = ctx.contents.len();
-
-        match self.on_read {
-            Action::Compress => match self.compressor.encode(&mut ctx.contents) {
-                Ok(()) => {
-                    self.metrics
-                        .decompressed_bytes_total
-                        .inc_by(original_size as u64);
-                    self.metrics
-                        .compressed_bytes_total
-                        .inc_by(ctx.contents.len() as u64);
-                    Some(ctx.into())
+        let flows = self.adaptive_flows.as_ref().map(|(read, _)| read);
+        self.apply(&self.on_read, flows, ctx.from, &mut ctx.contents)?;
+        Some(ctx.into())
+    }
+
+    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+        let flows = self.adaptive_flows.as_ref().map(|(_, write)| write);
+        self.apply(&self.on_write, flows, ctx.to, &mut ctx.contents)?;
+        Some(ctx.into())
+    }
+}
+
+impl Compress {
+    /// Applies `action` to a packet of `flow`, returning `None` if the packet
+    /// should be dropped.
+    fn apply(
+        &self,
+        action: &Action,
+        flows: Option<&Flows>,
+        flow: SocketAddr,
+        contents: &mut Vec<u8>,
+    ) -> Option<()> {
+        let original_size = contents.len();
+        match action {
+            Action::Compress => match self.encode(flows, flow, contents) {
+                Ok(compressed) => {
+                    if compressed {
+                        self.metrics
+                            .decompressed_bytes_total
+                            .inc_by(original_size as u64);
+                        self.metrics
+                            .compressed_bytes_total
+                            .inc_by(contents.len() as u64);
+                        if original_size > 0 {
+                            self.metrics
+                                .compression_ratio
+                                .observe(contents.len() as f64 / original_size as f64);
+                        }
+                    }
+                    Some(())
                 }
                 Err(err) => self.failed_compression(err),
             },
-            Action::Decompress => match self.compressor.decode(&mut ctx.contents) {
-                Ok(()) => {
-                    self.metrics
-                        .compressed_bytes_total
-                        .inc_by(original_size as u64);
-                    self.metrics
-                        .decompressed_bytes_total
-                        .inc_by(ctx.contents.len() as u64);
-                    Some(ctx.into())
+            Action::Decompress => match self.decode(contents) {
+                Ok(decompressed) => {
+                    if decompressed {
+                        self.metrics
+                            .compressed_bytes_total
+                            .inc_by(original_size as u64);
+                        self.metrics
+                            .decompressed_bytes_total
+                            .inc_by(contents.len() as u64);
+                    }
+                    Some(())
                 }
                 Err(err) => self.failed_decompression(err),
             },
-            Action::DoNothing => Some(ctx.into()),
+            Action::DoNothing => Some(()),
         }
     }
 
-    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
-        let original_size = ctx.contents.len();
-        match self.on_write {
-            Action::Compress => match self.compressor.encode(&mut ctx.contents) {
-                Ok(()) => {
-                    self.metrics
-                        .decompressed_bytes_total
-                        .inc_by(original_size as u64);
-                    self.metrics
-                        .compressed_bytes_total
-                        .inc_by(ctx.contents.len() as u64);
-                    Some(ctx.into())
-                }
-                Err(err) => self.failed_compression(err),
-            },
-            Action::Decompress => match self.compressor.decode(&mut ctx.contents) {
-                Ok(()) => {
-                    self.metrics
-                        .compressed_bytes_total
-                        .inc_by(original_size as u64);
-                    self.metrics
-                        .decompressed_bytes_total
-                        .inc_by(ctx.contents.len() as u64);
-                    Some(ctx.into())
-                }
+    /// Compresses `contents` unless adaptive mode decides to bypass `flow`,
+    /// or compressing them would not reduce their size and
+    /// `skip_if_not_smaller` is set. In either mode, a header is prepended
+    /// that records whether the contents are compressed. Returns whether the
+    /// contents were compressed.
+    fn encode(
+        &self,
+        flows: Option<&Flows>,
+        flow: SocketAddr,
+        contents: &mut Vec<u8>,
+    ) -> Result<bool> {
+        if !self.has_header() {
+            self.timed_encode(contents)?;
+            return Ok(true);
+        }
 
-                Err(err) => self.failed_decompression(err),
-            },
-            Action::DoNothing => Some(ctx.into()),
+        if let Some(flows) = flows {
+            if !flows.should_compress(flow) {
+                self.metrics.packets_bypassed_total.inc();
+                contents.insert(0, UNCOMPRESSED);
+                return Ok(false);
+            }
+        }
+
+        let original = if self.skip_if_not_smaller {
+            Some(contents.clone())
+        } else {
+            None
+        };
+        let original_size = contents.len();
+        let duration = self.timed_encode(contents)?;
+        if let Some(flows) = flows {
+            flows.record(flow, original_size, contents.len(), duration);
+        }
+        if let Some(original) = original.filter(|_| contents.len() >= original_size) {
+            self.metrics.packets_not_smaller_total.inc();
+            *contents = original;
+            contents.insert(0, UNCOMPRESSED);
+            return Ok(false);
+        }
+        contents.insert(0, COMPRESSED);
+        Ok(true)
+    }
+
+    /// Compresses `contents`, recording and returning how long it took.
+    fn timed_encode(&self, contents: &mut Vec<u8>) -> Result<Duration> {
+        let start = Instant::now();
+        self.compressor.encode(contents)?;
+        let duration = start.elapsed();
+        self.metrics
+            .compression_duration_seconds
+            .observe(duration.as_secs_f64());
+        Ok(duration)
+    }
+
+    /// Decompresses `contents`. If packets have a header, the contents are
+    /// only decompressed if their header says so. Returns whether the
+    /// contents were decompressed.
+    fn decode(&self, contents: &mut Vec<u8>) -> Result<bool> {
+        if !self.has_header() {
+            self.compressor.decode(contents)?;
+            return Ok(true);
+        }
+
+        match contents.first().copied() {
+            Some(UNCOMPRESSED) => {
+                contents.remove(0);
+                Ok(false)
+            }
+            Some(COMPRESSED) => {
+                contents.remove(0);
+                self.compressor.decode(contents)?;
+                Ok(true)
+            }
+            _ => Err("missing or invalid compression header".into()),
         }
     }
 }
@@ -300,9 +609,60 @@ impl Compressor for Snappy {
     }
 }
 
+struct Lz4 {}
+
+impl Compressor for Lz4 {
+    fn encode(&self, contents: &mut Vec<u8>) -> Result<()> {
+        *contents = lz4_flex::compress_prepend_size(contents);
+        Ok(())
+    }
+
+    fn decode(&self, contents: &mut Vec<u8>) -> Result<()> {
+        *contents = lz4_flex::decompress_size_prepended(contents)?;
+        Ok(())
+    }
+}
+
+/// Compresses with zstd, reusing its compression and decompression contexts
+/// so that the dictionary, if any, is only loaded once.
+struct Zstd {
+    compressor: Mutex<zstd::bulk::Compressor<'static>>,
+    decompressor: Mutex<zstd::bulk::Decompressor<'static>>,
+}
+
+impl Zstd {
+    fn new(config: ZstdConfig) -> io::Result<Self> {
+        Ok(Self {
+            compressor: Mutex::new(zstd::bulk::Compressor::with_dictionary(
+                config.level,
+                &config.dictionary,
+            )?),
+            decompressor: Mutex::new(zstd::bulk::Decompressor::with_dictionary(
+                &config.dictionary,
+            )?),
+        })
+    }
+}
+
+impl Compressor for Zstd {
+    fn encode(&self, contents: &mut Vec<u8>) -> Result<()> {
+        *contents = self.compressor.lock().compress(contents)?;
+        Ok(())
+    }
+
+    fn decode(&self, contents: &mut Vec<u8>) -> Result<()> {
+        *contents = self
+            .decompressor
+            .lock()
+            .decompress(contents, MAX_DATAGRAM_SIZE)?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::convert::TryFrom;
+    use std::time::Duration;
 
     use prometheus::Registry;
     use serde_yaml::{Mapping, Value};
@@ -315,11 +675,18 @@ mod tests {
     };
     use crate::test_utils::logger;
 
-    use super::quilkin::extensions::filters::compress::v1alpha1::{
-        compress::{Action as ProtoAction, ActionValue, Mode as ProtoMode, ModeValue},
+    use super::quilkin::extensions::filters::compress::v1beta1::{
+        compress::{
+            Action as ProtoAction, ActionValue, Adaptive as ProtoAdaptive, Mode as ProtoMode,
+            ModeValue, ZstdSettings as ProtoZstd,
+        },
         Compress as ProtoConfig,
     };
-    use super::{Action, Compress, CompressFactory, Config, Metrics, Mode, Snappy};
+    use super::{
+        default_max_duration_per_packet, default_sample_packets, default_zstd_level, Action,
+        AdaptiveConfig, Compress, CompressFactory, Config, Lz4, Metrics, Mode, Snappy, Zstd,
+        ZstdConfig, COMPRESSED, UNCOMPRESSED,
+    };
 
     #[test]
     fn convert_proto_config() {
@@ -336,11 +703,84 @@ mod tests {
                     on_write: Some(ActionValue {
                         value: ProtoAction::Decompress as i32,
                     }),
+                    adaptive: None,
+                    zstd: None,
+                    skip_if_not_smaller: None,
                 },
                 Some(Config {
                     mode: Mode::Snappy,
                     on_read: Action::Compress,
                     on_write: Action::Decompress,
+                    adaptive: None,
+                    zstd: None,
+                    skip_if_not_smaller: false,
+                }),
+            ),
+            (
+                "should convert adaptive config",
+                ProtoConfig {
+                    mode: None,
+                    on_read: Some(ActionValue {
+                        value: ProtoAction::Compress as i32,
+                    }),
+                    on_write: Some(ActionValue {
+                        value: ProtoAction::Decompress as i32,
+                    }),
+                    adaptive: Some(ProtoAdaptive {
+                        min_ratio: Some(1.5),
+                        sample_packets: None,
+                        evaluation_interval: Some(prost_types::Duration {
+                            seconds: 10,
+                            nanos: 0,
+                        }),
+                        max_duration_per_packet: None,
+                    }),
+                    zstd: None,
+                    skip_if_not_smaller: None,
+                },
+                Some(Config {
+                    mode: Mode::default(),
+                    on_read: Action::Compress,
+                    on_write: Action::Decompress,
+                    adaptive: Some(AdaptiveConfig {
+                        min_ratio: 1.5,
+                        sample_packets: default_sample_packets(),
+                        evaluation_interval: Duration::from_secs(10),
+                        max_duration_per_packet: default_max_duration_per_packet(),
+                    }),
+                    zstd: None,
+                    skip_if_not_smaller: false,
+                }),
+            ),
+            (
+                "should convert zstd config",
+                ProtoConfig {
+                    mode: Some(ModeValue {
+                        value: ProtoMode::Zstd as i32,
+                    }),
+                    on_read: Some(ActionValue {
+                        value: ProtoAction::Compress as i32,
+                    }),
+                    on_write: Some(ActionValue {
+                        value: ProtoAction::Decompress as i32,
+                    }),
+                    adaptive: None,
+                    zstd: Some(ProtoZstd {
+                        level: None,
+                        dictionary: b"hello".to_vec(),
+                    }),
+                    skip_if_not_smaller: Some(true),
+                },
+                Some(Config {
+                    mode: Mode::Zstd,
+                    on_read: Action::Compress,
+                    on_write: Action::Decompress,
+                    adaptive: None,
+                    zstd: Some(ZstdConfig {
+                        level: default_zstd_level(),
+                        dictionary: b"hello".to_vec(),
+                    }),
+                    skip_if_not_smaller: true,
                 }),
             ),
             (
@@ -353,6 +793,9 @@ mod tests {
                     on_write: Some(ActionValue {
                         value: ProtoAction::Decompress as i32,
                     }),
+                    adaptive: None,
+                    zstd: None,
+                    skip_if_not_smaller: None,
                 },
                 None,
             ),
@@ -366,6 +809,9 @@ mod tests {
                     on_write: Some(ActionValue {
                         value: ProtoAction::Decompress as i32,
                     }),
+                    adaptive: None,
+                    zstd: None,
+                    skip_if_not_smaller: None,
                 },
                 None,
             ),
@@ -379,6 +825,9 @@ mod tests {
                         value: ProtoAction::Decompress as i32,
                     }),
                     on_write: Some(ActionValue { value: 73 }),
+                    adaptive: None,
+                    zstd: None,
+                    skip_if_not_smaller: None,
                 },
                 None,
             ),
@@ -388,11 +837,17 @@ mod tests {
                     mode: None,
                     on_read: None,
                     on_write: None,
+                    adaptive: None,
+                    zstd: None,
+                    skip_if_not_smaller: None,
                 },
                 Some(Config {
                     mode: Mode::default(),
                     on_read: Action::default(),
                     on_write: Action::default(),
+                    adaptive: None,
+                    zstd: None,
+                    skip_if_not_smaller: false,
                 }),
             ),
         ];
@@ -410,6 +865,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            mode: Mode::Zstd,
+            on_read: Action::Compress,
+            on_write: Action::Decompress,
+            adaptive: Some(AdaptiveConfig {
+                min_ratio: 1.5,
+                sample_packets: 10,
+                evaluation_interval: Duration::from_millis(1500),
+                max_duration_per_packet: Duration::from_micros(500),
+            }),
+            zstd: Some(ZstdConfig {
+                level: 3,
+                dictionary: b"hello".to_vec(),
+            }),
+            skip_if_not_smaller: true,
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
     #[test]
     fn default_mode_factory() {
         let log = logger();
@@ -462,9 +941,13 @@ mod tests {
                 mode: Default::default(),
                 on_read: Action::Compress,
                 on_write: Action::Decompress,
+                adaptive: None,
+                zstd: None,
+                skip_if_not_smaller: false,
             },
             Metrics::new(&Registry::default()).unwrap(),
-        );
+        )
+        .unwrap();
         let expected = contents_fixture();
 
         // read compress
@@ -496,6 +979,11 @@ mod tests {
             read_response.contents.len() as u64,
             compress.metrics.compressed_bytes_total.get()
         );
+        assert_eq!(1, compress.metrics.compression_ratio.get_sample_count());
+        assert_eq!(
+            read_response.contents.len() as f64 / expected.len() as f64,
+            compress.metrics.compression_ratio.get_sample_sum()
+        );
 
         // write decompress
         let write_response = compress
@@ -531,9 +1019,13 @@ mod tests {
                 mode: Default::default(),
                 on_read: Action::Decompress,
                 on_write: Action::Compress,
+                adaptive: None,
+                zstd: None,
+                skip_if_not_smaller: false,
             },
             Metrics::new(&Registry::default()).unwrap(),
-        );
+        )
+        .unwrap();
 
         let (expected, compressed) = assert_downstream(&compress);
 
@@ -560,9 +1052,13 @@ mod tests {
                 mode: Default::default(),
                 on_read: Action::Compress,
                 on_write: Action::Decompress,
+                adaptive: None,
+                zstd: None,
+                skip_if_not_smaller: false,
             },
             Metrics::new(&Registry::default()).unwrap(),
-        );
+        )
+        .unwrap();
 
         let write_response = compression.write(WriteContext::new(
             &Endpoint::from_address("127.0.0.1:80".parse().unwrap()),
@@ -581,9 +1077,13 @@ mod tests {
                 mode: Default::default(),
                 on_read: Action::Decompress,
                 on_write: Action::Compress,
+                adaptive: None,
+                zstd: None,
+                skip_if_not_smaller: false,
             },
             Metrics::new(&Registry::default()).unwrap(),
-        );
+        )
+        .unwrap();
 
         let read_response = compression.read(ReadContext::new(
             UpstreamEndpoints::from(
@@ -612,9 +1112,13 @@ mod tests {
                 mode: Default::default(),
                 on_read: Action::default(),
                 on_write: Action::default(),
+                adaptive: None,
+                zstd: None,
+                skip_if_not_smaller: false,
             },
             Metrics::new(&Registry::default()).unwrap(),
-        );
+        )
+        .unwrap();
 
         let read_response = compression.read(ReadContext::new(
             UpstreamEndpoints::from(
@@ -638,6 +1142,191 @@ mod tests {
         assert_eq!(b"hello".to_vec(), write_response.unwrap().contents)
     }
 
+    #[test]
+    fn adaptive() {
+        let log = logger();
+        let config = || Config {
+            mode: Default::default(),
+            on_read: Action::Compress,
+            on_write: Action::Decompress,
+            adaptive: Some(AdaptiveConfig {
+                min_ratio: 1.1,
+                sample_packets: 2,
+                evaluation_interval: Duration::from_secs(60),
+                max_duration_per_packet: Duration::from_secs(1),
+            }),
+            zstd: None,
+            skip_if_not_smaller: false,
+        };
+        let compress = Compress::new(&log, config(), Metrics::new(&Registry::default()).unwrap())
+            .unwrap();
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        let decompress = Compress::new(
+            &log,
+            Config {
+                on_read: Action::Decompress,
+                on_write: Action::Compress,
+                ..config()
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+        .unwrap();
+
+        // Compressible packets stay compressed after sampling.
+        let compressible = contents_fixture();
+        for _ in 0..3 {
+            let compressed = compress
+                .read(read_context(compressible.clone()))
+                .expect("should compress")
+                .contents;
+            assert_eq!(COMPRESSED, compressed[0]);
+            assert!(compressible.len() > compressed.len());
+
+            let write_response = decompress
+                .write(write_context(&endpoint, compressed))
+                .expect("should decompress");
+            assert_eq!(compressible, write_response.contents);
+        }
+        assert_eq!(0, compress.metrics.packets_bypassed_total.get());
+
+        // Incompressible packets are bypassed after sampling.
+        let compress = Compress::new(&log, config(), Metrics::new(&Registry::default()).unwrap())
+            .unwrap();
+        let incompressible = (0..1000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        for _ in 0..2 {
+            let compressed = compress
+                .read(read_context(incompressible.clone()))
+                .expect("should compress")
+                .contents;
+            assert_eq!(COMPRESSED, compressed[0]);
+        }
+        let bypassed = compress
+            .read(read_context(incompressible.clone()))
+            .expect("should bypass compression")
+            .contents;
+        assert_eq!(UNCOMPRESSED, bypassed[0]);
+        assert_eq!(incompressible, bypassed[1..].to_vec());
+        assert_eq!(1, compress.metrics.packets_bypassed_total.get());
+
+        let write_response = decompress
+            .write(write_context(&endpoint, bypassed))
+            .expect("should pass through");
+        assert_eq!(incompressible, write_response.contents);
+
+        // Packets without a valid header are dropped.
+        assert!(decompress.write(write_context(&endpoint, vec![42])).is_none());
+        assert_eq!(1, decompress.metrics.packets_dropped_decompress.get());
+    }
+
+    #[test]
+    fn adaptive_factory_invalid_config() {
+        let log = logger();
+        let factory = CompressFactory::new(&log);
+        let config: Value = serde_yaml::from_str(
+            "
+on_read: COMPRESS
+on_write: DECOMPRESS
+adaptive:
+  sample_packets: 0
+",
+        )
+        .unwrap();
+
+        let result =
+            factory.create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn zstd_factory_invalid_config() {
+        let log = logger();
+        let factory = CompressFactory::new(&log);
+        for config in &[
+            "
+on_read: COMPRESS
+on_write: DECOMPRESS
+zstd:
+  level: 3
+",
+            "
+on_read: COMPRESS
+on_write: DECOMPRESS
+mode: ZSTD
+zstd:
+  level: 1000
+",
+        ] {
+            let config: Value = serde_yaml::from_str(config).unwrap();
+            let result =
+                factory.create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)));
+            assert!(result.is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn skip_if_not_smaller() {
+        let log = logger();
+        let config = || Config {
+            mode: Default::default(),
+            on_read: Action::Compress,
+            on_write: Action::Decompress,
+            adaptive: None,
+            zstd: None,
+            skip_if_not_smaller: true,
+        };
+        let compress = Compress::new(&log, config(), Metrics::new(&Registry::default()).unwrap())
+            .unwrap();
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+
+        // Packets that get smaller are compressed.
+        let compressible = contents_fixture();
+        let compressed = compress
+            .read(read_context(compressible.clone()))
+            .expect("should compress")
+            .contents;
+        assert_eq!(COMPRESSED, compressed[0]);
+        assert!(compressible.len() > compressed.len());
+        let write_response = compress
+            .write(write_context(&endpoint, compressed))
+            .expect("should decompress");
+        assert_eq!(compressible, write_response.contents);
+
+        // Packets that would grow are sent as they are.
+        let skipped = compress
+            .read(read_context(b"hello".to_vec()))
+            .expect("should pass through")
+            .contents;
+        assert_eq!(UNCOMPRESSED, skipped[0]);
+        assert_eq!(b"hello".to_vec(), skipped[1..].to_vec());
+        assert_eq!(1, compress.metrics.packets_not_smaller_total.get());
+        let write_response = compress
+            .write(write_context(&endpoint, skipped))
+            .expect("should pass through");
+        assert_eq!(b"hello".to_vec(), write_response.contents);
+    }
+
+    fn read_context(contents: Vec<u8>) -> ReadContext {
+        ReadContext::new(
+            UpstreamEndpoints::from(
+                Endpoints::new(vec![Endpoint::from_address(
+                    "127.0.0.1:80".parse().unwrap(),
+                )])
+                .unwrap(),
+            ),
+            "127.0.0.1:8080".parse().unwrap(),
+            contents,
+        )
+    }
+
+    fn write_context(endpoint: &Endpoint, contents: Vec<u8>) -> WriteContext {
+        WriteContext::new(
+            endpoint,
+            "127.0.0.1:80".parse().unwrap(),
+            "127.0.0.1:8080".parse().unwrap(),
+            contents,
+        )
+    }
+
     #[test]
     fn snappy() {
         let expected = contents_fixture();
@@ -669,6 +1358,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn lz4() {
+        assert_round_trip(&Lz4 {});
+    }
+
+    #[test]
+    fn zstd() {
+        assert_round_trip(
+            &Zstd::new(ZstdConfig {
+                level: default_zstd_level(),
+                dictionary: vec![],
+            })
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn zstd_dictionary() {
+        let zstd = || {
+            Zstd::new(ZstdConfig {
+                level: default_zstd_level(),
+                dictionary: b"hello my name is mark and I like to do things".to_vec(),
+            })
+            .unwrap()
+        };
+        let expected = b"my name is mark and I like to do things".to_vec();
+        let mut contents = expected.clone();
+        zstd().encode(&mut contents).unwrap();
+        assert!(
+            expected.len() > contents.len(),
+            "Original: {}. Compressed: {}",
+            expected.len(),
+            contents.len()
+        );
+
+        // Packets are decompressed with the same dictionary on the other end.
+        zstd().decode(&mut contents).unwrap();
+        assert_eq!(expected, contents);
+    }
+
+    fn assert_round_trip(compressor: &dyn Compressor) {
+        let expected = contents_fixture();
+        let mut contents = expected.clone();
+
+        compressor.encode(&mut contents).unwrap();
+        assert!(
+            expected.len() > contents.len(),
+            "Original: {}. Compressed: {}",
+            expected.len(),
+            contents.len()
+        );
+
+        compressor.decode(&mut contents).unwrap();
+        assert_eq!(expected, contents);
+    }
+
     /// At small data packets, compression will add data, so let's give a bigger data packet!
     fn contents_fixture() -> Vec<u8> {
         String::from("hello my name is mark and I like to do things")
@@ -0,0 +1,276 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// Prepended to a packet in adaptive mode, or when skipping packets that
+/// don't get smaller, if its contents are not compressed.
+pub(super) const UNCOMPRESSED: u8 = 0;
+/// Prepended to a packet in adaptive mode, or when skipping packets that
+/// don't get smaller, if its contents are compressed.
+pub(super) const COMPRESSED: u8 = 1;
+
+/// The maximum number of flows tracked in each direction. Once reached, flows
+/// that haven't been seen within an evaluation interval are forgotten.
+const MAX_TRACKED_FLOWS: usize = 10_000;
+
+/// AdaptiveConfig represents the configuration of adaptive compression.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AdaptiveConfig {
+    /// min_ratio is the minimum ratio of uncompressed to compressed bytes that
+    /// a flow must achieve for its packets to be compressed.
+    #[serde(default = "default_min_ratio")]
+    pub min_ratio: f64,
+    /// sample_packets is the number of packets in a flow that are compressed
+    /// to measure its compression ratio.
+    #[serde(default = "default_sample_packets")]
+    pub sample_packets: u32,
+    /// evaluation_interval is how long the decision to compress or bypass a
+    /// flow lasts before the flow is sampled again.
+    #[serde(with = "humantime_serde", default = "default_evaluation_interval")]
+    pub evaluation_interval: Duration,
+    /// max_duration_per_packet is the longest that compressing a flow's
+    /// packets can take on average for them to be compressed.
+    #[serde(with = "humantime_serde", default = "default_max_duration_per_packet")]
+    pub max_duration_per_packet: Duration,
+}
+
+/// default value for [`AdaptiveConfig::min_ratio`]
+pub(super) fn default_min_ratio() -> f64 {
+    1.1
+}
+
+/// default value for [`AdaptiveConfig::sample_packets`]
+pub(super) fn default_sample_packets() -> u32 {
+    100
+}
+
+/// default value for [`AdaptiveConfig::evaluation_interval`]
+pub(super) fn default_evaluation_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// default value for [`AdaptiveConfig::max_duration_per_packet`]
+pub(super) fn default_max_duration_per_packet() -> Duration {
+    Duration::from_millis(1)
+}
+
+/// Decides whether to compress the packets of each flow based on the
+/// compression ratio achieved for a sample of its packets, and the time
+/// taken to compress them.
+pub(super) struct Flows {
+    config: AdaptiveConfig,
+    flows: Mutex<HashMap<SocketAddr, Flow>>,
+}
+
+struct Flow {
+    state: State,
+    last_seen: Instant,
+}
+
+enum State {
+    Sampling {
+        packets: u32,
+        uncompressed_bytes: u64,
+        compressed_bytes: u64,
+        duration: Duration,
+    },
+    Compressing {
+        until: Instant,
+    },
+    Bypassing {
+        until: Instant,
+    },
+}
+
+impl State {
+    fn sampling() -> Self {
+        State::Sampling {
+            packets: 0,
+            uncompressed_bytes: 0,
+            compressed_bytes: 0,
+            duration: Duration::from_secs(0),
+        }
+    }
+}
+
+impl Flows {
+    pub(super) fn new(config: AdaptiveConfig) -> Self {
+        Self {
+            config,
+            flows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether the next packet of `flow` should be compressed.
+    pub(super) fn should_compress(&self, flow: SocketAddr) -> bool {
+        let now = Instant::now();
+        let mut flows = self.flows.lock();
+
+        if flows.len() >= MAX_TRACKED_FLOWS && !flows.contains_key(&flow) {
+            let evaluation_interval = self.config.evaluation_interval;
+            flows.retain(|_, flow| now.duration_since(flow.last_seen) < evaluation_interval);
+            if flows.len() >= MAX_TRACKED_FLOWS {
+                // Compress untracked flows, as we would have done without adaptive mode.
+                return true;
+            }
+        }
+
+        let entry = flows.entry(flow).or_insert_with(|| Flow {
+            state: State::sampling(),
+            last_seen: now,
+        });
+        entry.last_seen = now;
+
+        match entry.state {
+            State::Sampling { .. } => true,
+            State::Compressing { until } | State::Bypassing { until } if until <= now => {
+                entry.state = State::sampling();
+                true
+            }
+            State::Compressing { .. } => true,
+            State::Bypassing { .. } => false,
+        }
+    }
+
+    /// Records the size of a packet of `flow` before and after compression,
+    /// and how long compressing it took. Once enough packets have been
+    /// sampled, decides whether to keep compressing the flow's packets.
+    pub(super) fn record(
+        &self,
+        flow: SocketAddr,
+        uncompressed_size: usize,
+        compressed_size: usize,
+        duration: Duration,
+    ) {
+        let mut flows = self.flows.lock();
+        let entry = match flows.get_mut(&flow) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let compress = match &mut entry.state {
+            State::Sampling {
+                packets,
+                uncompressed_bytes,
+                compressed_bytes,
+                duration: total_duration,
+            } => {
+                *packets += 1;
+                *uncompressed_bytes += uncompressed_size as u64;
+                *compressed_bytes += compressed_size as u64;
+                *total_duration += duration;
+
+                if *packets < self.config.sample_packets {
+                    return;
+                }
+                *uncompressed_bytes as f64 / (*compressed_bytes).max(1) as f64
+                    >= self.config.min_ratio
+                    && *total_duration / *packets <= self.config.max_duration_per_packet
+            }
+            _ => return,
+        };
+
+        let until = Instant::now() + self.config.evaluation_interval;
+        entry.state = if compress {
+            State::Compressing { until }
+        } else {
+            State::Bypassing { until }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::{AdaptiveConfig, Flows};
+
+    fn flows(evaluation_interval: Duration) -> Flows {
+        Flows::new(AdaptiveConfig {
+            min_ratio: 2.0,
+            sample_packets: 2,
+            evaluation_interval,
+            max_duration_per_packet: Duration::from_micros(100),
+        })
+    }
+
+    /// A time within the maximum time to compress a packet.
+    const FAST: Duration = Duration::from_micros(10);
+
+    #[test]
+    fn compress_flow_above_min_ratio() {
+        let flows = flows(Duration::from_secs(60));
+        let flow: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        for _ in 0..2 {
+            assert!(flows.should_compress(flow));
+            flows.record(flow, 100, 10, FAST);
+        }
+        assert!(flows.should_compress(flow));
+    }
+
+    #[test]
+    fn bypass_flow_below_min_ratio() {
+        let flows = flows(Duration::from_secs(60));
+        let flow: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+
+        for _ in 0..2 {
+            assert!(flows.should_compress(flow));
+            flows.record(flow, 100, 90, FAST);
+        }
+        assert!(!flows.should_compress(flow));
+
+        // Other flows are unaffected.
+        assert!(flows.should_compress(other));
+    }
+
+    #[test]
+    fn sample_again_after_evaluation_interval() {
+        let flows = flows(Duration::from_millis(10));
+        let flow: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        for _ in 0..2 {
+            assert!(flows.should_compress(flow));
+            flows.record(flow, 100, 90, FAST);
+        }
+        assert!(!flows.should_compress(flow));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(flows.should_compress(flow));
+    }
+
+    #[test]
+    fn bypass_flow_above_max_duration() {
+        let flows = flows(Duration::from_secs(60));
+        let flow: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        // The flow compresses well, but takes too long to compress on
+        // average.
+        assert!(flows.should_compress(flow));
+        flows.record(flow, 100, 10, FAST);
+        assert!(flows.should_compress(flow));
+        flows.record(flow, 100, 10, Duration::from_micros(300));
+        assert!(!flows.should_compress(flow));
+    }
+}
@@ -13,11 +13,14 @@
  *  See the License for the specific language governing permissions and
  *  limitations under the License.
  */
+
 use prometheus::core::{AtomicU64, GenericCounter};
-use prometheus::{IntCounter, Registry};
-use prometheus::{IntCounterVec, Result as MetricsResult};
+use prometheus::{Histogram, Registry, Result as MetricsResult};
+
+use crate::filters::FilterMetrics;
+use crate::metrics::{compression_ratio_buckets, latency_buckets};
 
-use crate::metrics::{filter_opts, CollectorExt};
+use super::Compress;
 
 /// Register and manage metrics for this filter
 pub(super) struct Metrics {
@@ -25,42 +28,65 @@ pub(super) struct Metrics {
     pub(super) packets_dropped_decompress: GenericCounter<AtomicU64>,
     pub(super) compressed_bytes_total: GenericCounter<AtomicU64>,
     pub(super) decompressed_bytes_total: GenericCounter<AtomicU64>,
+    pub(super) packets_bypassed_total: GenericCounter<AtomicU64>,
+    pub(super) packets_not_smaller_total: GenericCounter<AtomicU64>,
+    pub(super) compression_duration_seconds: Histogram,
+    pub(super) compression_ratio: Histogram,
 }
 
 impl Metrics {
     pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
-        let operation_labels = vec!["action"];
-        let dropped_metric = IntCounterVec::new(
-            filter_opts(
-                "packets_dropped_total",
-                "Compress",
-                "Total number of packets dropped as they could not be processed. Labels: operation.",
-            ),
-            &operation_labels,
-        )?
-        .register(registry)?;
+        let metrics = FilterMetrics::new(registry, Compress::FILTER_NAME);
+        let dropped_metric = metrics.counter_vec(
+            "packets_dropped",
+            "Total number of packets dropped as they could not be processed. Labels: operation.",
+            &["action"],
+        )?;
 
-        let decompressed_bytes_total = IntCounter::with_opts(filter_opts(
-            "decompressed_bytes_total",
-            "Compress",
+        let decompressed_bytes_total = metrics.counter(
+            "decompressed_bytes",
             "Total number of decompressed bytes either received or sent.",
-        ))?
-        .register(registry)?;
+        )?;
 
-        let compressed_bytes_total = IntCounter::with_opts(filter_opts(
-            "compressed_bytes_total",
-            "Compress",
+        let compressed_bytes_total = metrics.counter(
+            "compressed_bytes",
             "Total number of compressed bytes either received or sent.",
-        ))?
-        .register(registry)?;
+        )?;
+
+        let packets_bypassed_total = metrics.counter(
+            "packets_bypassed",
+            "Total number of packets sent uncompressed by adaptive mode.",
+        )?;
+
+        let packets_not_smaller_total = metrics.counter(
+            "packets_not_smaller",
+            "Total number of packets sent uncompressed as compression did not shrink them.",
+        )?;
+
+        let compression_duration_seconds = metrics.histogram(
+            "compression_duration_seconds",
+            "Duration of compressing a single packet.",
+            Some(latency_buckets(&[
+                0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005,
+            ])),
+        )?;
+
+        let compression_ratio = metrics.histogram(
+            "compression_ratio",
+            "The size of a compressed packet divided by its original size.",
+            Some(compression_ratio_buckets()),
+        )?;
 
         Ok(Metrics {
-            packets_dropped_compress: dropped_metric
-                .get_metric_with_label_values(vec!["Compress"].as_slice())?,
+            packets_dropped_compress: dropped_metric.get_metric_with_label_values(&["Compress"])?,
             packets_dropped_decompress: dropped_metric
-                .get_metric_with_label_values(vec!["Decompress"].as_slice())?,
+                .get_metric_with_label_values(&["Decompress"])?,
             compressed_bytes_total,
             decompressed_bytes_total,
+            packets_bypassed_total,
+            packets_not_smaller_total,
+            compression_duration_seconds,
+            compression_ratio,
         })
     }
 }
@@ -19,9 +19,9 @@ pub(super) mod quilkin {
     pub mod extensions {
         pub mod filters {
             pub mod compress {
-                pub mod v1alpha1 {
+                pub mod v1beta1 {
                     #![doc(hidden)]
-                    tonic::include_proto!("quilkin.extensions.filters.compress.v1alpha1");
+                    tonic::include_proto!("quilkin.extensions.filters.compress.v1beta1");
                 }
             }
         }
@@ -19,23 +19,38 @@ use std::convert::TryFrom;
 use base64_serde::base64_serde_type;
 use serde::{Deserialize, Serialize};
 
+use metrics::Metrics;
+
 use crate::filters::prelude::*;
 use crate::map_proto_enum;
 
-crate::include_proto!("quilkin.extensions.filters.concatenate_bytes.v1alpha1");
-use self::quilkin::extensions::filters::concatenate_bytes::v1alpha1::{
-    concatenate_bytes::Strategy as ProtoStrategy, ConcatenateBytes as ProtoConfig,
+crate::include_proto!("quilkin.extensions.filters.concatenate_bytes.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::concatenate_bytes::v1beta1 as proto;
+
+use self::quilkin::extensions::filters::concatenate_bytes::v1beta1::{
+    concatenate_bytes::{
+        Strategy as ProtoStrategy, StrategyValue, Validate as ProtoValidate, ValidateValue,
+    },
+    ConcatenateBytes as ProtoConfig,
 };
 
+mod metrics;
+
 base64_serde_type!(Base64Standard, base64::STANDARD);
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-enum Strategy {
+/// Whether to add the bytes to packets, and where.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Strategy {
     #[serde(rename = "APPEND")]
+    /// Add the bytes to the end of the packet
     Append,
     #[serde(rename = "PREPEND")]
+    /// Add the bytes to the beginning of the packet
     Prepend,
     #[serde(rename = "DO_NOTHING")]
+    /// Leave the packet as it is
     DoNothing,
 }
 
@@ -45,18 +60,44 @@ impl Default for Strategy {
     }
 }
 
-/// Config represents a [`ConcatenateBytes`] filter configuration
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-struct Config {
+/// Where, if anywhere, the bytes are expected in packets on Filter `Read`.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Validate {
+    #[serde(rename = "DISABLED")]
+    /// Packets are not checked
+    Disabled,
+    #[serde(rename = "PREFIX")]
+    /// The bytes must be at the beginning of the packet
+    Prefix,
+    #[serde(rename = "SUFFIX")]
+    /// The bytes must be at the end of the packet
+    Suffix,
+}
+
+impl Default for Validate {
+    fn default() -> Self {
+        Validate::Disabled
+    }
+}
+
+/// Config represents a `ConcatenateBytes` filter configuration
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Config {
     /// Whether or not to `append` or `prepend` or `do nothing` on Filter `Read`
     #[serde(default)]
-    on_read: Strategy,
+    pub on_read: Strategy,
     /// Whether or not to `append` or `prepend` or `do nothing` on Filter `Write`
     #[serde(default)]
-    on_write: Strategy,
+    pub on_write: Strategy,
 
+    /// The bytes to add to packets
     #[serde(with = "Base64Standard")]
-    bytes: Vec<u8>,
+    pub bytes: Vec<u8>,
+
+    /// Whether to check that packets on Filter `Read` contain the bytes
+    /// exactly once, at their `prefix` or `suffix`, and strip them
+    #[serde(default)]
+    pub validate: Validate,
 }
 
 impl TryFrom<ProtoConfig> for Config {
@@ -91,21 +132,70 @@ impl TryFrom<ProtoConfig> for Config {
             .transpose()?
             .unwrap_or_else(Strategy::default);
 
+        let validate = p
+            .validate
+            .map(|validate| {
+                map_proto_enum!(
+                    value = validate.value,
+                    field = "validate",
+                    proto_enum_type = ProtoValidate,
+                    target_enum_type = Validate,
+                    variants = [Disabled, Prefix, Suffix]
+                )
+            })
+            .transpose()?
+            .unwrap_or_else(Validate::default);
+
         Ok(Self {
             on_read,
             on_write,
             bytes: p.bytes,
+            validate,
         })
     }
 }
 
+impl From<Strategy> for StrategyValue {
+    fn from(strategy: Strategy) -> Self {
+        let value = match strategy {
+            Strategy::Append => ProtoStrategy::Append,
+            Strategy::Prepend => ProtoStrategy::Prepend,
+            Strategy::DoNothing => ProtoStrategy::DoNothing,
+        };
+        Self {
+            value: value as i32,
+        }
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        let validate = match config.validate {
+            Validate::Disabled => ProtoValidate::Disabled,
+            Validate::Prefix => ProtoValidate::Prefix,
+            Validate::Suffix => ProtoValidate::Suffix,
+        };
+        Self {
+            on_write: Some(config.on_write.into()),
+            on_read: Some(config.on_read.into()),
+            bytes: config.bytes,
+            validate: Some(ValidateValue {
+                value: validate as i32,
+            }),
+        }
+    }
+}
+
 /// The `ConcatenateBytes` filter's job is to add a byte packet to either the beginning or end of each UDP packet that passes
 /// through. This is commonly used to provide an auth token to each packet, so they can be routed appropriately.
-#[crate::filter("quilkin.extensions.filters.concatenate_bytes.v1alpha1.ConcatenateBytes")]
+#[crate::filter("quilkin.extensions.filters.concatenate_bytes.v1beta1.ConcatenateBytes")]
 struct ConcatenateBytes {
     on_read: Strategy,
     on_write: Strategy,
     bytes: Vec<u8>,
+    validate: Validate,
+    /// metrics reporter for this filter.
+    metrics: Metrics,
 }
 
 pub struct ConcatBytesFactory;
@@ -121,26 +211,84 @@ impl FilterFactory for ConcatBytesFactory {
         ConcatenateBytes::FILTER_NAME
     }
 
+    fn deprecated_names(&self) -> &'static [&'static str] {
+        &["quilkin.extensions.filters.concatenate_bytes.v1alpha1.ConcatenateBytes"]
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("concatenate_bytes/schema.yaml"))
+    }
+
     fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config: Config = self
+            .require_config(args.config)?
+            .deserialize::<Config, ProtoConfig>(self.name())?;
+
+        if config.validate != Validate::Disabled && config.bytes.is_empty() {
+            return Err(Error::FieldInvalid {
+                field: "bytes".into(),
+                reason: "value must not be empty when `validate` is enabled".into(),
+            });
+        }
+
         Ok(Box::new(ConcatenateBytes::new(
-            self.require_config(args.config)?
-                .deserialize::<Config, ProtoConfig>(self.name())?,
+            config,
+            Metrics::new(&args.metrics_registry)?,
         )))
     }
 }
 
 impl ConcatenateBytes {
-    pub fn new(config: Config) -> Self {
+    fn new(config: Config, metrics: Metrics) -> Self {
         ConcatenateBytes {
             on_read: config.on_read,
             on_write: config.on_write,
             bytes: config.bytes,
+            validate: config.validate,
+            metrics,
+        }
+    }
+
+    /// Strips the bytes from `contents`, returning `false` if they aren't
+    /// where they're expected or are present more than once, such as when a
+    /// client appended them again to a packet that it retried.
+    fn strip(&self, contents: &mut Vec<u8>) -> bool {
+        let expected = match self.validate {
+            Validate::Disabled => return true,
+            Validate::Prefix => contents.starts_with(&self.bytes),
+            Validate::Suffix => contents.ends_with(&self.bytes),
+        };
+        if !expected || occurrences(contents, &self.bytes) != 1 {
+            return false;
+        }
+
+        match self.validate {
+            Validate::Prefix => {
+                contents.drain(..self.bytes.len());
+            }
+            Validate::Suffix => contents.truncate(contents.len() - self.bytes.len()),
+            Validate::Disabled => {}
         }
+        true
     }
 }
 
+/// Returns the number of times that `bytes`, which mustn't be empty, occurs
+/// in `contents`.
+fn occurrences(contents: &[u8], bytes: &[u8]) -> usize {
+    contents
+        .windows(bytes.len())
+        .filter(|window| *window == bytes)
+        .count()
+}
+
 impl Filter for ConcatenateBytes {
     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        if !self.strip(&mut ctx.contents) {
+            self.metrics.packets_dropped_total.inc();
+            return drop_packet("MalformedConcatenatedBytes");
+        }
+
         match self.on_read {
             Strategy::Append => {
                 ctx.contents.extend(self.bytes.iter());
@@ -180,11 +328,14 @@ mod tests {
     use crate::filters::{CreateFilterArgs, Filter, FilterFactory, ReadContext, WriteContext};
     use crate::test_utils::{assert_filter_read_no_change, assert_write_no_change};
 
-    use super::quilkin::extensions::filters::concatenate_bytes::v1alpha1::{
-        concatenate_bytes::{Strategy as ProtoStrategy, StrategyValue},
+    use super::metrics::Metrics;
+    use super::quilkin::extensions::filters::concatenate_bytes::v1beta1::{
+        concatenate_bytes::{
+            Strategy as ProtoStrategy, StrategyValue, Validate as ProtoValidate, ValidateValue,
+        },
         ConcatenateBytes as ProtoConfig,
     };
-    use super::{ConcatBytesFactory, ConcatenateBytes, Config, Strategy};
+    use super::{ConcatBytesFactory, ConcatenateBytes, Config, Strategy, Validate};
     use prometheus::Registry;
 
     #[test]
@@ -200,11 +351,15 @@ mod tests {
                         value: ProtoStrategy::DoNothing as i32,
                     }),
                     bytes: "abc".into(),
+                    validate: Some(ValidateValue {
+                        value: ProtoValidate::Suffix as i32,
+                    }),
                 },
                 Some(Config {
                     on_write: Strategy::Append,
                     on_read: Strategy::DoNothing,
                     bytes: "abc".into(),
+                    validate: Validate::Suffix,
                 }),
             ),
             (
@@ -213,6 +368,17 @@ mod tests {
                     on_read: Some(StrategyValue { value: 42 }),
                     on_write: None,
                     bytes: "abc".into(),
+                    validate: None,
+                },
+                None,
+            ),
+            (
+                "should fail when invalid validate is provided",
+                ProtoConfig {
+                    on_read: None,
+                    on_write: None,
+                    bytes: "abc".into(),
+                    validate: Some(ValidateValue { value: 42 }),
                 },
                 None,
             ),
@@ -222,11 +388,13 @@ mod tests {
                     on_write: None,
                     on_read: None,
                     bytes: "abc".into(),
+                    validate: None,
                 },
                 Some(Config {
                     on_write: Strategy::default(),
                     on_read: Strategy::default(),
                     bytes: "abc".into(),
+                    validate: Validate::default(),
                 }),
             ),
         ];
@@ -244,6 +412,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            on_read: Strategy::Prepend,
+            on_write: Strategy::Append,
+            bytes: b"abc".to_vec(),
+            validate: Validate::Suffix,
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
     #[test]
     fn factory_valid_config() {
         let factory = ConcatBytesFactory::default();
@@ -344,6 +526,60 @@ mod tests {
             Some(&Value::Mapping(map)),
         ));
         assert!(result.is_err());
+
+        // validating requires bytes to look for
+        let mut map = Mapping::new();
+        map.insert(Value::String("bytes".into()), Value::String("".into()));
+        map.insert(
+            Value::String("validate".into()),
+            Value::String("SUFFIX".into()),
+        );
+
+        let result = factory.create_filter(CreateFilterArgs::fixed(
+            Registry::default(),
+            Some(&Value::Mapping(map)),
+        ));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn read_validate() {
+        let filter = |validate| {
+            concatenate_bytes(Config {
+                on_read: Default::default(),
+                on_write: Default::default(),
+                bytes: b"hello".to_vec(),
+                validate,
+            })
+        };
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:81".parse().unwrap(),
+        )])
+        .unwrap();
+        let read = |filter: &ConcatenateBytes, contents: &str| {
+            filter
+                .read(ReadContext::new(
+                    endpoints.clone().into(),
+                    "127.0.0.1:80".parse().unwrap(),
+                    contents.as_bytes().to_vec(),
+                ))
+                .map(|response| response.contents)
+        };
+
+        let prefix = filter(Validate::Prefix);
+        assert_eq!(Some(b"abc".to_vec()), read(&prefix, "helloabc"));
+        assert_eq!(Some(vec![]), read(&prefix, "hello"));
+        assert_eq!(None, read(&prefix, "abchello"));
+        assert_eq!(None, read(&prefix, "hellohelloabc"));
+        assert_eq!(None, read(&prefix, "helloabchello"));
+        assert_eq!(3, prefix.metrics.packets_dropped_total.get());
+
+        let suffix = filter(Validate::Suffix);
+        assert_eq!(Some(b"abc".to_vec()), read(&suffix, "abchello"));
+        assert_eq!(None, read(&suffix, "helloabc"));
+        assert_eq!(None, read(&suffix, "abchellohello"));
+        assert_eq!(None, read(&suffix, "hell"));
+        assert_eq!(3, suffix.metrics.packets_dropped_total.get());
     }
 
     #[test]
@@ -366,8 +602,9 @@ mod tests {
             on_read: Default::default(),
             on_write: Strategy::Append,
             bytes: b"hello".to_vec(),
+            validate: Default::default(),
         };
-        let filter = ConcatenateBytes::new(config);
+        let filter = concatenate_bytes(config);
         assert_write_with_filter(&filter, "abchello");
     }
 
@@ -377,8 +614,9 @@ mod tests {
             on_read: Default::default(),
             on_write: Strategy::Prepend,
             bytes: b"hello".to_vec(),
+            validate: Default::default(),
         };
-        let filter = ConcatenateBytes::new(config);
+        let filter = concatenate_bytes(config);
         assert_write_with_filter(&filter, "helloabc");
     }
 
@@ -388,8 +626,9 @@ mod tests {
             on_read: Default::default(),
             on_write: Default::default(),
             bytes: vec![],
+            validate: Default::default(),
         };
-        let filter = ConcatenateBytes::new(config);
+        let filter = concatenate_bytes(config);
         assert_filter_read_no_change(&filter);
     }
 
@@ -399,8 +638,9 @@ mod tests {
             on_read: Default::default(),
             on_write: Default::default(),
             bytes: vec![],
+            validate: Default::default(),
         };
-        let filter = ConcatenateBytes::new(config);
+        let filter = concatenate_bytes(config);
         assert_write_no_change(&filter);
     }
 
@@ -410,12 +650,17 @@ mod tests {
             on_read,
             on_write: Default::default(),
             bytes: contents,
+            validate: Default::default(),
         };
-        let filter = ConcatenateBytes::new(config);
+        let filter = concatenate_bytes(config);
 
         assert_read_with_filter(&filter, expected);
     }
 
+    fn concatenate_bytes(config: Config) -> ConcatenateBytes {
+        ConcatenateBytes::new(config, Metrics::new(&Registry::default()).unwrap())
+    }
+
     fn assert_read_with_filter<F>(filter: &F, expected: &str)
     where
         F: Filter + ?Sized,
@@ -0,0 +1,39 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::core::{AtomicU64, GenericCounter};
+use prometheus::{Registry, Result as MetricsResult};
+
+use crate::filters::FilterMetrics;
+
+use super::ConcatenateBytes;
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped_total: GenericCounter<AtomicU64>,
+}
+
+impl Metrics {
+    pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let metrics = FilterMetrics::new(registry, ConcatenateBytes::FILTER_NAME);
+        Ok(Metrics {
+            packets_dropped_total: metrics.counter(
+                "packets_dropped",
+                "Total number of packets dropped due to malformed concatenated bytes",
+            )?,
+        })
+    }
+}
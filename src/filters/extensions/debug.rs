@@ -21,40 +21,65 @@ use slog::{info, o, Logger};
 
 use crate::filters::prelude::*;
 
-crate::include_proto!("quilkin.extensions.filters.debug.v1alpha1");
-use self::quilkin::extensions::filters::debug::v1alpha1::Debug as ProtoDebug;
+crate::include_proto!("quilkin.extensions.filters.debug.v1beta1");
 
-/// Debug logs all incoming and outgoing packets
-#[crate::filter("quilkin.extensions.filters.debug.v1alpha1.Debug")]
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::debug::v1beta1 as proto;
+
+use self::quilkin::extensions::filters::debug::v1beta1::Debug as ProtoDebug;
+
+/// Debug logs all incoming and outgoing packets, or those sampled at its
+/// sample rate if it has one.
+#[crate::filter("quilkin.extensions.filters.debug.v1beta1.Debug")]
 #[derive(Debug)]
 pub struct Debug {
     log: Logger,
+    sample_rate: f64,
 }
 
 impl Debug {
     /// Constructor for the Debug. Pass in a "id" to append a string to your log messages from this
     /// Filter.
-    fn new(base: &Logger, id: Option<String>) -> Self {
+    fn new(base: &Logger, id: Option<String>, sample_rate: Option<f64>) -> Self {
         let log = match id {
             None => base.new(o!("source" => "extensions::Debug")),
             Some(id) => base.new(o!("source" => "extensions::Debug", "id" => id)),
         };
 
-        Debug { log }
+        Debug {
+            log,
+            sample_rate: sample_rate.unwrap_or(1.0),
+        }
     }
 }
 
 /// A Debug filter's configuration.
-#[derive(Serialize, Deserialize, Debug)]
-struct Config {
-    id: Option<String>,
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Config {
+    /// An identifier included in the filter's log messages.
+    pub id: Option<String>,
+    /// The fraction of packets to log, as sampled by [`Sample`].
+    #[serde(default)]
+    pub sample_rate: Option<f64>,
 }
 
 impl TryFrom<ProtoDebug> for Config {
     type Error = ConvertProtoConfigError;
 
     fn try_from(p: ProtoDebug) -> Result<Self, Self::Error> {
-        Ok(Config { id: p.id })
+        Ok(Config {
+            id: p.id,
+            sample_rate: p.sample_rate,
+        })
+    }
+}
+
+impl From<Config> for ProtoDebug {
+    fn from(config: Config) -> Self {
+        Self {
+            id: config.id,
+            sample_rate: config.sample_rate,
+        }
     }
 }
 
@@ -74,31 +99,65 @@ impl FilterFactory for DebugFactory {
         Debug::FILTER_NAME
     }
 
+    fn deprecated_names(&self) -> &'static [&'static str] {
+        &["quilkin.extensions.filters.debug.v1alpha1.Debug"]
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("debug/schema.yaml"))
+    }
+
     fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
         let config: Option<Config> = args
             .config
             .map(|config| config.deserialize::<Config, ProtoDebug>(self.name()))
             .transpose()?;
-        Ok(Box::new(Debug::new(
-            &self.log,
-            config.and_then(|cfg| cfg.id),
-        )))
+        let (id, sample_rate) = config
+            .map(|cfg| (cfg.id, cfg.sample_rate))
+            .unwrap_or_default();
+        if let Some(sample_rate) = sample_rate {
+            if !(0.0..=1.0).contains(&sample_rate) {
+                return Err(Error::FieldInvalid {
+                    field: "sample_rate".into(),
+                    reason: "value must be between 0 and 1".into(),
+                });
+            }
+        }
+        Ok(Box::new(Debug::new(&self.log, id, sample_rate)))
     }
 }
 
 impl Filter for Debug {
     fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+        if !self.is_sampled(ctx.sample) {
+            return Some(ctx.into());
+        }
         info!(self.log, "Read filter event"; "from" => ctx.from, "contents" => packet_to_string(ctx.contents.clone()));
         Some(ctx.into())
     }
 
     fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        if !self.is_sampled(ctx.sample) {
+            return Some(ctx.into());
+        }
         info!(self.log, "Write filter event"; "endpoint" => ctx.endpoint.address,
         "from" => ctx.from,
         "to" => ctx.to,
         "contents" => packet_to_string(ctx.contents.clone()));
         Some(ctx.into())
     }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+impl Debug {
+    /// Returns whether a packet with `sample` is logged. Every packet is
+    /// logged at a sample rate of 1, even if its sample rounds up to it.
+    fn is_sampled(&self, sample: Sample) -> bool {
+        self.sample_rate >= 1.0 || sample.is_sampled(self.sample_rate)
+    }
 }
 
 /// packet_to_string takes the content, and attempts to convert it to a string.
@@ -122,13 +181,13 @@ mod tests {
 
     #[test]
     fn read() {
-        let df = Debug::new(&logger(), None);
+        let df = Debug::new(&logger(), None, None);
         assert_filter_read_no_change(&df);
     }
 
     #[test]
     fn write() {
-        let df = Debug::new(&logger(), None);
+        let df = Debug::new(&logger(), None, None);
         assert_write_no_change(&df);
     }
 
@@ -176,4 +235,39 @@ mod tests {
             ))
             .is_err());
     }
+
+    #[test]
+    fn sample_rate() {
+        let df = Debug::new(&logger(), None, Some(0.5));
+        assert!(df.is_sampled(Sample::from_value(0.25)));
+        assert!(!df.is_sampled(Sample::from_value(0.75)));
+
+        let df = Debug::new(&logger(), None, None);
+        assert!(df.is_sampled(Sample::from_value(1.0)));
+        let df = Debug::new(&logger(), None, Some(0.0));
+        assert!(!df.is_sampled(Sample::from_value(0.0)));
+        assert_filter_read_no_change(&df);
+    }
+
+    #[test]
+    fn from_config_invalid_sample_rate() {
+        let log = logger();
+        let mut map = Mapping::new();
+        let factory = DebugFactory::new(&log);
+
+        map.insert(Value::from("sample_rate"), Value::from(1.5));
+        assert_eq!(
+            Error::FieldInvalid {
+                field: "sample_rate".into(),
+                reason: "value must be between 0 and 1".into(),
+            },
+            factory
+                .create_filter(CreateFilterArgs::fixed(
+                    Registry::default(),
+                    Some(&Value::Mapping(map)),
+                ))
+                .err()
+                .unwrap()
+        );
+    }
 }
@@ -0,0 +1,1105 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod metrics;
+
+crate::include_proto!("quilkin.extensions.filters.fragment.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::fragment::v1beta1 as proto;
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::filters::prelude::*;
+use crate::map_proto_enum;
+
+use self::metrics::Metrics;
+use self::quilkin::extensions::filters::fragment::v1beta1::{
+    fragment::{
+        Action as ProtoAction, ActionValue, ByteOrder as ProtoByteOrder, ByteOrderValue,
+        Header as ProtoHeader,
+    },
+    Fragment as ProtoConfig,
+};
+
+/// The reason fragments are dropped for while they are held until the rest
+/// of their message is received.
+const BUFFERED_REASON: &str = "FragmentBuffered";
+/// The reason packets are dropped for if they aren't valid fragments.
+const INVALID_REASON: &str = "InvalidFragment";
+/// The reason fragments are dropped for if one with the same index has
+/// already been received for their message.
+const DUPLICATE_REASON: &str = "DuplicateFragment";
+/// The reason fragments are dropped for if their message is larger than
+/// the maximum message size.
+const TOO_LARGE_REASON: &str = "MessageTooLarge";
+/// The reason fragments of new messages are dropped for while the maximum
+/// number of messages are being reassembled.
+const BUFFER_FULL_REASON: &str = "FragmentBufferFull";
+/// The reason packets are dropped for if they need more fragments than the
+/// header can count.
+const TOO_MANY_FRAGMENTS_REASON: &str = "TooManyFragments";
+
+/// Whether to do nothing, reassemble or fragment the packet.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Action {
+    /// Pass the packet through unchanged.
+    #[serde(rename = "DO_NOTHING")]
+    DoNothing,
+    /// Hold the packet until all of the fragments of its message have been
+    /// received, then pass on the whole message.
+    #[serde(rename = "DEFRAGMENT")]
+    Defragment,
+    /// Split the packet into fragments of at most `max_fragment_size` bytes.
+    #[serde(rename = "FRAGMENT")]
+    Fragment,
+}
+
+/// The order of the bytes of the fields in a fragment's header.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ByteOrder {
+    /// The most significant byte comes first.
+    #[serde(rename = "BIG_ENDIAN")]
+    BigEndian,
+    /// The least significant byte comes first.
+    #[serde(rename = "LITTLE_ENDIAN")]
+    LittleEndian,
+}
+
+impl Default for ByteOrder {
+    fn default() -> Self {
+        ByteOrder::BigEndian
+    }
+}
+
+impl ByteOrder {
+    /// Reads an unsigned integer from `bytes`.
+    fn read(self, bytes: &[u8]) -> u64 {
+        let fold = |value: u64, byte: &u8| (value << 8) | u64::from(*byte);
+        match self {
+            ByteOrder::BigEndian => bytes.iter().fold(0, fold),
+            ByteOrder::LittleEndian => bytes.iter().rev().fold(0, fold),
+        }
+    }
+
+    /// Appends `value` to `out` as an unsigned integer of `size` bytes.
+    fn write(self, value: u64, size: usize, out: &mut Vec<u8>) {
+        let bytes = value.to_be_bytes();
+        let bytes = &bytes[bytes.len() - size..];
+        match self {
+            ByteOrder::BigEndian => out.extend_from_slice(bytes),
+            ByteOrder::LittleEndian => out.extend(bytes.iter().rev()),
+        }
+    }
+}
+
+/// The layout of the header at the start of each fragment: the id of the
+/// fragment's message, followed by the index of the fragment within the
+/// message and the number of fragments in the message.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Header {
+    /// The size in bytes of the message id.
+    #[serde(default = "default_id_size")]
+    pub id_size: usize,
+    /// The size in bytes of the fragment's index.
+    #[serde(default = "default_index_size")]
+    pub index_size: usize,
+    /// The size in bytes of the number of fragments.
+    #[serde(default = "default_count_size")]
+    pub count_size: usize,
+    /// The order of the bytes of each field.
+    #[serde(default)]
+    pub byte_order: ByteOrder,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Self {
+            id_size: default_id_size(),
+            index_size: default_index_size(),
+            count_size: default_count_size(),
+            byte_order: ByteOrder::default(),
+        }
+    }
+}
+
+impl Header {
+    /// Returns the size of the header in bytes.
+    fn size(&self) -> usize {
+        self.id_size + self.index_size + self.count_size
+    }
+}
+
+/// Config represents a `Fragment` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The layout of the header at the start of each fragment.
+    #[serde(default)]
+    pub header: Header,
+    /// What to do with packets received from clients.
+    #[serde(default = "default_on_read")]
+    pub on_read: Action,
+    /// What to do with packets received from endpoints.
+    #[serde(default = "default_on_write")]
+    pub on_write: Action,
+    /// The maximum size in bytes of a fragment, including its header.
+    #[serde(default = "default_max_fragment_size")]
+    pub max_fragment_size: usize,
+    /// The maximum size in bytes of a reassembled message.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    /// How long the fragments of a message are held for while waiting for
+    /// the rest of them.
+    #[serde(with = "humantime_serde", default = "default_timeout")]
+    pub timeout: Duration,
+    /// The maximum number of messages being reassembled at once.
+    #[serde(default = "default_max_messages")]
+    pub max_messages: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            header: Header::default(),
+            on_read: default_on_read(),
+            on_write: default_on_write(),
+            max_fragment_size: default_max_fragment_size(),
+            max_message_size: default_max_message_size(),
+            timeout: default_timeout(),
+            max_messages: default_max_messages(),
+        }
+    }
+}
+
+/// Default value for [`Header::id_size`]
+fn default_id_size() -> usize {
+    4
+}
+
+/// Default value for [`Header::index_size`]
+fn default_index_size() -> usize {
+    1
+}
+
+/// Default value for [`Header::count_size`]
+fn default_count_size() -> usize {
+    1
+}
+
+/// Default value for [`Config::on_read`]
+fn default_on_read() -> Action {
+    Action::Defragment
+}
+
+/// Default value for [`Config::on_write`]
+fn default_on_write() -> Action {
+    Action::Fragment
+}
+
+/// Default value for [`Config::max_fragment_size`]
+fn default_max_fragment_size() -> usize {
+    1200
+}
+
+/// Default value for [`Config::max_message_size`]
+fn default_max_message_size() -> usize {
+    65536
+}
+
+/// Default value for [`Config::timeout`]
+fn default_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// Default value for [`Config::max_messages`]
+fn default_max_messages() -> usize {
+    1024
+}
+
+impl TryFrom<ProtoHeader> for Header {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoHeader) -> Result<Self, Self::Error> {
+        let byte_order = p
+            .byte_order
+            .map(|byte_order| {
+                map_proto_enum!(
+                    value = byte_order.value,
+                    field = "header.byte_order",
+                    proto_enum_type = ProtoByteOrder,
+                    target_enum_type = ByteOrder,
+                    variants = [BigEndian, LittleEndian]
+                )
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            id_size: p.id_size.map_or_else(default_id_size, |size| size as usize),
+            index_size: p
+                .index_size
+                .map_or_else(default_index_size, |size| size as usize),
+            count_size: p
+                .count_size
+                .map_or_else(default_count_size, |size| size as usize),
+            byte_order,
+        })
+    }
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        let on_read = p
+            .on_read
+            .map(|on_read| {
+                map_proto_enum!(
+                    value = on_read.value,
+                    field = "on_read",
+                    proto_enum_type = ProtoAction,
+                    target_enum_type = Action,
+                    variants = [DoNothing, Defragment, Fragment]
+                )
+            })
+            .transpose()?
+            .unwrap_or_else(default_on_read);
+
+        let on_write = p
+            .on_write
+            .map(|on_write| {
+                map_proto_enum!(
+                    value = on_write.value,
+                    field = "on_write",
+                    proto_enum_type = ProtoAction,
+                    target_enum_type = Action,
+                    variants = [DoNothing, Defragment, Fragment]
+                )
+            })
+            .transpose()?
+            .unwrap_or_else(default_on_write);
+
+        Ok(Self {
+            header: p
+                .header
+                .map(Header::try_from)
+                .transpose()?
+                .unwrap_or_default(),
+            on_read,
+            on_write,
+            max_fragment_size: p
+                .max_fragment_size
+                .map_or_else(default_max_fragment_size, |size| size as usize),
+            max_message_size: p
+                .max_message_size
+                .map_or_else(default_max_message_size, |size| size as usize),
+            timeout: p
+                .timeout
+                .map(|timeout| {
+                    timeout.try_into().map_err(|err| {
+                        ConvertProtoConfigError::new(
+                            format!("invalid duration: {:?}", err),
+                            Some("timeout".into()),
+                        )
+                    })
+                })
+                .transpose()?
+                .unwrap_or_else(default_timeout),
+            max_messages: p
+                .max_messages
+                .map_or_else(default_max_messages, |max| max as usize),
+        })
+    }
+}
+
+impl From<Action> for ActionValue {
+    fn from(action: Action) -> Self {
+        let value = match action {
+            Action::DoNothing => ProtoAction::DoNothing,
+            Action::Defragment => ProtoAction::Defragment,
+            Action::Fragment => ProtoAction::Fragment,
+        };
+        Self {
+            value: value as i32,
+        }
+    }
+}
+
+impl From<Header> for ProtoHeader {
+    fn from(header: Header) -> Self {
+        let byte_order = match header.byte_order {
+            ByteOrder::BigEndian => ProtoByteOrder::BigEndian,
+            ByteOrder::LittleEndian => ProtoByteOrder::LittleEndian,
+        };
+        Self {
+            id_size: Some(header.id_size as u32),
+            index_size: Some(header.index_size as u32),
+            count_size: Some(header.count_size as u32),
+            byte_order: Some(ByteOrderValue {
+                value: byte_order as i32,
+            }),
+        }
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            header: Some(config.header.into()),
+            on_read: Some(config.on_read.into()),
+            on_write: Some(config.on_write.into()),
+            max_fragment_size: Some(config.max_fragment_size as u32),
+            max_message_size: Some(config.max_message_size as u32),
+            timeout: Some(config.timeout.into()),
+            max_messages: Some(config.max_messages as u32),
+        }
+    }
+}
+
+/// The header of a fragment, as read from the start of a packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FragmentHeader {
+    id: u64,
+    index: u64,
+    count: u64,
+}
+
+/// Identifies a message being reassembled, by the address it was sent from,
+/// the address it was sent to if it is being written to a client, and its id.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct MessageKey {
+    from: SocketAddr,
+    to: Option<SocketAddr>,
+    id: u64,
+}
+
+/// A message being reassembled from its fragments.
+struct Message {
+    /// When the message's first fragment was received.
+    started: Instant,
+    /// The payload of each fragment, once received.
+    fragments: Vec<Option<Vec<u8>>>,
+    /// The number of fragments received.
+    received: usize,
+    /// The size in bytes of the fragments received.
+    size: usize,
+}
+
+/// The messages being reassembled.
+struct Messages {
+    messages: HashMap<MessageKey, Message>,
+    /// The size in bytes of the fragments held for all messages.
+    size: usize,
+    /// When messages that timed out were last removed.
+    last_expired: Instant,
+}
+
+/// The `Fragment` filter reassembles messages that an application has split
+/// into fragments before later filters process them, and splits messages
+/// into fragments, so that filters such as compression and HMAC
+/// authentication operate on whole messages.
+#[crate::filter("quilkin.extensions.filters.fragment.v1beta1.Fragment")]
+struct Fragment {
+    header: Header,
+    on_read: Action,
+    on_write: Action,
+    max_fragment_size: usize,
+    max_message_size: usize,
+    timeout: Duration,
+    max_messages: usize,
+    /// The id of the next message that the filter splits into fragments.
+    next_id: AtomicU64,
+    messages: Mutex<Messages>,
+    metrics: Metrics,
+}
+
+/// Factory for the Fragment filter
+#[derive(Default)]
+pub struct FragmentFactory;
+
+impl FilterFactory for FragmentFactory {
+    fn name(&self) -> &'static str {
+        Fragment::FILTER_NAME
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("fragment/schema.yaml"))
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config: Config = args
+            .config
+            .map(|config| config.deserialize::<Config, ProtoConfig>(self.name()))
+            .transpose()?
+            .unwrap_or_default();
+
+        for (field, size) in &[
+            ("header.id_size", config.header.id_size),
+            ("header.index_size", config.header.index_size),
+            ("header.count_size", config.header.count_size),
+        ] {
+            if !(1..=8).contains(size) {
+                return Err(Error::FieldInvalid {
+                    field: (*field).into(),
+                    reason: "value must be between 1 and 8".into(),
+                });
+            }
+        }
+        if config.max_fragment_size <= config.header.size() {
+            return Err(Error::FieldInvalid {
+                field: "max_fragment_size".into(),
+                reason: format!(
+                    "value must be greater than the header size of {} bytes",
+                    config.header.size()
+                ),
+            });
+        }
+        for (field, value) in &[
+            ("max_message_size", config.max_message_size),
+            ("max_messages", config.max_messages),
+        ] {
+            if *value == 0 {
+                return Err(Error::FieldInvalid {
+                    field: (*field).into(),
+                    reason: "value must be greater than 0".into(),
+                });
+            }
+        }
+        if config.timeout == Duration::from_secs(0) {
+            return Err(Error::FieldInvalid {
+                field: "timeout".into(),
+                reason: "value must be greater than 0".into(),
+            });
+        }
+
+        Ok(Box::new(Fragment::new(
+            config,
+            Metrics::new(&args.metrics_registry)?,
+        )))
+    }
+}
+
+/// Returns the largest value that fits in `size` bytes.
+fn max_value(size: usize) -> u64 {
+    match size {
+        0 => 0,
+        1..=7 => (1 << (8 * size)) - 1,
+        _ => u64::MAX,
+    }
+}
+
+impl Fragment {
+    fn new(config: Config, metrics: Metrics) -> Self {
+        Self {
+            header: config.header,
+            on_read: config.on_read,
+            on_write: config.on_write,
+            max_fragment_size: config.max_fragment_size,
+            max_message_size: config.max_message_size,
+            timeout: config.timeout,
+            max_messages: config.max_messages,
+            next_id: AtomicU64::new(0),
+            messages: Mutex::new(Messages {
+                messages: HashMap::new(),
+                size: 0,
+                last_expired: Instant::now(),
+            }),
+            metrics,
+        }
+    }
+
+    /// Reads the header at the start of `contents`, if it is a valid one.
+    fn read_header(&self, contents: &[u8]) -> Option<FragmentHeader> {
+        if contents.len() < self.header.size() {
+            return None;
+        }
+        let Header {
+            id_size,
+            index_size,
+            count_size,
+            byte_order,
+        } = self.header;
+        let (id, rest) = contents.split_at(id_size);
+        let (index, rest) = rest.split_at(index_size);
+        let header = FragmentHeader {
+            id: byte_order.read(id),
+            index: byte_order.read(index),
+            count: byte_order.read(&rest[..count_size]),
+        };
+        if header.index >= header.count {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// Splits `contents` into fragments of at most `max_fragment_size` bytes,
+    /// each starting with a header.
+    fn fragment(&self, contents: &[u8]) -> Result<Vec<Vec<u8>>, &'static str> {
+        let Header {
+            id_size,
+            index_size,
+            count_size,
+            byte_order,
+        } = self.header;
+        let payload_size = self.max_fragment_size - self.header.size();
+        // An empty message is sent as a single empty fragment.
+        let count = std::cmp::max(1, (contents.len() + payload_size - 1) / payload_size) as u64;
+        if count > max_value(count_size) || count - 1 > max_value(index_size) {
+            return Err(TOO_MANY_FRAGMENTS_REASON);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) & max_value(id_size);
+        let mut chunks = contents.chunks(payload_size);
+        let fragments = (0..count)
+            .map(|index| {
+                let payload = chunks.next().unwrap_or_default();
+                let mut fragment = Vec::with_capacity(self.header.size() + payload.len());
+                byte_order.write(id, id_size, &mut fragment);
+                byte_order.write(index, index_size, &mut fragment);
+                byte_order.write(count, count_size, &mut fragment);
+                fragment.extend_from_slice(payload);
+                fragment
+            })
+            .collect::<Vec<_>>();
+        self.metrics.messages_fragmented.inc();
+        self.metrics.fragments_created.inc_by(count);
+        Ok(fragments)
+    }
+
+    /// Adds the fragment in `contents` to its message, returning the whole
+    /// message once all of its fragments have been received.
+    fn defragment(
+        &self,
+        from: SocketAddr,
+        to: Option<SocketAddr>,
+        contents: &[u8],
+    ) -> Result<Option<Vec<u8>>, &'static str> {
+        let header = self.read_header(contents).ok_or(INVALID_REASON)?;
+        let payload = &contents[self.header.size()..];
+        if header.count > self.max_message_size as u64 {
+            return Err(TOO_LARGE_REASON);
+        }
+        if header.count == 1 {
+            if payload.len() > self.max_message_size {
+                return Err(TOO_LARGE_REASON);
+            }
+            return Ok(Some(payload.to_vec()));
+        }
+
+        let now = Instant::now();
+        let mut messages = self.messages.lock();
+        let messages = &mut *messages;
+        if messages.messages.len() >= self.max_messages
+            || now.duration_since(messages.last_expired) >= self.timeout
+        {
+            self.expire(messages, now);
+        }
+
+        let key = MessageKey {
+            from,
+            to,
+            id: header.id,
+        };
+        let expired = messages.messages.get(&key).map_or(false, |message| {
+            now.duration_since(message.started) >= self.timeout
+        });
+        if expired {
+            let message = messages.messages.remove(&key).unwrap();
+            messages.size -= message.size;
+            self.metrics.messages_expired.inc();
+        }
+        if !messages.messages.contains_key(&key) && messages.messages.len() >= self.max_messages {
+            return Err(BUFFER_FULL_REASON);
+        }
+        let message = messages.messages.entry(key).or_insert_with(|| Message {
+            started: now,
+            fragments: vec![None; header.count as usize],
+            received: 0,
+            size: 0,
+        });
+
+        if message.fragments.len() as u64 != header.count {
+            return Err(INVALID_REASON);
+        }
+        let index = header.index as usize;
+        if message.fragments[index].is_some() {
+            return Err(DUPLICATE_REASON);
+        }
+        if message.size + payload.len() > self.max_message_size {
+            let size = message.size;
+            messages.messages.remove(&key);
+            messages.size -= size;
+            self.metrics
+                .pending_messages
+                .set(messages.messages.len() as i64);
+            return Err(TOO_LARGE_REASON);
+        }
+        message.fragments[index] = Some(payload.to_vec());
+        message.received += 1;
+        message.size += payload.len();
+        messages.size += payload.len();
+
+        let complete = message.received == message.fragments.len();
+        let result = if complete {
+            let message = messages.messages.remove(&key).unwrap();
+            messages.size -= message.size;
+            self.metrics.messages_reassembled.inc();
+            Some(message.fragments.into_iter().flatten().flatten().collect())
+        } else {
+            self.metrics.fragments_buffered.inc();
+            None
+        };
+        self.metrics
+            .pending_messages
+            .set(messages.messages.len() as i64);
+        Ok(result)
+    }
+
+    /// Removes the messages that haven't been completed within the timeout.
+    fn expire(&self, messages: &mut Messages, now: Instant) {
+        let timeout = self.timeout;
+        let mut expired_size = 0;
+        let before = messages.messages.len();
+        messages.messages.retain(|_, message| {
+            let expired = now.duration_since(message.started) >= timeout;
+            if expired {
+                expired_size += message.size;
+            }
+            !expired
+        });
+        messages.size -= expired_size;
+        messages.last_expired = now;
+        self.metrics
+            .messages_expired
+            .inc_by((before - messages.messages.len()) as u64);
+        self.metrics
+            .pending_messages
+            .set(messages.messages.len() as i64);
+    }
+
+    fn dropped<T>(&self, reason: &'static str) -> Option<T> {
+        self.metrics
+            .packets_dropped
+            .with_label_values(&[reason])
+            .inc();
+        drop_packet(reason)
+    }
+}
+
+impl Filter for Fragment {
+    fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        match self.on_read {
+            Action::DoNothing => Some(ctx.into()),
+            Action::Defragment => match self.defragment(ctx.from, None, &ctx.contents) {
+                Ok(Some(message)) => {
+                    ctx.contents = message;
+                    Some(ctx.into())
+                }
+                Ok(None) => drop_packet(BUFFERED_REASON),
+                Err(reason) => self.dropped(reason),
+            },
+            Action::Fragment => {
+                let mut fragments = match self.fragment(&ctx.contents) {
+                    Ok(fragments) => fragments.into_iter(),
+                    Err(reason) => return self.dropped(reason),
+                };
+                ctx.contents = fragments.next().unwrap_or_default();
+                let additional = fragments
+                    .map(|fragment| {
+                        let mut response = ReadResponse::from(ReadContext::new(
+                            ctx.endpoints.clone(),
+                            ctx.from,
+                            fragment,
+                        ));
+                        response.delay = ctx.delay;
+                        response
+                    })
+                    .collect();
+                let mut response = ReadResponse::from(ctx);
+                response.additional = additional;
+                Some(response)
+            }
+        }
+    }
+
+    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+        match self.on_write {
+            Action::DoNothing => Some(ctx.into()),
+            Action::Defragment => match self.defragment(ctx.from, Some(ctx.to), &ctx.contents) {
+                Ok(Some(message)) => {
+                    ctx.contents = message;
+                    Some(ctx.into())
+                }
+                Ok(None) => drop_packet(BUFFERED_REASON),
+                Err(reason) => self.dropped(reason),
+            },
+            Action::Fragment => {
+                let mut fragments = match self.fragment(&ctx.contents) {
+                    Ok(fragments) => fragments.into_iter(),
+                    Err(reason) => return self.dropped(reason),
+                };
+                ctx.contents = fragments.next().unwrap_or_default();
+                let additional = fragments
+                    .map(|fragment| {
+                        let mut response = WriteResponse::from(WriteContext::new(
+                            ctx.endpoint,
+                            ctx.from,
+                            ctx.to,
+                            fragment,
+                        ));
+                        response.delay = ctx.delay;
+                        response.priority = ctx.priority;
+                        response
+                    })
+                    .collect();
+                let mut response = WriteResponse::from(ctx);
+                response.additional = additional;
+                Some(response)
+            }
+        }
+    }
+
+    fn memory_usage(&self) -> Option<usize> {
+        Some(self.messages.lock().size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+    use serde_yaml::Value;
+
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::{
+        drop_reason, CreateFilterArgs, Filter, FilterFactory, ReadContext, WriteContext,
+    };
+
+    use super::quilkin::extensions::filters::fragment::v1beta1::fragment::{
+        Action as ProtoAction, ActionValue, ByteOrder as ProtoByteOrder, ByteOrderValue,
+        Header as ProtoHeader,
+    };
+    use super::{
+        Action, ByteOrder, Config, Fragment, FragmentFactory, Header, Metrics, ProtoConfig,
+        BUFFERED_REASON, DUPLICATE_REASON, INVALID_REASON, TOO_LARGE_REASON,
+        TOO_MANY_FRAGMENTS_REASON,
+    };
+
+    const CLIENT: &str = "127.0.0.1:7000";
+
+    fn fragment(config: Config) -> Fragment {
+        Fragment::new(config, Metrics::new(&Registry::default()).unwrap())
+    }
+
+    fn read(filter: &Fragment, contents: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:7001".parse().unwrap(),
+        )])
+        .unwrap();
+        filter
+            .read(ReadContext::new(
+                endpoints.into(),
+                CLIENT.parse().unwrap(),
+                contents.to_vec(),
+            ))
+            .map(|response| {
+                response
+                    .into_packets()
+                    .into_iter()
+                    .map(|packet| packet.contents)
+                    .collect()
+            })
+    }
+
+    fn write(filter: &Fragment, contents: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let endpoint = Endpoint::from_address("127.0.0.1:7001".parse().unwrap());
+        filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                CLIENT.parse().unwrap(),
+                contents.to_vec(),
+            ))
+            .map(|response| {
+                response
+                    .into_packets()
+                    .into_iter()
+                    .map(|packet| packet.contents)
+                    .collect()
+            })
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config::try_from(ProtoConfig {
+            header: Some(ProtoHeader {
+                id_size: Some(2),
+                index_size: None,
+                count_size: None,
+                byte_order: Some(ByteOrderValue {
+                    value: ProtoByteOrder::LittleEndian as i32,
+                }),
+            }),
+            on_read: Some(ActionValue {
+                value: ProtoAction::DoNothing as i32,
+            }),
+            on_write: None,
+            max_fragment_size: Some(500),
+            max_message_size: None,
+            timeout: Some(prost_types::Duration {
+                seconds: 2,
+                nanos: 0,
+            }),
+            max_messages: None,
+        })
+        .unwrap();
+        assert_eq!(
+            Config {
+                header: Header {
+                    id_size: 2,
+                    byte_order: ByteOrder::LittleEndian,
+                    ..Header::default()
+                },
+                on_read: Action::DoNothing,
+                max_fragment_size: 500,
+                timeout: Duration::from_secs(2),
+                ..Config::default()
+            },
+            config
+        );
+
+        assert!(Config::try_from(ProtoConfig {
+            on_write: Some(ActionValue { value: 42 }),
+            ..ProtoConfig::from(Config::default())
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            header: Header {
+                id_size: 8,
+                index_size: 2,
+                count_size: 2,
+                byte_order: ByteOrder::LittleEndian,
+            },
+            on_read: Action::Fragment,
+            on_write: Action::Defragment,
+            max_fragment_size: 1400,
+            max_message_size: 1 << 20,
+            timeout: Duration::from_millis(500),
+            max_messages: 10,
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
+    #[test]
+    fn factory_config() {
+        let create = |yaml: &str| {
+            let config = serde_yaml::from_str::<Value>(yaml).unwrap();
+            FragmentFactory::default()
+                .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+        };
+
+        assert!(FragmentFactory::default()
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), None))
+            .is_ok());
+        assert!(create(
+            "
+header:
+  id_size: 2
+  index_size: 1
+  count_size: 1
+  byte_order: LITTLE_ENDIAN
+on_read: DEFRAGMENT
+on_write: FRAGMENT
+max_fragment_size: 1200
+timeout: 500ms
+"
+        )
+        .is_ok());
+        assert!(create("header: { id_size: 0 }").is_err());
+        assert!(create("header: { count_size: 9 }").is_err());
+        assert!(create("max_fragment_size: 6").is_err());
+        assert!(create("max_messages: 0").is_err());
+        assert!(create("timeout: 0s").is_err());
+    }
+
+    #[test]
+    fn fragment_and_defragment() {
+        let sender = fragment(Config {
+            max_fragment_size: 106,
+            ..Config::default()
+        });
+        let receiver = fragment(Config::default());
+
+        let message = (0..250).map(|i| i as u8).collect::<Vec<_>>();
+        let fragments = write(&sender, &message).unwrap();
+        assert_eq!(3, fragments.len());
+        assert_eq!(&[0, 0, 0, 0, 0, 3], &fragments[0][..6]);
+        assert_eq!(&[0, 0, 0, 0, 2, 3], &fragments[2][..6]);
+        assert_eq!(
+            vec![106, 106, 56],
+            fragments.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(1, sender.metrics.messages_fragmented.get());
+        assert_eq!(3, sender.metrics.fragments_created.get());
+
+        // Fragments can arrive in any order.
+        assert_eq!(None, read(&receiver, &fragments[2]));
+        assert_eq!(BUFFERED_REASON, drop_reason::take());
+        assert_eq!(None, read(&receiver, &fragments[0]));
+        assert_eq!(150, receiver.memory_usage().unwrap());
+        assert_eq!(1, receiver.metrics.pending_messages.get());
+        assert_eq!(Some(vec![message]), read(&receiver, &fragments[1]));
+        assert_eq!(1, receiver.metrics.messages_reassembled.get());
+        assert_eq!(0, receiver.metrics.pending_messages.get());
+        assert_eq!(0, receiver.memory_usage().unwrap());
+
+        // The next message gets a new id.
+        let fragments = write(&sender, b"hello").unwrap();
+        assert_eq!(
+            vec![vec![0, 0, 0, 1, 0, 1, b'h', b'e', b'l', b'l', b'o']],
+            fragments
+        );
+        assert_eq!(
+            Some(vec![b"hello".to_vec()]),
+            read(&receiver, &fragments[0])
+        );
+    }
+
+    #[test]
+    fn little_endian_header() {
+        let filter = fragment(Config {
+            header: Header {
+                id_size: 2,
+                index_size: 2,
+                count_size: 2,
+                byte_order: ByteOrder::LittleEndian,
+            },
+            on_read: Action::Fragment,
+            max_fragment_size: 8,
+            ..Config::default()
+        });
+
+        let fragments = read(&filter, b"abcd").unwrap();
+        assert_eq!(
+            vec![
+                vec![0, 0, 0, 0, 2, 0, b'a', b'b'],
+                vec![0, 0, 1, 0, 2, 0, b'c', b'd'],
+            ],
+            fragments
+        );
+
+        let fragments = fragments.into_iter().rev().collect::<Vec<_>>();
+        assert_eq!(None, write_defragment(&filter, &fragments[0]));
+        assert_eq!(
+            Some(b"abcd".to_vec()),
+            write_defragment(&filter, &fragments[1])
+        );
+    }
+
+    /// Writes `contents` through `filter` as if it was configured to
+    /// defragment packets written to clients.
+    fn write_defragment(filter: &Fragment, contents: &[u8]) -> Option<Vec<u8>> {
+        let endpoint = Endpoint::from_address("127.0.0.1:7001".parse().unwrap());
+        filter
+            .defragment(endpoint.address, Some(CLIENT.parse().unwrap()), contents)
+            .unwrap()
+    }
+
+    #[test]
+    fn invalid_fragments() {
+        let filter = fragment(Config {
+            max_message_size: 10,
+            ..Config::default()
+        });
+
+        // Too short for a header.
+        assert_eq!(None, read(&filter, &[0, 0, 0]));
+        assert_eq!(INVALID_REASON, drop_reason::take());
+        // The index is past the end of the message.
+        assert_eq!(None, read(&filter, &[0, 0, 0, 0, 2, 2]));
+        assert_eq!(INVALID_REASON, drop_reason::take());
+
+        assert_eq!(None, read(&filter, &[0, 0, 0, 0, 0, 2, 1, 2, 3]));
+        assert_eq!(None, read(&filter, &[0, 0, 0, 0, 0, 2, 1, 2, 3]));
+        assert_eq!(DUPLICATE_REASON, drop_reason::take());
+        // The fragment has a different count to the rest of its message.
+        assert_eq!(None, read(&filter, &[0, 0, 0, 0, 1, 3, 4]));
+        assert_eq!(INVALID_REASON, drop_reason::take());
+
+        assert_eq!(
+            None,
+            read(&filter, &[0, 0, 0, 0, 1, 2, 4, 5, 6, 7, 8, 9, 10, 11])
+        );
+        assert_eq!(TOO_LARGE_REASON, drop_reason::take());
+        assert_eq!(0, filter.metrics.pending_messages.get());
+        assert_eq!(
+            1,
+            filter
+                .metrics
+                .packets_dropped
+                .with_label_values(&[DUPLICATE_REASON])
+                .get()
+        );
+    }
+
+    #[test]
+    fn too_many_fragments() {
+        let filter = fragment(Config {
+            max_fragment_size: 7,
+            ..Config::default()
+        });
+        assert!(write(&filter, &[0; 255]).is_some());
+        assert_eq!(None, write(&filter, &[0; 256]));
+        assert_eq!(TOO_MANY_FRAGMENTS_REASON, drop_reason::take());
+    }
+
+    #[test]
+    fn expire_incomplete_messages() {
+        let filter = fragment(Config {
+            timeout: Duration::from_millis(10),
+            ..Config::default()
+        });
+
+        assert_eq!(None, read(&filter, &[0, 0, 0, 0, 0, 2, 1]));
+        std::thread::sleep(Duration::from_millis(20));
+        // The first fragment has expired, so the message starts over.
+        assert_eq!(None, read(&filter, &[0, 0, 0, 0, 1, 2, 2]));
+        assert_eq!(1, filter.metrics.messages_expired.get());
+        assert_eq!(
+            Some(vec![vec![1, 2]]),
+            read(&filter, &[0, 0, 0, 0, 0, 2, 1])
+        );
+    }
+}
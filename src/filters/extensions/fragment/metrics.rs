@@ -0,0 +1,70 @@
+/*
+ * Copyright 2020 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *       http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ */
+
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Registry, Result as MetricsResult};
+
+use crate::filters::FilterMetrics;
+
+use super::Fragment;
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped: IntCounterVec,
+    pub(super) fragments_buffered: IntCounter,
+    pub(super) messages_reassembled: IntCounter,
+    pub(super) messages_expired: IntCounter,
+    pub(super) messages_fragmented: IntCounter,
+    pub(super) fragments_created: IntCounter,
+    pub(super) pending_messages: IntGauge,
+}
+
+impl Metrics {
+    pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let metrics = FilterMetrics::new(registry, Fragment::FILTER_NAME);
+        Ok(Metrics {
+            packets_dropped: metrics.counter_vec(
+                "packets_dropped",
+                "Total number of packets dropped as they could not be reassembled or fragmented. \
+                 labels: reason.",
+                &["reason"],
+            )?,
+            fragments_buffered: metrics.counter(
+                "fragments_buffered",
+                "Total number of fragments held until the rest of their message was received.",
+            )?,
+            messages_reassembled: metrics.counter(
+                "messages_reassembled",
+                "Total number of messages reassembled from their fragments.",
+            )?,
+            messages_expired: metrics.counter(
+                "messages_expired",
+                "Total number of messages whose fragments were discarded as incomplete.",
+            )?,
+            messages_fragmented: metrics.counter(
+                "messages_fragmented",
+                "Total number of messages split into fragments.",
+            )?,
+            fragments_created: metrics.counter(
+                "fragments_created",
+                "Total number of fragments that messages were split into.",
+            )?,
+            pending_messages: metrics.gauge(
+                "pending_messages",
+                "Number of messages waiting for the rest of their fragments.",
+            )?,
+        })
+    }
+}
@@ -0,0 +1,828 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod metrics;
+
+crate::include_proto!("quilkin.extensions.filters.handoff.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::handoff::v1beta1 as proto;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64_serde::base64_serde_type;
+use hmac::{Hmac, Mac, NewMac};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use slog::{debug, o, Logger};
+
+use crate::cluster::Endpoint;
+use crate::config::RetainedItems;
+use crate::filters::{extensions::handoff::metrics::Metrics, prelude::*};
+use crate::secret::SecretRef;
+
+use self::quilkin::extensions::filters::handoff::v1beta1::{
+    Handoff as ProtoConfig, SecretRef as ProtoSecretRef,
+};
+
+base64_serde_type!(Base64Standard, base64::STANDARD);
+
+/// The size in bytes of the signature of a control packet.
+const SIGNATURE_SIZE: usize = 32;
+/// The size in bytes of the timestamp of a control packet.
+const TIMESTAMP_SIZE: usize = 8;
+
+/// How long a client can go without sending a packet before its pinned
+/// endpoint is forgotten, matching the session timeout.
+const PIN_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// The maximum number of clients pinned to an endpoint. Once reached, idle
+/// pins are removed before a new one is added.
+const MAX_PINNED_CLIENTS: usize = 100_000;
+
+/// Config represents a `Handoff` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Config {
+    /// The secret that control packets are signed with.
+    #[serde(with = "Base64Standard", default)]
+    pub secret: Vec<u8>,
+    /// A reference to the secret that control packets are signed with, as
+    /// an alternative to setting `secret`.
+    #[serde(default)]
+    pub secret_ref: Option<SecretRef>,
+    /// The bytes that control packets start with.
+    #[serde(with = "Base64Standard", default = "default_prefix")]
+    pub prefix: Vec<u8>,
+    /// How old a control packet can be before it is rejected.
+    #[serde(with = "humantime_serde", default = "default_max_age")]
+    pub max_age: Duration,
+    /// The key in each endpoint's metadata of a secret of its own, which the
+    /// control packets that it sends are signed with instead of `secret`.
+    #[serde(default)]
+    pub endpoint_secret_key: Option<String>,
+}
+
+/// default value for [`Config::prefix`]
+fn default_prefix() -> Vec<u8> {
+    b"QUILKIN_HANDOFF".to_vec()
+}
+
+/// default value for [`Config::max_age`]
+fn default_max_age() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            secret: p.secret,
+            secret_ref: p.secret_ref.map(|secret_ref| SecretRef {
+                provider: secret_ref.provider,
+                key: secret_ref.key,
+            }),
+            prefix: p.prefix.unwrap_or_else(default_prefix),
+            max_age: p
+                .max_age
+                .map(|max_age| {
+                    max_age.try_into().map_err(|err| {
+                        ConvertProtoConfigError::new(
+                            format!("invalid duration: {:?}", err),
+                            Some("max_age".into()),
+                        )
+                    })
+                })
+                .transpose()?
+                .unwrap_or_else(default_max_age),
+            endpoint_secret_key: p.endpoint_secret_key,
+        })
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            secret: config.secret,
+            prefix: Some(config.prefix),
+            max_age: Some(config.max_age.into()),
+            secret_ref: config.secret_ref.map(|secret_ref| ProtoSecretRef {
+                provider: secret_ref.provider,
+                key: secret_ref.key,
+            }),
+            endpoint_secret_key: config.endpoint_secret_key,
+        }
+    }
+}
+
+/// The `Handoff` filter lets the endpoint that a client is talking to hand
+/// the client over to another endpoint, by sending a signed control packet.
+/// The client's packets are then only sent to the new endpoint.
+#[crate::filter("quilkin.extensions.filters.handoff.v1beta1.Handoff")]
+struct Handoff {
+    log: Logger,
+    /// The secret of endpoints without one of their own, which is empty if
+    /// only endpoints with their own secret can send control packets.
+    secret: Vec<u8>,
+    endpoint_secret_key: Option<String>,
+    prefix: Vec<u8>,
+    max_age: Duration,
+    metrics: Metrics,
+    /// The endpoint that each client has been handed over to, and when the
+    /// client last sent a packet.
+    pinned: Mutex<HashMap<SocketAddr, (SocketAddr, Instant)>>,
+}
+
+/// Factory for the Handoff filter
+pub struct HandoffFactory {
+    log: Logger,
+}
+
+impl HandoffFactory {
+    pub fn new(base: &Logger) -> Self {
+        HandoffFactory { log: base.clone() }
+    }
+}
+
+impl FilterFactory for HandoffFactory {
+    fn name(&self) -> &'static str {
+        Handoff::FILTER_NAME
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("handoff/schema.yaml"))
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let mut config = self
+            .require_config(args.config)?
+            .deserialize::<Config, ProtoConfig>(self.name())?;
+
+        if let Some(secret_ref) = &config.secret_ref {
+            if !config.secret.is_empty() {
+                return Err(Error::FieldInvalid {
+                    field: "secret_ref".into(),
+                    reason: "only one of `secret` and `secret_ref` can be set".into(),
+                });
+            }
+            config.secret = args
+                .secret_providers
+                .get(secret_ref)
+                .map_err(|err| Error::FieldInvalid {
+                    field: "secret_ref".into(),
+                    reason: err.to_string(),
+                })?;
+        }
+        match &config.endpoint_secret_key {
+            Some(key) if key.is_empty() => {
+                return Err(Error::FieldInvalid {
+                    field: "endpoint_secret_key".into(),
+                    reason: "value must not be empty".into(),
+                });
+            }
+            Some(_) => {}
+            None if config.secret.is_empty() => {
+                return Err(Error::FieldInvalid {
+                    field: "secret".into(),
+                    reason: "value must not be empty unless `endpoint_secret_key` is set".into(),
+                });
+            }
+            None => {}
+        }
+        if config.prefix.is_empty() {
+            return Err(Error::FieldInvalid {
+                field: "prefix".into(),
+                reason: "value must not be empty".into(),
+            });
+        }
+
+        Ok(Box::new(Handoff::new(
+            &self.log,
+            config,
+            Metrics::new(&args.metrics_registry)?,
+        )))
+    }
+}
+
+/// A client pinned to an endpoint, as exported by [`Handoff::export_state`].
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Pin {
+    client: SocketAddr,
+    endpoint: SocketAddr,
+}
+
+/// Why a control packet was rejected.
+#[derive(Debug, PartialEq)]
+enum Rejection {
+    Malformed,
+    InvalidSignature,
+    Expired,
+    NoSecret,
+}
+
+impl Handoff {
+    fn new(base: &Logger, config: Config, metrics: Metrics) -> Self {
+        Self {
+            log: base.new(o!("source" => "extensions::Handoff")),
+            secret: config.secret,
+            endpoint_secret_key: config.endpoint_secret_key,
+            prefix: config.prefix,
+            max_age: config.max_age,
+            metrics,
+            pinned: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the secret that the control packets sent by `endpoint` are
+    /// signed with: its own secret, if its metadata has one, or else the
+    /// filter's secret. An endpoint whose own secret is invalid has none, so
+    /// that it can't fall back to the filter's secret.
+    fn secret(&self, endpoint: &Endpoint) -> Result<Cow<[u8]>, Rejection> {
+        let value = self.endpoint_secret_key.as_ref().and_then(|key| {
+            endpoint
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get(key))
+        });
+        let secret = match value {
+            Some(serde_json::Value::String(secret)) => {
+                Cow::Owned(base64::decode(secret).map_err(|_| Rejection::NoSecret)?)
+            }
+            Some(_) => return Err(Rejection::NoSecret),
+            None => Cow::Borrowed(self.secret.as_slice()),
+        };
+        if secret.is_empty() {
+            return Err(Rejection::NoSecret);
+        }
+        Ok(secret)
+    }
+
+    /// Returns the endpoint address in a control packet sent by `endpoint`,
+    /// which consists of the prefix, an HMAC-SHA256 signature, and the signed
+    /// payload: the time the packet was created (in seconds since the UNIX
+    /// epoch, as a big endian u64) followed by the endpoint address as a
+    /// UTF-8 string.
+    fn parse_control_packet(
+        &self,
+        endpoint: &Endpoint,
+        contents: &[u8],
+    ) -> Result<SocketAddr, Rejection> {
+        let contents = &contents[self.prefix.len()..];
+        if contents.len() < SIGNATURE_SIZE + TIMESTAMP_SIZE {
+            return Err(Rejection::Malformed);
+        }
+        let (signature, payload) = contents.split_at(SIGNATURE_SIZE);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret(endpoint)?)
+            .map_err(|_| Rejection::InvalidSignature)?;
+        mac.update(payload);
+        mac.verify(signature).map_err(|_| Rejection::InvalidSignature)?;
+
+        let (timestamp, address) = payload.split_at(TIMESTAMP_SIZE);
+        let mut timestamp_bytes = [0; TIMESTAMP_SIZE];
+        timestamp_bytes.copy_from_slice(timestamp);
+        let created_at = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(timestamp_bytes));
+        let age = match SystemTime::now().duration_since(created_at) {
+            Ok(age) => age,
+            // Allow for the clocks of the proxy and the endpoint to be skewed.
+            Err(err) => err.duration(),
+        };
+        if age > self.max_age {
+            return Err(Rejection::Expired);
+        }
+
+        std::str::from_utf8(address)
+            .ok()
+            .and_then(|address| address.parse().ok())
+            .ok_or(Rejection::Malformed)
+    }
+
+    /// Pins `client` to the endpoint at `endpoint`.
+    fn pin(&self, client: SocketAddr, endpoint: SocketAddr) {
+        let now = Instant::now();
+        let mut pinned = self.pinned.lock();
+        if pinned.len() >= MAX_PINNED_CLIENTS && !pinned.contains_key(&client) {
+            pinned.retain(|_, (_, last_seen)| now.duration_since(*last_seen) < PIN_IDLE_TIMEOUT);
+            if pinned.len() >= MAX_PINNED_CLIENTS {
+                return;
+            }
+        }
+        pinned.insert(client, (endpoint, now));
+    }
+}
+
+impl Filter for Handoff {
+    fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        let endpoint = {
+            let mut pinned = self.pinned.lock();
+            match pinned.get_mut(&ctx.from) {
+                Some((endpoint, last_seen)) => {
+                    *last_seen = Instant::now();
+                    *endpoint
+                }
+                None => return Some(ctx.into()),
+            }
+        };
+
+        match ctx.endpoints.retain(|e| e.address == endpoint) {
+            RetainedItems::None => {
+                // The endpoint is gone, so route the client's packets as usual.
+                self.pinned.lock().remove(&ctx.from);
+                self.metrics.handoffs_ended_total.inc();
+                debug!(
+                    self.log,
+                    "Pinned endpoint is no longer available";
+                    "client" => ctx.from,
+                    "endpoint" => endpoint
+                );
+            }
+            _ => self.metrics.routing.retained(&ctx.endpoints),
+        }
+        Some(ctx.into())
+    }
+
+    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        if !ctx.contents.starts_with(&self.prefix) {
+            return Some(ctx.into());
+        }
+
+        // Control packets are never forwarded to the client, whether or not
+        // they are valid.
+        match self.parse_control_packet(ctx.endpoint, &ctx.contents) {
+            Ok(endpoint) => {
+                self.pin(ctx.to, endpoint);
+                self.metrics.handoffs_total.inc();
+                debug!(
+                    self.log,
+                    "Client handed over";
+                    "client" => ctx.to,
+                    "from" => ctx.from,
+                    "endpoint" => endpoint
+                );
+            }
+            Err(Rejection::Malformed) => self.metrics.control_packets_rejected_malformed.inc(),
+            Err(Rejection::InvalidSignature) => self
+                .metrics
+                .control_packets_rejected_invalid_signature
+                .inc(),
+            Err(Rejection::Expired) => self.metrics.control_packets_rejected_expired.inc(),
+            Err(Rejection::NoSecret) => self.metrics.control_packets_rejected_no_secret.inc(),
+        }
+        drop_packet("ControlPacket")
+    }
+
+    /// Returns the endpoint that each client has been handed over to.
+    fn export_state(&self) -> Option<serde_json::Value> {
+        let pins = self
+            .pinned
+            .lock()
+            .iter()
+            .map(|(client, (endpoint, _))| Pin {
+                client: *client,
+                endpoint: *endpoint,
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_value(pins).ok()
+    }
+
+    /// Pins each client to the endpoint it had been handed over to, as if it
+    /// had just sent a packet.
+    fn import_state(&self, state: serde_json::Value) -> Result<(), Error> {
+        let pins = serde_json::from_value::<Vec<Pin>>(state)
+            .map_err(|err| Error::DeserializeFailed(err.to_string()))?;
+        for Pin { client, endpoint } in pins {
+            self.pin(client, endpoint);
+        }
+        Ok(())
+    }
+
+    /// Returns the memory held by the pinned clients.
+    fn memory_usage(&self) -> Option<usize> {
+        let capacity = self.pinned.lock().capacity();
+        Some(capacity * std::mem::size_of::<(SocketAddr, (SocketAddr, Instant))>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use hmac::{Hmac, Mac, NewMac};
+    use prometheus::Registry;
+    use serde_yaml::{Mapping, Value};
+    use sha2::Sha256;
+
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::{CreateFilterArgs, Filter, FilterFactory, ReadContext, WriteContext};
+    use crate::secret::SecretRef;
+    use crate::test_utils::{assert_filter_read_no_change, assert_write_no_change, logger};
+
+    use super::{
+        default_max_age, default_prefix, Config, Handoff, HandoffFactory, Metrics, Pin,
+        ProtoConfig, Rejection,
+    };
+
+    const SECRET: &[u8] = b"secret";
+
+    fn handoff() -> Handoff {
+        Handoff::new(
+            &logger(),
+            Config {
+                secret: SECRET.to_vec(),
+                secret_ref: None,
+                prefix: default_prefix(),
+                max_age: default_max_age(),
+                endpoint_secret_key: None,
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    fn control_packet(secret: &[u8], created_at: SystemTime, endpoint: &str) -> Vec<u8> {
+        let mut payload = created_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_be_bytes()
+            .to_vec();
+        payload.extend_from_slice(endpoint.as_bytes());
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(&payload);
+
+        let mut packet = default_prefix();
+        packet.extend_from_slice(&mac.finalize().into_bytes());
+        packet.extend(payload);
+        packet
+    }
+
+    fn write(filter: &Handoff, client: SocketAddr, contents: Vec<u8>) -> Option<Vec<u8>> {
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                client,
+                contents,
+            ))
+            .map(|response| response.contents)
+    }
+
+    fn read(filter: &Handoff, client: SocketAddr) -> Vec<SocketAddr> {
+        let endpoints = Endpoints::new(vec![
+            Endpoint::from_address("127.0.0.1:80".parse().unwrap()),
+            Endpoint::from_address("127.0.0.1:81".parse().unwrap()),
+        ])
+        .unwrap();
+        filter
+            .read(ReadContext::new(endpoints.into(), client, b"hello".to_vec()))
+            .unwrap()
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.address)
+            .collect()
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config::try_from(ProtoConfig {
+            secret: SECRET.to_vec(),
+            prefix: None,
+            max_age: Some(prost_types::Duration {
+                seconds: 5,
+                nanos: 0,
+            }),
+            secret_ref: None,
+            endpoint_secret_key: None,
+        })
+        .unwrap();
+        assert_eq!(
+            Config {
+                secret: SECRET.to_vec(),
+                secret_ref: None,
+                prefix: default_prefix(),
+                max_age: Duration::from_secs(5),
+                endpoint_secret_key: None,
+            },
+            config
+        );
+    }
+
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            secret: vec![],
+            secret_ref: Some(SecretRef {
+                provider: "file".into(),
+                key: "/etc/quilkin/handoff".into(),
+            }),
+            prefix: b"HANDOFF".to_vec(),
+            max_age: Duration::from_millis(2500),
+            endpoint_secret_key: Some("myapp.com/handoff_secret".into()),
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
+    #[test]
+    fn factory_invalid_config() {
+        let factory = HandoffFactory::new(&logger());
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), None))
+            .is_err());
+
+        let mut map = Mapping::new();
+        map.insert(Value::String("secret".into()), Value::String("".into()));
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(
+                Registry::default(),
+                Some(&Value::Mapping(map)),
+            ))
+            .is_err());
+
+        // Only one of secret and secret_ref can be set.
+        let config = serde_yaml::from_str::<Value>(
+            "
+secret: c2VjcmV0
+secret_ref:
+  provider: env
+  key: QUILKIN_TEST_HANDOFF_SECRET
+",
+        )
+        .unwrap();
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+            .is_err());
+
+        // The secret is optional with endpoint secrets, but their key isn't.
+        let config = serde_yaml::from_str::<Value>("endpoint_secret_key: secret").unwrap();
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+            .is_ok());
+        let config = serde_yaml::from_str::<Value>("endpoint_secret_key: ''").unwrap();
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+            .is_err());
+    }
+
+    #[test]
+    fn secret_ref() {
+        std::env::set_var("QUILKIN_TEST_HANDOFF_SECRET", "secret");
+        let factory = HandoffFactory::new(&logger());
+        let config = serde_yaml::from_str::<Value>(
+            "
+secret_ref:
+  provider: env
+  key: QUILKIN_TEST_HANDOFF_SECRET
+",
+        )
+        .unwrap();
+        let filter = factory
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+            .unwrap();
+
+        // A control packet signed with the secret hands the client over.
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        let packet = control_packet(SECRET, SystemTime::now(), "127.0.0.1:81");
+        assert!(filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                client,
+                packet,
+            ))
+            .is_none());
+        let endpoints = Endpoints::new(vec![
+            Endpoint::from_address("127.0.0.1:80".parse().unwrap()),
+            Endpoint::from_address("127.0.0.1:81".parse().unwrap()),
+        ])
+        .unwrap();
+        let response = filter
+            .read(ReadContext::new(endpoints.into(), client, b"hello".to_vec()))
+            .unwrap();
+        let expected: SocketAddr = "127.0.0.1:81".parse().unwrap();
+        assert_eq!(
+            vec![expected],
+            response
+                .endpoints
+                .iter()
+                .map(|endpoint| endpoint.address)
+                .collect::<Vec<_>>()
+        );
+
+        // The secret can't be read.
+        let config = serde_yaml::from_str::<Value>(
+            "
+secret_ref:
+  provider: env
+  key: QUILKIN_TEST_MISSING_HANDOFF_SECRET
+",
+        )
+        .unwrap();
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+            .is_err());
+    }
+
+    #[test]
+    fn no_handoff() {
+        let filter = handoff();
+        assert_filter_read_no_change(&filter);
+        assert_write_no_change(&filter);
+    }
+
+    #[test]
+    fn handoff_to_endpoint() {
+        let filter = handoff();
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+
+        let packet = control_packet(SECRET, SystemTime::now(), "127.0.0.1:81");
+        assert_eq!(None, write(&filter, client, packet));
+        assert_eq!(1, filter.metrics.handoffs_total.get());
+
+        let expected: SocketAddr = "127.0.0.1:81".parse().unwrap();
+        assert_eq!(vec![expected], read(&filter, client));
+        assert_eq!(2, read(&filter, other).len());
+        assert_eq!(
+            1,
+            filter
+                .metrics
+                .routing
+                .retained_endpoints
+                .get_sample_count()
+        );
+    }
+
+    #[test]
+    fn export_import_state() {
+        let filter = handoff();
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let endpoint: SocketAddr = "127.0.0.1:81".parse().unwrap();
+
+        let packet = control_packet(SECRET, SystemTime::now(), "127.0.0.1:81");
+        assert_eq!(None, write(&filter, client, packet));
+        let state = filter.export_state().unwrap();
+        assert_eq!(
+            vec![Pin { client, endpoint }],
+            serde_json::from_value::<Vec<Pin>>(state.clone()).unwrap()
+        );
+
+        let imported = handoff();
+        imported.import_state(state).unwrap();
+        assert_eq!(vec![endpoint], read(&imported, client));
+
+        assert!(imported.import_state(serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn handoff_to_unknown_endpoint() {
+        let filter = handoff();
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+
+        let packet = control_packet(SECRET, SystemTime::now(), "127.0.0.1:82");
+        assert_eq!(None, write(&filter, client, packet));
+
+        assert_eq!(2, read(&filter, client).len());
+        assert_eq!(1, filter.metrics.handoffs_ended_total.get());
+        assert!(filter.pinned.lock().is_empty());
+    }
+
+    #[test]
+    fn reject_control_packets() {
+        let filter = handoff();
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let expired = SystemTime::now() - Duration::from_secs(60);
+
+        let cases = vec![
+            (
+                control_packet(b"wrong", SystemTime::now(), "127.0.0.1:81"),
+                Rejection::InvalidSignature,
+            ),
+            (
+                control_packet(SECRET, expired, "127.0.0.1:81"),
+                Rejection::Expired,
+            ),
+            (
+                control_packet(SECRET, SystemTime::now(), "not an address"),
+                Rejection::Malformed,
+            ),
+            (default_prefix(), Rejection::Malformed),
+        ];
+
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        for (packet, expected) in cases {
+            assert_eq!(
+                Err(expected),
+                filter.parse_control_packet(&endpoint, &packet)
+            );
+            assert_eq!(None, write(&filter, client, packet));
+        }
+        assert_eq!(
+            1,
+            filter
+                .metrics
+                .control_packets_rejected_invalid_signature
+                .get()
+        );
+        assert_eq!(1, filter.metrics.control_packets_rejected_expired.get());
+        assert_eq!(2, filter.metrics.control_packets_rejected_malformed.get());
+        assert_eq!(2, read(&filter, client).len());
+    }
+
+    #[test]
+    fn endpoint_secrets() {
+        let filter = Handoff::new(
+            &logger(),
+            Config {
+                secret: vec![],
+                secret_ref: None,
+                prefix: default_prefix(),
+                max_age: default_max_age(),
+                endpoint_secret_key: Some("myapp.com/handoff_secret".into()),
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        );
+        let endpoint = |secret: serde_json::Value| {
+            Endpoint::new(
+                "127.0.0.1:80".parse().unwrap(),
+                Default::default(),
+                Some(serde_json::json!({ "myapp.com/handoff_secret": secret })),
+            )
+        };
+        let packet = |secret: &[u8]| control_packet(secret, SystemTime::now(), "127.0.0.1:81");
+
+        // Each endpoint's control packets are signed with its own secret.
+        let own = endpoint(base64::encode("own").into());
+        assert!(filter.parse_control_packet(&own, &packet(b"own")).is_ok());
+        let other = endpoint(base64::encode("other").into());
+        assert_eq!(
+            Err(Rejection::InvalidSignature),
+            filter.parse_control_packet(&other, &packet(b"own"))
+        );
+
+        // Endpoints without a valid secret can't send control packets.
+        let cases = vec![
+            Endpoint::from_address("127.0.0.1:80".parse().unwrap()),
+            endpoint("".into()),
+            endpoint("not base64!".into()),
+            endpoint(42.into()),
+        ];
+        for endpoint in cases {
+            assert_eq!(
+                Err(Rejection::NoSecret),
+                filter.parse_control_packet(&endpoint, &packet(b""))
+            );
+        }
+
+        // Endpoints without a secret of their own use the filter's secret.
+        let filter = Handoff {
+            secret: SECRET.to_vec(),
+            ..filter
+        };
+        let shared = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        assert!(filter
+            .parse_control_packet(&shared, &packet(SECRET))
+            .is_ok());
+        assert_eq!(
+            Err(Rejection::InvalidSignature),
+            filter.parse_control_packet(&own, &packet(SECRET))
+        );
+
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        assert!(filter
+            .write(WriteContext::new(
+                &other,
+                other.address,
+                client,
+                packet(b"other")
+            ))
+            .is_none());
+        assert_eq!(1, filter.metrics.handoffs_total.get());
+        let expected: SocketAddr = "127.0.0.1:81".parse().unwrap();
+        assert_eq!(vec![expected], read(&filter, client));
+    }
+}
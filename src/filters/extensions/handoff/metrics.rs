@@ -0,0 +1,66 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::core::{AtomicU64, GenericCounter};
+use prometheus::{Registry, Result as MetricsResult};
+
+use crate::filters::{FilterMetrics, RoutingMetrics};
+
+use super::Handoff;
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) handoffs_total: GenericCounter<AtomicU64>,
+    pub(super) handoffs_ended_total: GenericCounter<AtomicU64>,
+    pub(super) control_packets_rejected_invalid_signature: GenericCounter<AtomicU64>,
+    pub(super) control_packets_rejected_expired: GenericCounter<AtomicU64>,
+    pub(super) control_packets_rejected_malformed: GenericCounter<AtomicU64>,
+    pub(super) control_packets_rejected_no_secret: GenericCounter<AtomicU64>,
+    pub(super) routing: RoutingMetrics,
+}
+
+impl Metrics {
+    pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let metrics = FilterMetrics::new(registry, Handoff::FILTER_NAME);
+        let handoffs_total = metrics.counter(
+            "handoffs",
+            "Total number of clients re-pinned to an endpoint by a control packet.",
+        )?;
+        let handoffs_ended_total = metrics.counter(
+            "handoffs_ended",
+            "Total number of clients whose pinned endpoint was no longer available.",
+        )?;
+        let control_packets_rejected = metrics.counter_vec(
+            "control_packets_rejected",
+            "Total number of control packets that were rejected. labels: reason.",
+            &["reason"],
+        )?;
+
+        Ok(Metrics {
+            handoffs_total,
+            handoffs_ended_total,
+            control_packets_rejected_invalid_signature: control_packets_rejected
+                .get_metric_with_label_values(&["InvalidSignature"])?,
+            control_packets_rejected_expired: control_packets_rejected
+                .get_metric_with_label_values(&["Expired"])?,
+            control_packets_rejected_malformed: control_packets_rejected
+                .get_metric_with_label_values(&["Malformed"])?,
+            control_packets_rejected_no_secret: control_packets_rejected
+                .get_metric_with_label_values(&["NoSecret"])?,
+            routing: RoutingMetrics::new(&metrics)?,
+        })
+    }
+}
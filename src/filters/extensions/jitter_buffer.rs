@@ -0,0 +1,486 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod metrics;
+
+crate::include_proto!("quilkin.extensions.filters.jitter_buffer.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::jitter_buffer::v1beta1 as proto;
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::filters::{extensions::jitter_buffer::metrics::Metrics, prelude::*};
+use crate::proxy::Scheduler;
+
+use self::quilkin::extensions::filters::jitter_buffer::v1beta1::JitterBuffer as ProtoConfig;
+
+/// How long a stream can go without a packet before its release schedule
+/// starts over, e.g. at the start of a new talkspurt.
+const STREAM_RESET_GAP: Duration = Duration::from_secs(1);
+/// How long a stream can go without a packet before it is forgotten.
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// The maximum number of streams tracked. Once reached, idle streams are
+/// removed before a new one is added.
+const MAX_STREAMS: usize = 100_000;
+/// The adaptive delay is this multiple of a stream's measured jitter.
+const JITTER_MULTIPLIER: f64 = 4.0;
+/// The weight of each new sample in a stream's interval and jitter
+/// estimates, as used for the interarrival jitter in RFC 3550.
+const ESTIMATE_GAIN: f64 = 1.0 / 16.0;
+
+/// Config represents a `JitterBuffer` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// How long packets are buffered for, or the initial delay if
+    /// `adaptive` is set.
+    #[serde(with = "humantime_serde", default = "default_target_delay")]
+    pub target_delay: Duration,
+    /// Whether to adjust the delay to the jitter measured on each stream.
+    #[serde(default = "default_adaptive")]
+    pub adaptive: bool,
+    /// The lowest delay that adaptive adjustment can choose.
+    #[serde(with = "humantime_serde", default = "default_min_delay")]
+    pub min_delay: Duration,
+    /// The highest delay that a packet can be buffered for.
+    #[serde(with = "humantime_serde", default = "default_max_delay")]
+    pub max_delay: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            target_delay: default_target_delay(),
+            adaptive: default_adaptive(),
+            min_delay: default_min_delay(),
+            max_delay: default_max_delay(),
+        }
+    }
+}
+
+/// default value for [`Config::target_delay`]
+fn default_target_delay() -> Duration {
+    Duration::from_millis(40)
+}
+
+/// default value for [`Config::adaptive`]
+fn default_adaptive() -> bool {
+    true
+}
+
+/// default value for [`Config::min_delay`]
+fn default_min_delay() -> Duration {
+    Duration::from_millis(10)
+}
+
+/// default value for [`Config::max_delay`]
+fn default_max_delay() -> Duration {
+    Duration::from_millis(200)
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        let duration = |value: Option<prost_types::Duration>, field: &str, default: Duration| {
+            value
+                .map(|value| {
+                    value.try_into().map_err(|err| {
+                        ConvertProtoConfigError::new(
+                            format!("invalid duration: {:?}", err),
+                            Some(field.into()),
+                        )
+                    })
+                })
+                .transpose()
+                .map(|value| value.unwrap_or(default))
+        };
+
+        Ok(Self {
+            target_delay: duration(p.target_delay, "target_delay", default_target_delay())?,
+            adaptive: p.adaptive.unwrap_or_else(default_adaptive),
+            min_delay: duration(p.min_delay, "min_delay", default_min_delay())?,
+            max_delay: duration(p.max_delay, "max_delay", default_max_delay())?,
+        })
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            target_delay: Some(config.target_delay.into()),
+            adaptive: Some(config.adaptive),
+            min_delay: Some(config.min_delay.into()),
+            max_delay: Some(config.max_delay.into()),
+        }
+    }
+}
+
+/// The `JitterBuffer` filter holds back the packets sent to each client by
+/// an endpoint, so that they are released at the steady cadence they were
+/// sent at rather than the uneven one they arrived at.
+#[crate::filter("quilkin.extensions.filters.jitter_buffer.v1beta1.JitterBuffer")]
+struct JitterBuffer {
+    config: Config,
+    metrics: Metrics,
+    /// The state of the packets sent by each endpoint to each client.
+    streams: Mutex<HashMap<(SocketAddr, SocketAddr), Stream>>,
+}
+
+/// Tracks the packets of a stream from an endpoint to a client.
+struct Stream {
+    /// When the stream's last packet arrived.
+    last_arrival: Instant,
+    /// When the stream's last packet is released.
+    last_release: Instant,
+    /// The estimated time in seconds between packets, once known.
+    interval: Option<f64>,
+    /// The estimated variation in seconds of the time between packets.
+    jitter: f64,
+}
+
+/// Factory for the JitterBuffer filter
+#[derive(Default)]
+pub struct JitterBufferFactory;
+
+impl FilterFactory for JitterBufferFactory {
+    fn name(&self) -> &'static str {
+        JitterBuffer::FILTER_NAME
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("jitter_buffer/schema.yaml"))
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config = args
+            .config
+            .map(|config| config.deserialize::<Config, ProtoConfig>(self.name()))
+            .transpose()?
+            .unwrap_or_default();
+
+        if config.max_delay > Scheduler::MAX_DELAY {
+            return Err(Error::FieldInvalid {
+                field: "max_delay".into(),
+                reason: format!(
+                    "value must not be greater than {:?}, the longest that packets can be delayed",
+                    Scheduler::MAX_DELAY
+                ),
+            });
+        }
+        if config.min_delay > config.max_delay {
+            return Err(Error::FieldInvalid {
+                field: "min_delay".into(),
+                reason: "value must not be greater than max_delay".into(),
+            });
+        }
+        if config.target_delay < config.min_delay || config.target_delay > config.max_delay {
+            return Err(Error::FieldInvalid {
+                field: "target_delay".into(),
+                reason: "value must be between min_delay and max_delay".into(),
+            });
+        }
+
+        Ok(Box::new(JitterBuffer::new(
+            config,
+            Metrics::new(&args.metrics_registry)?,
+        )))
+    }
+}
+
+impl JitterBuffer {
+    fn new(config: Config, metrics: Metrics) -> Self {
+        Self {
+            config,
+            metrics,
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns how long a stream's packets should be buffered for.
+    fn delay(&self, stream: &Stream) -> Duration {
+        if !self.config.adaptive {
+            return self.config.target_delay;
+        }
+        Duration::from_secs_f64(stream.jitter * JITTER_MULTIPLIER)
+            .max(self.config.min_delay)
+            .min(self.config.max_delay)
+    }
+
+    /// Returns when a packet of the stream identified by `key` that arrived
+    /// at `now` should be released.
+    fn release_at(&self, key: (SocketAddr, SocketAddr), now: Instant) -> Instant {
+        let mut streams = self.streams.lock();
+        if streams.len() >= MAX_STREAMS && !streams.contains_key(&key) {
+            streams.retain(|_, stream| {
+                now.duration_since(stream.last_arrival) < STREAM_IDLE_TIMEOUT
+            });
+        }
+
+        let stream = match streams.get_mut(&key) {
+            Some(stream) => stream,
+            None => {
+                let stream = Stream {
+                    last_arrival: now,
+                    last_release: now,
+                    interval: None,
+                    // Start at the target delay until jitter is measured.
+                    jitter: self.config.target_delay.as_secs_f64() / JITTER_MULTIPLIER,
+                };
+                let release = now + self.delay(&stream);
+                if streams.len() < MAX_STREAMS {
+                    streams.insert(
+                        key,
+                        Stream {
+                            last_release: release,
+                            ..stream
+                        },
+                    );
+                }
+                return release;
+            }
+        };
+
+        let gap = now.duration_since(stream.last_arrival);
+        stream.last_arrival = now;
+        if gap >= STREAM_RESET_GAP {
+            // Buffer up again after a pause, keeping the measured jitter.
+            let release = (now + self.delay(stream)).max(stream.last_release);
+            stream.last_release = release;
+            return release;
+        }
+
+        let gap = gap.as_secs_f64();
+        let interval = match stream.interval {
+            Some(interval) => {
+                stream.jitter += ((gap - interval).abs() - stream.jitter) * ESTIMATE_GAIN;
+                interval + (gap - interval) * ESTIMATE_GAIN
+            }
+            None => gap,
+        };
+        stream.interval = Some(interval);
+
+        let delay = self.delay(stream);
+        let cadence = stream.last_release + Duration::from_secs_f64(interval);
+        let release = if cadence < now {
+            // The packet arrived too late to keep the cadence, so the buffer
+            // has run dry. Buffer up again from this packet.
+            self.metrics.underruns_total.inc();
+            now + delay
+        } else {
+            // Early packets are held for their place in the cadence, unless
+            // that would hold them for much longer than the delay.
+            cadence.min(now + (delay * 2).min(self.config.max_delay))
+        };
+        // Never release a packet before the ones that arrived before it.
+        let release = release.max(stream.last_release);
+        stream.last_release = release;
+        release
+    }
+}
+
+impl Filter for JitterBuffer {
+    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+        let now = Instant::now();
+        let delay = self
+            .release_at((ctx.from, ctx.to), now)
+            .saturating_duration_since(now);
+        self.metrics.delay_seconds.observe(delay.as_secs_f64());
+        ctx.delay += delay;
+        Some(ctx.into())
+    }
+
+    /// Returns the memory held by the state of the streams.
+    fn memory_usage(&self) -> Option<usize> {
+        let capacity = self.streams.lock().capacity();
+        Some(capacity * std::mem::size_of::<((SocketAddr, SocketAddr), Stream)>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use prometheus::Registry;
+    use serde_yaml::{Mapping, Value};
+
+    use crate::filters::{CreateFilterArgs, FilterFactory};
+    use crate::test_utils::assert_filter_read_no_change;
+
+    use super::{Config, JitterBuffer, JitterBufferFactory, Metrics, ProtoConfig};
+
+    fn jitter_buffer(adaptive: bool) -> JitterBuffer {
+        JitterBuffer::new(
+            Config {
+                adaptive,
+                ..Config::default()
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    fn key() -> (SocketAddr, SocketAddr) {
+        (
+            "127.0.0.1:7001".parse().unwrap(),
+            "127.0.0.1:9000".parse().unwrap(),
+        )
+    }
+
+    /// Returns the release times of packets arriving at `arrivals`
+    /// milliseconds after `start`, in milliseconds after `start`.
+    fn release_times(filter: &JitterBuffer, start: Instant, arrivals: &[u64]) -> Vec<u64> {
+        arrivals
+            .iter()
+            .map(|&arrival| {
+                let release = filter.release_at(key(), start + Duration::from_millis(arrival));
+                (release.duration_since(start).as_micros() as u64 + 500) / 1000
+            })
+            .collect()
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config::try_from(ProtoConfig {
+            target_delay: Some(prost_types::Duration {
+                seconds: 0,
+                nanos: 60_000_000,
+            }),
+            adaptive: Some(false),
+            min_delay: None,
+            max_delay: None,
+        })
+        .unwrap();
+        assert_eq!(
+            Config {
+                target_delay: Duration::from_millis(60),
+                adaptive: false,
+                ..Config::default()
+            },
+            config
+        );
+    }
+
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            target_delay: Duration::from_millis(60),
+            adaptive: false,
+            min_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(100),
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
+    #[test]
+    fn factory_config() {
+        let factory = JitterBufferFactory::default();
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), None))
+            .is_ok());
+
+        let mut map = Mapping::new();
+        map.insert(
+            Value::String("target_delay".into()),
+            Value::String("500ms".into()),
+        );
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(
+                Registry::default(),
+                Some(&Value::Mapping(map)),
+            ))
+            .is_err());
+
+        // Packets can't be delayed for longer than the scheduler allows.
+        let mut map = Mapping::new();
+        map.insert(
+            Value::String("max_delay".into()),
+            Value::String("1m".into()),
+        );
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(
+                Registry::default(),
+                Some(&Value::Mapping(map)),
+            ))
+            .is_err());
+    }
+
+    #[test]
+    fn read_no_change() {
+        assert_filter_read_no_change(&jitter_buffer(true));
+    }
+
+    #[test]
+    fn smooths_jitter() {
+        let filter = jitter_buffer(false);
+        let start = Instant::now();
+
+        // Packets sent every 20ms arrive up to 15ms late, but are released
+        // about every 20ms after the 40ms target delay.
+        let releases = release_times(&filter, start, &[0, 20, 55, 62, 95, 100]);
+        assert_eq!(40, releases[0]);
+        for pair in releases.windows(2) {
+            let spacing = pair[1] - pair[0];
+            assert!((19..=21).contains(&spacing), "releases: {:?}", releases);
+        }
+        assert_eq!(0, filter.metrics.underruns_total.get());
+    }
+
+    #[test]
+    fn underrun() {
+        let filter = jitter_buffer(false);
+        let start = Instant::now();
+
+        // The third packet arrives after the buffer has run dry.
+        let releases = release_times(&filter, start, &[0, 20, 100]);
+        assert_eq!(vec![40, 60, 140], releases);
+        assert_eq!(1, filter.metrics.underruns_total.get());
+    }
+
+    #[test]
+    fn reset_after_pause() {
+        let filter = jitter_buffer(false);
+        let start = Instant::now();
+
+        let releases = release_times(&filter, start, &[0, 20, 2000, 2020]);
+        assert_eq!(vec![40, 60, 2040, 2060], releases);
+        assert_eq!(0, filter.metrics.underruns_total.get());
+    }
+
+    #[test]
+    fn adaptive_delay() {
+        let filter = jitter_buffer(true);
+        let start = Instant::now();
+
+        // A steady stream lowers the delay towards the minimum.
+        let arrivals = (0..200).map(|i| i * 20).collect::<Vec<_>>();
+        release_times(&filter, start, &arrivals);
+        let streams = filter.streams.lock();
+        let stream = streams.get(&key()).unwrap();
+        assert_eq!(Duration::from_millis(10), filter.delay(stream));
+    }
+}
@@ -0,0 +1,46 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{Histogram, IntCounter, Registry, Result as MetricsResult};
+
+use crate::filters::FilterMetrics;
+
+use super::JitterBuffer;
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) underruns_total: IntCounter,
+    pub(super) delay_seconds: Histogram,
+}
+
+impl Metrics {
+    pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let metrics = FilterMetrics::new(registry, JitterBuffer::FILTER_NAME);
+        Ok(Metrics {
+            underruns_total: metrics.counter(
+                "underruns",
+                "Total number of packets that arrived too late to keep their stream's cadence.",
+            )?,
+            delay_seconds: metrics.histogram(
+                "delay_seconds",
+                "Time in seconds that packets were buffered for.",
+                Some(vec![
+                    0.0, 0.005, 0.01, 0.02, 0.04, 0.08, 0.16, 0.32, 0.64, 1.28,
+                ]),
+            )?,
+        })
+    }
+}
@@ -14,23 +14,38 @@
  * limitations under the License.
  */
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
+use parking_lot::Mutex;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
 use crate::{config::UpstreamEndpoints, filters::prelude::*, map_proto_enum};
 
-crate::include_proto!("quilkin.extensions.filters.load_balancer.v1alpha1");
+crate::include_proto!("quilkin.extensions.filters.load_balancer.v1beta1");
 
-use self::quilkin::extensions::filters::load_balancer::v1alpha1::{
-    load_balancer::Policy as ProtoPolicy, LoadBalancer as ProtoConfig,
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::load_balancer::v1beta1 as proto;
+
+use self::quilkin::extensions::filters::load_balancer::v1beta1::{
+    load_balancer::Policy as ProtoPolicy, load_balancer::PolicyValue, LoadBalancer as ProtoConfig,
 };
 
-/// Policy represents how a [`LoadBalancerFilter`] distributes
+/// How long a client can go without sending a packet before the endpoint it
+/// was balanced to is forgotten, matching the session timeout.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+/// The maximum number of clients whose endpoints are remembered. Once
+/// reached, idle clients are removed before a new one is added, and clients
+/// beyond it are balanced packet by packet.
+const MAX_SESSIONS: usize = 100_000;
+
+/// Policy represents how a `LoadBalancerFilter` distributes
 /// packets across endpoints.
-#[derive(Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
 pub enum Policy {
     /// Send packets to endpoints in turns.
     #[serde(rename = "ROUND_ROBIN")]
@@ -38,6 +53,9 @@ pub enum Policy {
     /// Send packets to endpoints chosen at random.
     #[serde(rename = "RANDOM")]
     Random,
+    /// Send packets to endpoints in turns, in proportion to their weights.
+    #[serde(rename = "WEIGHTED_ROUND_ROBIN")]
+    WeightedRoundRobin,
 }
 
 impl Default for Policy {
@@ -46,11 +64,17 @@ impl Default for Policy {
     }
 }
 
-/// Config represents configuration for a [`LoadBalancerFilter`].
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-struct Config {
+/// Config represents configuration for a `LoadBalancerFilter`.
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Config {
+    /// How packets are distributed across endpoints.
+    #[serde(default)]
+    pub policy: Policy,
+    /// Whether each packet is balanced on its own. Otherwise, a client's
+    /// packets are sent to the endpoint that its first packet was balanced
+    /// to, for as long as that endpoint is available.
     #[serde(default)]
-    policy: Policy,
+    pub rebalance: bool,
 }
 impl TryFrom<ProtoConfig> for Config {
     type Error = ConvertProtoConfigError;
@@ -64,12 +88,31 @@ impl TryFrom<ProtoConfig> for Config {
                     field = "policy",
                     proto_enum_type = ProtoPolicy,
                     target_enum_type = Policy,
-                    variants = [RoundRobin, Random]
+                    variants = [RoundRobin, Random, WeightedRoundRobin]
                 )
             })
             .transpose()?
             .unwrap_or_else(Policy::default);
-        Ok(Self { policy })
+        Ok(Self {
+            policy,
+            rebalance: p.rebalance.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        let policy = match config.policy {
+            Policy::RoundRobin => ProtoPolicy::RoundRobin,
+            Policy::Random => ProtoPolicy::Random,
+            Policy::WeightedRoundRobin => ProtoPolicy::WeightedRoundRobin,
+        };
+        Self {
+            policy: Some(PolicyValue {
+                value: policy as i32,
+            }),
+            rebalance: Some(config.rebalance),
+        }
     }
 }
 
@@ -114,14 +157,90 @@ impl EndpointChooser for RandomEndpointChooser {
     }
 }
 
+/// WeightedRoundRobinEndpointChooser chooses endpoints in round-robin order,
+/// choosing each endpoint as many times in a row as its weight.
+pub struct WeightedRoundRobinEndpointChooser {
+    next_endpoint: AtomicUsize,
+}
+
+impl WeightedRoundRobinEndpointChooser {
+    fn new() -> Self {
+        WeightedRoundRobinEndpointChooser {
+            next_endpoint: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl EndpointChooser for WeightedRoundRobinEndpointChooser {
+    fn choose_endpoints(&self, endpoints: &mut UpstreamEndpoints) {
+        let count = self.next_endpoint.fetch_add(1, Ordering::Relaxed) as u64;
+        // Note: Weights are at least 1, so the total weight is never 0.
+        let mut slot = count % endpoints.total_weight();
+        let index = endpoints
+            .iter()
+            .position(|endpoint| {
+                let weight = u64::from(endpoint.weight);
+                if slot < weight {
+                    true
+                } else {
+                    slot -= weight;
+                    false
+                }
+            })
+            .expect("BUG: slot should have been within the total weight of the endpoints");
+        endpoints.keep(index)
+            .expect("BUG: unwrap should have been safe because index into endpoints list should be in range");
+    }
+}
+
 /// Creates instances of LoadBalancerFilter.
 #[derive(Default)]
 pub struct LoadBalancerFilterFactory;
 
 /// LoadBalancerFilter load balances packets over the upstream endpoints.
-#[crate::filter("quilkin.extensions.filters.load_balancer.v1alpha1.LoadBalancer")]
+#[crate::filter("quilkin.extensions.filters.load_balancer.v1beta1.LoadBalancer")]
 struct LoadBalancerFilter {
     endpoint_chooser: Box<dyn EndpointChooser>,
+    /// The endpoints that clients were balanced to, unless packets are
+    /// rebalanced.
+    sessions: Option<Sessions>,
+}
+
+/// The endpoint that each client was balanced to, and when it last sent a
+/// packet.
+#[derive(Default)]
+struct Sessions(Mutex<HashMap<SocketAddr, (SocketAddr, Instant)>>);
+
+/// A client and the endpoint it was balanced to, as exported by
+/// [`LoadBalancerFilter::export_state`].
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Session {
+    client: SocketAddr,
+    endpoint: SocketAddr,
+}
+
+impl Sessions {
+    /// Returns the endpoint that `client` was balanced to, if any, updating
+    /// when it last sent a packet.
+    fn endpoint(&self, client: SocketAddr) -> Option<SocketAddr> {
+        let mut sessions = self.0.lock();
+        let (endpoint, last_seen) = sessions.get_mut(&client)?;
+        *last_seen = Instant::now();
+        Some(*endpoint)
+    }
+
+    /// Records that `client` was balanced to the endpoint at `endpoint`.
+    fn stick(&self, client: SocketAddr, endpoint: SocketAddr) {
+        let now = Instant::now();
+        let mut sessions = self.0.lock();
+        if sessions.len() >= MAX_SESSIONS && !sessions.contains_key(&client) {
+            sessions.retain(|_, (_, last_seen)| now.duration_since(*last_seen) < SESSION_TIMEOUT);
+            if sessions.len() >= MAX_SESSIONS {
+                return;
+            }
+        }
+        sessions.insert(client, (endpoint, now));
+    }
 }
 
 impl FilterFactory for LoadBalancerFilterFactory {
@@ -129,6 +248,14 @@ impl FilterFactory for LoadBalancerFilterFactory {
         LoadBalancerFilter::FILTER_NAME
     }
 
+    fn deprecated_names(&self) -> &'static [&'static str] {
+        &["quilkin.extensions.filters.load_balancer.v1alpha1.LoadBalancer"]
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("load_balancer/schema.yaml"))
+    }
+
     fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
         let config: Config = self
             .require_config(args.config)?
@@ -137,17 +264,79 @@ impl FilterFactory for LoadBalancerFilterFactory {
         let endpoint_chooser: Box<dyn EndpointChooser> = match config.policy {
             Policy::RoundRobin => Box::new(RoundRobinEndpointChooser::new()),
             Policy::Random => Box::new(RandomEndpointChooser),
+            Policy::WeightedRoundRobin => Box::new(WeightedRoundRobinEndpointChooser::new()),
         };
 
-        Ok(Box::new(LoadBalancerFilter { endpoint_chooser }))
+        Ok(Box::new(LoadBalancerFilter {
+            endpoint_chooser,
+            sessions: if config.rebalance {
+                None
+            } else {
+                Some(Sessions::default())
+            },
+        }))
     }
 }
 
 impl Filter for LoadBalancerFilter {
     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        let sessions = match &self.sessions {
+            Some(sessions) => sessions,
+            None => {
+                self.endpoint_chooser.choose_endpoints(&mut ctx.endpoints);
+                return Some(ctx.into());
+            }
+        };
+
+        // A client stays on its endpoint however the weights of the
+        // endpoints change, and is only balanced again once its endpoint is
+        // no longer available, e.g as it was removed or marked unhealthy.
+        if let Some(endpoint) = sessions.endpoint(ctx.from) {
+            if !ctx.endpoints.retain(|e| e.address == endpoint).is_none() {
+                return Some(ctx.into());
+            }
+        }
         self.endpoint_chooser.choose_endpoints(&mut ctx.endpoints);
+        if let Some(endpoint) = ctx.endpoints.iter().next() {
+            sessions.stick(ctx.from, endpoint.address);
+        }
         Some(ctx.into())
     }
+
+    /// Returns the endpoint that each client was balanced to.
+    fn export_state(&self) -> Option<serde_json::Value> {
+        let sessions = self
+            .sessions
+            .as_ref()?
+            .0
+            .lock()
+            .iter()
+            .map(|(client, (endpoint, _))| Session {
+                client: *client,
+                endpoint: *endpoint,
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_value(sessions).ok()
+    }
+
+    /// Sends each client to the endpoint it was balanced to, as if it had
+    /// just sent a packet.
+    fn import_state(&self, state: serde_json::Value) -> Result<(), Error> {
+        let sessions = serde_json::from_value::<Vec<Session>>(state)
+            .map_err(|err| Error::DeserializeFailed(err.to_string()))?;
+        if let Some(stuck) = &self.sessions {
+            for Session { client, endpoint } in sessions {
+                stuck.stick(client, endpoint);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the memory held by the endpoints clients were balanced to.
+    fn memory_usage(&self) -> Option<usize> {
+        let capacity = self.sessions.as_ref()?.0.lock().capacity();
+        Some(capacity * std::mem::size_of::<(SocketAddr, (SocketAddr, Instant))>())
+    }
 }
 
 #[cfg(test)]
@@ -156,7 +345,7 @@ mod tests {
     use std::convert::TryFrom;
     use std::net::SocketAddr;
 
-    use super::quilkin::extensions::filters::load_balancer::v1alpha1::{
+    use super::quilkin::extensions::filters::load_balancer::v1beta1::{
         load_balancer::{Policy as ProtoPolicy, PolicyValue},
         LoadBalancer as ProtoConfig,
     };
@@ -182,18 +371,32 @@ mod tests {
     fn get_response_addresses(
         filter: &dyn Filter,
         input_addresses: &[SocketAddr],
+    ) -> Vec<SocketAddr> {
+        get_weighted_response_addresses(
+            filter,
+            &input_addresses
+                .iter()
+                .map(|addr| Endpoint::from_address(*addr))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn get_weighted_response_addresses(
+        filter: &dyn Filter,
+        input_endpoints: &[Endpoint],
+    ) -> Vec<SocketAddr> {
+        get_client_response_addresses(filter, "127.0.0.1:8080", input_endpoints)
+    }
+
+    fn get_client_response_addresses(
+        filter: &dyn Filter,
+        client: &str,
+        input_endpoints: &[Endpoint],
     ) -> Vec<SocketAddr> {
         filter
             .read(ReadContext::new(
-                Endpoints::new(
-                    input_addresses
-                        .iter()
-                        .map(|addr| Endpoint::from_address(*addr))
-                        .collect(),
-                )
-                .unwrap()
-                .into(),
-                "127.0.0.1:8080".parse().unwrap(),
+                Endpoints::new(input_endpoints.to_vec()).unwrap().into(),
+                client.parse().unwrap(),
                 vec![],
             ))
             .unwrap()
@@ -212,9 +415,11 @@ mod tests {
                     policy: Some(PolicyValue {
                         value: ProtoPolicy::Random as i32,
                     }),
+                    rebalance: None,
                 },
                 Some(Config {
                     policy: Policy::Random,
+                    rebalance: false,
                 }),
             ),
             (
@@ -223,23 +428,54 @@ mod tests {
                     policy: Some(PolicyValue {
                         value: ProtoPolicy::RoundRobin as i32,
                     }),
+                    rebalance: None,
                 },
                 Some(Config {
                     policy: Policy::RoundRobin,
+                    rebalance: false,
+                }),
+            ),
+            (
+                "WeightedRoundRobinPolicy",
+                ProtoConfig {
+                    policy: Some(PolicyValue {
+                        value: ProtoPolicy::WeightedRoundRobin as i32,
+                    }),
+                    rebalance: None,
+                },
+                Some(Config {
+                    policy: Policy::WeightedRoundRobin,
+                    rebalance: false,
+                }),
+            ),
+            (
+                "RebalancePackets",
+                ProtoConfig {
+                    policy: None,
+                    rebalance: Some(true),
+                },
+                Some(Config {
+                    policy: Policy::default(),
+                    rebalance: true,
                 }),
             ),
             (
                 "should fail when invalid policy is provided",
                 ProtoConfig {
                     policy: Some(PolicyValue { value: 42 }),
+                    rebalance: None,
                 },
                 None,
             ),
             (
                 "should use correct default values",
-                ProtoConfig { policy: None },
+                ProtoConfig {
+                    policy: None,
+                    rebalance: None,
+                },
                 Some(Config {
                     policy: Policy::default(),
+                    rebalance: false,
                 }),
             ),
         ];
@@ -257,6 +493,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn convert_config_to_proto() {
+        for &policy in &[
+            Policy::RoundRobin,
+            Policy::Random,
+            Policy::WeightedRoundRobin,
+        ] {
+            let config = Config {
+                policy,
+                rebalance: true,
+            };
+            assert_eq!(
+                config,
+                Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+            );
+        }
+    }
+
     #[test]
     fn round_robin_load_balancer_policy() {
         let addresses = vec![
@@ -267,6 +521,7 @@ mod tests {
 
         let yaml = "
 policy: ROUND_ROBIN
+rebalance: true
 ";
         let filter = create_filter(yaml);
 
@@ -283,6 +538,39 @@ policy: ROUND_ROBIN
         }
     }
 
+    #[test]
+    fn weighted_round_robin_load_balancer_policy() {
+        let endpoints = vec![
+            Endpoint {
+                weight: 2,
+                ..Endpoint::from_address("127.0.0.1:8080".parse().unwrap())
+            },
+            Endpoint::from_address("127.0.0.2:8080".parse().unwrap()),
+        ];
+
+        let yaml = "
+policy: WEIGHTED_ROUND_ROBIN
+rebalance: true
+";
+        let filter = create_filter(yaml);
+
+        // Check that each endpoint is chosen as many times in turn as its weight.
+        let expected_sequence = vec![
+            vec![endpoints[0].address],
+            vec![endpoints[0].address],
+            vec![endpoints[1].address],
+        ];
+
+        for _ in 0..10 {
+            assert_eq!(
+                expected_sequence,
+                (0..expected_sequence.len())
+                    .map(|_| get_weighted_response_addresses(filter.as_ref(), &endpoints))
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
     #[test]
     fn random_load_balancer_policy() {
         let addresses = vec![
@@ -293,6 +581,7 @@ policy: ROUND_ROBIN
 
         let yaml = "
 policy: RANDOM
+rebalance: true
 ";
         let filter = create_filter(yaml);
 
@@ -324,4 +613,67 @@ policy: RANDOM
             "the same sequence of addresses were chosen for random load balancer"
         );
     }
+
+    #[test]
+    fn sticky_sessions() {
+        let endpoints = vec![
+            Endpoint::from_address("127.0.0.1:8080".parse().unwrap()),
+            Endpoint::from_address("127.0.0.2:8080".parse().unwrap()),
+        ];
+        let filter = create_filter("policy: WEIGHTED_ROUND_ROBIN");
+        let read = |client: &str, endpoints: &[Endpoint]| {
+            get_client_response_addresses(filter.as_ref(), client, endpoints)
+        };
+        let (first, second) = (vec![endpoints[0].address], vec![endpoints[1].address]);
+
+        assert_eq!(first, read("127.0.0.1:7000", &endpoints));
+        assert_eq!(second, read("127.0.0.1:7001", &endpoints));
+
+        // Clients stay on their endpoints however the weights change.
+        let reweighted = vec![
+            Endpoint {
+                weight: 1,
+                ..endpoints[0].clone()
+            },
+            Endpoint {
+                weight: 100,
+                ..endpoints[1].clone()
+            },
+        ];
+        for _ in 0..10 {
+            assert_eq!(first, read("127.0.0.1:7000", &reweighted));
+            assert_eq!(second, read("127.0.0.1:7001", &reweighted));
+        }
+
+        // A client is only balanced again once its endpoint is gone, and
+        // then stays on its new endpoint.
+        assert_eq!(second, read("127.0.0.1:7000", &endpoints[1..]));
+        assert_eq!(second, read("127.0.0.1:7000", &endpoints));
+    }
+
+    #[test]
+    fn export_import_sessions() {
+        let endpoints = vec![
+            Endpoint::from_address("127.0.0.1:8080".parse().unwrap()),
+            Endpoint::from_address("127.0.0.2:8080".parse().unwrap()),
+        ];
+        let filter = create_filter("policy: ROUND_ROBIN");
+        get_client_response_addresses(filter.as_ref(), "127.0.0.1:7000", &endpoints);
+        let state = filter.export_state().unwrap();
+
+        let imported = create_filter("policy: ROUND_ROBIN");
+        imported.import_state(state).unwrap();
+        assert_eq!(
+            vec![endpoints[0].address],
+            get_client_response_addresses(imported.as_ref(), "127.0.0.1:7001", &endpoints)
+        );
+        // The imported client isn't balanced to the next endpoint in turn.
+        assert_eq!(
+            vec![endpoints[0].address],
+            get_client_response_addresses(imported.as_ref(), "127.0.0.1:7000", &endpoints)
+        );
+
+        let rebalanced = create_filter("policy: ROUND_ROBIN\nrebalance: true");
+        assert!(rebalanced.export_state().is_none());
+    }
 }
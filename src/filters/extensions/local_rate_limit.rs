@@ -15,6 +15,7 @@
  */
 
 use std::convert::{TryFrom, TryInto};
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -23,31 +24,134 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot::{channel, Sender};
 use tokio::time::{self, Instant};
 
+use endpoint::EndpointRateLimiter;
 use metrics::Metrics;
+#[cfg(feature = "redis")]
+use shared::{Decision, SharedRateLimiter, SharedRateLimiterArgs};
 
 use crate::filters::prelude::*;
 
+mod endpoint;
 mod metrics;
+#[cfg(feature = "redis")]
+mod shared;
 
-crate::include_proto!("quilkin.extensions.filters.local_rate_limit.v1alpha1");
-use self::quilkin::extensions::filters::local_rate_limit::v1alpha1::LocalRateLimit as ProtoConfig;
+crate::include_proto!("quilkin.extensions.filters.local_rate_limit.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::local_rate_limit::v1beta1 as proto;
+
+use self::quilkin::extensions::filters::local_rate_limit::v1beta1::{
+    local_rate_limit::{Redis as ProtoRedis, Write as ProtoWrite},
+    LocalRateLimit as ProtoConfig,
+};
 
 /// Config represents a RateLimitFilter's configuration.
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-struct Config {
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Config {
     /// max_packets is the maximum number of packets allowed
     /// to be forwarded by the rate limiter in a given duration.
-    max_packets: usize,
+    pub max_packets: usize,
+    /// period is the duration during which max_packets applies.
+    /// If none is provided, it defaults to 1 second.
+    #[serde(with = "humantime_serde", default = "default_period")]
+    pub period: Duration,
+    /// redis, if provided, shares the rate limit of each client
+    /// with other proxies using the same Redis server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redis: Option<RedisConfig>,
+    /// on_write, if provided, also limits the packets forwarded from each
+    /// endpoint to clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_write: Option<WriteConfig>,
+}
+
+/// RedisConfig represents the configuration of a shared rate limit backend.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RedisConfig {
+    /// address is the URL of the Redis server.
+    pub address: String,
+    /// key_prefix is prepended to the keys used to track packet counts.
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    /// sync_interval is how often packet counts are synchronized with Redis.
+    #[serde(with = "humantime_serde", default = "default_sync_interval")]
+    pub sync_interval: Duration,
+}
+
+/// WriteConfig represents the rate limit of the packets received from each
+/// endpoint.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct WriteConfig {
+    /// max_packets is the maximum number of packets from an endpoint
+    /// allowed to be forwarded in a given duration.
+    pub max_packets: usize,
     /// period is the duration during which max_packets applies.
     /// If none is provided, it defaults to 1 second.
     #[serde(with = "humantime_serde", default = "default_period")]
-    period: Duration,
+    pub period: Duration,
 }
 
 /// default value for [`Config::period`]
 fn default_period() -> Duration {
     Duration::from_secs(1)
 }
+
+/// default value for [`RedisConfig::key_prefix`]
+fn default_key_prefix() -> String {
+    "quilkin.dev/local_rate_limit".into()
+}
+
+/// default value for [`RedisConfig::sync_interval`]
+fn default_sync_interval() -> Duration {
+    Duration::from_millis(100)
+}
+
+impl TryFrom<ProtoRedis> for RedisConfig {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoRedis) -> Result<Self, Self::Error> {
+        Ok(Self {
+            address: p.address,
+            key_prefix: p.key_prefix.unwrap_or_else(default_key_prefix),
+            sync_interval: p
+                .sync_interval
+                .map(|sync_interval| {
+                    sync_interval.try_into().map_err(|err| {
+                        ConvertProtoConfigError::new(
+                            format!("invalid duration: {:?}", err),
+                            Some("redis.sync_interval".into()),
+                        )
+                    })
+                })
+                .transpose()?
+                .unwrap_or_else(default_sync_interval),
+        })
+    }
+}
+
+impl TryFrom<ProtoWrite> for WriteConfig {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoWrite) -> Result<Self, Self::Error> {
+        Ok(Self {
+            max_packets: p.max_packets as usize,
+            period: p
+                .period
+                .map(|period| {
+                    period.try_into().map_err(|err| {
+                        ConvertProtoConfigError::new(
+                            format!("invalid duration: {:?}", err),
+                            Some("on_write.period".into()),
+                        )
+                    })
+                })
+                .transpose()?
+                .unwrap_or_else(default_period),
+        })
+    }
+}
+
 impl TryFrom<ProtoConfig> for Config {
     type Error = ConvertProtoConfigError;
 
@@ -66,10 +170,42 @@ impl TryFrom<ProtoConfig> for Config {
                 })
                 .transpose()?
                 .unwrap_or_else(default_period),
+            redis: p.redis.map(RedisConfig::try_from).transpose()?,
+            on_write: p.on_write.map(WriteConfig::try_from).transpose()?,
         })
     }
 }
 
+impl From<RedisConfig> for ProtoRedis {
+    fn from(config: RedisConfig) -> Self {
+        Self {
+            address: config.address,
+            key_prefix: Some(config.key_prefix),
+            sync_interval: Some(config.sync_interval.into()),
+        }
+    }
+}
+
+impl From<WriteConfig> for ProtoWrite {
+    fn from(config: WriteConfig) -> Self {
+        Self {
+            max_packets: config.max_packets as u64,
+            period: Some(config.period.into()),
+        }
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            max_packets: config.max_packets as u64,
+            period: Some(config.period.into()),
+            redis: config.redis.map(ProtoRedis::from),
+            on_write: config.on_write.map(ProtoWrite::from),
+        }
+    }
+}
+
 /// Creates instances of RateLimitFilter.
 #[derive(Default)]
 pub struct RateLimitFilterFactory;
@@ -78,8 +214,8 @@ pub struct RateLimitFilterFactory;
 /// the token-bucket algorithm.
 /// Packets that violate the rate limit are dropped.
 /// It only applies rate limiting on packets that are destined for the
-/// proxy's endpoints. All other packets flow through the filter untouched.
-#[crate::filter("quilkin.extensions.filters.local_rate_limit.v1alpha1.LocalRateLimit")]
+/// proxy's endpoints, unless packets from each endpoint are also limited.
+#[crate::filter("quilkin.extensions.filters.local_rate_limit.v1beta1.LocalRateLimit")]
 struct RateLimitFilter {
     /// available_tokens is how many tokens are left in the bucket any
     /// any given moment.
@@ -88,6 +224,13 @@ struct RateLimitFilter {
     metrics: Metrics,
     /// shutdown_tx signals the spawned token refill future to exit.
     shutdown_tx: Option<Sender<()>>,
+    /// shared, if set, rate limits each client using a shared backend
+    /// instead of the local token bucket.
+    #[cfg(feature = "redis")]
+    shared: Option<SharedRateLimiter>,
+    /// on_write, if set, rate limits the packets received from each
+    /// endpoint.
+    on_write: Option<EndpointRateLimiter>,
 }
 
 impl FilterFactory for RateLimitFilterFactory {
@@ -95,22 +238,77 @@ impl FilterFactory for RateLimitFilterFactory {
         RateLimitFilter::FILTER_NAME
     }
 
+    fn deprecated_names(&self) -> &'static [&'static str] {
+        &["quilkin.extensions.filters.local_rate_limit.v1alpha1.LocalRateLimit"]
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("local_rate_limit/schema.yaml"))
+    }
+
     fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
         let config: Config = self
             .require_config(args.config)?
             .deserialize::<Config, ProtoConfig>(self.name())?;
 
         if config.period.lt(&Duration::from_millis(100)) {
-            Err(Error::FieldInvalid {
+            return Err(Error::FieldInvalid {
                 field: "period".into(),
                 reason: "value must be at least 100ms".into(),
-            })
-        } else {
-            Ok(Box::new(RateLimitFilter::new(
-                config,
-                Metrics::new(&args.metrics_registry)?,
-            )))
+            });
+        }
+
+        if let Some(on_write) = &config.on_write {
+            if on_write.period.lt(&Duration::from_millis(100)) {
+                return Err(Error::FieldInvalid {
+                    field: "on_write.period".into(),
+                    reason: "value must be at least 100ms".into(),
+                });
+            }
         }
+
+        let metrics = Metrics::new(&args.metrics_registry)?;
+        #[cfg(not(feature = "redis"))]
+        if config.redis.is_some() {
+            return Err(Error::FieldInvalid {
+                field: "redis".into(),
+                reason: "Quilkin must be built with the `redis` feature to share rate limits"
+                    .into(),
+            });
+        }
+        #[cfg(feature = "redis")]
+        let shared = match &config.redis {
+            Some(redis_config) => {
+                if redis_config.sync_interval.lt(&Duration::from_millis(10)) {
+                    return Err(Error::FieldInvalid {
+                        field: "redis.sync_interval".into(),
+                        reason: "value must be at least 10ms".into(),
+                    });
+                }
+
+                let client = redis::Client::open(redis_config.address.as_str()).map_err(|err| {
+                    Error::FieldInvalid {
+                        field: "redis.address".into(),
+                        reason: err.to_string(),
+                    }
+                })?;
+
+                Some(SharedRateLimiter::new(SharedRateLimiterArgs {
+                    client,
+                    key_prefix: redis_config.key_prefix.clone(),
+                    max_packets: config.max_packets,
+                    period: config.period,
+                    sync_interval: redis_config.sync_interval,
+                    backend_errors_total: metrics.backend_errors_total.clone(),
+                }))
+            }
+            None => None,
+        };
+
+        let filter = RateLimitFilter::new(config, metrics);
+        #[cfg(feature = "redis")]
+        let filter = filter.with_shared(shared);
+        Ok(Box::new(filter))
     }
 }
 
@@ -121,6 +319,10 @@ impl RateLimitFilter {
         let (shutdown_tx, mut shutdown_rx) = channel();
 
         let tokens = Arc::new(AtomicUsize::new(config.max_packets));
+        let on_write = config
+            .on_write
+            .as_ref()
+            .map(|on_write| EndpointRateLimiter::new(on_write.max_packets, on_write.period));
 
         let max_tokens = config.max_packets;
         let period = config.period;
@@ -154,7 +356,34 @@ impl RateLimitFilter {
             available_tokens: tokens,
             metrics,
             shutdown_tx: Some(shutdown_tx),
+            #[cfg(feature = "redis")]
+            shared: None,
+            on_write,
+        }
+    }
+
+    /// with_shared sets the shared rate limiter used in place of the
+    /// local token bucket.
+    #[cfg(feature = "redis")]
+    fn with_shared(mut self, shared: Option<SharedRateLimiter>) -> Self {
+        self.shared = shared;
+        self
+    }
+
+    /// acquire_client_token returns whether the client at `address` may
+    /// forward another packet, as decided by the shared backend if set and
+    /// it's counting the client's packets, or else by the local token bucket.
+    #[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+    fn acquire_client_token(&self, address: IpAddr) -> Option<()> {
+        #[cfg(feature = "redis")]
+        if let Some(shared) = &self.shared {
+            match shared.acquire_token(address) {
+                Decision::Forward => return Some(()),
+                Decision::Drop => return None,
+                Decision::Untracked => {}
+            }
         }
+        self.acquire_token()
     }
 
     /// acquire_token is called on behalf of every packet that is eligible
@@ -195,11 +424,30 @@ impl Drop for RateLimitFilter {
 
 impl Filter for RateLimitFilter {
     fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
-        self.acquire_token().map(|()| ctx.into()).or_else(|| {
+        let token = self.acquire_client_token(ctx.from.ip());
+        token.map(|()| ctx.into()).or_else(|| {
             self.metrics.packets_dropped_total.inc();
-            None
+            drop_packet("RateLimited")
         })
     }
+
+    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        let on_write = match &self.on_write {
+            Some(on_write) => on_write,
+            None => return Some(ctx.into()),
+        };
+
+        match on_write.acquire_token(ctx.endpoint.address) {
+            Some(()) => Some(ctx.into()),
+            None => {
+                self.metrics
+                    .write_packets_dropped_total
+                    .with_label_values(&[&ctx.endpoint.address.to_string()])
+                    .inc();
+                drop_packet("RateLimited")
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -210,12 +458,14 @@ mod tests {
     use prometheus::Registry;
     use tokio::time;
 
-    use super::ProtoConfig;
+    use super::{ProtoConfig, ProtoRedis, ProtoWrite};
     use crate::cluster::Endpoint;
     use crate::config::Endpoints;
     use crate::filters::{
-        extensions::local_rate_limit::{metrics::Metrics, Config, RateLimitFilter},
-        Filter, ReadContext,
+        extensions::local_rate_limit::{
+            metrics::Metrics, Config, RateLimitFilter, RedisConfig, WriteConfig,
+        },
+        Filter, ReadContext, WriteContext,
     };
     use crate::test_utils::assert_write_no_change;
 
@@ -231,10 +481,14 @@ mod tests {
                 ProtoConfig {
                     max_packets: 10,
                     period: Some(Duration::from_secs(2).into()),
+                    redis: None,
+                    on_write: None,
                 },
                 Some(Config {
                     max_packets: 10,
                     period: Duration::from_secs(2),
+                    redis: None,
+                    on_write: None,
                 }),
             ),
             (
@@ -242,10 +496,58 @@ mod tests {
                 ProtoConfig {
                     max_packets: 10,
                     period: None,
+                    redis: None,
+                    on_write: None,
+                },
+                Some(Config {
+                    max_packets: 10,
+                    period: Duration::from_secs(1),
+                    redis: None,
+                    on_write: None,
+                }),
+            ),
+            (
+                "should convert redis config",
+                ProtoConfig {
+                    max_packets: 10,
+                    period: None,
+                    redis: Some(ProtoRedis {
+                        address: "redis://127.0.0.1:6379".into(),
+                        key_prefix: None,
+                        sync_interval: Some(Duration::from_millis(50).into()),
+                    }),
+                    on_write: None,
                 },
                 Some(Config {
                     max_packets: 10,
                     period: Duration::from_secs(1),
+                    redis: Some(RedisConfig {
+                        address: "redis://127.0.0.1:6379".into(),
+                        key_prefix: "quilkin.dev/local_rate_limit".into(),
+                        sync_interval: Duration::from_millis(50),
+                    }),
+                    on_write: None,
+                }),
+            ),
+            (
+                "should convert on_write config",
+                ProtoConfig {
+                    max_packets: 10,
+                    period: None,
+                    redis: None,
+                    on_write: Some(ProtoWrite {
+                        max_packets: 5,
+                        period: None,
+                    }),
+                },
+                Some(Config {
+                    max_packets: 10,
+                    period: Duration::from_secs(1),
+                    redis: None,
+                    on_write: Some(WriteConfig {
+                        max_packets: 5,
+                        period: Duration::from_secs(1),
+                    }),
                 }),
             ),
         ];
@@ -263,12 +565,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            max_packets: 10,
+            period: Duration::from_millis(500),
+            redis: Some(RedisConfig {
+                address: "redis://127.0.0.1:6379".into(),
+                key_prefix: "rate_limit".into(),
+                sync_interval: Duration::from_millis(50),
+            }),
+            on_write: Some(WriteConfig {
+                max_packets: 5,
+                period: Duration::from_millis(200),
+            }),
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
     #[tokio::test]
     async fn initially_available_tokens() {
         // Test that we always start with the max number of tokens available.
         let r = rate_limiter(Config {
             max_packets: 3,
             period: Duration::from_millis(100),
+            redis: None,
+            on_write: None,
         });
 
         assert_eq!(r.acquire_token(), Some(()));
@@ -282,6 +607,8 @@ mod tests {
         let r = rate_limiter(Config {
             max_packets: 2,
             period: Duration::from_millis(100),
+            redis: None,
+            on_write: None,
         });
 
         // Exhaust tokens
@@ -316,6 +643,8 @@ mod tests {
         let r = rate_limiter(Config {
             max_packets: 3,
             period: Duration::from_millis(30),
+            redis: None,
+            on_write: None,
         });
 
         // Use up some of the tokens.
@@ -336,6 +665,8 @@ mod tests {
         let r = rate_limiter(Config {
             max_packets: 0,
             period: Duration::from_millis(100),
+            redis: None,
+            on_write: None,
         });
 
         // Check that other routes are not affected.
@@ -360,6 +691,8 @@ mod tests {
         let r = rate_limiter(Config {
             max_packets: 1,
             period: Duration::from_millis(100),
+            redis: None,
+            on_write: None,
         });
 
         let result = r
@@ -380,4 +713,48 @@ mod tests {
         // Check that other routes are not affected.
         assert_write_no_change(&r);
     }
+
+    #[tokio::test]
+    async fn filter_write_per_endpoint() {
+        let r = rate_limiter(Config {
+            max_packets: 1,
+            period: Duration::from_millis(100),
+            redis: None,
+            on_write: Some(WriteConfig {
+                max_packets: 1,
+                period: Duration::from_secs(60),
+            }),
+        });
+
+        let endpoint = Endpoint::from_address("127.0.0.1:8080".parse().unwrap());
+        let other = Endpoint::from_address("127.0.0.1:8081".parse().unwrap());
+        let write = |endpoint: &Endpoint| {
+            r.write(WriteContext::new(
+                endpoint,
+                endpoint.address,
+                "127.0.0.1:70".parse().unwrap(),
+                vec![9],
+            ))
+        };
+
+        assert!(write(&endpoint).is_some());
+        assert!(write(&endpoint).is_none());
+        // Packets from other endpoints are still forwarded.
+        assert!(write(&other).is_some());
+
+        assert_eq!(
+            1,
+            r.metrics
+                .write_packets_dropped_total
+                .with_label_values(&["127.0.0.1:8080"])
+                .get()
+        );
+        assert_eq!(
+            0,
+            r.metrics
+                .write_packets_dropped_total
+                .with_label_values(&["127.0.0.1:8081"])
+                .get()
+        );
+    }
 }
@@ -0,0 +1,112 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Rate limits the packets received from each endpoint, so that a
+/// malfunctioning game server can't flood its clients.
+///
+/// Each endpoint is identified by its address and is allowed `max_packets`
+/// within consecutive windows of length `period`, starting from the first
+/// packet received from it.
+pub(super) struct EndpointRateLimiter {
+    max_packets: usize,
+    period: Duration,
+    windows: Mutex<HashMap<SocketAddr, Window>>,
+}
+
+/// The packets received from an endpoint in its current window.
+struct Window {
+    started: Instant,
+    packets: usize,
+}
+
+impl EndpointRateLimiter {
+    pub(super) fn new(max_packets: usize, period: Duration) -> Self {
+        Self {
+            max_packets,
+            period,
+            windows: Mutex::default(),
+        }
+    }
+
+    /// acquire_token is called on behalf of every packet received from
+    /// `endpoint`. It returns whether the endpoint is still within its limit
+    /// for the current window.
+    pub(super) fn acquire_token(&self, endpoint: SocketAddr) -> Option<()> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock();
+        if !windows.contains_key(&endpoint) {
+            // Forget the endpoints that no packets were received from in the
+            // last window, e.g. as they were removed from the cluster.
+            let expiry = self.period * 2;
+            windows.retain(|_, window| now.duration_since(window.started) < expiry);
+        }
+
+        let window = windows.entry(endpoint).or_insert(Window {
+            started: now,
+            packets: 0,
+        });
+        if now.duration_since(window.started) >= self.period {
+            window.started = now;
+            window.packets = 0;
+        }
+        if window.packets >= self.max_packets {
+            return None;
+        }
+        window.packets += 1;
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::EndpointRateLimiter;
+
+    #[tokio::test]
+    async fn acquire_token() {
+        let limiter = EndpointRateLimiter::new(2, Duration::from_millis(50));
+        let endpoint = "127.0.0.1:8080".parse().unwrap();
+        let other = "127.0.0.1:8081".parse().unwrap();
+
+        assert_eq!(limiter.acquire_token(endpoint), Some(()));
+        assert_eq!(limiter.acquire_token(endpoint), Some(()));
+        assert_eq!(limiter.acquire_token(endpoint), None);
+        // Each endpoint has a limit of its own.
+        assert_eq!(limiter.acquire_token(other), Some(()));
+
+        time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(limiter.acquire_token(endpoint), Some(()));
+    }
+
+    #[tokio::test]
+    async fn forget_idle_endpoints() {
+        let limiter = EndpointRateLimiter::new(1, Duration::from_millis(10));
+        limiter.acquire_token("127.0.0.1:8080".parse().unwrap());
+
+        time::sleep(Duration::from_millis(30)).await;
+        limiter.acquire_token("127.0.0.1:8081".parse().unwrap());
+        assert_eq!(1, limiter.windows.lock().len());
+    }
+}
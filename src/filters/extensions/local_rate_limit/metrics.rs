@@ -14,24 +14,37 @@
  * limitations under the License.
  */
 
-use crate::metrics::{filter_opts, CollectorExt};
 use prometheus::core::{AtomicU64, GenericCounter};
-use prometheus::Result as MetricsResult;
-use prometheus::{IntCounter, Registry};
+use prometheus::{IntCounterVec, Registry, Result as MetricsResult};
+
+use crate::filters::FilterMetrics;
+
+use super::RateLimitFilter;
 
 pub(super) struct Metrics {
     pub(super) packets_dropped_total: GenericCounter<AtomicU64>,
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    pub(super) backend_errors_total: GenericCounter<AtomicU64>,
+    pub(super) write_packets_dropped_total: IntCounterVec,
 }
 
 impl Metrics {
     pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let metrics = FilterMetrics::new(registry, RateLimitFilter::FILTER_NAME);
         Ok(Metrics {
-            packets_dropped_total: IntCounter::with_opts(filter_opts(
+            packets_dropped_total: metrics.counter(
                 "packets_dropped",
-                "LocalRateLimit",
                 "Total number of packets dropped due to rate limiting",
-            ))?
-            .register(registry)?,
+            )?,
+            backend_errors_total: metrics.counter(
+                "backend_errors",
+                "Total number of errors encountered while synchronizing with the shared backend",
+            )?,
+            write_packets_dropped_total: metrics.counter_vec(
+                "write_packets_dropped",
+                "Total number of packets from a given endpoint dropped due to rate limiting",
+                &["endpoint"],
+            )?,
         })
     }
 }
@@ -0,0 +1,411 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use prometheus::core::{AtomicU64, GenericCounter};
+use redis::aio::MultiplexedConnection;
+use tokio::sync::oneshot::{channel, Sender};
+use tokio::time;
+
+/// The most clients whose packets are counted locally between
+/// synchronizations, so that packets with spoofed addresses can't grow the
+/// counts without bound while Redis is unreachable.
+const MAX_PENDING_CLIENTS: usize = 100_000;
+
+/// Rate limits clients using packet counts shared with other proxies via Redis.
+///
+/// Each client is identified by its IP address and is allowed `max_packets`
+/// within fixed windows of length `period`. Packets are counted locally and
+/// periodically added to the client's count in Redis, which returns the
+/// total count across all proxies. Once that total reaches `max_packets`,
+/// the client's packets are dropped until the next window.
+///
+/// If Redis cannot be reached, packets are forwarded, and their counts are
+/// added at the next synchronization that succeeds within the same window.
+/// Once the packets of [`MAX_PENDING_CLIENTS`] clients are waiting to be
+/// counted, the packets of other clients are left to the local token bucket.
+pub(super) struct SharedRateLimiter {
+    max_packets: usize,
+    state: Arc<Mutex<State>>,
+    /// shutdown_tx signals the spawned synchronization future to exit.
+    shutdown_tx: Option<Sender<()>>,
+}
+
+/// The local view of the rate limit for the current window.
+#[derive(Default)]
+struct State {
+    /// The window that `pending` and `exhausted` belong to.
+    window: u64,
+    /// Packets forwarded per client since the last synchronization.
+    pending: HashMap<IpAddr, u64>,
+    /// Clients that have reached the limit in the current window.
+    exhausted: HashSet<IpAddr>,
+}
+
+/// What a [`SharedRateLimiter`] decided for a packet.
+#[derive(Debug, PartialEq)]
+pub(super) enum Decision {
+    /// The packet may be forwarded.
+    Forward,
+    /// The client has reached the limit, so the packet is dropped.
+    Drop,
+    /// The packets of too many other clients are waiting to be counted, so
+    /// the packet is left to the local token bucket.
+    Untracked,
+}
+
+/// Arguments to create a [`SharedRateLimiter`].
+pub(super) struct SharedRateLimiterArgs {
+    pub(super) client: redis::Client,
+    pub(super) key_prefix: String,
+    pub(super) max_packets: usize,
+    pub(super) period: Duration,
+    pub(super) sync_interval: Duration,
+    pub(super) backend_errors_total: GenericCounter<AtomicU64>,
+}
+
+impl SharedRateLimiter {
+    /// Returns a new SharedRateLimiter. It spawns a future in the background
+    /// that periodically synchronizes packet counts with Redis.
+    pub(super) fn new(args: SharedRateLimiterArgs) -> Self {
+        let (shutdown_tx, mut shutdown_rx) = channel();
+
+        let state = Arc::new(Mutex::new(State {
+            window: current_window(args.period),
+            ..State::default()
+        }));
+
+        let max_packets = args.max_packets;
+        let sync_state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(args.sync_interval);
+            let mut connection = None;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        if Self::sync(&args, &sync_state, &mut connection).await.is_err() {
+                            args.backend_errors_total.inc();
+                        }
+                    },
+                    _ = &mut shutdown_rx => {
+                        return;
+                    }
+                }
+            }
+        });
+
+        SharedRateLimiter {
+            max_packets,
+            state,
+            shutdown_tx: Some(shutdown_tx),
+        }
+    }
+
+    /// Returns whether the client at `address` may forward another packet.
+    pub(super) fn acquire_token(&self, address: IpAddr) -> Decision {
+        if self.max_packets == 0 {
+            return Decision::Drop;
+        }
+
+        let mut state = self.state.lock();
+        if state.exhausted.contains(&address) {
+            return Decision::Drop;
+        }
+        if state.pending.len() >= MAX_PENDING_CLIENTS && !state.pending.contains_key(&address) {
+            return Decision::Untracked;
+        }
+
+        *state.pending.entry(address).or_insert(0) += 1;
+        Decision::Forward
+    }
+
+    /// Adds the locally counted packets to the shared counts and marks any
+    /// client that has reached the limit as exhausted. If synchronization
+    /// fails, the packets are counted again by the next attempt, unless
+    /// their window has passed by then, or the packets of
+    /// [`MAX_PENDING_CLIENTS`] clients are already waiting to be counted.
+    async fn sync(
+        args: &SharedRateLimiterArgs,
+        state: &Mutex<State>,
+        connection: &mut Option<MultiplexedConnection>,
+    ) -> redis::RedisResult<()> {
+        let (window, pending) = {
+            let mut state = state.lock();
+            let pending = std::mem::take(&mut state.pending);
+            let window = state.window;
+
+            let current_window = current_window(args.period);
+            if current_window != state.window {
+                state.window = current_window;
+                state.exhausted.clear();
+            }
+
+            (window, pending.into_iter().collect::<Vec<_>>())
+        };
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let totals = match Self::push(args, window, &pending, connection).await {
+            Ok(totals) => totals,
+            Err(err) => {
+                let mut state = state.lock();
+                if state.window == window {
+                    for (address, count) in pending {
+                        if state.pending.len() < MAX_PENDING_CLIENTS
+                            || state.pending.contains_key(&address)
+                        {
+                            *state.pending.entry(address).or_insert(0) += count;
+                        }
+                    }
+                }
+                return Err(err);
+            }
+        };
+
+        let mut state = state.lock();
+        if state.window == window {
+            for ((address, _), total) in pending.into_iter().zip(totals) {
+                if total >= args.max_packets as u64 {
+                    state.exhausted.insert(address);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the `pending` packet counts to the shared counts of `window`,
+    /// returning the total count of each client. The connection is only
+    /// kept if this succeeds, so that the next attempt reconnects after a
+    /// failure.
+    async fn push(
+        args: &SharedRateLimiterArgs,
+        window: u64,
+        pending: &[(IpAddr, u64)],
+        connection: &mut Option<MultiplexedConnection>,
+    ) -> redis::RedisResult<Vec<u64>> {
+        let mut conn = match connection.take() {
+            Some(conn) => conn,
+            None => args.client.get_multiplexed_tokio_connection().await?,
+        };
+
+        // Keep keys around long enough to cover clock skew between proxies.
+        let expiry_millis = args.period.as_millis() as usize * 2;
+        let mut pipe = redis::pipe();
+        for (address, count) in pending.iter() {
+            let key = format!("{}:{}:{}", args.key_prefix, address, window);
+            pipe.incr(&key, *count).pexpire(&key, expiry_millis).ignore();
+        }
+        let totals = pipe.query_async(&mut conn).await?;
+        *connection = Some(conn);
+        Ok(totals)
+    }
+}
+
+impl Drop for SharedRateLimiter {
+    fn drop(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            shutdown_tx.send(()).ok();
+        }
+    }
+}
+
+/// Returns the index of the fixed window of length `period` that the current
+/// time falls into. Proxies with synchronized clocks agree on the window.
+fn current_window(period: Duration) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    (now / period.as_millis().max(1)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+    use prometheus::IntCounter;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{current_window, SharedRateLimiter, SharedRateLimiterArgs, State};
+
+    // Nothing listens on this address so synchronization always fails.
+    const UNREACHABLE: &str = "redis://127.0.0.1:1";
+
+    fn args(address: &str, max_packets: usize) -> SharedRateLimiterArgs {
+        SharedRateLimiterArgs {
+            client: redis::Client::open(address).unwrap(),
+            key_prefix: "quilkin".into(),
+            max_packets,
+            // Long enough that the window doesn't change during a test.
+            period: Duration::from_secs(3600),
+            sync_interval: Duration::from_secs(60),
+            backend_errors_total: IntCounter::new("errors", "errors").unwrap(),
+        }
+    }
+
+    fn rate_limiter(max_packets: usize) -> SharedRateLimiter {
+        SharedRateLimiter::new(args(UNREACHABLE, max_packets))
+    }
+
+    fn state(args: &SharedRateLimiterArgs, pending: &[(IpAddr, u64)]) -> Mutex<State> {
+        Mutex::new(State {
+            window: current_window(args.period),
+            pending: pending.iter().copied().collect(),
+            ..State::default()
+        })
+    }
+
+    /// Answers the commands of a single Redis connection, replying to INCRBY
+    /// with `total` and to any other command with 1.
+    async fn fake_redis(listener: TcpListener, total: u64) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let size = stream.read(&mut buf).await.unwrap();
+            if size == 0 {
+                return;
+            }
+            received.extend_from_slice(&buf[..size]);
+            while let Some((command, len)) = parse_command(&received) {
+                received.drain(..len);
+                let reply = if command.eq_ignore_ascii_case("INCRBY") {
+                    format!(":{}\r\n", total)
+                } else {
+                    ":1\r\n".into()
+                };
+                stream.write_all(reply.as_bytes()).await.unwrap();
+            }
+        }
+    }
+
+    /// Returns the name of the command that `received` starts with, along
+    /// with the command's length, once it has been received in full.
+    fn parse_command(received: &[u8]) -> Option<(String, usize)> {
+        let received = std::str::from_utf8(received).ok()?;
+        // The last line is incomplete until it ends with CRLF.
+        let lines = received.split("\r\n").collect::<Vec<_>>();
+        let lines = &lines[..lines.len() - 1];
+        // An array of bulk strings, each a length line then the string.
+        let args = lines.first()?.strip_prefix('*')?.parse::<usize>().ok()?;
+        let command = lines.get(..1 + 2 * args)?;
+        let len = command.iter().map(|line| line.len() + 2).sum();
+        Some((command.get(2)?.to_string(), len))
+    }
+
+    #[tokio::test]
+    async fn acquire_token_counts_pending_packets() {
+        let r = rate_limiter(10);
+        let address: IpAddr = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(r.acquire_token(address), Decision::Forward);
+        assert_eq!(r.acquire_token(address), Decision::Forward);
+        assert_eq!(2, *r.state.lock().pending.get(&address).unwrap());
+    }
+
+    #[tokio::test]
+    async fn acquire_token_too_many_clients() {
+        let r = rate_limiter(10);
+        let tracked: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        {
+            let mut state = r.state.lock();
+            state.pending.insert(tracked, 1);
+            state.pending.extend(
+                (0..MAX_PENDING_CLIENTS as u32 - 1).map(|i| (IpAddr::from(i.to_be_bytes()), 1)),
+            );
+        }
+
+        // Only the clients already being counted are.
+        assert_eq!(r.acquire_token(tracked), Decision::Forward);
+        assert_eq!(r.acquire_token(other), Decision::Untracked);
+        assert_eq!(MAX_PENDING_CLIENTS, r.state.lock().pending.len());
+    }
+
+    #[tokio::test]
+    async fn acquire_token_exhausted() {
+        let r = rate_limiter(10);
+        let exhausted: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+
+        r.state.lock().exhausted.insert(exhausted);
+
+        assert_eq!(r.acquire_token(exhausted), Decision::Drop);
+        assert_eq!(r.acquire_token(other), Decision::Forward);
+    }
+
+    #[tokio::test]
+    async fn acquire_token_no_packets_allowed() {
+        let r = rate_limiter(0);
+        assert_eq!(
+            r.acquire_token("127.0.0.1".parse().unwrap()),
+            Decision::Drop
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_exhausts_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("redis://{}", listener.local_addr().unwrap());
+        tokio::spawn(fake_redis(listener, 10));
+
+        let args = args(&address, 10);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let state = state(&args, &[(client, 3)]);
+        let mut connection = None;
+        SharedRateLimiter::sync(&args, &state, &mut connection)
+            .await
+            .unwrap();
+
+        // The connection is kept for the next synchronization.
+        assert!(connection.is_some());
+        let state = state.lock();
+        assert!(state.pending.is_empty());
+        assert!(state.exhausted.contains(&client));
+    }
+
+    #[tokio::test]
+    async fn sync_failure_keeps_pending_packets() {
+        let args = args(UNREACHABLE, 10);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let state = state(&args, &[(client, 3)]);
+        let mut connection = None;
+        assert!(SharedRateLimiter::sync(&args, &state, &mut connection)
+            .await
+            .is_err());
+        assert!(connection.is_none());
+        // The packets are counted again by the next synchronization.
+        assert_eq!(Some(&3), state.lock().pending.get(&client));
+
+        // Unless their window has passed by then.
+        state.lock().window -= 1;
+        assert!(SharedRateLimiter::sync(&args, &state, &mut connection)
+            .await
+            .is_err());
+        assert!(state.lock().pending.is_empty());
+    }
+}
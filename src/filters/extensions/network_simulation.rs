@@ -0,0 +1,504 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod metrics;
+
+crate::include_proto!("quilkin.extensions.filters.network_simulation.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::network_simulation::v1beta1 as proto;
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use base64_serde::base64_serde_type;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::filters::{extensions::CAPTURED_BYTES, prelude::*};
+use crate::proxy::Scheduler;
+
+use self::metrics::Metrics;
+use self::quilkin::extensions::filters::network_simulation::v1beta1::{
+    network_simulation::Profile as ProtoProfile, NetworkSimulation as ProtoConfig,
+};
+
+base64_serde_type!(Base64Standard, base64::STANDARD);
+
+/// The reason packets are dropped for to simulate packet loss.
+const LOSS_REASON: &str = "SimulatedLoss";
+/// How long a client can go without sending a packet before it is
+/// forgotten, after which the packets sent to it are no longer subject to
+/// its profile.
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// The maximum number of clients tracked. Once reached, idle clients are
+/// removed before a new one is added.
+const MAX_CLIENTS: usize = 100_000;
+
+/// Config represents a `NetworkSimulation` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The key to use when retrieving the token from the Filter's dynamic
+    /// metadata.
+    #[serde(rename = "metadataKey", default = "default_metadata_key")]
+    pub metadata_key: String,
+    /// The network conditions simulated for the clients of specific tokens.
+    pub profiles: Vec<Profile>,
+}
+
+/// The network conditions simulated for the clients of a token.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// The token the profile applies to.
+    #[serde(with = "Base64Standard")]
+    pub token: Vec<u8>,
+    /// How long each packet is delayed for.
+    #[serde(default, with = "humantime_serde")]
+    pub latency: Duration,
+    /// The most that a packet is delayed for on top of `latency`, picked at
+    /// random for each packet.
+    #[serde(default, with = "humantime_serde")]
+    pub jitter: Duration,
+    /// The probability, between 0 and 1, that a packet is dropped.
+    #[serde(default)]
+    pub loss: f64,
+}
+
+/// Default value for [`Config::metadata_key`]
+fn default_metadata_key() -> String {
+    CAPTURED_BYTES.into()
+}
+
+/// Converts a protobuf duration in `field` to a [`Duration`].
+fn convert_duration(
+    duration: Option<prost_types::Duration>,
+    field: &str,
+) -> Result<Duration, ConvertProtoConfigError> {
+    duration
+        .map(|duration| {
+            duration.try_into().map_err(|err| {
+                ConvertProtoConfigError::new(
+                    format!("invalid duration: {:?}", err),
+                    Some(field.into()),
+                )
+            })
+        })
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+impl TryFrom<ProtoProfile> for Profile {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoProfile) -> Result<Self, Self::Error> {
+        Ok(Self {
+            token: p.token,
+            latency: convert_duration(p.latency, "profiles.latency")?,
+            jitter: convert_duration(p.jitter, "profiles.jitter")?,
+            loss: p.loss.unwrap_or_default(),
+        })
+    }
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            metadata_key: p.metadata_key.unwrap_or_else(default_metadata_key),
+            profiles: p
+                .profiles
+                .into_iter()
+                .map(Profile::try_from)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl From<Profile> for ProtoProfile {
+    fn from(profile: Profile) -> Self {
+        Self {
+            token: profile.token,
+            latency: Some(profile.latency.into()),
+            jitter: Some(profile.jitter.into()),
+            loss: Some(profile.loss),
+        }
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            metadata_key: Some(config.metadata_key),
+            profiles: config
+                .profiles
+                .into_iter()
+                .map(ProtoProfile::from)
+                .collect(),
+        }
+    }
+}
+
+/// A client whose packets are subject to a profile.
+struct Client {
+    /// The index of the client's profile.
+    profile: usize,
+    /// When the client last sent a packet.
+    last_seen: Instant,
+}
+
+/// The `NetworkSimulation` filter delays and drops the packets of the
+/// clients of specific tokens, in both directions, so that testers can
+/// degrade their own connection on a production-like proxy without
+/// affecting other players.
+#[crate::filter("quilkin.extensions.filters.network_simulation.v1beta1.NetworkSimulation")]
+struct NetworkSimulation {
+    metadata_key: String,
+    profiles: Vec<Profile>,
+    /// The profile of each client, so that the packets sent to clients are
+    /// subject to the profile of their token.
+    clients: Mutex<HashMap<SocketAddr, Client>>,
+    metrics: Metrics,
+}
+
+/// Factory for the NetworkSimulation filter
+#[derive(Default)]
+pub struct NetworkSimulationFactory;
+
+impl FilterFactory for NetworkSimulationFactory {
+    fn name(&self) -> &'static str {
+        NetworkSimulation::FILTER_NAME
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("network_simulation/schema.yaml"))
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config: Config = self
+            .require_config(args.config)?
+            .deserialize::<Config, ProtoConfig>(self.name())?;
+
+        for profile in &config.profiles {
+            if !(0.0..=1.0).contains(&profile.loss) {
+                return Err(Error::FieldInvalid {
+                    field: "profiles.loss".into(),
+                    reason: "value must be between 0 and 1".into(),
+                });
+            }
+            if profile.latency + profile.jitter > Scheduler::MAX_DELAY {
+                return Err(Error::FieldInvalid {
+                    field: "profiles.latency".into(),
+                    reason: format!(
+                        "latency and jitter must not add up to more than {:?}, the longest \
+                         that packets can be delayed",
+                        Scheduler::MAX_DELAY
+                    ),
+                });
+            }
+        }
+
+        Ok(Box::new(NetworkSimulation::new(
+            config,
+            Metrics::new(&args.metrics_registry)?,
+        )))
+    }
+}
+
+impl NetworkSimulation {
+    fn new(config: Config, metrics: Metrics) -> Self {
+        Self {
+            metadata_key: config.metadata_key,
+            profiles: config.profiles,
+            clients: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// Records that the client at `address` sent a packet with `token`,
+    /// returning the index of the token's profile, if it has one.
+    fn track(&self, address: SocketAddr, token: &[u8], now: Instant) -> Option<usize> {
+        let profile = self
+            .profiles
+            .iter()
+            .position(|profile| profile.token == token);
+        let mut clients = self.clients.lock();
+        match profile {
+            Some(profile) => {
+                if clients.len() >= MAX_CLIENTS && !clients.contains_key(&address) {
+                    clients.retain(|_, client| {
+                        now.duration_since(client.last_seen) < CLIENT_IDLE_TIMEOUT
+                    });
+                }
+                if clients.len() < MAX_CLIENTS || clients.contains_key(&address) {
+                    clients.insert(
+                        address,
+                        Client {
+                            profile,
+                            last_seen: now,
+                        },
+                    );
+                }
+            }
+            // The client's token no longer has a profile.
+            None => {
+                clients.remove(&address);
+            }
+        }
+        self.metrics.clients.set(clients.len() as i64);
+        profile
+    }
+
+    /// Applies `profile` to a packet, returning how much longer to delay it
+    /// for, or `None` if it is dropped.
+    fn simulate(&self, profile: &Profile) -> Option<Duration> {
+        if profile.loss > 0.0 && rand::random::<f64>() < profile.loss {
+            self.metrics.packets_dropped.inc();
+            return None;
+        }
+        let delay = profile.latency + profile.jitter.mul_f64(rand::random::<f64>());
+        if delay > Duration::from_secs(0) {
+            self.metrics.packets_delayed.inc();
+        }
+        Some(delay)
+    }
+}
+
+impl Filter for NetworkSimulation {
+    fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        let token = match ctx
+            .metadata
+            .get(&self.metadata_key)
+            .and_then(|value| value.downcast_ref::<Vec<u8>>())
+        {
+            Some(token) => token,
+            None => return Some(ctx.into()),
+        };
+        let profile = match self.track(ctx.from, token, Instant::now()) {
+            Some(profile) => profile,
+            None => return Some(ctx.into()),
+        };
+
+        match self.simulate(&self.profiles[profile]) {
+            Some(delay) => {
+                ctx.delay += delay;
+                Some(ctx.into())
+            }
+            None => drop_packet(LOSS_REASON),
+        }
+    }
+
+    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+        // Clients are only known once they have sent a packet with a token.
+        let profile = match self.clients.lock().get(&ctx.to) {
+            Some(client) => client.profile,
+            None => return Some(ctx.into()),
+        };
+
+        match self.simulate(&self.profiles[profile]) {
+            Some(delay) => {
+                ctx.delay += delay;
+                Some(ctx.into())
+            }
+            None => drop_packet(LOSS_REASON),
+        }
+    }
+
+    /// Returns the memory held by the profiles of clients.
+    fn memory_usage(&self) -> Option<usize> {
+        let capacity = self.clients.lock().capacity();
+        Some(capacity * std::mem::size_of::<(SocketAddr, Client)>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+    use serde_yaml::Value;
+
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::{
+        drop_reason, extensions::CAPTURED_BYTES, CreateFilterArgs, Filter, FilterFactory,
+        ReadContext, WriteContext,
+    };
+
+    use super::{
+        Config, Metrics, NetworkSimulation, NetworkSimulationFactory, Profile, ProtoConfig,
+        ProtoProfile, LOSS_REASON,
+    };
+
+    const CLIENT: &str = "127.0.0.1:7000";
+
+    fn network_simulation(profiles: Vec<Profile>) -> NetworkSimulation {
+        NetworkSimulation::new(
+            Config {
+                metadata_key: CAPTURED_BYTES.into(),
+                profiles,
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    fn profile(token: &[u8], latency_ms: u64, jitter_ms: u64, loss: f64) -> Profile {
+        Profile {
+            token: token.to_vec(),
+            latency: Duration::from_millis(latency_ms),
+            jitter: Duration::from_millis(jitter_ms),
+            loss,
+        }
+    }
+
+    /// Returns the delay of a packet read from the client with `token`, or
+    /// `None` if it was dropped.
+    fn read(filter: &NetworkSimulation, token: Option<&[u8]>) -> Option<Duration> {
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:7001".parse().unwrap(),
+        )])
+        .unwrap();
+        let mut ctx =
+            ReadContext::new(endpoints.into(), CLIENT.parse().unwrap(), b"hello".to_vec());
+        if let Some(token) = token {
+            ctx.metadata
+                .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(token.to_vec()));
+        }
+        filter.read(ctx).map(|response| response.delay)
+    }
+
+    /// Returns the delay of a packet written to the client, or `None` if it
+    /// was dropped.
+    fn write(filter: &NetworkSimulation) -> Option<Duration> {
+        let endpoint = Endpoint::from_address("127.0.0.1:7001".parse().unwrap());
+        filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                CLIENT.parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .map(|response| response.delay)
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config::try_from(ProtoConfig {
+            metadata_key: None,
+            profiles: vec![ProtoProfile {
+                token: b"abc".to_vec(),
+                latency: Some(prost_types::Duration {
+                    seconds: 0,
+                    nanos: 100_000_000,
+                }),
+                jitter: None,
+                loss: Some(0.1),
+            }],
+        })
+        .unwrap();
+        assert_eq!(
+            Config {
+                metadata_key: CAPTURED_BYTES.into(),
+                profiles: vec![profile(b"abc", 100, 0, 0.1)],
+            },
+            config
+        );
+    }
+
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            metadata_key: "TOKEN".into(),
+            profiles: vec![profile(b"abc", 100, 20, 0.1)],
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
+    #[test]
+    fn factory_config() {
+        let create = |yaml: &str| {
+            let config = serde_yaml::from_str::<Value>(yaml).unwrap();
+            NetworkSimulationFactory::default()
+                .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+        };
+
+        assert!(create(
+            "
+profiles:
+  - token: YWJj
+    latency: 100ms
+    jitter: 20ms
+    loss: 0.05
+"
+        )
+        .is_ok());
+        assert!(create("profiles: [{token: YWJj, loss: 1.5}]").is_err());
+        // Packets can't be delayed for longer than the scheduler allows.
+        assert!(create("profiles: [{token: YWJj, latency: 9s, jitter: 2s}]").is_err());
+    }
+
+    #[test]
+    fn other_clients_unchanged() {
+        let filter = network_simulation(vec![profile(b"abc", 100, 0, 1.0)]);
+
+        assert_eq!(Some(Duration::from_secs(0)), read(&filter, None));
+        assert_eq!(Some(Duration::from_secs(0)), read(&filter, Some(b"xyz")));
+        assert_eq!(Some(Duration::from_secs(0)), write(&filter));
+        assert_eq!(0, filter.metrics.packets_dropped.get());
+        assert_eq!(0, filter.metrics.clients.get());
+    }
+
+    #[test]
+    fn latency() {
+        let filter = network_simulation(vec![profile(b"abc", 100, 20, 0.0)]);
+
+        for delay in &[read(&filter, Some(b"abc")), write(&filter)] {
+            let delay = delay.unwrap();
+            assert!(
+                delay >= Duration::from_millis(100) && delay <= Duration::from_millis(120),
+                "delay: {:?}",
+                delay
+            );
+        }
+        assert_eq!(2, filter.metrics.packets_delayed.get());
+        assert_eq!(1, filter.metrics.clients.get());
+    }
+
+    #[test]
+    fn loss() {
+        let filter = network_simulation(vec![profile(b"abc", 0, 0, 1.0)]);
+
+        assert_eq!(None, read(&filter, Some(b"abc")));
+        assert_eq!(LOSS_REASON, drop_reason::take());
+        assert_eq!(None, write(&filter));
+        assert_eq!(2, filter.metrics.packets_dropped.get());
+
+        // The packets sent to the client are no longer dropped once its
+        // token has no profile.
+        assert_eq!(Some(Duration::from_secs(0)), read(&filter, Some(b"xyz")));
+        assert_eq!(Some(Duration::from_secs(0)), write(&filter));
+        assert_eq!(0, filter.metrics.clients.get());
+    }
+}
@@ -0,0 +1,48 @@
+/*
+ * Copyright 2020 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *       http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ */
+
+use prometheus::{IntCounter, IntGauge, Registry, Result as MetricsResult};
+
+use crate::filters::FilterMetrics;
+
+use super::NetworkSimulation;
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_delayed: IntCounter,
+    pub(super) packets_dropped: IntCounter,
+    pub(super) clients: IntGauge,
+}
+
+impl Metrics {
+    pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let metrics = FilterMetrics::new(registry, NetworkSimulation::FILTER_NAME);
+        Ok(Metrics {
+            packets_delayed: metrics.counter(
+                "packets_delayed",
+                "Total number of packets delayed to simulate latency.",
+            )?,
+            packets_dropped: metrics.counter(
+                "packets_dropped",
+                "Total number of packets dropped to simulate packet loss.",
+            )?,
+            clients: metrics.gauge(
+                "clients",
+                "Number of clients whose packets are subject to a profile.",
+            )?,
+        })
+    }
+}
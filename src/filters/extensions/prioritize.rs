@@ -0,0 +1,306 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+crate::include_proto!("quilkin.extensions.filters.prioritize.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::prioritize::v1beta1 as proto;
+
+use std::convert::TryFrom;
+
+use base64_serde::base64_serde_type;
+use serde::{Deserialize, Serialize};
+
+use crate::filters::{prelude::*, Priority};
+use crate::map_proto_enum;
+
+use self::quilkin::extensions::filters::prioritize::v1beta1::{
+    prioritize::{Priority as ProtoPriority, PriorityValue, Rule as ProtoRule},
+    Prioritize as ProtoConfig,
+};
+
+base64_serde_type!(Base64Standard, base64::STANDARD);
+
+/// Config represents a `Prioritize` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The rules that packets are matched against, in order.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// The priority of packets that don't match any rule.
+    #[serde(default)]
+    pub default_priority: Priority,
+}
+
+/// Gives packets that match all of its conditions a priority.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The bytes that matching packets start with.
+    #[serde(with = "Base64Standard", default)]
+    pub prefix: Vec<u8>,
+    /// The largest size in bytes of matching packets.
+    #[serde(default)]
+    pub max_size: Option<usize>,
+    /// The priority of matching packets.
+    pub priority: Priority,
+}
+
+impl Rule {
+    fn matches(&self, contents: &[u8]) -> bool {
+        contents.starts_with(&self.prefix)
+            && self.max_size.map_or(true, |max_size| contents.len() <= max_size)
+    }
+}
+
+/// Converts a protobuf priority to a [`Priority`].
+fn convert_priority(value: i32) -> Result<Priority, ConvertProtoConfigError> {
+    map_proto_enum!(
+        value = value,
+        field = "priority",
+        proto_enum_type = ProtoPriority,
+        target_enum_type = Priority,
+        variants = [High, Normal, Low]
+    )
+}
+
+/// Converts a [`Priority`] to a protobuf priority.
+fn priority_to_proto(priority: Priority) -> ProtoPriority {
+    match priority {
+        Priority::High => ProtoPriority::High,
+        Priority::Normal => ProtoPriority::Normal,
+        Priority::Low => ProtoPriority::Low,
+    }
+}
+
+impl TryFrom<ProtoRule> for Rule {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoRule) -> Result<Self, Self::Error> {
+        Ok(Self {
+            prefix: p.prefix,
+            max_size: p.max_size.map(|max_size| max_size as usize),
+            priority: convert_priority(p.priority)?,
+        })
+    }
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            rules: p
+                .rules
+                .into_iter()
+                .map(Rule::try_from)
+                .collect::<Result<_, _>>()?,
+            default_priority: p
+                .default_priority
+                .map(|priority| convert_priority(priority.value))
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+impl From<Rule> for ProtoRule {
+    fn from(rule: Rule) -> Self {
+        Self {
+            prefix: rule.prefix,
+            max_size: rule.max_size.map(|max_size| max_size as u64),
+            priority: priority_to_proto(rule.priority) as i32,
+        }
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            rules: config.rules.into_iter().map(ProtoRule::from).collect(),
+            default_priority: Some(PriorityValue {
+                value: priority_to_proto(config.default_priority) as i32,
+            }),
+        }
+    }
+}
+
+/// The `Prioritize` filter sets the [`Priority`] of the packets sent to
+/// clients from the first rule they match, so that packets such as
+/// handshakes and acknowledgements are sent ahead of bulk data when the
+/// proxy is congested.
+#[crate::filter("quilkin.extensions.filters.prioritize.v1beta1.Prioritize")]
+struct Prioritize {
+    config: Config,
+}
+
+/// Factory for the Prioritize filter
+#[derive(Default)]
+pub struct PrioritizeFactory;
+
+impl FilterFactory for PrioritizeFactory {
+    fn name(&self) -> &'static str {
+        Prioritize::FILTER_NAME
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("prioritize/schema.yaml"))
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config = args
+            .config
+            .map(|config| config.deserialize::<Config, ProtoConfig>(self.name()))
+            .transpose()?
+            .unwrap_or_default();
+        Ok(Box::new(Prioritize { config }))
+    }
+}
+
+impl Filter for Prioritize {
+    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+        ctx.priority = self
+            .config
+            .rules
+            .iter()
+            .find(|rule| rule.matches(&ctx.contents))
+            .map_or(self.config.default_priority, |rule| rule.priority);
+        Some(ctx.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use prometheus::Registry;
+    use serde_yaml::Value;
+
+    use crate::cluster::Endpoint;
+    use crate::filters::{CreateFilterArgs, Filter, FilterFactory, Priority, WriteContext};
+    use crate::test_utils::assert_filter_read_no_change;
+
+    use super::quilkin::extensions::filters::prioritize::v1beta1::prioritize::{
+        Priority as ProtoPriority, PriorityValue, Rule as ProtoRule,
+    };
+    use super::{Config, Prioritize, PrioritizeFactory, ProtoConfig, Rule};
+
+    fn prioritize() -> Prioritize {
+        Prioritize {
+            config: Config {
+                rules: vec![
+                    Rule {
+                        prefix: b"ACK".to_vec(),
+                        max_size: None,
+                        priority: Priority::High,
+                    },
+                    Rule {
+                        prefix: vec![],
+                        max_size: Some(8),
+                        priority: Priority::Normal,
+                    },
+                ],
+                default_priority: Priority::Low,
+            },
+        }
+    }
+
+    fn write_priority(filter: &Prioritize, contents: &[u8]) -> Priority {
+        let endpoint = Endpoint::from_address("127.0.0.1:7001".parse().unwrap());
+        filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                "127.0.0.1:7000".parse().unwrap(),
+                contents.to_vec(),
+            ))
+            .unwrap()
+            .priority
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config::try_from(ProtoConfig {
+            rules: vec![ProtoRule {
+                prefix: b"ACK".to_vec(),
+                max_size: Some(64),
+                priority: ProtoPriority::High as i32,
+            }],
+            default_priority: Some(PriorityValue {
+                value: ProtoPriority::Low as i32,
+            }),
+        })
+        .unwrap();
+        assert_eq!(
+            Config {
+                rules: vec![Rule {
+                    prefix: b"ACK".to_vec(),
+                    max_size: Some(64),
+                    priority: Priority::High,
+                }],
+                default_priority: Priority::Low,
+            },
+            config
+        );
+
+        assert!(Config::try_from(ProtoConfig {
+            rules: vec![],
+            default_priority: Some(PriorityValue { value: 42 }),
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn convert_config_to_proto() {
+        let config = prioritize().config;
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
+    #[test]
+    fn factory_config() {
+        let config = serde_yaml::from_str::<Value>(
+            "
+rules:
+  - prefix: QUNL
+    priority: HIGH
+  - max_size: 8
+    priority: NORMAL
+default_priority: LOW
+",
+        )
+        .unwrap();
+        assert!(PrioritizeFactory::default()
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+            .is_ok());
+    }
+
+    #[test]
+    fn read_no_change() {
+        assert_filter_read_no_change(&prioritize());
+    }
+
+    #[test]
+    fn write_first_matching_rule() {
+        let filter = prioritize();
+        assert_eq!(Priority::High, write_priority(&filter, b"ACK and a long payload"));
+        assert_eq!(Priority::Normal, write_priority(&filter, b"short"));
+        assert_eq!(Priority::Low, write_priority(&filter, b"a long snapshot payload"));
+    }
+}
@@ -0,0 +1,364 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+crate::include_proto!("quilkin.extensions.filters.shadow.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::shadow::v1beta1 as proto;
+
+mod metrics;
+
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Endpoint;
+use crate::config::Endpoints;
+use crate::filters::{prelude::*, Sample};
+
+use self::metrics::Metrics;
+use self::quilkin::extensions::filters::shadow::v1beta1::Shadow as ProtoConfig;
+
+/// The reason the shadow endpoint's responses are dropped for.
+const SHADOW_RESPONSE_REASON: &str = "ShadowResponse";
+
+/// The `endpoint` label of the metrics of the responses from the endpoints
+/// that packets are sent to as normal.
+const PRIMARY_LABEL: &str = "primary";
+
+/// The `endpoint` label of the metrics of the responses from the shadow
+/// endpoint.
+const SHADOW_LABEL: &str = "shadow";
+
+/// Config represents a `Shadow` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The address of the shadow endpoint that packets are copied to.
+    pub endpoint: SocketAddr,
+    /// The fraction of clients whose packets are copied to the shadow
+    /// endpoint.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+/// Default value for [`Config::sample_rate`]
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            endpoint: p.endpoint.parse().map_err(|err| {
+                ConvertProtoConfigError::new(
+                    format!("invalid address: {}", err),
+                    Some("endpoint".into()),
+                )
+            })?,
+            sample_rate: p.sample_rate.unwrap_or_else(default_sample_rate),
+        })
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            endpoint: config.endpoint.to_string(),
+            sample_rate: Some(config.sample_rate),
+        }
+    }
+}
+
+/// The `Shadow` filter copies the packets of clients to a shadow endpoint,
+/// e.g. a new build of a game server, as well as sending them on as normal,
+/// and drops the shadow endpoint's responses, so that the new build can be
+/// validated against live traffic without clients noticing.
+#[crate::filter("quilkin.extensions.filters.shadow.v1beta1.Shadow")]
+struct Shadow {
+    endpoint: Endpoints,
+    sample_rate: f64,
+    metrics: Metrics,
+}
+
+/// Factory for the Shadow filter
+#[derive(Default)]
+pub struct ShadowFactory;
+
+impl FilterFactory for ShadowFactory {
+    fn name(&self) -> &'static str {
+        Shadow::FILTER_NAME
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("shadow/schema.yaml"))
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config = self
+            .require_config(args.config)?
+            .deserialize::<Config, ProtoConfig>(self.name())?;
+        if !(0.0..=1.0).contains(&config.sample_rate) {
+            return Err(Error::FieldInvalid {
+                field: "sample_rate".into(),
+                reason: "value must be between 0 and 1".into(),
+            });
+        }
+
+        Ok(Box::new(Shadow::new(
+            config,
+            Metrics::new(&args.metrics_registry)?,
+        )))
+    }
+}
+
+impl Shadow {
+    fn new(config: Config, metrics: Metrics) -> Self {
+        Self {
+            // Never empty, as it holds the shadow endpoint.
+            endpoint: Endpoints::new(vec![Endpoint::from_address(config.endpoint)]).unwrap(),
+            sample_rate: config.sample_rate,
+            metrics,
+        }
+    }
+
+    /// Returns the address of the shadow endpoint.
+    fn address(&self) -> SocketAddr {
+        self.endpoint.as_ref()[0].address
+    }
+
+    /// Returns whether the packets of `client` are mirrored. Clients are
+    /// sampled by their address alone, so that all of the packets of a
+    /// mirrored client are.
+    fn is_mirrored(&self, client: SocketAddr) -> bool {
+        Sample::new(client, &[]).is_sampled(self.sample_rate)
+    }
+
+    fn record_response(&self, label: &str, size: usize) {
+        self.metrics.responses.with_label_values(&[label]).inc();
+        self.metrics
+            .response_bytes
+            .with_label_values(&[label])
+            .inc_by(size as u64);
+    }
+}
+
+impl Filter for Shadow {
+    fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+        // Packets that are already being sent to the shadow endpoint aren't
+        // copied to it again.
+        let address = self.address();
+        if !self.is_mirrored(ctx.from) || ctx.endpoints.iter().any(|e| e.address == address) {
+            return Some(ctx.into());
+        }
+
+        self.metrics.packets_mirrored.inc();
+        let mut shadow = ReadResponse::from(ReadContext::new(
+            self.endpoint.clone().into(),
+            ctx.from,
+            ctx.contents.clone(),
+        ));
+        shadow.delay = ctx.delay;
+        let mut response = ReadResponse::from(ctx);
+        response.additional.push(shadow);
+        Some(response)
+    }
+
+    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        if ctx.endpoint.address == self.address() {
+            self.record_response(SHADOW_LABEL, ctx.contents.len());
+            return drop_packet(SHADOW_RESPONSE_REASON);
+        }
+        if self.is_mirrored(ctx.to) {
+            self.record_response(PRIMARY_LABEL, ctx.contents.len());
+        }
+        Some(ctx.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use prometheus::Registry;
+    use serde_yaml::Value;
+
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::{
+        drop_reason, CreateFilterArgs, Filter, FilterFactory, ReadContext, WriteContext,
+    };
+
+    use super::{
+        Config, Metrics, ProtoConfig, Shadow, ShadowFactory, PRIMARY_LABEL, SHADOW_LABEL,
+        SHADOW_RESPONSE_REASON,
+    };
+
+    const CLIENT: &str = "127.0.0.1:7000";
+    const PRIMARY: &str = "127.0.0.1:7001";
+    const SHADOW: &str = "127.0.0.1:7002";
+
+    fn shadow(sample_rate: f64) -> Shadow {
+        Shadow::new(
+            Config {
+                endpoint: SHADOW.parse().unwrap(),
+                sample_rate,
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    /// Returns the contents and endpoints of the packets that `filter`
+    /// sends on for a packet read from the client.
+    fn read(filter: &Shadow, contents: &[u8]) -> Vec<(Vec<u8>, Vec<String>)> {
+        let endpoints =
+            Endpoints::new(vec![Endpoint::from_address(PRIMARY.parse().unwrap())]).unwrap();
+        filter
+            .read(ReadContext::new(
+                endpoints.into(),
+                CLIENT.parse().unwrap(),
+                contents.to_vec(),
+            ))
+            .unwrap()
+            .into_packets()
+            .into_iter()
+            .map(|packet| {
+                let endpoints = packet
+                    .endpoints
+                    .iter()
+                    .map(|endpoint| endpoint.address.to_string())
+                    .collect();
+                (packet.contents, endpoints)
+            })
+            .collect()
+    }
+
+    fn write(filter: &Shadow, from: &str, contents: &[u8]) -> bool {
+        let endpoint = Endpoint::from_address(from.parse().unwrap());
+        filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                CLIENT.parse().unwrap(),
+                contents.to_vec(),
+            ))
+            .is_some()
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config::try_from(ProtoConfig {
+            endpoint: SHADOW.into(),
+            sample_rate: None,
+        })
+        .unwrap();
+        assert_eq!(
+            Config {
+                endpoint: SHADOW.parse().unwrap(),
+                sample_rate: 1.0,
+            },
+            config
+        );
+
+        assert!(Config::try_from(ProtoConfig {
+            endpoint: "nope".into(),
+            sample_rate: None,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            endpoint: SHADOW.parse().unwrap(),
+            sample_rate: 0.25,
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
+    #[test]
+    fn factory_config() {
+        let create = |yaml: Option<&str>| {
+            let config = yaml.map(|yaml| serde_yaml::from_str::<Value>(yaml).unwrap());
+            ShadowFactory::default().create_filter(CreateFilterArgs::fixed(
+                Registry::default(),
+                config.as_ref(),
+            ))
+        };
+
+        assert!(create(Some("endpoint: 127.0.0.1:26001\nsample_rate: 0.1")).is_ok());
+        assert!(create(None).is_err());
+        assert!(create(Some("sample_rate: 0.1")).is_err());
+        assert!(create(Some("endpoint: 127.0.0.1:26001\nsample_rate: 2")).is_err());
+    }
+
+    #[test]
+    fn mirror() {
+        let filter = shadow(1.0);
+        assert_eq!(
+            vec![
+                (b"hello".to_vec(), vec![PRIMARY.to_string()]),
+                (b"hello".to_vec(), vec![SHADOW.to_string()]),
+            ],
+            read(&filter, b"hello")
+        );
+        assert_eq!(1, filter.metrics.packets_mirrored.get());
+
+        assert!(write(&filter, PRIMARY, b"hello"));
+        assert!(!write(&filter, SHADOW, b"hi"));
+        assert_eq!(SHADOW_RESPONSE_REASON, drop_reason::take());
+        let responses = |label| filter.metrics.responses.with_label_values(&[label]).get();
+        let bytes = |label| {
+            filter
+                .metrics
+                .response_bytes
+                .with_label_values(&[label])
+                .get()
+        };
+        assert_eq!(1, responses(PRIMARY_LABEL));
+        assert_eq!(5, bytes(PRIMARY_LABEL));
+        assert_eq!(1, responses(SHADOW_LABEL));
+        assert_eq!(2, bytes(SHADOW_LABEL));
+    }
+
+    #[test]
+    fn unsampled_clients_are_not_mirrored() {
+        let filter = shadow(0.0);
+        assert_eq!(
+            vec![(b"hello".to_vec(), vec![PRIMARY.to_string()])],
+            read(&filter, b"hello")
+        );
+        assert!(write(&filter, PRIMARY, b"hello"));
+        assert_eq!(0, filter.metrics.packets_mirrored.get());
+        assert_eq!(
+            0,
+            filter
+                .metrics
+                .responses
+                .with_label_values(&[PRIMARY_LABEL])
+                .get()
+        );
+
+        // The shadow endpoint's responses are dropped whatever the client.
+        assert!(!write(&filter, SHADOW, b"hi"));
+    }
+}
@@ -0,0 +1,52 @@
+/*
+ * Copyright 2020 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *       http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ */
+
+use prometheus::{IntCounter, IntCounterVec, Registry, Result as MetricsResult};
+
+use crate::filters::FilterMetrics;
+
+use super::Shadow;
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_mirrored: IntCounter,
+    pub(super) responses: IntCounterVec,
+    pub(super) response_bytes: IntCounterVec,
+}
+
+impl Metrics {
+    pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let metrics = FilterMetrics::new(registry, Shadow::FILTER_NAME);
+        Ok(Metrics {
+            packets_mirrored: metrics.counter(
+                "packets_mirrored",
+                "Total number of packets copied to the shadow endpoint.",
+            )?,
+            responses: metrics.counter_vec(
+                "responses",
+                "Total number of packets received from the endpoints of mirrored clients. \
+                 labels: endpoint.",
+                &["endpoint"],
+            )?,
+            response_bytes: metrics.counter_vec(
+                "response_bytes",
+                "Total number of bytes received from the endpoints of mirrored clients. \
+                 labels: endpoint.",
+                &["endpoint"],
+            )?,
+        })
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+crate::include_proto!("quilkin.extensions.filters.static_metadata.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::static_metadata::v1beta1 as proto;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::filters::prelude::*;
+
+use self::quilkin::extensions::filters::static_metadata::v1beta1::StaticMetadata as ProtoConfig;
+
+/// Config represents a `StaticMetadata` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The values set in the dynamic metadata of every packet, by key.
+    #[serde(default)]
+    pub values: BTreeMap<String, String>,
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            values: p.values.into_iter().collect(),
+        })
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            values: config.values.into_iter().collect(),
+        }
+    }
+}
+
+/// The `StaticMetadata` filter sets configured values in the dynamic
+/// metadata of every packet, so that later filters can act on attributes of
+/// the deployment, such as its environment, without them being encoded in
+/// packets.
+#[crate::filter("quilkin.extensions.filters.static_metadata.v1beta1.StaticMetadata")]
+struct StaticMetadata {
+    /// The values as bytes, as other filters store them, with their keys
+    /// allocated once rather than for every packet.
+    values: Vec<(Arc<String>, Vec<u8>)>,
+}
+
+/// Factory for the StaticMetadata filter
+#[derive(Default)]
+pub struct StaticMetadataFactory;
+
+impl FilterFactory for StaticMetadataFactory {
+    fn name(&self) -> &'static str {
+        StaticMetadata::FILTER_NAME
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("static_metadata/schema.yaml"))
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config = self
+            .require_config(args.config)?
+            .deserialize::<Config, ProtoConfig>(self.name())?;
+        if config.values.keys().any(String::is_empty) {
+            return Err(Error::FieldInvalid {
+                field: "values".into(),
+                reason: "keys must not be empty".into(),
+            });
+        }
+        Ok(Box::new(StaticMetadata::new(config)))
+    }
+}
+
+impl StaticMetadata {
+    fn new(config: Config) -> Self {
+        Self {
+            values: config
+                .values
+                .into_iter()
+                .map(|(key, value)| (Arc::new(key), value.into_bytes()))
+                .collect(),
+        }
+    }
+}
+
+impl Filter for StaticMetadata {
+    fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        for (key, value) in &self.values {
+            ctx.metadata.insert(key.clone(), Box::new(value.clone()));
+        }
+        Some(ctx.into())
+    }
+
+    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+        for (key, value) in &self.values {
+            ctx.metadata
+                .insert(key.as_ref().clone(), Box::new(value.clone()));
+        }
+        Some(ctx.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    use prometheus::Registry;
+    use serde_yaml::Value;
+
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::{CreateFilterArgs, Filter, FilterFactory, ReadContext, WriteContext};
+
+    use super::{Config, ProtoConfig, StaticMetadata, StaticMetadataFactory};
+
+    fn config() -> Config {
+        let mut values = BTreeMap::new();
+        values.insert(
+            "myapp.com/environment".to_string(),
+            "production".to_string(),
+        );
+        values.insert("myapp.com/tier".to_string(), "gold".to_string());
+        Config { values }
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = config();
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
+    #[test]
+    fn factory_config() {
+        let create = |yaml: &str| {
+            let config = serde_yaml::from_str::<Value>(yaml).unwrap();
+            StaticMetadataFactory::default()
+                .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+        };
+        assert!(create("values: { myapp.com/environment: production }").is_ok());
+        assert!(create("values: { '': production }").is_err());
+        assert!(create("value: { myapp.com/environment: production }").is_err());
+    }
+
+    #[test]
+    fn read() {
+        let filter = StaticMetadata::new(config());
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:7001".parse().unwrap(),
+        )])
+        .unwrap();
+        let mut ctx = ReadContext::new(
+            endpoints.into(),
+            "127.0.0.1:7000".parse().unwrap(),
+            b"hello".to_vec(),
+        );
+        // Values already set by earlier filters are replaced.
+        ctx.metadata.insert(
+            Arc::new("myapp.com/tier".into()),
+            Box::new(b"silver".to_vec()),
+        );
+
+        let response = filter.read(ctx).unwrap();
+        assert_eq!(b"hello".to_vec(), response.contents);
+        let value = |key: &str| {
+            response.metadata[&Arc::new(key.to_string())]
+                .downcast_ref::<Vec<u8>>()
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(b"production".to_vec(), value("myapp.com/environment"));
+        assert_eq!(b"gold".to_vec(), value("myapp.com/tier"));
+    }
+
+    #[test]
+    fn write() {
+        let filter = StaticMetadata::new(config());
+        let endpoint = Endpoint::from_address("127.0.0.1:7001".parse().unwrap());
+        let response = filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                "127.0.0.1:7000".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(
+            Some(&b"gold".to_vec()),
+            response.metadata["myapp.com/tier"].downcast_ref::<Vec<u8>>()
+        );
+    }
+}
@@ -0,0 +1,801 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod metrics;
+
+crate::include_proto!("quilkin.extensions.filters.token_quota.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::token_quota::v1beta1 as proto;
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use base64_serde::base64_serde_type;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::filters::{extensions::CAPTURED_BYTES, prelude::*};
+use crate::map_proto_enum;
+
+use self::metrics::Metrics;
+use self::quilkin::extensions::filters::token_quota::v1beta1::{
+    token_quota::{Action as ProtoAction, ActionValue, Quota as ProtoQuota},
+    TokenQuota as ProtoConfig,
+};
+
+base64_serde_type!(Base64Standard, base64::STANDARD);
+
+/// The reason packets are dropped for while their token is throttled.
+const THROTTLED_REASON: &str = "QuotaThrottled";
+/// The reason packets are dropped for once their token is over its quota.
+const EXCEEDED_REASON: &str = "QuotaExceeded";
+
+/// What the filter does with the packets of a token that is over its quota.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub enum Action {
+    /// Drop the packets in both directions, so that the session expires.
+    #[serde(rename = "TERMINATE")]
+    Terminate,
+    /// Replace the next packet sent to the client with the rejection
+    /// payload, then drop the packets in both directions.
+    #[serde(rename = "REJECT")]
+    Reject,
+    /// Limit the packets in both directions to the throttle rate, dropping
+    /// those over it.
+    #[serde(rename = "THROTTLE")]
+    Throttle,
+}
+
+impl Default for Action {
+    fn default() -> Self {
+        Action::Terminate
+    }
+}
+
+/// Config represents a `TokenQuota` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The key to use when retrieving the token from the Filter's dynamic
+    /// metadata.
+    #[serde(rename = "metadataKey", default = "default_metadata_key")]
+    pub metadata_key: String,
+    /// The number of bytes that each token can send and receive, if
+    /// limited.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// How long after a token is first seen that it can be used for, if
+    /// limited.
+    #[serde(default, with = "humantime_serde")]
+    pub max_duration: Option<Duration>,
+    /// Quotas for specific tokens, in place of `max_bytes` and
+    /// `max_duration`.
+    #[serde(default)]
+    pub tokens: Vec<Quota>,
+    /// What to do with the packets of a token that is over its quota.
+    #[serde(default)]
+    pub action: Action,
+    /// The rate in bytes per second that packets are limited to by
+    /// [`Action::Throttle`].
+    #[serde(default = "default_throttle_bytes_per_second")]
+    pub throttle_bytes_per_second: u64,
+    /// The payload sent to clients by [`Action::Reject`].
+    #[serde(with = "Base64Standard", default)]
+    pub rejection_payload: Vec<u8>,
+}
+
+/// The quota of a specific token.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// The token the quota applies to.
+    #[serde(with = "Base64Standard")]
+    pub token: Vec<u8>,
+    /// The number of bytes that the token can send and receive, if limited.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// How long after the token is first seen that it can be used for, if
+    /// limited.
+    #[serde(default, with = "humantime_serde")]
+    pub max_duration: Option<Duration>,
+}
+
+/// Default value for [`Config::metadata_key`]
+fn default_metadata_key() -> String {
+    CAPTURED_BYTES.into()
+}
+
+/// Default value for [`Config::throttle_bytes_per_second`]
+fn default_throttle_bytes_per_second() -> u64 {
+    1024
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            metadata_key: default_metadata_key(),
+            max_bytes: None,
+            max_duration: None,
+            tokens: vec![],
+            action: Action::default(),
+            throttle_bytes_per_second: default_throttle_bytes_per_second(),
+            rejection_payload: vec![],
+        }
+    }
+}
+
+/// Converts a protobuf duration in `field` to a [`Duration`].
+fn convert_duration(
+    duration: Option<prost_types::Duration>,
+    field: &str,
+) -> Result<Option<Duration>, ConvertProtoConfigError> {
+    duration
+        .map(|duration| {
+            duration.try_into().map_err(|err| {
+                ConvertProtoConfigError::new(
+                    format!("invalid duration: {:?}", err),
+                    Some(field.into()),
+                )
+            })
+        })
+        .transpose()
+}
+
+impl TryFrom<ProtoQuota> for Quota {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoQuota) -> Result<Self, Self::Error> {
+        Ok(Self {
+            token: p.token,
+            max_bytes: p.max_bytes,
+            max_duration: convert_duration(p.max_duration, "tokens.max_duration")?,
+        })
+    }
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        let action = p
+            .action
+            .map(|action| {
+                map_proto_enum!(
+                    value = action.value,
+                    field = "action",
+                    proto_enum_type = ProtoAction,
+                    target_enum_type = Action,
+                    variants = [Terminate, Reject, Throttle]
+                )
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            metadata_key: p.metadata_key.unwrap_or_else(default_metadata_key),
+            max_bytes: p.max_bytes,
+            max_duration: convert_duration(p.max_duration, "max_duration")?,
+            tokens: p
+                .tokens
+                .into_iter()
+                .map(Quota::try_from)
+                .collect::<Result<_, _>>()?,
+            action,
+            throttle_bytes_per_second: p
+                .throttle_bytes_per_second
+                .unwrap_or_else(default_throttle_bytes_per_second),
+            rejection_payload: p.rejection_payload,
+        })
+    }
+}
+
+impl From<Quota> for ProtoQuota {
+    fn from(quota: Quota) -> Self {
+        Self {
+            token: quota.token,
+            max_bytes: quota.max_bytes,
+            max_duration: quota.max_duration.map(Into::into),
+        }
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        let action = match config.action {
+            Action::Terminate => ProtoAction::Terminate,
+            Action::Reject => ProtoAction::Reject,
+            Action::Throttle => ProtoAction::Throttle,
+        };
+        Self {
+            metadata_key: Some(config.metadata_key),
+            max_bytes: config.max_bytes,
+            max_duration: config.max_duration.map(Into::into),
+            tokens: config.tokens.into_iter().map(ProtoQuota::from).collect(),
+            action: Some(ActionValue {
+                value: action as i32,
+            }),
+            throttle_bytes_per_second: Some(config.throttle_bytes_per_second),
+            rejection_payload: config.rejection_payload,
+        }
+    }
+}
+
+/// The limits of a token's quota.
+#[derive(Clone, Copy)]
+struct Limits {
+    max_bytes: Option<u64>,
+    max_duration: Option<Duration>,
+}
+
+/// A token's use of its quota.
+struct Usage {
+    /// When the token was first seen.
+    first_seen: Instant,
+    /// The bytes the token has sent and received.
+    bytes: u64,
+    /// Whether the token has exceeded its quota.
+    exceeded: bool,
+    /// Whether the token's client has been sent the rejection payload.
+    rejected: bool,
+    /// The bytes that can be sent while throttled, which is refilled at the
+    /// throttle rate.
+    allowance: f64,
+    /// When the allowance was last refilled.
+    refilled: Instant,
+}
+
+impl Usage {
+    fn new(first_seen: Instant) -> Self {
+        Self {
+            first_seen,
+            bytes: 0,
+            exceeded: false,
+            rejected: false,
+            allowance: 0.0,
+            refilled: first_seen,
+        }
+    }
+}
+
+/// The usage of each token, and the token of each client so that the
+/// packets sent to clients can be counted towards their token's quota.
+#[derive(Default)]
+struct State {
+    usage: HashMap<Vec<u8>, Usage>,
+    clients: HashMap<SocketAddr, Vec<u8>>,
+}
+
+/// A token's use of its quota, as exported by [`TokenQuota::export_state`].
+#[derive(Deserialize, Serialize)]
+struct ExportedUsage {
+    #[serde(with = "Base64Standard")]
+    token: Vec<u8>,
+    bytes: u64,
+    /// How long ago the token was first seen.
+    #[serde(with = "humantime_serde")]
+    age: Duration,
+    exceeded: bool,
+}
+
+/// What to do with a packet, given its token's usage.
+enum Verdict {
+    Pass,
+    Drop(&'static str),
+    /// Send the rejection payload in place of the packet.
+    Reject,
+}
+
+/// The `TokenQuota` filter limits the bytes that each token can send and
+/// receive, and how long it can be used for, applying the configured
+/// [`Action`] to the packets of tokens that are over their quota, e.g. to
+/// enforce trial access at the network layer.
+#[crate::filter("quilkin.extensions.filters.token_quota.v1beta1.TokenQuota")]
+struct TokenQuota {
+    metadata_key: String,
+    default_limits: Limits,
+    limits: HashMap<Vec<u8>, Limits>,
+    action: Action,
+    throttle_bytes_per_second: f64,
+    rejection_payload: Vec<u8>,
+    state: Mutex<State>,
+    metrics: Metrics,
+}
+
+/// Factory for the TokenQuota filter
+#[derive(Default)]
+pub struct TokenQuotaFactory;
+
+impl FilterFactory for TokenQuotaFactory {
+    fn name(&self) -> &'static str {
+        TokenQuota::FILTER_NAME
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("token_quota/schema.yaml"))
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config: Config = self
+            .require_config(args.config)?
+            .deserialize::<Config, ProtoConfig>(self.name())?;
+
+        match config.action {
+            Action::Throttle if config.throttle_bytes_per_second == 0 => {
+                return Err(Error::FieldInvalid {
+                    field: "throttle_bytes_per_second".into(),
+                    reason: "value must be greater than 0 for the THROTTLE action".into(),
+                })
+            }
+            Action::Reject if config.rejection_payload.is_empty() => {
+                return Err(Error::FieldInvalid {
+                    field: "rejection_payload".into(),
+                    reason: "a rejection payload is required by the REJECT action".into(),
+                })
+            }
+            _ => {}
+        }
+
+        Ok(Box::new(TokenQuota::new(
+            config,
+            Metrics::new(&args.metrics_registry)?,
+        )))
+    }
+}
+
+impl TokenQuota {
+    fn new(config: Config, metrics: Metrics) -> Self {
+        Self {
+            metadata_key: config.metadata_key,
+            default_limits: Limits {
+                max_bytes: config.max_bytes,
+                max_duration: config.max_duration,
+            },
+            limits: config
+                .tokens
+                .into_iter()
+                .map(|quota| {
+                    let limits = Limits {
+                        max_bytes: quota.max_bytes,
+                        max_duration: quota.max_duration,
+                    };
+                    (quota.token, limits)
+                })
+                .collect(),
+            action: config.action,
+            throttle_bytes_per_second: config.throttle_bytes_per_second as f64,
+            rejection_payload: config.rejection_payload,
+            state: Mutex::new(State::default()),
+            metrics,
+        }
+    }
+
+    /// Counts a packet of `size` bytes towards `token`'s quota, returning
+    /// what to do with it.
+    fn enforce(&self, token: &[u8], usage: &mut Usage, size: usize, now: Instant) -> Verdict {
+        if !usage.exceeded {
+            let limits = self.limits.get(token).unwrap_or(&self.default_limits);
+            let exceeded = limits.max_bytes.map_or(false, |max| usage.bytes >= max)
+                || limits
+                    .max_duration
+                    .map_or(false, |max| now.duration_since(usage.first_seen) >= max);
+            if !exceeded {
+                usage.bytes += size as u64;
+                return Verdict::Pass;
+            }
+            usage.exceeded = true;
+            usage.refilled = now;
+            self.metrics.quotas_exceeded.inc();
+        }
+
+        match self.action {
+            Action::Throttle => {
+                // Allow bursts of up to a second's worth of bytes.
+                let refill = now.duration_since(usage.refilled).as_secs_f64()
+                    * self.throttle_bytes_per_second;
+                usage.allowance = (usage.allowance + refill).min(self.throttle_bytes_per_second);
+                usage.refilled = now;
+                if usage.allowance < size as f64 {
+                    return Verdict::Drop(THROTTLED_REASON);
+                }
+                usage.allowance -= size as f64;
+                usage.bytes += size as u64;
+                Verdict::Pass
+            }
+            Action::Reject if !usage.rejected => Verdict::Reject,
+            Action::Reject | Action::Terminate => Verdict::Drop(EXCEEDED_REASON),
+        }
+    }
+
+    fn dropped<T>(&self, reason: &'static str) -> Option<T> {
+        self.metrics
+            .packets_dropped
+            .with_label_values(&[reason])
+            .inc();
+        drop_packet(reason)
+    }
+}
+
+impl Filter for TokenQuota {
+    fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+        let token = match ctx
+            .metadata
+            .get(&self.metadata_key)
+            .and_then(|value| value.downcast_ref::<Vec<u8>>())
+        {
+            Some(token) => token,
+            None => return Some(ctx.into()),
+        };
+
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        if state.clients.get(&ctx.from) != Some(token) {
+            state.clients.insert(ctx.from, token.clone());
+        }
+        if !state.usage.contains_key(token) {
+            state.usage.insert(token.clone(), Usage::new(now));
+            self.metrics.tokens.set(state.usage.len() as i64);
+        }
+        let usage = state.usage.get_mut(token).unwrap();
+        match self.enforce(token, usage, ctx.contents.len(), now) {
+            Verdict::Pass => Some(ctx.into()),
+            // The client is sent the rejection payload with the next packet
+            // sent to it.
+            Verdict::Reject => self.dropped(EXCEEDED_REASON),
+            Verdict::Drop(reason) => self.dropped(reason),
+        }
+    }
+
+    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        let State { usage, clients } = &mut *state;
+        // Clients are only known once they have sent a packet with a token.
+        let (token, usage) = match clients
+            .get(&ctx.to)
+            .and_then(|token| usage.get_mut(token).map(|usage| (token, usage)))
+        {
+            Some(found) => found,
+            None => return Some(ctx.into()),
+        };
+
+        match self.enforce(token, usage, ctx.contents.len(), now) {
+            Verdict::Pass => Some(ctx.into()),
+            Verdict::Reject => {
+                usage.rejected = true;
+                self.metrics.rejections_sent.inc();
+                ctx.contents = self.rejection_payload.clone();
+                Some(ctx.into())
+            }
+            Verdict::Drop(reason) => self.dropped(reason),
+        }
+    }
+
+    /// Returns the usage of each token.
+    fn export_state(&self) -> Option<serde_json::Value> {
+        let now = Instant::now();
+        let usage = self
+            .state
+            .lock()
+            .usage
+            .iter()
+            .map(|(token, usage)| ExportedUsage {
+                token: token.clone(),
+                bytes: usage.bytes,
+                age: now.duration_since(usage.first_seen),
+                exceeded: usage.exceeded,
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_value(usage).ok()
+    }
+
+    /// Restores the usage of each token, so that tokens can't get a fresh
+    /// quota by being moved to another proxy.
+    fn import_state(&self, state: serde_json::Value) -> Result<(), Error> {
+        let exported = serde_json::from_value::<Vec<ExportedUsage>>(state)
+            .map_err(|err| Error::DeserializeFailed(err.to_string()))?;
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        for ExportedUsage {
+            token,
+            bytes,
+            age,
+            exceeded,
+        } in exported
+        {
+            let mut usage = Usage::new(now.checked_sub(age).unwrap_or(now));
+            usage.bytes = bytes;
+            usage.exceeded = exceeded;
+            usage.refilled = now;
+            state.usage.insert(token, usage);
+        }
+        self.metrics.tokens.set(state.usage.len() as i64);
+        Ok(())
+    }
+
+    /// Returns the memory held by the usage of tokens and the tokens of
+    /// clients.
+    fn memory_usage(&self) -> Option<usize> {
+        let state = self.state.lock();
+        Some(
+            state.usage.capacity() * std::mem::size_of::<(Vec<u8>, Usage)>()
+                + state.clients.capacity() * std::mem::size_of::<(SocketAddr, Vec<u8>)>(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+    use serde_yaml::Value;
+
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::{
+        drop_reason, extensions::CAPTURED_BYTES, CreateFilterArgs, Filter, FilterFactory,
+        ReadContext, WriteContext,
+    };
+
+    use super::quilkin::extensions::filters::token_quota::v1beta1::token_quota::{
+        Action as ProtoAction, ActionValue, Quota as ProtoQuota,
+    };
+    use super::{
+        Action, Config, Metrics, ProtoConfig, Quota, TokenQuota, TokenQuotaFactory,
+        EXCEEDED_REASON, THROTTLED_REASON,
+    };
+
+    const CLIENT: &str = "127.0.0.1:7000";
+
+    fn token_quota(config: Config) -> TokenQuota {
+        TokenQuota::new(config, Metrics::new(&Registry::default()).unwrap())
+    }
+
+    fn read(filter: &TokenQuota, token: &[u8], contents: &[u8]) -> bool {
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:7001".parse().unwrap(),
+        )])
+        .unwrap();
+        let mut ctx =
+            ReadContext::new(endpoints.into(), CLIENT.parse().unwrap(), contents.to_vec());
+        ctx.metadata
+            .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(token.to_vec()));
+        filter.read(ctx).is_some()
+    }
+
+    fn write(filter: &TokenQuota, contents: &[u8]) -> Option<Vec<u8>> {
+        let endpoint = Endpoint::from_address("127.0.0.1:7001".parse().unwrap());
+        filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                CLIENT.parse().unwrap(),
+                contents.to_vec(),
+            ))
+            .map(|response| response.contents)
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config::try_from(ProtoConfig {
+            metadata_key: None,
+            max_bytes: Some(1000),
+            max_duration: Some(prost_types::Duration {
+                seconds: 60,
+                nanos: 0,
+            }),
+            tokens: vec![ProtoQuota {
+                token: b"abc".to_vec(),
+                max_bytes: None,
+                max_duration: None,
+            }],
+            action: Some(ActionValue {
+                value: ProtoAction::Throttle as i32,
+            }),
+            throttle_bytes_per_second: None,
+            rejection_payload: vec![],
+        })
+        .unwrap();
+        assert_eq!(
+            Config {
+                max_bytes: Some(1000),
+                max_duration: Some(Duration::from_secs(60)),
+                tokens: vec![Quota {
+                    token: b"abc".to_vec(),
+                    max_bytes: None,
+                    max_duration: None,
+                }],
+                action: Action::Throttle,
+                ..Config::default()
+            },
+            config
+        );
+
+        assert!(Config::try_from(ProtoConfig {
+            action: Some(ActionValue { value: 42 }),
+            ..ProtoConfig::from(Config::default())
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            metadata_key: "TOKEN".into(),
+            max_bytes: Some(1000),
+            max_duration: Some(Duration::from_secs(60)),
+            tokens: vec![Quota {
+                token: b"abc".to_vec(),
+                max_bytes: Some(10),
+                max_duration: None,
+            }],
+            action: Action::Reject,
+            throttle_bytes_per_second: 100,
+            rejection_payload: b"trial over".to_vec(),
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
+    #[test]
+    fn factory_config() {
+        let create = |yaml: &str| {
+            let config = serde_yaml::from_str::<Value>(yaml).unwrap();
+            TokenQuotaFactory::default()
+                .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+        };
+
+        assert!(create(
+            "
+max_bytes: 1000000
+max_duration: 30m
+tokens:
+  - token: YWJj
+    max_duration: 2h
+action: REJECT
+rejection_payload: VFJJQUxfT1ZFUg==
+"
+        )
+        .is_ok());
+        assert!(create("action: REJECT").is_err());
+        assert!(create("action: THROTTLE\nthrottle_bytes_per_second: 0").is_err());
+    }
+
+    #[test]
+    fn no_token() {
+        let filter = token_quota(Config {
+            max_bytes: Some(0),
+            ..Config::default()
+        });
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:7001".parse().unwrap(),
+        )])
+        .unwrap();
+        assert!(filter
+            .read(ReadContext::new(
+                endpoints.into(),
+                CLIENT.parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .is_some());
+        assert_eq!(Some(b"hello".to_vec()), write(&filter, b"hello"));
+    }
+
+    #[test]
+    fn terminate_over_max_bytes() {
+        let filter = token_quota(Config {
+            max_bytes: Some(10),
+            ..Config::default()
+        });
+
+        assert!(read(&filter, b"abc", b"hello"));
+        assert_eq!(Some(b"world".to_vec()), write(&filter, b"world"));
+        // Other tokens have a quota of their own.
+        assert!(read(&filter, b"xyz", b"hello"));
+
+        assert!(!read(&filter, b"abc", b"hello"));
+        assert_eq!(EXCEEDED_REASON, drop_reason::take());
+        assert_eq!(None, write(&filter, b"world"));
+        assert_eq!(1, filter.metrics.quotas_exceeded.get());
+        assert_eq!(
+            2,
+            filter
+                .metrics
+                .packets_dropped
+                .with_label_values(&[EXCEEDED_REASON])
+                .get()
+        );
+        assert_eq!(2, filter.metrics.tokens.get());
+    }
+
+    #[test]
+    fn terminate_over_max_duration() {
+        let filter = token_quota(Config {
+            max_duration: Some(Duration::from_millis(10)),
+            tokens: vec![Quota {
+                token: b"xyz".to_vec(),
+                max_bytes: None,
+                max_duration: None,
+            }],
+            ..Config::default()
+        });
+
+        assert!(read(&filter, b"abc", b"hello"));
+        assert!(read(&filter, b"xyz", b"hello"));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!read(&filter, b"abc", b"hello"));
+        // The token's own quota is unlimited.
+        assert!(read(&filter, b"xyz", b"hello"));
+    }
+
+    #[test]
+    fn reject() {
+        let filter = token_quota(Config {
+            max_bytes: Some(5),
+            action: Action::Reject,
+            rejection_payload: b"trial over".to_vec(),
+            ..Config::default()
+        });
+
+        assert!(read(&filter, b"abc", b"hello"));
+        assert!(!read(&filter, b"abc", b"hello"));
+        assert_eq!(Some(b"trial over".to_vec()), write(&filter, b"world"));
+        assert_eq!(None, write(&filter, b"world"));
+        assert_eq!(1, filter.metrics.rejections_sent.get());
+    }
+
+    #[test]
+    fn throttle() {
+        let filter = token_quota(Config {
+            max_bytes: Some(5),
+            action: Action::Throttle,
+            throttle_bytes_per_second: 1000,
+            ..Config::default()
+        });
+
+        assert!(read(&filter, b"abc", b"hello"));
+        assert!(!read(&filter, b"abc", b"hello"));
+        assert_eq!(THROTTLED_REASON, drop_reason::take());
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(read(&filter, b"abc", b"hello"));
+    }
+
+    #[test]
+    fn export_import_state() {
+        let config = Config {
+            max_bytes: Some(10),
+            ..Config::default()
+        };
+        let filter = token_quota(config.clone());
+        assert!(read(&filter, b"abc", b"hello world"));
+        let state = filter.export_state().unwrap();
+
+        let imported = token_quota(config);
+        imported.import_state(state).unwrap();
+        assert!(!read(&imported, b"abc", b"hello"));
+        assert_eq!(1, imported.metrics.tokens.get());
+
+        assert!(imported.import_state(serde_json::json!({})).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+/*
+ * Copyright 2020 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *       http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ */
+
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Registry, Result as MetricsResult};
+
+use crate::filters::FilterMetrics;
+
+use super::TokenQuota;
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped: IntCounterVec,
+    pub(super) quotas_exceeded: IntCounter,
+    pub(super) rejections_sent: IntCounter,
+    pub(super) tokens: IntGauge,
+}
+
+impl Metrics {
+    pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let metrics = FilterMetrics::new(registry, TokenQuota::FILTER_NAME);
+        Ok(Metrics {
+            packets_dropped: metrics.counter_vec(
+                "packets_dropped",
+                "Total number of packets dropped as their token is over its quota. labels: reason.",
+                &["reason"],
+            )?,
+            quotas_exceeded: metrics.counter(
+                "quotas_exceeded",
+                "Total number of tokens that have exceeded their quota.",
+            )?,
+            rejections_sent: metrics.counter(
+                "rejections_sent",
+                "Total number of rejection payloads sent to clients whose token is over its quota.",
+            )?,
+            tokens: metrics.gauge("tokens", "Number of tokens whose usage is being tracked.")?,
+        })
+    }
+}
@@ -16,9 +16,13 @@
 
 mod metrics;
 
-crate::include_proto!("quilkin.extensions.filters.token_router.v1alpha1");
+crate::include_proto!("quilkin.extensions.filters.token_router.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::token_router::v1beta1 as proto;
 
 use std::convert::TryFrom;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
@@ -30,16 +34,47 @@ use crate::{
         extensions::{token_router::metrics::Metrics, CAPTURED_BYTES},
         prelude::*,
     },
+    map_proto_enum,
+};
+
+use self::quilkin::extensions::filters::token_router::v1beta1::{
+    token_router::Fallback as ProtoFallback, token_router::FallbackValue,
+    TokenRouter as ProtoConfig,
 };
 
-use self::quilkin::extensions::filters::token_router::v1alpha1::TokenRouter as ProtoConfig;
+/// Fallback represents what a `TokenRouter` does with packets whose token
+/// doesn't match any endpoint, or that have no token.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub enum Fallback {
+    /// Drop the packets.
+    #[serde(rename = "DROP")]
+    Drop,
+    /// Send the packets to the lobby endpoint only.
+    #[serde(rename = "LOBBY")]
+    Lobby,
+    /// Send the packets to all endpoints.
+    #[serde(rename = "ALL")]
+    All,
+}
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+impl Default for Fallback {
+    fn default() -> Self {
+        Fallback::Drop
+    }
+}
+
+/// Config represents a `TokenRouter` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(default)]
-struct Config {
+pub struct Config {
     /// the key to use when retrieving the token from the Filter's dynamic metadata
     #[serde(rename = "metadataKey", default = "default_metadata_key")]
-    metadata_key: String,
+    pub metadata_key: String,
+    /// what to do with packets that can't be routed by their token
+    pub fallback: Fallback,
+    /// the address of the endpoint that packets are sent to by [`Fallback::Lobby`]
+    #[serde(rename = "lobbyEndpoint")]
+    pub lobby_endpoint: Option<SocketAddr>,
 }
 
 /// Default value for [`Config::metadata_key`]
@@ -51,6 +86,8 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             metadata_key: default_metadata_key(),
+            fallback: Fallback::default(),
+            lobby_endpoint: None,
         }
     }
 }
@@ -59,18 +96,64 @@ impl TryFrom<ProtoConfig> for Config {
     type Error = ConvertProtoConfigError;
 
     fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        let fallback = p
+            .fallback
+            .map(|fallback| {
+                map_proto_enum!(
+                    value = fallback.value,
+                    field = "fallback",
+                    proto_enum_type = ProtoFallback,
+                    target_enum_type = Fallback,
+                    variants = [Drop, Lobby, All]
+                )
+            })
+            .transpose()?
+            .unwrap_or_else(Fallback::default);
+        let lobby_endpoint = p
+            .lobby_endpoint
+            .map(|address| {
+                address.parse().map_err(|err| {
+                    ConvertProtoConfigError::new(
+                        format!("invalid address: {}", err),
+                        Some("lobby_endpoint".into()),
+                    )
+                })
+            })
+            .transpose()?;
+
         Ok(Self {
             metadata_key: p.metadata_key.unwrap_or_else(default_metadata_key),
+            fallback,
+            lobby_endpoint,
         })
     }
 }
 
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        let fallback = match config.fallback {
+            Fallback::Drop => ProtoFallback::Drop,
+            Fallback::Lobby => ProtoFallback::Lobby,
+            Fallback::All => ProtoFallback::All,
+        };
+        Self {
+            metadata_key: Some(config.metadata_key),
+            fallback: Some(FallbackValue {
+                value: fallback as i32,
+            }),
+            lobby_endpoint: config.lobby_endpoint.map(|address| address.to_string()),
+        }
+    }
+}
+
 /// Filter that only allows packets to be passed to Endpoints that have a matching
 /// connection_id to the token stored in the Filter's dynamic metadata.
-#[crate::filter("quilkin.extensions.filters.token_router.v1alpha1.TokenRouter")]
+#[crate::filter("quilkin.extensions.filters.token_router.v1beta1.TokenRouter")]
 struct TokenRouter {
     log: Logger,
     metadata_key: Arc<String>,
+    fallback: Fallback,
+    lobby_endpoint: Option<SocketAddr>,
     metrics: Metrics,
 }
 
@@ -90,6 +173,14 @@ impl FilterFactory for TokenRouterFactory {
         TokenRouter::FILTER_NAME
     }
 
+    fn deprecated_names(&self) -> &'static [&'static str] {
+        &["quilkin.extensions.filters.token_router.v1alpha1.TokenRouter"]
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("token_router/schema.yaml"))
+    }
+
     fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
         let config: Config = args
             .config
@@ -97,6 +188,13 @@ impl FilterFactory for TokenRouterFactory {
             .transpose()?
             .unwrap_or_default();
 
+        if config.fallback == Fallback::Lobby && config.lobby_endpoint.is_none() {
+            return Err(Error::FieldInvalid {
+                field: "lobbyEndpoint".into(),
+                reason: "a lobby endpoint is required by the LOBBY fallback".into(),
+            });
+        }
+
         Ok(Box::new(TokenRouter::new(
             &self.log,
             config,
@@ -110,15 +208,44 @@ impl TokenRouter {
         Self {
             log: base.new(o!("source" => "extensions::TokenRouter")),
             metadata_key: Arc::new(config.metadata_key),
+            fallback: config.fallback,
+            lobby_endpoint: config.lobby_endpoint,
             metrics,
         }
     }
+
+    /// Routes a packet that can't be routed by its token according to the
+    /// configured fallback, returning `None` if it should be dropped for
+    /// `reason` instead.
+    fn fallback(&self, mut ctx: ReadContext, reason: &'static str) -> Option<ReadResponse> {
+        match self.fallback {
+            Fallback::Drop => return None,
+            Fallback::Lobby => {
+                let lobby_endpoint = self.lobby_endpoint;
+                if let RetainedItems::None =
+                    ctx.endpoints.retain(|e| Some(e.address) == lobby_endpoint)
+                {
+                    return None;
+                }
+                self.metrics.routing.retained(&ctx.endpoints);
+            }
+            Fallback::All => self.metrics.routing.fell_through(),
+        }
+        self.metrics
+            .packets_fallback
+            .with_label_values(&[reason])
+            .inc();
+        Some(ctx.into())
+    }
 }
 
 impl Filter for TokenRouter {
     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
         match ctx.metadata.get(self.metadata_key.as_ref()) {
             None => {
+                if let Some(response) = self.fallback(ctx, "NoTokenFound") {
+                    return Some(response);
+                }
                 if self.metrics.packets_dropped_no_token_found.get() % LOG_SAMPLING_RATE == 0 {
                     error!(
                         self.log,
@@ -128,15 +255,21 @@ impl Filter for TokenRouter {
                     );
                 }
                 self.metrics.packets_dropped_no_token_found.inc();
-                None
+                drop_packet("NoTokenFound")
             }
             Some(value) => match value.downcast_ref::<Vec<u8>>() {
                 Some(token) => match ctx.endpoints.retain(|e| e.tokens.contains(token)) {
-                    RetainedItems::None => {
-                        self.metrics.packets_dropped_no_endpoint_match.inc();
-                        None
+                    RetainedItems::None => match self.fallback(ctx, "NoEndpointMatch") {
+                        Some(response) => Some(response),
+                        None => {
+                            self.metrics.packets_dropped_no_endpoint_match.inc();
+                            drop_packet("NoEndpointMatch")
+                        }
+                    },
+                    _ => {
+                        self.metrics.routing.retained(&ctx.endpoints);
+                        Some(ctx.into())
                     }
-                    _ => Some(ctx.into()),
                 },
                 None => {
                     if self.metrics.packets_dropped_invalid_token.get() % LOG_SAMPLING_RATE == 0 {
@@ -148,7 +281,7 @@ impl Filter for TokenRouter {
                         );
                     }
                     self.metrics.packets_dropped_invalid_token.inc();
-                    None
+                    drop_packet("InvalidToken")
                 }
             },
         }
@@ -172,11 +305,15 @@ mod tests {
     use crate::test_utils::{assert_write_no_change, logger};
 
     use super::{
-        default_metadata_key, Config, Metrics, ProtoConfig, TokenRouter, TokenRouterFactory,
+        default_metadata_key,
+        quilkin::extensions::filters::token_router::v1beta1::token_router::{
+            Fallback as ProtoFallback, FallbackValue,
+        },
+        Config, Fallback, Metrics, ProtoConfig, TokenRouter, TokenRouterFactory,
     };
     use crate::cluster::Endpoint;
     use crate::filters::{
-        extensions::CAPTURED_BYTES, CreateFilterArgs, Filter, FilterFactory, ReadContext,
+        extensions::CAPTURED_BYTES, CreateFilterArgs, Error, Filter, FilterFactory, ReadContext,
     };
 
     const TOKEN_KEY: &str = "TOKEN";
@@ -196,18 +333,48 @@ mod tests {
                 "should succeed when all valid values are provided",
                 ProtoConfig {
                     metadata_key: Some("foobar".into()),
+                    fallback: Some(FallbackValue {
+                        value: ProtoFallback::Lobby as i32,
+                    }),
+                    lobby_endpoint: Some("127.0.0.1:90".into()),
                 },
                 Some(Config {
                     metadata_key: "foobar".into(),
+                    fallback: Fallback::Lobby,
+                    lobby_endpoint: Some("127.0.0.1:90".parse().unwrap()),
                 }),
             ),
             (
                 "should use correct default values",
-                ProtoConfig { metadata_key: None },
+                ProtoConfig {
+                    metadata_key: None,
+                    fallback: None,
+                    lobby_endpoint: None,
+                },
                 Some(Config {
                     metadata_key: default_metadata_key(),
+                    fallback: Fallback::Drop,
+                    lobby_endpoint: None,
                 }),
             ),
+            (
+                "should fail when invalid fallback is provided",
+                ProtoConfig {
+                    metadata_key: None,
+                    fallback: Some(FallbackValue { value: 42 }),
+                    lobby_endpoint: None,
+                },
+                None,
+            ),
+            (
+                "should fail when invalid lobby endpoint is provided",
+                ProtoConfig {
+                    metadata_key: None,
+                    fallback: None,
+                    lobby_endpoint: Some("lobby".into()),
+                },
+                None,
+            ),
         ];
         for (name, proto_config, expected) in test_cases {
             let result = Config::try_from(proto_config);
@@ -223,6 +390,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            metadata_key: TOKEN_KEY.into(),
+            fallback: Fallback::Lobby,
+            lobby_endpoint: Some("127.0.0.1:7001".parse().unwrap()),
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
     #[test]
     fn factory_custom_tokens() {
         let factory = TokenRouterFactory::new(&logger());
@@ -279,6 +459,7 @@ mod tests {
         // valid key
         let config = Config {
             metadata_key: CAPTURED_BYTES.into(),
+            ..Config::default()
         };
         let filter = router(config);
 
@@ -311,10 +492,127 @@ mod tests {
         assert_eq!(1, filter.metrics.packets_dropped_invalid_token.get());
     }
 
+    #[test]
+    fn factory_lobby_without_endpoint() {
+        let factory = TokenRouterFactory::new(&logger());
+        let config: Value = serde_yaml::from_str("fallback: LOBBY").unwrap();
+
+        let result = factory.create_filter(CreateFilterArgs::fixed(
+            Registry::default(),
+            Some(&config),
+        ));
+        assert_eq!(
+            Error::FieldInvalid {
+                field: "lobbyEndpoint".into(),
+                reason: "a lobby endpoint is required by the LOBBY fallback".into(),
+            },
+            result.err().unwrap()
+        );
+    }
+
+    #[test]
+    fn fallback_lobby() {
+        let filter = router(Config {
+            fallback: Fallback::Lobby,
+            lobby_endpoint: Some("127.0.0.1:90".parse().unwrap()),
+            ..Config::default()
+        });
+
+        // a matching token is still routed by the token
+        let mut ctx = new_ctx();
+        ctx.metadata
+            .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(b"123".to_vec()));
+        let result = filter.read(ctx).unwrap();
+        let endpoints = result.endpoints.iter().collect::<Vec<_>>();
+        assert_eq!(1, endpoints.len());
+        assert_eq!("127.0.0.1:80", endpoints[0].address.to_string());
+
+        // no matching token
+        let mut ctx = new_ctx();
+        ctx.metadata
+            .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(b"567".to_vec()));
+        let result = filter.read(ctx).unwrap();
+        let endpoints = result.endpoints.iter().collect::<Vec<_>>();
+        assert_eq!(1, endpoints.len());
+        assert_eq!("127.0.0.1:90", endpoints[0].address.to_string());
+
+        // no token
+        let result = filter.read(new_ctx()).unwrap();
+        assert_eq!(1, result.endpoints.size());
+
+        assert_eq!(0, filter.metrics.packets_dropped_no_endpoint_match.get());
+        assert_eq!(0, filter.metrics.packets_dropped_no_token_found.get());
+        assert_eq!(
+            3,
+            filter
+                .metrics
+                .routing
+                .retained_endpoints
+                .get_sample_count()
+        );
+        assert_eq!(
+            1,
+            filter
+                .metrics
+                .packets_fallback
+                .with_label_values(&["NoEndpointMatch"])
+                .get()
+        );
+        assert_eq!(
+            1,
+            filter
+                .metrics
+                .packets_fallback
+                .with_label_values(&["NoTokenFound"])
+                .get()
+        );
+
+        // the lobby endpoint is missing
+        let filter = router(Config {
+            fallback: Fallback::Lobby,
+            lobby_endpoint: Some("127.0.0.1:100".parse().unwrap()),
+            ..Config::default()
+        });
+        assert!(filter.read(new_ctx()).is_none());
+        assert_eq!(1, filter.metrics.packets_dropped_no_token_found.get());
+    }
+
+    #[test]
+    fn fallback_all() {
+        let filter = router(Config {
+            fallback: Fallback::All,
+            ..Config::default()
+        });
+
+        let mut ctx = new_ctx();
+        ctx.metadata
+            .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(b"567".to_vec()));
+        assert_eq!(2, filter.read(ctx).unwrap().endpoints.size());
+        assert_eq!(2, filter.read(new_ctx()).unwrap().endpoints.size());
+        assert_eq!(2, filter.metrics.routing.packets_routed_to_all_endpoints.get());
+        assert_eq!(
+            0,
+            filter
+                .metrics
+                .routing
+                .retained_endpoints
+                .get_sample_count()
+        );
+
+        // a token of the wrong type is still dropped
+        let mut ctx = new_ctx();
+        ctx.metadata.insert(
+            Arc::new(CAPTURED_BYTES.into()),
+            Box::new(String::from("wrong")),
+        );
+        assert!(filter.read(ctx).is_none());
+    }
+
     #[test]
     fn write() {
         let config = Config {
             metadata_key: CAPTURED_BYTES.into(),
+            ..Config::default()
         };
         let filter = router(config);
         assert_write_no_change(&filter);
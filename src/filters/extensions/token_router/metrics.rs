@@ -13,38 +13,46 @@
  *  See the License for the specific language governing permissions and
  *  limitations under the License.
  */
+
 use prometheus::core::{AtomicU64, GenericCounter};
 use prometheus::{IntCounterVec, Registry, Result as MetricsResult};
 
-use crate::metrics::{filter_opts, CollectorExt};
+use crate::filters::{FilterMetrics, RoutingMetrics};
+
+use super::TokenRouter;
 
 /// Register and manage metrics for this filter
 pub(super) struct Metrics {
     pub(super) packets_dropped_no_token_found: GenericCounter<AtomicU64>,
     pub(super) packets_dropped_invalid_token: GenericCounter<AtomicU64>,
     pub(super) packets_dropped_no_endpoint_match: GenericCounter<AtomicU64>,
+    pub(super) packets_fallback: IntCounterVec,
+    pub(super) routing: RoutingMetrics,
 }
 
 impl Metrics {
     pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
-        let label_names = vec!["reason"];
-        let metric = IntCounterVec::new(
-            filter_opts(
-                "packets_dropped",
-                "TokenRouter",
-                "Total number of packets dropped. labels: reason.",
-            ),
-            &label_names,
-        )?
-        .register(registry)?;
+        let metrics = FilterMetrics::new(registry, TokenRouter::FILTER_NAME);
+        let metric = metrics.counter_vec(
+            "packets_dropped",
+            "Total number of packets dropped. labels: reason.",
+            &["reason"],
+        )?;
+        let packets_fallback = metrics.counter_vec(
+            "packets_fallback",
+            "Total number of packets routed using the fallback. labels: reason.",
+            &["reason"],
+        )?;
 
         Ok(Metrics {
             packets_dropped_no_token_found: metric
-                .get_metric_with_label_values(vec!["NoTokenFound"].as_slice())?,
+                .get_metric_with_label_values(&["NoTokenFound"])?,
             packets_dropped_invalid_token: metric
-                .get_metric_with_label_values(vec!["InvalidToken"].as_slice())?,
+                .get_metric_with_label_values(&["InvalidToken"])?,
             packets_dropped_no_endpoint_match: metric
-                .get_metric_with_label_values(vec!["NoEndpointMatch"].as_slice())?,
+                .get_metric_with_label_values(&["NoEndpointMatch"])?,
+            packets_fallback,
+            routing: RoutingMetrics::new(&metrics)?,
         })
     }
 }
@@ -0,0 +1,533 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+
+use base64_serde::base64_serde_type;
+use serde::{Deserialize, Serialize};
+
+use metrics::Metrics;
+
+use crate::filters::prelude::*;
+use crate::map_proto_enum;
+
+crate::include_proto!("quilkin.extensions.filters.transform.v1beta1");
+
+/// Protobuf config for this filter.
+pub use self::quilkin::extensions::filters::transform::v1beta1 as proto;
+
+use self::quilkin::extensions::filters::transform::v1beta1::{
+    transform::{Kind as ProtoKind, Operation as ProtoOperation},
+    Transform as ProtoConfig,
+};
+
+mod metrics;
+
+base64_serde_type!(Base64Standard, base64::STANDARD);
+
+/// The lengths, in bytes, of the fields whose byte order can be swapped.
+const SWAP_LENGTHS: [usize; 3] = [2, 4, 8];
+
+/// How an [`Operation`] changes packets.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Kind {
+    #[serde(rename = "SWAP_BYTES")]
+    /// Reverse the byte order of the `length` bytes at `offset`
+    SwapBytes,
+    #[serde(rename = "INSERT")]
+    /// Insert `bytes` at `offset`
+    Insert,
+    #[serde(rename = "REMOVE")]
+    /// Remove the `length` bytes at `offset`
+    Remove,
+    #[serde(rename = "REPLACE")]
+    /// Overwrite the bytes at `offset` with `bytes`
+    Replace,
+}
+
+/// A change to the bytes at a fixed position of packets.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Operation {
+    pub kind: Kind,
+    /// The position of the bytes the operation applies to. Negative offsets
+    /// count back from the end of the packet.
+    #[serde(default)]
+    pub offset: i64,
+    /// The number of bytes swapped or removed.
+    #[serde(default)]
+    pub length: usize,
+    /// The bytes inserted or written.
+    #[serde(default, with = "Base64Standard")]
+    pub bytes: Vec<u8>,
+}
+
+/// Config represents a `Transform` filter configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The operations applied, in order, to packets on Filter `Read`.
+    #[serde(default)]
+    pub on_read: Vec<Operation>,
+    /// The operations applied, in order, to packets on Filter `Write`.
+    #[serde(default)]
+    pub on_write: Vec<Operation>,
+}
+
+impl TryFrom<ProtoOperation> for Operation {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoOperation) -> Result<Self, Self::Error> {
+        let kind = map_proto_enum!(
+            value = p.kind,
+            field = "kind",
+            proto_enum_type = ProtoKind,
+            target_enum_type = Kind,
+            variants = [SwapBytes, Insert, Remove, Replace]
+        )?;
+        Ok(Self {
+            kind,
+            offset: p.offset,
+            length: p.length as usize,
+            bytes: p.bytes,
+        })
+    }
+}
+
+impl From<Operation> for ProtoOperation {
+    fn from(operation: Operation) -> Self {
+        let kind = match operation.kind {
+            Kind::SwapBytes => ProtoKind::SwapBytes,
+            Kind::Insert => ProtoKind::Insert,
+            Kind::Remove => ProtoKind::Remove,
+            Kind::Replace => ProtoKind::Replace,
+        };
+        Self {
+            kind: kind as i32,
+            offset: operation.offset,
+            length: operation.length as u32,
+            bytes: operation.bytes,
+        }
+    }
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            on_read: p
+                .on_read
+                .into_iter()
+                .map(Operation::try_from)
+                .collect::<Result<_, _>>()?,
+            on_write: p
+                .on_write
+                .into_iter()
+                .map(Operation::try_from)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            on_read: config.on_read.into_iter().map(Into::into).collect(),
+            on_write: config.on_write.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl Operation {
+    /// Returns why the operation can't be applied to any packet, if so.
+    fn validate(&self) -> Result<(), String> {
+        match self.kind {
+            Kind::SwapBytes if !SWAP_LENGTHS.contains(&self.length) => Err(format!(
+                "`length` must be one of {:?} to swap bytes",
+                SWAP_LENGTHS
+            )),
+            Kind::Remove if self.length == 0 => {
+                Err("`length` must be greater than zero to remove bytes".into())
+            }
+            Kind::SwapBytes | Kind::Remove if !self.bytes.is_empty() => {
+                Err("`bytes` is only used to insert or replace bytes".into())
+            }
+            Kind::Insert | Kind::Replace if self.bytes.is_empty() => {
+                Err("`bytes` must not be empty to insert or replace bytes".into())
+            }
+            Kind::Insert | Kind::Replace if self.length != 0 => {
+                Err("`length` is only used to swap or remove bytes".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the operation to `contents`, returning `None` without
+    /// changing them if they're too short for it.
+    fn apply(&self, contents: &mut Vec<u8>) -> Option<()> {
+        let start = if self.offset < 0 {
+            let back = usize::try_from(self.offset.checked_neg()?).ok()?;
+            contents.len().checked_sub(back)?
+        } else {
+            usize::try_from(self.offset).ok()?
+        };
+        let length = match self.kind {
+            Kind::SwapBytes | Kind::Remove => self.length,
+            Kind::Insert => 0,
+            Kind::Replace => self.bytes.len(),
+        };
+        let end = start.checked_add(length)?;
+        if end > contents.len() {
+            return None;
+        }
+
+        match self.kind {
+            Kind::SwapBytes => contents[start..end].reverse(),
+            Kind::Insert => {
+                contents.splice(start..start, self.bytes.iter().cloned());
+            }
+            Kind::Remove => {
+                contents.drain(start..end);
+            }
+            Kind::Replace => contents[start..end].copy_from_slice(&self.bytes),
+        }
+        Some(())
+    }
+}
+
+/// The `Transform` filter applies a list of operations to the bytes at fixed
+/// positions of packets, such as swapping the byte order of a field, so that
+/// small differences between the wire formats of clients and servers can be
+/// fixed up without writing a filter.
+#[crate::filter("quilkin.extensions.filters.transform.v1beta1.Transform")]
+struct Transform {
+    on_read: Vec<Operation>,
+    on_write: Vec<Operation>,
+    metrics: Metrics,
+}
+
+/// Factory for the Transform filter
+#[derive(Default)]
+pub struct TransformFactory;
+
+impl FilterFactory for TransformFactory {
+    fn name(&self) -> &'static str {
+        Transform::FILTER_NAME
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(include_str!("transform/schema.yaml"))
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config = self
+            .require_config(args.config)?
+            .deserialize::<Config, ProtoConfig>(self.name())?;
+
+        let operations = config
+            .on_read
+            .iter()
+            .enumerate()
+            .map(|(index, operation)| ("on_read", index, operation))
+            .chain(
+                config
+                    .on_write
+                    .iter()
+                    .enumerate()
+                    .map(|(index, operation)| ("on_write", index, operation)),
+            );
+        for (field, index, operation) in operations {
+            operation.validate().map_err(|reason| Error::FieldInvalid {
+                field: format!("{}[{}]", field, index),
+                reason,
+            })?;
+        }
+
+        Ok(Box::new(Transform::new(
+            config,
+            Metrics::new(&args.metrics_registry)?,
+        )))
+    }
+}
+
+impl Transform {
+    fn new(config: Config, metrics: Metrics) -> Self {
+        Self {
+            on_read: config.on_read,
+            on_write: config.on_write,
+            metrics,
+        }
+    }
+
+    /// Applies `operations` to `contents` in order, returning `None` if the
+    /// packet is too short for any of them.
+    fn apply(&self, operations: &[Operation], contents: &mut Vec<u8>) -> Option<()> {
+        for operation in operations {
+            if operation.apply(contents).is_none() {
+                self.metrics.packets_dropped_total.inc();
+                return None;
+            }
+        }
+        Some(())
+    }
+}
+
+impl Filter for Transform {
+    fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        if self.apply(&self.on_read, &mut ctx.contents).is_none() {
+            return drop_packet("PacketTooShort");
+        }
+        Some(ctx.into())
+    }
+
+    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+        if self.apply(&self.on_write, &mut ctx.contents).is_none() {
+            return drop_packet("PacketTooShort");
+        }
+        Some(ctx.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use prometheus::Registry;
+    use serde_yaml::Value;
+
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::{CreateFilterArgs, Filter, FilterFactory, ReadContext, WriteContext};
+
+    use super::metrics::Metrics;
+    use super::quilkin::extensions::filters::transform::v1beta1::{
+        transform::{Kind as ProtoKind, Operation as ProtoOperation},
+        Transform as ProtoConfig,
+    };
+    use super::{Config, Kind, Operation, Transform, TransformFactory};
+
+    fn operation(kind: Kind, offset: i64, length: usize, bytes: &[u8]) -> Operation {
+        Operation {
+            kind,
+            offset,
+            length,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    fn filter(on_read: Vec<Operation>, on_write: Vec<Operation>) -> Transform {
+        Transform::new(
+            Config { on_read, on_write },
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let test_cases = vec![
+            (
+                "should succeed when all valid values are provided",
+                ProtoConfig {
+                    on_read: vec![ProtoOperation {
+                        kind: ProtoKind::SwapBytes as i32,
+                        offset: 2,
+                        length: 4,
+                        bytes: vec![],
+                    }],
+                    on_write: vec![ProtoOperation {
+                        kind: ProtoKind::Insert as i32,
+                        offset: -1,
+                        length: 0,
+                        bytes: b"abc".to_vec(),
+                    }],
+                },
+                Some(Config {
+                    on_read: vec![operation(Kind::SwapBytes, 2, 4, b"")],
+                    on_write: vec![operation(Kind::Insert, -1, 0, b"abc")],
+                }),
+            ),
+            (
+                "should fail when invalid kind is provided",
+                ProtoConfig {
+                    on_read: vec![],
+                    on_write: vec![ProtoOperation {
+                        kind: 42,
+                        offset: 0,
+                        length: 1,
+                        bytes: vec![],
+                    }],
+                },
+                None,
+            ),
+            (
+                "should use correct default values",
+                ProtoConfig {
+                    on_read: vec![],
+                    on_write: vec![],
+                },
+                Some(Config::default()),
+            ),
+        ];
+        for (name, proto_config, expected) in test_cases {
+            let result = Config::try_from(proto_config);
+            assert_eq!(
+                result.is_err(),
+                expected.is_none(),
+                "{}: error expectation does not match",
+                name
+            );
+            if let Some(expected) = expected {
+                assert_eq!(expected, result.unwrap(), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn convert_config_to_proto() {
+        let config = Config {
+            on_read: vec![operation(Kind::Remove, -4, 4, b"")],
+            on_write: vec![operation(Kind::Replace, 0, 0, b"v2")],
+        };
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+    }
+
+    #[test]
+    fn factory_config() {
+        let create = |yaml: &str| {
+            let config = serde_yaml::from_str::<Value>(yaml).unwrap();
+            TransformFactory::default()
+                .create_filter(CreateFilterArgs::fixed(Registry::default(), Some(&config)))
+        };
+        assert!(create(
+            "
+on_read:
+  - kind: SWAP_BYTES
+    offset: 4
+    length: 2
+  - kind: INSERT
+    bytes: AQI=
+on_write:
+  - kind: REMOVE
+    offset: -4
+    length: 4
+  - kind: REPLACE
+    bytes: AQI=
+"
+        )
+        .is_ok());
+        assert!(create("on_read: [{ kind: SWAP_BYTES, length: 3 }]").is_err());
+        assert!(create("on_read: [{ kind: SWAP_BYTES, length: 2, bytes: AQI= }]").is_err());
+        assert!(create("on_read: [{ kind: REMOVE }]").is_err());
+        assert!(create("on_write: [{ kind: INSERT }]").is_err());
+        assert!(create("on_write: [{ kind: REPLACE, length: 2, bytes: AQI= }]").is_err());
+        assert!(create("on_write: [{ kind: ROTATE, length: 2 }]").is_err());
+    }
+
+    #[test]
+    fn apply() {
+        let apply = |operation: Operation, contents: &[u8]| {
+            let mut contents = contents.to_vec();
+            operation.apply(&mut contents).map(|_| contents)
+        };
+
+        assert_eq!(
+            Some(b"a\x02\x01bc".to_vec()),
+            apply(operation(Kind::SwapBytes, 1, 2, b""), b"a\x01\x02bc")
+        );
+        assert_eq!(
+            Some(b"ab\x04\x03\x02\x01".to_vec()),
+            apply(
+                operation(Kind::SwapBytes, -4, 4, b""),
+                b"ab\x01\x02\x03\x04"
+            )
+        );
+        assert_eq!(
+            Some(b"hexxllo".to_vec()),
+            apply(operation(Kind::Insert, 2, 0, b"xx"), b"hello")
+        );
+        assert_eq!(
+            Some(b"helloxx".to_vec()),
+            apply(operation(Kind::Insert, 5, 0, b"xx"), b"hello")
+        );
+        assert_eq!(
+            Some(b"hlo".to_vec()),
+            apply(operation(Kind::Remove, 1, 2, b""), b"hello")
+        );
+        assert_eq!(
+            Some(b"helxx".to_vec()),
+            apply(operation(Kind::Replace, -2, 0, b"xx"), b"hello")
+        );
+
+        // Operations that don't fit in the packet aren't applied.
+        assert_eq!(None, apply(operation(Kind::SwapBytes, 4, 2, b""), b"hello"));
+        assert_eq!(None, apply(operation(Kind::Remove, -6, 1, b""), b"hello"));
+        assert_eq!(None, apply(operation(Kind::Insert, 6, 0, b"xx"), b"hello"));
+        assert_eq!(None, apply(operation(Kind::Replace, 4, 0, b"xx"), b"hello"));
+        assert_eq!(
+            None,
+            apply(operation(Kind::Remove, i64::MIN, 1, b""), b"hello")
+        );
+    }
+
+    #[test]
+    fn read() {
+        let filter = filter(
+            vec![
+                operation(Kind::Remove, 0, 1, b""),
+                operation(Kind::SwapBytes, 0, 2, b""),
+            ],
+            vec![],
+        );
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:7001".parse().unwrap(),
+        )])
+        .unwrap();
+        let read = |contents: &[u8]| {
+            filter.read(ReadContext::new(
+                endpoints.clone().into(),
+                "127.0.0.1:7000".parse().unwrap(),
+                contents.to_vec(),
+            ))
+        };
+
+        // Each operation applies to the result of the previous one.
+        let response = read(b"\x02\x01\x00hello").unwrap();
+        assert_eq!(b"\x00\x01hello".to_vec(), response.contents);
+
+        assert!(read(b"\x02\x01").is_none());
+        assert_eq!(1, filter.metrics.packets_dropped_total.get());
+    }
+
+    #[test]
+    fn write() {
+        let filter = filter(vec![], vec![operation(Kind::Insert, 0, 0, b"v2")]);
+        let endpoint = Endpoint::from_address("127.0.0.1:7001".parse().unwrap());
+        let response = filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                "127.0.0.1:7000".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(b"v2hello".to_vec(), response.contents);
+        assert_eq!(0, filter.metrics.packets_dropped_total.get());
+    }
+}
@@ -17,6 +17,7 @@
 use prometheus::Registry;
 
 use crate::filters::{ConfigType, Error, Filter};
+use crate::secret::SecretProviders;
 
 /// An owned pointer to a dynamic [`FilterFactory`] instance.
 pub type DynFilterFactory = Box<dyn FilterFactory>;
@@ -30,10 +31,25 @@ pub trait FilterFactory: Sync + Send {
     ///     <module>: The rust module name containing the filter item
     ///     <version>: The filter's version.
     ///     <item-name>: The name of the rust item (e.g enum, struct) implementing the filter.
-    /// For example the `v1alpha1` version of the debug filter has the name:
-    ///     `quilkin.extensions.filters.debug_filter.v1alpha1.Debug`
+    /// For example the `v1beta1` version of the debug filter has the name:
+    ///     `quilkin.extensions.filters.debug.v1beta1.Debug`
     fn name(&self) -> &'static str;
 
+    /// Returns the names of previous versions of the filter that are still
+    /// accepted during their deprecation window. Configurations using these
+    /// names are handed to this factory, which must be able to translate the
+    /// older configuration into its current form.
+    fn deprecated_names(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Returns the schema of the filter's configuration, as a JSON schema
+    /// written in YAML, so that tooling can check configurations without
+    /// creating the filter.
+    fn config_schema(&self) -> Option<&'static str> {
+        None
+    }
+
     /// Returns a filter based on the provided arguments.
     fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error>;
 
@@ -53,6 +69,9 @@ pub struct CreateFilterArgs<'a> {
     pub config: Option<ConfigType<'a>>,
     /// metrics_registry is used to register filter metrics collectors.
     pub metrics_registry: Registry,
+    /// The providers that secrets referred to by the configuration are
+    /// read from.
+    pub secret_providers: SecretProviders,
 }
 
 impl CreateFilterArgs<'_> {
@@ -65,6 +84,7 @@ impl CreateFilterArgs<'_> {
         CreateFilterArgs {
             config: config.map(|config| ConfigType::Static(config)),
             metrics_registry,
+            secret_providers: SecretProviders::default(),
         }
     }
 
@@ -77,6 +97,7 @@ impl CreateFilterArgs<'_> {
         CreateFilterArgs {
             config: config.map(ConfigType::Dynamic),
             metrics_registry,
+            secret_providers: SecretProviders::default(),
         }
     }
 
@@ -88,4 +109,13 @@ impl CreateFilterArgs<'_> {
             ..self
         }
     }
+
+    /// Consumes `self` and returns a new instance of [`Self`] that reads
+    /// secrets from `secret_providers`.
+    pub(crate) fn with_secret_providers(self, secret_providers: SecretProviders) -> Self {
+        CreateFilterArgs {
+            secret_providers,
+            ..self
+        }
+    }
 }
@@ -14,6 +14,7 @@
  * limitations under the License.
  */
 
+use crate::config::FilterUpdateFailurePolicy;
 use crate::filters::{FilterChain, FilterRegistry};
 
 use std::sync::Arc;
@@ -33,10 +34,12 @@ pub struct FilterManager {
 }
 
 /// ListenerManagerArgs contains arguments when invoking the LDS resource manager.
+#[derive(Clone)]
 pub(crate) struct ListenerManagerArgs {
     pub filter_chain_updates_tx: mpsc::Sender<Arc<FilterChain>>,
     pub filter_registry: FilterRegistry,
     pub metrics_registry: Registry,
+    pub filter_update_failure: FilterUpdateFailurePolicy,
 }
 
 impl ListenerManagerArgs {
@@ -49,12 +52,25 @@ impl ListenerManagerArgs {
             filter_chain_updates_tx,
             filter_registry,
             metrics_registry,
+            filter_update_failure: FilterUpdateFailurePolicy::default(),
+        }
+    }
+
+    /// Sets what is done with filter chain updates in which a filter fails
+    /// to be created.
+    pub fn with_filter_update_failure(
+        self,
+        filter_update_failure: FilterUpdateFailurePolicy,
+    ) -> Self {
+        Self {
+            filter_update_failure,
+            ..self
         }
     }
 }
 
 impl FilterManager {
-    fn update(&mut self, filter_chain: Arc<FilterChain>) {
+    pub(crate) fn update(&mut self, filter_chain: Arc<FilterChain>) {
         self.filter_chain = filter_chain;
     }
 
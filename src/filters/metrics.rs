@@ -0,0 +1,244 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{
+    Histogram, IntCounter, IntCounterVec, IntGauge, Registry, Result as MetricsResult,
+};
+
+use crate::config::UpstreamEndpoints;
+use crate::metrics::{filter_opts, histogram_opts, CollectorExt};
+
+/// The buckets of the histogram of how many endpoints packets are routed to.
+const RETAINED_ENDPOINTS_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+
+/// Creates and registers the metrics of a filter, so that all filters' metrics
+/// are named and registered the same way.
+///
+/// Metrics are named `quilkin_filter_<FilterName>_<name>`, where `FilterName`
+/// is the last part of the filter's name, and counters always end in `_total`.
+/// A metric that is already registered, e.g. because the filter was created
+/// again by a filter chain update, is not registered twice.
+///
+/// ```
+/// # use prometheus::Registry;
+/// # use quilkin::filters::FilterMetrics;
+/// let registry = Registry::default();
+/// let metrics = FilterMetrics::new(&registry, "quilkin.extensions.filters.debug.v1beta1.Debug");
+/// let packets_dropped = metrics
+///     .counter("packets_dropped", "Total number of packets dropped.")
+///     .unwrap();
+/// packets_dropped.inc();
+/// assert_eq!(
+///     "quilkin_filter_Debug_packets_dropped_total",
+///     registry.gather()[0].get_name()
+/// );
+/// ```
+pub struct FilterMetrics<'a> {
+    registry: &'a Registry,
+    filter_name: &'a str,
+}
+
+impl<'a> FilterMetrics<'a> {
+    /// Returns a FilterMetrics that registers metrics for the filter named
+    /// `filter_name` with `registry`.
+    pub fn new(registry: &'a Registry, filter_name: &'a str) -> Self {
+        Self {
+            registry,
+            filter_name: filter_name.rsplit('.').next().unwrap_or(filter_name),
+        }
+    }
+
+    /// Registers a counter.
+    pub fn counter(&self, name: &str, description: &str) -> MetricsResult<IntCounter> {
+        IntCounter::with_opts(filter_opts(
+            &counter_name(name),
+            self.filter_name,
+            description,
+        ))?
+        .register_if_not_exists(self.registry)
+    }
+
+    /// Registers a counter with the labels in `label_names`.
+    pub fn counter_vec(
+        &self,
+        name: &str,
+        description: &str,
+        label_names: &[&str],
+    ) -> MetricsResult<IntCounterVec> {
+        IntCounterVec::new(
+            filter_opts(&counter_name(name), self.filter_name, description),
+            label_names,
+        )?
+        .register_if_not_exists(self.registry)
+    }
+
+    /// Registers a gauge.
+    pub fn gauge(&self, name: &str, description: &str) -> MetricsResult<IntGauge> {
+        IntGauge::with_opts(filter_opts(name, self.filter_name, description))?
+            .register_if_not_exists(self.registry)
+    }
+
+    /// Registers a histogram, with the default buckets if `buckets` is `None`.
+    pub fn histogram(
+        &self,
+        name: &str,
+        description: &str,
+        buckets: Option<Vec<f64>>,
+    ) -> MetricsResult<Histogram> {
+        Histogram::with_opts(histogram_opts(
+            name,
+            &format!("filter_{}", self.filter_name),
+            description,
+            buckets,
+        ))?
+        .register_if_not_exists(self.registry)
+    }
+}
+
+/// The metrics of a filter that routes packets to a subset of the endpoints,
+/// which show how well its routing works: packets routed to many endpoints,
+/// or to all of them as none matched, point at e.g. misconfigured tokens.
+///
+/// ```
+/// # use prometheus::Registry;
+/// # use quilkin::filters::{FilterMetrics, RoutingMetrics};
+/// let registry = Registry::default();
+/// let metrics = FilterMetrics::new(&registry, "quilkin.extensions.filters.debug.v1beta1.Debug");
+/// let routing = RoutingMetrics::new(&metrics).unwrap();
+/// routing.fell_through();
+/// assert_eq!(1, routing.packets_routed_to_all_endpoints.get());
+/// ```
+pub struct RoutingMetrics {
+    /// How many endpoints the filter's retain passes left packets to be
+    /// routed to.
+    pub retained_endpoints: Histogram,
+    /// The packets that couldn't be routed and were sent to all endpoints.
+    pub packets_routed_to_all_endpoints: IntCounter,
+}
+
+impl RoutingMetrics {
+    /// Registers the routing metrics of the filter that `metrics` registers
+    /// metrics for.
+    pub fn new(metrics: &FilterMetrics) -> MetricsResult<Self> {
+        Ok(Self {
+            retained_endpoints: metrics.histogram(
+                "retained_endpoints",
+                "Number of endpoints that packets were routed to by the filter.",
+                Some(RETAINED_ENDPOINTS_BUCKETS.into()),
+            )?,
+            packets_routed_to_all_endpoints: metrics.counter(
+                "packets_routed_to_all_endpoints",
+                "Total number of packets that couldn't be routed and were sent to all endpoints.",
+            )?,
+        })
+    }
+
+    /// Records that a retain pass left a packet to be routed to `endpoints`.
+    pub fn retained(&self, endpoints: &UpstreamEndpoints) {
+        self.retained_endpoints.observe(endpoints.size() as f64);
+    }
+
+    /// Records that a packet couldn't be routed and was sent to all
+    /// endpoints.
+    pub fn fell_through(&self) {
+        self.packets_routed_to_all_endpoints.inc();
+    }
+}
+
+/// Returns `name` with the `_total` suffix that counters are named with.
+fn counter_name(name: &str) -> String {
+    if name.ends_with("_total") {
+        name.into()
+    } else {
+        format!("{}_total", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::Registry;
+
+    use super::{FilterMetrics, RoutingMetrics};
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, UpstreamEndpoints};
+
+    #[test]
+    fn names() {
+        let registry = Registry::default();
+        let metrics = FilterMetrics::new(&registry, "quilkin.extensions.filters.test.v1.Test");
+        metrics.counter("dropped", "help").unwrap().inc();
+        metrics.counter("errors_total", "help").unwrap().inc();
+        metrics
+            .counter_vec("rejected", "help", &["reason"])
+            .unwrap()
+            .with_label_values(&["Invalid"])
+            .inc();
+        metrics.gauge("sessions", "help").unwrap().set(1);
+        metrics.histogram("duration_seconds", "help", None).unwrap();
+
+        let mut names = registry
+            .gather()
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            vec![
+                "quilkin_filter_Test_dropped_total",
+                "quilkin_filter_Test_duration_seconds",
+                "quilkin_filter_Test_errors_total",
+                "quilkin_filter_Test_rejected_total",
+                "quilkin_filter_Test_sessions",
+            ],
+            names
+        );
+    }
+
+    #[test]
+    fn routing() {
+        let registry = Registry::default();
+        let metrics = FilterMetrics::new(&registry, "Test");
+        let routing = RoutingMetrics::new(&metrics).unwrap();
+        let mut endpoints = UpstreamEndpoints::from(
+            Endpoints::new(vec![
+                Endpoint::from_address("127.0.0.1:8080".parse().unwrap()),
+                Endpoint::from_address("127.0.0.1:8081".parse().unwrap()),
+            ])
+            .unwrap(),
+        );
+        routing.retained(&endpoints);
+        let _ = endpoints.keep(0);
+        routing.retained(&endpoints);
+        routing.fell_through();
+
+        assert_eq!(2, routing.retained_endpoints.get_sample_count());
+        assert_eq!(3, routing.retained_endpoints.get_sample_sum() as u64);
+        assert_eq!(1, routing.packets_routed_to_all_endpoints.get());
+
+        // Filters created again, e.g. by a filter chain update, share them.
+        RoutingMetrics::new(&metrics).unwrap();
+        assert_eq!(2, registry.gather().len());
+    }
+
+    #[test]
+    fn register_twice() {
+        let registry = Registry::default();
+        let metrics = FilterMetrics::new(&registry, "Test");
+        metrics.counter("dropped", "help").unwrap();
+        metrics.counter("dropped", "help").unwrap();
+        assert_eq!(1, registry.gather().len());
+    }
+}
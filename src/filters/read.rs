@@ -14,11 +14,18 @@
  * limitations under the License.
  */
 
-use std::{any::Any, collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    any::Any,
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use crate::config::UpstreamEndpoints;
 #[cfg(doc)]
 use crate::filters::Filter;
+use crate::filters::Sample;
 
 /// Shared state between [`Filter`]s during processing for a single packet.
 type DynamicMetadata = HashMap<Arc<String>, Box<dyn Any + Send>>;
@@ -34,26 +41,42 @@ pub struct ReadContext {
     pub contents: Vec<u8>,
     /// Arbitrary values that can be passed from one filter to another.
     pub metadata: DynamicMetadata,
+    /// When the packet was received. This is the kernel's receive timestamp
+    /// where supported, so that the time between the packet arriving and it
+    /// being processed can be measured.
+    pub received_at: SystemTime,
+    /// How long the packet will be held before it is forwarded, as requested
+    /// by earlier filters in the chain.
+    pub delay: Duration,
+    /// The packet's sampling decision, shared by every filter in the chain.
+    pub sample: Sample,
 }
 
 impl ReadContext {
     /// Creates a new [`ReadContext`].
     pub fn new(endpoints: UpstreamEndpoints, from: SocketAddr, contents: Vec<u8>) -> Self {
         Self {
+            sample: Sample::new(from, &contents),
             endpoints,
             from,
             contents,
             metadata: HashMap::new(),
+            received_at: SystemTime::now(),
+            delay: Duration::from_secs(0),
         }
     }
 
     /// Creates a new [`ReadContext`] from a given [`ReadResponse`].
+    /// Any additional packets in the response are discarded.
     pub fn with_response(from: SocketAddr, response: ReadResponse) -> Self {
         Self {
+            sample: Sample::new(from, &response.contents),
             endpoints: response.endpoints,
             from,
             contents: response.contents,
             metadata: response.metadata,
+            received_at: SystemTime::now(),
+            delay: response.delay,
         }
     }
 }
@@ -64,6 +87,8 @@ impl From<ReadContext> for ReadResponse {
             endpoints: ctx.endpoints,
             contents: ctx.contents,
             metadata: ctx.metadata,
+            delay: ctx.delay,
+            additional: Vec::new(),
         }
     }
 }
@@ -78,6 +103,37 @@ impl From<ReadContext> for ReadResponse {
 ///       Some(ctx.into())
 ///   }
 /// ```
+///
+/// A filter can forward more than one packet for each packet it receives by
+/// adding responses to [`ReadResponse::additional`].
+///
+/// ```rust
+/// # use quilkin::filters::{ReadContext, ReadResponse};
+///   fn read(mut ctx: ReadContext) -> Option<ReadResponse> {
+///       // Split the packet in two.
+///       let second_half = ctx.contents.split_off(ctx.contents.len() / 2);
+///       let second = ReadContext::new(ctx.endpoints.clone(), ctx.from, second_half);
+///
+///       let mut response = ReadResponse::from(ctx);
+///       response.additional.push(second.into());
+///       Some(response)
+///   }
+/// ```
+///
+/// A filter can hold a packet back for a while instead of forwarding it
+/// right away by setting [`ReadResponse::delay`]. Packets are forwarded in
+/// the order their delays elapse, so a delayed packet may be forwarded after
+/// packets that were received later.
+///
+/// ```rust
+/// # use std::time::Duration;
+/// # use quilkin::filters::{ReadContext, ReadResponse};
+///   fn read(ctx: ReadContext) -> Option<ReadResponse> {
+///       let mut response = ReadResponse::from(ctx);
+///       response.delay += Duration::from_millis(20);
+///       Some(response)
+///   }
+/// ```
 #[non_exhaustive]
 pub struct ReadResponse {
     /// The upstream endpoints that the packet should be forwarded to.
@@ -86,4 +142,33 @@ pub struct ReadResponse {
     pub contents: Vec<u8>,
     /// Arbitrary values that can be passed from one filter to another
     pub metadata: DynamicMetadata,
+    /// How long to hold the packet before forwarding it.
+    pub delay: Duration,
+    /// Further packets to be forwarded after this one. Each is passed through
+    /// the rest of the filter chain independently of this packet.
+    pub additional: Vec<ReadResponse>,
+}
+
+impl ReadResponse {
+    /// Returns this response followed by all of its additional responses,
+    /// in the order that they should be forwarded.
+    pub fn into_packets(mut self) -> Vec<ReadResponse> {
+        let additional = std::mem::take(&mut self.additional);
+        let mut packets = vec![self];
+        for response in additional {
+            packets.extend(response.into_packets());
+        }
+        packets
+    }
+
+    /// Returns the first of `packets` with the rest as its additional
+    /// responses, or `None` if there are no packets.
+    pub(crate) fn from_packets(mut packets: Vec<ReadResponse>) -> Option<ReadResponse> {
+        if packets.is_empty() {
+            return None;
+        }
+        let mut response = packets.remove(0);
+        response.additional = packets;
+        Some(response)
+    }
 }
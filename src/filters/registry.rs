@@ -14,9 +14,34 @@
  * limitations under the License.
  */
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
+use serde::Serialize;
+
+use crate::config::{FilterBudget, FilterSchedule, FilterTimeout, RoutingCache, TrustedClients};
+use crate::filters::budget::{BudgetFilter, FilterBudgets};
+use crate::filters::bypass::BypassFilter;
+use crate::filters::routing_cache::RoutingCacheFilter;
+use crate::filters::schedule::{FilterSchedules, ScheduledFilter};
+use crate::filters::timeout::{FilterThreads, FilterTimeouts, TimeoutFilter};
 use crate::filters::{CreateFilterArgs, Error, Filter, FilterMap, FilterSet};
+use crate::secret::SecretProviders;
+
+#[cfg(doc)]
+use crate::filters::FilterFactory;
+
+/// Describes a filter that is available in a [`FilterRegistry`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FilterDescription {
+    /// The name of the filter, as used in configurations.
+    pub name: &'static str,
+    /// The names of previous versions of the filter that are still accepted.
+    pub deprecated_names: &'static [&'static str],
+    /// The schema of the filter's configuration, if it has a valid one. See
+    /// [`FilterFactory::config_schema`].
+    pub config_schema: Option<serde_yaml::Value>,
+}
 
 /// Registry of all [`Filter`]s that can be applied in the system.
 ///
@@ -26,29 +51,260 @@ use crate::filters::{CreateFilterArgs, Error, Filter, FilterMap, FilterSet};
 #[derive(Clone, Default)]
 pub struct FilterRegistry {
     registry: Arc<FilterMap>,
+    /// Maps deprecated filter names to the current name of the filter.
+    deprecated: Arc<HashMap<&'static str, &'static str>>,
+    /// The timeouts of filters, by their current name.
+    timeouts: Arc<FilterTimeouts>,
+    /// The threads that filters with a timeout run on, if any filter has
+    /// one.
+    filter_threads: Option<Arc<FilterThreads>>,
+    /// The budgets of filters, by their current name.
+    budgets: Arc<FilterBudgets>,
+    /// The windows during which filters are active, by their current name.
+    schedules: Arc<FilterSchedules>,
+    /// The caching of the routing of filters, which are referred to by
+    /// their current name, if enabled.
+    routing_cache: Option<Arc<RoutingCache>>,
+    /// The clients whose packets skip filters, which are referred to by
+    /// their current name, if any.
+    trusted_clients: Option<Arc<TrustedClients>>,
+    /// The providers that filters read secrets from.
+    secret_providers: SecretProviders,
 }
 
 impl FilterRegistry {
     /// Creates a new registry using the provided [`FilterSet`] as the set of
     /// available filters.
     pub fn new(factories: FilterSet) -> Self {
+        let registry: FilterMap = factories
+            .into_iter()
+            .map(|factory| (factory.name(), factory))
+            .collect();
+
+        let deprecated = registry
+            .values()
+            .flat_map(|factory| {
+                factory
+                    .deprecated_names()
+                    .iter()
+                    .map(move |deprecated_name| (*deprecated_name, factory.name()))
+            })
+            .filter(|(deprecated_name, _)| !registry.contains_key(deprecated_name))
+            .collect();
+
+        Self {
+            registry: Arc::new(registry),
+            deprecated: Arc::new(deprecated),
+            timeouts: Arc::default(),
+            filter_threads: None,
+            budgets: Arc::default(),
+            schedules: Arc::default(),
+            routing_cache: None,
+            trusted_clients: None,
+            secret_providers: SecretProviders::default(),
+        }
+    }
+
+    /// Consumes `self` and returns a new instance of [`Self`] where the
+    /// filters in `timeouts` are created with a timeout. The filters of every
+    /// chain built from the registry share the same threads.
+    pub(crate) fn with_timeouts(self, timeouts: Vec<FilterTimeout>) -> Self {
+        let filter_threads = if timeouts.is_empty() {
+            None
+        } else {
+            Some(Arc::new(FilterThreads::new()))
+        };
+        let timeouts = timeouts
+            .into_iter()
+            .map(|timeout| {
+                let name = self
+                    .replacement_for(&timeout.filter)
+                    .map(String::from)
+                    .unwrap_or_else(|| timeout.filter.clone());
+                (name, timeout)
+            })
+            .collect();
+        Self {
+            timeouts: Arc::new(timeouts),
+            filter_threads,
+            ..self
+        }
+    }
+
+    /// Consumes `self` and returns a new instance of [`Self`] where the
+    /// filters in `budgets` are created with a budget.
+    pub(crate) fn with_budgets(self, budgets: Vec<FilterBudget>) -> Self {
+        let budgets = budgets
+            .into_iter()
+            .map(|budget| {
+                let name = self
+                    .replacement_for(&budget.filter)
+                    .map(String::from)
+                    .unwrap_or_else(|| budget.filter.clone());
+                (name, budget)
+            })
+            .collect();
         Self {
-            registry: Arc::new(
-                factories
-                    .into_iter()
-                    .map(|factory| (factory.name(), factory))
-                    .collect(),
-            ),
+            budgets: Arc::new(budgets),
+            ..self
         }
     }
 
+    /// Consumes `self` and returns a new instance of [`Self`] where the
+    /// filters in `schedules` are only active during their windows.
+    pub(crate) fn with_schedules(self, schedules: Vec<FilterSchedule>) -> Self {
+        let mut by_name = FilterSchedules::new();
+        for schedule in schedules {
+            let name = self
+                .replacement_for(&schedule.filter)
+                .map(String::from)
+                .unwrap_or(schedule.filter);
+            by_name.entry(name).or_default().extend(schedule.active);
+        }
+        Self {
+            schedules: Arc::new(by_name),
+            ..self
+        }
+    }
+
+    /// Consumes `self` and returns a new instance of [`Self`] where the
+    /// routing of the filters in `routing_cache` is cached, if enabled.
+    pub(crate) fn with_routing_cache(self, routing_cache: Option<RoutingCache>) -> Self {
+        let routing_cache = routing_cache.map(|routing_cache| {
+            let filters = routing_cache
+                .filters
+                .iter()
+                .map(|filter| {
+                    self.replacement_for(filter)
+                        .map(String::from)
+                        .unwrap_or_else(|| filter.clone())
+                })
+                .collect();
+            Arc::new(RoutingCache {
+                filters,
+                ..routing_cache
+            })
+        });
+        Self {
+            routing_cache,
+            ..self
+        }
+    }
+
+    /// Consumes `self` and returns a new instance of [`Self`] where the
+    /// packets of `trusted_clients`, if any, skip the filters it lists.
+    pub(crate) fn with_trusted_clients(self, trusted_clients: Option<TrustedClients>) -> Self {
+        let trusted_clients = trusted_clients.map(|trusted_clients| {
+            let filters = trusted_clients
+                .filters
+                .iter()
+                .map(|filter| {
+                    self.replacement_for(filter)
+                        .map(String::from)
+                        .unwrap_or_else(|| filter.clone())
+                })
+                .collect();
+            Arc::new(TrustedClients {
+                filters,
+                ..trusted_clients
+            })
+        });
+        Self {
+            trusted_clients,
+            ..self
+        }
+    }
+
+    /// Consumes `self` and returns a new instance of [`Self`] where filters
+    /// read secrets from `secret_providers`.
+    pub(crate) fn with_secret_providers(self, secret_providers: SecretProviders) -> Self {
+        Self {
+            secret_providers,
+            ..self
+        }
+    }
+
+    /// Returns the providers that filters read secrets from.
+    pub(crate) fn secret_providers(&self) -> &SecretProviders {
+        &self.secret_providers
+    }
+
+    /// Returns a description of each filter in the registry, sorted by name,
+    /// so that tooling can check that a configuration only refers to filters
+    /// that are available.
+    pub fn filters(&self) -> Vec<FilterDescription> {
+        let mut filters = self
+            .registry
+            .values()
+            .map(|factory| FilterDescription {
+                name: factory.name(),
+                deprecated_names: factory.deprecated_names(),
+                config_schema: factory
+                    .config_schema()
+                    .and_then(|schema| serde_yaml::from_str(schema).ok()),
+            })
+            .collect::<Vec<_>>();
+        filters.sort_by_key(|filter| filter.name);
+        filters
+    }
+
+    /// Returns the current name of the filter if `key` is a deprecated name
+    /// of a registered filter, otherwise returns `None`.
+    pub fn replacement_for(&self, key: &str) -> Option<&'static str> {
+        self.deprecated.get(key).copied()
+    }
+
     /// Creates and returns a new dynamic instance of [`Filter`] for a given
     /// `key`. Errors if ther filter cannot be found, or if there is a
-    /// configuration issue.
+    /// configuration issue. Deprecated filter names are resolved to the
+    /// filter's current name.
     pub fn get(&self, key: &str, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
-        match self.registry.get(key).map(|p| p.create_filter(args)) {
-            None => Err(Error::NotFound(key.to_owned())),
-            Some(filter) => filter,
+        let key = self.replacement_for(key).unwrap_or(key);
+        let metrics_registry = args.metrics_registry.clone();
+        let args = args.with_secret_providers(self.secret_providers.clone());
+        let filter = match self.registry.get(key).map(|p| p.create_filter(args)) {
+            None => return Err(Error::NotFound(key.to_owned())),
+            Some(filter) => filter?,
+        };
+        let filter: Box<dyn Filter> = match self.budgets.get(key) {
+            Some(budget) => Box::new(BudgetFilter::new(key, filter, budget, &metrics_registry)?),
+            None => filter,
+        };
+        let filter: Box<dyn Filter> = match (self.timeouts.get(key), &self.filter_threads) {
+            (Some(timeout), Some(filter_threads)) => Box::new(TimeoutFilter::new(
+                key,
+                filter,
+                timeout,
+                filter_threads,
+                &metrics_registry,
+            )?),
+            _ => filter,
+        };
+        let filter: Box<dyn Filter> = match &self.routing_cache {
+            Some(routing_cache) if routing_cache.filters.iter().any(|name| name == key) => {
+                Box::new(RoutingCacheFilter::new(
+                    key,
+                    filter,
+                    routing_cache,
+                    &metrics_registry,
+                )?)
+            }
+            _ => filter,
+        };
+        let filter: Box<dyn Filter> = match self.schedules.get(key) {
+            Some(active) => Box::new(ScheduledFilter::new(filter, active.clone())),
+            None => filter,
+        };
+        match &self.trusted_clients {
+            Some(trusted_clients) if trusted_clients.filters.iter().any(|name| name == key) => {
+                Ok(Box::new(BypassFilter::new(
+                    key,
+                    filter,
+                    trusted_clients.addresses.clone(),
+                    &metrics_registry,
+                )?))
+            }
+            _ => Ok(filter),
         }
     }
 }
@@ -61,8 +317,10 @@ mod tests {
 
     use super::*;
     use crate::cluster::Endpoint;
-    use crate::config::Endpoints;
-    use crate::filters::{ReadContext, ReadResponse, WriteContext, WriteResponse};
+    use crate::config::{Endpoints, UpstreamEndpoints};
+    use crate::filters::{
+        DynFilterFactory, FilterFactory, ReadContext, ReadResponse, WriteContext, WriteResponse,
+    };
     use prometheus::Registry;
 
     struct TestFilter {}
@@ -77,6 +335,97 @@ mod tests {
         }
     }
 
+    struct VersionedFilterFactory {}
+
+    impl FilterFactory for VersionedFilterFactory {
+        fn name(&self) -> &'static str {
+            "quilkin.extensions.filters.versioned.v1beta1.Versioned"
+        }
+
+        fn deprecated_names(&self) -> &'static [&'static str] {
+            &["quilkin.extensions.filters.versioned.v1alpha1.Versioned"]
+        }
+
+        fn create_filter(&self, _: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+            Ok(Box::new(TestFilter {}))
+        }
+    }
+
+    #[test]
+    fn get_deprecated_name() {
+        let reg = FilterRegistry::new(FilterSet::default_with(
+            &logger(),
+            std::array::IntoIter::new([DynFilterFactory::from(Box::from(
+                VersionedFilterFactory {},
+            ))]),
+        ));
+
+        assert_eq!(
+            Some("quilkin.extensions.filters.versioned.v1beta1.Versioned"),
+            reg.replacement_for("quilkin.extensions.filters.versioned.v1alpha1.Versioned")
+        );
+        assert_eq!(
+            None,
+            reg.replacement_for("quilkin.extensions.filters.versioned.v1beta1.Versioned")
+        );
+
+        for name in &[
+            "quilkin.extensions.filters.versioned.v1alpha1.Versioned",
+            "quilkin.extensions.filters.versioned.v1beta1.Versioned",
+        ] {
+            assert!(reg
+                .get(name, CreateFilterArgs::fixed(Registry::default(), None))
+                .is_ok());
+        }
+
+        // Built-in filters remain available under their deprecated names.
+        assert_eq!(
+            Some("quilkin.extensions.filters.debug.v1beta1.Debug"),
+            reg.replacement_for("quilkin.extensions.filters.debug.v1alpha1.Debug")
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let reg = FilterRegistry::new(FilterSet::default_with(
+            &logger(),
+            std::array::IntoIter::new([DynFilterFactory::from(Box::from(
+                VersionedFilterFactory {},
+            ))]),
+        ));
+        let filters = reg.filters();
+
+        let mut names = filters.iter().map(|filter| filter.name).collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            filters.iter().map(|filter| filter.name).collect::<Vec<_>>()
+        );
+
+        let versioned = filters
+            .iter()
+            .find(|filter| filter.name == "quilkin.extensions.filters.versioned.v1beta1.Versioned")
+            .unwrap();
+        assert_eq!(
+            &["quilkin.extensions.filters.versioned.v1alpha1.Versioned"],
+            versioned.deprecated_names
+        );
+        assert_eq!(None, versioned.config_schema);
+
+        // Every built-in filter has a valid schema.
+        for filter in filters
+            .iter()
+            .filter(|filter| filter.name != versioned.name)
+        {
+            let schema = filter.config_schema.as_ref().unwrap();
+            assert!(
+                schema.get("properties").is_some(),
+                "{} has no properties in its schema",
+                filter.name
+            );
+        }
+    }
+
     #[test]
     fn insert_and_get() {
         let reg = new_registry(&logger());
@@ -121,4 +470,66 @@ mod tests {
             .write(WriteContext::new(&endpoint, addr, addr, vec![],))
             .is_some());
     }
+
+    #[test]
+    fn get_with_timeout() {
+        let reg = FilterRegistry::new(FilterSet::default_with(
+            &logger(),
+            std::array::IntoIter::new([DynFilterFactory::from(Box::from(
+                VersionedFilterFactory {},
+            ))]),
+        ))
+        .with_timeouts(vec![FilterTimeout {
+            filter: "quilkin.extensions.filters.versioned.v1alpha1.Versioned".into(),
+            timeout: std::time::Duration::from_secs(1),
+            on_timeout: crate::config::FilterTimeoutPolicy::Drop,
+        }]);
+
+        let has_timeout = |name: &str| {
+            let registry = Registry::default();
+            reg.get(name, CreateFilterArgs::fixed(registry.clone(), None)).unwrap();
+            registry
+                .gather()
+                .iter()
+                .any(|family| family.get_name() == "filter_timeouts_total")
+        };
+        // The timeout applies to the filter under its current name.
+        assert!(has_timeout("quilkin.extensions.filters.versioned.v1beta1.Versioned"));
+        assert!(!has_timeout("quilkin.extensions.filters.debug.v1beta1.Debug"));
+    }
+
+    #[test]
+    fn get_with_trusted_clients() {
+        let reg = FilterRegistry::new(FilterSet::default_with(
+            &logger(),
+            std::array::IntoIter::new([DynFilterFactory::from(Box::from(
+                VersionedFilterFactory {},
+            ))]),
+        ))
+        .with_trusted_clients(Some(TrustedClients {
+            addresses: vec!["10.0.0.0/8".parse().unwrap()],
+            filters: vec!["quilkin.extensions.filters.versioned.v1alpha1.Versioned".into()],
+        }));
+        let filter = reg
+            .get(
+                "quilkin.extensions.filters.versioned.v1beta1.Versioned",
+                CreateFilterArgs::fixed(Registry::default(), None),
+            )
+            .unwrap();
+
+        let endpoints = || {
+            let endpoint = Endpoint::from_address("127.0.0.1:8080".parse().unwrap());
+            UpstreamEndpoints::from(Endpoints::new(vec![endpoint]).unwrap())
+        };
+        // The packets of trusted clients skip the filter, which drops every
+        // packet, under its current name.
+        let trusted = "10.0.0.1:7000".parse().unwrap();
+        assert!(filter
+            .read(ReadContext::new(endpoints(), trusted, vec![]))
+            .is_some());
+        let untrusted = "192.0.2.1:7000".parse().unwrap();
+        assert!(filter
+            .read(ReadContext::new(endpoints(), untrusted, vec![]))
+            .is_none());
+    }
 }
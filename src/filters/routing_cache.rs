@@ -0,0 +1,268 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use prometheus::{IntCounter, Opts, Registry};
+
+use crate::config::{RoutingCache, UpstreamEndpoints};
+use crate::filters::prelude::*;
+use crate::metrics::CollectorExt;
+
+/// Wraps a filter that only routes packets, caching the endpoints that it
+/// routes each client to. A client's packets skip the filter for as long as
+/// the endpoints the filter is given are the same as when the route was
+/// cached, up to the cache's TTL. Since the filter chain, and so the cache,
+/// is replaced whenever the chain is updated, and updates to the cluster
+/// change the endpoints the filter is given, neither is served stale routes.
+pub(crate) struct RoutingCacheFilter {
+    filter: Box<dyn Filter>,
+    ttl: Duration,
+    max_clients: usize,
+    routes: Mutex<HashMap<SocketAddr, Route>>,
+    hits_total: IntCounter,
+    misses_total: IntCounter,
+}
+
+/// The endpoints that a client was last routed to.
+struct Route {
+    /// The addresses of the endpoints that the filter was given.
+    given: Vec<SocketAddr>,
+    /// The addresses of the endpoints that the filter kept.
+    kept: Vec<SocketAddr>,
+    cached_at: Instant,
+}
+
+impl RoutingCacheFilter {
+    pub(crate) fn new(
+        name: &str,
+        filter: Box<dyn Filter>,
+        config: &RoutingCache,
+        metrics_registry: &Registry,
+    ) -> Result<Self, Error> {
+        let counter = |name_suffix: &str, help: &str| {
+            IntCounter::with_opts(
+                Opts::new(format!("filter_routing_cache_{}", name_suffix), help)
+                    .const_label("filter", name),
+            )
+            .and_then(|counter| counter.register_if_not_exists(metrics_registry))
+        };
+        Ok(Self {
+            filter,
+            ttl: config.ttl,
+            max_clients: config.max_clients,
+            routes: Mutex::default(),
+            hits_total: counter(
+                "hits_total",
+                "Total number of packets that a given filter's cached routes were used for.",
+            )?,
+            misses_total: counter(
+                "misses_total",
+                "Total number of packets that a given filter routed as no route was cached.",
+            )?,
+        })
+    }
+
+    /// Returns the route cached for `from`, if it's still valid for a
+    /// packet given the endpoints `given`.
+    fn cached(
+        &self,
+        from: SocketAddr,
+        given: &[SocketAddr],
+        now: Instant,
+    ) -> Option<Vec<SocketAddr>> {
+        self.routes
+            .lock()
+            .get(&from)
+            .filter(|route| {
+                route.given == given && now.saturating_duration_since(route.cached_at) < self.ttl
+            })
+            .map(|route| route.kept.clone())
+    }
+
+    fn cache(&self, from: SocketAddr, route: Route) {
+        let mut routes = self.routes.lock();
+        if routes.len() >= self.max_clients && !routes.contains_key(&from) {
+            let now = route.cached_at;
+            let ttl = self.ttl;
+            routes.retain(|_, route| now.saturating_duration_since(route.cached_at) < ttl);
+            if routes.len() >= self.max_clients {
+                return;
+            }
+        }
+        routes.insert(from, route);
+    }
+}
+
+impl Filter for RoutingCacheFilter {
+    fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        let now = Instant::now();
+        let given = addresses(&ctx.endpoints);
+        if let Some(kept) = self.cached(ctx.from, &given, now) {
+            if !ctx
+                .endpoints
+                .retain(|endpoint| kept.contains(&endpoint.address))
+                .is_none()
+            {
+                self.hits_total.inc();
+                return Some(ctx.into());
+            }
+        }
+
+        self.misses_total.inc();
+        let from = ctx.from;
+        let response = self.filter.read(ctx)?;
+        // A filter that produces more than one packet isn't only routing.
+        if response.additional.is_empty() {
+            let kept = addresses(&response.endpoints);
+            self.cache(
+                from,
+                Route {
+                    given,
+                    kept,
+                    cached_at: now,
+                },
+            );
+        }
+        Some(response)
+    }
+
+    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        self.filter.write(ctx)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.filter.is_read_only()
+    }
+
+    fn export_state(&self) -> Option<serde_json::Value> {
+        self.filter.export_state()
+    }
+
+    fn import_state(&self, state: serde_json::Value) -> Result<(), Error> {
+        self.filter.import_state(state)
+    }
+
+    fn memory_usage(&self) -> Option<usize> {
+        self.filter.memory_usage()
+    }
+}
+
+fn addresses(endpoints: &UpstreamEndpoints) -> Vec<SocketAddr> {
+    endpoints.iter().map(|endpoint| endpoint.address).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+
+    use super::RoutingCacheFilter;
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, RoutingCache};
+    use crate::filters::prelude::*;
+
+    /// Routes every packet to the first endpoint it's given, counting the
+    /// packets it routes.
+    struct First(Arc<AtomicUsize>);
+
+    impl Filter for First {
+        fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            ctx.endpoints.keep(0).ok()?;
+            Some(ctx.into())
+        }
+    }
+
+    fn read(filter: &RoutingCacheFilter, from: &str, endpoints: &[&str]) -> Vec<SocketAddr> {
+        let endpoints = Endpoints::new(
+            endpoints
+                .iter()
+                .map(|address| Endpoint::from_address(address.parse().unwrap()))
+                .collect(),
+        )
+        .unwrap();
+        filter
+            .read(ReadContext::new(
+                endpoints.into(),
+                from.parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap()
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.address)
+            .collect()
+    }
+
+    fn routing_cache_filter(routed: Arc<AtomicUsize>, ttl: Duration) -> RoutingCacheFilter {
+        RoutingCacheFilter::new(
+            "First",
+            Box::new(First(routed)),
+            &RoutingCache {
+                filters: vec!["First".into()],
+                ttl,
+                max_clients: 1,
+            },
+            &Registry::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn read_cached() {
+        let routed = Arc::new(AtomicUsize::new(0));
+        let filter = routing_cache_filter(routed.clone(), Duration::from_secs(60));
+        let first: SocketAddr = "127.0.0.1:81".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:82".parse().unwrap();
+
+        let endpoints = ["127.0.0.1:81", "127.0.0.1:82"];
+        assert_eq!(vec![first], read(&filter, "127.0.0.1:7000", &endpoints));
+        assert_eq!(vec![first], read(&filter, "127.0.0.1:7000", &endpoints));
+        assert_eq!(1, routed.load(Ordering::SeqCst));
+        assert_eq!(1, filter.hits_total.get());
+        assert_eq!(1, filter.misses_total.get());
+
+        // The route is invalidated once the endpoints change.
+        let endpoints = ["127.0.0.1:82", "127.0.0.1:81"];
+        assert_eq!(vec![second], read(&filter, "127.0.0.1:7000", &endpoints));
+        assert_eq!(2, routed.load(Ordering::SeqCst));
+
+        // Routes of clients beyond the limit aren't cached.
+        read(&filter, "127.0.0.1:7001", &endpoints);
+        read(&filter, "127.0.0.1:7001", &endpoints);
+        assert_eq!(4, routed.load(Ordering::SeqCst));
+        assert_eq!(1, filter.hits_total.get());
+    }
+
+    #[test]
+    fn read_expired() {
+        let routed = Arc::new(AtomicUsize::new(0));
+        let filter = routing_cache_filter(routed.clone(), Duration::from_secs(0));
+        let endpoints = ["127.0.0.1:81", "127.0.0.1:82"];
+        read(&filter, "127.0.0.1:7000", &endpoints);
+        read(&filter, "127.0.0.1:7000", &endpoints);
+        assert_eq!(2, routed.load(Ordering::SeqCst));
+        assert_eq!(0, filter.hits_total.get());
+    }
+}
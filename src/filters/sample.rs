@@ -0,0 +1,137 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(doc)]
+use crate::filters::{ReadContext, WriteContext};
+
+/// The seed that samples are derived from, picked at random the first time
+/// a sample is taken so that clients can't craft packets that are never
+/// sampled.
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+/// A per-packet value that filters compare against their sampling rates, so
+/// that filters which sample packets, e.g to log them, all sample the same
+/// packets rather than each picking their own. A filter sampling at a lower
+/// rate samples a subset of the packets sampled at a higher rate.
+///
+/// The sample of a packet is derived from the address of its client and its
+/// contents, along with a seed picked when the proxy starts. It is taken
+/// when the packet's [`ReadContext`] or [`WriteContext`] is created, and
+/// stays the same through the rest of the filter chain, even if filters
+/// change the packet.
+///
+/// ```rust
+/// # use quilkin::filters::{ReadContext, ReadResponse};
+///   fn read(ctx: ReadContext) -> Option<ReadResponse> {
+///       if ctx.sample.is_sampled(0.01) {
+///           println!("{:?}", ctx.contents);
+///       }
+///       Some(ctx.into())
+///   }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Sample(f64);
+
+impl Sample {
+    /// Returns the sample of a packet of the session with the client at
+    /// `client`.
+    pub fn new(client: SocketAddr, contents: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        seed().hash(&mut hasher);
+        client.hash(&mut hasher);
+        contents.hash(&mut hasher);
+        Self::from_bits(hasher.finish())
+    }
+
+    /// Returns a sample with the provided value, which is clamped to
+    /// `[0, 1)`.
+    pub fn from_value(value: f64) -> Self {
+        Self(value.max(0.0).min(1.0 - f64::EPSILON))
+    }
+
+    /// Returns the sample's value, which is within `[0, 1)`.
+    pub fn value(&self) -> f64 {
+        self.0
+    }
+
+    /// Returns whether the packet is sampled at `rate`, the fraction of
+    /// packets that should be sampled.
+    pub fn is_sampled(&self, rate: f64) -> bool {
+        self.0 < rate
+    }
+
+    /// Maps a hash evenly onto `[0, 1)`.
+    fn from_bits(bits: u64) -> Self {
+        // An f64 has 53 bits of precision.
+        Self((bits >> 11) as f64 / (1u64 << 53) as f64)
+    }
+}
+
+/// Returns the seed, picking it if it hasn't been yet.
+fn seed() -> u64 {
+    let seed = SEED.load(Ordering::Relaxed);
+    if seed != 0 {
+        return seed;
+    }
+    // Zero marks the seed as unset, so it is never picked.
+    let picked = rand::random::<u64>() | 1;
+    match SEED.compare_exchange(0, picked, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => picked,
+        Err(seed) => seed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::Sample;
+
+    #[test]
+    fn consistent() {
+        let client: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        assert_eq!(Sample::new(client, b"hello"), Sample::new(client, b"hello"));
+
+        // Different packets of a session, and the same packet of different
+        // sessions, are sampled independently.
+        let samples = (0..1000u32)
+            .map(|i| Sample::new(client, &i.to_be_bytes()))
+            .collect::<Vec<_>>();
+        let sampled = samples.iter().filter(|s| s.is_sampled(0.5)).count();
+        assert!((400..600).contains(&sampled), "sampled {}", sampled);
+        assert_ne!(Sample::new(client, b"hello"), Sample::new(other, b"hello"));
+    }
+
+    #[test]
+    fn is_sampled() {
+        let sample = Sample::from_value(0.25);
+        assert_eq!(0.25, sample.value());
+        assert!(sample.is_sampled(1.0));
+        assert!(sample.is_sampled(0.5));
+        assert!(!sample.is_sampled(0.25));
+        assert!(!sample.is_sampled(0.0));
+
+        // Every sample is sampled at a rate of 1, and none at a rate of 0.
+        assert!(Sample::from_value(2.0).is_sampled(1.0));
+        assert!(!Sample::from_value(-1.0).is_sampled(0.0));
+    }
+}
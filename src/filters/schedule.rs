@@ -0,0 +1,154 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use crate::config::ActivationWindow;
+use crate::filters::prelude::*;
+
+/// The windows during which filters are active, by filter name.
+pub(crate) type FilterSchedules = HashMap<String, Vec<ActivationWindow>>;
+
+/// Wraps a filter so that it only processes packets during its activation
+/// windows. Outside of them, packets pass through unchanged.
+pub(crate) struct ScheduledFilter {
+    filter: Box<dyn Filter>,
+    active: Vec<ActivationWindow>,
+    /// Reads the proxy's clock.
+    now: fn() -> SystemTime,
+}
+
+impl ScheduledFilter {
+    pub(crate) fn new(filter: Box<dyn Filter>, active: Vec<ActivationWindow>) -> Self {
+        Self {
+            filter,
+            active,
+            now: SystemTime::now,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        ActivationWindow::any_contains(&self.active, (self.now)())
+    }
+}
+
+impl Filter for ScheduledFilter {
+    fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+        if self.is_active() {
+            self.filter.read(ctx)
+        } else {
+            Some(ctx.into())
+        }
+    }
+
+    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        if self.is_active() {
+            self.filter.write(ctx)
+        } else {
+            Some(ctx.into())
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.filter.is_read_only()
+    }
+
+    fn export_state(&self) -> Option<serde_json::Value> {
+        self.filter.export_state()
+    }
+
+    fn import_state(&self, state: serde_json::Value) -> Result<(), Error> {
+        self.filter.import_state(state)
+    }
+
+    fn memory_usage(&self) -> Option<usize> {
+        self.filter.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::ScheduledFilter;
+    use crate::cluster::Endpoint;
+    use crate::config::{ActivationWindow, Endpoints, TimeOfDay};
+    use crate::filters::prelude::*;
+
+    /// Drops every packet.
+    struct Reject;
+
+    impl Filter for Reject {
+        fn read(&self, _: ReadContext) -> Option<ReadResponse> {
+            drop_packet("Maintenance")
+        }
+
+        fn write(&self, _: WriteContext) -> Option<WriteResponse> {
+            drop_packet("Maintenance")
+        }
+    }
+
+    /// Rejects packets from 02:00 to 03:00.
+    fn maintenance(now: fn() -> SystemTime) -> ScheduledFilter {
+        ScheduledFilter {
+            now,
+            ..ScheduledFilter::new(
+                Box::new(Reject),
+                vec![ActivationWindow {
+                    start: TimeOfDay::new(2, 0, 0).unwrap(),
+                    end: TimeOfDay::new(3, 0, 0).unwrap(),
+                }],
+            )
+        }
+    }
+
+    fn read(filter: &ScheduledFilter) -> Option<ReadResponse> {
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:80".parse().unwrap(),
+        )])
+        .unwrap();
+        filter.read(ReadContext::new(
+            endpoints.into(),
+            "127.0.0.1:70".parse().unwrap(),
+            b"hello".to_vec(),
+        ))
+    }
+
+    fn write(filter: &ScheduledFilter) -> Option<WriteResponse> {
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        filter.write(WriteContext::new(
+            &endpoint,
+            endpoint.address,
+            "127.0.0.1:70".parse().unwrap(),
+            b"hello".to_vec(),
+        ))
+    }
+
+    #[test]
+    fn active() {
+        let filter = maintenance(|| UNIX_EPOCH + Duration::from_secs(2 * 3600 + 30 * 60));
+        assert!(read(&filter).is_none());
+        assert!(write(&filter).is_none());
+    }
+
+    #[test]
+    fn inactive() {
+        let filter = maintenance(|| UNIX_EPOCH + Duration::from_secs(3 * 3600));
+        assert_eq!(b"hello".to_vec(), read(&filter).unwrap().contents);
+        assert_eq!(b"hello".to_vec(), write(&filter).unwrap().contents);
+    }
+}
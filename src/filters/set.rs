@@ -42,6 +42,15 @@ impl FilterSet {
     /// - [`CaptureBytes`][extensions::CaptureBytesFactory]
     /// - [`TokenRouter`][extensions::TokenRouterFactory]
     /// - [`Compress`][extensions::CompressFactory]
+    /// - [`Handoff`][extensions::HandoffFactory]
+    /// - [`JitterBuffer`][extensions::JitterBufferFactory]
+    /// - [`Prioritize`][extensions::PrioritizeFactory]
+    /// - [`TokenQuota`][extensions::TokenQuotaFactory]
+    /// - [`Fragment`][extensions::FragmentFactory]
+    /// - [`Shadow`][extensions::ShadowFactory]
+    /// - [`StaticMetadata`][extensions::StaticMetadataFactory]
+    /// - [`Transform`][extensions::TransformFactory]
+    /// - [`NetworkSimulation`][extensions::NetworkSimulationFactory]
     pub fn default(base: &Logger) -> Self {
         Self::default_with(base, Option::into_iter(None))
     }
@@ -64,6 +73,15 @@ impl FilterSet {
                 Box::from(extensions::CaptureBytesFactory::new(base)),
                 Box::from(extensions::TokenRouterFactory::new(base)),
                 Box::from(extensions::CompressFactory::new(base)),
+                Box::from(extensions::HandoffFactory::new(base)),
+                Box::from(extensions::JitterBufferFactory::default()),
+                Box::from(extensions::PrioritizeFactory::default()),
+                Box::from(extensions::TokenQuotaFactory::default()),
+                Box::from(extensions::FragmentFactory::default()),
+                Box::from(extensions::ShadowFactory::default()),
+                Box::from(extensions::StaticMetadataFactory::default()),
+                Box::from(extensions::TransformFactory::default()),
+                Box::from(extensions::NetworkSimulationFactory::default()),
             ])
             .chain(filters),
         )
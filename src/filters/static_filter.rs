@@ -0,0 +1,167 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::Registry;
+use serde::Serialize;
+
+use crate::filters::{ConfigType, CreateFilterArgs, DynFilterFactory, Error, Filter, FilterFactory};
+use crate::secret::SecretProviders;
+
+#[cfg(doc)]
+use crate::filters::FilterRegistry;
+
+/// A filter created by a given [`FilterFactory`] from a typed configuration,
+/// rather than looked up by name in a [`FilterRegistry`] and configured
+/// from YAML.
+///
+/// Usually built with [`filter_chain!`](crate::filter_chain).
+pub struct StaticFilter {
+    factory: DynFilterFactory,
+    config: Result<Option<serde_yaml::Value>, Error>,
+}
+
+impl StaticFilter {
+    /// Creates a new [`StaticFilter`] without a configuration.
+    pub fn new(factory: impl FilterFactory + 'static) -> Self {
+        Self {
+            factory: Box::new(factory),
+            config: Ok(None),
+        }
+    }
+
+    /// Consumes `self` and returns a new instance of [`Self`] configured
+    /// with `config`, which is passed to the factory the same way as a
+    /// static configuration.
+    pub fn with_config(self, config: impl Serialize) -> Self {
+        Self {
+            config: serde_yaml::to_value(config)
+                .map(Some)
+                .map_err(|err| Error::DeserializeFailed(err.to_string())),
+            ..self
+        }
+    }
+
+    /// Returns the name of the filter.
+    pub fn name(&self) -> &'static str {
+        self.factory.name()
+    }
+
+    /// Creates the filter, registering its metrics with `metrics_registry`.
+    pub(crate) fn create(self, metrics_registry: &Registry) -> Result<Box<dyn Filter>, Error> {
+        let config = self.config?;
+        self.factory.create_filter(CreateFilterArgs {
+            config: config.as_ref().map(ConfigType::Static),
+            metrics_registry: metrics_registry.clone(),
+            secret_providers: SecretProviders::default(),
+        })
+    }
+}
+
+/// Builds a list of [`StaticFilter`]s from filter factories, each followed
+/// by `=>` and its configuration if it has one. A configuration can be any
+/// value that serializes to the filter's configuration, such as a struct
+/// defined by the filter, so it is type checked at compile time.
+///
+/// The list can be passed to [`Builder::with_filter_chain`] to run the proxy
+/// with these filters rather than the ones in its static configuration.
+///
+/// ```rust
+/// use quilkin::filters::extensions::DebugFactory;
+///
+/// #[derive(serde::Serialize)]
+/// struct DebugConfig {
+///     id: String,
+/// }
+///
+/// let log = quilkin::proxy::logger();
+/// let filters = quilkin::filter_chain![
+///     DebugFactory::new(&log) => DebugConfig { id: "before".into() },
+///     DebugFactory::new(&log),
+/// ];
+/// assert_eq!(2, filters.len());
+/// ```
+///
+/// [`Builder::with_filter_chain`]: crate::proxy::Builder::with_filter_chain
+#[macro_export]
+macro_rules! filter_chain {
+    ($($factory:expr $(=> $config:expr)?),* $(,)?) => {
+        vec![$($crate::filters::StaticFilter::new($factory)$(.with_config($config))?),*]
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use crate::filters::extensions::ConcatBytesFactory;
+    use crate::filters::prelude::*;
+    use crate::test_utils::TestFilterFactory;
+
+    #[derive(Serialize)]
+    struct ConcatBytesConfig {
+        on_read: &'static str,
+        bytes: &'static str,
+    }
+
+    #[test]
+    fn create_filters() {
+        let registry = prometheus::Registry::default();
+        let filters = crate::filter_chain![
+            TestFilterFactory {},
+            ConcatBytesFactory::default() => ConcatBytesConfig {
+                on_read: "APPEND",
+                // "!"
+                bytes: "IQ==",
+            },
+        ];
+        assert_eq!(
+            vec![
+                "TestFilter",
+                "quilkin.extensions.filters.concatenate_bytes.v1beta1.ConcatenateBytes"
+            ],
+            filters.iter().map(|filter| filter.name()).collect::<Vec<_>>()
+        );
+
+        let filters = filters
+            .into_iter()
+            .map(|filter| filter.create(&registry).unwrap())
+            .collect::<Vec<_>>();
+        let response = filters[1]
+            .read(ReadContext::new(
+                crate::config::Endpoints::new(vec![crate::cluster::Endpoint::from_address(
+                    "127.0.0.1:80".parse().unwrap(),
+                )])
+                .unwrap()
+                .into(),
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(b"hello!".to_vec(), response.contents);
+    }
+
+    #[test]
+    fn missing_config() {
+        let registry = prometheus::Registry::default();
+        let mut filters = crate::filter_chain![ConcatBytesFactory::default()];
+        assert_eq!(
+            Error::MissingConfig(
+                "quilkin.extensions.filters.concatenate_bytes.v1beta1.ConcatenateBytes"
+            ),
+            filters.remove(0).create(&registry).err().unwrap()
+        );
+    }
+}
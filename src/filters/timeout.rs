@@ -0,0 +1,422 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use prometheus::{IntCounter, Opts, Registry};
+use tokio::sync::mpsc;
+
+use crate::config::{FilterTimeout, FilterTimeoutPolicy};
+use crate::filters::{drop_reason, prelude::*};
+use crate::metrics::CollectorExt;
+
+/// The reason code reported for packets dropped due to a timeout.
+const TIMEOUT_REASON: &str = "Timeout";
+
+/// The maximum number of packets waiting for the filter threads. Once
+/// reached, e.g because filters are stuck, packets time out right away.
+const QUEUE_SIZE: usize = 1024;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The timeouts of filters, by filter name.
+pub(crate) type FilterTimeouts = HashMap<String, FilterTimeout>;
+
+/// The threads that filters with a timeout run on, which are shared by
+/// every such filter in every filter chain built from a registry.
+pub(crate) struct FilterThreads {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl FilterThreads {
+    /// Spawns a thread per CPU. The threads exit once the FilterThreads and
+    /// every filter running on them are dropped, unless they are stuck in a
+    /// filter.
+    pub(crate) fn new() -> Self {
+        let (jobs, jobs_rx) = mpsc::channel::<Job>(QUEUE_SIZE);
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+        for id in 0..num_cpus::get() {
+            let jobs_rx = jobs_rx.clone();
+            std::thread::Builder::new()
+                .name(format!("quilkin-filter-timeout-{}", id))
+                .spawn(move || loop {
+                    let job = match jobs_rx.lock().blocking_recv() {
+                        Some(job) => job,
+                        None => return,
+                    };
+                    job();
+                })
+                .expect("failed to spawn filter thread");
+        }
+        Self { jobs }
+    }
+}
+
+/// Wraps a filter so that it runs on the filter threads, and packets it
+/// doesn't process within `timeout` are dropped or passed through, rather
+/// than holding up the packets behind them.
+pub(crate) struct TimeoutFilter {
+    filter: Arc<dyn Filter>,
+    timeout: Duration,
+    on_timeout: FilterTimeoutPolicy,
+    jobs: mpsc::Sender<Job>,
+    timeouts_total: IntCounter,
+}
+
+impl TimeoutFilter {
+    /// Returns a new TimeoutFilter that runs `filter` on `threads`.
+    pub(crate) fn new(
+        name: &str,
+        filter: Box<dyn Filter>,
+        config: &FilterTimeout,
+        threads: &FilterThreads,
+        metrics_registry: &Registry,
+    ) -> Result<Self, Error> {
+        let timeouts_total = IntCounter::with_opts(
+            Opts::new(
+                "filter_timeouts_total",
+                "Total number of packets that a given filter didn't process within its timeout.",
+            )
+            .const_label("filter", name),
+        )?
+        .register_if_not_exists(metrics_registry)?;
+
+        Ok(Self {
+            filter: filter.into(),
+            timeout: config.timeout,
+            on_timeout: config.on_timeout,
+            jobs: threads.jobs.clone(),
+            timeouts_total,
+        })
+    }
+
+    /// Runs `f` on the filter's threads, returning its result if it
+    /// completes within the timeout. Otherwise, returns `None` and counts the
+    /// timeout.
+    fn run<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&dyn Filter) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_tx, result_rx) = std_mpsc::channel();
+        let filter = self.filter.clone();
+        let job: Job = Box::new(move || {
+            // The caller may have stopped waiting for the result.
+            let _ = result_tx.send(f(filter.as_ref()));
+        });
+        let result = match self.jobs.try_send(job) {
+            Ok(()) => result_rx.recv_timeout(self.timeout).ok(),
+            Err(_) => None,
+        };
+        if result.is_none() {
+            self.timeouts_total.inc();
+        }
+        result
+    }
+
+    /// Returns the response for a packet that timed out, given a copy of
+    /// the packet as it was passed to the filter.
+    fn timed_out<T>(&self, unchanged: Option<T>) -> Option<T> {
+        match self.on_timeout {
+            FilterTimeoutPolicy::Drop => drop_packet(TIMEOUT_REASON),
+            FilterTimeoutPolicy::PassThrough => unchanged,
+        }
+    }
+}
+
+impl Filter for TimeoutFilter {
+    fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+        // Metadata can't be copied, so a packet that is passed through
+        // loses the metadata set by earlier filters.
+        let unchanged = match self.on_timeout {
+            FilterTimeoutPolicy::Drop => None,
+            FilterTimeoutPolicy::PassThrough => {
+                let mut unchanged =
+                    ReadContext::new(ctx.endpoints.clone(), ctx.from, ctx.contents.clone());
+                unchanged.received_at = ctx.received_at;
+                unchanged.delay = ctx.delay;
+                unchanged.sample = ctx.sample;
+                Some(unchanged.into())
+            }
+        };
+
+        let result = self.run(move |filter| {
+            drop_reason::clear();
+            let response = filter.read(ctx);
+            let reason = drop_reason::take();
+            (response, reason)
+        });
+        match result {
+            Some((Some(response), _)) => Some(response),
+            Some((None, reason)) => drop_packet(reason),
+            None => self.timed_out(unchanged),
+        }
+    }
+
+    fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        let unchanged = match self.on_timeout {
+            FilterTimeoutPolicy::Drop => None,
+            FilterTimeoutPolicy::PassThrough => {
+                let mut unchanged =
+                    WriteContext::new(ctx.endpoint, ctx.from, ctx.to, ctx.contents.clone());
+                unchanged.delay = ctx.delay;
+                unchanged.priority = ctx.priority;
+                unchanged.sample = ctx.sample;
+                Some(unchanged.into())
+            }
+        };
+
+        // The filter's threads can't borrow the endpoint or the downstreams,
+        // so they are given copies of them.
+        let endpoint = ctx.endpoint.clone();
+        let downstreams = ctx.downstreams.to_vec();
+        let (from, to, contents, metadata, delay, priority, sample) = (
+            ctx.from,
+            ctx.to,
+            ctx.contents,
+            ctx.metadata,
+            ctx.delay,
+            ctx.priority,
+            ctx.sample,
+        );
+        let result = self.run(move |filter| {
+            drop_reason::clear();
+            let response = filter.write(WriteContext {
+                endpoint: &endpoint,
+                from,
+                to,
+                downstreams: &downstreams,
+                contents,
+                metadata,
+                delay,
+                priority,
+                sample,
+            });
+            let reason = drop_reason::take();
+            (response, reason)
+        });
+        match result {
+            Some((Some(response), _)) => Some(response),
+            Some((None, reason)) => drop_packet(reason),
+            None => self.timed_out(unchanged),
+        }
+    }
+
+    /// Packets that time out are dropped unless they are passed through.
+    fn is_read_only(&self) -> bool {
+        self.on_timeout == FilterTimeoutPolicy::PassThrough && self.filter.is_read_only()
+    }
+
+    fn export_state(&self) -> Option<serde_json::Value> {
+        self.filter.export_state()
+    }
+
+    fn import_state(&self, state: serde_json::Value) -> Result<(), Error> {
+        self.filter.import_state(state)
+    }
+
+    fn memory_usage(&self) -> Option<usize> {
+        self.filter.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+
+    use super::{FilterThreads, TimeoutFilter, TIMEOUT_REASON};
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, FilterTimeout, FilterTimeoutPolicy};
+    use crate::filters::{drop_reason, prelude::*};
+
+    /// Blocks while `stuck` is set, otherwise drops packets containing
+    /// `drop` and passes the rest through with `!` appended.
+    struct StuckFilter {
+        stuck: Arc<AtomicBool>,
+    }
+
+    impl StuckFilter {
+        fn wait(&self) {
+            while self.stuck.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    impl Filter for StuckFilter {
+        fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+            self.wait();
+            if ctx.contents == b"drop" {
+                return drop_packet("Dropped");
+            }
+            ctx.contents.push(b'!');
+            Some(ctx.into())
+        }
+
+        fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+            self.wait();
+            ctx.contents.push(b'!');
+            Some(ctx.into())
+        }
+    }
+
+    /// Only observes packets.
+    struct ReadOnlyFilter;
+
+    impl Filter for ReadOnlyFilter {
+        fn is_read_only(&self) -> bool {
+            true
+        }
+    }
+
+    fn timeout_filter(on_timeout: FilterTimeoutPolicy) -> (TimeoutFilter, Arc<AtomicBool>) {
+        let stuck = Arc::new(AtomicBool::new(false));
+        let filter = TimeoutFilter::new(
+            "StuckFilter",
+            Box::new(StuckFilter {
+                stuck: stuck.clone(),
+            }),
+            &FilterTimeout {
+                filter: "StuckFilter".into(),
+                timeout: Duration::from_millis(50),
+                on_timeout,
+            },
+            &FilterThreads::new(),
+            &Registry::default(),
+        )
+        .unwrap();
+        (filter, stuck)
+    }
+
+    fn read(filter: &TimeoutFilter, contents: &[u8]) -> Option<Vec<u8>> {
+        let endpoints = Endpoints::new(vec![Endpoint::from_address(
+            "127.0.0.1:80".parse().unwrap(),
+        )])
+        .unwrap();
+        filter
+            .read(ReadContext::new(
+                endpoints.into(),
+                "127.0.0.1:70".parse().unwrap(),
+                contents.to_vec(),
+            ))
+            .map(|response| response.contents)
+    }
+
+    #[test]
+    fn read_drop_on_timeout() {
+        let (filter, stuck) = timeout_filter(FilterTimeoutPolicy::Drop);
+
+        assert_eq!(Some(b"hello!".to_vec()), read(&filter, b"hello"));
+        assert_eq!(None, read(&filter, b"drop"));
+        assert_eq!("Dropped", drop_reason::take());
+
+        stuck.store(true, Ordering::SeqCst);
+        assert_eq!(None, read(&filter, b"hello"));
+        assert_eq!(TIMEOUT_REASON, drop_reason::take());
+        assert_eq!(1, filter.timeouts_total.get());
+
+        stuck.store(false, Ordering::SeqCst);
+        assert_eq!(Some(b"hello!".to_vec()), read(&filter, b"hello"));
+    }
+
+    #[test]
+    fn pass_through_on_timeout() {
+        let (filter, stuck) = timeout_filter(FilterTimeoutPolicy::PassThrough);
+        stuck.store(true, Ordering::SeqCst);
+
+        assert_eq!(Some(b"hello".to_vec()), read(&filter, b"hello"));
+
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        let response = filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(b"hello".to_vec(), response.contents);
+        assert_eq!(2, filter.timeouts_total.get());
+
+        stuck.store(false, Ordering::SeqCst);
+        let response = filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(b"hello!".to_vec(), response.contents);
+    }
+
+    #[test]
+    fn share_threads() {
+        let threads = FilterThreads::new();
+        let filters = (0..2)
+            .map(|_| {
+                TimeoutFilter::new(
+                    "StuckFilter",
+                    Box::new(StuckFilter {
+                        stuck: Arc::new(AtomicBool::new(false)),
+                    }),
+                    &FilterTimeout {
+                        filter: "StuckFilter".into(),
+                        timeout: Duration::from_millis(50),
+                        on_timeout: FilterTimeoutPolicy::Drop,
+                    },
+                    &threads,
+                    &Registry::default(),
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for filter in &filters {
+            assert_eq!(Some(b"hello!".to_vec()), read(filter, b"hello"));
+        }
+    }
+
+    #[test]
+    fn read_only() {
+        let read_only_filter = |on_timeout| {
+            TimeoutFilter::new(
+                "ReadOnlyFilter",
+                Box::new(ReadOnlyFilter),
+                &FilterTimeout {
+                    filter: "ReadOnlyFilter".into(),
+                    timeout: Duration::from_millis(50),
+                    on_timeout,
+                },
+                &FilterThreads::new(),
+                &Registry::default(),
+            )
+            .unwrap()
+        };
+        // Packets that time out would be dropped.
+        assert!(!read_only_filter(FilterTimeoutPolicy::Drop).is_read_only());
+        assert!(read_only_filter(FilterTimeoutPolicy::PassThrough).is_read_only());
+
+        let (filter, _) = timeout_filter(FilterTimeoutPolicy::PassThrough);
+        assert!(!filter.is_read_only());
+    }
+}
@@ -14,9 +14,12 @@
  * limitations under the License.
  */
 
-use std::{any::Any, collections::HashMap, net::SocketAddr};
+use std::{any::Any, collections::HashMap, net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
 
 use crate::cluster::Endpoint;
+use crate::filters::Sample;
 
 #[cfg(doc)]
 use crate::filters::Filter;
@@ -30,10 +33,40 @@ pub struct WriteContext<'a> {
     pub from: SocketAddr,
     /// The destination of the received packet.
     pub to: SocketAddr,
+    /// Further downstream addresses registered on the session, e.g. those of
+    /// spectators, which the packet can be redirected or duplicated to by
+    /// setting [`WriteResponse::to`].
+    pub downstreams: &'a [SocketAddr],
     /// Contents of the received packet.
     pub contents: Vec<u8>,
     /// Arbitrary values that can be passed from one filter to another
     pub metadata: HashMap<String, Box<dyn Any + Send>>,
+    /// How long the packet will be held before it is sent, as requested by
+    /// earlier filters in the chain.
+    pub delay: Duration,
+    /// The priority of the packet, as set by earlier filters in the chain.
+    pub priority: Priority,
+    /// The packet's sampling decision, shared by every filter in the chain.
+    pub sample: Sample,
+}
+
+/// How urgently a packet is sent to its client when packets are waiting to
+/// be sent. Packets with a higher priority are sent first, e.g. so that
+/// connection critical packets aren't stuck behind bulk data.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum Priority {
+    #[serde(rename = "HIGH")]
+    High,
+    #[serde(rename = "NORMAL")]
+    Normal,
+    #[serde(rename = "LOW")]
+    Low,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
 }
 
 /// The output of [`Filter::write`].
@@ -46,12 +79,53 @@ pub struct WriteContext<'a> {
 ///       Some(ctx.into())
 ///   }
 /// ```
+///
+/// A filter can send more than one packet for each packet it receives by
+/// adding responses to [`WriteResponse::additional`], hold a packet back
+/// for a while by setting [`WriteResponse::delay`], have a packet sent
+/// ahead of others by setting [`WriteResponse::priority`], and send a packet
+/// to another of the session's downstreams by setting [`WriteResponse::to`].
 #[non_exhaustive]
 pub struct WriteResponse {
-    /// Contents of the packet to be sent back to the original sender.
+    /// The address the packet is sent to. This is the original sender
+    /// unless set to one of [`WriteContext::downstreams`], and the packet is
+    /// dropped if set to any other address.
+    pub to: SocketAddr,
+    /// Contents of the packet to be sent.
     pub contents: Vec<u8>,
     /// Arbitrary values that can be passed from one filter to another.
     pub metadata: HashMap<String, Box<dyn Any + Send>>,
+    /// How long to hold the packet before sending it.
+    pub delay: Duration,
+    /// The priority of the packet.
+    pub priority: Priority,
+    /// Further packets to be sent after this one. Each is passed through
+    /// the rest of the filter chain independently of this packet.
+    pub additional: Vec<WriteResponse>,
+}
+
+impl WriteResponse {
+    /// Returns this response followed by all of its additional responses,
+    /// in the order that they should be sent.
+    pub fn into_packets(mut self) -> Vec<WriteResponse> {
+        let additional = std::mem::take(&mut self.additional);
+        let mut packets = vec![self];
+        for response in additional {
+            packets.extend(response.into_packets());
+        }
+        packets
+    }
+
+    /// Returns the first of `packets` with the rest as its additional
+    /// responses, or `None` if there are no packets.
+    pub(crate) fn from_packets(mut packets: Vec<WriteResponse>) -> Option<WriteResponse> {
+        if packets.is_empty() {
+            return None;
+        }
+        let mut response = packets.remove(0);
+        response.additional = packets;
+        Some(response)
+    }
 }
 
 impl WriteContext<'_> {
@@ -66,24 +140,33 @@ impl WriteContext<'_> {
             endpoint,
             from,
             to,
+            downstreams: &[],
+            sample: Sample::new(to, &contents),
             contents,
             metadata: HashMap::new(),
+            delay: Duration::from_secs(0),
+            priority: Priority::default(),
         }
     }
 
-    /// Creates a new [`WriteContext`] from a given [`WriteResponse`].
+    /// Creates a new [`WriteContext`] from a given [`WriteResponse`], sent to
+    /// [`WriteResponse::to`]. Any additional packets in the response are
+    /// discarded.
     pub fn with_response(
         endpoint: &Endpoint,
         from: SocketAddr,
-        to: SocketAddr,
         response: WriteResponse,
     ) -> WriteContext {
         WriteContext {
             endpoint,
             from,
-            to,
+            to: response.to,
+            downstreams: &[],
+            sample: Sample::new(response.to, &response.contents),
             contents: response.contents,
             metadata: response.metadata,
+            delay: response.delay,
+            priority: response.priority,
         }
     }
 }
@@ -91,8 +174,12 @@ impl WriteContext<'_> {
 impl From<WriteContext<'_>> for WriteResponse {
     fn from(ctx: WriteContext) -> Self {
         Self {
+            to: ctx.to,
             contents: ctx.contents,
             metadata: ctx.metadata,
+            delay: ctx.delay,
+            priority: ctx.priority,
+            additional: Vec::new(),
         }
     }
 }
@@ -14,15 +14,21 @@
  * limitations under the License.
  */
 
-mod cluster;
+pub(crate) mod audit_log;
+pub mod cluster;
 pub mod config;
+pub(crate) mod faults;
 pub mod filters;
+pub mod load;
 pub(crate) mod metrics;
 pub mod proxy;
 pub mod runner;
+pub mod secret;
+pub(crate) mod supervisor;
+pub mod test_server;
 pub mod test_utils;
 pub(crate) mod utils;
-pub(crate) mod xds;
+pub mod xds;
 
 pub use quilkin_macros::{filter, include_proto};
 
@@ -45,6 +51,15 @@ mod external_doc_tests {
             #[doc = include_str!("../docs/extensions/filters/capture_bytes.md")]
             #[doc = include_str!("../docs/extensions/filters/token_router.md")]
             #[doc = include_str!("../docs/extensions/filters/compress.md")]
+            #[doc = include_str!("../docs/extensions/filters/handoff.md")]
+            #[doc = include_str!("../docs/extensions/filters/jitter_buffer.md")]
+            #[doc = include_str!("../docs/extensions/filters/prioritize.md")]
+            #[doc = include_str!("../docs/extensions/filters/token_quota.md")]
+            #[doc = include_str!("../docs/extensions/filters/fragment.md")]
+            #[doc = include_str!("../docs/extensions/filters/shadow.md")]
+            #[doc = include_str!("../docs/extensions/filters/static_metadata.md")]
+            #[doc = include_str!("../docs/extensions/filters/transform.md")]
+            #[doc = include_str!("../docs/extensions/filters/network_simulation.md")]
             mod tests {}
         };
     }
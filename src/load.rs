@@ -0,0 +1,375 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A load generator that simulates many downstream clients sending packets
+//! to a proxy, to capacity test it.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use slog::{info, o, Logger};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::time;
+
+/// The size of the sequence number and send time at the start of each packet.
+pub const HEADER_SIZE: usize = 16;
+/// How long to keep waiting for replies after a client stops sending on a
+/// socket.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// The shortest time between the sends of a client. Clients that would send
+/// more often, as there are far fewer of them than packets per second, send
+/// at this rate instead.
+const MIN_SEND_PERIOD: Duration = Duration::from_micros(1);
+
+/// Configures a load test.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// The address to send packets to.
+    pub target: SocketAddr,
+    /// The number of packets sent per second, across all clients.
+    pub packets_per_second: u64,
+    /// The size in bytes of each packet, which must be at least
+    /// [`HEADER_SIZE`].
+    pub packet_size: usize,
+    /// The number of clients sending packets at the same time.
+    pub sessions: usize,
+    /// How long to send packets for.
+    pub duration: Duration,
+    /// How long each client sends from the same address before it is replaced
+    /// by a client with a new address. If zero, clients are never replaced.
+    pub session_lifetime: Duration,
+}
+
+/// The results of a load test. Packets are considered lost unless they are
+/// echoed back to the client that sent them, e.g. by a
+/// [`TestServer`](crate::test_server::TestServer) in echo mode.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The number of packets sent.
+    pub sent: u64,
+    /// The number of packets echoed back.
+    pub received: u64,
+    /// The number of clients that were started, including replacements.
+    pub sessions: u64,
+    /// The round trip time of each received packet, in ascending order.
+    pub latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Returns the fraction of sent packets that were not received.
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.sent.saturating_sub(self.received) as f64 / self.sent as f64
+    }
+
+    /// Returns the round trip time that `percentile` percent of received
+    /// packets were at or below.
+    pub fn latency_percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        let index = rank.max(1).min(self.latencies.len()) - 1;
+        Some(self.latencies[index])
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "sessions: {}", self.sessions)?;
+        writeln!(f, "sent: {}", self.sent)?;
+        writeln!(f, "received: {}", self.received)?;
+        writeln!(f, "loss: {:.2}%", self.loss() * 100.0)?;
+        for &percentile in &[50.0, 90.0, 99.0, 100.0] {
+            match self.latency_percentile(percentile) {
+                Some(latency) => writeln!(f, "latency p{}: {:?}", percentile, latency)?,
+                None => writeln!(f, "latency p{}: n/a", percentile)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    sent: AtomicU64,
+    received: AtomicU64,
+    sessions: AtomicU64,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+/// Runs a load test against `config.target`, until `config.duration` has
+/// elapsed or a value is sent on `shutdown_rx`.
+pub async fn run(
+    base: &Logger,
+    config: Config,
+    shutdown_rx: watch::Receiver<()>,
+) -> io::Result<Report> {
+    let log = base.new(o!("source" => "load"));
+    info!(log, "Starting load test";
+        "target" => config.target,
+        "packets_per_second" => config.packets_per_second,
+        "packet_size" => config.packet_size,
+        "sessions" => config.sessions);
+
+    let started_at = Instant::now();
+    let send_until = started_at + config.duration;
+    let stats = Arc::new(Stats::default());
+    let config = Arc::new(config);
+
+    let mut clients = Vec::with_capacity(config.sessions);
+    for id in 0..config.sessions {
+        clients.push(tokio::spawn(run_client(
+            id,
+            config.clone(),
+            started_at,
+            send_until,
+            stats.clone(),
+            shutdown_rx.clone(),
+        )));
+    }
+    for client in clients {
+        client
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))??;
+    }
+    // Wait for the replies to the last packets sent.
+    time::sleep(DRAIN_TIMEOUT).await;
+
+    let mut latencies = std::mem::take(&mut *stats.latencies.lock());
+    latencies.sort();
+    Ok(Report {
+        sent: stats.sent.load(Ordering::Relaxed),
+        received: stats.received.load(Ordering::Relaxed),
+        sessions: stats.sessions.load(Ordering::Relaxed),
+        latencies,
+    })
+}
+
+/// Sends packets from a single simulated client, replacing its socket every
+/// `config.session_lifetime`.
+async fn run_client(
+    id: usize,
+    config: Arc<Config>,
+    started_at: Instant,
+    send_until: Instant,
+    stats: Arc<Stats>,
+    mut shutdown_rx: watch::Receiver<()>,
+) -> io::Result<()> {
+    // Spread the clients' sends out evenly over each interval. The period
+    // can round down to zero, which `interval_at` doesn't accept.
+    let period = Duration::from_secs_f64(config.sessions as f64 / config.packets_per_second as f64)
+        .max(MIN_SEND_PERIOD);
+    let mut interval = time::interval_at(
+        (started_at + period.mul_f64(id as f64 / config.sessions as f64)).into(),
+        period,
+    );
+    let mut packet = vec![0; config.packet_size.max(HEADER_SIZE)];
+    let mut sequence = 0u64;
+    let mut session: Option<(Arc<UdpSocket>, Instant)> = None;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown_rx.changed() => return Ok(()),
+        }
+        let now = Instant::now();
+        if now >= send_until {
+            return Ok(());
+        }
+
+        let socket = match &session {
+            Some((socket, session_end)) if now < *session_end => socket.clone(),
+            _ => {
+                let socket = Arc::new(bind(config.target).await?);
+                stats.sessions.fetch_add(1, Ordering::Relaxed);
+                let session_end = if config.session_lifetime > Duration::from_secs(0) {
+                    (now + config.session_lifetime).min(send_until)
+                } else {
+                    send_until
+                };
+                tokio::spawn(receive(
+                    socket.clone(),
+                    started_at,
+                    session_end + DRAIN_TIMEOUT,
+                    stats.clone(),
+                ));
+                session = Some((socket.clone(), session_end));
+                socket
+            }
+        };
+
+        let sent_at = now.duration_since(started_at).as_nanos() as u64;
+        packet[..8].copy_from_slice(&sequence.to_be_bytes());
+        packet[8..HEADER_SIZE].copy_from_slice(&sent_at.to_be_bytes());
+        sequence += 1;
+        socket.send_to(&packet, config.target).await?;
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Records the packets received on `socket` until `deadline`.
+async fn receive(
+    socket: Arc<UdpSocket>,
+    started_at: Instant,
+    deadline: Instant,
+    stats: Arc<Stats>,
+) {
+    let mut buf = vec![0; 1 << 16];
+    while let Ok(Ok((size, _))) =
+        time::timeout_at(deadline.into(), socket.recv_from(&mut buf)).await
+    {
+        if size < HEADER_SIZE {
+            continue;
+        }
+        let sent_at = u64::from_be_bytes(buf[8..HEADER_SIZE].try_into().unwrap());
+        let latency = started_at
+            .elapsed()
+            .checked_sub(Duration::from_nanos(sent_at))
+            .unwrap_or_default();
+        stats.received.fetch_add(1, Ordering::Relaxed);
+        stats.latencies.lock().push(latency);
+    }
+}
+
+/// Binds a socket to an ephemeral port that can send packets to `target`.
+async fn bind(target: SocketAddr) -> io::Result<UdpSocket> {
+    let addr: SocketAddr = if target.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    UdpSocket::bind(addr).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use tokio::sync::watch;
+
+    use super::{run, Config, Report};
+    use crate::test_server::{self, TestServer};
+    use crate::test_utils::logger;
+
+    #[tokio::test]
+    async fn run_against_echo_server() {
+        let server = TestServer::bind(&logger(), test_server::Config::default())
+            .await
+            .unwrap();
+        let target = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            server.local_addr().unwrap().port(),
+        );
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(server.run(shutdown_rx.clone()));
+
+        let report = run(
+            &logger(),
+            Config {
+                target,
+                packets_per_second: 100,
+                packet_size: 64,
+                sessions: 2,
+                duration: Duration::from_millis(500),
+                session_lifetime: Duration::from_millis(200),
+            },
+            shutdown_rx,
+        )
+        .await
+        .unwrap();
+        shutdown_tx.send(()).unwrap();
+
+        assert!(report.sent > 0);
+        assert_eq!(report.sent, report.received);
+        assert_eq!(0.0, report.loss());
+        // Each client was replaced at least once.
+        assert!(report.sessions > 2);
+        assert_eq!(report.received as usize, report.latencies.len());
+    }
+
+    #[tokio::test]
+    async fn rate_beyond_timer_resolution() {
+        let server = TestServer::bind(&logger(), test_server::Config::default())
+            .await
+            .unwrap();
+        let target = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            server.local_addr().unwrap().port(),
+        );
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(server.run(shutdown_rx.clone()));
+
+        // A send period that rounds down to zero is clamped rather than
+        // panicking.
+        let report = run(
+            &logger(),
+            Config {
+                target,
+                packets_per_second: u64::MAX,
+                packet_size: 64,
+                sessions: 1,
+                duration: Duration::from_millis(20),
+                session_lifetime: Duration::from_secs(0),
+            },
+            shutdown_rx,
+        )
+        .await
+        .unwrap();
+        shutdown_tx.send(()).unwrap();
+
+        assert!(report.sent > 0);
+    }
+
+    #[test]
+    fn report() {
+        let report = Report {
+            sent: 4,
+            received: 3,
+            sessions: 1,
+            latencies: vec![
+                Duration::from_millis(1),
+                Duration::from_millis(2),
+                Duration::from_millis(3),
+            ],
+        };
+        assert_eq!(0.25, report.loss());
+        assert_eq!(
+            Some(Duration::from_millis(2)),
+            report.latency_percentile(50.0)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(3)),
+            report.latency_percentile(100.0)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(1)),
+            report.latency_percentile(0.0)
+        );
+        assert_eq!(None, Report::default().latency_percentile(50.0));
+        assert_eq!(0.0, Report::default().loss());
+    }
+}
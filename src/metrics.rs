@@ -14,10 +14,61 @@
  * limitations under the License.
  */
 
+use parking_lot::{const_rwlock, RwLock};
 use prometheus::core::Collector;
 pub use prometheus::Result;
 use prometheus::{HistogramOpts, Opts, Registry, DEFAULT_BUCKETS};
 
+use crate::config::HistogramBuckets;
+
+/// The default buckets of histograms of packet sizes, in bytes.
+pub const DEFAULT_PACKET_SIZE_BUCKETS: &[f64] = &[
+    64.0, 128.0, 256.0, 512.0, 1024.0, 1200.0, 1500.0, 4096.0, 16384.0, 65535.0,
+];
+
+/// The default buckets of histograms of compression ratios.
+pub const DEFAULT_COMPRESSION_RATIO_BUCKETS: &[f64] =
+    &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0, 1.5];
+
+/// The buckets that override the defaults of histograms created from now on.
+/// Histograms are created throughout the proxy, so the buckets from its
+/// config are set here once, before any are created.
+static HISTOGRAM_BUCKETS: RwLock<HistogramBuckets> = const_rwlock(HistogramBuckets {
+    latency: None,
+    packet_size: None,
+    compression_ratio: None,
+});
+
+/// Overrides the buckets of histograms created from now on.
+pub fn set_histogram_buckets(buckets: HistogramBuckets) {
+    *HISTOGRAM_BUCKETS.write() = buckets;
+}
+
+/// Returns the buckets of a histogram of how long packets take to process,
+/// which are `default` unless overridden.
+pub fn latency_buckets(default: &[f64]) -> Vec<f64> {
+    let buckets = HISTOGRAM_BUCKETS.read();
+    buckets.latency.clone().unwrap_or_else(|| default.into())
+}
+
+/// Returns the buckets of a histogram of packet sizes.
+pub fn packet_size_buckets() -> Vec<f64> {
+    let buckets = HISTOGRAM_BUCKETS.read();
+    buckets
+        .packet_size
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PACKET_SIZE_BUCKETS.into())
+}
+
+/// Returns the buckets of a histogram of compression ratios.
+pub fn compression_ratio_buckets() -> Vec<f64> {
+    let buckets = HISTOGRAM_BUCKETS.read();
+    buckets
+        .compression_ratio
+        .clone()
+        .unwrap_or_else(|| DEFAULT_COMPRESSION_RATIO_BUCKETS.into())
+}
+
 /// Create a generic metrics options.
 /// Use [filter_opts] instead if the intended target is a filter.
 pub fn opts(name: &str, subsystem: &str, description: &str) -> Opts {
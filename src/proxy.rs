@@ -14,15 +14,28 @@
  * limitations under the License.
  */
 
-pub(crate) use admin::Admin;
+pub(crate) use admin::{Admin, Tap, TapDirection};
+pub(crate) use compute_pool::ComputePool;
 pub use builder::{logger, Builder, PendingValidation, Validated};
-pub(crate) use health::Health;
+pub use config_handle::{ConfigHandle, ConfigUpdate, UpdateError};
+pub(crate) use health::{contain_panics, Health};
+pub(crate) use info::{register_build_info, version, Info};
 pub(crate) use metrics::Metrics;
-pub use server::Server;
+pub(crate) use scheduler::Scheduler;
+pub use server::{DoctorReport, Server};
 
 mod admin;
 mod builder;
+mod compute_pool;
+mod config_handle;
+mod connect_udp;
 mod health;
+mod info;
 mod metrics;
+mod relay;
+mod relayed;
+mod scheduler;
 mod server;
 mod sessions;
+mod socks5;
+mod tunnel;
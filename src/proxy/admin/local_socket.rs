@@ -0,0 +1,211 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Serves the admin interface on a local control socket, so that node-local
+//! tooling can reach it without the proxy opening a TCP port. This is a unix
+//! domain socket, or a named pipe on Windows.
+
+use std::convert::Infallible;
+use std::io;
+use std::path::PathBuf;
+
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use slog::{debug, warn, Logger};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+
+use super::{Handlers, Listener};
+
+/// Serves admin requests on the unix domain socket at `path` until a value
+/// is sent on `shutdown_rx`. The socket is only accessible by the user the
+/// proxy runs as.
+#[cfg(unix)]
+pub(super) async fn serve(
+    log: Logger,
+    path: PathBuf,
+    handlers: Handlers,
+    mut shutdown_rx: watch::Receiver<()>,
+) -> io::Result<()> {
+    use std::fs;
+    use std::os::unix::fs::FileTypeExt;
+
+    // Remove a socket left behind by a previous run, which would otherwise
+    // fail the bind. Anything other than a socket is left alone.
+    if let Ok(metadata) = fs::symlink_metadata(&path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(&path)?;
+        }
+    }
+    let listener = bind_private(&path)?;
+
+    loop {
+        tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, _)) => {
+                    let client = match stream.peer_cred() {
+                        Ok(cred) => format!("local_socket(uid={})", cred.uid()),
+                        Err(_) => "local_socket".into(),
+                    };
+                    serve_connection(&log, stream, client, handlers.clone())
+                }
+                Err(err) => warn!(log, "Failed to accept admin connection"; "error" => %err),
+            },
+            _ = shutdown_rx.changed() => break,
+        }
+    }
+
+    fs::remove_file(&path).ok();
+    Ok(())
+}
+
+/// Binds a unix domain socket at `path` that only the user the proxy runs
+/// as can connect to. The socket is bound inside a directory that only
+/// that user can access, and is only moved to `path` once its permissions
+/// are restricted, so it's never reachable with looser permissions.
+#[cfg(unix)]
+fn bind_private(path: &std::path::Path) -> io::Result<tokio::net::UnixListener> {
+    use std::fs::{self, DirBuilder, Permissions};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid local socket path `{}`", path.display()),
+        )
+    })?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let bound = dir.join(file_name);
+    let result = tokio::net::UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    fs::remove_dir_all(&dir).ok();
+    result
+}
+
+/// Serves admin requests on the named pipe at `path`, e.g.
+/// `\\.\pipe\quilkin`, until a value is sent on `shutdown_rx`.
+#[cfg(windows)]
+pub(super) async fn serve(
+    log: Logger,
+    path: PathBuf,
+    handlers: Handlers,
+    mut shutdown_rx: watch::Receiver<()>,
+) -> io::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&path)?;
+    loop {
+        let connected = tokio::select! {
+            result = server.connect() => result,
+            _ = shutdown_rx.changed() => return Ok(()),
+        };
+        if let Err(err) = connected {
+            warn!(log, "Failed to accept admin connection"; "error" => %err);
+            continue;
+        }
+        // Each client is served by its own instance of the pipe, so create
+        // the instance that the next client connects to.
+        let client = std::mem::replace(&mut server, ServerOptions::new().create(&path)?);
+        serve_connection(&log, client, "local_socket".into(), handlers.clone());
+    }
+}
+
+/// Spawns a task serving the admin requests sent over `stream` by `client`.
+fn serve_connection<S>(log: &Logger, stream: S, client: String, handlers: Handlers)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let log = log.clone();
+    tokio::spawn(async move {
+        let service = service_fn(move |req| {
+            let handlers = handlers.clone();
+            let client = client.clone();
+            async move {
+                Ok::<_, Infallible>(
+                    handlers
+                        .handle_request(&client, Listener::LocalSocket, req)
+                        .await,
+                )
+            }
+        });
+        if let Err(err) = Http::new().serve_connection(stream, service).await {
+            debug!(log, "Admin connection failed"; "error" => %err);
+        }
+    });
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+
+    use prometheus::Registry;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+    use tokio::sync::watch;
+
+    use super::serve;
+    use crate::proxy::admin::Admin;
+    use crate::proxy::{Health, Metrics};
+    use crate::test_utils::{config_with_dummy_endpoint, logger};
+
+    #[tokio::test]
+    async fn serve_unix_socket() {
+        let log = logger();
+        let handlers = Admin::new(
+            &log,
+            Arc::new(config_with_dummy_endpoint().build()),
+            Arc::new(Metrics::new(&log, Registry::default())),
+            Health::new(&log),
+        )
+        .handlers;
+        let path = std::env::temp_dir().join(format!("quilkin-admin-{}.sock", std::process::id()));
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let server = tokio::spawn(serve(log, path.clone(), handlers, shutdown_rx));
+
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        stream
+            .write_all(b"GET /live HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("ok"), "{}", response);
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,430 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Instant};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Error as TonicError, Server};
+use tonic::{Request, Response, Status};
+
+use super::SharedSessionManager;
+use crate::filters::Sample;
+use crate::proxy::sessions::{PacketCounts, Session, SessionKey, PACKET_SIZE_BUCKETS};
+
+crate::include_proto!("quilkin.proxy.session_stats.v1alpha1");
+use self::quilkin::proxy::session_stats::v1alpha1::{
+    session_stats_server::{SessionStats as SessionStatsService, SessionStatsServer},
+    PacketSummary, Report, SessionSummary, WatchRequest,
+};
+
+/// The maximum number of streams that can be open at once, so that watchers
+/// can't slow down the proxy by each walking its sessions.
+const MAX_WATCHERS: usize = 16;
+
+/// How often reports are sent if the request doesn't say.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often reports can be sent at most.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many reports are buffered for each watcher. A watcher that falls
+/// further behind holds up its own reports rather than the proxy.
+const WATCHER_BUFFER_SIZE: usize = 4;
+
+/// Streams summaries of the rates and sizes of the packets of the proxy's
+/// sessions to the clients of the session stats service.
+pub(crate) struct SessionStats {
+    session_manager: SharedSessionManager,
+    watching: AtomicUsize,
+}
+
+/// A stream opened by a client of the session stats service, and the
+/// counts of the sessions it reports as of its last report.
+struct Watcher {
+    address: Option<SocketAddr>,
+    sample_rate: f64,
+    /// The counts of the packets received from the client and from the
+    /// endpoint of each reported session.
+    previous: HashMap<SessionKey, (PacketCounts, PacketCounts)>,
+    last_report: Instant,
+}
+
+impl SessionStats {
+    pub(crate) fn new(session_manager: SharedSessionManager) -> Self {
+        Self {
+            session_manager,
+            watching: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts streaming reports on the sessions matching `request`,
+    /// returning the stream.
+    fn watch(
+        self: &Arc<Self>,
+        request: WatchRequest,
+    ) -> Result<ReceiverStream<Result<Report, Status>>, Status> {
+        let address = match request.address.as_str() {
+            "" => None,
+            address => Some(address.parse::<SocketAddr>().map_err(|err| {
+                Status::invalid_argument(format!("invalid address `{}`: {}", address, err))
+            })?),
+        };
+        let sample_rate = request.sample_rate.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(Status::invalid_argument(
+                "sample_rate must be between 0 and 1",
+            ));
+        }
+        let interval = match request.interval {
+            Some(interval) => Duration::try_from(interval)
+                .map_err(|_| Status::invalid_argument("interval must not be negative"))?,
+            None => DEFAULT_INTERVAL,
+        };
+        if interval < MIN_INTERVAL {
+            return Err(Status::invalid_argument(format!(
+                "interval must be at least {:?}",
+                MIN_INTERVAL
+            )));
+        }
+        let session_manager = self
+            .session_manager
+            .lock()
+            .clone()
+            .ok_or_else(|| Status::unavailable("the proxy hasn't started yet"))?;
+
+        if self.watching.fetch_add(1, Ordering::Relaxed) >= MAX_WATCHERS {
+            self.watching.fetch_sub(1, Ordering::Relaxed);
+            return Err(Status::resource_exhausted(format!(
+                "at most {} streams can be open at once",
+                MAX_WATCHERS
+            )));
+        }
+
+        let (reports, reports_rx) = mpsc::channel(WATCHER_BUFFER_SIZE);
+        let mut watcher = Watcher {
+            address,
+            sample_rate,
+            previous: HashMap::new(),
+            last_report: Instant::now(),
+        };
+        let stats = self.clone();
+        tokio::spawn(async move {
+            // Sessions are only reported on from when the stream was opened.
+            watcher.report(
+                session_manager.get_sessions().await.values(),
+                Instant::now(),
+            );
+            let mut ticks = time::interval_at(Instant::now() + interval, interval);
+            loop {
+                select! {
+                    _ = ticks.tick() => {}
+                    _ = reports.closed() => break,
+                }
+                let report = watcher.report(
+                    session_manager.get_sessions().await.values(),
+                    Instant::now(),
+                );
+                if reports.send(Ok(report)).await.is_err() {
+                    break;
+                }
+            }
+            stats.watching.fetch_sub(1, Ordering::Relaxed);
+        });
+
+        Ok(ReceiverStream::new(reports_rx))
+    }
+}
+
+impl Watcher {
+    fn matches(&self, session: &Session) -> bool {
+        let client = session.client();
+        self.address.map_or(true, |address| address == client)
+            && Sample::new(client, &[]).is_sampled(self.sample_rate)
+    }
+
+    /// Returns a report on the matching `sessions` that received packets
+    /// since the last report, as of `now`.
+    fn report<'a>(
+        &mut self,
+        sessions: impl IntoIterator<Item = &'a Session>,
+        now: Instant,
+    ) -> Report {
+        let elapsed = now.duration_since(self.last_report);
+        self.last_report = now;
+
+        let mut previous = HashMap::new();
+        let mut summaries = vec![];
+        for session in sessions {
+            if !self.matches(session) {
+                continue;
+            }
+            let key = session.key();
+            let counts = (
+                session.downstream_packet_counts(),
+                session.upstream_packet_counts(),
+            );
+            // Sessions created since the last report are covered from when
+            // they were created.
+            let (earlier, period) = match self.previous.get(&key) {
+                Some(earlier) => (*earlier, elapsed),
+                None => (Default::default(), session.age().min(elapsed)),
+            };
+            let downstream = counts.0.since(&earlier.0);
+            let upstream = counts.1.since(&earlier.1);
+            previous.insert(key.clone(), counts);
+            if downstream.packets == 0 && upstream.packets == 0 {
+                continue;
+            }
+
+            summaries.push(SessionSummary {
+                client: session.client().to_string(),
+                client_id: key.client.id().map(<[u8]>::to_vec).unwrap_or_default(),
+                endpoint: key.endpoint.to_string(),
+                age: Some(session.age().into()),
+                period: Some(period.into()),
+                downstream: Some(summary(&downstream, period)),
+                upstream: Some(summary(&upstream, period)),
+            });
+        }
+        self.previous = previous;
+
+        Report {
+            sessions: summaries,
+            size_buckets: PACKET_SIZE_BUCKETS
+                .iter()
+                .map(|bound| *bound as u32)
+                .collect(),
+            timestamp: Some(SystemTime::now().into()),
+        }
+    }
+}
+
+/// Summarises the packets in `counts`, received over `period`.
+fn summary(counts: &PacketCounts, period: Duration) -> PacketSummary {
+    let rate = |count: u64| {
+        let seconds = period.as_secs_f64();
+        if seconds > 0.0 {
+            count as f64 / seconds
+        } else {
+            0.0
+        }
+    };
+    PacketSummary {
+        packets: counts.packets,
+        bytes: counts.bytes,
+        packets_per_second: rate(counts.packets),
+        bytes_per_second: rate(counts.bytes),
+        mean_size: counts.mean_size(),
+        size_stddev: counts.size_stddev(),
+        size_buckets: counts.sizes.to_vec(),
+    }
+}
+
+/// Serves the session stats service for `stats`.
+struct Service {
+    stats: Arc<SessionStats>,
+}
+
+#[tonic::async_trait]
+impl SessionStatsService for Service {
+    type WatchStream = ReceiverStream<Result<Report, Status>>;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        self.stats.watch(request.into_inner()).map(Response::new)
+    }
+}
+
+/// Serves the session stats service on `addr` until `shutdown_rx` is
+/// notified.
+pub(super) async fn serve(
+    addr: SocketAddr,
+    stats: Arc<SessionStats>,
+    mut shutdown_rx: watch::Receiver<()>,
+) -> Result<(), TonicError> {
+    Server::builder()
+        .add_service(SessionStatsServer::new(Service { stats }))
+        .serve_with_shutdown(addr, async move {
+            shutdown_rx.changed().await.ok();
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+    use tokio::sync::{mpsc, watch};
+    use tokio::time::Instant;
+
+    use super::quilkin::proxy::session_stats::v1alpha1::WatchRequest;
+    use super::{SessionStats, Watcher, MAX_WATCHERS};
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, UpstreamSocket};
+    use crate::filters::{manager::FilterManager, FilterChain};
+    use crate::proxy::admin::SharedSessionManager;
+    use crate::proxy::sessions::metrics::Metrics;
+    use crate::proxy::sessions::session_manager::SessionManager;
+    use crate::proxy::sessions::{Packet, PacketSizeLimit, Session, SessionArgs};
+    use crate::test_utils::logger;
+
+    async fn session(from: &str, sender: mpsc::Sender<Packet>) -> Session {
+        let registry = Registry::default();
+        Session::new(
+            &logger(),
+            SessionArgs {
+                metrics: Metrics::new(&registry).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: from.parse().unwrap(),
+                client_key: None,
+                connection_id_header: None,
+                dest: Endpoint::from_address("127.0.0.1:7001".parse().unwrap()),
+                sender,
+                ttl: Duration::from_secs(60),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let session_manager = SharedSessionManager::default();
+        let stats = Arc::new(SessionStats::new(session_manager.clone()));
+        assert_eq!(
+            tonic::Code::Unavailable,
+            stats.watch(WatchRequest::default()).unwrap_err().code()
+        );
+
+        let registry = Registry::default();
+        let endpoint = Endpoint::from_address("127.0.0.1:7001".parse().unwrap());
+        let cluster_manager =
+            ClusterManager::fixed(&registry, Endpoints::new(vec![endpoint]).unwrap()).unwrap();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        *session_manager.lock() = Some(SessionManager::new(
+            logger(),
+            Metrics::new(&registry).unwrap(),
+            cluster_manager,
+            None,
+            shutdown_rx,
+        ));
+
+        for request in vec![
+            WatchRequest {
+                address: "nope".into(),
+                ..WatchRequest::default()
+            },
+            WatchRequest {
+                sample_rate: Some(1.5),
+                ..WatchRequest::default()
+            },
+            WatchRequest {
+                interval: Some(prost_types::Duration {
+                    seconds: 0,
+                    nanos: 100_000_000,
+                }),
+                ..WatchRequest::default()
+            },
+        ] {
+            assert_eq!(
+                tonic::Code::InvalidArgument,
+                stats.watch(request).unwrap_err().code()
+            );
+        }
+
+        let streams = (0..MAX_WATCHERS)
+            .map(|_| stats.watch(WatchRequest::default()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tonic::Code::ResourceExhausted,
+            stats.watch(WatchRequest::default()).unwrap_err().code()
+        );
+
+        drop(streams);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(stats.watch(WatchRequest::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn report() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let first = session("127.0.0.1:7000", sender.clone()).await;
+        let second = session("127.0.0.1:7002", sender).await;
+        let mut watcher = Watcher {
+            address: Some("127.0.0.1:7000".parse().unwrap()),
+            sample_rate: 1.0,
+            previous: HashMap::new(),
+            last_report: Instant::now(),
+        };
+
+        // Packets sent before the first report aren't reported by it.
+        first.send(&[0; 10]).await.unwrap();
+        let start = Instant::now();
+        watcher.report(vec![&first, &second], start);
+
+        first.send(&[0; 10]).await.unwrap();
+        first.send(&[0; 30]).await.unwrap();
+        first.send(&[0; 2000]).await.unwrap();
+        second.send(&[0; 10]).await.unwrap();
+        let report = watcher.report(vec![&first, &second], start + Duration::from_secs(2));
+        assert_eq!(6, report.size_buckets.len());
+        assert_eq!(1, report.sessions.len());
+
+        let summary = &report.sessions[0];
+        assert_eq!("127.0.0.1:7000", summary.client);
+        assert!(summary.client_id.is_empty());
+        assert_eq!("127.0.0.1:7001", summary.endpoint);
+        assert_eq!(Some(Duration::from_secs(2).into()), summary.period);
+        let downstream = summary.downstream.as_ref().unwrap();
+        assert_eq!(3, downstream.packets);
+        assert_eq!(2040, downstream.bytes);
+        assert_eq!(1.5, downstream.packets_per_second);
+        assert_eq!(1020.0, downstream.bytes_per_second);
+        assert_eq!(680.0, downstream.mean_size);
+        assert_eq!(vec![2, 0, 0, 0, 0, 0, 1], downstream.size_buckets);
+        assert_eq!(0, summary.upstream.as_ref().unwrap().packets);
+
+        // Sessions without packets since the last report are left out.
+        let report = watcher.report(vec![&first, &second], start + Duration::from_secs(4));
+        assert!(report.sessions.is_empty());
+    }
+}
@@ -0,0 +1,389 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Error as TonicError, Server};
+use tonic::{Request, Response, Status};
+
+use crate::filters::{DropReason, Sample};
+
+crate::include_proto!("quilkin.proxy.tap.v1alpha1");
+pub(crate) use self::quilkin::proxy::tap::v1alpha1::tap_event::Direction;
+use self::quilkin::proxy::tap::v1alpha1::{
+    tap_event::Stage,
+    tap_server::{Tap as TapService, TapServer},
+    TapEvent, WatchRequest,
+};
+
+/// The maximum number of streams that can be open at once, so that watchers
+/// can't slow down the proxy by each needing a copy of every packet.
+const MAX_WATCHERS: usize = 16;
+
+/// How many events are buffered for each watcher. Events are dropped, rather
+/// than holding up packets, while a watcher's buffer is full.
+const WATCHER_BUFFER_SIZE: usize = 1024;
+
+type EventSender = mpsc::Sender<Result<TapEvent, Status>>;
+
+/// Streams copies of the packets passing through the proxy to the clients of
+/// the tap service that are watching them.
+#[derive(Default)]
+pub(crate) struct Tap {
+    watchers: Mutex<Vec<Watcher>>,
+    /// The number of watchers, so that packets aren't matched against them,
+    /// which takes the lock, while there are none.
+    watching: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+/// A stream opened by a client of the tap service, and the packets it
+/// matches.
+struct Watcher {
+    id: u64,
+    address: Option<SocketAddr>,
+    token: Option<Vec<u8>>,
+    sample_rate: f64,
+    events: EventSender,
+}
+
+/// The watchers that a packet matched, which its events are streamed to.
+pub(crate) struct Tapped {
+    direction: Direction,
+    client: SocketAddr,
+    watchers: Vec<EventSender>,
+}
+
+impl Tap {
+    /// Returns the watchers that a packet of `client` moving in `direction`
+    /// matches, if any, given its `contents` before they are processed by
+    /// the filter chain.
+    pub(crate) fn select(
+        &self,
+        direction: Direction,
+        client: SocketAddr,
+        contents: &[u8],
+    ) -> Option<Tapped> {
+        if self.watching.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        // The same sample as the packet's filter context, so that watchers
+        // sample the same packets as filters do.
+        let sample = Sample::new(client, contents);
+        let watchers = self
+            .watchers
+            .lock()
+            .iter()
+            .filter(|watcher| watcher.matches(client, contents, sample))
+            .map(|watcher| watcher.events.clone())
+            .collect::<Vec<_>>();
+        if watchers.is_empty() {
+            return None;
+        }
+        Some(Tapped {
+            direction,
+            client,
+            watchers,
+        })
+    }
+
+    /// Starts streaming the packets matching `request`, returning the
+    /// stream.
+    fn watch(
+        self: &Arc<Self>,
+        request: WatchRequest,
+    ) -> Result<ReceiverStream<Result<TapEvent, Status>>, Status> {
+        let address = match request.address.as_str() {
+            "" => None,
+            address => Some(address.parse::<SocketAddr>().map_err(|err| {
+                Status::invalid_argument(format!("invalid address `{}`: {}", address, err))
+            })?),
+        };
+        let sample_rate = request.sample_rate.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(Status::invalid_argument("sample_rate must be between 0 and 1"));
+        }
+
+        let (events, events_rx) = mpsc::channel(WATCHER_BUFFER_SIZE);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut watchers = self.watchers.lock();
+            if watchers.len() >= MAX_WATCHERS {
+                return Err(Status::resource_exhausted(format!(
+                    "at most {} streams can be open at once",
+                    MAX_WATCHERS
+                )));
+            }
+            watchers.push(Watcher {
+                id,
+                address,
+                token: Some(request.token).filter(|token| !token.is_empty()),
+                sample_rate,
+                events: events.clone(),
+            });
+            self.watching.store(watchers.len(), Ordering::Relaxed);
+        }
+
+        // Stop matching packets against the watcher once its stream is
+        // closed.
+        let tap = self.clone();
+        tokio::spawn(async move {
+            events.closed().await;
+            let mut watchers = tap.watchers.lock();
+            watchers.retain(|watcher| watcher.id != id);
+            tap.watching.store(watchers.len(), Ordering::Relaxed);
+        });
+
+        Ok(ReceiverStream::new(events_rx))
+    }
+}
+
+impl Watcher {
+    fn matches(&self, client: SocketAddr, contents: &[u8], sample: Sample) -> bool {
+        self.address.map_or(true, |address| address == client)
+            && self.token.as_ref().map_or(true, |token| {
+                contents
+                    .windows(token.len())
+                    .any(|window| window == token.as_slice())
+            })
+            && sample.is_sampled(self.sample_rate)
+    }
+}
+
+impl Tapped {
+    /// Streams the packet's `contents` before they are processed by the
+    /// filter chain.
+    pub(crate) fn pre_filter(
+        &self,
+        endpoints: impl IntoIterator<Item = SocketAddr>,
+        contents: &[u8],
+    ) {
+        self.send(Stage::PreFilter, endpoints, contents.to_vec(), None);
+    }
+
+    /// Streams the packet's `contents` after they were processed by the
+    /// filter chain.
+    pub(crate) fn post_filter(
+        &self,
+        endpoints: impl IntoIterator<Item = SocketAddr>,
+        contents: &[u8],
+    ) {
+        self.send(Stage::PostFilter, endpoints, contents.to_vec(), None);
+    }
+
+    /// Streams that the filter chain dropped the packet for `reason`.
+    pub(crate) fn dropped(
+        &self,
+        endpoints: impl IntoIterator<Item = SocketAddr>,
+        reason: &DropReason,
+    ) {
+        self.send(Stage::Dropped, endpoints, vec![], Some(reason));
+    }
+
+    fn send(
+        &self,
+        stage: Stage,
+        endpoints: impl IntoIterator<Item = SocketAddr>,
+        contents: Vec<u8>,
+        reason: Option<&DropReason>,
+    ) {
+        let event = TapEvent {
+            direction: self.direction as i32,
+            stage: stage as i32,
+            client: self.client.to_string(),
+            endpoints: endpoints.into_iter().map(|endpoint| endpoint.to_string()).collect(),
+            contents,
+            drop_filter: reason.map(|r| r.filter.clone()).unwrap_or_default(),
+            drop_code: reason.map(|r| r.code.to_string()).unwrap_or_default(),
+            timestamp: Some(SystemTime::now().into()),
+        };
+        for watcher in &self.watchers {
+            // The event is dropped if the watcher has fallen behind.
+            let _ = watcher.try_send(Ok(event.clone()));
+        }
+    }
+}
+
+/// Serves the tap service for `tap`.
+struct Service {
+    tap: Arc<Tap>,
+}
+
+#[tonic::async_trait]
+impl TapService for Service {
+    type WatchStream = ReceiverStream<Result<TapEvent, Status>>;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        self.tap.watch(request.into_inner()).map(Response::new)
+    }
+}
+
+/// Serves the tap service on `addr` until `shutdown_rx` is notified.
+pub(super) async fn serve(
+    addr: SocketAddr,
+    tap: Arc<Tap>,
+    mut shutdown_rx: watch::Receiver<()>,
+) -> Result<(), TonicError> {
+    Server::builder()
+        .add_service(TapServer::new(Service { tap }))
+        .serve_with_shutdown(addr, async move {
+            shutdown_rx.changed().await.ok();
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::watch;
+    use tokio_stream::StreamExt;
+
+    use super::quilkin::proxy::tap::v1alpha1::{
+        tap_client::TapClient,
+        tap_event::{Direction, Stage},
+        WatchRequest,
+    };
+    use super::{serve, Tap, MAX_WATCHERS};
+    use crate::filters::DropReason;
+
+    #[tokio::test]
+    async fn select() {
+        let tap = Arc::new(Tap::default());
+        let client: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        assert!(tap.select(Direction::Read, client, b"hello").is_none());
+
+        let _all = tap.watch(WatchRequest::default()).unwrap();
+        let _client = tap
+            .watch(WatchRequest {
+                address: client.to_string(),
+                token: b"abc".to_vec(),
+                ..WatchRequest::default()
+            })
+            .unwrap();
+        let _none = tap
+            .watch(WatchRequest {
+                sample_rate: Some(0.0),
+                ..WatchRequest::default()
+            })
+            .unwrap();
+
+        let selected = |client, contents: &[u8]| {
+            tap.select(Direction::Read, client, contents)
+                .map_or(0, |tapped| tapped.watchers.len())
+        };
+        assert_eq!(2, selected(client, b"xabcx"));
+        assert_eq!(1, selected(client, b"hello"));
+        assert_eq!(1, selected(other, b"xabcx"));
+
+        for request in vec![
+            WatchRequest {
+                address: "nope".into(),
+                ..WatchRequest::default()
+            },
+            WatchRequest {
+                sample_rate: Some(1.5),
+                ..WatchRequest::default()
+            },
+        ] {
+            assert_eq!(
+                tonic::Code::InvalidArgument,
+                tap.watch(request).unwrap_err().code()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_watchers_are_removed() {
+        let tap = Arc::new(Tap::default());
+        let client: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let streams = (0..MAX_WATCHERS)
+            .map(|_| tap.watch(WatchRequest::default()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tonic::Code::ResourceExhausted,
+            tap.watch(WatchRequest::default()).unwrap_err().code()
+        );
+
+        drop(streams);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(tap.select(Direction::Read, client, b"hello").is_none());
+        assert!(tap.watch(WatchRequest::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let tap = Arc::new(Tap::default());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(serve(addr, tap.clone(), shutdown_rx));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut client = TapClient::connect(format!("http://{}", addr)).await.unwrap();
+        let mut stream = client
+            .watch(WatchRequest::default())
+            .await
+            .unwrap()
+            .into_inner();
+
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let endpoint: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let tapped = tap.select(Direction::Write, from, b"hello").unwrap();
+        tapped.pre_filter(Some(endpoint), b"hello");
+        tapped.post_filter(Some(endpoint), b"olleh");
+        tapped.dropped(
+            Some(endpoint),
+            &DropReason {
+                filter: "Auth".into(),
+                code: "InvalidSignature",
+            },
+        );
+
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(Direction::Write as i32, event.direction);
+        assert_eq!(Stage::PreFilter as i32, event.stage);
+        assert_eq!("127.0.0.1:7000", event.client);
+        assert_eq!(vec!["127.0.0.1:7001".to_string()], event.endpoints);
+        assert_eq!(b"hello".to_vec(), event.contents);
+        assert!(event.timestamp.is_some());
+
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(Stage::PostFilter as i32, event.stage);
+        assert_eq!(b"olleh".to_vec(), event.contents);
+
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(Stage::Dropped as i32, event.stage);
+        assert!(event.contents.is_empty());
+        assert_eq!("Auth", event.drop_filter);
+        assert_eq!("InvalidSignature", event.drop_code);
+    }
+}
@@ -14,35 +14,68 @@
  *  limitations under the License.
  */
 
-use std::{collections::HashSet, convert::TryInto, marker::PhantomData, sync::Arc};
+use std::{
+    collections::HashSet, convert::TryInto, marker::PhantomData, sync::Arc, time::Duration,
+};
 
 use prometheus::Registry;
-use slog::{o, Drain, Logger};
+use slog::{o, warn, Drain, Logger};
 use tonic::transport::Endpoint as TonicEndpoint;
 
-use crate::cluster::Endpoint;
+use crate::cluster::{mdns, Endpoint};
 use crate::config::{
-    parse_endpoint_metadata_from_yaml, Config, Endpoints, ManagementServer, Proxy, Source,
-    ValidationError, ValueInvalidArgs,
+    parse_endpoint_metadata_from_yaml, Config, EndPoint, EndpointUpdateGuard, Endpoints,
+    Experiment, Failover, FilterUpdateFailurePolicy, ManagementServer, Mdns, PortConflictPolicy,
+    Proxy, Source, Startup, StartupPolicy, ValidationError, ValueInvalidArgs, MAX_DATAGRAM_SIZE,
+};
+use crate::filters::{
+    chain::Error as FilterChainError, FilterChain, FilterRegistry, FilterSet, StaticFilter,
 };
-use crate::filters::{chain::Error as FilterChainError, FilterChain, FilterRegistry, FilterSet};
 use crate::proxy::server::metrics::Metrics as ProxyMetrics;
 use crate::proxy::sessions::metrics::Metrics as SessionMetrics;
-use crate::proxy::{Admin as ProxyAdmin, Health, Metrics, Server};
+use crate::proxy::{Admin as ProxyAdmin, ConfigHandle, Health, Metrics, Server};
+use crate::secret::{SecretProviders, SecretRef};
 
 pub(super) enum ValidatedSource {
     Static {
         filter_chain: Arc<FilterChain>,
         endpoints: Endpoints,
     },
+    /// A static source whose endpoints are discovered with mDNS, in
+    /// addition to its static endpoints, if any.
+    Mdns {
+        filter_chain: Arc<FilterChain>,
+        endpoints: Option<Endpoints>,
+        mdns: Mdns,
+    },
     Dynamic {
         management_servers: Vec<ManagementServer>,
+        startup: ValidatedStartup,
+        failover: Option<Failover>,
+        endpoint_update_guard: Option<EndpointUpdateGuard>,
+        filter_update_failure: FilterUpdateFailurePolicy,
+    },
+}
+
+/// The validated form of [`Startup`].
+pub(super) enum ValidatedStartup {
+    Wait,
+    Exit {
+        timeout: Duration,
+    },
+    Fallback {
+        timeout: Duration,
+        filter_chain: Arc<FilterChain>,
+        endpoints: Endpoints,
     },
 }
 
 pub(super) struct ValidatedConfig {
     pub proxy: Proxy,
     pub source: ValidatedSource,
+    /// The endpoints that packets are accepted from but never sent to, if
+    /// any.
+    pub response_only_endpoints: Option<Endpoints>,
     // Limit struct creation to the builder.
     pub phantom: PhantomData<()>,
 }
@@ -93,6 +126,8 @@ pub struct Builder<V> {
     log: Logger,
     config: Arc<Config>,
     filter_registry: FilterRegistry,
+    filter_chain: Option<Vec<StaticFilter>>,
+    secret_providers: SecretProviders,
     admin: Option<ProxyAdmin>,
     metrics: Arc<Metrics>,
     validation_status: V,
@@ -101,12 +136,16 @@ pub struct Builder<V> {
 impl From<Arc<Config>> for Builder<PendingValidation> {
     fn from(config: Arc<Config>) -> Self {
         let log = logger();
-        let metrics = Arc::new(Metrics::new(&log, Registry::default()));
+        let metrics = Arc::new(
+            Metrics::new(&log, Registry::default()).with_config(config.proxy.metrics.clone()),
+        );
         let health = Health::new(&log);
-        let admin = ProxyAdmin::new(&log, config.admin.address, metrics.clone(), health);
+        let admin = ProxyAdmin::new(&log, config.clone(), metrics.clone(), health);
         Builder {
             config,
             filter_registry: FilterRegistry::new(FilterSet::default(&log)),
+            filter_chain: None,
+            secret_providers: SecretProviders::default(),
             admin: Some(admin),
             metrics,
             log,
@@ -119,62 +158,862 @@ impl ValidatedConfig {
     fn validate(
         config: Arc<Config>,
         filter_registry: &FilterRegistry,
+        filter_chain: Option<Vec<StaticFilter>>,
         metrics: &Metrics,
     ) -> Result<Self, Error> {
+        if config.proxy.max_packet_size == 0 || config.proxy.max_packet_size > MAX_DATAGRAM_SIZE {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.max_packet_size".into(),
+                clarification: Some(format!(
+                    "the maximum packet size must be between 1 and {}",
+                    MAX_DATAGRAM_SIZE
+                )),
+                examples: Some(vec!["1200".into(), "65507".into()]),
+            })
+            .into());
+        }
+
+        if let Some(token) = &config.admin.token {
+            validate_secret_ref("admin.token", token, filter_registry.secret_providers())?;
+        }
+        if config.admin.privileged_endpoints
+            && config.admin.token.is_none()
+            && config.admin.local_socket.is_none()
+        {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "admin.privileged_endpoints".into(),
+                clarification: Some(
+                    "privileged endpoints require a token, or a local socket to be served on"
+                        .into(),
+                ),
+                examples: None,
+            })
+            .into());
+        }
+
+        if let Some(failover_buffer) = &config.proxy.failover_buffer {
+            if failover_buffer.max_delay == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.failover_buffer.max_delay".into(),
+                    clarification: Some("the maximum delay must be greater than 0".into()),
+                    examples: Some(vec!["50ms".into(), "100ms".into()]),
+                })
+                .into());
+            }
+            if failover_buffer.max_packets == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.failover_buffer.max_packets".into(),
+                    clarification: Some(
+                        "the maximum number of packets must be greater than 0".into(),
+                    ),
+                    examples: Some(vec!["16".into(), "32".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(connection_tracker) = &config.proxy.connection_tracker {
+            let res: Result<TonicEndpoint, _> = connection_tracker.address.clone().try_into();
+            if res.is_err() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.connection_tracker.address".into(),
+                    clarification: Some("the provided value must be a valid URI".into()),
+                    examples: Some(vec!["http://127.0.0.1:9000".into()]),
+                })
+                .into());
+            }
+            if connection_tracker.timeout == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.connection_tracker.timeout".into(),
+                    clarification: Some("the timeout must be greater than 0".into()),
+                    examples: Some(vec!["50ms".into(), "100ms".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(handshake) = &config.proxy.handshake {
+            if handshake.prefix.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.handshake.prefix".into(),
+                    clarification: Some("the prefix must not be empty".into()),
+                    examples: Some(vec!["UVVJTEtJTl9DT09LSUU=".into()]),
+                })
+                .into());
+            }
+            if !handshake.secret.is_empty() && handshake.secret_ref.is_some() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.handshake.secret_ref".into(),
+                    clarification: Some("only one of `secret` and `secret_ref` can be set".into()),
+                    examples: None,
+                })
+                .into());
+            }
+            if handshake.cookie_lifetime == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.handshake.cookie_lifetime".into(),
+                    clarification: Some("the cookie lifetime must be greater than 0".into()),
+                    examples: Some(vec!["10s".into(), "30s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(connection_id) = &config.proxy.connection_id {
+            if connection_id.prefix.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.connection_id.prefix".into(),
+                    clarification: Some("the prefix must not be empty".into()),
+                    examples: Some(vec!["UVVJTEtJTl9DSUQ=".into()]),
+                })
+                .into());
+            }
+            if !connection_id.secret.is_empty() && connection_id.secret_ref.is_some() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.connection_id.secret_ref".into(),
+                    clarification: Some("only one of `secret` and `secret_ref` can be set".into()),
+                    examples: None,
+                })
+                .into());
+            }
+            if config.proxy.session_key.is_some() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.connection_id".into(),
+                    clarification: Some(
+                        "sessions are keyed by connection ids, so `session_key` can't be set"
+                            .into(),
+                    ),
+                    examples: None,
+                })
+                .into());
+            }
+        }
+
+        if let Some(ice) = &config.proxy.ice {
+            // Credentials are made of `ice-char`s, see RFC 8839 section 5.4.
+            let ice_chars = |value: &str| {
+                value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+            };
+            if !ice.ufrag.is_empty() && (ice.ufrag.len() < 4 || !ice_chars(&ice.ufrag)) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ice.ufrag".into(),
+                    clarification: Some(
+                        "the username fragment must be at least 4 letters, digits, `+` or `/`"
+                            .into(),
+                    ),
+                    examples: Some(vec!["quilkin".into()]),
+                })
+                .into());
+            }
+            if !ice.password.is_empty() && (ice.password.len() < 22 || !ice_chars(&ice.password)) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ice.password".into(),
+                    clarification: Some(
+                        "the password must be at least 22 letters, digits, `+` or `/`".into(),
+                    ),
+                    examples: Some(vec!["asd88fgpdd777uzjYhagZg".into()]),
+                })
+                .into());
+            }
+            if ice.candidates.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ice.candidates".into(),
+                    clarification: Some("at least one candidate must be set".into()),
+                    examples: Some(vec!["203.0.113.1:7000".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(ban_gossip) = &config.proxy.ban_gossip {
+            if ban_gossip.secret.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ban_gossip.secret".into(),
+                    clarification: Some("a base64 encoded secret must be set".into()),
+                    examples: Some(vec!["c2VjcmV0".into()]),
+                })
+                .into());
+            }
+            if ban_gossip.port == config.proxy.port {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ban_gossip.port".into(),
+                    clarification: Some("the port must differ from the proxy's port".into()),
+                    examples: Some(vec!["7100".into()]),
+                })
+                .into());
+            }
+            if ban_gossip.duration.as_nanos() == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ban_gossip.duration".into(),
+                    clarification: Some("the duration must be greater than 0".into()),
+                    examples: Some(vec!["60s".into(), "5m".into()]),
+                })
+                .into());
+            }
+            if ban_gossip.min_drops == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ban_gossip.min_drops".into(),
+                    clarification: Some("the number of drops must be greater than 0".into()),
+                    examples: Some(vec!["10".into()]),
+                })
+                .into());
+            }
+            if ban_gossip.max_bans == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ban_gossip.max_bans".into(),
+                    clarification: Some("the maximum number of bans must be greater than 0".into()),
+                    examples: Some(vec!["100000".into()]),
+                })
+                .into());
+            }
+            if ban_gossip.sweep_interval == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ban_gossip.sweep_interval".into(),
+                    clarification: Some("the interval must be greater than 0".into()),
+                    examples: Some(vec!["1s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(standby) = &config.proxy.standby {
+            if standby.secret.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.standby.secret".into(),
+                    clarification: Some("a base64 encoded secret must be set".into()),
+                    examples: Some(vec!["c2VjcmV0".into()]),
+                })
+                .into());
+            }
+            if standby.port == config.proxy.port {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.standby.port".into(),
+                    clarification: Some("the port must differ from the proxy's port".into()),
+                    examples: Some(vec!["7200".into()]),
+                })
+                .into());
+            }
+            if standby.heartbeat_interval == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.standby.heartbeat_interval".into(),
+                    clarification: Some("the interval must be greater than 0".into()),
+                    examples: Some(vec!["100ms".into()]),
+                })
+                .into());
+            }
+            if standby.failover_timeout <= standby.heartbeat_interval {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.standby.failover_timeout".into(),
+                    clarification: Some(
+                        "the timeout must be greater than the heartbeat interval".into(),
+                    ),
+                    examples: Some(vec!["500ms".into()]),
+                })
+                .into());
+            }
+            if standby.sync_interval == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.standby.sync_interval".into(),
+                    clarification: Some("the interval must be greater than 0".into()),
+                    examples: Some(vec!["200ms".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(compute_pool) = &config.proxy.compute_pool {
+            if compute_pool.threads == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.compute_pool.threads".into(),
+                    clarification: Some("the number of threads must be greater than 0".into()),
+                    examples: Some(vec!["2".into(), "4".into()]),
+                })
+                .into());
+            }
+            if compute_pool.queue_size == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.compute_pool.queue_size".into(),
+                    clarification: Some("the queue size must be greater than 0".into()),
+                    examples: Some(vec!["512".into(), "1024".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(audit_log) = &config.proxy.audit_log {
+            if audit_log.file.is_none() && audit_log.syslog.is_none() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.audit_log".into(),
+                    clarification: Some("at least one of file or syslog must be set".into()),
+                    examples: Some(vec!["file: /var/log/quilkin/audit.log".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(fair_queue) = &config.proxy.fair_queue {
+            if fair_queue.queue_size == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.fair_queue.queue_size".into(),
+                    clarification: Some("the queue size must be greater than 0".into()),
+                    examples: Some(vec!["512".into(), "1024".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(client_version) = &config.proxy.client_version {
+            if client_version.length == 0 || client_version.length > 64 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.client_version.length".into(),
+                    clarification: Some("the length must be between 1 and 64 bytes".into()),
+                    examples: Some(vec!["4".into(), "16".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(ordered_sends) = &config.proxy.ordered_sends {
+            if ordered_sends.queue_size == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ordered_sends.queue_size".into(),
+                    clarification: Some("the queue size must be greater than 0".into()),
+                    examples: Some(vec!["128".into(), "256".into()]),
+                })
+                .into());
+            }
+            if config.proxy.fair_queue.is_some() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.ordered_sends".into(),
+                    clarification: Some(
+                        "`fair_queue` spreads each client's packets over workers, so it can't \
+                         be set"
+                            .into(),
+                    ),
+                    examples: None,
+                })
+                .into());
+            }
+        }
+
+        if let Some(analyzer) = &config.proxy.analyzer {
+            if analyzer.window == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.analyzer.window".into(),
+                    clarification: Some("the window must be greater than 0".into()),
+                    examples: Some(vec!["10s".into(), "1m".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(burst_detection) = &config.proxy.burst_detection {
+            if burst_detection.window == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.burst_detection.window".into(),
+                    clarification: Some("the window must be greater than 0".into()),
+                    examples: Some(vec!["10ms".into()]),
+                })
+                .into());
+            }
+            if let Some(webhook) = &burst_detection.webhook {
+                let uri = webhook.parse::<hyper::Uri>();
+                if !matches!(uri, Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some())
+                {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "proxy.burst_detection.webhook".into(),
+                        clarification: Some("the URL must be a valid http URL".into()),
+                        examples: Some(vec!["http://alerts:8080/bursts".into()]),
+                    })
+                    .into());
+                }
+            }
+        }
+
+        if let Some(decision_log) = &config.proxy.decision_log {
+            if !(0.0..=1.0).contains(&decision_log.sample_rate) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.decision_log.sample_rate".into(),
+                    clarification: Some("the sample rate must be between 0 and 1".into()),
+                    examples: Some(vec!["0.001".into(), "0.1".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(telemetry) = &config.proxy.telemetry {
+            if !matches!(config.source, Source::Dynamic { .. }) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.telemetry".into(),
+                    clarification: Some(
+                        "telemetry is reported to management servers, so requires dynamic configuration"
+                            .into(),
+                    ),
+                    examples: None,
+                })
+                .into());
+            }
+            if telemetry.interval < Duration::from_secs(1) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.telemetry.interval".into(),
+                    clarification: Some("the interval must be at least 1s".into()),
+                    examples: Some(vec!["60s".into(), "5m".into()]),
+                })
+                .into());
+            }
+        }
+
+        for filter_timeout in &config.proxy.filter_timeouts {
+            if filter_timeout.timeout == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.filter_timeouts.timeout".into(),
+                    clarification: Some("the timeout must be greater than 0".into()),
+                    examples: Some(vec!["1ms".into(), "10ms".into()]),
+                })
+                .into());
+            }
+        }
+
+        for filter_budget in &config.proxy.filter_budgets {
+            if filter_budget.period == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.filter_budgets.period".into(),
+                    clarification: Some("the period must be greater than 0".into()),
+                    examples: Some(vec!["1s".into(), "10s".into()]),
+                })
+                .into());
+            }
+            if filter_budget.cpu_time == Some(Duration::from_secs(0)) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.filter_budgets.cpu_time".into(),
+                    clarification: Some("the CPU time must be greater than 0".into()),
+                    examples: Some(vec!["10ms".into(), "100ms".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(routing_cache) = &config.proxy.routing_cache {
+            if routing_cache.filters.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.routing_cache.filters".into(),
+                    clarification: Some("at least one filter is required".into()),
+                    examples: Some(vec![
+                        "quilkin.extensions.filters.token_router.v1beta1.TokenRouter".into(),
+                    ]),
+                })
+                .into());
+            }
+            if routing_cache.ttl == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.routing_cache.ttl".into(),
+                    clarification: Some("the TTL must be greater than 0".into()),
+                    examples: Some(vec!["10s".into()]),
+                })
+                .into());
+            }
+            if routing_cache.max_clients == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.routing_cache.max_clients".into(),
+                    clarification: Some("the maximum must be greater than 0".into()),
+                    examples: Some(vec!["10000".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(trusted_clients) = &config.proxy.trusted_clients {
+            if trusted_clients.addresses.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.trusted_clients.addresses".into(),
+                    clarification: Some("at least one address range is required".into()),
+                    examples: Some(vec!["10.0.0.0/8".into()]),
+                })
+                .into());
+            }
+            if trusted_clients.filters.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.trusted_clients.filters".into(),
+                    clarification: Some("at least one filter is required".into()),
+                    examples: Some(vec![
+                        "quilkin.extensions.filters.local_rate_limit.v1beta1.LocalRateLimit".into(),
+                    ]),
+                })
+                .into());
+            }
+        }
+
+        let schedule = &config.proxy.schedule;
+        let windows = schedule
+            .filters
+            .iter()
+            .map(|filter| ("proxy.schedule.filters.active", &filter.active))
+            .chain(
+                schedule
+                    .endpoints
+                    .iter()
+                    .map(|endpoints| ("proxy.schedule.endpoints.active", &endpoints.active)),
+            );
+        for (field, active) in windows {
+            if active.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: field.into(),
+                    clarification: Some("at least one window is required".into()),
+                    examples: None,
+                })
+                .into());
+            }
+            if active.iter().any(|window| window.start == window.end) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: field.into(),
+                    clarification: Some("a window's start and end must differ".into()),
+                    examples: Some(vec!["start: 02:00, end: 03:00".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(resource_limits) = &config.proxy.resource_limits {
+            if resource_limits.check_interval == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.resource_limits.check_interval".into(),
+                    clarification: Some("the interval must be greater than 0".into()),
+                    examples: Some(vec!["1s".into(), "5s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(health_check) = &config.proxy.endpoint_health_check {
+            if health_check.max_send_failures == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.endpoint_health_check.max_send_failures".into(),
+                    clarification: Some("the number of failures must be greater than 0".into()),
+                    examples: Some(vec!["3".into()]),
+                })
+                .into());
+            }
+            if health_check.response_timeout == Some(Duration::from_secs(0)) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.endpoint_health_check.response_timeout".into(),
+                    clarification: Some("the timeout must be greater than 0".into()),
+                    examples: Some(vec!["10s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(slow_start) = &config.proxy.endpoint_slow_start {
+            if slow_start.window == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.endpoint_slow_start.window".into(),
+                    clarification: Some("the window must be greater than 0".into()),
+                    examples: Some(vec!["60s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(faults) = &config.proxy.faults {
+            if !cfg!(any(debug_assertions, feature = "testing")) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.faults".into(),
+                    clarification: Some(
+                        "faults can only be injected by debug builds, or builds with the \
+                         `testing` feature"
+                            .into(),
+                    ),
+                    examples: None,
+                })
+                .into());
+            }
+            if !(0.0..=1.0).contains(&faults.send_failure_rate) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.faults.send_failure_rate".into(),
+                    clarification: Some("the rate must be between 0 and 1".into()),
+                    examples: Some(vec!["0.1".into()]),
+                })
+                .into());
+            }
+            if faults.xds_disconnect_after == Some(Duration::from_secs(0)) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.faults.xds_disconnect_after".into(),
+                    clarification: Some("the duration must be greater than 0".into()),
+                    examples: Some(vec!["10s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(metrics_push) = &config.proxy.metrics_push {
+            let uri = metrics_push.url.parse::<hyper::Uri>();
+            if !matches!(uri, Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some()) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.metrics_push.url".into(),
+                    clarification: Some("the URL must be a valid http URL".into()),
+                    examples: Some(vec!["http://pushgateway:9091".into()]),
+                })
+                .into());
+            }
+            if metrics_push.interval == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.metrics_push.interval".into(),
+                    clarification: Some("the interval must be greater than 0".into()),
+                    examples: Some(vec!["15s".into(), "1m".into()]),
+                })
+                .into());
+            }
+        }
+
+        let histogram_buckets = &config.proxy.metrics.histogram_buckets;
+        let histogram_buckets_by_kind = [
+            ("latency", &histogram_buckets.latency),
+            ("packet_size", &histogram_buckets.packet_size),
+            ("compression_ratio", &histogram_buckets.compression_ratio),
+        ];
+        for (kind, buckets) in std::array::IntoIter::new(histogram_buckets_by_kind) {
+            let valid = buckets.as_ref().map_or(true, |buckets| {
+                !buckets.is_empty()
+                    && buckets.iter().all(|bucket| bucket.is_finite())
+                    && buckets.windows(2).all(|pair| pair[0] < pair[1])
+            });
+            if !valid {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: format!("proxy.metrics.histogram_buckets.{}", kind),
+                    clarification: Some(
+                        "the buckets must be a non-empty list of numbers in increasing order"
+                            .into(),
+                    ),
+                    examples: Some(vec!["[0.00001, 0.0001, 0.001]".into()]),
+                })
+                .into());
+            }
+        }
+        let metrics_config = &config.proxy.metrics;
+        let patterns = [("allow", &metrics_config.allow), ("deny", &metrics_config.deny)];
+        for (field, patterns) in std::array::IntoIter::new(patterns) {
+            if patterns.iter().any(|pattern| pattern.is_empty()) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: format!("proxy.metrics.{}", field),
+                    clarification: Some("the patterns must not be empty".into()),
+                    examples: Some(vec!["quilkin_proxy_*".into()]),
+                })
+                .into());
+            }
+        }
+        for relabel in &metrics_config.relabel {
+            let valid_name = relabel
+                .replacement
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+                && !relabel.replacement.starts_with(|c: char| c.is_ascii_digit());
+            if relabel.prefix.is_empty() || !valid_name {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.metrics.relabel".into(),
+                    clarification: Some(
+                        "the prefix must not be empty, and the replacement must be the start of \
+                         a valid metric name"
+                            .into(),
+                    ),
+                    examples: Some(vec!["{prefix: quilkin_, replacement: mygame_}".into()]),
+                })
+                .into());
+            }
+        }
+
+        // Histograms are created from here on, e.g by the filter chain.
+        crate::metrics::set_histogram_buckets(histogram_buckets.clone());
+
+        if let Some(tunnel_peer) = &config.proxy.tunnel_peer {
+            if crate::proxy::tunnel::split_host_port(&tunnel_peer.address).is_none() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.tunnel_peer.address".into(),
+                    clarification: Some("the address must be a host and port".into()),
+                    examples: Some(vec!["relay.example.com:443".into(), "10.0.0.1:7443".into()]),
+                })
+                .into());
+            }
+            if tunnel_peer.connect_timeout == Duration::from_secs(0) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.tunnel_peer.connect_timeout".into(),
+                    clarification: Some("the timeout must be greater than 0".into()),
+                    examples: Some(vec!["1s".into(), "5s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if config.proxy.port_conflict_policy == PortConflictPolicy::TakeOver
+            && !cfg!(target_os = "linux")
+        {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.port_conflict_policy".into(),
+                clarification: Some("taking over the port is only supported on Linux".into()),
+                examples: Some(vec!["FAIL".into()]),
+            })
+            .into());
+        }
+        if config.proxy.port_conflict_policy == PortConflictPolicy::TakeOver
+            && config.proxy.port_conflict_secret.is_empty()
+        {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.port_conflict_secret".into(),
+                clarification: Some(
+                    "a base64 encoded secret must be set to take over the port".into(),
+                ),
+                examples: Some(vec!["c2VjcmV0".into()]),
+            })
+            .into());
+        }
+
+        if let Some(socks5) = &config.proxy.socks5 {
+            if socks5.max_associations == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.socks5.max_associations".into(),
+                    clarification: Some("at least one association must be allowed".into()),
+                    examples: Some(vec!["1024".into()]),
+                })
+                .into());
+            }
+        }
+
+        let upstream_socket = &config.proxy.upstream_socket;
+        if let Some(ttl) = upstream_socket.ttl {
+            if ttl == 0 || ttl > 255 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.upstream_socket.ttl".into(),
+                    clarification: Some("the TTL must be between 1 and 255".into()),
+                    examples: Some(vec!["64".into(), "128".into()]),
+                })
+                .into());
+            }
+        }
+        if upstream_socket.dont_fragment.is_some() && !cfg!(target_os = "linux") {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.upstream_socket.dont_fragment".into(),
+                clarification: Some(
+                    "setting the don't fragment bit is only supported on Linux".into(),
+                ),
+                examples: None,
+            })
+            .into());
+        }
+
+        let response_only_endpoints = if config.proxy.response_only_endpoints.is_empty() {
+            None
+        } else {
+            Some(validate_endpoints(
+                "proxy.response_only_endpoints",
+                &config.proxy.response_only_endpoints,
+            )?)
+        };
+        if let Source::Static { endpoints, .. } = &config.source {
+            let response_only = config
+                .proxy
+                .response_only_endpoints
+                .iter()
+                .map(|endpoint| endpoint.address)
+                .collect::<HashSet<_>>();
+            if endpoints
+                .iter()
+                .any(|endpoint| response_only.contains(&endpoint.address))
+            {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.response_only_endpoints.address".into(),
+                    clarification: Some(
+                        "a response-only endpoint can't also be a static endpoint".into(),
+                    ),
+                    examples: None,
+                })
+                .into());
+            }
+        }
+
         let validated_source = match &config.source {
             Source::Static {
                 filters,
-                endpoints: config_endpoints,
+                endpoints,
+                mdns,
+                experiment,
             } => {
-                if config_endpoints
-                    .iter()
-                    .map(|ep| ep.address)
-                    .collect::<HashSet<_>>()
-                    .len()
-                    != config_endpoints.len()
-                {
-                    return Err(
-                        ValidationError::NotUnique("static.endpoints.address".to_string()).into(),
-                    );
-                }
-
-                let mut endpoints = Vec::with_capacity(config_endpoints.len());
-                for ep in config_endpoints {
-                    endpoints.push(Endpoint::from_config(ep).map_err(|err| {
-                        ValidationError::ValueInvalid(ValueInvalidArgs {
-                            field: "static.endpoints".to_string(),
-                            clarification: Some(format!("invalid endpoint config: {}", err)),
-                            examples: None,
-                        })
-                    })?);
+                if let Some(experiment) = experiment {
+                    validate_experiment(experiment, !filters.is_empty() || filter_chain.is_some())?;
                 }
-                let endpoints = Endpoints::new(endpoints).map_err(|_empty_list_error| {
-                    ValidationError::EmptyList("static.endpoints".into())
-                })?;
+                let filter_chain = Arc::new(match (filter_chain, experiment) {
+                    (Some(filter_chain), _) => {
+                        FilterChain::from_static(filter_chain, &metrics.registry)?
+                    }
+                    (None, Some(experiment)) => FilterChain::try_create_experiment(
+                        experiment,
+                        filter_registry,
+                        &metrics.registry,
+                    )?,
+                    (None, None) => FilterChain::try_create(
+                        filters.clone(),
+                        filter_registry,
+                        &metrics.registry,
+                    )?,
+                });
 
-                for ep in config_endpoints {
-                    if let Some(ref metadata) = ep.metadata {
-                        if let Err(err) = parse_endpoint_metadata_from_yaml(metadata.clone()) {
+                match mdns {
+                    None => ValidatedSource::Static {
+                        filter_chain,
+                        endpoints: validate_endpoints("static.endpoints", endpoints)?,
+                    },
+                    Some(mdns) => {
+                        if !mdns::is_service_type(&mdns.service_type) {
                             return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
-                                field: "static.endpoints.metadata".into(),
-                                clarification: Some(err),
-                                examples: None,
+                                field: "static.mdns.service_type".into(),
+                                clarification: Some(
+                                    "the service type must be a service name and protocol".into(),
+                                ),
+                                examples: Some(vec!["_game._udp".into(), "_quilkin._udp".into()]),
+                            })
+                            .into());
+                        }
+                        if mdns.query_interval == Duration::from_secs(0) {
+                            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                                field: "static.mdns.query_interval".into(),
+                                clarification: Some(
+                                    "the interval must be greater than zero".into(),
+                                ),
+                                examples: Some(vec!["5s".into(), "10s".into()]),
                             })
                             .into());
                         }
-                    }
-                }
 
-                ValidatedSource::Static {
-                    filter_chain: Arc::new(FilterChain::try_create(
-                        filters.clone(),
-                        filter_registry,
-                        &metrics.registry,
-                    )?),
-                    endpoints,
+                        ValidatedSource::Mdns {
+                            filter_chain,
+                            endpoints: if endpoints.is_empty() {
+                                None
+                            } else {
+                                Some(validate_endpoints("static.endpoints", endpoints)?)
+                            },
+                            mdns: mdns.clone(),
+                        }
+                    }
                 }
             }
-            Source::Dynamic { management_servers } => {
+            Source::Dynamic {
+                management_servers,
+                startup,
+                failover,
+                endpoint_update_guard,
+                filter_update_failure,
+            } => {
+                if filter_chain.is_some() {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "dynamic".into(),
+                        clarification: Some(
+                            "a filter chain can only be set with a static configuration".into(),
+                        ),
+                        examples: None,
+                    })
+                    .into());
+                }
+
                 if management_servers.is_empty() {
                     return Err(ValidationError::EmptyList(
                         "dynamic.management_servers".to_string(),
@@ -196,6 +1035,13 @@ impl ValidatedConfig {
                 }
 
                 for server in management_servers {
+                    if let Some(token) = &server.token {
+                        validate_secret_ref(
+                            "dynamic.management_servers.token",
+                            token,
+                            filter_registry.secret_providers(),
+                        )?;
+                    }
                     let res: Result<TonicEndpoint, _> = server.address.clone().try_into();
                     if res.is_err() {
                         return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
@@ -211,51 +1057,337 @@ impl ValidatedConfig {
                     }
                 }
 
+                if let Some(failover) = failover {
+                    if failover.cluster.is_empty() {
+                        return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                            field: "dynamic.failover.cluster".into(),
+                            clarification: Some("the cluster name must not be empty".into()),
+                            examples: None,
+                        })
+                        .into());
+                    }
+                }
+
+                if let Some(guard) = endpoint_update_guard {
+                    if !(0.0..=100.0).contains(&guard.max_removal_percent) {
+                        return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                            field: "dynamic.endpoint_update_guard.max_removal_percent".into(),
+                            clarification: Some("the percentage must be between 0 and 100".into()),
+                            examples: Some(vec!["0".into(), "25".into(), "50".into()]),
+                        })
+                        .into());
+                    }
+                    if guard.phase_in_interval == Duration::from_secs(0) {
+                        return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                            field: "dynamic.endpoint_update_guard.phase_in_interval".into(),
+                            clarification: Some("the interval must be greater than zero".into()),
+                            examples: Some(vec!["10s".into(), "1m".into()]),
+                        })
+                        .into());
+                    }
+                }
+
                 ValidatedSource::Dynamic {
                     management_servers: management_servers.clone(),
+                    startup: ValidatedStartup::validate(startup, filter_registry, metrics)?,
+                    failover: failover.clone(),
+                    endpoint_update_guard: *endpoint_update_guard,
+                    filter_update_failure: *filter_update_failure,
                 }
             }
         };
 
+        let mut proxy = config.proxy.clone();
+        if let Some(handshake) = &mut proxy.handshake {
+            // The handshake's secret is read once, as cookies signed with
+            // it must stay valid for as long as the proxy runs.
+            if let Some(secret_ref) = handshake.secret_ref.take() {
+                handshake.secret = filter_registry
+                    .secret_providers()
+                    .get(&secret_ref)
+                    .map_err(|err| {
+                        ValidationError::ValueInvalid(ValueInvalidArgs {
+                            field: "proxy.handshake.secret_ref".into(),
+                            clarification: Some(err.to_string()),
+                            examples: None,
+                        })
+                    })?;
+            }
+        }
+
+        if let Some(connection_id) = &mut proxy.connection_id {
+            // As for the handshake, connection ids must stay valid for as
+            // long as the proxy runs.
+            if let Some(secret_ref) = connection_id.secret_ref.take() {
+                connection_id.secret = filter_registry
+                    .secret_providers()
+                    .get(&secret_ref)
+                    .map_err(|err| {
+                        ValidationError::ValueInvalid(ValueInvalidArgs {
+                            field: "proxy.connection_id.secret_ref".into(),
+                            clarification: Some(err.to_string()),
+                            examples: None,
+                        })
+                    })?;
+            }
+        }
+
         Ok(ValidatedConfig {
-            proxy: config.proxy.clone(),
+            proxy,
             source: validated_source,
+            response_only_endpoints,
             phantom: Default::default(),
         })
     }
 }
 
-impl Builder<PendingValidation> {
-    pub fn with_log(self, log: Logger) -> Self {
-        Self { log, ..self }
+impl ValidatedStartup {
+    fn validate(
+        startup: &Startup,
+        filter_registry: &FilterRegistry,
+        metrics: &Metrics,
+    ) -> Result<Self, Error> {
+        if startup.policy != StartupPolicy::Wait && startup.timeout == Duration::from_secs(0) {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "dynamic.startup.timeout".into(),
+                clarification: Some("the timeout must be greater than 0".into()),
+                examples: Some(vec!["10s".into(), "1m".into()]),
+            })
+            .into());
+        }
+
+        Ok(match startup.policy {
+            StartupPolicy::Wait => ValidatedStartup::Wait,
+            StartupPolicy::Exit => ValidatedStartup::Exit {
+                timeout: startup.timeout,
+            },
+            StartupPolicy::Fallback => {
+                let fallback = startup.fallback.as_ref().ok_or_else(|| {
+                    ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "dynamic.startup.fallback".into(),
+                        clarification: Some(
+                            "a fallback configuration is required by the FALLBACK policy".into(),
+                        ),
+                        examples: None,
+                    })
+                })?;
+
+                ValidatedStartup::Fallback {
+                    timeout: startup.timeout,
+                    filter_chain: Arc::new(FilterChain::try_create(
+                        fallback.filters.clone(),
+                        filter_registry,
+                        &metrics.registry,
+                    )?),
+                    endpoints: validate_endpoints(
+                        "dynamic.startup.fallback.endpoints",
+                        &fallback.endpoints,
+                    )?,
+                }
+            }
+        })
     }
+}
 
-    pub fn with_filter_registry(self, filter_registry: FilterRegistry) -> Self {
-        Self {
-            filter_registry,
-            ..self
-        }
+/// Validates the variants of `experiment`, whose filter chains can't be used
+/// along with the static filters if `has_filters` is set.
+fn validate_experiment(experiment: &Experiment, has_filters: bool) -> Result<(), Error> {
+    if has_filters {
+        return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+            field: "static.experiment".into(),
+            clarification: Some(
+                "the variants' filters are used instead of static.filters, which must be empty"
+                    .into(),
+            ),
+            examples: None,
+        })
+        .into());
+    }
+    let variants = &experiment.variants;
+    if variants.len() != 2 {
+        return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+            field: "static.experiment.variants".into(),
+            clarification: Some("an experiment must have exactly two variants".into()),
+            examples: None,
+        })
+        .into());
     }
 
-    /// Disable the admin interface
-    pub fn disable_admin(self) -> Self {
-        Self {
-            admin: None,
-            ..self
+    let mut names = HashSet::new();
+    for variant in variants {
+        if variant.name.is_empty() || !names.insert(&variant.name) {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "static.experiment.variants.name".into(),
+                clarification: Some("each variant must have a unique, non-empty name".into()),
+                examples: Some(vec!["control".into(), "with_firewall".into()]),
+            })
+            .into());
+        }
+    }
+    if variants.iter().all(|variant| variant.weight == 0) {
+        return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+            field: "static.experiment.variants.weight".into(),
+            clarification: Some("at least one variant must have a weight above zero".into()),
+            examples: None,
+        })
+        .into());
+    }
+    Ok(())
+}
+
+/// Validates that `secret_ref` refers to one of `secret_providers`, using
+/// `field` to refer to it in any error. The secret itself is read when it's
+/// used, so that it can be rotated.
+fn validate_secret_ref(
+    field: &str,
+    secret_ref: &SecretRef,
+    secret_providers: &SecretProviders,
+) -> Result<(), Error> {
+    if secret_providers.contains(&secret_ref.provider) {
+        return Ok(());
+    }
+    Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+        field: format!("{}.provider", field),
+        clarification: Some(format!("secret provider `{}` not found", secret_ref.provider)),
+        examples: Some(vec!["file".into(), "env".into()]),
+    })
+    .into())
+}
+
+/// Validates a list of endpoint configs, using `field` to refer to the
+/// list in any error.
+pub(super) fn validate_endpoints(
+    field: &str,
+    config_endpoints: &[EndPoint],
+) -> Result<Endpoints, Error> {
+    if config_endpoints
+        .iter()
+        .map(|ep| ep.address)
+        .collect::<HashSet<_>>()
+        .len()
+        != config_endpoints.len()
+    {
+        return Err(ValidationError::NotUnique(format!("{}.address", field)).into());
+    }
+
+    let mut endpoints = Vec::with_capacity(config_endpoints.len());
+    for ep in config_endpoints {
+        endpoints.push(Endpoint::from_config(ep).map_err(|err| {
+            ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: field.to_string(),
+                clarification: Some(format!("invalid endpoint config: {}", err)),
+                examples: None,
+            })
+        })?);
+    }
+    let endpoints = Endpoints::new(endpoints)
+        .map_err(|_empty_list_error| ValidationError::EmptyList(field.to_string()))?;
+
+    for ep in config_endpoints {
+        if let Some(ref metadata) = ep.metadata {
+            if let Err(err) = parse_endpoint_metadata_from_yaml(metadata.clone()) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: format!("{}.metadata", field),
+                    clarification: Some(err),
+                    examples: None,
+                })
+                .into());
+            }
+        }
+        if ep.weight == Some(0) {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: format!("{}.weight", field),
+                clarification: Some("the weight must be greater than 0".into()),
+                examples: Some(vec!["1".into(), "10".into()]),
+            })
+            .into());
+        }
+    }
+
+    Ok(endpoints)
+}
+
+impl Builder<PendingValidation> {
+    pub fn with_log(self, log: Logger) -> Self {
+        Self { log, ..self }
+    }
+
+    pub fn with_filter_registry(self, filter_registry: FilterRegistry) -> Self {
+        Self {
+            filter_registry,
+            ..self
+        }
+    }
+
+    /// Runs the proxy with `filter_chain`, e.g built with
+    /// [`filter_chain!`](crate::filter_chain), rather than the filters in
+    /// its static configuration.
+    pub fn with_filter_chain(self, filter_chain: Vec<StaticFilter>) -> Self {
+        Self {
+            filter_chain: Some(filter_chain),
+            ..self
+        }
+    }
+
+    /// Reads the secrets that the configuration refers to from
+    /// `secret_providers`, rather than from the default providers.
+    pub fn with_secret_providers(self, secret_providers: SecretProviders) -> Self {
+        Self {
+            secret_providers,
+            ..self
+        }
+    }
+
+    /// Disable the admin interface
+    pub fn disable_admin(self) -> Self {
+        Self {
+            admin: None,
+            ..self
         }
     }
 
     // Validates the builder's config and filter configurations.
     pub fn validate(self) -> Result<Builder<Validated>, Error> {
-        let validated_config =
-            ValidatedConfig::validate(self.config.clone(), &self.filter_registry, &self.metrics)?;
+        let filters = match &self.config.source {
+            Source::Static { filters, .. } => Some(filters),
+            Source::Dynamic { startup, .. } => startup.fallback.as_ref().map(|f| &f.filters),
+        };
+        if let Some(filters) = filters {
+            for filter in filters {
+                if let Some(replacement) = self.filter_registry.replacement_for(&filter.name) {
+                    warn!(
+                        self.log,
+                        "Filter name is deprecated and will be removed in a future release";
+                        "filter" => &filter.name,
+                        "replacement" => replacement
+                    );
+                }
+            }
+        }
+
+        let filter_registry = self
+            .filter_registry
+            .with_timeouts(self.config.proxy.filter_timeouts.clone())
+            .with_budgets(self.config.proxy.filter_budgets.clone())
+            .with_schedules(self.config.proxy.schedule.filters.clone())
+            .with_routing_cache(self.config.proxy.routing_cache.clone())
+            .with_trusted_clients(self.config.proxy.trusted_clients.clone())
+            .with_secret_providers(self.secret_providers.clone());
+        let validated_config = ValidatedConfig::validate(
+            self.config.clone(),
+            &filter_registry,
+            self.filter_chain,
+            &self.metrics,
+        )?;
 
         Ok(Builder {
             log: self.log,
             config: self.config,
             admin: self.admin,
             metrics: self.metrics,
-            filter_registry: self.filter_registry,
+            filter_registry,
+            filter_chain: None,
+            secret_providers: self.secret_providers,
             validation_status: Validated(validated_config),
         })
     }
@@ -273,6 +1405,7 @@ impl Builder<Validated> {
             admin: self.admin,
             metrics: self.metrics,
             filter_registry: self.filter_registry,
+            config_handle: ConfigHandle::default(),
         }
     }
 }
@@ -293,7 +1426,9 @@ mod tests {
     use std::sync::Arc;
 
     use crate::config::{Config, ValidationError};
+    use crate::filters::extensions::ConcatBytesFactory;
     use crate::proxy::builder::Validated;
+    use crate::test_utils::TestFilterFactory;
 
     use super::{Builder, Error};
 
@@ -359,54 +1494,1668 @@ dynamic:
                 .to_string(),
             validate_unwrap_err(yaml).to_string()
         );
+
+        let yaml = "
+# Valid management server token.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+      token:
+        provider: file
+        key: /var/run/secrets/xds/token
+  ";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# Unknown management server token provider.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+      token:
+        provider: vault
+        key: secret/xds/token
+  ";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(
+                    args.field,
+                    "dynamic.management_servers.token.provider".to_string()
+                );
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
     }
 
     #[test]
-    fn validate() {
-        // client - valid
+    fn validate_handshake_secret_ref() {
+        std::env::set_var("QUILKIN_TEST_HANDSHAKE_SECRET", "secret");
         let yaml = "
+# Valid secret reference.
 version: v1alpha1
+proxy:
+  handshake:
+    secret_ref:
+      provider: env
+      key: QUILKIN_TEST_HANDSHAKE_SECRET
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let handshake = validate_unwrap_ok(yaml)
+            .validation_status
+            .0
+            .proxy
+            .handshake
+            .unwrap();
+        assert_eq!(b"secret".to_vec(), handshake.secret);
+        assert_eq!(None, handshake.secret_ref);
+
+        let yaml = "
+# Missing secret.
+version: v1alpha1
+proxy:
+  handshake:
+    secret_ref:
+      provider: env
+      key: QUILKIN_TEST_MISSING_HANDSHAKE_SECRET
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.handshake.secret_ref".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Both a secret and a secret reference.
+version: v1alpha1
+proxy:
+  handshake:
+    secret: c2VjcmV0
+    secret_ref:
+      provider: env
+      key: QUILKIN_TEST_HANDSHAKE_SECRET
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.handshake.secret_ref".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_connection_id() {
+        let yaml = "
+# Empty prefix.
+version: v1alpha1
+proxy:
+  connection_id:
+    prefix: ''
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.connection_id.prefix".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Sessions keyed by something else.
+version: v1alpha1
+proxy:
+  connection_id: {}
+  session_key:
+    kind: IP_AND_TOKEN
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.connection_id".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        std::env::set_var("QUILKIN_TEST_CONNECTION_ID_SECRET", "secret");
+        let yaml = "
+# Valid secret reference.
+version: v1alpha1
+proxy:
+  connection_id:
+    secret_ref:
+      provider: env
+      key: QUILKIN_TEST_CONNECTION_ID_SECRET
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let connection_id = validate_unwrap_ok(yaml)
+            .validation_status
+            .0
+            .proxy
+            .connection_id
+            .unwrap();
+        assert_eq!(b"secret".to_vec(), connection_id.secret);
+        assert_eq!(None, connection_id.secret_ref);
+    }
+
+    #[test]
+    fn validate_ice() {
+        let yaml = "
+# Valid ICE credentials.
+version: v1alpha1
+proxy:
+  ice:
+    ufrag: quilkin
+    password: asd88fgpdd777uzjYhagZg
+    candidates:
+      - 203.0.113.1:7000
 static:
   endpoints:
     - address: 127.0.0.1:25999
-    - address: 127.0.0.1:25998
 ";
         let _ = validate_unwrap_ok(yaml);
 
         let yaml = "
-# Non unique addresses.
+# Password too short.
 version: v1alpha1
+proxy:
+  ice:
+    password: short
+    candidates:
+      - 203.0.113.1:7000
 static:
   endpoints:
     - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.ice.password".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# No candidates.
+version: v1alpha1
+proxy:
+  ice:
+    candidates: []
+static:
+  endpoints:
     - address: 127.0.0.1:25999
 ";
-        assert_eq!(
-            ValidationError::NotUnique("static.endpoints.address".to_string()).to_string(),
-            validate_unwrap_err(yaml).to_string()
-        );
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.ice.candidates".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
 
+    #[test]
+    fn validate_static_source_mdns() {
         let yaml = "
-# Empty endpoints list
+# Only discovered endpoints.
 version: v1alpha1
 static:
-  endpoints: []
+  mdns:
+    service_type: _game._udp
 ";
-        assert_eq!(
-            ValidationError::EmptyList("static.endpoints".to_string()).to_string(),
-            validate_unwrap_err(yaml).to_string()
-        );
+        let _ = validate_unwrap_ok(yaml);
 
         let yaml = "
-# Invalid metadata
+# Static and discovered endpoints.
 version: v1alpha1
 static:
   endpoints:
     - address: 127.0.0.1:25999
-      metadata:
-        quilkin.dev:
-          tokens: abc
+  mdns:
+    service_type: _game._udp
+    query_interval: 1s
 ";
-        let _ = validate_unwrap_err(yaml);
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# Neither static nor discovered endpoints.
+version: v1alpha1
+static:
+  filters: []
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::EmptyList(field) => {
+                assert_eq!(field, "static.endpoints".to_string());
+            }
+            err => unreachable!("expected empty list error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid service type.
+version: v1alpha1
+static:
+  mdns:
+    service_type: game.local
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "static.mdns.service_type".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Zero query interval.
+version: v1alpha1
+static:
+  mdns:
+    service_type: _game._udp
+    query_interval: 0s
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "static.mdns.query_interval".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_static_source_experiment() {
+        let yaml = "
+# Valid experiment.
+version: v1alpha1
+static:
+  experiment:
+    variants:
+      - name: control
+        weight: 9
+      - name: concatenated
+        filters:
+          - name: quilkin.extensions.filters.concatenate_bytes.v1beta1.ConcatenateBytes
+            config:
+              on_read: APPEND
+              bytes: YWJj
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        for (yaml, field) in &[
+            (
+                "
+# Static filters along with an experiment.
+version: v1alpha1
+static:
+  filters:
+    - name: quilkin.extensions.filters.debug.v1beta1.Debug
+  experiment:
+    variants:
+      - name: a
+      - name: b
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+                "static.experiment",
+            ),
+            (
+                "
+# A single variant.
+version: v1alpha1
+static:
+  experiment:
+    variants:
+      - name: a
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+                "static.experiment.variants",
+            ),
+            (
+                "
+# Duplicate variant names.
+version: v1alpha1
+static:
+  experiment:
+    variants:
+      - name: a
+      - name: a
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+                "static.experiment.variants.name",
+            ),
+            (
+                "
+# No weight.
+version: v1alpha1
+static:
+  experiment:
+    variants:
+      - name: a
+        weight: 0
+      - name: b
+        weight: 0
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+                "static.experiment.variants.weight",
+            ),
+        ] {
+            match validate_unwrap_err(yaml) {
+                ValidationError::ValueInvalid(args) => assert_eq!(&args.field, field),
+                err => unreachable!("expected invalid value error: got {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn validate_ban_gossip() {
+        let yaml = "
+# Valid ban gossip.
+version: v1alpha1
+proxy:
+  ban_gossip:
+    port: 7100
+    peers:
+      - 10.0.0.2:7100
+    secret: c2VjcmV0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# No secret.
+version: v1alpha1
+proxy:
+  ban_gossip:
+    port: 7100
+    secret: ''
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.ban_gossip.secret".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Same port as the proxy.
+version: v1alpha1
+proxy:
+  port: 7000
+  ban_gossip:
+    port: 7000
+    secret: c2VjcmV0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.ban_gossip.port".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Zero duration.
+version: v1alpha1
+proxy:
+  ban_gossip:
+    port: 7100
+    secret: c2VjcmV0
+    duration: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.ban_gossip.duration".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Zero sweep interval.
+version: v1alpha1
+proxy:
+  ban_gossip:
+    port: 7100
+    secret: c2VjcmV0
+    sweep_interval: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.ban_gossip.sweep_interval".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn validate_port_conflict_secret() {
+        let yaml = "
+# Valid take over.
+version: v1alpha1
+proxy:
+  port_conflict_policy: TAKE_OVER
+  port_conflict_secret: c2VjcmV0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# No secret.
+version: v1alpha1
+proxy:
+  port_conflict_policy: TAKE_OVER
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.port_conflict_secret".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_socks5() {
+        let yaml = "
+# Valid SOCKS5 listener.
+version: v1alpha1
+proxy:
+  socks5:
+    port: 1080
+    max_associations: 64
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# No associations allowed.
+version: v1alpha1
+proxy:
+  socks5:
+    port: 1080
+    max_associations: 0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.socks5.max_associations".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_admin() {
+        let yaml = "
+# Privileged endpoints served on the local socket.
+version: v1alpha1
+admin:
+  local_socket: /run/quilkin/admin.sock
+  privileged_endpoints: true
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# Privileged endpoints served over TCP with a token.
+version: v1alpha1
+admin:
+  address: 127.0.0.1:9091
+  privileged_endpoints: true
+  token:
+    provider: env
+    key: QUILKIN_ADMIN_TOKEN
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# Privileged endpoints served over TCP without a token.
+version: v1alpha1
+admin:
+  address: 127.0.0.1:9091
+  privileged_endpoints: true
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "admin.privileged_endpoints".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Unknown secret provider.
+version: v1alpha1
+admin:
+  privileged_endpoints: true
+  token:
+    provider: vault
+    key: quilkin/admin
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "admin.token.provider".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_standby() {
+        let yaml = "
+# Valid standby.
+version: v1alpha1
+proxy:
+  standby:
+    port: 7200
+    peer: 10.0.0.2:7200
+    secret: c2VjcmV0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# No secret.
+version: v1alpha1
+proxy:
+  standby:
+    port: 7200
+    peer: 10.0.0.2:7200
+    secret: ''
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.standby.secret".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Same port as the proxy.
+version: v1alpha1
+proxy:
+  port: 7000
+  standby:
+    port: 7000
+    peer: 10.0.0.2:7000
+    secret: c2VjcmV0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.standby.port".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Failover timeout no longer than the heartbeat interval.
+version: v1alpha1
+proxy:
+  standby:
+    port: 7200
+    peer: 10.0.0.2:7200
+    secret: c2VjcmV0
+    heartbeat_interval: 500ms
+    failover_timeout: 500ms
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.standby.failover_timeout".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Zero sync interval.
+version: v1alpha1
+proxy:
+  standby:
+    port: 7200
+    peer: 10.0.0.2:7200
+    secret: c2VjcmV0
+    sync_interval: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.standby.sync_interval".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_metrics_relabel() {
+        let yaml = "
+# Valid relabeling.
+version: v1alpha1
+proxy:
+  metrics:
+    allow:
+      - quilkin_proxy_*
+    relabel:
+      - prefix: quilkin_
+        replacement: mygame_
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# Invalid replacement.
+version: v1alpha1
+proxy:
+  metrics:
+    relabel:
+      - prefix: quilkin_
+        replacement: my-game_
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.metrics.relabel".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Empty pattern.
+version: v1alpha1
+proxy:
+  metrics:
+    deny:
+      - ''
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.metrics.deny".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_routing_cache() {
+        let yaml = "
+# Valid routing cache.
+version: v1alpha1
+proxy:
+  routing_cache:
+    filters:
+      - quilkin.extensions.filters.token_router.v1beta1.TokenRouter
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# No filters.
+version: v1alpha1
+proxy:
+  routing_cache:
+    filters: []
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.routing_cache.filters".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Zero TTL.
+version: v1alpha1
+proxy:
+  routing_cache:
+    filters:
+      - quilkin.extensions.filters.token_router.v1beta1.TokenRouter
+    ttl: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.routing_cache.ttl".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_trusted_clients() {
+        let yaml = "
+# Valid trusted clients.
+version: v1alpha1
+proxy:
+  trusted_clients:
+    addresses:
+      - 10.0.0.0/8
+    filters:
+      - quilkin.extensions.filters.local_rate_limit.v1beta1.LocalRateLimit
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# No addresses.
+version: v1alpha1
+proxy:
+  trusted_clients:
+    addresses: []
+    filters:
+      - quilkin.extensions.filters.local_rate_limit.v1beta1.LocalRateLimit
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.trusted_clients.addresses".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# No filters.
+version: v1alpha1
+proxy:
+  trusted_clients:
+    addresses:
+      - 10.0.0.0/8
+    filters: []
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.trusted_clients.filters".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_dynamic_source_startup() {
+        let yaml = "
+# Valid fallback configuration.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  startup:
+    policy: FALLBACK
+    timeout: 10s
+    fallback:
+      endpoints:
+        - address: 127.0.0.1:26000
+  ";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# Missing fallback configuration.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  startup:
+    policy: FALLBACK
+  ";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "dynamic.startup.fallback".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Empty fallback endpoints.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  startup:
+    policy: FALLBACK
+    fallback:
+      endpoints: []
+  ";
+        assert_eq!(
+            ValidationError::EmptyList("dynamic.startup.fallback.endpoints".to_string())
+                .to_string(),
+            validate_unwrap_err(yaml).to_string()
+        );
+
+        let yaml = "
+# Zero timeout.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  startup:
+    policy: EXIT
+    timeout: 0s
+  ";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "dynamic.startup.timeout".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_dynamic_source_failover() {
+        let yaml = "
+# Valid failover configuration.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  failover:
+    cluster: us-west1
+    failback_delay: 1m
+  ";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# Empty failover cluster name.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  failover:
+    cluster: ''
+  ";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "dynamic.failover.cluster".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_dynamic_source_endpoint_update_guard() {
+        let yaml = "
+# Valid endpoint update guard.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  endpoint_update_guard:
+    max_removal_percent: 25
+    policy: PHASE_IN
+    phase_in_interval: 30s
+  ";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# Percentage out of range.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  endpoint_update_guard:
+    max_removal_percent: 101
+  ";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(
+                    args.field,
+                    "dynamic.endpoint_update_guard.max_removal_percent".to_string()
+                );
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Zero phase in interval.
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+  endpoint_update_guard:
+    max_removal_percent: 25
+    phase_in_interval: 0s
+  ";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(
+                    args.field,
+                    "dynamic.endpoint_update_guard.phase_in_interval".to_string()
+                );
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate() {
+        // client - valid
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+    - address: 127.0.0.1:25998
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+# Non unique addresses.
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+    - address: 127.0.0.1:25999
+";
+        assert_eq!(
+            ValidationError::NotUnique("static.endpoints.address".to_string()).to_string(),
+            validate_unwrap_err(yaml).to_string()
+        );
+
+        let yaml = "
+# Empty endpoints list
+version: v1alpha1
+static:
+  endpoints: []
+";
+        assert_eq!(
+            ValidationError::EmptyList("static.endpoints".to_string()).to_string(),
+            validate_unwrap_err(yaml).to_string()
+        );
+
+        let yaml = "
+# Invalid metadata
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+      metadata:
+        quilkin.dev:
+          tokens: abc
+";
+        let _ = validate_unwrap_err(yaml);
+
+        let yaml = "
+# Invalid max packet size
+version: v1alpha1
+proxy:
+  max_packet_size: 0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.max_packet_size".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Max packet size larger than a datagram
+version: v1alpha1
+proxy:
+  max_packet_size: 65536
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.max_packet_size".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid failover buffer size
+version: v1alpha1
+proxy:
+  failover_buffer:
+    max_packets: 0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.failover_buffer.max_packets".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid connection tracker timeout
+version: v1alpha1
+proxy:
+  connection_tracker:
+    address: http://127.0.0.1:9000
+    timeout: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.connection_tracker.timeout".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid handshake cookie lifetime
+version: v1alpha1
+proxy:
+  handshake:
+    cookie_lifetime: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.handshake.cookie_lifetime".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid compute pool threads
+version: v1alpha1
+proxy:
+  compute_pool:
+    heavy_filters: []
+    threads: 0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.compute_pool.threads".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Audit log without a destination
+version: v1alpha1
+proxy:
+  audit_log: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.audit_log".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Response-only endpoint that is also a static endpoint
+version: v1alpha1
+proxy:
+  response_only_endpoints:
+    - address: 127.0.0.1:25999
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(
+                    args.field,
+                    "proxy.response_only_endpoints.address".to_string()
+                );
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid fair queue size
+version: v1alpha1
+proxy:
+  fair_queue:
+    queue_size: 0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.fair_queue.queue_size".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid client version length
+version: v1alpha1
+proxy:
+  client_version:
+    offset: 4
+    length: 0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.client_version.length".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid ordered sends queue size
+version: v1alpha1
+proxy:
+  ordered_sends:
+    queue_size: 0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.ordered_sends.queue_size".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Ordered sends with a fair queue
+version: v1alpha1
+proxy:
+  ordered_sends: {}
+  fair_queue: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.ordered_sends".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Zero analyzer window
+version: v1alpha1
+proxy:
+  analyzer:
+    window: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.analyzer.window".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Zero burst detection window
+version: v1alpha1
+proxy:
+  burst_detection:
+    window: 0s
+    max_packets: 20
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.burst_detection.window".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid burst detection webhook
+version: v1alpha1
+proxy:
+  burst_detection:
+    max_packets: 20
+    webhook: alerts:8080
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.burst_detection.webhook".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid decision log sample rate
+version: v1alpha1
+proxy:
+  decision_log:
+    sample_rate: 1.5
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.decision_log.sample_rate".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Telemetry without management servers
+version: v1alpha1
+proxy:
+  telemetry: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.telemetry".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid telemetry interval
+version: v1alpha1
+proxy:
+  telemetry:
+    interval: 10ms
+dynamic:
+  management_servers:
+    - address: http://127.0.0.1:18000
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.telemetry.interval".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid upstream socket TTL
+version: v1alpha1
+proxy:
+  upstream_socket:
+    ttl: 256
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.upstream_socket.ttl".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid filter timeout
+version: v1alpha1
+proxy:
+  filter_timeouts:
+    - filter: quilkin.extensions.filters.debug.v1beta1.Debug
+      timeout: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.filter_timeouts.timeout".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid filter budget period
+version: v1alpha1
+proxy:
+  filter_budgets:
+    - filter: quilkin.extensions.filters.debug.v1beta1.Debug
+      period: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.filter_budgets.period".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid filter budget CPU time
+version: v1alpha1
+proxy:
+  filter_budgets:
+    - filter: quilkin.extensions.filters.debug.v1beta1.Debug
+      cpu_time: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.filter_budgets.cpu_time".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Empty activation window
+version: v1alpha1
+proxy:
+  schedule:
+    endpoints:
+      - addresses: [127.0.0.1:25999]
+        active:
+          - start: 02:00
+            end: 02:00
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.schedule.endpoints.active".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# No activation windows
+version: v1alpha1
+proxy:
+  schedule:
+    filters:
+      - filter: quilkin.extensions.filters.debug.v1beta1.Debug
+        active: []
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.schedule.filters.active".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid resource check interval
+version: v1alpha1
+proxy:
+  resource_limits:
+    max_open_fds: 60000
+    check_interval: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(
+                    args.field,
+                    "proxy.resource_limits.check_interval".to_string()
+                );
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid endpoint health check
+version: v1alpha1
+proxy:
+  endpoint_health_check:
+    max_send_failures: 0
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(
+                    args.field,
+                    "proxy.endpoint_health_check.max_send_failures".to_string()
+                );
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid endpoint slow start
+version: v1alpha1
+proxy:
+  endpoint_slow_start:
+    window: 0s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.endpoint_slow_start.window".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid fault injection
+version: v1alpha1
+proxy:
+  faults:
+    send_failure_rate: 2
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.faults.send_failure_rate".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid endpoint weight
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+      weight: 0
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "static.endpoints.weight".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Histogram buckets out of order
+version: v1alpha1
+proxy:
+  metrics:
+    histogram_buckets:
+      latency: [0.001, 0.0001]
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(
+                    args.field,
+                    "proxy.metrics.histogram_buckets.latency".to_string()
+                );
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Invalid metrics push URL
+version: v1alpha1
+proxy:
+  metrics_push:
+    url: pushgateway:9091
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.metrics_push.url".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+
+        let yaml = "
+# Tunnel peer address without a port
+version: v1alpha1
+proxy:
+  tunnel_peer:
+    address: relay.example.com
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "proxy.tunnel_peer.address".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
+    }
+
+    #[test]
+    fn validate_filter_chain() {
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .with_filter_chain(crate::filter_chain![TestFilterFactory {}])
+            .validate()
+            .unwrap();
+
+        // Filters are still validated when they are created.
+        let err = Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .with_filter_chain(crate::filter_chain![ConcatBytesFactory::default()])
+            .validate()
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::CreateFilterChain(_)));
+
+        let yaml = "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+";
+        let err = Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .with_filter_chain(crate::filter_chain![TestFilterFactory {}])
+            .validate()
+            .err()
+            .unwrap();
+        match err {
+            Error::InvalidConfig(ValidationError::ValueInvalid(args)) => {
+                assert_eq!(args.field, "dynamic".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
     }
 }
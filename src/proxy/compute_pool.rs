@@ -0,0 +1,150 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::ComputePool as ComputePoolConfig;
+use crate::filters::FilterChain;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A pool of threads that filter chains containing heavy filters run on, so
+/// that they don't delay packets going through other filter chains on the
+/// async runtime.
+pub struct ComputePool {
+    heavy_filters: Vec<String>,
+    jobs: mpsc::Sender<Job>,
+}
+
+impl ComputePool {
+    /// Returns a new ComputePool, spawning its threads. The threads exit once
+    /// the pool is dropped.
+    pub fn new(config: ComputePoolConfig) -> Self {
+        let (jobs, jobs_rx) = mpsc::channel::<Job>(config.queue_size);
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+        for id in 0..config.threads {
+            let jobs_rx = jobs_rx.clone();
+            std::thread::Builder::new()
+                .name(format!("quilkin-compute-{}", id))
+                .spawn(move || loop {
+                    let job = match jobs_rx.lock().blocking_recv() {
+                        Some(job) => job,
+                        None => return,
+                    };
+                    // A panicking filter only fails its own packet. The
+                    // panic is still reported by the proxy's panic hook.
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                })
+                .expect("failed to spawn compute pool thread");
+        }
+
+        Self {
+            heavy_filters: config.heavy_filters,
+            jobs,
+        }
+    }
+
+    /// Returns whether `filter_chain` should run on the pool.
+    pub fn is_heavy(&self, filter_chain: &FilterChain) -> bool {
+        filter_chain.contains_any(&self.heavy_filters)
+    }
+
+    /// Runs `f` on the pool and returns its result. Returns `None` without
+    /// running `f` if the pool's queue is full, or if `f` panics.
+    pub async fn run<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_tx, result_rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // The caller may have stopped waiting for the result.
+            let _ = result_tx.send(f());
+        });
+        if self.jobs.try_send(job).is_err() {
+            return None;
+        }
+        result_rx.await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::ComputePool;
+    use crate::config::ComputePool as ComputePoolConfig;
+
+    fn compute_pool(threads: usize, queue_size: usize) -> ComputePool {
+        ComputePool::new(ComputePoolConfig {
+            heavy_filters: vec![],
+            threads,
+            queue_size,
+        })
+    }
+
+    #[tokio::test]
+    async fn run() {
+        let pool = compute_pool(2, 4);
+        let runtime_thread = thread::current().id();
+        let pool_thread = pool.run(|| thread::current().id()).await.unwrap();
+        assert_ne!(runtime_thread, pool_thread);
+
+        assert_eq!(None, pool.run(|| -> u8 { panic!("oh no!") }).await);
+        // The pool still runs jobs after one panicked.
+        assert_eq!(Some(2), pool.run(|| 1 + 1).await);
+    }
+
+    #[tokio::test]
+    async fn run_queue_full() {
+        let pool = Arc::new(compute_pool(1, 1));
+
+        // Block the only thread.
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let blocked = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                pool.run(move || {
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                })
+                .await
+            }
+        });
+        tokio::task::spawn_blocking(move || started_rx.recv().unwrap())
+            .await
+            .unwrap();
+
+        // Fill the queue.
+        let queued = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|| 1).await }
+        });
+        tokio::task::yield_now().await;
+
+        assert_eq!(None, pool.run(|| 2).await);
+
+        release_tx.send(()).unwrap();
+        assert_eq!(Some(()), blocked.await.unwrap());
+        assert_eq!(Some(1), queued.await.unwrap());
+    }
+}
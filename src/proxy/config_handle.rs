@@ -0,0 +1,262 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use prometheus::Registry;
+
+use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::config::{EndPoint, Endpoints, Filter as FilterConfig};
+use crate::filters::{manager::SharedFilterManager, FilterChain, FilterRegistry};
+use crate::proxy::builder::{validate_endpoints, Error as BuilderError};
+
+/// The changes to make to the config of a running proxy in a single update.
+/// Parts that are `None` are left as they are.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigUpdate {
+    /// The endpoints that packets are sent to, along with their metadata.
+    pub endpoints: Option<Vec<EndPoint>>,
+    /// The filters that packets are processed by.
+    pub filters: Option<Vec<FilterConfig>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpdateError {
+    #[error("the proxy isn't running with a static config")]
+    Unavailable,
+    #[error("invalid update: {}", .0)]
+    Invalid(BuilderError),
+    #[error("there is no update to roll back")]
+    NothingToRollBack,
+}
+
+impl From<BuilderError> for UpdateError {
+    fn from(err: BuilderError) -> Self {
+        UpdateError::Invalid(err)
+    }
+}
+
+/// A handle for embedders to update the endpoints and filter chain of a
+/// running proxy with a static config. Each update is validated as a whole
+/// before any of it is applied, and is applied at once, so that no packet
+/// is processed with the filter chain of one update and the endpoints of
+/// another. The last update can be rolled back.
+///
+/// **Note:** Cloning [`ConfigHandle`] is shallow, clones update the same
+/// proxy.
+#[derive(Clone, Default)]
+pub struct ConfigHandle {
+    /// The proxy's resources, once it has started with a static config.
+    resources: Arc<Mutex<Option<Resources>>>,
+}
+
+/// The resources of a running proxy that a [`ConfigHandle`] updates.
+struct Resources {
+    cluster_manager: SharedClusterManager,
+    filter_manager: SharedFilterManager,
+    filter_registry: FilterRegistry,
+    metrics_registry: Registry,
+    /// The resources that were replaced by the last update, if it hasn't
+    /// been rolled back.
+    previous: Option<Snapshot>,
+}
+
+/// The endpoints and filter chain of a proxy at a point in time.
+struct Snapshot {
+    endpoints: Option<Endpoints>,
+    filter_chain: Arc<FilterChain>,
+}
+
+impl ConfigHandle {
+    /// Attaches the handle to the resources of a proxy that has started.
+    pub(crate) fn attach(
+        &self,
+        cluster_manager: SharedClusterManager,
+        filter_manager: SharedFilterManager,
+        filter_registry: FilterRegistry,
+        metrics_registry: Registry,
+    ) {
+        *self.resources.lock() = Some(Resources {
+            cluster_manager,
+            filter_manager,
+            filter_registry,
+            metrics_registry,
+            previous: None,
+        });
+    }
+
+    /// Applies `update` to the proxy. If any part of it is invalid, none of
+    /// it is applied.
+    pub fn update(&self, update: ConfigUpdate) -> Result<(), UpdateError> {
+        let mut resources = self.resources.lock();
+        let resources = resources.as_mut().ok_or(UpdateError::Unavailable)?;
+
+        let endpoints = update
+            .endpoints
+            .map(|endpoints| validate_endpoints("endpoints", &endpoints))
+            .transpose()?;
+        let filter_chain = update
+            .filters
+            .map(|filters| {
+                FilterChain::try_create(
+                    filters,
+                    &resources.filter_registry,
+                    &resources.metrics_registry,
+                )
+                .map(Arc::new)
+            })
+            .transpose()
+            .map_err(BuilderError::from)?;
+
+        let previous = resources.replace(endpoints.map(Some), filter_chain);
+        resources.previous = Some(previous);
+        Ok(())
+    }
+
+    /// Restores the endpoints and filter chain that the last update
+    /// replaced. An update can only be rolled back once.
+    pub fn rollback(&self) -> Result<(), UpdateError> {
+        let mut resources = self.resources.lock();
+        let resources = resources.as_mut().ok_or(UpdateError::Unavailable)?;
+        let previous = resources
+            .previous
+            .take()
+            .ok_or(UpdateError::NothingToRollBack)?;
+        resources.replace(Some(previous.endpoints), Some(previous.filter_chain));
+        Ok(())
+    }
+}
+
+impl Resources {
+    /// Replaces the parts of the proxy's resources that are set, returning
+    /// a snapshot of them as they were. The cluster manager is locked
+    /// before the filter manager, in the order packets read them in.
+    fn replace(
+        &self,
+        endpoints: Option<Option<Endpoints>>,
+        filter_chain: Option<Arc<FilterChain>>,
+    ) -> Snapshot {
+        let mut cluster_manager = self.cluster_manager.write();
+        let mut filter_manager = self.filter_manager.write();
+        let previous = Snapshot {
+            endpoints: cluster_manager.endpoints().cloned(),
+            filter_chain: filter_manager.get_filter_chain(),
+        };
+        if let Some(endpoints) = endpoints {
+            cluster_manager.set_endpoints(endpoints);
+        }
+        if let Some(filter_chain) = filter_chain {
+            filter_manager.update(filter_chain);
+        }
+        previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use prometheus::Registry;
+
+    use super::{ConfigHandle, ConfigUpdate, UpdateError};
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::{EndPoint, Endpoints, Filter as FilterConfig};
+    use crate::filters::{manager::FilterManager, FilterChain};
+    use crate::test_utils::{logger, new_registry};
+
+    fn addresses(handle: &ConfigHandle) -> Vec<String> {
+        let resources = handle.resources.lock();
+        let cluster_manager = resources.as_ref().unwrap().cluster_manager.read();
+        cluster_manager
+            .get_all_endpoints()
+            .unwrap()
+            .iter()
+            .map(|endpoint| endpoint.address.to_string())
+            .collect()
+    }
+
+    fn has_test_filter(handle: &ConfigHandle) -> bool {
+        let resources = handle.resources.lock();
+        let filter_manager = resources.as_ref().unwrap().filter_manager.read();
+        filter_manager
+            .get_filter_chain()
+            .contains_any(&["TestFilter".into()])
+    }
+
+    #[test]
+    fn update_and_rollback() {
+        let handle = ConfigHandle::default();
+        assert!(matches!(
+            handle.update(ConfigUpdate::default()),
+            Err(UpdateError::Unavailable)
+        ));
+
+        let registry = Registry::default();
+        handle.attach(
+            ClusterManager::fixed(
+                &registry,
+                Endpoints::new(vec![Endpoint::from_address(
+                    "127.0.0.1:7001".parse().unwrap(),
+                )])
+                .unwrap(),
+            )
+            .unwrap(),
+            FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
+            new_registry(&logger()),
+            registry,
+        );
+        assert!(matches!(
+            handle.rollback(),
+            Err(UpdateError::NothingToRollBack)
+        ));
+
+        handle
+            .update(ConfigUpdate {
+                endpoints: Some(vec![EndPoint::new("127.0.0.1:7002".parse().unwrap())]),
+                filters: Some(vec![FilterConfig {
+                    name: "TestFilter".into(),
+                    config: None,
+                }]),
+            })
+            .unwrap();
+        assert_eq!(vec!["127.0.0.1:7002"], addresses(&handle));
+        assert!(has_test_filter(&handle));
+
+        // Nothing is applied if any part of an update is invalid.
+        let err = handle
+            .update(ConfigUpdate {
+                endpoints: Some(vec![EndPoint::new("127.0.0.1:7003".parse().unwrap())]),
+                filters: Some(vec![FilterConfig {
+                    name: "MissingFilter".into(),
+                    config: None,
+                }]),
+            })
+            .unwrap_err();
+        assert!(matches!(err, UpdateError::Invalid(_)));
+        assert_eq!(vec!["127.0.0.1:7002"], addresses(&handle));
+        assert!(has_test_filter(&handle));
+
+        handle.rollback().unwrap();
+        assert_eq!(vec!["127.0.0.1:7001"], addresses(&handle));
+        assert!(!has_test_filter(&handle));
+        assert!(matches!(
+            handle.rollback(),
+            Err(UpdateError::NothingToRollBack)
+        ));
+    }
+}
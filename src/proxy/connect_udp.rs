@@ -0,0 +1,590 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Accepts UDP proxying requests over HTTP/1.1, as specified by RFC 9298
+//! (CONNECT-UDP, part of MASQUE), so that clients can reach the proxy through
+//! standards compliant HTTP proxies and networking stacks rather than a
+//! custom SDK.
+//!
+//! A client sends a `GET` request for
+//! `/.well-known/masque/udp/{target_host}/{target_port}/` that upgrades the
+//! connection to `connect-udp`, after which UDP payloads are exchanged in
+//! DATAGRAM capsules (RFC 9297). Whatever the target, the payloads sent by
+//! the client are handed to the proxy's workers as if it had received them
+//! over UDP from the address the client connected from, so that they go
+//! through the filter chain to endpoints like any other packet.
+
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, UPGRADE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use slog::{debug, o, warn, Logger};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::ConnectUdp;
+use crate::proxy::relayed::RelayedClients;
+use crate::proxy::server::metrics::Metrics as ProxyMetrics;
+use crate::proxy::tunnel::tls_acceptor;
+
+/// The path that requests are accepted on, followed by the target host and
+/// port.
+const PATH_PREFIX: &str = "/.well-known/masque/udp/";
+
+/// The protocol that connections are upgraded to.
+const PROTOCOL: &str = "connect-udp";
+
+/// The header announcing that the capsule protocol is used.
+const CAPSULE_PROTOCOL: &str = "capsule-protocol";
+
+/// The type of the capsules carrying HTTP datagrams.
+const DATAGRAM_CAPSULE: u64 = 0x00;
+
+/// The context ID of the HTTP datagrams carrying UDP payloads.
+const UDP_PAYLOAD_CONTEXT: u64 = 0;
+
+/// The length of the largest capsule accepted from clients: a UDP payload of
+/// the largest size along with its context ID.
+const MAX_CAPSULE_LENGTH: u64 = 65535 + 8;
+
+/// How long a client has to complete the TLS handshake after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts UDP proxying requests, handing the payloads of each to the
+/// proxy's workers.
+pub(crate) struct Listener {
+    log: Logger,
+    acceptor: Option<TlsAcceptor>,
+    clients: RelayedClients,
+    metrics: ProxyMetrics,
+    /// How long a stream can go without a payload from the client before it
+    /// is closed.
+    idle_timeout: Duration,
+}
+
+impl Listener {
+    pub(crate) fn new(
+        log: Logger,
+        config: &ConnectUdp,
+        clients: RelayedClients,
+        metrics: ProxyMetrics,
+        idle_timeout: Duration,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            log,
+            acceptor: config.tls.as_ref().map(tls_acceptor).transpose()?,
+            clients,
+            metrics,
+            idle_timeout,
+        })
+    }
+
+    /// Accepts connections on `listener` in the background until
+    /// `shutdown_rx` is notified.
+    pub(crate) fn run(self, listener: TcpListener, mut shutdown_rx: watch::Receiver<()>) {
+        let listener_ref = Arc::new(self);
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, from)) => {
+                            listener_ref.clone().accept(stream, from, shutdown_rx.clone())
+                        }
+                        Err(err) => {
+                            warn!(listener_ref.log, "Failed to accept connection"; "error" => %err)
+                        }
+                    },
+                    _ = shutdown_rx.changed() => return,
+                }
+            }
+        });
+    }
+
+    /// Serves the requests sent over `stream` in the background.
+    fn accept(
+        self: Arc<Self>,
+        stream: TcpStream,
+        from: SocketAddr,
+        shutdown_rx: watch::Receiver<()>,
+    ) {
+        tokio::spawn(async move {
+            let log = self.log.new(o!("connect_udp_from" => from));
+            let served = match &self.acceptor {
+                Some(acceptor) => {
+                    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => self.serve(&log, stream, from, shutdown_rx).await,
+                        Ok(Err(err)) => Err(err),
+                        Err(_) => Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "the TLS handshake timed out",
+                        )),
+                    }
+                }
+                None => self.serve(&log, stream, from, shutdown_rx).await,
+            };
+            if let Err(err) = served {
+                debug!(log, "UDP proxying connection failed"; "error" => %err);
+            }
+        });
+    }
+
+    async fn serve<S>(
+        self: &Arc<Self>,
+        log: &Logger,
+        stream: S,
+        from: SocketAddr,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let listener_ref = self.clone();
+        let log = log.clone();
+        let service = service_fn(move |request| {
+            let response = listener_ref.handle_request(&log, request, from, shutdown_rx.clone());
+            async move { Ok::<_, Infallible>(response) }
+        });
+        Http::new()
+            .serve_connection(stream, service)
+            .with_upgrades()
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+
+    /// Responds to `request` from the client at `from`, forwarding payloads
+    /// in the background once the connection has been upgraded if it is a
+    /// valid UDP proxying request.
+    fn handle_request(
+        self: &Arc<Self>,
+        log: &Logger,
+        request: Request<Body>,
+        from: SocketAddr,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> Response<Body> {
+        let (host, port) = match parse_target(request.uri().path()) {
+            Some(target) => target,
+            None => return self.reject(StatusCode::NOT_FOUND),
+        };
+        if request.method() != Method::GET
+            || !has_token(&request, UPGRADE, PROTOCOL)
+            || !has_token(&request, CONNECTION, "upgrade")
+        {
+            return self.reject(StatusCode::BAD_REQUEST);
+        }
+
+        self.metrics.connect_udp_accepted.inc();
+        let log = log.new(o!("target_host" => host, "target_port" => port));
+        debug!(log, "Accepted UDP proxying request");
+        let listener_ref = self.clone();
+        tokio::spawn(async move {
+            let upgraded = match hyper::upgrade::on(request).await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    debug!(log, "Failed to upgrade connection"; "error" => %err);
+                    return;
+                }
+            };
+            listener_ref.metrics.active_connect_udp_streams.inc();
+            match listener_ref.forward(upgraded, from, shutdown_rx).await {
+                Ok(()) => debug!(log, "UDP proxying stream closed"),
+                Err(err) => {
+                    debug!(log, "UDP proxying stream closed with an error"; "error" => %err)
+                }
+            }
+            listener_ref.metrics.active_connect_udp_streams.dec();
+        });
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        let headers = response.headers_mut();
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, HeaderValue::from_static(PROTOCOL));
+        headers.insert(
+            HeaderName::from_static(CAPSULE_PROTOCOL),
+            HeaderValue::from_static("?1"),
+        );
+        response
+    }
+
+    fn reject(&self, status: StatusCode) -> Response<Body> {
+        self.metrics.connect_udp_rejected.inc();
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        response
+    }
+
+    /// Forwards payloads between the stream and the proxy until either the
+    /// client closes the stream or goes idle, or `shutdown_rx` is notified.
+    /// The proxy sees the payloads as coming from `from`, the address the
+    /// client connected from.
+    async fn forward(
+        &self,
+        upgraded: Upgraded,
+        from: SocketAddr,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) -> io::Result<()> {
+        let (association, mut responses) = self.clients.register(from);
+        let (reader, mut writer) = tokio::io::split(upgraded);
+
+        // Payloads from the client are forwarded on a task of their own,
+        // since waiting for a capsule can't be cancelled without losing its
+        // start.
+        let idle_timeout = self.idle_timeout;
+        let mut from_client = tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let (capsule_type, value) =
+                    match time::timeout(idle_timeout, read_capsule(&mut reader)).await {
+                        Ok(Ok(Some(capsule))) => capsule,
+                        Ok(Ok(None)) | Err(_) => return Ok(()),
+                        Ok(Err(err)) => return Err(err),
+                    };
+                // Capsules of other types are ignored, as are datagrams with
+                // context IDs other than the one for UDP payloads.
+                if capsule_type != DATAGRAM_CAPSULE {
+                    continue;
+                }
+                let payload = match decode_varint(&value) {
+                    Some((UDP_PAYLOAD_CONTEXT, len)) => &value[len..],
+                    _ => continue,
+                };
+                if !association.send(payload.to_vec()).await {
+                    return Ok(());
+                }
+            }
+        });
+
+        let result = loop {
+            select! {
+                Some(packet) = responses.recv() => {
+                    if let Err(err) = write_datagram(&mut writer, &packet).await {
+                        break Err(err);
+                    }
+                }
+                result = &mut from_client => {
+                    break result
+                        .unwrap_or_else(|err| Err(io::Error::new(io::ErrorKind::Other, err)));
+                }
+                _ = shutdown_rx.changed() => break Ok(()),
+            }
+        };
+        from_client.abort();
+        result
+    }
+}
+
+/// Returns the target host and port of a request for `path`, or `None` if it
+/// isn't a UDP proxying path.
+fn parse_target(path: &str) -> Option<(String, u16)> {
+    let mut segments = path.strip_prefix(PATH_PREFIX)?.split('/');
+    let host = segments.next().filter(|host| !host.is_empty())?;
+    let port = segments.next()?.parse().ok()?;
+    match (segments.next(), segments.next()) {
+        (None, _) | (Some(""), None) => Some((percent_decode(host)?, port)),
+        _ => None,
+    }
+}
+
+/// Decodes the percent-encoded characters in `value`, e.g the colons of an
+/// IPv6 address.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Returns whether the `header` of `request` contains `token`.
+fn has_token(request: &Request<Body>, header: HeaderName, token: &str) -> bool {
+    request
+        .headers()
+        .get_all(header)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// Writes a DATAGRAM capsule containing the UDP payload `payload`.
+async fn write_datagram<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    let mut context = vec![];
+    encode_varint(UDP_PAYLOAD_CONTEXT, &mut context);
+
+    let mut capsule = Vec::with_capacity(payload.len() + 8);
+    encode_varint(DATAGRAM_CAPSULE, &mut capsule);
+    encode_varint((context.len() + payload.len()) as u64, &mut capsule);
+    capsule.extend_from_slice(&context);
+    capsule.extend_from_slice(payload);
+    writer.write_all(&capsule).await?;
+    writer.flush().await
+}
+
+/// Reads a capsule, returning its type and value or `None` if the stream was
+/// closed.
+async fn read_capsule<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let capsule_type = match read_varint(reader).await? {
+        Some(capsule_type) => capsule_type,
+        None => return Ok(None),
+    };
+    let length = read_varint(reader)
+        .await?
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    if length > MAX_CAPSULE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the capsule is too large",
+        ));
+    }
+    let mut value = vec![0; length as usize];
+    reader.read_exact(&mut value).await?;
+    Ok(Some((capsule_type, value)))
+}
+
+/// Reads a variable-length integer as encoded by QUIC, or returns `None` if
+/// the stream was closed.
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<u64>> {
+    let first = match reader.read_u8().await {
+        Ok(first) => first,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut value = u64::from(first & 0x3f);
+    for _ in 1..varint_len(first) {
+        value = (value << 8) | u64::from(reader.read_u8().await?);
+    }
+    Ok(Some(value))
+}
+
+/// Decodes the variable-length integer that `buf` starts with, returning it
+/// along with its length.
+fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = varint_len(first);
+    let value = buf
+        .get(1..len)?
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, byte| {
+            (value << 8) | u64::from(*byte)
+        });
+    Some((value, len))
+}
+
+/// Returns the length of the variable-length integer starting with `first`.
+fn varint_len(first: u8) -> usize {
+    1 << (first >> 6)
+}
+
+/// Appends `value`, which must be less than 2^62, to `buf` as a
+/// variable-length integer.
+fn encode_varint(value: u64, buf: &mut Vec<u8>) {
+    if value < 1 << 6 {
+        buf.push(value as u8);
+    } else if value < 1 << 14 {
+        buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes());
+    } else if value < 1 << 30 {
+        buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes());
+    } else {
+        buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use prometheus::Registry;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio::time::{timeout, Duration};
+
+    use super::{
+        decode_varint, encode_varint, parse_target, read_capsule, write_datagram, Listener,
+        DATAGRAM_CAPSULE,
+    };
+    use crate::config::ConnectUdp;
+    use crate::proxy::relayed::RelayedClients;
+    use crate::proxy::server::metrics::Metrics as ProxyMetrics;
+    use crate::test_utils::TestHelper;
+
+    /// Runs a listener whose packets are echoed back by the proxy. Returns
+    /// the listener's address and metrics, and the addresses that the proxy
+    /// received packets from.
+    async fn run_listener(
+        t: &mut TestHelper,
+    ) -> (
+        SocketAddr,
+        ProxyMetrics,
+        mpsc::UnboundedReceiver<SocketAddr>,
+    ) {
+        let metrics = ProxyMetrics::new(&Registry::default()).unwrap();
+        let (clients, mut packets) = RelayedClients::new();
+        let (from_tx, from_rx) = mpsc::unbounded_channel();
+        let echo = clients.clone();
+        tokio::spawn(async move {
+            while let Some((from, contents, _)) = packets.recv().await {
+                echo.deliver(from, &contents);
+                let _ = from_tx.send(from);
+            }
+        });
+
+        let listener = Listener::new(
+            t.log.clone(),
+            &ConnectUdp { port: 0, tls: None },
+            clients,
+            metrics.clone(),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        listener.run(socket, t.get_shutdown_subscriber().await);
+        (address, metrics, from_rx)
+    }
+
+    /// Sends `request` and returns the head of the response.
+    async fn send_request(stream: &mut TcpStream, request: &str) -> String {
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn varint() {
+        // The examples from RFC 9000, section A.1.
+        let examples = vec![
+            (vec![0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c], 151_288_809_941_952_652),
+            (vec![0x9d, 0x7f, 0x3e, 0x7d], 494_878_333),
+            (vec![0x7b, 0xbd], 15_293),
+            (vec![0x25], 37),
+        ];
+        for (encoded, value) in examples {
+            assert_eq!(Some((value, encoded.len())), decode_varint(&encoded));
+            let mut buf = vec![];
+            encode_varint(value, &mut buf);
+            assert_eq!(encoded, buf);
+        }
+        assert_eq!(None, decode_varint(&[0x7b]));
+    }
+
+    #[test]
+    fn target() {
+        assert_eq!(
+            Some(("192.0.2.6".to_string(), 443)),
+            parse_target("/.well-known/masque/udp/192.0.2.6/443/")
+        );
+        assert_eq!(
+            Some(("2001:db8::42".to_string(), 443)),
+            parse_target("/.well-known/masque/udp/2001%3Adb8%3A%3A42/443/")
+        );
+        assert_eq!(
+            Some(("example.com".to_string(), 7777)),
+            parse_target("/.well-known/masque/udp/example.com/7777")
+        );
+        assert_eq!(None, parse_target("/.well-known/masque/udp//443/"));
+        assert_eq!(None, parse_target("/.well-known/masque/udp/example.com/http/"));
+        assert_eq!(None, parse_target("/.well-known/masque/udp/example.com/443/more"));
+        assert_eq!(None, parse_target("/live"));
+    }
+
+    #[tokio::test]
+    async fn connect_udp() {
+        let mut t = TestHelper::default();
+        let (address, metrics, mut received_from) = run_listener(&mut t).await;
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let head = send_request(
+            &mut stream,
+            "GET /.well-known/masque/udp/game.example.com/7777/ HTTP/1.1\r\n\
+             Host: proxy.example.com\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: connect-udp\r\n\
+             Capsule-Protocol: ?1\r\n\r\n",
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head.to_lowercase().contains("capsule-protocol: ?1"), "{}", head);
+
+        for packet in &["hello", "world"] {
+            write_datagram(&mut stream, packet.as_bytes()).await.unwrap();
+            let (capsule_type, value) = timeout(Duration::from_secs(5), read_capsule(&mut stream))
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(DATAGRAM_CAPSULE, capsule_type);
+            // The context ID for UDP payloads, followed by the payload.
+            assert_eq!(0, value[0]);
+            assert_eq!(packet.as_bytes(), &value[1..]);
+            // The proxy sees the payloads as coming from the client.
+            assert_eq!(
+                stream.local_addr().unwrap(),
+                received_from.recv().await.unwrap()
+            );
+        }
+        assert_eq!(1, metrics.connect_udp_accepted.get());
+        assert_eq!(1, metrics.active_connect_udp_streams.get());
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_requests() {
+        let mut t = TestHelper::default();
+        let (address, metrics, _) = run_listener(&mut t).await;
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let head = send_request(
+            &mut stream,
+            "GET /.well-known/masque/udp/game.example.com/7777/ HTTP/1.1\r\n\
+             Host: proxy.example.com\r\n\r\n",
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 400"), "{}", head);
+
+        let head = send_request(
+            &mut stream,
+            "GET /game.example.com/7777/ HTTP/1.1\r\n\
+             Host: proxy.example.com\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: connect-udp\r\n\r\n",
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 404"), "{}", head);
+
+        assert_eq!(2, metrics.connect_udp_rejected.get());
+        assert_eq!(0, metrics.connect_udp_accepted.get());
+    }
+}
@@ -17,11 +17,36 @@
 use std::sync::atomic::AtomicBool;
 
 use hyper::{Body, Response, StatusCode};
-use slog::{error, o, Logger};
-use std::panic;
+use slog::{error, o, warn, Logger};
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
 
+thread_local! {
+    /// Whether a panic on this thread is being contained by
+    /// [`contain_panics`], and so shouldn't make the proxy unhealthy.
+    static CONTAINING_PANICS: Cell<bool> = Cell::new(false);
+}
+
+/// Runs `f`, returning the message of the panic if it panics. Unlike other
+/// panics, this doesn't move the proxy to unhealthy, so it is used to run
+/// code that is expected to fail on bad input, such as filters created from
+/// an update that hasn't been applied yet. Panics on other threads spawned
+/// by `f` are not contained.
+pub(crate) fn contain_panics<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    let containing = CONTAINING_PANICS.with(|containing| containing.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CONTAINING_PANICS.with(|current| current.set(containing));
+    result.map_err(|panic| {
+        panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".into())
+    })
+}
+
 pub struct Health {
     log: Logger,
     healthy: Arc<AtomicBool>,
@@ -38,8 +63,12 @@ impl Health {
         let healthy = health.healthy.clone();
         let default_hook = panic::take_hook();
         panic::set_hook(Box::new(move |panic_info| {
-            error!(log, "Panic has occurred. Moving to Unhealthy");
-            healthy.swap(false, Relaxed);
+            if CONTAINING_PANICS.with(Cell::get) {
+                warn!(log, "Contained panic has occurred. Staying healthy");
+            } else {
+                error!(log, "Panic has occurred. Moving to Unhealthy");
+                healthy.swap(false, Relaxed);
+            }
             default_hook(panic_info);
         }));
 
@@ -60,7 +89,7 @@ impl Health {
 
 #[cfg(test)]
 mod tests {
-    use crate::proxy::health::Health;
+    use crate::proxy::health::{contain_panics, Health};
     use crate::test_utils::logger;
     use hyper::StatusCode;
     use std::panic;
@@ -80,4 +109,17 @@ mod tests {
         let response = health.check_healthy();
         assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
+
+    #[test]
+    fn contain_panics_returns_message() {
+        assert_eq!(Ok(1), contain_panics(|| 1));
+        assert_eq!(
+            Err("oh no!".to_string()),
+            contain_panics(|| -> u8 { panic!("oh no!") })
+        );
+        assert_eq!(
+            Err("oh no! 1".to_string()),
+            contain_panics(|| -> u8 { panic!("oh no! {}", 1) })
+        );
+    }
 }
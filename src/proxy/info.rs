@@ -0,0 +1,186 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::Instant;
+
+use hyper::{Body, Response, StatusCode};
+use prometheus::{IntGaugeVec, Opts, Registry, Result as MetricsResult};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::metrics::CollectorExt;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The git commit that the proxy was built from, set by the build script.
+const GIT_SHA: &str = env!("QUILKIN_GIT_SHA");
+/// The version of the compiler that built the proxy, set by the build script.
+const RUSTC_VERSION: &str = env!("QUILKIN_RUSTC_VERSION");
+/// The comma separated cargo features the proxy was built with, set by the
+/// build script.
+const FEATURES: &str = env!("QUILKIN_FEATURES");
+
+#[cfg(debug_assertions)]
+pub(crate) fn version() -> String {
+    format!("{}+debug", VERSION)
+}
+
+#[cfg(not(debug_assertions))]
+pub(crate) fn version() -> String {
+    VERSION.into()
+}
+
+/// Registers the `quilkin_build_info` metric, whose labels describe how the
+/// proxy was built and whose value is always 1.
+pub(crate) fn register_build_info(registry: &Registry) -> MetricsResult<()> {
+    let build_info = IntGaugeVec::new(
+        Opts::new(
+            "build_info",
+            "A metric with a constant value of 1, labeled by the version, git commit, compiler version and features the proxy was built with.",
+        )
+        .namespace("quilkin"),
+        &["version", "git_sha", "rustc_version", "features"],
+    )?
+    .register_if_not_exists(registry)?;
+    build_info
+        .get_metric_with_label_values(&[&version(), GIT_SHA, RUSTC_VERSION, FEATURES])?
+        .set(1);
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct InfoResponse<'a> {
+    version: String,
+    git_sha: &'static str,
+    rustc_version: &'static str,
+    features: Vec<&'static str>,
+    uptime_seconds: u64,
+    config_hash: &'a str,
+    node_id: &'a str,
+}
+
+/// Info reports build and runtime information about the proxy.
+pub struct Info {
+    started_at: Instant,
+    /// The SHA-256 hash of the proxy's effective config, as hex.
+    config_hash: String,
+    /// The node id the proxy identifies itself with to management servers.
+    node_id: String,
+}
+
+impl Info {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            started_at: Instant::now(),
+            config_hash: config_hash(config),
+            node_id: config.proxy.id.clone(),
+        }
+    }
+
+    /// Returns the proxy's build and runtime information as JSON.
+    pub fn info(&self) -> Response<Body> {
+        let info = InfoResponse {
+            version: version(),
+            git_sha: GIT_SHA,
+            rustc_version: RUSTC_VERSION,
+            features: FEATURES
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+            uptime_seconds: self.started_at.elapsed().as_secs(),
+            config_hash: &self.config_hash,
+            node_id: &self.node_id,
+        };
+
+        let mut response = Response::new(Body::empty());
+        match serde_json::to_string_pretty(&info) {
+            Ok(body) => {
+                response.headers_mut().insert(
+                    "Content-Type",
+                    hyper::header::HeaderValue::from_static("application/json"),
+                );
+                *response.body_mut() = Body::from(body);
+            }
+            Err(_) => {
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        }
+        response
+    }
+}
+
+/// Returns the SHA-256 hash of the JSON representation of `config`, as hex.
+fn config_hash(config: &Config) -> String {
+    let json = serde_json::to_vec(config).unwrap_or_default();
+    Sha256::digest(&json)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+    use prometheus::Registry;
+
+    use super::{config_hash, register_build_info, version, Info};
+    use crate::test_utils::config_with_dummy_endpoint;
+
+    #[tokio::test]
+    async fn info() {
+        let config = config_with_dummy_endpoint().build();
+        let info = Info::new(&config);
+        let response = info.info();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["version"], version());
+        assert_eq!(info["node_id"], "test");
+        assert_eq!(info["config_hash"], config_hash(&config));
+        assert_eq!(info["uptime_seconds"], 0);
+    }
+
+    #[test]
+    fn config_hash_changes_with_config() {
+        let config = config_with_dummy_endpoint().build();
+        let hash = config_hash(&config);
+        assert_eq!(64, hash.len());
+        assert_eq!(hash, config_hash(&config_with_dummy_endpoint().build()));
+
+        let mut other = config_with_dummy_endpoint().build();
+        other.proxy.port += 1;
+        assert_ne!(hash, config_hash(&other));
+    }
+
+    #[test]
+    fn build_info() {
+        let registry = Registry::default();
+        register_build_info(&registry).unwrap();
+        // Registering again is a no-op.
+        register_build_info(&registry).unwrap();
+
+        let families = registry.gather();
+        assert_eq!(1, families.len());
+        assert_eq!("quilkin_build_info", families[0].get_name());
+        let metric = &families[0].get_metric()[0];
+        assert_eq!(1.0, metric.get_gauge().get_value());
+        assert!(metric
+            .get_label()
+            .iter()
+            .any(|label| label.get_name() == "version" && label.get_value() == version()));
+    }
+}
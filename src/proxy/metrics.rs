@@ -14,56 +14,205 @@
  *  limitations under the License.
  */
 
-use hyper::{Body, Response, StatusCode};
+use std::time::Duration;
+
+use hyper::{header::CONTENT_TYPE, Body, Client, Method, Request, Response, StatusCode};
+use prometheus::proto::MetricFamily;
 use prometheus::{Encoder, Registry, TextEncoder};
-use slog::{o, warn, Logger};
+use slog::{debug, o, warn, Logger};
+use tokio::sync::watch;
+
+use crate::config::{Metrics as MetricsConfig, MetricsPush};
+use crate::proxy::register_build_info;
+
+/// How long pushing metrics can take before it is given up on.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Metrics contains metrics configuration for the server.
 #[derive(Clone)]
 pub struct Metrics {
     log: Logger,
     pub(crate) registry: Registry,
+    /// Decides which metric families are exposed, and under which names.
+    config: MetricsConfig,
 }
 
 impl Metrics {
     pub fn new(base: &Logger, registry: Registry) -> Self {
+        let log = base.new(o!("source" => "proxy::Metrics"));
+        if let Err(err) = register_build_info(&registry) {
+            warn!(log, "Failed to register build info metric"; "error" => %err);
+        }
         Metrics {
-            log: base.new(o!("source" => "proxy::Metrics")),
+            log,
             registry,
+            config: MetricsConfig::default(),
         }
     }
 
+    /// Only exposes the metric families that `config` allows, renamed as
+    /// it configures.
+    pub fn with_config(self, config: MetricsConfig) -> Self {
+        Metrics { config, ..self }
+    }
+
     pub fn collect_metrics(&self) -> Response<Body> {
         let mut response = Response::new(Body::empty());
+        match self.encode() {
+            Some(body) => {
+                *response.body_mut() = Body::from(body);
+            }
+            None => {
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        };
+
+        response
+    }
+
+    /// Returns the metrics in the Prometheus text format, or `None` if they
+    /// couldn't be encoded.
+    fn encode(&self) -> Option<String> {
         let mut buffer = vec![];
         let encoder = TextEncoder::new();
-        let body = encoder
-            .encode(&self.registry.gather(), &mut buffer)
+        encoder
+            .encode(&self.expose(self.registry.gather()), &mut buffer)
             .map_err(|err| warn!(self.log, "Failed to encode metrics"; "error" => %err))
             .and_then(|_| {
-                String::from_utf8(buffer).map(Body::from).map_err(
+                String::from_utf8(buffer).map_err(
                     |err| warn!(self.log, "Failed to convert metrics to utf8"; "error" => %err),
                 )
-            });
+            })
+            .ok()
+    }
 
-        match body {
-            Ok(body) => {
-                *response.body_mut() = body;
+    /// Returns the exposed metric families whose exposed name matches one
+    /// of `patterns`, or all of them if there are no patterns.
+    pub(crate) fn gather(&self, patterns: &[String]) -> Vec<MetricFamily> {
+        self.expose(self.registry.gather())
+            .into_iter()
+            .filter(|family| patterns.is_empty() || matches(patterns, family.get_name()))
+            .collect()
+    }
+
+    /// Returns the families in `families` that are exposed, renamed by the
+    /// first relabel rule that applies to them.
+    fn expose(&self, families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        families
+            .into_iter()
+            .filter(|family| {
+                let name = family.get_name();
+                (self.config.allow.is_empty() || matches(&self.config.allow, name))
+                    && !matches(&self.config.deny, name)
+            })
+            .map(|mut family| {
+                let relabel = self
+                    .config
+                    .relabel
+                    .iter()
+                    .find(|relabel| family.get_name().starts_with(&relabel.prefix));
+                if let Some(relabel) = relabel {
+                    let name = format!(
+                        "{}{}",
+                        relabel.replacement,
+                        &family.get_name()[relabel.prefix.len()..]
+                    );
+                    family.set_name(name);
+                }
+                family
+            })
+            .collect()
+    }
+
+    /// Spawns a task that pushes metrics to the push gateway in `config`
+    /// every interval, grouped by its job and `instance`, and once more when
+    /// the proxy shuts down.
+    pub(crate) fn run_push(
+        &self,
+        config: MetricsPush,
+        instance: &str,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) {
+        let metrics = self.clone();
+        let url = push_url(&config, instance);
+        debug!(self.log, "Pushing metrics"; "url" => &url, "interval" => ?config.interval);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(config.interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => metrics.push(&url).await,
+                    _ = shutdown_rx.changed() => {
+                        metrics.push(&url).await;
+                        return;
+                    }
+                }
             }
-            Err(_) => {
-                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        });
+    }
+
+    /// Pushes the metrics to `url`, replacing the metrics previously pushed
+    /// to it.
+    async fn push(&self, url: &str) {
+        let body = match self.encode() {
+            Some(body) => body,
+            None => return,
+        };
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(url)
+            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(body));
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                warn!(self.log, "Failed to create metrics push request"; "error" => %err);
+                return;
             }
         };
-
-        response
+        let response = tokio::time::timeout(PUSH_TIMEOUT, Client::new().request(request)).await;
+        match response {
+            Ok(Ok(response)) if response.status().is_success() => {}
+            Ok(Ok(response)) => {
+                warn!(self.log, "Push gateway rejected metrics"; "status" => %response.status())
+            }
+            Ok(Err(err)) => warn!(self.log, "Failed to push metrics"; "error" => %err),
+            Err(_) => warn!(self.log, "Timed out pushing metrics"),
+        }
     }
 }
 
+/// Returns whether `name` matches one of `patterns`. A pattern ending in `*`
+/// matches any name starting with the rest of the pattern.
+fn matches(patterns: &[String], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => pattern == name,
+        })
+}
+
+/// Returns the URL that metrics are pushed to for `instance`.
+fn push_url(config: &MetricsPush, instance: &str) -> String {
+    format!(
+        "{}/metrics/job/{}/instance/{}",
+        config.url.trim_end_matches('/'),
+        config.job,
+        instance
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    use hyper::StatusCode;
-    use prometheus::Registry;
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server, StatusCode};
+    use prometheus::{IntCounter, Registry};
+    use tokio::sync::{mpsc, watch};
 
+    use crate::config::{MetricRelabel, Metrics as MetricsConfig, MetricsPush};
     use crate::proxy::Metrics;
     use crate::test_utils::logger;
 
@@ -74,4 +223,96 @@ mod tests {
         let response = metrics.collect_metrics();
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[test]
+    fn expose() {
+        let registry = Registry::default();
+        for name in &["quilkin_proxy_a_total", "quilkin_proxy_b_total", "quilkin_session_c_total"] {
+            let counter = IntCounter::new(*name, "A metric").unwrap();
+            registry.register(Box::new(counter)).unwrap();
+        }
+        let names = |metrics: &Metrics| {
+            metrics
+                .expose(registry.gather())
+                .iter()
+                .map(|family| family.get_name().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let metrics = Metrics::new(&logger(), registry.clone());
+        assert_eq!(4, names(&metrics).len());
+
+        let metrics = metrics.with_config(MetricsConfig {
+            allow: vec!["quilkin_proxy_*".into()],
+            deny: vec!["quilkin_proxy_b_total".into()],
+            relabel: vec![
+                MetricRelabel {
+                    prefix: "quilkin_proxy_".into(),
+                    replacement: "game_".into(),
+                },
+                MetricRelabel {
+                    prefix: "quilkin_".into(),
+                    replacement: "other_".into(),
+                },
+            ],
+            ..MetricsConfig::default()
+        });
+        assert_eq!(vec!["game_a_total"], names(&metrics));
+    }
+
+    #[tokio::test]
+    async fn push() {
+        // A push gateway that forwards each push it receives.
+        let (pushes_tx, mut pushes) = mpsc::unbounded_channel();
+        let make_svc = make_service_fn(move |_conn| {
+            let pushes_tx = pushes_tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let pushes_tx = pushes_tx.clone();
+                    async move {
+                        let (parts, body) = req.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap();
+                        pushes_tx
+                            .send((parts.method, parts.uri.path().to_string(), body))
+                            .unwrap();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let registry = Registry::default();
+        let counter = IntCounter::new("pushed_total", "A pushed metric").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let metrics = Metrics::new(&logger(), registry);
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        metrics.run_push(
+            MetricsPush {
+                url: format!("http://{}/", addr),
+                interval: Duration::from_secs(60),
+                job: "test-job".into(),
+            },
+            "test-proxy",
+            shutdown_rx,
+        );
+
+        // Metrics are pushed right away, and once more on shutdown.
+        let (method, path, body) = pushes.recv().await.unwrap();
+        assert_eq!(Method::PUT, method);
+        assert_eq!("/metrics/job/test-job/instance/test-proxy", path);
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("pushed_total 1"));
+
+        counter.inc();
+        shutdown_tx.send(()).unwrap();
+        let (_, _, body) = pushes.recv().await.unwrap();
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("pushed_total 2"));
+    }
 }
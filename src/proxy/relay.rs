@@ -0,0 +1,89 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The envelope that packets sent to relay endpoints, which are themselves
+//! proxies, are wrapped in. An envelope is the prefix, followed by the
+//! length of the token in a byte and the token itself. The relay removes
+//! the envelope, and only sends the packet to its endpoints with the token.
+
+/// The bytes that a relay envelope starts with.
+const PREFIX: &[u8] = b"QUILKIN_RELAY";
+
+/// The outcome of checking a received packet for a relay envelope.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Envelope {
+    /// The packet isn't enveloped. Holds the packet.
+    Missing(Vec<u8>),
+    /// The packet is enveloped. Holds the envelope's token and the packet
+    /// that it contained.
+    Valid(Vec<u8>, Vec<u8>),
+    /// The packet starts with the prefix, but not a valid envelope.
+    Invalid,
+}
+
+/// Returns `packet` wrapped in an envelope with `token`. Tokens are at most
+/// 255 bytes long, which is checked when endpoints are configured.
+pub(crate) fn seal(token: &[u8], packet: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(PREFIX.len() + 1 + token.len() + packet.len());
+    sealed.extend_from_slice(PREFIX);
+    sealed.push(token.len() as u8);
+    sealed.extend_from_slice(token);
+    sealed.extend_from_slice(packet);
+    sealed
+}
+
+/// Removes the envelope that `packet` is wrapped in, if any.
+pub(crate) fn open(mut packet: Vec<u8>) -> Envelope {
+    if !packet.starts_with(PREFIX) {
+        return Envelope::Missing(packet);
+    }
+    let token_len = match packet.get(PREFIX.len()) {
+        Some(&len) if len > 0 => usize::from(len),
+        _ => return Envelope::Invalid,
+    };
+    let header_len = PREFIX.len() + 1 + token_len;
+    if packet.len() < header_len {
+        return Envelope::Invalid;
+    }
+
+    let token = packet[PREFIX.len() + 1..header_len].to_vec();
+    packet.drain(..header_len);
+    Envelope::Valid(token, packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, seal, Envelope};
+
+    #[test]
+    fn open_sealed() {
+        assert_eq!(
+            Envelope::Valid(b"abc".to_vec(), b"hello".to_vec()),
+            open(seal(b"abc", b"hello"))
+        );
+        assert_eq!(
+            Envelope::Valid(b"abc".to_vec(), vec![]),
+            open(seal(b"abc", b""))
+        );
+        assert_eq!(Envelope::Missing(b"hello".to_vec()), open(b"hello".to_vec()));
+
+        // The envelope must hold a token of the length it gives.
+        let mut truncated = seal(b"abc", b"");
+        truncated.pop();
+        assert_eq!(Envelope::Invalid, open(truncated));
+        assert_eq!(Envelope::Invalid, open(seal(b"", b"hello")));
+    }
+}
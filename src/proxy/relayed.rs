@@ -0,0 +1,132 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Hands the packets of clients that reach the proxy through a relay
+//! listener, over SOCKS5 or CONNECT-UDP, to the proxy's workers as if they
+//! had been received on its UDP socket from the client itself, so that
+//! filters, rate limits and bans apply to the client's own address. The
+//! packets sent to those clients are delivered back through their relay.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::RwLock;
+use tokio::sync::mpsc;
+
+/// The maximum number of packets from relayed clients waiting for a worker.
+const QUEUE_SIZE: usize = 1024;
+
+/// The maximum number of packets waiting to be sent to a relayed client.
+const CLIENT_QUEUE_SIZE: usize = 1024;
+
+/// A packet received from a relayed client: the client's address, the
+/// packet's contents and when it was received.
+pub(crate) type RelayedPacket = (SocketAddr, Vec<u8>, SystemTime);
+
+/// The clients currently connected through a relay listener.
+#[derive(Clone)]
+pub(crate) struct RelayedClients {
+    packets: mpsc::Sender<RelayedPacket>,
+    /// Where the packets sent to each relayed client are delivered.
+    routes: Arc<RwLock<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>,
+}
+
+impl RelayedClients {
+    /// Returns the relayed clients, along with the queue that their packets
+    /// are handed to the workers on.
+    pub(crate) fn new() -> (Self, mpsc::Receiver<RelayedPacket>) {
+        let (packets, packets_rx) = mpsc::channel(QUEUE_SIZE);
+        let clients = Self {
+            packets,
+            routes: Arc::default(),
+        };
+        (clients, packets_rx)
+    }
+
+    /// Registers the client at `address`, returning its association and the
+    /// packets sent to it. The client is removed once the association is
+    /// dropped.
+    pub(crate) fn register(&self, address: SocketAddr) -> (Association, mpsc::Receiver<Vec<u8>>) {
+        let (sender, receiver) = mpsc::channel(CLIENT_QUEUE_SIZE);
+        self.routes.write().insert(address, sender);
+        let association = Association {
+            address,
+            clients: self.clone(),
+        };
+        (association, receiver)
+    }
+
+    /// Delivers `contents` to the client at `dest` through its relay,
+    /// returning whether it is a relayed client. The packet is dropped if
+    /// the relay is falling behind.
+    pub(crate) fn deliver(&self, dest: SocketAddr, contents: &[u8]) -> bool {
+        match self.routes.read().get(&dest) {
+            Some(sender) => {
+                let _ = sender.try_send(contents.to_vec());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A relayed client's association with the proxy.
+pub(crate) struct Association {
+    address: SocketAddr,
+    clients: RelayedClients,
+}
+
+impl Association {
+    /// Hands a packet from the client to the workers, waiting while they're
+    /// busy. Returns whether the proxy is still accepting packets.
+    pub(crate) async fn send(&self, contents: Vec<u8>) -> bool {
+        let packet = (self.address, contents, SystemTime::now());
+        self.clients.packets.send(packet).await.is_ok()
+    }
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        self.clients.routes.write().remove(&self.address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RelayedClients;
+
+    #[tokio::test]
+    async fn relay_packets() {
+        let (clients, mut packets) = RelayedClients::new();
+        let address = "192.0.2.1:7000".parse().unwrap();
+        let (association, mut received) = clients.register(address);
+
+        assert!(association.send(b"hello".to_vec()).await);
+        let (from, contents, _) = packets.recv().await.unwrap();
+        assert_eq!(address, from);
+        assert_eq!(b"hello".to_vec(), contents);
+
+        assert!(clients.deliver(address, b"world"));
+        assert_eq!(Some(b"world".to_vec()), received.recv().await);
+        assert!(!clients.deliver("192.0.2.2:7000".parse().unwrap(), b"world"));
+
+        // The client is removed along with its association.
+        drop(association);
+        assert!(!clients.deliver(address, b"world"));
+    }
+}
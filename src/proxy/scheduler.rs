@@ -0,0 +1,204 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use prometheus::{IntCounter, IntCounterVec, Registry, Result as MetricsResult};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::StreamExt;
+use tokio_util::time::DelayQueue;
+
+use crate::metrics::{opts, CollectorExt};
+
+/// The maximum number of jobs waiting for their delay to elapse. Jobs
+/// scheduled beyond it are dropped, so that the packets held back don't grow
+/// without bound when they are delayed faster than they are sent.
+const MAX_PENDING_JOBS: usize = 65_536;
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs jobs once their delay has elapsed, which is used to send the packets
+/// that filters have delayed. Delays are tracked by a single timer wheel
+/// rather than a timer per packet.
+pub struct Scheduler {
+    jobs: mpsc::Sender<(Duration, Job)>,
+    /// The number of jobs that were scheduled and haven't run yet.
+    pending: Arc<AtomicUsize>,
+    jobs_dropped_delay_too_long: IntCounter,
+    jobs_dropped_queue_full: IntCounter,
+}
+
+impl Scheduler {
+    /// The longest that a job can be delayed for. Jobs with longer delays are
+    /// dropped, so that a filter can't hold packets back indefinitely.
+    pub(crate) const MAX_DELAY: Duration = Duration::from_secs(10);
+
+    /// Returns a new Scheduler, spawning the task that runs its jobs. Jobs
+    /// that are still pending when a value is sent on `shutdown_rx` are
+    /// dropped.
+    pub fn new(registry: &Registry, mut shutdown_rx: watch::Receiver<()>) -> MetricsResult<Self> {
+        let jobs_dropped_total = IntCounterVec::new(
+            opts(
+                "jobs_dropped_total",
+                "scheduler",
+                "Total number of delayed packets dropped rather than scheduled. \
+                 Labels: reason - DelayTooLong, QueueFull",
+            ),
+            &["reason"],
+        )?
+        .register_if_not_exists(registry)?;
+
+        let (jobs, mut jobs_rx) = mpsc::channel::<(Duration, Job)>(MAX_PENDING_JOBS);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let pending_ref = pending.clone();
+        tokio::spawn(async move {
+            let mut queue = DelayQueue::new();
+            loop {
+                tokio::select! {
+                    job = jobs_rx.recv() => match job {
+                        Some((delay, job)) => {
+                            queue.insert(job, delay);
+                        }
+                        None => return,
+                    },
+                    Some(Ok(expired)) = queue.next(), if !queue.is_empty() => {
+                        pending_ref.fetch_sub(1, Ordering::Relaxed);
+                        // Run each job on its own task, so that a slow job
+                        // doesn't delay the others.
+                        tokio::spawn(expired.into_inner());
+                    }
+                    _ = shutdown_rx.changed() => return,
+                }
+            }
+        });
+
+        Ok(Self {
+            jobs,
+            pending,
+            jobs_dropped_delay_too_long: jobs_dropped_total
+                .get_metric_with_label_values(&["DelayTooLong"])?,
+            jobs_dropped_queue_full: jobs_dropped_total
+                .get_metric_with_label_values(&["QueueFull"])?,
+        })
+    }
+
+    /// Runs `job` once `delay` has elapsed. The job is dropped without being
+    /// run if `delay` is longer than [`Self::MAX_DELAY`], or if too many jobs are
+    /// already waiting.
+    pub fn schedule<F>(&self, delay: Duration, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if delay > Self::MAX_DELAY {
+            self.jobs_dropped_delay_too_long.inc();
+            return;
+        }
+        if self.pending.fetch_add(1, Ordering::Relaxed) >= MAX_PENDING_JOBS {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            self.jobs_dropped_queue_full.inc();
+            return;
+        }
+        // The channel holds at most the pending jobs, so it only fails once
+        // the jobs receiver is dropped on shutdown, when pending jobs are
+        // dropped anyway.
+        let _ = self.jobs.try_send((delay, Box::pin(job)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use prometheus::Registry;
+    use tokio::sync::{mpsc, watch};
+    use tokio::time::{self, Instant};
+
+    use super::{Scheduler, MAX_PENDING_JOBS};
+
+    #[tokio::test]
+    async fn schedule() {
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let scheduler = Scheduler::new(&Registry::default(), shutdown_rx).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let start = Instant::now();
+        for &(id, delay) in &[(1, 200), (2, 0), (3, 100)] {
+            let tx = tx.clone();
+            scheduler.schedule(Duration::from_millis(delay), async move {
+                tx.send(id).unwrap();
+            });
+        }
+
+        assert_eq!(Some(2), rx.recv().await);
+        assert_eq!(Some(3), rx.recv().await);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(Some(1), rx.recv().await);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let scheduler = Scheduler::new(&Registry::default(), shutdown_rx).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+
+        scheduler.schedule(Duration::from_millis(100), async move {
+            tx.send(()).unwrap();
+        });
+        shutdown_tx.send(()).unwrap();
+
+        // The job is dropped along with its sender, rather than run.
+        assert_eq!(
+            None,
+            time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_jobs() {
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let scheduler = Scheduler::new(&Registry::default(), shutdown_rx).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+
+        {
+            let tx = tx.clone();
+            scheduler.schedule(Scheduler::MAX_DELAY + Duration::from_secs(1), async move {
+                tx.send(()).unwrap();
+            });
+        }
+        assert_eq!(1, scheduler.jobs_dropped_delay_too_long.get());
+
+        for _ in 0..MAX_PENDING_JOBS + 1 {
+            let tx = tx.clone();
+            scheduler.schedule(Duration::from_millis(100), async move {
+                tx.send(()).unwrap();
+            });
+        }
+        assert_eq!(1, scheduler.jobs_dropped_queue_full.get());
+        drop(tx);
+
+        // Only the jobs within the limits run.
+        let mut ran = 0;
+        while rx.recv().await.is_some() {
+            ran += 1;
+        }
+        assert_eq!(MAX_PENDING_JOBS, ran);
+    }
+}
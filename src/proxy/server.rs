@@ -14,38 +14,101 @@
  * limitations under the License.
  */
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::result::Result as StdResult;
 use std::sync::Arc;
+use std::time::SystemTime;
 
-use slog::{debug, error, info, trace, warn, Logger};
-use tokio::net::UdpSocket;
+use slog::{debug, error, info, o, trace, warn, Logger};
+use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::{mpsc, watch};
 use tokio::task::JoinHandle;
-use tokio::time::Duration;
-
+use tokio::time::{self, Duration};
+
+use analyzer::Analyzer;
+use ban_gossip::BanGossip;
+use burst_detector::BurstDetector;
+use client_version::ClientVersions;
+use connection_id::{ConnectionId, ConnectionIds};
+use connection_tracker::{Admission, ConnectionTracker};
+use decision_log::DecisionLog;
+use fair_queue::FairQueue;
+use handshake::{Cookie, Handshake};
+use ice::{IceCheck, IceLite};
 use metrics::Metrics as ProxyMetrics;
+use packet_buffer::PacketBuffer;
+use port_conflict::{
+    Kind as PortConflictKind, RecentRequests as RecentPortConflictRequests,
+    Request as PortConflictRequest,
+};
+use priority_queues::PriorityQueues;
 use resource_manager::{DynamicResourceManagers, StaticResourceManagers};
+use resource_usage::ResourceMonitor;
+use standby::Standby;
+use state::StateTransfer;
 
+use crate::audit_log::{Action, AuditLog, Outcome, Record};
 use crate::cluster::cluster_manager::SharedClusterManager;
-use crate::cluster::Endpoint;
-use crate::filters::{manager::SharedFilterManager, Filter, FilterRegistry, ReadContext};
+use crate::cluster::{Endpoint, EndpointHealth, SlowStart};
+use crate::config::{
+    ActivationWindow, ConnectUdp, Endpoints, FirstPacket, OrderedSends, PortConflictPolicy, Relay,
+    SessionKeySource, Socks5, TunnelListener, UpstreamEndpoints, UpstreamSocket,
+};
+use crate::faults::FaultInjector;
+use crate::filters::{manager::SharedFilterManager, FilterRegistry, Priority, ReadContext};
 use crate::proxy::builder::{ValidatedConfig, ValidatedSource};
+use crate::proxy::relay::{self, Envelope};
+use crate::proxy::relayed::{RelayedClients, RelayedPacket};
 use crate::proxy::server::error::Error;
 use crate::proxy::sessions::metrics::Metrics as SessionMetrics;
 use crate::proxy::sessions::session_manager::SessionManager;
-use crate::proxy::sessions::{Packet, Session, SESSION_TIMEOUT_SECONDS};
-use crate::proxy::Admin;
+use crate::proxy::sessions::{
+    is_message_too_large, ClientDrops, ClientKey, Packet, PacketSizeLimit, Session, SessionArgs,
+    SessionKey, SESSION_TIMEOUT_SECONDS,
+};
+use crate::proxy::tunnel::{self, Connector as TunnelConnector};
+use crate::proxy::{connect_udp, socks5};
+use crate::proxy::{Admin, ComputePool, ConfigHandle, Scheduler, Tap, TapDirection};
+use crate::supervisor::Supervisor;
 use crate::utils::debug;
+use crate::xds::telemetry::TelemetryReporter;
 
 use super::metrics::Metrics;
 
+pub use doctor::Report as DoctorReport;
+
+pub(super) mod analyzer;
+pub(super) mod ban_gossip;
+mod burst_detector;
+mod client_version;
+mod connection_id;
+mod connection_tracker;
+pub(super) mod decision_log;
+mod doctor;
 pub mod error;
+mod fair_queue;
+mod handshake;
+pub(super) mod ice;
 pub(super) mod metrics;
+mod packet_buffer;
+mod port_conflict;
+mod preflight;
+mod priority_queues;
+mod recv_timestamp;
 mod resource_manager;
+mod resource_usage;
+pub(super) mod standby;
+pub(super) mod state;
+mod systemd;
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// How often packets held in the failover buffer are forwarded or expired.
+const FAILOVER_BUFFER_FLUSH_INTERVAL: Duration = Duration::from_millis(5);
+
 /// Server is the UDP server main implementation
 pub struct Server {
     // We use pub(super) to limit instantiation only to the Builder.
@@ -57,6 +120,7 @@ pub struct Server {
     pub(super) proxy_metrics: ProxyMetrics,
     pub(super) session_metrics: SessionMetrics,
     pub(super) filter_registry: FilterRegistry,
+    pub(super) config_handle: ConfigHandle,
 }
 
 /// Represents arguments to the `Server::run_recv_from` method.
@@ -67,6 +131,18 @@ struct RunRecvFromArgs {
     session_manager: SessionManager,
     session_ttl: Duration,
     send_packets: mpsc::Sender<Packet>,
+    connection_tracker: Option<Arc<ConnectionTracker>>,
+    tunnel: Option<Arc<TunnelConnector>>,
+    scheduler: Arc<Scheduler>,
+    endpoint_health: Option<EndpointHealth>,
+    slow_start: Option<SlowStart>,
+    faults: Option<Arc<FaultInjector>>,
+    ban_gossip: Option<Arc<BanGossip>>,
+    standby: Option<Arc<Standby>>,
+    supervisor: Option<Supervisor>,
+    /// The packets of clients connected through a relay listener, if any
+    /// are enabled.
+    relayed_packets: Option<mpsc::Receiver<RelayedPacket>>,
     shutdown_rx: watch::Receiver<()>,
 }
 
@@ -75,14 +151,38 @@ struct RunRecvFromArgs {
 struct DownstreamReceiveWorkerConfig {
     /// ID of the worker.
     worker_id: usize,
-    /// Channel from which the worker picks up the downstream packets.
-    packet_rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+    /// Queue from which the worker picks up the downstream packets.
+    packet_rx: WorkerQueue,
     /// Configuration required to process a received downstream packet.
     receive_config: ProcessDownstreamReceiveConfig,
     /// The worker task exits when a value is received from this shutdown channel.
     shutdown_rx: watch::Receiver<()>,
 }
 
+/// The queue that a worker task picks up downstream packets from.
+enum WorkerQueue {
+    /// A queue of the worker's own, which packets are spread over in turn.
+    Channel(mpsc::Receiver<(SocketAddr, Vec<u8>, SystemTime)>),
+    /// A queue shared by all workers, which is drained one client at a time.
+    Fair(Arc<FairQueue<(Vec<u8>, SystemTime)>>),
+}
+
+impl WorkerQueue {
+    /// Waits for a packet, returning `None` if the queue was closed.
+    async fn recv(&mut self) -> Option<(SocketAddr, Vec<u8>, SystemTime)> {
+        match self {
+            WorkerQueue::Channel(packet_rx) => packet_rx.recv().await,
+            WorkerQueue::Fair(fair_queue) => {
+                let (recv_addr, (packet, received_at)) = fair_queue.pop().await;
+                Some((recv_addr, packet, received_at))
+            }
+        }
+    }
+}
+
+/// The windows during which endpoints are active, by address.
+type EndpointSchedules = HashMap<SocketAddr, Vec<ActivationWindow>>;
+
 /// Contains arguments to process a received downstream packet, through the
 /// filter chain and session pipeline.
 struct ProcessDownstreamReceiveConfig {
@@ -94,27 +194,472 @@ struct ProcessDownstreamReceiveConfig {
     session_manager: SessionManager,
     session_ttl: Duration,
     send_packets: mpsc::Sender<Packet>,
+    packet_size_limit: PacketSizeLimit,
+    /// Holds packets received while there are no endpoints to send them to,
+    /// if enabled.
+    packet_buffer: Option<Arc<PacketBuffer>>,
+    /// Decides whether new sessions are created, if enabled.
+    connection_tracker: Option<Arc<ConnectionTracker>>,
+    /// Verifies that clients own their address before sessions are created
+    /// for them, if enabled.
+    handshake: Option<Arc<Handshake>>,
+    /// Runs filter chains containing heavy filters, if enabled.
+    compute_pool: Option<Arc<ComputePool>>,
+    /// Options for the sockets that sessions send packets to endpoints from.
+    upstream_socket: UpstreamSocket,
+    /// Opens the tunnels that sessions send packets to endpoints through,
+    /// if enabled.
+    tunnel: Option<Arc<TunnelConnector>>,
+    /// Sends the packets that filters have delayed.
+    scheduler: Arc<Scheduler>,
+    /// How long after being received a packet is dropped if it hasn't been
+    /// processed by the filter chain, if enabled.
+    packet_deadline: Option<Duration>,
+    /// The checks the first packet of a session must pass, if enabled.
+    first_packet: Option<FirstPacket>,
+    /// Reads the version of a client's build from the first packet of its
+    /// sessions, if enabled.
+    client_versions: Option<Arc<ClientVersions>>,
+    /// The endpoints that packets are accepted from but never sent to, if
+    /// any.
+    response_only_endpoints: Option<Arc<Endpoints>>,
+    /// The windows during which endpoints are active, if any are scheduled.
+    endpoint_schedules: Option<Arc<EndpointSchedules>>,
+    /// Tracks which endpoints are unhealthy, if enabled.
+    endpoint_health: Option<EndpointHealth>,
+    /// Ramps up the share of traffic of endpoints that are warming up, if
+    /// enabled.
+    slow_start: Option<SlowStart>,
+    /// Streams copies of packets to the watchers of the tap service, if
+    /// enabled.
+    tap: Option<Arc<Tap>>,
+    /// Injects faults into the proxy, if enabled.
+    faults: Option<Arc<FaultInjector>>,
+    /// What clients are identified by, if not only by their address.
+    session_key: Option<SessionKeySource>,
+    /// Issues and verifies the connection ids that clients are keyed by, if
+    /// enabled.
+    connection_ids: Option<Arc<ConnectionIds>>,
+    /// Answers the ICE connectivity checks that clients send, if enabled.
+    ice: Option<Arc<IceLite>>,
+    /// Records why sampled packets were sent to the endpoints they were, if
+    /// enabled.
+    decision_log: Option<Arc<DecisionLog>>,
+    /// Bans clients and shares the bans with peer proxies, if enabled.
+    ban_gossip: Option<Arc<BanGossip>>,
+    /// The drop reasons of the sessions of each client.
+    client_drops: Arc<ClientDrops>,
+    /// Sends the packets that other proxies wrap in a relay envelope to the
+    /// endpoints with its token, if enabled.
+    relay: Option<Relay>,
+    /// If set, each session sends its packets from a queue drained by a
+    /// single task.
+    ordered_sends: Option<OrderedSends>,
+    /// Restarts the receive loops of sessions that panic, if set.
+    supervisor: Option<Supervisor>,
+}
+
+impl ProcessDownstreamReceiveConfig {
+    /// Returns the arguments to create a session for the client at `from`,
+    /// identified by `client_key`, that sends its packets to `dest`, and
+    /// expires after `ttl` unless it receives a packet.
+    fn session_args(
+        &self,
+        from: SocketAddr,
+        client_key: ClientKey,
+        dest: Endpoint,
+        ttl: Duration,
+    ) -> SessionArgs {
+        let filter_chain = self.filter_manager.read().get_filter_chain();
+        SessionArgs {
+            metrics: self.session_metrics.for_variant(filter_chain.variant(from)),
+            filter_manager: self.filter_manager.clone(),
+            from,
+            connection_id_header: match (&self.connection_ids, &client_key) {
+                (Some(connection_ids), ClientKey::ConnectionId(id)) => {
+                    Some(connection_ids.header(id))
+                }
+                _ => None,
+            },
+            client_key: Some(client_key),
+            dest,
+            sender: self.send_packets.clone(),
+            ttl,
+            packet_size_limit: self.packet_size_limit,
+            compute_pool: self.compute_pool.clone(),
+            upstream_socket: self.upstream_socket,
+            tunnel: self.tunnel.clone(),
+            response_only_endpoints: self.response_only_endpoints.clone(),
+            endpoint_health: self.endpoint_health.clone(),
+            tap: self.tap.clone(),
+            faults: self.faults.clone(),
+            supervisor: self.supervisor.clone(),
+            send_queue_size: self.ordered_sends.map(|config| config.queue_size),
+        }
+    }
+
+    /// Returns the subset of `endpoints` that are within their activation
+    /// windows, or `None` if none are, as endpoints outside of their windows
+    /// are treated as if they weren't configured.
+    fn active_endpoints(&self, mut endpoints: UpstreamEndpoints) -> Option<UpstreamEndpoints> {
+        let schedules = match &self.endpoint_schedules {
+            Some(schedules) => schedules,
+            None => return Some(endpoints),
+        };
+        let now = SystemTime::now();
+        let retained = endpoints.retain(|endpoint| {
+            schedules
+                .get(&endpoint.address)
+                .map_or(true, |active| ActivationWindow::any_contains(active, now))
+        });
+        if retained.is_none() {
+            None
+        } else {
+            Some(endpoints)
+        }
+    }
+
+    /// Returns `endpoints` without the endpoints that have been marked
+    /// unhealthy. If all of them have, packets are still sent to them rather
+    /// than dropped, unless the failover buffer is enabled, in which case
+    /// `None` is returned so that packets are held until an endpoint
+    /// recovers or is replaced.
+    fn healthy_endpoints(&self, mut endpoints: UpstreamEndpoints) -> Option<UpstreamEndpoints> {
+        if let Some(health) = &self.endpoint_health {
+            if endpoints.retain_healthy(health).is_none() && self.packet_buffer.is_some() {
+                return None;
+            }
+        }
+        Some(endpoints)
+    }
+
+    /// Returns the endpoints that packets can be sent to out of `endpoints`,
+    /// or `None` if there are none.
+    fn available_endpoints(
+        &self,
+        endpoints: Option<UpstreamEndpoints>,
+    ) -> Option<UpstreamEndpoints> {
+        endpoints
+            .and_then(|endpoints| self.active_endpoints(endpoints))
+            .and_then(|endpoints| self.healthy_endpoints(endpoints))
+            .map(|endpoints| self.warm_endpoints(endpoints))
+    }
+
+    /// Returns whether there are endpoints that packets can be sent to, so
+    /// that the packets held in the failover buffer can be forwarded.
+    fn has_available_endpoints(&self) -> bool {
+        let endpoints = self.cluster_manager.read().get_all_endpoints();
+        self.available_endpoints(endpoints).is_some()
+    }
+
+    /// Returns `endpoints` with the weights of the endpoints that are
+    /// warming up scaled down, if slow start is enabled.
+    fn warm_endpoints(&self, mut endpoints: UpstreamEndpoints) -> UpstreamEndpoints {
+        if let Some(slow_start) = &self.slow_start {
+            endpoints.apply_slow_start(slow_start);
+        }
+        endpoints
+    }
+
+    /// Updates the peak number of active sessions after a session has been
+    /// created. Sessions are only created while holding the write lock on
+    /// the sessions map, so the peak can't be updated concurrently.
+    fn update_peak_active_sessions(&self) {
+        let active_sessions = self.session_metrics.active_sessions.get();
+        if active_sessions > self.session_metrics.peak_active_sessions.get() {
+            self.session_metrics
+                .peak_active_sessions
+                .set(active_sessions);
+        }
+    }
+}
+
+/// The outcome of sending a packet to an endpoint through a session.
+#[derive(Debug, PartialEq)]
+enum SessionSendResult {
+    /// The packet was sent, or dropped for a reason already accounted for.
+    Done,
+    /// The packet was dropped as there is no session for it and the client
+    /// has not completed the handshake.
+    HandshakeRequired,
+    /// The packet was dropped as there is no session for it and it failed
+    /// the checks on the first packet of a session.
+    Rejected,
+}
+
+/// Whether a packet can create a session for its client if there is none.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NewSession {
+    /// A session can be created, if the connection tracker (if enabled)
+    /// admits it.
+    Allowed,
+    /// The client has not completed the handshake.
+    HandshakeRequired,
+    /// The packet failed the checks on the first packet of a session.
+    Rejected,
 }
 
 impl Server {
+    /// Returns a handle to update the endpoints and filter chain of the proxy
+    /// once it is running. Updates are only possible with a static config,
+    /// as a management server or mDNS would replace them.
+    pub fn config_handle(&self) -> ConfigHandle {
+        self.config_handle.clone()
+    }
+
+    /// Runs the checks of `quilkin doctor` against the proxy's config and
+    /// environment, probing up to `endpoint_sample` of its static endpoints.
+    pub async fn doctor(&self, endpoint_sample: usize) -> DoctorReport {
+        doctor::run(&self.config, endpoint_sample).await
+    }
+
     /// start the async processing of incoming UDP packets. Will block until an
     /// event is sent through the stop Receiver.
     pub async fn run(self, mut shutdown_rx: watch::Receiver<()>) -> Result<()> {
         self.log_config();
 
+        // A socket passed by systemd is already bound to the port, so the
+        // port is only checked if there is none.
+        let listen_socket = systemd::listen_socket().map_err(Error::Bind)?;
+        let port_conflict_secret = &self.config.proxy.port_conflict_secret;
+        let peer = match listen_socket {
+            Some(_) => None,
+            None => port_conflict::probe(self.config.proxy.port, port_conflict_secret)
+                .await
+                .unwrap_or_else(|err| {
+                    warn!(self.log, "Failed to probe the port for another proxy"; "error" => %err);
+                    None
+                }),
+        };
+        let report = preflight::run(&self.config, listen_socket.is_none(), peer.as_ref()).await;
+        report.log(&self.log);
+        if report.failures().next().is_some() {
+            return Err(Error::Preflight(report.to_string()));
+        }
+
+        let supervisor = Supervisor::new(&self.log, &self.metrics.registry)
+            .map_err(|err| Error::Initialize(format!("failed to create supervisor: {}", err)))?;
+        let audit_log = self.open_audit_log()?;
         if let Some(admin) = &self.admin {
-            admin.run(shutdown_rx.clone());
+            if let Some(audit_log) = &audit_log {
+                admin.set_audit_log(audit_log.clone());
+            }
+            admin.set_filter_registry(self.filter_registry.clone());
+            admin.run(&supervisor, shutdown_rx.clone());
+        }
+        if let Some(metrics_push) = &self.config.proxy.metrics_push {
+            self.metrics
+                .run_push(metrics_push.clone(), &self.config.proxy.id, shutdown_rx.clone());
         }
 
-        let socket = Arc::new(Server::bind(self.config.proxy.port).await?);
-        let session_manager = SessionManager::new(self.log.clone(), shutdown_rx.clone());
+        let socket = Arc::new(match listen_socket {
+            Some(socket) => {
+                info!(self.log, "Using the socket passed by systemd";
+                    "address" => ?socket.local_addr().ok());
+                socket
+            }
+            None => {
+                let reuse_port =
+                    self.config.proxy.port_conflict_policy == PortConflictPolicy::TakeOver;
+                Server::bind(self.config.proxy.port, reuse_port).await?
+            }
+        });
+        // The preflight checks only pass with a proxy on the port if this
+        // proxy is set to take it over.
+        if peer.is_some() {
+            self.take_over_port().await;
+        }
+        if let Err(err) = recv_timestamp::enable(&socket) {
+            warn!(self.log, "Kernel receive timestamps are unavailable"; "error" => %err);
+        }
         let (send_packets, receive_packets) = mpsc::channel::<Packet>(1024);
 
         let session_ttl = Duration::from_secs(SESSION_TIMEOUT_SECONDS);
 
-        let (cluster_manager, filter_manager) =
-            self.create_resource_managers(shutdown_rx.clone()).await?;
-        self.run_receive_packet(socket.clone(), receive_packets);
+        let connection_tracker = self
+            .config
+            .proxy
+            .connection_tracker
+            .clone()
+            .map(|config| {
+                ConnectionTracker::new(
+                    self.log.new(o!("source" => "proxy::ConnectionTracker")),
+                    self.config.proxy.id.clone(),
+                    config,
+                    self.proxy_metrics.clone(),
+                )
+                .map(Arc::new)
+            })
+            .transpose()
+            .map_err(|err| {
+                Error::Initialize(format!("failed to create connection tracker: {}", err))
+            })?;
+
+        let ban_gossip = match &self.config.proxy.ban_gossip {
+            Some(config) => {
+                let socket =
+                    UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), config.port))
+                        .await
+                        .map_err(Error::Bind)?;
+                // Trusted clients are never banned either.
+                let mut ban_config = config.clone();
+                if let Some(trusted_clients) = &self.config.proxy.trusted_clients {
+                    ban_config
+                        .never_ban
+                        .extend_from_slice(&trusted_clients.addresses);
+                }
+                let ban_gossip = Arc::new(BanGossip::new(
+                    self.log.new(o!("source" => "proxy::BanGossip")),
+                    ban_config,
+                    socket,
+                    self.proxy_metrics.clone(),
+                ));
+                let loaded = ban_gossip
+                    .load()
+                    .map_err(|err| Error::Initialize(format!("failed to load bans: {}", err)))?;
+                ban_gossip.clone().run(shutdown_rx.clone());
+                info!(self.log, "Sharing bans with peers";
+                    "port" => config.port, "peers" => config.peers.len(),
+                    "loaded_bans" => loaded);
+                if let Some(admin) = &self.admin {
+                    admin.set_ban_gossip(ban_gossip.clone());
+                }
+                Some(ban_gossip)
+            }
+            None => None,
+        };
+
+        let standby = match &self.config.proxy.standby {
+            Some(config) => {
+                let address = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), config.port);
+                let socket = UdpSocket::bind(address).await.map_err(Error::Bind)?;
+                let listener = TcpListener::bind(address).await.map_err(Error::Bind)?;
+                let standby = Arc::new(Standby::new(
+                    self.log.new(o!("source" => "proxy::Standby")),
+                    config.clone(),
+                    socket,
+                    listener,
+                    self.proxy_metrics.clone(),
+                ));
+                info!(self.log, "Starting as the standby of an active/standby pair";
+                    "port" => config.port, "peer" => %config.peer);
+                if let Some(admin) = &self.admin {
+                    admin.set_standby(standby.clone());
+                }
+                Some(standby)
+            }
+            None => None,
+        };
+
+        let tunnel = self
+            .config
+            .proxy
+            .tunnel_peer
+            .as_ref()
+            .map(|config| TunnelConnector::new(config).map(Arc::new))
+            .transpose()
+            .map_err(|err| {
+                Error::Initialize(format!("failed to create tunnel connector: {}", err))
+            })?;
+
+        let slow_start = self
+            .config
+            .proxy
+            .endpoint_slow_start
+            .map(|config| SlowStart::new(&self.log, config));
+        let endpoint_health = self
+            .config
+            .proxy
+            .endpoint_health_check
+            .map(|config| EndpointHealth::new(&self.log, config, &self.metrics.registry))
+            .transpose()
+            .map_err(|err| {
+                Error::Initialize(format!("failed to create endpoint health check: {}", err))
+            })?
+            .map(|health| match &slow_start {
+                Some(slow_start) => health.with_slow_start(slow_start.clone()),
+                None => health,
+            });
+        if let (Some(admin), Some(endpoint_health)) = (&self.admin, &endpoint_health) {
+            admin.set_endpoint_health(endpoint_health.clone());
+        }
+
+        let faults = self
+            .config
+            .proxy
+            .faults
+            .map(|config| FaultInjector::new(&self.log, config, &self.metrics.registry))
+            .transpose()
+            .map_err(|err| Error::Initialize(format!("failed to create fault injector: {}", err)))?
+            .map(Arc::new);
+
+        let (cluster_manager, filter_manager) = self
+            .create_resource_managers(
+                audit_log,
+                faults.clone(),
+                supervisor.clone(),
+                shutdown_rx.clone(),
+            )
+            .await?;
+        if let ValidatedSource::Static { .. } = &self.config.source {
+            self.config_handle.attach(
+                cluster_manager.clone(),
+                filter_manager.clone(),
+                self.filter_registry.clone(),
+                self.metrics.registry.clone(),
+            );
+        }
+        if let Some(config) = &self.config.proxy.tunnel_listener {
+            self.run_tunnel_listener(config, cluster_manager.clone(), shutdown_rx.clone())
+                .await?;
+        }
+        let (relayed_clients, relayed_packets) =
+            if self.config.proxy.connect_udp.is_some() || self.config.proxy.socks5.is_some() {
+                let (clients, packets) = RelayedClients::new();
+                (Some(clients), Some(packets))
+            } else {
+                (None, None)
+            };
+        if let (Some(config), Some(clients)) = (&self.config.proxy.connect_udp, &relayed_clients) {
+            self.run_connect_udp_listener(config, clients.clone(), shutdown_rx.clone())
+                .await?;
+        }
+        if let (Some(config), Some(clients)) = (&self.config.proxy.socks5, &relayed_clients) {
+            self.run_socks5_listener(config, clients.clone(), shutdown_rx.clone())
+                .await?;
+        }
+        let session_manager = SessionManager::new(
+            self.log.clone(),
+            self.session_metrics.clone(),
+            cluster_manager.clone(),
+            self.config.proxy.first_response_timeout,
+            shutdown_rx.clone(),
+        );
+        if let Some(admin) = &self.admin {
+            admin.set_session_manager(session_manager.clone());
+        }
+        ResourceMonitor {
+            log: self.log.new(o!("source" => "proxy::ResourceMonitor")),
+            proxy_metrics: self.proxy_metrics.clone(),
+            session_metrics: self.session_metrics.clone(),
+            session_manager: session_manager.clone(),
+            limits: self.config.proxy.resource_limits,
+        }
+        .run(shutdown_rx.clone());
+        let scheduler = Scheduler::new(&self.metrics.registry, shutdown_rx.clone())
+            .map_err(|err| Error::Initialize(format!("failed to create scheduler: {}", err)))?;
+        let scheduler = Arc::new(scheduler);
+        self.notify_systemd("READY=1");
+        if let Some(interval) = systemd::watchdog_interval() {
+            self.run_watchdog(interval, shutdown_rx.clone());
+        }
+        self.run_receive_packet(
+            socket.clone(),
+            receive_packets,
+            scheduler.clone(),
+            faults.clone(),
+            relayed_clients,
+        );
         let recv_loop = self.run_recv_from(RunRecvFromArgs {
             cluster_manager,
             filter_manager,
@@ -122,6 +667,16 @@ impl Server {
             session_manager,
             session_ttl,
             send_packets,
+            connection_tracker,
+            tunnel,
+            scheduler,
+            endpoint_health,
+            slow_start,
+            faults,
+            ban_gossip,
+            standby,
+            supervisor: Some(supervisor),
+            relayed_packets,
             shutdown_rx: shutdown_rx.clone(),
         });
 
@@ -132,13 +687,189 @@ impl Server {
                     .and_then(|inner| inner.map_err(Error::RecvLoop))
             }
             _ = shutdown_rx.changed() => {
+                self.notify_systemd("STOPPING=1");
                 Ok(())
             }
         }
     }
 
+    /// Asks the proxy listening on the port alongside this one to stop, so
+    /// that this proxy receives all of the port's packets. If it doesn't, the
+    /// kernel splits the packets between the two proxies.
+    async fn take_over_port(&self) {
+        let secret = &self.config.proxy.port_conflict_secret;
+        match port_conflict::take_over(self.config.proxy.port, secret).await {
+            Ok(Some(peer)) if peer.stopping => {
+                info!(self.log, "Took over the port from another proxy";
+                    "id" => peer.id, "pid" => peer.pid);
+            }
+            Ok(Some(peer)) => {
+                warn!(self.log, "Another proxy on the port refused to stop";
+                    "id" => peer.id, "pid" => peer.pid);
+            }
+            Ok(None) => {
+                warn!(self.log, "Another proxy on the port didn't respond to a request to stop");
+            }
+            Err(err) => {
+                warn!(self.log, "Failed to ask another proxy on the port to stop";
+                    "error" => %err);
+            }
+        }
+    }
+
+    /// Opens the audit log if enabled, recording that the configuration was
+    /// loaded.
+    fn open_audit_log(&self) -> Result<Option<AuditLog>> {
+        let config = match &self.config.proxy.audit_log {
+            Some(config) => config,
+            None => return Ok(None),
+        };
+        let audit_log = AuditLog::new(self.log.new(o!("source" => "AuditLog")), config)
+            .map_err(|err| Error::Initialize(format!("failed to open audit log: {}", err)))?;
+
+        let detail = match &self.config.source {
+            ValidatedSource::Static { endpoints, .. } => {
+                format!("static configuration with {} endpoints", endpoints.size())
+            }
+            ValidatedSource::Mdns {
+                endpoints, mdns, ..
+            } => format!(
+                "static configuration with {} endpoints and mDNS discovery of {}",
+                endpoints.as_ref().map(Endpoints::size).unwrap_or_default(),
+                mdns.service_type
+            ),
+            ValidatedSource::Dynamic {
+                management_servers,
+                ..
+            } => format!(
+                "dynamic configuration from {}",
+                management_servers
+                    .iter()
+                    .map(|server| server.address.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        audit_log.record(Record {
+            actor: &self.config.proxy.id,
+            action: Action::ConfigLoad,
+            target: "config",
+            outcome: Outcome::Succeeded,
+            detail: Some(&detail),
+        });
+        Ok(Some(audit_log))
+    }
+
+    /// Accepts tunnels from peer proxies in the background, forwarding
+    /// their packets to the endpoints of `cluster_manager`.
+    async fn run_tunnel_listener(
+        &self,
+        config: &TunnelListener,
+        cluster_manager: SharedClusterManager,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> Result<()> {
+        let listener = tunnel::Listener::new(
+            self.log.new(o!("source" => "proxy::TunnelListener")),
+            config,
+            cluster_manager,
+            self.proxy_metrics.clone(),
+            Duration::from_secs(SESSION_TIMEOUT_SECONDS),
+        )
+        .map_err(|err| Error::Initialize(format!("failed to create tunnel listener: {}", err)))?;
+        let socket = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), config.port))
+            .await
+            .map_err(Error::Bind)?;
+        info!(self.log, "Accepting tunnels"; "port" => config.port);
+        listener.run(socket, shutdown_rx);
+        Ok(())
+    }
+
+    /// Accepts UDP proxying requests in the background, handing their
+    /// packets to the workers as those of relayed `clients`.
+    async fn run_connect_udp_listener(
+        &self,
+        config: &ConnectUdp,
+        clients: RelayedClients,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> Result<()> {
+        let listener = connect_udp::Listener::new(
+            self.log.new(o!("source" => "proxy::ConnectUdpListener")),
+            config,
+            clients,
+            self.proxy_metrics.clone(),
+            Duration::from_secs(SESSION_TIMEOUT_SECONDS),
+        )
+        .map_err(|err| {
+            Error::Initialize(format!("failed to create CONNECT-UDP listener: {}", err))
+        })?;
+        let socket = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), config.port))
+            .await
+            .map_err(Error::Bind)?;
+        info!(self.log, "Accepting CONNECT-UDP requests"; "port" => config.port);
+        listener.run(socket, shutdown_rx);
+        Ok(())
+    }
+
+    /// Accepts SOCKS5 UDP associations in the background, handing their
+    /// packets to the workers as those of relayed `clients`.
+    async fn run_socks5_listener(
+        &self,
+        config: &Socks5,
+        clients: RelayedClients,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> Result<()> {
+        let listener = socks5::Listener::new(
+            self.log.new(o!("source" => "proxy::Socks5Listener")),
+            clients,
+            self.proxy_metrics.clone(),
+            Duration::from_secs(SESSION_TIMEOUT_SECONDS),
+            config.max_associations,
+        );
+        let socket = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), config.port))
+            .await
+            .map_err(Error::Bind)?;
+        info!(self.log, "Accepting SOCKS5 UDP associations"; "port" => config.port);
+        listener.run(socket, shutdown_rx);
+        Ok(())
+    }
+
+    /// Sends `state` to systemd, if the proxy was started by it.
+    fn notify_systemd(&self, state: &str) {
+        match systemd::notify(state) {
+            Ok(true) => debug!(self.log, "Notified systemd"; "state" => state),
+            Ok(false) => {}
+            Err(err) => {
+                warn!(self.log, "Failed to notify systemd"; "state" => state, "error" => %err)
+            }
+        }
+    }
+
+    /// Spawns a task that pings the systemd watchdog at half of `interval`,
+    /// as recommended by systemd. The pings run on the same runtime as the
+    /// rest of the proxy, so they stop if it hangs.
+    fn run_watchdog(&self, interval: Duration, mut shutdown_rx: watch::Receiver<()>) {
+        let log = self.log.clone();
+        info!(log, "Enabling the systemd watchdog"; "interval" => ?interval);
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval / 2);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        if let Err(err) = systemd::notify("WATCHDOG=1") {
+                            warn!(log, "Failed to ping the systemd watchdog"; "error" => %err);
+                        }
+                    }
+                    _ = shutdown_rx.changed() => return,
+                }
+            }
+        });
+    }
+
     async fn create_resource_managers(
         &self,
+        audit_log: Option<AuditLog>,
+        faults: Option<Arc<FaultInjector>>,
+        supervisor: Supervisor,
         shutdown_rx: watch::Receiver<()>,
     ) -> Result<(SharedClusterManager, SharedFilterManager)> {
         match &self.config.source {
@@ -154,13 +885,47 @@ impl Server {
                 .map_err(|err| Error::Initialize(format!("{}", err)))?;
                 Ok((manager.cluster_manager, manager.filter_manager))
             }
-            ValidatedSource::Dynamic { management_servers } => {
+            ValidatedSource::Mdns {
+                filter_chain,
+                endpoints,
+                mdns,
+            } => {
+                let manager = StaticResourceManagers::with_mdns(
+                    self.log.clone(),
+                    &self.metrics.registry,
+                    endpoints.clone(),
+                    filter_chain.clone(),
+                    mdns,
+                    shutdown_rx,
+                )
+                .map_err(|err| Error::Initialize(format!("{}", err)))?;
+                Ok((manager.cluster_manager, manager.filter_manager))
+            }
+            ValidatedSource::Dynamic {
+                management_servers,
+                startup,
+                failover,
+                endpoint_update_guard,
+                filter_update_failure,
+            } => {
+                let telemetry =
+                    self.config.proxy.telemetry.clone().map(|config| {
+                        TelemetryReporter::new(&self.log, self.metrics.clone(), config)
+                    });
                 let manager = DynamicResourceManagers::new(
                     self.log.clone(),
                     self.config.proxy.id.clone(),
                     self.metrics.registry.clone(),
                     self.filter_registry.clone(),
                     management_servers.to_vec(),
+                    startup,
+                    failover.clone(),
+                    *endpoint_update_guard,
+                    *filter_update_failure,
+                    audit_log,
+                    faults,
+                    telemetry,
+                    supervisor,
                     shutdown_rx,
                 )
                 .await
@@ -192,36 +957,179 @@ impl Server {
     /// off the aforementioned queue and processing them through the filter chain and session
     /// pipeline.
     fn run_recv_from(&self, args: RunRecvFromArgs) -> JoinHandle<StdResult<(), String>> {
-        let session_manager = args.session_manager;
+        let RunRecvFromArgs {
+            cluster_manager,
+            filter_manager,
+            socket,
+            session_manager,
+            session_ttl,
+            send_packets,
+            connection_tracker,
+            tunnel,
+            scheduler,
+            endpoint_health,
+            slow_start,
+            faults,
+            ban_gossip,
+            standby,
+            supervisor,
+            relayed_packets,
+            shutdown_rx,
+        } = args;
         let log = self.log.clone();
         let proxy_metrics = self.proxy_metrics.clone();
         let session_metrics = self.session_metrics.clone();
+        let packet_size_limit = PacketSizeLimit::new(
+            self.config.proxy.max_packet_size,
+            self.config.proxy.oversized_packet_policy,
+        );
+        let packet_buffer = self
+            .config
+            .proxy
+            .failover_buffer
+            .map(|config| Arc::new(PacketBuffer::new(config, proxy_metrics.clone())));
+        let handshake = self
+            .config
+            .proxy
+            .handshake
+            .clone()
+            .map(|config| Arc::new(Handshake::new(config)));
+        let connection_ids = self
+            .config
+            .proxy
+            .connection_id
+            .clone()
+            .map(|config| Arc::new(ConnectionIds::new(config)));
+        let ice = self
+            .config
+            .proxy
+            .ice
+            .clone()
+            .map(|config| Arc::new(IceLite::new(config)));
+        if let (Some(admin), Some(ice)) = (&self.admin, &ice) {
+            admin.set_ice(ice.clone());
+        }
+        let decision_log = self
+            .config
+            .proxy
+            .decision_log
+            .clone()
+            .map(|config| Arc::new(DecisionLog::new(log.clone(), config)));
+        if let (Some(admin), Some(decision_log)) = (&self.admin, &decision_log) {
+            admin.set_decision_log(decision_log.clone());
+        }
+        let compute_pool = self
+            .config
+            .proxy
+            .compute_pool
+            .clone()
+            .map(|config| Arc::new(ComputePool::new(config)));
+        let client_versions = self
+            .config
+            .proxy
+            .client_version
+            .clone()
+            .map(|config| Arc::new(ClientVersions::new(config, proxy_metrics.clone())));
+        let response_only_endpoints = self.config.response_only_endpoints.clone().map(Arc::new);
+        let mut endpoint_schedules = EndpointSchedules::new();
+        for schedule in &self.config.proxy.schedule.endpoints {
+            for address in &schedule.addresses {
+                endpoint_schedules
+                    .entry(*address)
+                    .or_default()
+                    .extend(schedule.active.iter().copied());
+            }
+        }
+        let endpoint_schedules = Some(Arc::new(endpoint_schedules))
+            .filter(|endpoint_schedules| !endpoint_schedules.is_empty());
+        let tap = self.admin.as_ref().and_then(Admin::tap);
+        let client_drops = Arc::new(ClientDrops::default());
+        let receive_config = || ProcessDownstreamReceiveConfig {
+            log: log.clone(),
+            proxy_metrics: proxy_metrics.clone(),
+            session_metrics: session_metrics.clone(),
+            cluster_manager: cluster_manager.clone(),
+            filter_manager: filter_manager.clone(),
+            session_manager: session_manager.clone(),
+            session_ttl,
+            send_packets: send_packets.clone(),
+            packet_size_limit,
+            packet_buffer: packet_buffer.clone(),
+            connection_tracker: connection_tracker.clone(),
+            handshake: handshake.clone(),
+            compute_pool: compute_pool.clone(),
+            upstream_socket: self.config.proxy.upstream_socket,
+            tunnel: tunnel.clone(),
+            scheduler: scheduler.clone(),
+            packet_deadline: self.config.proxy.packet_deadline,
+            first_packet: self.config.proxy.first_packet.clone(),
+            client_versions: client_versions.clone(),
+            response_only_endpoints: response_only_endpoints.clone(),
+            endpoint_schedules: endpoint_schedules.clone(),
+            endpoint_health: endpoint_health.clone(),
+            slow_start: slow_start.clone(),
+            tap: tap.clone(),
+            faults: faults.clone(),
+            session_key: self.config.proxy.session_key.clone(),
+            connection_ids: connection_ids.clone(),
+            ice: ice.clone(),
+            decision_log: decision_log.clone(),
+            ban_gossip: ban_gossip.clone(),
+            client_drops: client_drops.clone(),
+            relay: self.config.proxy.relay,
+            ordered_sends: self.config.proxy.ordered_sends,
+            supervisor: supervisor.clone(),
+        };
+
+        if let Some(admin) = &self.admin {
+            admin.set_state_transfer(StateTransfer::new(receive_config()));
+        }
+        if let Some(standby) = &standby {
+            standby
+                .clone()
+                .run(StateTransfer::new(receive_config()), shutdown_rx.clone());
+        }
 
         // The number of worker tasks to spawn. Each task gets a dedicated queue to
         // consume packets off.
         let num_workers = num_cpus::get();
 
+        // If enabled, all worker tasks share a queue that is drained fairly
+        // between clients, instead of each getting a dedicated queue.
+        let fair_queue = self
+            .config
+            .proxy
+            .fair_queue
+            .map(|config| Arc::new(FairQueue::new(config.queue_size)));
+
         // Contains channel Senders for each worker task.
         let mut packet_txs = vec![];
         // Contains config for each worker task.
         let mut worker_configs = vec![];
         for worker_id in 0..num_workers {
-            let (packet_tx, packet_rx) = mpsc::channel(num_workers);
-            packet_txs.push(packet_tx);
+            let packet_rx = match &fair_queue {
+                Some(fair_queue) => WorkerQueue::Fair(fair_queue.clone()),
+                None => {
+                    let (packet_tx, packet_rx) = mpsc::channel(num_workers);
+                    packet_txs.push(packet_tx);
+                    WorkerQueue::Channel(packet_rx)
+                }
+            };
             worker_configs.push(DownstreamReceiveWorkerConfig {
                 worker_id,
                 packet_rx,
-                shutdown_rx: args.shutdown_rx.clone(),
-                receive_config: ProcessDownstreamReceiveConfig {
-                    log: log.clone(),
-                    proxy_metrics: proxy_metrics.clone(),
-                    session_metrics: session_metrics.clone(),
-                    cluster_manager: args.cluster_manager.clone(),
-                    filter_manager: args.filter_manager.clone(),
-                    session_manager: session_manager.clone(),
-                    session_ttl: args.session_ttl,
-                    send_packets: args.send_packets.clone(),
-                },
+                shutdown_rx: shutdown_rx.clone(),
+                receive_config: receive_config(),
+            })
+        }
+        // The packets of relayed clients never reach the socket, and are
+        // processed by a worker of their own.
+        if let Some(relayed_packets) = relayed_packets {
+            worker_configs.push(DownstreamReceiveWorkerConfig {
+                worker_id: num_workers,
+                packet_rx: WorkerQueue::Channel(relayed_packets),
+                shutdown_rx: shutdown_rx.clone(),
+                receive_config: receive_config(),
             })
         }
 
@@ -229,28 +1137,136 @@ impl Server {
         // and processes them.
         Self::spawn_downstream_receive_workers(log.clone(), worker_configs);
 
+        if packet_buffer.is_some() {
+            Self::spawn_failover_buffer_flush(log.clone(), receive_config(), shutdown_rx.clone());
+        }
+
         // Start the background task to receive downstream packets from the socket
         // and place them onto the worker tasks' queue for processing.
-        let socket = args.socket;
+        let shed_when_full = self.config.proxy.packet_deadline.is_some();
+        let proxy_id = self.config.proxy.id.clone();
+        let take_over_allowed =
+            self.config.proxy.port_conflict_policy == PortConflictPolicy::TakeOver;
+        let port_conflict_secret = self.config.proxy.port_conflict_secret.clone();
+        let oversized_total = session_metrics.upstream_packets_oversized_total.clone();
+        let ordered_sends = self.config.proxy.ordered_sends.is_some();
+        let analyzer = self
+            .config
+            .proxy
+            .analyzer
+            .map(|config| Arc::new(Analyzer::new(config)));
+        if let (Some(admin), Some(analyzer)) = (&self.admin, &analyzer) {
+            admin.set_analyzer(analyzer.clone());
+        }
+        let burst_detector = self.config.proxy.burst_detection.clone().map(|config| {
+            BurstDetector::new(
+                self.log.new(o!("source" => "proxy::BurstDetector")),
+                self.config.proxy.id.clone(),
+                config,
+                proxy_metrics.clone(),
+                shutdown_rx.clone(),
+            )
+        });
         tokio::spawn(async move {
             // Index to round-robin over workers to process packets.
             let mut next_worker = 0;
             let num_workers = num_workers;
 
-            // Initialize a buffer for the UDP packet. We use the maximum size of a UDP
-            // packet, which is the maximum value of 16 a bit integer.
-            let mut buf = [0; 1 << 16];
+            // Initialize a buffer for the UDP packet, sized so that packets
+            // exceeding the maximum packet size can be detected.
+            let mut buf = vec![0; packet_size_limit.recv_buffer_size()];
+            let mut recent_port_conflict_requests = RecentPortConflictRequests::default();
             loop {
-                match socket.recv_from(&mut buf).await {
-                    Ok((size, recv_addr)) => {
-                        let packet_tx = &mut packet_txs[next_worker % num_workers];
-                        next_worker += 1;
-
-                        if packet_tx
-                            .send((recv_addr, (&buf[..size]).to_vec()))
-                            .await
-                            .is_err()
-                        {
+                match recv_timestamp::recv_from(&socket, &mut buf).await {
+                    Ok((size, recv_addr, received_at)) => {
+                        // Requests from a proxy starting on the same port
+                        // are answered rather than processed.
+                        let request = PortConflictRequest::parse(
+                            recv_addr,
+                            &buf[..size],
+                            &port_conflict_secret,
+                        );
+                        if let Some(request) = request {
+                            if request.is_own() || !recent_port_conflict_requests.insert(&request) {
+                                continue;
+                            }
+                            let stopping =
+                                request.kind == PortConflictKind::TakeOver && take_over_allowed;
+                            let reply = request.reply(&proxy_id, stopping, &port_conflict_secret);
+                            if let Err(err) = socket.send_to(&reply, recv_addr).await {
+                                warn!(log, "Failed to reply to another proxy"; "error" => %err);
+                            }
+                            if stopping {
+                                warn!(log, "Stopping as another proxy is taking over the port");
+                                return Ok(());
+                            }
+                            if request.kind == PortConflictKind::TakeOver {
+                                warn!(log, "Ignoring a request to take over the port";
+                                    "port_conflict_policy" => "FAIL");
+                            }
+                            continue;
+                        }
+
+                        if let Some(analyzer) = &analyzer {
+                            analyzer.record(recv_addr, size, received_at);
+                        }
+
+                        // Only the active proxy of a pair processes packets.
+                        if let Some(standby) = &standby {
+                            if !standby.is_active() {
+                                proxy_metrics.packets_dropped_standby.inc();
+                                continue;
+                            }
+                        }
+
+                        if let Some(burst_detector) = &burst_detector {
+                            burst_detector.record(recv_addr);
+                        }
+
+                        let size = match packet_size_limit.limit_len(size, &oversized_total) {
+                            Some(size) => size,
+                            None => {
+                                proxy_metrics.packets_dropped_oversized.inc();
+                                continue;
+                            }
+                        };
+
+                        if let Some(fair_queue) = &fair_queue {
+                            let packet = ((&buf[..size]).to_vec(), received_at);
+                            if fair_queue.push(recv_addr, packet) {
+                                proxy_metrics.packets_shed_fair_queue_full.inc();
+                            }
+                            continue;
+                        }
+
+                        let worker = if ordered_sends {
+                            // Each client's packets are processed by the same
+                            // worker, so that they aren't reordered.
+                            let mut hasher = DefaultHasher::new();
+                            recv_addr.hash(&mut hasher);
+                            hasher.finish() as usize
+                        } else {
+                            next_worker += 1;
+                            next_worker - 1
+                        };
+                        let packet_tx = &mut packet_txs[worker % num_workers];
+
+                        let packet = (recv_addr, (&buf[..size]).to_vec(), received_at);
+                        let sent = if shed_when_full {
+                            // Rather than waiting for a busy worker, which
+                            // delays every packet behind this one, drop the
+                            // packet as it would likely miss its deadline.
+                            match packet_tx.try_send(packet) {
+                                Err(mpsc::error::TrySendError::Full(_)) => {
+                                    proxy_metrics.packets_shed_queue_full.inc();
+                                    Ok(())
+                                }
+                                result => result.map_err(|_| ()),
+                            }
+                        } else {
+                            packet_tx.send(packet).await.map_err(|_| ())
+                        };
+                        if sent.is_err() {
                             // We cannot recover from this error since
                             // it implies that the receiver has been dropped.
                             let reason =
@@ -290,7 +1306,7 @@ impl Server {
                     tokio::select! {
                       packet = packet_rx.recv() => {
                         match packet {
-                          Some((recv_addr, packet)) => Self::process_downstream_received_packet((recv_addr, packet), &receive_config).await,
+                          Some((recv_addr, packet, received_at)) => Self::process_downstream_received_packet((recv_addr, packet, received_at), &receive_config).await,
                           None => {
                             debug!(log, "Worker-{} exiting: work sender channel was closed.", worker_id);
                             return;
@@ -307,12 +1323,62 @@ impl Server {
         }
     }
 
+    /// Spawns a background task that periodically forwards the packets held
+    /// in the failover buffer once endpoints are available, and drops those
+    /// held for too long otherwise.
+    fn spawn_failover_buffer_flush(
+        log: Logger,
+        args: ProcessDownstreamReceiveConfig,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) {
+        let packet_buffer = match args.packet_buffer.clone() {
+            Some(packet_buffer) => packet_buffer,
+            None => return,
+        };
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(FAILOVER_BUFFER_FLUSH_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        if !packet_buffer.is_empty() {
+                            Self::flush_failover_buffer(&packet_buffer, &args).await;
+                        }
+                    }
+                    _ = shutdown_rx.changed() => {
+                        debug!(log, "Failover buffer flush exiting: received shutdown signal.");
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Forwards the packets held in `packet_buffer` if there are endpoints
+    /// available, otherwise drops the packets that have been held for too
+    /// long.
+    async fn flush_failover_buffer(
+        packet_buffer: &PacketBuffer,
+        args: &ProcessDownstreamReceiveConfig,
+    ) {
+        if !args.has_available_endpoints() {
+            packet_buffer.expire();
+            return;
+        }
+
+        // The buffer doesn't keep receive times, so buffered packets are
+        // considered received when they are forwarded.
+        for (recv_addr, packet) in packet_buffer.take_all() {
+            Self::forward_or_buffer_packet(recv_addr, packet, SystemTime::now(), args).await;
+        }
+    }
+
     /// Processes a packet by running it through the filter chain.
     async fn process_downstream_received_packet(
-        packet: (SocketAddr, Vec<u8>),
+        packet: (SocketAddr, Vec<u8>, SystemTime),
         args: &ProcessDownstreamReceiveConfig,
     ) {
-        let (recv_addr, packet) = packet;
+        let (recv_addr, packet, received_at) = packet;
 
         trace!(
             args.log,
@@ -321,103 +1387,487 @@ impl Server {
             "contents" => debug::bytes_to_string(&packet),
         );
 
-        let endpoints = match args.cluster_manager.read().get_all_endpoints() {
-            Some(endpoints) => endpoints,
-            None => {
-                args.proxy_metrics.packets_dropped_no_endpoints.inc();
+        if let Some(ban_gossip) = &args.ban_gossip {
+            if ban_gossip.is_banned(recv_addr.ip()) {
+                args.proxy_metrics.packets_dropped_banned.inc();
                 return;
             }
-        };
+        }
 
-        let filter_chain = {
-            let filter_manager_guard = args.filter_manager.read();
-            filter_manager_guard.get_filter_chain()
+        // Connectivity checks are answered by the proxy itself, rather than
+        // being sent to endpoints.
+        let packet = match &args.ice {
+            Some(ice) => match ice.check(recv_addr, packet) {
+                IceCheck::NotStun(packet) => packet,
+                IceCheck::Valid {
+                    response,
+                    nominated,
+                } => {
+                    Self::send_ice_response(recv_addr, response, nominated, args).await;
+                    return;
+                }
+                IceCheck::Invalid => {
+                    args.proxy_metrics.packets_dropped_invalid_ice_check.inc();
+                    return;
+                }
+            },
+            None => packet,
         };
-        let result = filter_chain.read(ReadContext::new(endpoints, recv_addr, packet));
 
-        if let Some(response) = result {
-            for endpoint in response.endpoints.iter() {
-                Self::session_send_packet(
-                    &response.contents.as_slice(),
-                    recv_addr,
-                    endpoint,
-                    &args,
-                )
-                .await;
+        if let Some(packet_buffer) = &args.packet_buffer {
+            // Forward any packets held for this client first, so that they
+            // arrive in the order they were received.
+            let buffered = if args.has_available_endpoints() {
+                packet_buffer.take(recv_addr)
+            } else {
+                vec![]
+            };
+            for buffered in buffered {
+                Self::forward_or_buffer_packet(recv_addr, buffered, SystemTime::now(), args)
+                    .await;
             }
         }
+
+        Self::forward_or_buffer_packet(recv_addr, packet, received_at, args).await;
     }
 
-    /// Send a packet received from `recv_addr` to an endpoint.
-    async fn session_send_packet(
-        packet: &[u8],
+    /// Forwards a packet to the current endpoints. If none are available, the
+    /// packet is held in the failover buffer if enabled, or dropped otherwise.
+    async fn forward_or_buffer_packet(
         recv_addr: SocketAddr,
-        endpoint: &Endpoint,
+        packet: Vec<u8>,
+        received_at: SystemTime,
         args: &ProcessDownstreamReceiveConfig,
     ) {
-        let session_key = (recv_addr, endpoint.address);
-
-        // Grab a read lock and find the session.
-        let guard = args.session_manager.get_sessions().await;
-        if let Some(session) = guard.get(&session_key) {
-            // If it exists then send the packet, we're done.
-            Self::session_send_packet_helper(&args.log, session, packet, args.session_ttl).await
-        } else {
-            // If it does not exist, grab a write lock so that we can create it.
-            //
-            // NOTE: We must drop the lock guard to release the lock before
-            // trying to acquire a write lock since these lock aren't reentrant,
-            // otherwise we will deadlock with our self.
-            drop(guard);
+        // The endpoints and the filter chain are read together, so that a
+        // packet is never processed with the endpoints of one config update
+        // and the filter chain of another.
+        let (endpoints, filter_chain) = {
+            let cluster_manager = args.cluster_manager.read();
+            let filter_chain = args.filter_manager.read().get_filter_chain();
+            (cluster_manager.get_all_endpoints(), filter_chain)
+        };
+        let endpoints = match (args.available_endpoints(endpoints), &args.packet_buffer) {
+            (Some(endpoints), _) => endpoints,
+            (None, Some(packet_buffer)) => {
+                packet_buffer.push(recv_addr, packet);
+                return;
+            }
+            (None, None) => {
+                args.proxy_metrics.packets_dropped_no_endpoints.inc();
+                return;
+            }
+        };
 
-            // Grab a write lock.
-            let mut guard = args.session_manager.get_sessions_mut().await;
+        // Packets that another proxy relays through this one are only sent to
+        // the endpoints with the token in their envelope.
+        let (packet, endpoints) = match &args.relay {
+            Some(config) => match relay::open(packet) {
+                Envelope::Missing(_) if config.require_envelope => {
+                    args.proxy_metrics
+                        .packets_dropped_invalid_relay_envelope
+                        .inc();
+                    return;
+                }
+                Envelope::Missing(packet) => (packet, endpoints),
+                Envelope::Valid(token, packet) => {
+                    let mut endpoints = endpoints;
+                    if endpoints
+                        .retain(|endpoint| endpoint.tokens.contains(&token))
+                        .is_none()
+                    {
+                        args.proxy_metrics.packets_dropped_no_relay_endpoint.inc();
+                        return;
+                    }
+                    (packet, endpoints)
+                }
+                Envelope::Invalid => {
+                    args.proxy_metrics
+                        .packets_dropped_invalid_relay_envelope
+                        .inc();
+                    return;
+                }
+            },
+            None => (packet, endpoints),
+        };
 
-            // Although we have the write lock now, check whether some other thread
+        let received_len = packet.len();
+        // Packets without a connection id are keyed by the one the client
+        // is issued, which is derived from its address.
+        let (packet, connection_id) = match &args.connection_ids {
+            Some(connection_ids) => match connection_ids.parse(packet) {
+                ConnectionId::Missing(packet) => {
+                    (packet, Some((connection_ids.id(recv_addr), false)))
+                }
+                ConnectionId::Valid(id, packet) => (packet, Some((id, true))),
+                ConnectionId::Invalid => {
+                    args.proxy_metrics
+                        .packets_dropped_invalid_connection_id
+                        .inc();
+                    return;
+                }
+            },
+            None => (packet, None),
+        };
+        let (packet, verified) = match &args.handshake {
+            Some(handshake) => match handshake.verify(recv_addr, packet) {
+                Cookie::Missing(packet) => (packet, false),
+                Cookie::Valid(packet) => (packet, true),
+                Cookie::Invalid => {
+                    args.proxy_metrics.packets_dropped_invalid_cookie.inc();
+                    return;
+                }
+            },
+            None => (packet, true),
+        };
+        let new_session = match &args.first_packet {
+            Some(first_packet) if !first_packet.accepts(&packet) => NewSession::Rejected,
+            _ if !verified => NewSession::HandshakeRequired,
+            _ => NewSession::Allowed,
+        };
+        let client_version = args
+            .client_versions
+            .as_ref()
+            .and_then(|client_versions| client_versions.parse(&packet));
+
+        let mut ctx = ReadContext::new(endpoints, recv_addr, packet);
+        ctx.received_at = received_at;
+        let tapped = args
+            .tap
+            .as_ref()
+            .and_then(|tap| tap.select(TapDirection::Read, recv_addr, &ctx.contents));
+        if let Some(tapped) = &tapped {
+            tapped.pre_filter(None, &ctx.contents);
+        }
+        let elapsed = || {
+            SystemTime::now()
+                .duration_since(received_at)
+                .unwrap_or_default()
+        };
+        args.proxy_metrics
+            .read_delay_seconds
+            .observe(elapsed().as_secs_f64());
+        let past_deadline =
+            || matches!(args.packet_deadline, Some(deadline) if elapsed() >= deadline);
+        if past_deadline() {
+            args.proxy_metrics.packets_shed_queued.inc();
+            return;
+        }
+        if let Some(faults) = &args.faults {
+            faults.delay_filters();
+        }
+        let decision_log = args
+            .decision_log
+            .as_ref()
+            .filter(|decision_log| decision_log.is_sampled(ctx.sample));
+        let (candidates, traced) = (ctx.endpoints.size(), decision_log.is_some());
+        let compute_pool = args
+            .compute_pool
+            .as_ref()
+            .filter(|compute_pool| compute_pool.is_heavy(&filter_chain));
+        let read_latency = args
+            .session_metrics
+            .read_latency(filter_chain.variant(recv_addr));
+        let read = move || {
+            let mut trace = vec![];
+            let result = filter_chain.try_read_traced(ctx, Some(&mut trace).filter(|_| traced));
+            (result, trace)
+        };
+        let (result, trace) = match compute_pool {
+            Some(compute_pool) => match compute_pool.run(read).await {
+                Some(result) => result,
+                None => {
+                    args.proxy_metrics.packets_dropped_compute_pool_full.inc();
+                    return;
+                }
+            },
+            None => read(),
+        };
+        if let Some(decision_log) = decision_log {
+            decision_log.record(recv_addr, candidates, trace, &result);
+        }
+        let response = match result {
+            Ok(response) => response,
+            Err(reason) => {
+                if let Some(tapped) = &tapped {
+                    tapped.dropped(None, &reason);
+                }
+                // Sessions keyed by a token can't be found from a dropped
+                // packet, as the token is only known once the filter chain
+                // has processed it.
+                let client_key = match &connection_id {
+                    Some((id, _)) => ClientKey::ConnectionId(id.clone()),
+                    None => ClientKey::Address(recv_addr),
+                };
+                let has_session = args.client_drops.record(&client_key, &reason);
+                if let Some(ban_gossip) = &args.ban_gossip {
+                    let established = has_session || (args.handshake.is_some() && verified);
+                    ban_gossip.dropped(recv_addr.ip(), reason.code, established);
+                }
+                return;
+            }
+        };
+        if past_deadline() {
+            args.proxy_metrics.packets_shed_filtering.inc();
+            return;
+        }
+        read_latency.observe(elapsed().as_secs_f64());
+
+        // A session only moves to the address a packet was received from if
+        // the packet proves that it's from the session's client, as tokens
+        // and connection ids found by filters can be copied by anyone.
+        let rebind =
+            matches!(connection_id, Some((_, true))) || (args.handshake.is_some() && verified);
+        // Only one challenge is sent, or rejection counted, per received
+        // packet, however many sessions it would have created.
+        let mut challenged = false;
+        let mut rejected = false;
+        for response in response.into_packets() {
+            let token = args
+                .connection_tracker
+                .as_ref()
+                .and_then(|connection_tracker| connection_tracker.token(&response.metadata));
+            let client_key = match &connection_id {
+                Some((id, _)) => ClientKey::ConnectionId(id.clone()),
+                None => ClientKey::new(args.session_key.as_ref(), recv_addr, &response.metadata),
+            };
+            let contents = match args.packet_size_limit.apply(
+                response.contents,
+                &args.session_metrics.upstream_packets_oversized_total,
+            ) {
+                Some(contents) => contents,
+                None => {
+                    args.proxy_metrics.packets_dropped_oversized.inc();
+                    continue;
+                }
+            };
+            if let Some(tapped) = &tapped {
+                tapped.post_filter(
+                    response.endpoints.iter().map(|endpoint| endpoint.address),
+                    &contents,
+                );
+            }
+
+            for endpoint in response.endpoints.iter() {
+                // Packets are never sent to response-only endpoints, even if
+                // a management server lists them as endpoints.
+                if let Some(response_only_endpoints) = &args.response_only_endpoints {
+                    if response_only_endpoints
+                        .as_ref()
+                        .iter()
+                        .any(|response_only| response_only.address == endpoint.address)
+                    {
+                        continue;
+                    }
+                }
+                let result = Self::session_send_packet(
+                    &contents.as_slice(),
+                    response.delay,
+                    recv_addr,
+                    &client_key,
+                    matches!(connection_id, Some((_, true))),
+                    rebind,
+                    endpoint,
+                    token.as_deref(),
+                    new_session,
+                    client_version.as_deref(),
+                    &args,
+                )
+                .await;
+                match result {
+                    SessionSendResult::HandshakeRequired if !challenged => {
+                        challenged = true;
+                        Self::send_challenge(recv_addr, received_len, args).await;
+                    }
+                    SessionSendResult::Rejected if !rejected => {
+                        rejected = true;
+                        args.proxy_metrics
+                            .packets_dropped_first_packet_rejected
+                            .inc();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Sends a handshake challenge to `recv_addr`, whose packet of
+    /// `received_len` bytes was dropped as it would have created a session.
+    /// No challenge is sent if it is larger than the received packet, so
+    /// that packets with a spoofed address can't be used to amplify traffic
+    /// towards it.
+    async fn send_challenge(
+        recv_addr: SocketAddr,
+        received_len: usize,
+        args: &ProcessDownstreamReceiveConfig,
+    ) {
+        args.proxy_metrics.packets_dropped_handshake_required.inc();
+        let handshake = match &args.handshake {
+            Some(handshake) if handshake.challenge_len() <= received_len => handshake,
+            _ => return,
+        };
+
+        args.proxy_metrics.handshake_challenges_total.inc();
+        let challenge =
+            Packet::new(recv_addr, handshake.challenge(recv_addr)).with_priority(Priority::High);
+        if args.send_packets.send(challenge).await.is_err() {
+            error!(args.log, "Failed to send handshake challenge"; "to" => recv_addr);
+        }
+    }
+
+    /// Sends the response to a connectivity check from `recv_addr`, which
+    /// `nominated` the path the check was sent on.
+    async fn send_ice_response(
+        recv_addr: SocketAddr,
+        response: Vec<u8>,
+        nominated: bool,
+        args: &ProcessDownstreamReceiveConfig,
+    ) {
+        args.proxy_metrics.ice_checks_total.inc();
+        if nominated {
+            debug!(args.log, "ICE candidate pair nominated"; "from" => recv_addr);
+        }
+        let response = Packet::new(recv_addr, response).with_priority(Priority::High);
+        if args.send_packets.send(response).await.is_err() {
+            error!(args.log, "Failed to send ICE check response"; "to" => recv_addr);
+        }
+    }
+
+    /// Send a packet received from `recv_addr`, the client identified by
+    /// `client_key`, to an endpoint. If there is no session for the packet
+    /// yet, one is only created if `new_session` allows it, and the
+    /// connection tracker (if enabled) admits it given the `token` found in
+    /// the packet's metadata. The packet is sent once `delay` has elapsed.
+    /// `confirmed` is whether the packet carried the client's connection id,
+    /// `rebind` whether an existing session may move to `recv_addr`, and
+    /// `client_version` the version read from the packet, if any.
+    #[allow(clippy::too_many_arguments)]
+    async fn session_send_packet(
+        packet: &[u8],
+        delay: Duration,
+        recv_addr: SocketAddr,
+        client_key: &ClientKey,
+        confirmed: bool,
+        rebind: bool,
+        endpoint: &Endpoint,
+        token: Option<&[u8]>,
+        new_session: NewSession,
+        client_version: Option<&str>,
+        args: &ProcessDownstreamReceiveConfig,
+    ) -> SessionSendResult {
+        let session_key = SessionKey {
+            client: client_key.clone(),
+            endpoint: endpoint.address,
+        };
+
+        // Grab a read lock and find the session.
+        let lookup_timer = args.session_metrics.map_lookup_duration_seconds.start_timer();
+        let guard = args.session_manager.get_sessions().await;
+        let session = guard.get(&session_key);
+        lookup_timer.observe_duration();
+        if let Some(session) = session {
+            // If it exists then send the packet, we're done.
+            Self::session_send_packet_helper(
+                session, recv_addr, confirmed, rebind, packet, delay, args,
+            )
+            .await
+        } else {
+            // If it does not exist, grab a write lock so that we can create it.
+            //
+            // NOTE: We must drop the lock guard to release the lock before
+            // trying to acquire a write lock since these lock aren't reentrant,
+            // otherwise we will deadlock with our self.
+            drop(guard);
+
+            match new_session {
+                NewSession::Allowed => {}
+                NewSession::HandshakeRequired => return SessionSendResult::HandshakeRequired,
+                NewSession::Rejected => return SessionSendResult::Rejected,
+            }
+
+            // Ask the connection tracker before taking the write lock, so that
+            // other sessions aren't blocked while waiting for its decision.
+            let session_log = match &args.connection_tracker {
+                Some(connection_tracker) => {
+                    match connection_tracker.admit(recv_addr, token).await {
+                        Admission::Allow(annotations) if annotations.is_empty() => {
+                            args.log.clone()
+                        }
+                        Admission::Allow(annotations) => args
+                            .log
+                            .new(o!("annotations" => format!("{:?}", annotations))),
+                        Admission::Reject => {
+                            args.proxy_metrics.packets_dropped_session_rejected.inc();
+                            if let Some(ban_gossip) = &args.ban_gossip {
+                                ban_gossip.rejected(recv_addr.ip());
+                            }
+                            return SessionSendResult::Done;
+                        }
+                    }
+                }
+                None => args.log.clone(),
+            };
+            let session_log = match client_version {
+                Some(client_version) => {
+                    session_log.new(o!("client_version" => client_version.to_string()))
+                }
+                None => session_log,
+            };
+
+            // Grab a write lock. The insert is timed from here, so that it
+            // includes waiting for the lock and creating the session while
+            // holding it.
+            let insert_timer = args.session_metrics.map_insert_duration_seconds.start_timer();
+            let mut guard = args.session_manager.get_sessions_mut().await;
+
+            // Although we have the write lock now, check whether some other thread
             // managed to create the session in-between our dropping the read
             // lock and grabbing the write lock.
             if let Some(session) = guard.get(&session_key) {
                 // If the session now exists then we have less work to do,
                 // simply send the packet.
-                Self::session_send_packet_helper(&args.log, session, packet, args.session_ttl)
-                    .await;
+                Self::session_send_packet_helper(
+                    session, recv_addr, confirmed, rebind, packet, delay, args,
+                )
+                .await;
             } else {
                 // Otherwise, create the session and insert into the map.
-                match Session::new(
-                    &args.log,
-                    args.session_metrics.clone(),
-                    args.filter_manager.clone(),
-                    session_key.0,
+                let session_args = args.session_args(
+                    recv_addr,
+                    session_key.client.clone(),
                     endpoint.clone(),
-                    args.send_packets.clone(),
                     args.session_ttl,
-                )
-                .await
-                {
+                );
+                match Session::new(&session_log, session_args).await {
                     Ok(session) => {
                         // Insert the session into the map and release the write lock
                         // immediately since we don't want to block other threads while we send
                         // the packet. Instead, re-acquire a read lock and send the packet.
-                        guard.insert(session.key(), session);
+                        let key = session.key();
+                        args.client_drops
+                            .register(key.client.clone(), session.drop_reasons());
+                        guard.insert(key, session);
+                        args.update_peak_active_sessions();
+                        if let Some(client_versions) = &args.client_versions {
+                            client_versions.record_session(client_version);
+                        }
 
                         // Release the write lock.
                         drop(guard);
+                        insert_timer.observe_duration();
 
                         // Grab a read lock to send the packet.
                         let guard = args.session_manager.get_sessions().await;
                         if let Some(session) = guard.get(&session_key) {
                             Self::session_send_packet_helper(
-                                &args.log,
-                                &session,
-                                packet,
-                                args.session_ttl,
+                                &session, recv_addr, confirmed, rebind, packet, delay, args,
                             )
                             .await;
                         } else {
                             warn!(
                                 args.log,
                                 "Could not find session";
-                                "key" => format!("({}:{})", session_key.0.to_string(), session_key.1.to_string())
+                                "key" => %session_key
                             )
                         }
                     }
@@ -427,58 +1877,148 @@ impl Server {
                 }
             }
         }
+        SessionSendResult::Done
     }
 
-    // A helper function to push a session's packet on its socket.
+    // A helper function to push a session's packet on its socket, or
+    // schedule it to be pushed once `delay` has elapsed. If `rebind`, the
+    // session is moved to `recv_addr` first if its client's address changed.
+    // It stops sending the client its connection id once `confirmed`.
     async fn session_send_packet_helper(
-        log: &Logger,
         session: &Session,
+        recv_addr: SocketAddr,
+        confirmed: bool,
+        rebind: bool,
         packet: &[u8],
-        ttl: Duration,
+        delay: Duration,
+        args: &ProcessDownstreamReceiveConfig,
     ) {
-        match session.send(packet).await {
+        if rebind {
+            session.rebind(recv_addr);
+        }
+        if confirmed {
+            session.confirm_connection_id();
+        }
+        let result = if delay > Duration::from_secs(0) {
+            session.send_after(&args.scheduler, delay, packet.to_vec());
+            Ok(None)
+        } else {
+            session.send(packet).await
+        };
+        match result {
             Ok(_) => {
-                if let Err(err) = session.update_expiration(ttl) {
-                    warn!(log, "Error updating session expiration"; "error" => %err)
+                if let Err(err) = session.update_expiration(args.session_ttl) {
+                    warn!(args.log, "Error updating session expiration"; "error" => %err)
                 }
             }
-            Err(err) => error!(log, "Error sending packet from session"; "error" => %err),
+            Err(err) => error!(args.log, "Error sending packet from session"; "error" => %err),
         };
     }
 
     /// run_receive_packet is a non-blocking loop on receive_packets.recv() channel
-    /// and sends each packet on to the Packet.dest
+    /// and sends each packet on to the Packet.dest, delayed packets being
+    /// handed to `scheduler`. Packets waiting to be sent are sent in priority
+    /// order, and packets to relayed clients through their relay.
     fn run_receive_packet(
         &self,
         socket: Arc<UdpSocket>,
         mut receive_packets: mpsc::Receiver<Packet>,
+        scheduler: Arc<Scheduler>,
+        faults: Option<Arc<FaultInjector>>,
+        relayed: Option<RelayedClients>,
     ) {
         let log = self.log.clone();
+        let metrics = self.proxy_metrics.clone();
         tokio::spawn(async move {
-            while let Some(packet) = receive_packets.recv().await {
+            let mut queues = PriorityQueues::default();
+            loop {
+                if queues.is_empty() {
+                    match receive_packets.recv().await {
+                        Some(packet) => queues.push(packet),
+                        None => break,
+                    }
+                }
+                // Take the packets that are already waiting, so that higher
+                // priority packets overtake lower priority ones whenever
+                // packets arrive faster than they can be sent.
+                while !queues.is_full() {
+                    match receive_packets.try_recv() {
+                        Ok(packet) => queues.push(packet),
+                        Err(_) => break,
+                    }
+                }
+                let packet = match queues.pop() {
+                    Some(packet) => packet,
+                    None => continue,
+                };
+
                 debug!(
                     log,
                     "Sending packet back to origin";
                     "origin" => packet.dest(),
+                    "delay" => ?packet.delay(),
+                    "priority" => ?packet.priority(),
                     "contents" => debug::bytes_to_string(packet.contents()),
                 );
 
-                if let Err(err) = socket.send_to(packet.contents(), &packet.dest()).await {
-                    error!(log, "Error sending packet"; "dest" => %packet.dest(), "error" => %err);
+                if packet.delay() > Duration::from_secs(0) {
+                    let log = log.clone();
+                    let socket = socket.clone();
+                    let metrics = metrics.clone();
+                    let faults = faults.clone();
+                    let relayed = relayed.clone();
+                    scheduler.schedule(packet.delay(), async move {
+                        let (faults, relayed) = (faults.as_deref(), relayed.as_ref());
+                        Self::send_packet(&log, &socket, &metrics, faults, relayed, packet).await
+                    });
+                } else {
+                    let (faults, relayed) = (faults.as_deref(), relayed.as_ref());
+                    Self::send_packet(&log, &socket, &metrics, faults, relayed, packet).await;
                 }
             }
             debug!(log, "Receiver closed");
         });
     }
 
+    async fn send_packet(
+        log: &Logger,
+        socket: &UdpSocket,
+        metrics: &ProxyMetrics,
+        faults: Option<&FaultInjector>,
+        relayed: Option<&RelayedClients>,
+        packet: Packet,
+    ) {
+        if let Some(relayed) = relayed {
+            if relayed.deliver(packet.dest(), packet.contents()) {
+                return;
+            }
+        }
+        let result = match faults.map(FaultInjector::send) {
+            Some(Err(err)) => Err(err),
+            _ => socket.send_to(packet.contents(), &packet.dest()).await,
+        };
+        if let Err(err) = result {
+            if is_message_too_large(&err) {
+                metrics.packets_dropped_message_too_large.inc();
+            }
+            error!(log, "Error sending packet"; "dest" => %packet.dest(), "error" => %err);
+        }
+    }
+
     /// log_config outputs a log of what is configured
     fn log_config(&self) {
         info!(self.log, "Starting"; "port" => self.config.proxy.port);
     }
 
-    /// bind binds the local configured port
-    async fn bind(port: u16) -> Result<UdpSocket> {
+    /// bind binds the local configured port, alongside other sockets with
+    /// `SO_REUSEPORT` set if `reuse_port` is.
+    async fn bind(port: u16, reuse_port: bool) -> Result<UdpSocket> {
         let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
+        if reuse_port {
+            return port_conflict::bind_reuse_port(addr)
+                .and_then(UdpSocket::from_std)
+                .map_err(Error::Bind);
+        }
         UdpSocket::bind(addr).await.map_err(Error::Bind)
     }
 }
@@ -498,7 +2038,9 @@ mod tests {
 
     use crate::cluster::cluster_manager::ClusterManager;
     use crate::config;
-    use crate::config::{Builder as ConfigBuilder, EndPoint, Endpoints};
+    use crate::config::{
+        Builder as ConfigBuilder, Config, EndPoint, Endpoints, FairQueue as FairQueueConfig,
+    };
     use crate::filters::{manager::FilterManager, FilterChain};
     use crate::proxy::sessions::Packet;
     use crate::proxy::Builder;
@@ -508,6 +2050,57 @@ mod tests {
 
     use super::*;
 
+    /// Returns the config of a worker that sends packets to the endpoints of
+    /// `cluster_manager` through an empty filter chain and creates sessions
+    /// in `session_manager`, with every optional feature disabled.
+    pub(super) fn receive_config(
+        t: &TestHelper,
+        registry: &Registry,
+        cluster_manager: SharedClusterManager,
+        session_manager: SessionManager,
+        send_packets: mpsc::Sender<Packet>,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> ProcessDownstreamReceiveConfig {
+        ProcessDownstreamReceiveConfig {
+            log: t.log.clone(),
+            proxy_metrics: ProxyMetrics::new(registry).unwrap(),
+            session_metrics: SessionMetrics::new(registry).unwrap(),
+            cluster_manager,
+            filter_manager: FilterManager::fixed(Arc::new(
+                FilterChain::new(vec![], registry).unwrap(),
+            )),
+            session_manager,
+            session_ttl: Duration::from_secs(10),
+            send_packets,
+            packet_size_limit: PacketSizeLimit::default(),
+            packet_buffer: None,
+            connection_tracker: None,
+            handshake: None,
+            compute_pool: None,
+            upstream_socket: UpstreamSocket::default(),
+            tunnel: None,
+            scheduler: Arc::new(Scheduler::new(registry, shutdown_rx).unwrap()),
+            packet_deadline: None,
+            first_packet: None,
+            client_versions: None,
+            response_only_endpoints: None,
+            endpoint_schedules: None,
+            endpoint_health: None,
+            slow_start: None,
+            tap: None,
+            faults: None,
+            session_key: None,
+            connection_ids: None,
+            ice: None,
+            decision_log: None,
+            ban_gossip: None,
+            client_drops: Arc::default(),
+            relay: None,
+            ordered_sends: None,
+            supervisor: None,
+        }
+    }
+
     #[tokio::test]
     async fn run_server() {
         let mut t = TestHelper::default();
@@ -563,6 +2156,34 @@ mod tests {
         assert_eq!(msg, endpoint.packet_rx.await.unwrap());
     }
 
+    #[tokio::test]
+    async fn responses_sent_from_listening_port() {
+        let mut t = TestHelper::default();
+
+        let endpoint = t.run_echo_server().await;
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12368);
+        let config = ConfigBuilder::empty()
+            .with_port(local_addr.port())
+            .with_static(vec![], vec![EndPoint::new(endpoint)])
+            .build();
+        t.run_server_with_config(config);
+
+        // Clients behind restrictive NATs only accept packets from the
+        // address and port they sent to, so responses must be sent from the
+        // listening socket rather than the session's upstream socket.
+        let client = t.create_socket().await;
+        let mut buf = vec![0; 1024];
+        for _ in 0..2 {
+            client.send_to(b"hello", &local_addr).await.unwrap();
+            let (size, from) = timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(b"hello", &buf[..size]);
+            assert_eq!(local_addr, from);
+        }
+    }
+
     #[tokio::test]
     async fn run_with_filter() {
         let mut t = TestHelper::default();
@@ -602,7 +2223,7 @@ mod tests {
 
     #[tokio::test]
     async fn bind() {
-        let socket = Server::bind(12345).await.unwrap();
+        let socket = Server::bind(12345, false).await.unwrap();
         let addr = socket.local_addr().unwrap();
 
         let expected = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 12345);
@@ -639,7 +2260,6 @@ mod tests {
             // need to switch to 127.0.0.1, as the request comes locally
             receive_addr.set_ip("127.0.0.1".parse().unwrap());
 
-            let session_manager = SessionManager::new(t.log.clone(), shutdown_rx.clone());
             let (send_packets, mut recv_packets) = mpsc::channel::<Packet>(1);
 
             let time_increment = 10;
@@ -656,6 +2276,13 @@ mod tests {
                 Endpoints::new(vec![Endpoint::from_address(endpoint_address)]).unwrap(),
             )
             .unwrap();
+            let session_manager = SessionManager::new(
+                t.log.clone(),
+                SessionMetrics::new(registry).unwrap(),
+                cluster_manager.clone(),
+                None,
+                shutdown_rx.clone(),
+            );
             let filter_manager = FilterManager::fixed(chain.clone());
             for worker_id in 0..num_workers {
                 let (packet_tx, packet_rx) = mpsc::channel(num_workers);
@@ -666,17 +2293,20 @@ mod tests {
                 let session_metrics = SessionMetrics::new(&metrics.registry).unwrap();
                 worker_configs.push(DownstreamReceiveWorkerConfig {
                     worker_id,
-                    packet_rx,
+                    packet_rx: WorkerQueue::Channel(packet_rx),
                     shutdown_rx: shutdown_rx.clone(),
                     receive_config: ProcessDownstreamReceiveConfig {
-                        log: t.log.clone(),
                         proxy_metrics,
                         session_metrics,
-                        cluster_manager: cluster_manager.clone(),
                         filter_manager: filter_manager.clone(),
-                        session_manager: session_manager.clone(),
-                        session_ttl: Duration::from_secs(10),
-                        send_packets: send_packets.clone(),
+                        ..receive_config(
+                            &t,
+                            registry,
+                            cluster_manager.clone(),
+                            session_manager.clone(),
+                            send_packets.clone(),
+                            shutdown_rx.clone(),
+                        )
                     },
                 })
             }
@@ -685,7 +2315,7 @@ mod tests {
 
             for packet_tx in packet_txs {
                 packet_tx
-                    .send((receive_addr, msg.as_bytes().to_vec()))
+                    .send((receive_addr, msg.as_bytes().to_vec(), SystemTime::now()))
                     .await
                     .unwrap();
             }
@@ -697,7 +2327,7 @@ mod tests {
 
             let map = session_manager.get_sessions().await;
             assert_eq!(expected.session_len, map.len());
-            let build_key = (receive_addr, endpoint.socket.local_addr().unwrap());
+            let build_key = SessionKey::from((receive_addr, endpoint.socket.local_addr().unwrap()));
             assert!(map.contains_key(&build_key));
             let session = map.get(&build_key).unwrap();
             let now_secs = SystemTime::now()
@@ -745,29 +2375,523 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn run_recv_from() {
+    async fn shed_packets_past_deadline() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(
+                "127.0.0.1:7001".parse().unwrap(),
+            )])
+            .unwrap(),
+        )
+        .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+        let config = ProcessDownstreamReceiveConfig {
+            packet_deadline: Some(Duration::from_millis(100)),
+            ..receive_config(
+                &t,
+                &registry,
+                cluster_manager,
+                session_manager.clone(),
+                send_packets,
+                shutdown_rx.clone(),
+            )
+        };
+
+        let received_at = SystemTime::now() - Duration::from_secs(1);
+        Server::process_downstream_received_packet(
+            ("127.0.0.1:7000".parse().unwrap(), b"hello".to_vec(), received_at),
+            &config,
+        )
+        .await;
+
+        assert_eq!(1, config.proxy_metrics.packets_shed_queued.get());
+        assert!(session_manager.get_sessions().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn buffer_while_endpoints_unhealthy() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let endpoint = "127.0.0.1:7001".parse().unwrap();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(endpoint)]).unwrap(),
+        )
+        .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
+        let endpoint_health = EndpointHealth::new(
+            &t.log,
+            config::EndpointHealthCheck {
+                max_send_failures: 1,
+                response_timeout: None,
+                cooldown: Duration::from_secs(60),
+            },
+            &registry,
+        )
+        .unwrap();
+        endpoint_health.record_send(endpoint, false);
+        let packet_buffer = Arc::new(PacketBuffer::new(
+            config::FailoverBuffer {
+                max_delay: Duration::from_secs(60),
+                max_packets: 10,
+            },
+            ProxyMetrics::new(&registry).unwrap(),
+        ));
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+        let config = ProcessDownstreamReceiveConfig {
+            endpoint_health: Some(endpoint_health),
+            packet_buffer: Some(packet_buffer.clone()),
+            ..receive_config(
+                &t,
+                &registry,
+                cluster_manager,
+                session_manager.clone(),
+                send_packets,
+                shutdown_rx.clone(),
+            )
+        };
+
+        // The packet is held rather than sent to the unhealthy endpoint.
+        Server::process_downstream_received_packet(
+            (
+                "127.0.0.1:7000".parse().unwrap(),
+                b"hello".to_vec(),
+                SystemTime::now(),
+            ),
+            &config,
+        )
+        .await;
+        assert!(!packet_buffer.is_empty());
+        assert!(session_manager.get_sessions().await.is_empty());
+
+        // It isn't forwarded while the endpoint is still unhealthy.
+        Server::flush_failover_buffer(&packet_buffer, &config).await;
+        assert!(!packet_buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reject_first_packet() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(
+                "127.0.0.1:7001".parse().unwrap(),
+            )])
+            .unwrap(),
+        )
+        .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+        let config = ProcessDownstreamReceiveConfig {
+            first_packet: Some(FirstPacket {
+                prefix: b"QUIL".to_vec(),
+                min_size: 8,
+            }),
+            ..receive_config(
+                &t,
+                &registry,
+                cluster_manager,
+                session_manager.clone(),
+                send_packets,
+                shutdown_rx.clone(),
+            )
+        };
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+
+        for packet in &[&b"QUIL"[..], b"hello world"] {
+            Server::process_downstream_received_packet(
+                (from, packet.to_vec(), SystemTime::now()),
+                &config,
+            )
+            .await;
+        }
+        assert_eq!(
+            2,
+            config
+                .proxy_metrics
+                .packets_dropped_first_packet_rejected
+                .get()
+        );
+        assert!(session_manager.get_sessions().await.is_empty());
+
+        Server::process_downstream_received_packet(
+            (from, b"QUILKIN!".to_vec(), SystemTime::now()),
+            &config,
+        )
+        .await;
+        assert_eq!(1, session_manager.get_sessions().await.len());
+
+        // Once the client has a session, its packets aren't checked.
+        Server::process_downstream_received_packet(
+            (from, b"hello world".to_vec(), SystemTime::now()),
+            &config,
+        )
+        .await;
+        assert_eq!(
+            2,
+            config
+                .proxy_metrics
+                .packets_dropped_first_packet_rejected
+                .get()
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_id() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let endpoint: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(endpoint)]).unwrap(),
+        )
+        .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
+        let connection_ids = Arc::new(ConnectionIds::new(config::ConnectionId {
+            secret: b"secret".to_vec(),
+            secret_ref: None,
+            prefix: b"CID".to_vec(),
+        }));
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+        let config = ProcessDownstreamReceiveConfig {
+            connection_ids: Some(connection_ids.clone()),
+            ..receive_config(
+                &t,
+                &registry,
+                cluster_manager,
+                session_manager.clone(),
+                send_packets,
+                shutdown_rx.clone(),
+            )
+        };
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let moved: SocketAddr = "127.0.0.1:7002".parse().unwrap();
+
+        // The session created for the client is keyed by the id it is issued.
+        Server::process_downstream_received_packet(
+            (from, b"hello".to_vec(), SystemTime::now()),
+            &config,
+        )
+        .await;
+        let id = connection_ids.id(from);
+        let key = SessionKey {
+            client: ClientKey::ConnectionId(id.clone()),
+            endpoint,
+        };
+        assert!(session_manager.get_sessions().await.contains_key(&key));
+
+        // Once the client moves, packets carrying its id are matched to its
+        // session.
+        let mut packet = connection_ids.header(&id);
+        packet.extend_from_slice(b"hello");
+        Server::process_downstream_received_packet((moved, packet, SystemTime::now()), &config)
+            .await;
+        let sessions = session_manager.get_sessions().await;
+        assert_eq!(1, sessions.len());
+        assert_eq!(moved, sessions.get(&key).unwrap().client());
+        drop(sessions);
+
+        // Packets carrying a forged id are dropped.
+        let mut packet = connection_ids.header(&connection_ids.id(moved));
+        packet[4] ^= 1;
+        Server::process_downstream_received_packet((moved, packet, SystemTime::now()), &config)
+            .await;
+        assert_eq!(
+            1,
+            config
+                .proxy_metrics
+                .packets_dropped_invalid_connection_id
+                .get()
+        );
+        assert_eq!(1, session_manager.get_sessions().await.len());
+    }
+
+    #[tokio::test]
+    async fn rebind_unverified() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let endpoint = Endpoint::from_address("127.0.0.1:7001".parse().unwrap());
+        let cluster_manager =
+            ClusterManager::fixed(&registry, Endpoints::new(vec![endpoint.clone()]).unwrap())
+                .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+        let config = receive_config(
+            &t,
+            &registry,
+            cluster_manager,
+            session_manager.clone(),
+            send_packets,
+            shutdown_rx.clone(),
+        );
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let moved: SocketAddr = "127.0.0.1:7002".parse().unwrap();
+        let client_key = ClientKey::ConnectionId(b"abc".to_vec());
+        let key = SessionKey {
+            client: client_key.clone(),
+            endpoint: endpoint.address,
+        };
+        let send = |recv_addr, rebind| {
+            Server::session_send_packet(
+                b"hello",
+                Duration::from_secs(0),
+                recv_addr,
+                &client_key,
+                false,
+                rebind,
+                &endpoint,
+                None,
+                NewSession::Allowed,
+                None,
+                &config,
+            )
+        };
+
+        // The session stays with its client while packets with its key from
+        // other addresses don't prove they're from the client.
+        send(from, false).await;
+        send(moved, false).await;
+        let sessions = session_manager.get_sessions().await;
+        assert_eq!(from, sessions.get(&key).unwrap().client());
+        drop(sessions);
+
+        send(moved, true).await;
+        let sessions = session_manager.get_sessions().await;
+        assert_eq!(moved, sessions.get(&key).unwrap().client());
+    }
+
+    #[tokio::test]
+    async fn relay() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let relayed: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![
+                Endpoint::new(relayed, vec![b"abc".to_vec()].into_iter().collect(), None),
+                Endpoint::from_address("127.0.0.1:7002".parse().unwrap()),
+            ])
+            .unwrap(),
+        )
+        .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+        let config = ProcessDownstreamReceiveConfig {
+            relay: Some(Relay {
+                require_envelope: true,
+            }),
+            ..receive_config(
+                &t,
+                &registry,
+                cluster_manager,
+                session_manager.clone(),
+                send_packets,
+                shutdown_rx.clone(),
+            )
+        };
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+
+        // Enveloped packets are only sent to the endpoints with their token.
+        Server::process_downstream_received_packet(
+            (from, relay::seal(b"abc", b"hello"), SystemTime::now()),
+            &config,
+        )
+        .await;
+        let sessions = session_manager.get_sessions().await;
+        assert_eq!(1, sessions.len());
+        assert!(sessions.contains_key(&SessionKey::from((from, relayed))));
+        drop(sessions);
+
+        Server::process_downstream_received_packet(
+            (from, relay::seal(b"xyz", b"hello"), SystemTime::now()),
+            &config,
+        )
+        .await;
+        assert_eq!(
+            1,
+            config.proxy_metrics.packets_dropped_no_relay_endpoint.get()
+        );
+
+        // Packets without an envelope are dropped, as one is required.
+        Server::process_downstream_received_packet(
+            (from, b"hello".to_vec(), SystemTime::now()),
+            &config,
+        )
+        .await;
+        assert_eq!(
+            1,
+            config
+                .proxy_metrics
+                .packets_dropped_invalid_relay_envelope
+                .get()
+        );
+        assert_eq!(1, session_manager.get_sessions().await.len());
+    }
+
+    /// Returns a window that starts `start` from now and lasts an hour.
+    fn window_from_now(start: Duration) -> config::ActivationWindow {
+        let time_of_day = |offset: Duration| {
+            let since_midnight =
+                (SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + offset).as_secs() % 86400;
+            let since_midnight = since_midnight as u32;
+            config::TimeOfDay::new(
+                since_midnight / 3600,
+                since_midnight / 60 % 60,
+                since_midnight % 60,
+            )
+            .unwrap()
+        };
+        config::ActivationWindow {
+            start: time_of_day(start),
+            end: time_of_day(start + Duration::from_secs(3600)),
+        }
+    }
+
+    #[tokio::test]
+    async fn schedule_endpoints() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let active: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let inactive: SocketAddr = "127.0.0.1:7002".parse().unwrap();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![
+                Endpoint::from_address(active),
+                Endpoint::from_address(inactive),
+            ])
+            .unwrap(),
+        )
+        .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+        let mut endpoint_schedules = EndpointSchedules::new();
+        endpoint_schedules.insert(
+            active,
+            vec![window_from_now(Duration::from_secs(23 * 3600))],
+        );
+        endpoint_schedules.insert(inactive, vec![window_from_now(Duration::from_secs(3600))]);
+        let mut config = ProcessDownstreamReceiveConfig {
+            endpoint_schedules: Some(Arc::new(endpoint_schedules)),
+            ..receive_config(
+                &t,
+                &registry,
+                cluster_manager,
+                session_manager.clone(),
+                send_packets,
+                shutdown_rx.clone(),
+            )
+        };
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+
+        Server::process_downstream_received_packet(
+            (from, b"hello".to_vec(), SystemTime::now()),
+            &config,
+        )
+        .await;
+        {
+            let sessions = session_manager.get_sessions().await;
+            assert_eq!(1, sessions.len());
+            assert!(sessions.contains_key(&SessionKey::from((from, active))));
+        }
+
+        // Packets are dropped while no endpoints are active.
+        let mut endpoint_schedules = EndpointSchedules::new();
+        endpoint_schedules.insert(active, vec![window_from_now(Duration::from_secs(3600))]);
+        endpoint_schedules.insert(inactive, vec![window_from_now(Duration::from_secs(3600))]);
+        config.endpoint_schedules = Some(Arc::new(endpoint_schedules));
+        Server::process_downstream_received_packet(
+            ("127.0.0.1:7003".parse().unwrap(), b"hello".to_vec(), SystemTime::now()),
+            &config,
+        )
+        .await;
+        assert_eq!(1, config.proxy_metrics.packets_dropped_no_endpoints.get());
+        assert_eq!(1, session_manager.get_sessions().await.len());
+    }
+
+    /// Runs the receive loop of a server with `config`, and checks that it
+    /// forwards a packet to an endpoint.
+    async fn assert_run_recv_from(config: Config) {
         let t = TestHelper::default();
         let (_shutdown_tx, shutdown_rx) = watch::channel(());
 
         let msg = "hello";
         let endpoint = t.open_socket_and_recv_single_packet().await;
         let socket = t.create_socket().await;
-        let session_manager = SessionManager::new(t.log.clone(), shutdown_rx.clone());
+        let registry = Registry::default();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(
+                endpoint.socket.local_addr().unwrap(),
+            )])
+            .unwrap(),
+        )
+        .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
         let (send_packets, mut recv_packets) = mpsc::channel::<Packet>(1);
 
-        let config = Arc::new(config_with_dummy_endpoint().build());
-        let server = Builder::from(config).validate().unwrap().build();
-        let registry = Registry::default();
+        let server = Builder::from(Arc::new(config)).validate().unwrap().build();
 
         server.run_recv_from(RunRecvFromArgs {
-            cluster_manager: ClusterManager::fixed(
-                &registry,
-                Endpoints::new(vec![Endpoint::from_address(
-                    endpoint.socket.local_addr().unwrap(),
-                )])
-                .unwrap(),
-            )
-            .unwrap(),
+            cluster_manager,
             filter_manager: FilterManager::fixed(Arc::new(
                 FilterChain::new(vec![], &registry).unwrap(),
             )),
@@ -775,6 +2899,17 @@ mod tests {
             session_manager: session_manager.clone(),
             session_ttl: Duration::from_secs(10),
             send_packets,
+            connection_tracker: None,
+            tunnel: None,
+            scheduler: Arc::new(Scheduler::new(&registry, shutdown_rx.clone()).unwrap()),
+            endpoint_health: None,
+            slow_start: None,
+            faults: None,
+            decision_log: None,
+            ban_gossip: None,
+            standby: None,
+            supervisor: None,
+            relayed_packets: None,
             shutdown_rx,
         });
 
@@ -791,6 +2926,106 @@ mod tests {
         recv_packets.close();
     }
 
+    #[tokio::test]
+    async fn run_recv_from() {
+        assert_run_recv_from(config_with_dummy_endpoint().build()).await;
+    }
+
+    #[tokio::test]
+    async fn run_recv_from_fair_queue() {
+        let mut config = config_with_dummy_endpoint().build();
+        config.proxy.fair_queue = Some(FairQueueConfig { queue_size: 16 });
+        assert_run_recv_from(config).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn run_recv_from_take_over() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let mut config = config_with_dummy_endpoint().build();
+        config.proxy.port_conflict_policy = config::PortConflictPolicy::TakeOver;
+        config.proxy.port_conflict_secret = b"secret".to_vec();
+        let socket = t.create_socket().await;
+        let registry = Registry::default();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(
+                "127.0.0.1:7001".parse().unwrap(),
+            )])
+            .unwrap(),
+        )
+        .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+
+        let server = Builder::from(Arc::new(config)).validate().unwrap().build();
+        let recv_loop = server.run_recv_from(RunRecvFromArgs {
+            cluster_manager,
+            filter_manager: FilterManager::fixed(Arc::new(
+                FilterChain::new(vec![], &registry).unwrap(),
+            )),
+            socket: socket.clone(),
+            session_manager,
+            session_ttl: Duration::from_secs(10),
+            send_packets,
+            connection_tracker: None,
+            tunnel: None,
+            scheduler: Arc::new(Scheduler::new(&registry, shutdown_rx.clone()).unwrap()),
+            endpoint_health: None,
+            slow_start: None,
+            faults: None,
+            decision_log: None,
+            ban_gossip: None,
+            standby: None,
+            supervisor: None,
+            relayed_packets: None,
+            shutdown_rx,
+        });
+
+        // A request from another process, as one from this process is
+        // ignored.
+        let request = PortConflictRequest {
+            kind: PortConflictKind::TakeOver,
+            nonce: 1,
+            pid: std::process::id() + 1,
+            sent_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+        };
+        let port = socket.local_addr().unwrap().port();
+        let client = t.create_socket().await;
+        client
+            .send_to(&request.encode(b"secret"), ("127.0.0.1", port))
+            .await
+            .unwrap();
+
+        let mut buf = [0; 512];
+        let (size, _) = timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .expect("should get a reply")
+            .unwrap();
+        let peer = port_conflict::Peer::parse(&buf[..size], request.nonce, b"secret").unwrap();
+        assert_eq!("test", peer.id);
+        assert!(peer.stopping);
+
+        // The proxy stops once it has replied.
+        assert_eq!(
+            Ok(()),
+            timeout(Duration::from_secs(1), recv_loop)
+                .await
+                .expect("receive loop should stop")
+                .unwrap()
+        );
+    }
+
     #[tokio::test]
     async fn run_receive_packet() {
         let t = TestHelper::default();
@@ -812,7 +3047,223 @@ mod tests {
         }
         let config = Arc::new(config_with_dummy_endpoint().build());
         let server = Builder::from(config).validate().unwrap().build();
-        server.run_receive_packet(endpoint.socket, recv_packet);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        server.run_receive_packet(
+            endpoint.socket,
+            recv_packet,
+            Arc::new(Scheduler::new(&Registry::default(), shutdown_rx).unwrap()),
+            None,
+            None,
+        );
         assert_eq!(msg, endpoint.packet_rx.await.unwrap());
     }
+
+    #[tokio::test]
+    async fn run_receive_packet_relayed() {
+        let t = TestHelper::default();
+
+        let (send_packet, recv_packet) = mpsc::channel::<Packet>(1);
+        let (relayed, _relayed_packets) = RelayedClients::new();
+        let client = "192.0.2.1:7000".parse().unwrap();
+        let (_association, mut received) = relayed.register(client);
+        send_packet
+            .send(Packet::new(client, b"hello".to_vec()))
+            .await
+            .unwrap();
+        let config = Arc::new(config_with_dummy_endpoint().build());
+        let server = Builder::from(config).validate().unwrap().build();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        server.run_receive_packet(
+            t.create_socket().await,
+            recv_packet,
+            Arc::new(Scheduler::new(&Registry::default(), shutdown_rx).unwrap()),
+            None,
+            Some(relayed),
+        );
+
+        // Packets to relayed clients are delivered through their relay.
+        let packet = timeout(Duration::from_secs(1), received.recv())
+            .await
+            .unwrap();
+        assert_eq!(Some(b"hello".to_vec()), packet);
+    }
+
+    #[tokio::test]
+    async fn run_receive_packet_delayed() {
+        let t = TestHelper::default();
+
+        let (send_packet, recv_packet) = mpsc::channel::<Packet>(1);
+        let endpoint = t.open_socket_and_recv_single_packet().await;
+        send_packet
+            .send(
+                Packet::new(endpoint.socket.local_addr().unwrap(), b"hello".to_vec())
+                    .with_delay(Duration::from_millis(100)),
+            )
+            .await
+            .unwrap();
+        let config = Arc::new(config_with_dummy_endpoint().build());
+        let server = Builder::from(config).validate().unwrap().build();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let start = std::time::Instant::now();
+        server.run_receive_packet(
+            endpoint.socket,
+            recv_packet,
+            Arc::new(Scheduler::new(&Registry::default(), shutdown_rx).unwrap()),
+            None,
+            None,
+        );
+        assert_eq!("hello", endpoint.packet_rx.await.unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn run_receive_packet_prioritized() {
+        let mut t = TestHelper::default();
+
+        let (send_packet, recv_packet) = mpsc::channel::<Packet>(3);
+        let (mut packet_rx, socket) = t.open_socket_and_recv_multiple_packets().await;
+        let dest = socket.local_addr().unwrap();
+        for &(contents, priority) in &[
+            ("low", Priority::Low),
+            ("normal", Priority::Normal),
+            ("high", Priority::High),
+        ] {
+            send_packet
+                .send(Packet::new(dest, contents.into()).with_priority(priority))
+                .await
+                .unwrap();
+        }
+
+        let config = Arc::new(config_with_dummy_endpoint().build());
+        let server = Builder::from(config).validate().unwrap().build();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        server.run_receive_packet(
+            t.create_socket().await,
+            recv_packet,
+            Arc::new(Scheduler::new(&Registry::default(), shutdown_rx).unwrap()),
+            None,
+            None,
+        );
+
+        // All packets were already waiting, so they are sent in priority
+        // order.
+        for &expected in &["high", "normal", "low"] {
+            assert_eq!(expected, packet_rx.recv().await.unwrap());
+        }
+    }
+
+    /// Returns the sample count, mean and approximate 99th percentile of
+    /// `histogram`.
+    fn summarize(histogram: &prometheus::Histogram) -> String {
+        use prometheus::core::Metric;
+
+        let histogram = histogram.metric();
+        let histogram = histogram.get_histogram();
+        let count = histogram.get_sample_count();
+        let p99 = histogram
+            .get_bucket()
+            .iter()
+            .find(|bucket| bucket.get_cumulative_count() as f64 >= count as f64 * 0.99)
+            .map(|bucket| format!("<= {}s", bucket.get_upper_bound()))
+            .unwrap_or_else(|| "> largest bucket".into());
+        format!(
+            "count: {}, mean: {:.9}s, p99: {}",
+            count,
+            histogram.get_sample_sum() / count.max(1) as f64,
+            p99
+        )
+    }
+
+    /// Stresses the session map with many workers sending packets from a
+    /// pool of clients through `session_send_packet`, which looks up and
+    /// creates short lived sessions while expired sessions are pruned, and
+    /// prints how long each map operation took.
+    ///
+    /// Run it with `cargo test stress_session_map -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn stress_session_map() {
+        const WORKERS: usize = 32;
+        const CLIENTS: u16 = 2000;
+        const DURATION: Duration = Duration::from_secs(10);
+
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        // The endpoint never responds, so sessions are pruned shortly after
+        // they're created.
+        let endpoint_socket = t.create_socket().await;
+        let endpoint = Endpoint::from_address(
+            (
+                Ipv4Addr::LOCALHOST,
+                endpoint_socket.local_addr().unwrap().port(),
+            )
+                .into(),
+        );
+        let cluster_manager =
+            ClusterManager::fixed(&registry, Endpoints::new(vec![endpoint.clone()]).unwrap())
+                .unwrap();
+        let metrics = SessionMetrics::new(&registry).unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            metrics.clone(),
+            cluster_manager.clone(),
+            Some(Duration::from_millis(100)),
+            shutdown_rx.clone(),
+        );
+        let (send_packets, mut recv_packets) = mpsc::channel::<Packet>(1);
+        tokio::spawn(async move { while recv_packets.recv().await.is_some() {} });
+        let config = Arc::new(ProcessDownstreamReceiveConfig {
+            session_metrics: metrics.clone(),
+            session_ttl: Duration::from_secs(1),
+            ..receive_config(
+                &t,
+                &registry,
+                cluster_manager,
+                session_manager,
+                send_packets,
+                shutdown_rx,
+            )
+        });
+        let deadline = time::Instant::now() + DURATION;
+
+        let workers = (0..WORKERS)
+            .map(|_| {
+                let config = config.clone();
+                let endpoint = endpoint.clone();
+                tokio::spawn(async move {
+                    while time::Instant::now() < deadline {
+                        let port = 10000 + rand::random::<u16>() % CLIENTS;
+                        let from: SocketAddr = (Ipv4Addr::LOCALHOST, port).into();
+                        Server::session_send_packet(
+                            b"hello",
+                            Duration::from_secs(0),
+                            from,
+                            &ClientKey::Address(from),
+                            false,
+                            false,
+                            &endpoint,
+                            None,
+                            NewSession::Allowed,
+                            None,
+                            &config,
+                        )
+                        .await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+
+        info!(t.log, "Session map stress test finished";
+            "sessions_created" => metrics.sessions_total.get(),
+            "lookup" => summarize(&metrics.map_lookup_duration_seconds),
+            "insert" => summarize(&metrics.map_insert_duration_seconds),
+            "prune" => summarize(&metrics.map_prune_duration_seconds));
+        assert!(metrics.map_lookup_duration_seconds.get_sample_count() > 0);
+        assert!(metrics.map_insert_duration_seconds.get_sample_count() > 0);
+        assert!(metrics.map_prune_duration_seconds.get_sample_count() > 0);
+    }
 }
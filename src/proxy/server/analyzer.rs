@@ -0,0 +1,300 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Keeps statistics on the packets received from clients, so that operators
+//! can see what is hitting the proxy without capturing its traffic.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::config::Analyzer as AnalyzerConfig;
+
+/// The upper bounds, in bytes, of the packet size buckets. Packets larger
+/// than the last bound are counted in a final bucket.
+const SIZE_BUCKETS: [usize; 6] = [64, 128, 256, 512, 1024, 1500];
+
+/// Keeps statistics on received packets over the current and the previous
+/// window.
+pub(crate) struct Analyzer {
+    config: AnalyzerConfig,
+    windows: Mutex<Windows>,
+}
+
+struct Windows {
+    /// When the current window started.
+    started: Instant,
+    current: Window,
+    previous: Window,
+    /// When the last packet was received.
+    last_received: Option<SystemTime>,
+}
+
+/// The statistics of the packets received in a window.
+#[derive(Default)]
+struct Window {
+    packets: u64,
+    bytes: u64,
+    /// The packets received from each address, up to `max_sources`
+    /// addresses.
+    sources: HashMap<SocketAddr, Talker>,
+    /// The number of packets from addresses beyond `max_sources`.
+    untracked_packets: u64,
+    /// The number of packets in each size bucket.
+    sizes: [u64; SIZE_BUCKETS.len() + 1],
+    inter_arrival: InterArrival,
+}
+
+/// The packets received from an address.
+#[derive(Clone, Copy, Default)]
+struct Talker {
+    packets: u64,
+    bytes: u64,
+}
+
+/// The time between consecutive packets.
+#[derive(Clone, Copy, Default)]
+struct InterArrival {
+    samples: u64,
+    total: Duration,
+    min: Option<Duration>,
+    max: Duration,
+}
+
+impl InterArrival {
+    fn record(&mut self, gap: Duration) {
+        self.samples += 1;
+        self.total += gap;
+        self.min = Some(self.min.map_or(gap, |min| min.min(gap)));
+        self.max = self.max.max(gap);
+    }
+
+    fn merge(&mut self, other: &InterArrival) {
+        self.samples += other.samples;
+        self.total += other.total;
+        self.min = match (self.min, other.min) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max = self.max.max(other.max);
+    }
+}
+
+impl Analyzer {
+    pub(crate) fn new(config: AnalyzerConfig) -> Self {
+        Self {
+            config,
+            windows: Mutex::new(Windows {
+                started: Instant::now(),
+                current: Window::default(),
+                previous: Window::default(),
+                last_received: None,
+            }),
+        }
+    }
+
+    /// Records a packet of `size` bytes received from `source` at
+    /// `received_at`.
+    pub(crate) fn record(&self, source: SocketAddr, size: usize, received_at: SystemTime) {
+        let mut windows = self.windows.lock();
+        self.rotate(&mut windows, Instant::now());
+
+        if let Some(gap) = windows
+            .last_received
+            .and_then(|last| received_at.duration_since(last).ok())
+        {
+            windows.current.inter_arrival.record(gap);
+        }
+        windows.last_received = Some(received_at);
+
+        let window = &mut windows.current;
+        window.packets += 1;
+        window.bytes += size as u64;
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|bound| size <= *bound)
+            .unwrap_or(SIZE_BUCKETS.len());
+        window.sizes[bucket] += 1;
+        if window.sources.contains_key(&source) || window.sources.len() < self.config.max_sources {
+            let talker = window.sources.entry(source).or_default();
+            talker.packets += 1;
+            talker.bytes += size as u64;
+        } else {
+            window.untracked_packets += 1;
+        }
+    }
+
+    /// Starts a new window if the current one has ended by `now`.
+    fn rotate(&self, windows: &mut Windows, now: Instant) {
+        let elapsed = now.duration_since(windows.started);
+        if elapsed < self.config.window {
+            return;
+        }
+        windows.previous = if elapsed < self.config.window * 2 {
+            std::mem::take(&mut windows.current)
+        } else {
+            // No packets were received in the window after the current one.
+            windows.current = Window::default();
+            Window::default()
+        };
+        windows.started = now;
+    }
+
+    /// Returns the statistics of the current and the previous window as
+    /// JSON.
+    pub(crate) fn report(&self) -> Value {
+        let mut windows = self.windows.lock();
+        self.rotate(&mut windows, Instant::now());
+        let Windows {
+            current, previous, ..
+        } = &*windows;
+
+        let mut sources = current.sources.clone();
+        for (address, talker) in &previous.sources {
+            let total = sources.entry(*address).or_default();
+            total.packets += talker.packets;
+            total.bytes += talker.bytes;
+        }
+        let mut top_talkers = sources.into_iter().collect::<Vec<_>>();
+        top_talkers.sort_by(|(_, a), (_, b)| b.packets.cmp(&a.packets));
+        top_talkers.truncate(self.config.top_talkers);
+
+        let packets = current.packets + previous.packets;
+        let share = |count: u64| {
+            if packets == 0 {
+                0.0
+            } else {
+                count as f64 / packets as f64
+            }
+        };
+        let top_talkers = top_talkers
+            .into_iter()
+            .map(|(address, talker)| {
+                json!({
+                    "address": address.to_string(),
+                    "packets": talker.packets,
+                    "bytes": talker.bytes,
+                    "share": share(talker.packets),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let packet_sizes = SIZE_BUCKETS
+            .iter()
+            .enumerate()
+            .map(|(i, bound)| match i {
+                0 => format!("0-{}", bound),
+                _ => format!("{}-{}", SIZE_BUCKETS[i - 1] + 1, bound),
+            })
+            .chain(std::iter::once(format!(
+                "{}+",
+                SIZE_BUCKETS[SIZE_BUCKETS.len() - 1] + 1
+            )))
+            .zip(current.sizes.iter().zip(previous.sizes.iter()))
+            .map(|(range, (a, b))| json!({ "bytes": range, "packets": a + b }))
+            .collect::<Vec<_>>();
+
+        let mut inter_arrival = current.inter_arrival;
+        inter_arrival.merge(&previous.inter_arrival);
+        let millis = |duration: Duration| duration.as_micros() as f64 / 1000.0;
+        let inter_arrival = match inter_arrival.min {
+            Some(min) => json!({
+                "min_ms": millis(min),
+                "mean_ms": millis(inter_arrival.total) / inter_arrival.samples as f64,
+                "max_ms": millis(inter_arrival.max),
+            }),
+            None => Value::Null,
+        };
+
+        json!({
+            "window_seconds": self.config.window.as_secs_f64(),
+            "packets": packets,
+            "bytes": current.bytes + previous.bytes,
+            "untracked_packets": current.untracked_packets + previous.untracked_packets,
+            "top_talkers": top_talkers,
+            "packet_sizes": packet_sizes,
+            "inter_arrival": inter_arrival,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::Analyzer;
+    use crate::config::Analyzer as AnalyzerConfig;
+
+    #[test]
+    fn report() {
+        let analyzer = Analyzer::new(AnalyzerConfig {
+            top_talkers: 2,
+            window: Duration::from_secs(60),
+            max_sources: 3,
+        });
+        let now = SystemTime::now();
+        let packets = [
+            ("127.0.0.1:7001", 10),
+            ("127.0.0.1:7002", 100),
+            ("127.0.0.1:7001", 2000),
+            ("127.0.0.1:7003", 64),
+            ("127.0.0.1:7004", 65),
+            ("127.0.0.1:7001", 10),
+        ];
+        for (i, (source, size)) in packets.iter().enumerate() {
+            analyzer.record(
+                source.parse().unwrap(),
+                *size,
+                now + Duration::from_millis(10 * i as u64),
+            );
+        }
+
+        let report = analyzer.report();
+        assert_eq!(6, report["packets"]);
+        assert_eq!(2249, report["bytes"]);
+        assert_eq!(1, report["untracked_packets"]);
+        assert_eq!(2, report["top_talkers"].as_array().unwrap().len());
+        assert_eq!("127.0.0.1:7001", report["top_talkers"][0]["address"]);
+        assert_eq!(3, report["top_talkers"][0]["packets"]);
+        assert_eq!(0.5, report["top_talkers"][0]["share"]);
+        assert_eq!("0-64", report["packet_sizes"][0]["bytes"]);
+        assert_eq!(3, report["packet_sizes"][0]["packets"]);
+        assert_eq!(2, report["packet_sizes"][1]["packets"]);
+        assert_eq!("1501+", report["packet_sizes"][6]["bytes"]);
+        assert_eq!(1, report["packet_sizes"][6]["packets"]);
+        assert_eq!(10.0, report["inter_arrival"]["min_ms"]);
+        assert_eq!(10.0, report["inter_arrival"]["mean_ms"]);
+    }
+
+    #[test]
+    fn windows_expire() {
+        let analyzer = Analyzer::new(AnalyzerConfig {
+            top_talkers: 10,
+            window: Duration::from_millis(10),
+            max_sources: 10,
+        });
+        analyzer.record("127.0.0.1:7001".parse().unwrap(), 10, SystemTime::now());
+        assert_eq!(1, analyzer.report()["packets"]);
+
+        std::thread::sleep(Duration::from_millis(30));
+        let report = analyzer.report();
+        assert_eq!(0, report["packets"]);
+        assert!(report["inter_arrival"].is_null());
+    }
+}
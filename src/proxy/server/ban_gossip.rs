@@ -0,0 +1,688 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use slog::{debug, warn, Logger};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Duration, Instant};
+
+use crate::config::{BanGossip as BanGossipConfig, Cidr};
+use crate::proxy::server::metrics::Metrics;
+
+/// The bytes that a ban sent to peers starts with.
+const PREFIX: &[u8] = b"QUILKIN_BAN";
+/// The size in bytes of the time a client was banned at.
+const TIMESTAMP_SIZE: usize = 8;
+/// The size in bytes of how long a client is banned for.
+const DURATION_SIZE: usize = 8;
+/// The size in bytes of the signature of a ban.
+const SIGNATURE_SIZE: usize = 32;
+/// The size of the buffer that bans are received into, which is large
+/// enough for the ban of an IPv6 address.
+const MAX_BAN_SIZE: usize = 128;
+/// The maximum number of bans waiting to be sent to peers.
+const QUEUE_SIZE: usize = 1024;
+
+/// Bans clients whose packets are dropped for one of the configured reasons,
+/// sharing the bans with peer proxies.
+///
+/// A client is only banned once `min_drops` of its packets are dropped
+/// within a sweep interval, and only if it has completed the handshake or
+/// has a session, so that packets with spoofed addresses can't get other
+/// clients banned.
+///
+/// A ban sent to peers is the prefix, followed by the time the client was
+/// banned at and how long it's banned for, both in milliseconds, the
+/// client's IP address, and an HMAC-SHA256 signature of all of them. Peers
+/// only apply bans that are signed with their secret and haven't expired,
+/// for at most as long as their own bans last. Bans received from peers
+/// aren't sent on, so each proxy must list all of the others as peers.
+///
+/// Bans are sent to peers in the background, so that banning a client never
+/// waits on the network. Loopback addresses and the configured `never_ban`
+/// ranges are never banned, whether locally, by a peer or by an operator.
+///
+/// Expired bans are removed every sweep interval, at which point the bans
+/// are also saved to the ban file, if configured and they've changed, so
+/// that they're loaded again once the proxy restarts.
+pub(crate) struct BanGossip {
+    log: Logger,
+    socket: UdpSocket,
+    peers: Vec<SocketAddr>,
+    secret: Vec<u8>,
+    duration: Duration,
+    drop_reasons: HashSet<String>,
+    min_drops: u32,
+    /// How many packets of each client have been dropped for one of
+    /// `drop_reasons` since the last sweep.
+    drops: Mutex<HashMap<IpAddr, u32>>,
+    ban_rejected: bool,
+    max_bans: usize,
+    file: Option<PathBuf>,
+    sweep_interval: Duration,
+    /// The ranges of the addresses of clients that are never banned.
+    never_ban: Vec<Cidr>,
+    /// When the ban of each banned client expires. Every packet checks it,
+    /// while it only changes when a client is banned or a ban expires.
+    bans: RwLock<HashMap<IpAddr, Instant>>,
+    /// The bans waiting to be sent to peers.
+    outgoing: mpsc::Sender<Vec<u8>>,
+    /// Taken by the background task that sends bans to peers.
+    outgoing_rx: Mutex<Option<mpsc::Receiver<Vec<u8>>>>,
+    /// Whether the bans have changed since they were last saved.
+    changed: AtomicBool,
+    metrics: Metrics,
+}
+
+/// A client's ban, as saved to the ban file and listed by the admin server.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Ban {
+    pub(crate) ip: IpAddr,
+    /// When the ban expires, in milliseconds since the UNIX epoch.
+    pub(crate) expires_unix_ms: u64,
+}
+
+impl BanGossip {
+    /// Returns a new BanGossip, which sends and receives bans on `socket`.
+    pub(crate) fn new(
+        log: Logger,
+        config: BanGossipConfig,
+        socket: UdpSocket,
+        metrics: Metrics,
+    ) -> Self {
+        let (outgoing, outgoing_rx) = mpsc::channel(QUEUE_SIZE);
+        let loopback = [
+            Cidr::new(Ipv4Addr::LOCALHOST.into(), 8),
+            Cidr::new(Ipv6Addr::LOCALHOST.into(), 128),
+        ];
+        Self {
+            log,
+            socket,
+            peers: config.peers,
+            secret: config.secret,
+            duration: config.duration,
+            drop_reasons: config.drop_reasons.into_iter().collect(),
+            min_drops: config.min_drops,
+            drops: Mutex::default(),
+            ban_rejected: config.ban_rejected,
+            max_bans: config.max_bans,
+            file: config.file,
+            sweep_interval: config.sweep_interval,
+            never_ban: loopback
+                .iter()
+                .flatten()
+                .copied()
+                .chain(config.never_ban)
+                .collect(),
+            bans: RwLock::default(),
+            outgoing,
+            outgoing_rx: Mutex::new(Some(outgoing_rx)),
+            changed: AtomicBool::new(false),
+            metrics,
+        }
+    }
+
+    /// Loads the bans saved to the ban file, if any, skipping those that
+    /// have expired since. Returns the number of clients banned.
+    pub(super) fn load(&self) -> io::Result<usize> {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let bans = serde_json::from_slice::<Vec<Ban>>(&contents)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let now = unix_time_millis();
+        let mut loaded = 0;
+        for ban in bans {
+            let remaining = ban.expires_unix_ms.saturating_sub(now);
+            if remaining > 0 && self.insert(ban.ip, Duration::from_millis(remaining), false) {
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Returns whether the client at `ip` is banned. Expired bans are left
+    /// for the next sweep to remove.
+    pub(super) fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans
+            .read()
+            .get(&ip)
+            .map_or(false, |expires_at| *expires_at > Instant::now())
+    }
+
+    /// Returns whether the client at `ip` is never banned.
+    pub(crate) fn is_never_banned(&self, ip: IpAddr) -> bool {
+        Cidr::any_contains(&self.never_ban, ip)
+    }
+
+    /// Counts a packet of the client at `ip` that a filter dropped for
+    /// `reason`, banning the client once enough of its packets have been
+    /// dropped for reasons it's banned for. Clients are only banned if
+    /// `established`, i.e they've completed the handshake or have a session.
+    pub(super) fn dropped(&self, ip: IpAddr, reason: &str, established: bool) {
+        if !established || !self.drop_reasons.contains(reason) {
+            return;
+        }
+        {
+            let mut drops = self.drops.lock();
+            // Clients are only counted while fewer than `max_bans` are, so
+            // that spoofed addresses can't grow the counts without bound.
+            if drops.len() >= self.max_bans && !drops.contains_key(&ip) {
+                return;
+            }
+            let count = drops.entry(ip).or_insert(0);
+            *count += 1;
+            if *count < self.min_drops {
+                return;
+            }
+            drops.remove(&ip);
+        }
+        self.ban(ip);
+    }
+
+    /// Bans the client at `ip` once the connection tracker rejected it, if
+    /// clients are banned for it.
+    pub(super) fn rejected(&self, ip: IpAddr) {
+        if self.ban_rejected {
+            self.ban(ip);
+        }
+    }
+
+    /// Returns the bans of the clients that are currently banned, soonest
+    /// to expire first.
+    pub(crate) fn list(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let unix_now = unix_time_millis();
+        let mut bans = self
+            .bans
+            .read()
+            .iter()
+            .filter(|(_, expires_at)| **expires_at > now)
+            .map(|(ip, expires_at)| Ban {
+                ip: *ip,
+                expires_unix_ms: unix_now.saturating_add(millis(*expires_at - now)),
+            })
+            .collect::<Vec<_>>();
+        bans.sort_by_key(|ban| ban.expires_unix_ms);
+        bans
+    }
+
+    /// Bans the client at `ip` for `duration`, or for the configured
+    /// duration if unset, on behalf of an operator, replacing any ban it
+    /// already has, and sends the ban to peers. Returns whether the client
+    /// was banned, which it isn't while `max_bans` clients are banned, or if
+    /// it's never banned.
+    pub(crate) fn add(&self, ip: IpAddr, duration: Option<Duration>) -> bool {
+        let duration = duration.unwrap_or(self.duration);
+        if !self.insert(ip, duration, true) {
+            return false;
+        }
+        self.metrics.bans_admin.inc();
+        debug!(self.log, "Banned client on behalf of an operator";
+            "ip" => %ip, "duration" => ?duration);
+        self.send(ip, duration);
+        true
+    }
+
+    /// Lifts the ban of the client at `ip`, returning whether it was banned.
+    /// Peers aren't told, so they keep banning the client until their own
+    /// bans of it expire.
+    pub(crate) fn remove(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.write();
+        let removed = bans
+            .remove(&ip)
+            .map_or(false, |expires_at| expires_at > Instant::now());
+        self.metrics.active_bans.set(bans.len() as i64);
+        self.changed.store(true, Ordering::Relaxed);
+        removed
+    }
+
+    /// Sends bans to and receives bans from peers, and removes expired bans
+    /// in the background, until shutdown, when the bans are saved one last
+    /// time.
+    pub(super) fn run(self: Arc<Self>, mut shutdown_rx: watch::Receiver<()>) {
+        let mut outgoing = match self.outgoing_rx.lock().take() {
+            Some(outgoing) => outgoing,
+            None => {
+                warn!(self.log, "Ban gossip is already running");
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            let mut buf = [0; MAX_BAN_SIZE];
+            let mut sweep = time::interval(self.sweep_interval);
+            loop {
+                tokio::select! {
+                    result = self.socket.recv_from(&mut buf) => match result {
+                        Ok((size, from)) => self.received(from, &buf[..size]),
+                        Err(err) => {
+                            warn!(self.log, "Failed to receive ban"; "error" => %err);
+                        }
+                    },
+                    Some(ban) = outgoing.recv() => self.send_to_peers(&ban).await,
+                    _ = sweep.tick() => self.sweep().await,
+                    _ = shutdown_rx.changed() => {
+                        self.save().await;
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Bans the client at `ip` and sends the ban to peers, unless it's
+    /// already banned.
+    fn ban(&self, ip: IpAddr) {
+        if !self.insert(ip, self.duration, false) {
+            return;
+        }
+        self.metrics.bans_local.inc();
+        debug!(self.log, "Banned client"; "ip" => %ip, "duration" => ?self.duration);
+        self.send(ip, self.duration);
+    }
+
+    /// Queues the ban of the client at `ip` for `duration` to be sent to
+    /// peers. The ban is only applied locally if the queue is full.
+    fn send(&self, ip: IpAddr, duration: Duration) {
+        if self.peers.is_empty() {
+            return;
+        }
+        let ban = self.encode(ip, unix_time_millis(), duration);
+        if self.outgoing.try_send(ban).is_err() {
+            debug!(self.log, "Too many bans waiting to be sent to peers, not sending ban";
+                "ip" => %ip);
+        }
+    }
+
+    /// Sends `ban` to peers.
+    async fn send_to_peers(&self, ban: &[u8]) {
+        for peer in &self.peers {
+            if let Err(err) = self.socket.send_to(ban, peer).await {
+                warn!(self.log, "Failed to send ban to peer"; "peer" => %peer, "error" => %err);
+            }
+        }
+    }
+
+    fn received(&self, from: SocketAddr, ban: &[u8]) {
+        match self.decode(ban, unix_time_millis()) {
+            Some((ip, remaining)) => {
+                if self.insert(ip, remaining, false) {
+                    self.metrics.bans_peer.inc();
+                    debug!(self.log, "Banned client on behalf of peer";
+                        "ip" => %ip, "peer" => %from, "duration" => ?remaining);
+                }
+            }
+            None => {
+                self.metrics.ban_gossip_invalid_total.inc();
+                debug!(self.log, "Received an invalid or expired ban"; "peer" => %from);
+            }
+        }
+    }
+
+    /// Bans the client at `ip` for `duration`, returning whether it wasn't
+    /// already banned, or whether it was banned at all if its existing ban
+    /// is replaced. Clients that are never banned aren't.
+    fn insert(&self, ip: IpAddr, duration: Duration, replace: bool) -> bool {
+        if self.is_never_banned(ip) {
+            return false;
+        }
+        let now = Instant::now();
+        let mut bans = self.bans.write();
+        match bans.get(&ip) {
+            Some(expires_at) if *expires_at > now && !replace => return false,
+            Some(_) => {}
+            None if bans.len() >= self.max_bans => {
+                bans.retain(|_, expires_at| *expires_at > now);
+                if bans.len() >= self.max_bans {
+                    return false;
+                }
+            }
+            None => {}
+        }
+        bans.insert(ip, now + duration);
+        self.metrics.active_bans.set(bans.len() as i64);
+        self.changed.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// Removes expired bans and resets the counts of dropped packets, then
+    /// saves the bans if they've changed.
+    async fn sweep(&self) {
+        self.drops.lock().clear();
+        let now = Instant::now();
+        {
+            let mut bans = self.bans.write();
+            let len = bans.len();
+            bans.retain(|_, expires_at| *expires_at > now);
+            if bans.len() < len {
+                self.metrics.active_bans.set(bans.len() as i64);
+                self.changed.store(true, Ordering::Relaxed);
+            }
+        }
+        self.save().await;
+    }
+
+    /// Saves the bans to the ban file, if configured and they've changed
+    /// since they were last saved.
+    async fn save(&self) {
+        let path = match &self.file {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let result = match serde_json::to_vec(&self.list()) {
+            Ok(contents) => tokio::task::spawn_blocking(move || write_file(&path, &contents))
+                .await
+                .unwrap_or_else(|err| Err(io::Error::new(io::ErrorKind::Other, err))),
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            warn!(self.log, "Failed to save bans"; "error" => %err);
+            // Try again at the next sweep.
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the ban, sent to peers, of the client at `ip` for `duration`
+    /// from `banned_at` milliseconds since the UNIX epoch.
+    fn encode(&self, ip: IpAddr, banned_at: u64, duration: Duration) -> Vec<u8> {
+        let duration = millis(duration);
+        let mut ban = PREFIX.to_vec();
+        ban.extend_from_slice(&banned_at.to_be_bytes());
+        ban.extend_from_slice(&duration.to_be_bytes());
+        match ip {
+            IpAddr::V4(ip) => ban.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => ban.extend_from_slice(&ip.octets()),
+        }
+        let signature = self.mac(&ban).finalize().into_bytes();
+        ban.extend_from_slice(&signature);
+        ban
+    }
+
+    /// Returns the client banned by `ban` and how much longer it's banned
+    /// for at `now` milliseconds since the UNIX epoch, if the ban is valid.
+    fn decode(&self, ban: &[u8], now: u64) -> Option<(IpAddr, Duration)> {
+        if !ban.starts_with(PREFIX) || ban.len() < PREFIX.len() + SIGNATURE_SIZE {
+            return None;
+        }
+        let (signed, signature) = ban.split_at(ban.len() - SIGNATURE_SIZE);
+        self.mac(signed).verify(signature).ok()?;
+
+        let fields = &signed[PREFIX.len()..];
+        if fields.len() < TIMESTAMP_SIZE + DURATION_SIZE {
+            return None;
+        }
+        let (banned_at, fields) = fields.split_at(TIMESTAMP_SIZE);
+        let (duration, ip) = fields.split_at(DURATION_SIZE);
+        let banned_at = u64::from_be_bytes(banned_at.try_into().ok()?);
+        let duration = u64::from_be_bytes(duration.try_into().ok()?);
+        let ip = match ip.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+            16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+            _ => return None,
+        };
+
+        let remaining = banned_at
+            .saturating_add(duration)
+            .checked_sub(now)
+            .filter(|remaining| *remaining > 0)?;
+        Some((ip, Duration::from_millis(remaining).min(self.duration)))
+    }
+
+    fn mac(&self, ban: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC can take a key of any size");
+        mac.update(ban);
+        mac
+    }
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch.
+fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Returns `duration` in whole milliseconds.
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Writes `contents` to the file at `path` through a temporary file, so that
+/// the file is never left partially written.
+fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use prometheus::Registry;
+    use tokio::net::UdpSocket;
+    use tokio::sync::watch;
+    use tokio::time::{self, Duration};
+
+    use super::BanGossip;
+    use crate::config::BanGossip as BanGossipConfig;
+    use crate::proxy::server::metrics::Metrics;
+    use crate::test_utils::logger;
+
+    async fn ban_gossip(secret: &[u8], peers: Vec<SocketAddr>) -> BanGossip {
+        BanGossip::new(
+            logger(),
+            BanGossipConfig {
+                port: 0,
+                peers,
+                secret: secret.to_vec(),
+                duration: Duration::from_secs(60),
+                drop_reasons: vec!["RateLimited".into()],
+                min_drops: 2,
+                ban_rejected: false,
+                max_bans: 1,
+                file: None,
+                sweep_interval: Duration::from_secs(1),
+                never_ban: vec!["10.0.0.0/8".parse().unwrap()],
+            },
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    async fn persisted_ban_gossip(file: &Path) -> BanGossip {
+        BanGossip::new(
+            logger(),
+            BanGossipConfig {
+                port: 0,
+                peers: vec![],
+                secret: b"secret".to_vec(),
+                duration: Duration::from_secs(60),
+                drop_reasons: vec![],
+                min_drops: 1,
+                ban_rejected: false,
+                max_bans: 10,
+                file: Some(file.to_path_buf()),
+                sweep_interval: Duration::from_secs(1),
+                never_ban: vec![],
+            },
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn decode() {
+        let ban_gossip = ban_gossip(b"secret", vec![]).await;
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let ban = ban_gossip.encode(ip, 1_000, Duration::from_secs(10));
+
+        assert_eq!(
+            Some((ip, Duration::from_secs(6))),
+            ban_gossip.decode(&ban, 5_000)
+        );
+        // Bans last at most as long as the proxy's own bans.
+        let long_ban = ban_gossip.encode(ip, 1_000, Duration::from_secs(600));
+        assert_eq!(
+            Some((ip, Duration::from_secs(60))),
+            ban_gossip.decode(&long_ban, 1_000)
+        );
+
+        // Expired bans are invalid.
+        assert_eq!(None, ban_gossip.decode(&ban, 11_000));
+        // As are bans signed with another secret.
+        let other = self::ban_gossip(b"other", vec![]).await;
+        assert_eq!(None, other.decode(&ban, 5_000));
+        // Or tampered with.
+        let mut tampered = ban.clone();
+        tampered[super::PREFIX.len()] ^= 1;
+        assert_eq!(None, ban_gossip.decode(&tampered, 5_000));
+        assert_eq!(None, ban_gossip.decode(b"QUILKIN_BAN", 5_000));
+    }
+
+    #[tokio::test]
+    async fn ban() {
+        let peer = Arc::new(ban_gossip(b"secret", vec![]).await);
+        let peer_addr = peer.socket.local_addr().unwrap();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        peer.clone().run(shutdown_rx.clone());
+        let ban_gossip = Arc::new(ban_gossip(b"secret", vec![peer_addr]).await);
+        ban_gossip.clone().run(shutdown_rx);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        // Only drops for one of the configured reasons ban the client.
+        ban_gossip.dropped(ip, "Unspecified", true);
+        ban_gossip.dropped(ip, "Unspecified", true);
+        ban_gossip.rejected(ip);
+        assert!(!ban_gossip.is_banned(ip));
+        // And only those of clients that are established.
+        ban_gossip.dropped(ip, "RateLimited", false);
+        ban_gossip.dropped(ip, "RateLimited", false);
+        assert!(!ban_gossip.is_banned(ip));
+
+        // The client is banned once enough of its packets are dropped.
+        ban_gossip.dropped(ip, "RateLimited", true);
+        assert!(!ban_gossip.is_banned(ip));
+        ban_gossip.dropped(ip, "RateLimited", true);
+        assert!(ban_gossip.is_banned(ip));
+        assert_eq!(1, ban_gossip.metrics.bans_local.get());
+        // Clients that are already banned aren't banned again.
+        ban_gossip.dropped(ip, "RateLimited", true);
+        ban_gossip.dropped(ip, "RateLimited", true);
+        assert_eq!(1, ban_gossip.metrics.bans_local.get());
+
+        // The peer bans the client too.
+        time::timeout(Duration::from_secs(5), async {
+            while !peer.is_banned(ip) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(1, peer.metrics.bans_peer.get());
+
+        // No more clients are banned than the limit.
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        ban_gossip.dropped(other, "RateLimited", true);
+        ban_gossip.dropped(other, "RateLimited", true);
+        assert!(!ban_gossip.is_banned(other));
+    }
+
+    #[tokio::test]
+    async fn never_ban() {
+        let ban_gossip = ban_gossip(b"secret", vec![]).await;
+        for ip in &["127.0.0.1", "::1", "::ffff:127.0.0.1", "10.1.2.3"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            ban_gossip.dropped(ip, "RateLimited", true);
+            ban_gossip.dropped(ip, "RateLimited", true);
+            assert!(!ban_gossip.add(ip, None), "{}", ip);
+            assert!(!ban_gossip.is_banned(ip), "{}", ip);
+        }
+
+        // Nor can peers ban them.
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let ban = ban_gossip.encode(ip, super::unix_time_millis(), Duration::from_secs(10));
+        ban_gossip.received("10.0.0.2:7100".parse().unwrap(), &ban);
+        assert_eq!(0, ban_gossip.metrics.bans_peer.get());
+        assert_eq!(0, ban_gossip.metrics.active_bans.get());
+    }
+
+    #[tokio::test]
+    async fn persist() {
+        let path = std::env::temp_dir().join(format!("quilkin-bans-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let ban_gossip = persisted_ban_gossip(&path).await;
+        // There are no bans to load before the file is first saved.
+        assert_eq!(0, ban_gossip.load().unwrap());
+
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let expiring: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(ban_gossip.add(ip, None));
+        let expires_soon = Some(Duration::from_millis(50));
+        assert!(ban_gossip.add(expiring, expires_soon));
+        assert_eq!(2, ban_gossip.metrics.bans_admin.get());
+        time::sleep(Duration::from_millis(100)).await;
+
+        // Expired bans are removed, and the rest are saved.
+        ban_gossip.sweep().await;
+        assert_eq!(1, ban_gossip.metrics.active_bans.get());
+        let bans = ban_gossip.list();
+        assert_eq!(1, bans.len());
+        assert_eq!(ip, bans[0].ip);
+
+        let restarted = persisted_ban_gossip(&path).await;
+        assert_eq!(1, restarted.load().unwrap());
+        assert!(restarted.is_banned(ip));
+        assert!(!restarted.is_banned(expiring));
+
+        assert!(restarted.remove(ip));
+        assert!(!restarted.is_banned(ip));
+        assert!(!restarted.remove(ip));
+
+        std::fs::write(&path, "not bans").unwrap();
+        assert!(restarted.load().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Flags clients that send bursts of packets, giving early warning of speed
+//! hacks and floods before the game servers notice them.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::{header::CONTENT_TYPE, Body, Client, Method, Request};
+use parking_lot::Mutex;
+use serde::Serialize;
+use slog::{warn, Logger};
+use tokio::sync::{mpsc, watch};
+
+use crate::config::BurstDetection as BurstDetectionConfig;
+use crate::proxy::server::metrics::Metrics;
+
+/// The number of events that can wait to be sent to the webhook. Further
+/// events are dropped until there's room again.
+const WEBHOOK_QUEUE_SIZE: usize = 1024;
+/// How long sending an event to the webhook can take before it is given up
+/// on.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counts the packets received from each client over consecutive windows,
+/// starting from the client's first packet, and flags clients that send
+/// more than `max_packets` within a window.
+pub(super) struct BurstDetector {
+    log: Logger,
+    proxy_id: String,
+    config: BurstDetectionConfig,
+    clients: Mutex<HashMap<SocketAddr, ClientWindow>>,
+    /// Queues events to be sent to the webhook, if configured.
+    webhook: Option<mpsc::Sender<Event>>,
+    metrics: Metrics,
+}
+
+/// The packets received from a client in its current window.
+struct ClientWindow {
+    started: Instant,
+    packets: u64,
+    /// When an event about the client was last emitted.
+    last_event: Option<Instant>,
+}
+
+/// An event about a client that sent a burst of packets.
+#[derive(Debug, Serialize)]
+struct Event {
+    /// The id of the proxy that flagged the client.
+    proxy: String,
+    client: SocketAddr,
+    /// The number of packets a client can send within the window without
+    /// being flagged.
+    max_packets: u64,
+    window_ms: u64,
+    /// When the client was flagged, in milliseconds since the UNIX epoch.
+    unix_ms: u64,
+}
+
+impl BurstDetector {
+    /// Returns a new BurstDetector, sending events to the configured webhook
+    /// in the background until shutdown.
+    pub(super) fn new(
+        log: Logger,
+        proxy_id: String,
+        config: BurstDetectionConfig,
+        metrics: Metrics,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> Self {
+        let webhook = config.webhook.clone().map(|url| {
+            let (events_tx, events_rx) = mpsc::channel(WEBHOOK_QUEUE_SIZE);
+            tokio::spawn(send_events(
+                log.clone(),
+                url,
+                events_rx,
+                metrics.clone(),
+                shutdown_rx,
+            ));
+            events_tx
+        });
+        Self {
+            log,
+            proxy_id,
+            config,
+            clients: Mutex::default(),
+            webhook,
+            metrics,
+        }
+    }
+
+    /// Counts a packet received from `client`, flagging the client once it
+    /// has sent more than `max_packets` within its current window.
+    pub(super) fn record(&self, client: SocketAddr) {
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        if !clients.contains_key(&client) && clients.len() >= self.config.max_clients {
+            self.prune(&mut clients, now);
+            if clients.len() >= self.config.max_clients {
+                return;
+            }
+        }
+
+        let window = clients.entry(client).or_insert(ClientWindow {
+            started: now,
+            packets: 0,
+            last_event: None,
+        });
+        if now.duration_since(window.started) >= self.config.window {
+            window.started = now;
+            window.packets = 0;
+        }
+        window.packets += 1;
+        // Each burst is only flagged once, no matter how many more packets
+        // the client sends within the window.
+        if window.packets != self.config.max_packets + 1 {
+            return;
+        }
+        self.metrics.bursts_total.inc();
+
+        let cooling_down = window.last_event.map_or(false, |last| {
+            now.duration_since(last) < self.config.cooldown
+        });
+        if cooling_down {
+            return;
+        }
+        window.last_event = Some(now);
+        drop(clients);
+        self.emit(client);
+    }
+
+    /// Removes the clients whose window has ended and that no event was
+    /// emitted about within the cooldown.
+    fn prune(&self, clients: &mut HashMap<SocketAddr, ClientWindow>, now: Instant) {
+        clients.retain(|_, window| {
+            now.duration_since(window.started) < self.config.window
+                || window.last_event.map_or(false, |last| {
+                    now.duration_since(last) < self.config.cooldown
+                })
+        });
+    }
+
+    /// Logs an event about `client` and sends it to the webhook, if
+    /// configured.
+    fn emit(&self, client: SocketAddr) {
+        if self.config.log {
+            warn!(self.log, "Client sent a burst of packets";
+                "client" => %client, "max_packets" => self.config.max_packets,
+                "window" => ?self.config.window);
+        }
+        if let Some(webhook) = &self.webhook {
+            let event = Event {
+                proxy: self.proxy_id.clone(),
+                client,
+                max_packets: self.config.max_packets,
+                window_ms: self.config.window.as_millis() as u64,
+                unix_ms: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_millis() as u64)
+                    .unwrap_or_default(),
+            };
+            if webhook.try_send(event).is_err() {
+                self.metrics.burst_webhook_errors_total.inc();
+            }
+        }
+    }
+}
+
+/// Sends each event received on `events` to the webhook at `url` as JSON,
+/// until shutdown.
+async fn send_events(
+    log: Logger,
+    url: String,
+    mut events: mpsc::Receiver<Event>,
+    metrics: Metrics,
+    mut shutdown_rx: watch::Receiver<()>,
+) {
+    let client = Client::new();
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => event,
+                None => return,
+            },
+            _ = shutdown_rx.changed() => return,
+        };
+        let request = serde_json::to_vec(&event)
+            .map_err(|err| err.to_string())
+            .and_then(|body| {
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(&url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .map_err(|err| err.to_string())
+            });
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                metrics.burst_webhook_errors_total.inc();
+                warn!(log, "Failed to create burst webhook request"; "error" => err);
+                continue;
+            }
+        };
+        match tokio::time::timeout(WEBHOOK_TIMEOUT, client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => {}
+            Ok(Ok(response)) => {
+                metrics.burst_webhook_errors_total.inc();
+                warn!(log, "Burst webhook rejected event"; "status" => %response.status());
+            }
+            Ok(Err(err)) => {
+                metrics.burst_webhook_errors_total.inc();
+                warn!(log, "Failed to send event to burst webhook"; "error" => %err);
+            }
+            Err(_) => {
+                metrics.burst_webhook_errors_total.inc();
+                warn!(log, "Timed out sending event to burst webhook");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use prometheus::Registry;
+    use tokio::sync::{mpsc, watch};
+
+    use super::BurstDetector;
+    use crate::config::BurstDetection as BurstDetectionConfig;
+    use crate::proxy::server::metrics::Metrics;
+    use crate::test_utils::logger;
+
+    #[tokio::test]
+    async fn record() {
+        // A webhook that forwards each event it receives.
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let make_svc = make_service_fn(move |_conn| {
+            let events_tx = events_tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let events_tx = events_tx.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let event = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+                        events_tx.send(event).unwrap();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let detector = BurstDetector::new(
+            logger(),
+            "test-proxy".into(),
+            BurstDetectionConfig {
+                window: Duration::from_secs(60),
+                max_packets: 2,
+                cooldown: Duration::from_secs(60),
+                log: true,
+                webhook: Some(format!("http://{}/bursts", addr)),
+                max_clients: 2,
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+            shutdown_rx,
+        );
+
+        let client = "127.0.0.1:7000".parse().unwrap();
+        detector.record(client);
+        detector.record(client);
+        assert_eq!(0, detector.metrics.bursts_total.get());
+        detector.record(client);
+        assert_eq!(1, detector.metrics.bursts_total.get());
+        // The burst is only flagged once.
+        detector.record(client);
+        assert_eq!(1, detector.metrics.bursts_total.get());
+
+        let event = events.recv().await.unwrap();
+        assert_eq!("test-proxy", event["proxy"]);
+        assert_eq!("127.0.0.1:7000", event["client"]);
+        assert_eq!(2, event["max_packets"]);
+        assert_eq!(60_000, event["window_ms"]);
+
+        // Packets from clients beyond the limit aren't counted.
+        let other = "127.0.0.1:7001".parse().unwrap();
+        detector.record(other);
+        let untracked = "127.0.0.1:7002".parse().unwrap();
+        for _ in 0..3 {
+            detector.record(untracked);
+        }
+        assert_eq!(1, detector.metrics.bursts_total.get());
+        assert_eq!(0, detector.metrics.burst_webhook_errors_total.get());
+    }
+
+    #[tokio::test]
+    async fn cooldown() {
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let detector = BurstDetector::new(
+            logger(),
+            "test-proxy".into(),
+            BurstDetectionConfig {
+                window: Duration::from_millis(10),
+                max_packets: 1,
+                cooldown: Duration::from_secs(60),
+                log: false,
+                webhook: None,
+                max_clients: 1,
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+            shutdown_rx,
+        );
+
+        let client = "127.0.0.1:7000".parse().unwrap();
+        detector.record(client);
+        detector.record(client);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // Bursts in later windows are counted, but no event is emitted about
+        // them until the cooldown has passed.
+        detector.record(client);
+        detector.record(client);
+        assert_eq!(2, detector.metrics.bursts_total.get());
+        let clients = detector.clients.lock();
+        let window = clients.get(&client).unwrap();
+        assert!(window.last_event.unwrap() < window.started);
+
+        // Clients flagged within the cooldown aren't pruned to make room
+        // for other clients.
+        drop(clients);
+        detector.record("127.0.0.1:7001".parse().unwrap());
+        assert!(detector.clients.lock().contains_key(&client));
+    }
+}
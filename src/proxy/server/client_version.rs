@@ -0,0 +1,131 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reads the version of a client's build from the first packet of its
+//! sessions, so that network issues can be correlated with specific builds.
+
+use std::collections::HashSet;
+
+use parking_lot::Mutex;
+
+use crate::config::ClientVersion as ClientVersionConfig;
+use crate::proxy::server::metrics::Metrics;
+
+/// The label of the sessions whose client's version couldn't be read.
+const UNKNOWN: &str = "unknown";
+/// The label of the sessions of versions beyond `max_versions`.
+const OTHER: &str = "other";
+
+/// Reads client versions and counts the sessions of each, keeping the
+/// number of distinct labels within `max_versions`.
+pub(super) struct ClientVersions {
+    config: ClientVersionConfig,
+    /// The versions that are counted under their own label, which are the
+    /// first `max_versions` seen.
+    labelled: Mutex<HashSet<String>>,
+    metrics: Metrics,
+}
+
+impl ClientVersions {
+    pub(super) fn new(config: ClientVersionConfig, metrics: Metrics) -> Self {
+        Self {
+            config,
+            labelled: Mutex::default(),
+            metrics,
+        }
+    }
+
+    /// Returns the version of the client that sent `packet`, if it has one.
+    pub(super) fn parse(&self, packet: &[u8]) -> Option<String> {
+        self.config.parse(packet)
+    }
+
+    /// Counts a session created for a client of `version`, which is counted
+    /// as unknown if `None`.
+    pub(super) fn record_session(&self, version: Option<&str>) {
+        let label = match version {
+            Some(version) => self.label(version),
+            None => UNKNOWN,
+        };
+        self.metrics
+            .client_version_sessions_total
+            .with_label_values(&[label])
+            .inc();
+    }
+
+    /// Returns the label that sessions of `version` are counted under.
+    fn label<'a>(&self, version: &'a str) -> &'a str {
+        let mut labelled = self.labelled.lock();
+        if labelled.contains(version) {
+            return version;
+        }
+        // The labels of the fallbacks can't be taken by a version.
+        if labelled.len() >= self.config.max_versions || version == UNKNOWN || version == OTHER {
+            return OTHER;
+        }
+        labelled.insert(version.to_string());
+        version
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::Registry;
+
+    use super::ClientVersions;
+    use crate::config::{ClientVersion as ClientVersionConfig, ClientVersionEncoding};
+    use crate::proxy::server::metrics::Metrics;
+
+    #[test]
+    fn record_session() {
+        let client_versions = ClientVersions::new(
+            ClientVersionConfig {
+                offset: 0,
+                length: 8,
+                encoding: ClientVersionEncoding::Text,
+                max_versions: 2,
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        );
+        let sessions = |label: &str| {
+            client_versions
+                .metrics
+                .client_version_sessions_total
+                .with_label_values(&[label])
+                .get()
+        };
+
+        assert_eq!(
+            Some("1.4.2".to_string()),
+            client_versions.parse(b"1.4.2\0\0\0")
+        );
+        client_versions.record_session(Some("1.4.2"));
+        client_versions.record_session(Some("1.4.2"));
+        client_versions.record_session(Some("1.5.0"));
+        client_versions.record_session(None);
+        assert_eq!(2, sessions("1.4.2"));
+        assert_eq!(1, sessions("1.5.0"));
+        assert_eq!(1, sessions("unknown"));
+
+        // Versions beyond the limit share a label.
+        client_versions.record_session(Some("1.6.0"));
+        client_versions.record_session(Some("1.6.1"));
+        client_versions.record_session(Some("1.4.2"));
+        assert_eq!(0, sessions("1.6.0"));
+        assert_eq!(2, sessions("other"));
+        assert_eq!(3, sessions("1.4.2"));
+    }
+}
@@ -0,0 +1,189 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::{IpAddr, SocketAddr};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::config::ConnectionId as ConnectionIdConfig;
+
+/// The size in bytes of the address a connection id is issued for.
+const ADDRESS_SIZE: usize = 18;
+/// The size in bytes of the signature of a connection id.
+const SIGNATURE_SIZE: usize = 32;
+/// The size in bytes of a connection id.
+const ID_SIZE: usize = ADDRESS_SIZE + SIGNATURE_SIZE;
+/// The size in bytes of the secret generated if none is configured.
+const GENERATED_SECRET_SIZE: usize = 32;
+
+/// The outcome of checking a received packet for a connection id.
+#[derive(Debug, PartialEq)]
+pub(super) enum ConnectionId {
+    /// The packet does not contain a connection id. Holds the packet.
+    Missing(Vec<u8>),
+    /// The packet starts with a valid connection id. Holds the id and the
+    /// rest of the packet.
+    Valid(Vec<u8>, Vec<u8>),
+    /// The packet starts with an invalid connection id.
+    Invalid,
+}
+
+/// Issues and verifies the connection ids that clients are keyed by, so
+/// that a client keeps its session when its address changes, e.g. when a NAT
+/// rebinds it.
+///
+/// A client's connection id is the address it was first seen at followed by
+/// an HMAC-SHA256 signature of that address. Since the id of a
+/// client without one is derived from its address, the session created for
+/// its first packet is already keyed by the id it is issued, and no state is
+/// needed to find that session once the client starts sending the id. The
+/// signature stops clients from forging ids to take over other sessions.
+pub(super) struct ConnectionIds {
+    secret: Vec<u8>,
+    prefix: Vec<u8>,
+}
+
+impl ConnectionIds {
+    pub(super) fn new(config: ConnectionIdConfig) -> Self {
+        let secret = if config.secret.is_empty() {
+            (0..GENERATED_SECRET_SIZE)
+                .map(|_| rand::random::<u8>())
+                .collect()
+        } else {
+            config.secret
+        };
+        Self {
+            secret,
+            prefix: config.prefix,
+        }
+    }
+
+    /// Returns the connection id issued to the client first seen at `from`.
+    pub(super) fn id(&self, from: SocketAddr) -> Vec<u8> {
+        let ip = match from.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        let mut id = Vec::with_capacity(ID_SIZE);
+        id.extend_from_slice(&ip.octets());
+        id.extend_from_slice(&from.port().to_be_bytes());
+        let signature = self.mac(&id).finalize().into_bytes();
+        id.extend_from_slice(&signature);
+        id
+    }
+
+    /// Returns the header that packets carrying the connection id `id`
+    /// start with.
+    pub(super) fn header(&self, id: &[u8]) -> Vec<u8> {
+        let mut header = self.prefix.clone();
+        header.extend_from_slice(id);
+        header
+    }
+
+    /// Checks whether `packet` starts with a valid connection id.
+    pub(super) fn parse(&self, mut packet: Vec<u8>) -> ConnectionId {
+        if !packet.starts_with(&self.prefix) {
+            return ConnectionId::Missing(packet);
+        }
+        let header_len = self.prefix.len() + ID_SIZE;
+        if packet.len() < header_len {
+            return ConnectionId::Invalid;
+        }
+
+        let id = packet[self.prefix.len()..header_len].to_vec();
+        let (address, signature) = id.split_at(ADDRESS_SIZE);
+        if self.mac(address).verify(signature).is_err() {
+            return ConnectionId::Invalid;
+        }
+
+        packet.drain(..header_len);
+        ConnectionId::Valid(id, packet)
+    }
+
+    fn mac(&self, address: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC can take a key of any size");
+        mac.update(address);
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{ConnectionId, ConnectionIds, ID_SIZE};
+    use crate::config::ConnectionId as ConnectionIdConfig;
+
+    fn connection_ids(secret: &[u8]) -> ConnectionIds {
+        ConnectionIds::new(ConnectionIdConfig {
+            secret: secret.to_vec(),
+            secret_ref: None,
+            prefix: b"CID".to_vec(),
+        })
+    }
+
+    #[test]
+    fn id() {
+        let connection_ids = connection_ids(b"secret");
+        let client: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let v6: SocketAddr = "[::1]:8080".parse().unwrap();
+
+        assert_eq!(ID_SIZE, connection_ids.id(client).len());
+        assert_eq!(ID_SIZE, connection_ids.id(v6).len());
+        assert_eq!(connection_ids.id(client), connection_ids.id(client));
+        assert_ne!(connection_ids.id(client), connection_ids.id(other));
+        assert_ne!(
+            connection_ids.id(client),
+            self::connection_ids(b"other").id(client)
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let connection_ids = connection_ids(b"secret");
+        let client: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let id = connection_ids.id(client);
+
+        assert_eq!(
+            ConnectionId::Missing(b"hello".to_vec()),
+            connection_ids.parse(b"hello".to_vec())
+        );
+
+        let mut packet = connection_ids.header(&id);
+        packet.extend_from_slice(b"hello");
+        assert_eq!(
+            ConnectionId::Valid(id.clone(), b"hello".to_vec()),
+            connection_ids.parse(packet.clone())
+        );
+
+        // The id is only valid for proxies with the same secret.
+        assert_eq!(
+            ConnectionId::Invalid,
+            self::connection_ids(b"other").parse(packet.clone())
+        );
+
+        // Forged ids are invalid.
+        let mut forged = packet.clone();
+        forged[3] ^= 1;
+        assert_eq!(ConnectionId::Invalid, connection_ids.parse(forged));
+
+        // Truncated ids are invalid.
+        assert_eq!(ConnectionId::Invalid, connection_ids.parse(packet[..10].to_vec()));
+    }
+}
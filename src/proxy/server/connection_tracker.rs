@@ -0,0 +1,444 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use slog::{debug, warn, Logger};
+use tonic::transport::{Channel, Endpoint as TonicEndpoint, Error as TonicError};
+
+use crate::config::{ConnectionTracker as ConnectionTrackerConfig, FailurePolicy};
+use crate::proxy::server::metrics::Metrics;
+
+crate::include_proto!("quilkin.proxy.connection_tracker.v1alpha1");
+use self::quilkin::proxy::connection_tracker::v1alpha1::{
+    connection_tracker_client::ConnectionTrackerClient, AdmitRequest, AdmitResponse,
+};
+
+/// How long the outcome of a failed request is reused, so that an
+/// unavailable connection tracker isn't asked on every packet.
+const FAILURE_CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// The maximum number of decisions cached. Once reached, expired decisions
+/// are removed before a new one is cached.
+const MAX_CACHED_DECISIONS: usize = 100_000;
+
+/// The connection tracker's decision on whether to create a session.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Admission {
+    /// Create the session, annotated with the provided values.
+    Allow(BTreeMap<String, String>),
+    /// Don't create the session.
+    Reject,
+}
+
+struct CachedAdmission {
+    admission: Admission,
+    expires_at: Instant,
+}
+
+/// A cached decision to admit a client, as exported by
+/// [`ConnectionTracker::export`].
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ExportedAdmission {
+    pub client: SocketAddr,
+    /// The token sent by the client, base64 encoded, if any.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The values the session for the client is annotated with.
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    /// How long until the decision expires.
+    #[serde(with = "humantime_serde")]
+    pub expires_in: Duration,
+}
+
+/// Consults an external connection tracker before a session is created for
+/// a client, caching its decisions.
+pub(super) struct ConnectionTracker {
+    log: Logger,
+    proxy_id: String,
+    config: ConnectionTrackerConfig,
+    client: ConnectionTrackerClient<Channel>,
+    metrics: Metrics,
+    cache: Mutex<HashMap<(SocketAddr, Option<Vec<u8>>), CachedAdmission>>,
+}
+
+impl ConnectionTracker {
+    /// Returns a new ConnectionTracker. The connection to the connection
+    /// tracker is only established once the first decision is needed.
+    pub(super) fn new(
+        log: Logger,
+        proxy_id: String,
+        config: ConnectionTrackerConfig,
+        metrics: Metrics,
+    ) -> Result<Self, TonicError> {
+        let endpoint: TonicEndpoint = config.address.clone().try_into()?;
+        let client = ConnectionTrackerClient::new(endpoint.connect_lazy()?);
+        Ok(Self {
+            log,
+            proxy_id,
+            config,
+            client,
+            metrics,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the token in `metadata` that is sent along with the client's
+    /// address, if any.
+    pub(super) fn token(
+        &self,
+        metadata: &HashMap<Arc<String>, Box<dyn Any + Send>>,
+    ) -> Option<Vec<u8>> {
+        metadata
+            .get(&self.config.metadata_key)
+            .and_then(|value| value.downcast_ref::<Vec<u8>>())
+            .cloned()
+    }
+
+    /// Returns whether a session should be created for the client at `from`
+    /// that sent `token`.
+    pub(super) async fn admit(&self, from: SocketAddr, token: Option<&[u8]>) -> Admission {
+        let key = (from, token.map(<[u8]>::to_vec));
+        let now = Instant::now();
+        let cached = self
+            .cache
+            .lock()
+            .get(&key)
+            .filter(|cached| cached.expires_at > now)
+            .map(|cached| cached.admission.clone());
+        if let Some(admission) = cached {
+            return admission;
+        }
+
+        let (admission, ttl) = match self.request(from, token).await {
+            Ok(response) => {
+                let ttl = response
+                    .cache_ttl
+                    .and_then(|ttl| ttl.try_into().ok())
+                    .unwrap_or(self.config.cache_ttl);
+                if response.allow {
+                    self.metrics.connection_tracker_allowed.inc();
+                    (
+                        Admission::Allow(response.annotations.into_iter().collect()),
+                        ttl,
+                    )
+                } else {
+                    self.metrics.connection_tracker_rejected.inc();
+                    debug!(
+                        self.log,
+                        "Connection tracker rejected session";
+                        "from" => from,
+                        "reason" => response.reason
+                    );
+                    (Admission::Reject, ttl)
+                }
+            }
+            Err(err) => {
+                self.metrics.connection_tracker_errors.inc();
+                warn!(
+                    self.log,
+                    "Failed to get a decision from the connection tracker, applying failure policy";
+                    "from" => from,
+                    "error" => %err,
+                    "failure_policy" => ?self.config.failure_policy
+                );
+                let admission = match self.config.failure_policy {
+                    FailurePolicy::Open => Admission::Allow(BTreeMap::new()),
+                    FailurePolicy::Closed => Admission::Reject,
+                };
+                (admission, FAILURE_CACHE_TTL)
+            }
+        };
+
+        let mut cache = self.cache.lock();
+        if cache.len() >= MAX_CACHED_DECISIONS {
+            cache.retain(|_, cached| cached.expires_at > now);
+        }
+        if cache.len() < MAX_CACHED_DECISIONS {
+            cache.insert(
+                key,
+                CachedAdmission {
+                    admission: admission.clone(),
+                    expires_at: now + ttl,
+                },
+            );
+        }
+
+        admission
+    }
+
+    /// Returns the cached decisions to admit clients that haven't expired.
+    pub(super) fn export(&self) -> Vec<ExportedAdmission> {
+        let now = Instant::now();
+        self.cache
+            .lock()
+            .iter()
+            .filter(|(_, cached)| cached.expires_at > now)
+            .filter_map(|((client, token), cached)| match &cached.admission {
+                Admission::Allow(annotations) => Some(ExportedAdmission {
+                    client: *client,
+                    token: token.as_ref().map(base64::encode),
+                    annotations: annotations.clone(),
+                    expires_in: cached.expires_at - now,
+                }),
+                Admission::Reject => None,
+            })
+            .collect()
+    }
+
+    /// Caches the decisions to admit clients exported by another proxy, so
+    /// that the connection tracker isn't asked about the clients again until
+    /// the decisions expire. Returns how many decisions were cached, or an
+    /// error without caching any if a token isn't valid base64.
+    pub(super) fn import(
+        &self,
+        admissions: Vec<ExportedAdmission>,
+    ) -> Result<usize, base64::DecodeError> {
+        let now = Instant::now();
+        let admissions = admissions
+            .into_iter()
+            .map(|admission| {
+                let token = admission.token.map(base64::decode).transpose()?;
+                let cached = CachedAdmission {
+                    admission: Admission::Allow(admission.annotations),
+                    expires_at: now + admission.expires_in,
+                };
+                Ok::<_, base64::DecodeError>(((admission.client, token), cached))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut cache = self.cache.lock();
+        let mut imported = 0;
+        for (key, cached) in admissions {
+            if cache.len() >= MAX_CACHED_DECISIONS {
+                break;
+            }
+            cache.insert(key, cached);
+            imported += 1;
+        }
+        Ok(imported)
+    }
+
+    /// Asks the connection tracker whether to admit the client, waiting at
+    /// most the configured timeout.
+    async fn request(
+        &self,
+        from: SocketAddr,
+        token: Option<&[u8]>,
+    ) -> Result<AdmitResponse, String> {
+        let request = AdmitRequest {
+            source_address: from.to_string(),
+            token: token.map(<[u8]>::to_vec).unwrap_or_default(),
+            proxy_id: self.proxy_id.clone(),
+        };
+
+        // The client is cheap to clone, and a clone is needed to send requests
+        // concurrently.
+        let mut client = self.client.clone();
+        tokio::time::timeout(self.config.timeout, client.admit(request))
+            .await
+            .map_err(|_| format!("timed out after {:?}", self.config.timeout))?
+            .map(|response| response.into_inner())
+            .map_err(|status| status.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+    use tonic::transport::Server;
+
+    use super::quilkin::proxy::connection_tracker::v1alpha1::{
+        connection_tracker_server::{
+            ConnectionTracker as ConnectionTrackerService, ConnectionTrackerServer,
+        },
+        AdmitRequest, AdmitResponse,
+    };
+    use super::{Admission, ConnectionTracker, ExportedAdmission};
+    use crate::config::{ConnectionTracker as ConnectionTrackerConfig, FailurePolicy};
+    use crate::proxy::server::metrics::Metrics;
+    use crate::test_utils::logger;
+
+    /// Admits clients that send the token `allow`.
+    #[derive(Clone, Default)]
+    struct TokenTracker {
+        requests: Arc<AtomicUsize>,
+    }
+
+    #[tonic::async_trait]
+    impl ConnectionTrackerService for TokenTracker {
+        async fn admit(
+            &self,
+            request: tonic::Request<AdmitRequest>,
+        ) -> Result<tonic::Response<AdmitResponse>, tonic::Status> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let request = request.into_inner();
+            let response = if request.token == b"allow" {
+                let mut annotations = HashMap::new();
+                annotations.insert("player".into(), request.source_address);
+                AdmitResponse {
+                    allow: true,
+                    annotations,
+                    ..AdmitResponse::default()
+                }
+            } else {
+                AdmitResponse {
+                    allow: false,
+                    reason: "bad token".into(),
+                    ..AdmitResponse::default()
+                }
+            };
+            Ok(tonic::Response::new(response))
+        }
+    }
+
+    fn connection_tracker(address: String, failure_policy: FailurePolicy) -> ConnectionTracker {
+        ConnectionTracker::new(
+            logger(),
+            "test-proxy".into(),
+            ConnectionTrackerConfig {
+                address,
+                metadata_key: "quilkin.dev/captured_bytes".into(),
+                timeout: Duration::from_secs(1),
+                cache_ttl: Duration::from_secs(60),
+                failure_policy,
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn admit() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let service = TokenTracker::default();
+        let requests = service.requests.clone();
+        tokio::spawn(
+            Server::builder()
+                .add_service(ConnectionTrackerServer::new(service))
+                .serve(addr),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let tracker = connection_tracker(format!("http://{}", addr), FailurePolicy::Closed);
+        let from: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        let mut annotations = BTreeMap::new();
+        annotations.insert("player".to_string(), from.to_string());
+        assert_eq!(
+            Admission::Allow(annotations),
+            tracker.admit(from, Some(&b"allow"[..])).await
+        );
+        assert_eq!(Admission::Reject, tracker.admit(from, Some(&b"deny"[..])).await);
+        assert_eq!(Admission::Reject, tracker.admit(from, None).await);
+        assert_eq!(3, requests.load(Ordering::SeqCst));
+
+        // Decisions are cached per client and token.
+        assert_eq!(Admission::Reject, tracker.admit(from, Some(&b"deny"[..])).await);
+        assert_eq!(3, requests.load(Ordering::SeqCst));
+        assert_eq!(1, tracker.metrics.connection_tracker_allowed.get());
+        assert_eq!(2, tracker.metrics.connection_tracker_rejected.get());
+    }
+
+    #[tokio::test]
+    async fn admit_failure_policy() {
+        let from: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        // Nothing listens on this address so requests always fail.
+        let tracker = connection_tracker("http://127.0.0.1:1".into(), FailurePolicy::Open);
+        assert_eq!(
+            Admission::Allow(BTreeMap::new()),
+            tracker.admit(from, None).await
+        );
+        assert_eq!(1, tracker.metrics.connection_tracker_errors.get());
+
+        let tracker = connection_tracker("http://127.0.0.1:1".into(), FailurePolicy::Closed);
+        assert_eq!(Admission::Reject, tracker.admit(from, None).await);
+        assert_eq!(1, tracker.metrics.connection_tracker_errors.get());
+    }
+
+    #[tokio::test]
+    async fn export_import() {
+        let from: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        // Nothing listens on this address so requests fail, and clients are
+        // only admitted if a decision was imported.
+        let tracker = connection_tracker("http://127.0.0.1:1".into(), FailurePolicy::Closed);
+        assert!(tracker.export().is_empty());
+
+        let mut annotations = BTreeMap::new();
+        annotations.insert("player".to_string(), "1".to_string());
+        let admission = ExportedAdmission {
+            client: from,
+            token: Some(base64::encode(b"allow")),
+            annotations: annotations.clone(),
+            expires_in: Duration::from_secs(60),
+        };
+        assert_eq!(1, tracker.import(vec![admission]).unwrap());
+        assert_eq!(
+            Admission::Allow(annotations.clone()),
+            tracker.admit(from, Some(&b"allow"[..])).await
+        );
+        assert_eq!(0, tracker.metrics.connection_tracker_errors.get());
+
+        // Rejections aren't exported.
+        assert_eq!(Admission::Reject, tracker.admit(from, None).await);
+        let exported = tracker.export();
+        assert_eq!(1, exported.len());
+        assert_eq!(Some(base64::encode(b"allow")), exported[0].token);
+        assert_eq!(annotations, exported[0].annotations);
+        assert!(exported[0].expires_in <= Duration::from_secs(60));
+
+        let invalid = ExportedAdmission {
+            client: from,
+            token: Some("!".into()),
+            annotations: BTreeMap::new(),
+            expires_in: Duration::from_secs(60),
+        };
+        assert!(tracker.import(vec![invalid]).is_err());
+    }
+
+    #[tokio::test]
+    async fn token() {
+        let tracker = connection_tracker("http://127.0.0.1:1".into(), FailurePolicy::Open);
+
+        let mut metadata: HashMap<Arc<String>, Box<dyn std::any::Any + Send>> = HashMap::new();
+        assert_eq!(None, tracker.token(&metadata));
+
+        metadata.insert(
+            Arc::new("quilkin.dev/captured_bytes".into()),
+            Box::new(b"abc".to_vec()),
+        );
+        assert_eq!(Some(b"abc".to_vec()), tracker.token(&metadata));
+    }
+}
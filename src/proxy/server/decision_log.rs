@@ -0,0 +1,323 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Records why a sample of the received packets were sent to the endpoints
+//! they were, so that questions like "why did this player land on server X"
+//! can be answered from the proxy itself.
+
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use slog::{info, Logger};
+
+use crate::config::DecisionLog as DecisionLogConfig;
+use crate::filters::{DropReason, FilterStep, ReadResponse, Sample};
+
+/// Logs the endpoint selection decisions of sampled packets, keeping the
+/// latest `max_decisions` of them to be served by the admin server.
+pub(crate) struct DecisionLog {
+    log: Logger,
+    config: DecisionLogConfig,
+    recent: Mutex<VecDeque<Decision>>,
+}
+
+/// How the endpoints a packet was sent to were selected.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Decision {
+    /// When the packet was read, in milliseconds since the UNIX epoch.
+    unix_ms: u64,
+    client: SocketAddr,
+    /// The number of endpoints the packet could have been sent to before
+    /// any filter read it.
+    candidates: usize,
+    /// The filters that read the packet, in order, with the number of
+    /// endpoints left after each.
+    filters: Vec<FilterStep>,
+    /// The endpoints the packet was sent to, which are none if it was
+    /// dropped.
+    endpoints: Vec<SocketAddr>,
+    reason: Reason,
+}
+
+/// Why the packet was sent to the endpoints it was.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Reason {
+    /// No filter narrowed down the candidates.
+    AllEndpoints,
+    /// The candidates were narrowed down, last by `filter`.
+    Routed { filter: String },
+    /// The packet was dropped by `filter`.
+    Dropped { filter: String, code: &'static str },
+}
+
+impl DecisionLog {
+    pub(crate) fn new(log: Logger, config: DecisionLogConfig) -> Self {
+        Self {
+            log,
+            recent: Mutex::new(VecDeque::with_capacity(config.max_decisions)),
+            config,
+        }
+    }
+
+    /// Returns whether the decision of the packet with `sample` is recorded.
+    pub(crate) fn is_sampled(&self, sample: Sample) -> bool {
+        sample.is_sampled(self.config.sample_rate)
+    }
+
+    /// Records the decision of the packet received from `client`, which had
+    /// `candidates` endpoints before being read by the `filters` of the
+    /// filter chain with `result`.
+    pub(crate) fn record(
+        &self,
+        client: SocketAddr,
+        candidates: usize,
+        filters: Vec<FilterStep>,
+        result: &Result<ReadResponse, DropReason>,
+    ) {
+        let mut endpoints = vec![];
+        let reason = match result {
+            Ok(response) => {
+                chosen_endpoints(response, &mut endpoints);
+                let mut remaining = candidates;
+                let mut routed_by = None;
+                for step in &filters {
+                    if step.endpoints < remaining {
+                        routed_by = Some(step.filter.clone());
+                    }
+                    remaining = step.endpoints;
+                }
+                routed_by.map_or(Reason::AllEndpoints, |filter| Reason::Routed { filter })
+            }
+            Err(reason) => Reason::Dropped {
+                filter: reason.filter.clone(),
+                code: reason.code,
+            },
+        };
+        let decision = Decision {
+            unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or_default(),
+            client,
+            candidates,
+            filters,
+            endpoints,
+            reason,
+        };
+
+        if self.config.log {
+            info!(self.log, "Endpoint selection decision";
+                "client" => %decision.client, "candidates" => decision.candidates,
+                "filters" => ?decision.filters, "endpoints" => ?decision.endpoints,
+                "reason" => ?decision.reason);
+        }
+        if self.config.max_decisions == 0 {
+            return;
+        }
+        let mut recent = self.recent.lock();
+        if recent.len() >= self.config.max_decisions {
+            recent.pop_front();
+        }
+        recent.push_back(decision);
+    }
+
+    /// Returns the latest decisions, oldest first, only including those of
+    /// packets from `client` if set. A client without a port matches all of
+    /// the ports of its address.
+    pub(crate) fn recent(&self, client: Option<ClientFilter>) -> Vec<Decision> {
+        self.recent
+            .lock()
+            .iter()
+            .filter(|decision| match client {
+                Some(ClientFilter::Address(address)) => decision.client == address,
+                Some(ClientFilter::Ip(ip)) => decision.client.ip() == ip,
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// The client whose decisions are returned by [`DecisionLog::recent`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ClientFilter {
+    Address(SocketAddr),
+    Ip(IpAddr),
+}
+
+impl std::str::FromStr for ClientFilter {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map(Self::Address)
+            .or_else(|_| s.parse().map(Self::Ip))
+    }
+}
+
+/// Pushes the endpoints that `response` and its additional packets are sent
+/// to onto `endpoints`, once each.
+fn chosen_endpoints(response: &ReadResponse, endpoints: &mut Vec<SocketAddr>) {
+    for endpoint in response.endpoints.iter() {
+        if !endpoints.contains(&endpoint.address) {
+            endpoints.push(endpoint.address);
+        }
+    }
+    for response in &response.additional {
+        chosen_endpoints(response, endpoints);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{ClientFilter, DecisionLog, Reason};
+    use crate::cluster::Endpoint;
+    use crate::config::{DecisionLog as DecisionLogConfig, Endpoints};
+    use crate::filters::{DropReason, FilterStep, ReadContext, ReadResponse, Sample};
+    use crate::test_utils::logger;
+
+    fn decision_log(max_decisions: usize) -> DecisionLog {
+        DecisionLog::new(
+            logger(),
+            DecisionLogConfig {
+                sample_rate: 0.5,
+                log: false,
+                max_decisions,
+            },
+        )
+    }
+
+    fn step(filter: &str, endpoints: usize) -> FilterStep {
+        FilterStep {
+            filter: filter.into(),
+            endpoints,
+        }
+    }
+
+    #[test]
+    fn is_sampled() {
+        let decision_log = decision_log(1);
+        assert!(decision_log.is_sampled(Sample::from_value(0.1)));
+        assert!(!decision_log.is_sampled(Sample::from_value(0.9)));
+    }
+
+    /// Returns the response to a packet from `client` sent to the endpoint
+    /// at `index` of three endpoints, or to all of them.
+    fn response(client: SocketAddr, index: Option<usize>) -> ReadResponse {
+        let mut ctx = ReadContext::new(
+            Endpoints::new(vec![
+                Endpoint::from_address("127.0.0.1:8080".parse().unwrap()),
+                Endpoint::from_address("127.0.0.1:8081".parse().unwrap()),
+                Endpoint::from_address("127.0.0.1:8082".parse().unwrap()),
+            ])
+            .unwrap()
+            .into(),
+            client,
+            b"hello".to_vec(),
+        );
+        if let Some(index) = index {
+            ctx.endpoints.keep(index).unwrap();
+        }
+        ctx.into()
+    }
+
+    #[test]
+    fn record() {
+        let decision_log = decision_log(2);
+        let client = "127.0.0.1:7000".parse().unwrap();
+
+        decision_log.record(
+            client,
+            3,
+            vec![step("Capture", 3), step("Router", 1), step("Debug", 1)],
+            &Ok(response(client, Some(1))),
+        );
+        let decisions = decision_log.recent(None);
+        assert_eq!(1, decisions.len());
+        assert_eq!(3, decisions[0].candidates);
+        assert_eq!(
+            vec!["127.0.0.1:8081".parse::<SocketAddr>().unwrap()],
+            decisions[0].endpoints
+        );
+        assert_eq!(
+            Reason::Routed {
+                filter: "Router".into()
+            },
+            decisions[0].reason
+        );
+
+        decision_log.record(
+            client,
+            3,
+            vec![step("Debug", 3)],
+            &Ok(response(client, None)),
+        );
+        let decisions = decision_log.recent(None);
+        assert_eq!(3, decisions[1].endpoints.len());
+        assert_eq!(Reason::AllEndpoints, decisions[1].reason);
+
+        // Only the latest decisions are kept.
+        let other = "127.0.0.2:7000".parse().unwrap();
+        decision_log.record(
+            other,
+            3,
+            vec![],
+            &Err(DropReason {
+                filter: "Firewall".into(),
+                code: "Denied",
+            }),
+        );
+        let decisions = decision_log.recent(None);
+        assert_eq!(2, decisions.len());
+        assert_eq!(other, decisions[1].client);
+        assert!(decisions[1].endpoints.is_empty());
+        assert_eq!(
+            Reason::Dropped {
+                filter: "Firewall".into(),
+                code: "Denied",
+            },
+            decisions[1].reason
+        );
+    }
+
+    #[test]
+    fn recent_by_client() {
+        let decision_log = decision_log(10);
+        for client in &["127.0.0.1:7000", "127.0.0.1:7001", "127.0.0.2:7000"] {
+            decision_log.record(
+                client.parse().unwrap(),
+                1,
+                vec![],
+                &Err(DropReason {
+                    filter: "Firewall".into(),
+                    code: "Denied",
+                }),
+            );
+        }
+
+        let filter = "127.0.0.1:7000".parse::<ClientFilter>().unwrap();
+        assert_eq!(1, decision_log.recent(Some(filter)).len());
+        let filter = "127.0.0.1".parse::<ClientFilter>().unwrap();
+        assert_eq!(2, decision_log.recent(Some(filter)).len());
+        assert!("client".parse::<ClientFilter>().is_err());
+    }
+}
@@ -0,0 +1,311 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Diagnoses problems with a proxy's config and environment, by running the
+//! preflight checks along with checks of kernel settings and probes of the
+//! proxy's upstreams.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rand::seq::SliceRandom;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Instant};
+
+use crate::config::{ManagementServer, PortConflictPolicy};
+use crate::proxy::builder::{ValidatedConfig, ValidatedSource};
+
+use super::port_conflict;
+use super::preflight::{self, Check, Status};
+
+/// How long to wait for an endpoint to respond to a probe.
+const ENDPOINT_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The minimum recommended maximum size of a socket's receive buffer. Packets
+/// that arrive while the buffer is full are dropped by the kernel.
+#[cfg(target_os = "linux")]
+const MIN_RECOMMENDED_RMEM_MAX: u64 = 4 * 1024 * 1024;
+
+/// The results of the checks run by `quilkin doctor`.
+#[derive(Debug)]
+pub struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    /// Returns the number of checks that failed.
+    pub fn failures(&self) -> usize {
+        self.count(Status::Fail)
+    }
+
+    fn count(&self, status: Status) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == status)
+            .count()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Pass => "PASS",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            writeln!(f, "{} {}: {}", status, check.name, check.detail)?;
+        }
+        writeln!(
+            f,
+            "{} passed, {} warnings, {} failed",
+            self.count(Status::Pass),
+            self.count(Status::Warn),
+            self.failures()
+        )
+    }
+}
+
+/// Runs all checks against the provided config, probing up to
+/// `endpoint_sample` of its static endpoints, picked at random.
+pub(super) async fn run(config: &ValidatedConfig, endpoint_sample: usize) -> Report {
+    let port = config.proxy.port;
+    let policy = config.proxy.port_conflict_policy;
+    let peer = port_conflict::probe(port, &config.proxy.port_conflict_secret)
+        .await
+        .ok()
+        .flatten();
+    let mut checks = vec![
+        preflight::check_port_conflict(port, policy, peer.as_ref()),
+        preflight::check_port(port, policy == PortConflictPolicy::TakeOver),
+    ];
+
+    #[cfg(unix)]
+    checks.push(preflight::check_nofile());
+    #[cfg(target_os = "linux")]
+    checks.push(check_rmem_max());
+    #[cfg(target_os = "linux")]
+    checks.push(check_reuseport());
+
+    match &config.source {
+        ValidatedSource::Static { endpoints, .. }
+        | ValidatedSource::Mdns {
+            endpoints: Some(endpoints),
+            ..
+        } => {
+            let addresses = endpoints
+                .as_ref()
+                .choose_multiple(&mut rand::thread_rng(), endpoint_sample)
+                .map(|endpoint| endpoint.address)
+                .collect::<Vec<_>>();
+            for address in addresses {
+                checks.push(probe_endpoint(address).await);
+            }
+        }
+        // The endpoints discovered with mDNS aren't known until the proxy runs.
+        ValidatedSource::Mdns {
+            endpoints: None, ..
+        } => {}
+        ValidatedSource::Dynamic {
+            management_servers, ..
+        } => {
+            for server in management_servers {
+                checks.push(probe_management_server(server).await);
+            }
+        }
+    }
+
+    Report { checks }
+}
+
+/// Checks that the kernel allows sockets' receive buffers to be large enough
+/// to absorb bursts of packets.
+#[cfg(target_os = "linux")]
+fn check_rmem_max() -> Check {
+    let name = "rmem_max";
+    let rmem_max = match std::fs::read_to_string("/proc/sys/net/core/rmem_max")
+        .map_err(|err| err.to_string())
+        .and_then(|value| value.trim().parse::<u64>().map_err(|err| err.to_string()))
+    {
+        Ok(rmem_max) => rmem_max,
+        Err(err) => {
+            return Check::new(
+                name,
+                Status::Warn,
+                format!("failed to read net.core.rmem_max: {}", err),
+            )
+        }
+    };
+
+    if rmem_max < MIN_RECOMMENDED_RMEM_MAX {
+        Check::new(
+            name,
+            Status::Warn,
+            format!(
+                "net.core.rmem_max is {} bytes, so packets may be dropped during bursts of traffic; \
+                 consider raising it to at least {} (e.g `sysctl -w net.core.rmem_max={}`)",
+                rmem_max, MIN_RECOMMENDED_RMEM_MAX, MIN_RECOMMENDED_RMEM_MAX
+            ),
+        )
+    } else {
+        Check::new(
+            name,
+            Status::Pass,
+            format!("net.core.rmem_max is {} bytes", rmem_max),
+        )
+    }
+}
+
+/// Checks that a management server accepts connections.
+async fn probe_management_server(server: &ManagementServer) -> Check {
+    let name = "management_server";
+    match preflight::connect(&server.address).await {
+        Ok(()) => Check::new(
+            name,
+            Status::Pass,
+            format!("{} is reachable", server.address),
+        ),
+        Err(err) => Check::new(
+            name,
+            Status::Fail,
+            format!("{} is unreachable ({})", server.address, err),
+        ),
+    }
+}
+
+/// Sends an empty packet to an endpoint and waits for it to respond. An
+/// endpoint that doesn't respond is only a warning, as it may ignore packets
+/// it doesn't recognise, but one whose host rejects the packet is a failure.
+async fn probe_endpoint(address: SocketAddr) -> Check {
+    let name = "endpoint";
+    let started = Instant::now();
+    match timeout(ENDPOINT_PROBE_TIMEOUT, send_probe(address)).await {
+        Ok(Ok(())) => Check::new(
+            name,
+            Status::Pass,
+            format!("{} responded in {:?}", address, started.elapsed()),
+        ),
+        Ok(Err(err)) => Check::new(
+            name,
+            Status::Fail,
+            format!("{} is unreachable ({})", address, err),
+        ),
+        Err(_) => Check::new(
+            name,
+            Status::Warn,
+            format!(
+                "{} didn't respond within {:?}; check that it is running if it should respond \
+                 to empty packets",
+                address, ENDPOINT_PROBE_TIMEOUT
+            ),
+        ),
+    }
+}
+
+/// Sends an empty packet to `address` and waits for a packet back. Errors
+/// if the packet is rejected, e.g because nothing is listening on the port.
+async fn send_probe(address: SocketAddr) -> io::Result<()> {
+    let local: SocketAddr = if address.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(address).await?;
+    socket.send(&[]).await?;
+    let mut buf = [0; 1];
+    socket.recv(&mut buf).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::{probe_endpoint, probe_management_server, Check, Report, Status};
+    use crate::config::ManagementServer;
+
+    #[tokio::test]
+    async fn probe_responsive_endpoint() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1];
+            let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+            socket.send_to(b"hello", from).await.unwrap();
+        });
+        assert_eq!(Status::Pass, probe_endpoint(address).await.status);
+    }
+
+    #[tokio::test]
+    async fn probe_unresponsive_endpoint() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        assert_eq!(Status::Warn, probe_endpoint(address).await.status);
+        drop(socket);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn probe_closed_endpoint() {
+        let address = {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.local_addr().unwrap()
+        };
+        assert_eq!(Status::Fail, probe_endpoint(address).await.status);
+    }
+
+    #[tokio::test]
+    async fn probe_management_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = ManagementServer {
+            address: format!("http://{}", listener.local_addr().unwrap()),
+            token: None,
+        };
+        let unreachable = ManagementServer {
+            address: "http://127.0.0.1:1".into(),
+            token: None,
+        };
+        assert_eq!(
+            Status::Pass,
+            probe_management_server(&reachable).await.status
+        );
+        assert_eq!(
+            Status::Fail,
+            probe_management_server(&unreachable).await.status
+        );
+    }
+
+    #[test]
+    fn display_report() {
+        let report = Report {
+            checks: vec![
+                Check::new("listen_port", Status::Pass, "port 7000 is available"),
+                Check::new("rmem_max", Status::Warn, "net.core.rmem_max is 212992 bytes"),
+                Check::new("endpoint", Status::Fail, "127.0.0.1:7001 is unreachable"),
+            ],
+        };
+        assert_eq!(1, report.failures());
+        assert_eq!(
+            "PASS listen_port: port 7000 is available\n\
+             WARN rmem_max: net.core.rmem_max is 212992 bytes\n\
+             FAIL endpoint: 127.0.0.1:7001 is unreachable\n\
+             1 passed, 1 warnings, 1 failed\n",
+            report.to_string()
+        );
+    }
+}
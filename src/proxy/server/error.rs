@@ -20,6 +20,7 @@ use std::fmt::{self, Display, Formatter};
 #[derive(Debug)]
 pub enum Error {
     Initialize(String),
+    Preflight(String),
     Session(SessionError),
     Bind(tokio::io::Error),
     RecvLoop(String),
@@ -31,6 +32,7 @@ impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Error::Initialize(reason) => write!(f, "failed to startup properly: {}", reason),
+            Error::Preflight(reason) => write!(f, "preflight checks failed: {}", reason),
             Error::Session(inner) => write!(f, "session error: {}", inner),
             Error::Bind(inner) => write!(f, "failed to bind to port: {}", inner),
             Error::RecvLoop(reason) => write!(f, "receive loop exited with an error: {}", reason),
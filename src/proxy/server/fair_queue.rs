@@ -0,0 +1,217 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+/// A queue of received packets that is drained by taking a packet from each
+/// source with queued packets in turn, so that a source sending more packets
+/// than the proxy can process only delays its own packets.
+pub(crate) struct FairQueue<T> {
+    state: Mutex<State<T>>,
+    /// Notified whenever a packet is queued.
+    queued: Notify,
+    queue_size: usize,
+}
+
+struct State<T> {
+    packets: HashMap<SocketAddr, VecDeque<T>>,
+    /// The sources with queued packets, in the order they are drained.
+    sources: VecDeque<SocketAddr>,
+    len: usize,
+}
+
+impl<T> FairQueue<T> {
+    pub(crate) fn new(queue_size: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                packets: HashMap::new(),
+                sources: VecDeque::new(),
+                len: 0,
+            }),
+            queued: Notify::new(),
+            queue_size,
+        }
+    }
+
+    /// Queues `packet` from `source`. If the queue is full, a packet from
+    /// the source with the most queued packets is dropped to make room,
+    /// which is `packet` itself unless another source has more queued
+    /// packets than `source`. Returns whether a packet was dropped.
+    pub(crate) fn push(&self, source: SocketAddr, packet: T) -> bool {
+        let mut state = self.state.lock();
+        let State {
+            packets,
+            sources,
+            len,
+        } = &mut *state;
+        let mut dropped = false;
+        if *len >= self.queue_size {
+            let queued = packets.get(&source).map_or(0, VecDeque::len);
+            let longest = packets
+                .values_mut()
+                .max_by_key(|queue| queue.len())
+                .filter(|queue| queue.len() > queued + 1);
+            match longest {
+                // The oldest packet is dropped, as it is the most likely to
+                // be stale by the time it is processed.
+                Some(longest) => {
+                    longest.pop_front();
+                    *len -= 1;
+                    dropped = true;
+                }
+                None => return true,
+            }
+        }
+
+        packets
+            .entry(source)
+            .or_insert_with(|| {
+                sources.push_back(source);
+                VecDeque::new()
+            })
+            .push_back(packet);
+        *len += 1;
+        self.queued.notify_one();
+        dropped
+    }
+
+    /// Waits for a packet, taking one from each source with queued packets
+    /// in turn.
+    pub(crate) async fn pop(&self) -> (SocketAddr, T) {
+        loop {
+            if let Some(packet) = self.try_pop() {
+                return packet;
+            }
+            self.queued.notified().await;
+        }
+    }
+
+    fn try_pop(&self) -> Option<(SocketAddr, T)> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let source = state.sources.pop_front()?;
+        let packets = state.packets.get_mut(&source)?;
+        let packet = packets.pop_front()?;
+        if packets.is_empty() {
+            state.packets.remove(&source);
+        } else {
+            state.sources.push_back(source);
+        }
+        state.len -= 1;
+        // Another worker may be waiting while packets remain, as only one
+        // waiter is notified for each packet.
+        if state.len > 0 {
+            self.queued.notify_one();
+        }
+        Some((source, packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use tokio::time::{timeout, Duration};
+
+    use super::FairQueue;
+
+    fn source(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn round_robin() {
+        let queue = FairQueue::new(16);
+        for packet in 0..4 {
+            assert!(!queue.push(source(1), packet));
+        }
+        assert!(!queue.push(source(2), 10));
+        assert!(!queue.push(source(3), 20));
+        assert!(!queue.push(source(2), 11));
+
+        let mut popped = vec![];
+        for _ in 0..7 {
+            popped.push(queue.pop().await);
+        }
+        assert_eq!(
+            vec![
+                (source(1), 0),
+                (source(2), 10),
+                (source(3), 20),
+                (source(1), 1),
+                (source(2), 11),
+                (source(1), 2),
+                (source(1), 3),
+            ],
+            popped
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_from_longest() {
+        let queue = FairQueue::new(4);
+        for packet in 0..3 {
+            assert!(!queue.push(source(1), packet));
+        }
+        assert!(!queue.push(source(2), 10));
+
+        // The queue is full, so the oldest packet of the flooding source
+        // makes room for the other source's packet.
+        assert!(queue.push(source(2), 11));
+        // Until both sources have as many packets queued, after which a
+        // source's own packets are dropped.
+        assert!(queue.push(source(2), 12));
+        assert!(queue.push(source(1), 3));
+
+        let mut popped = vec![];
+        for _ in 0..4 {
+            popped.push(queue.pop().await);
+        }
+        assert_eq!(
+            vec![
+                (source(1), 1),
+                (source(2), 10),
+                (source(1), 2),
+                (source(2), 11),
+            ],
+            popped
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_for_packet() {
+        let queue = Arc::new(FairQueue::new(4));
+        let popped = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await }
+        });
+        tokio::task::yield_now().await;
+
+        queue.push(source(1), 0);
+        assert_eq!(
+            (source(1), 0),
+            timeout(Duration::from_secs(5), popped)
+                .await
+                .unwrap()
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,216 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::config::Handshake as HandshakeConfig;
+
+/// The size in bytes of the timestamp of a cookie.
+const TIMESTAMP_SIZE: usize = 8;
+/// The size in bytes of the signature of a cookie.
+const SIGNATURE_SIZE: usize = 32;
+/// The size in bytes of the secret generated if none is configured.
+const GENERATED_SECRET_SIZE: usize = 32;
+
+/// The outcome of checking a received packet for a cookie.
+#[derive(Debug, PartialEq)]
+pub(super) enum Cookie {
+    /// The packet does not contain a cookie. Holds the packet.
+    Missing(Vec<u8>),
+    /// The packet starts with a valid cookie. Holds the rest of the packet.
+    Valid(Vec<u8>),
+    /// The packet starts with an invalid or expired cookie.
+    Invalid,
+}
+
+/// Implements a stateless handshake that a client must complete before a
+/// session is created for it.
+///
+/// The proxy answers a client it has no session for with a challenge: the
+/// prefix followed by a cookie, which is the time the challenge was sent and
+/// an HMAC-SHA256 signature of that time and the client's address. The
+/// client completes the handshake by sending the challenge back at the start
+/// of its next packet. Since only a client that can receive packets at its
+/// address gets a cookie, spoofed packets can't create sessions, and no state
+/// is kept for clients until they complete the handshake.
+pub(super) struct Handshake {
+    secret: Vec<u8>,
+    prefix: Vec<u8>,
+    cookie_lifetime: Duration,
+}
+
+impl Handshake {
+    pub(super) fn new(config: HandshakeConfig) -> Self {
+        let secret = if config.secret.is_empty() {
+            (0..GENERATED_SECRET_SIZE)
+                .map(|_| rand::random::<u8>())
+                .collect()
+        } else {
+            config.secret
+        };
+        Self {
+            secret,
+            prefix: config.prefix,
+            cookie_lifetime: config.cookie_lifetime,
+        }
+    }
+
+    /// Returns the challenge to send to the client at `to`.
+    pub(super) fn challenge(&self, to: SocketAddr) -> Vec<u8> {
+        self.challenge_at(to, unix_time())
+    }
+
+    fn challenge_at(&self, to: SocketAddr, timestamp: u64) -> Vec<u8> {
+        let timestamp = timestamp.to_be_bytes();
+        let mut challenge = self.prefix.clone();
+        challenge.extend_from_slice(&timestamp);
+        challenge.extend_from_slice(&self.mac(to, &timestamp).finalize().into_bytes());
+        challenge
+    }
+
+    /// Returns the size of a challenge, and so of the bytes a client must
+    /// send back to complete the handshake.
+    pub(super) fn challenge_len(&self) -> usize {
+        self.prefix.len() + TIMESTAMP_SIZE + SIGNATURE_SIZE
+    }
+
+    /// Checks whether `packet` received from `from` starts with a valid
+    /// cookie.
+    pub(super) fn verify(&self, from: SocketAddr, mut packet: Vec<u8>) -> Cookie {
+        if !packet.starts_with(&self.prefix) {
+            return Cookie::Missing(packet);
+        }
+        if packet.len() < self.challenge_len() {
+            return Cookie::Invalid;
+        }
+
+        let (timestamp, signature) =
+            packet[self.prefix.len()..self.challenge_len()].split_at(TIMESTAMP_SIZE);
+        if self.mac(from, timestamp).verify(signature).is_err() {
+            return Cookie::Invalid;
+        }
+
+        let mut timestamp_bytes = [0; TIMESTAMP_SIZE];
+        timestamp_bytes.copy_from_slice(timestamp);
+        let sent_at = u64::from_be_bytes(timestamp_bytes);
+        // Cookies can be verified by a different proxy sharing the same
+        // secret, so allow for their clocks to be skewed.
+        let now = unix_time();
+        let age = Duration::from_secs(
+            now.checked_sub(sent_at)
+                .unwrap_or_else(|| sent_at - now),
+        );
+        if age > self.cookie_lifetime {
+            return Cookie::Invalid;
+        }
+
+        packet.drain(..self.challenge_len());
+        Cookie::Valid(packet)
+    }
+
+    fn mac(&self, addr: SocketAddr, timestamp: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC can take a key of any size");
+        mac.update(timestamp);
+        mac.update(addr.to_string().as_bytes());
+        mac
+    }
+}
+
+/// Returns the current time in seconds since the UNIX epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::{unix_time, Cookie, Handshake};
+    use crate::config::Handshake as HandshakeConfig;
+
+    fn handshake(secret: &[u8]) -> Handshake {
+        Handshake::new(HandshakeConfig {
+            secret: secret.to_vec(),
+            secret_ref: None,
+            prefix: b"COOKIE".to_vec(),
+            cookie_lifetime: Duration::from_secs(30),
+        })
+    }
+
+    #[test]
+    fn verify() {
+        let handshake = handshake(b"secret");
+        let client: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        assert_eq!(
+            Cookie::Missing(b"hello".to_vec()),
+            handshake.verify(client, b"hello".to_vec())
+        );
+
+        let mut packet = handshake.challenge(client);
+        assert_eq!(handshake.challenge_len(), packet.len());
+        packet.extend_from_slice(b"hello");
+        assert_eq!(
+            Cookie::Valid(b"hello".to_vec()),
+            handshake.verify(client, packet.clone())
+        );
+
+        // The cookie is only valid for the client it was sent to.
+        let other: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        assert_eq!(Cookie::Invalid, handshake.verify(other, packet.clone()));
+
+        // The cookie is only valid for proxies with the same secret.
+        assert_eq!(
+            Cookie::Invalid,
+            self::handshake(b"other").verify(client, packet)
+        );
+
+        // Truncated cookies are invalid.
+        assert_eq!(Cookie::Invalid, handshake.verify(client, b"COOKIE1234".to_vec()));
+    }
+
+    #[test]
+    fn verify_expired() {
+        let handshake = handshake(b"secret");
+        let client: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        let packet = handshake.challenge_at(client, unix_time() - 31);
+        assert_eq!(Cookie::Invalid, handshake.verify(client, packet));
+
+        let packet = handshake.challenge_at(client, unix_time() - 5);
+        assert_eq!(Cookie::Valid(vec![]), handshake.verify(client, packet));
+    }
+
+    #[test]
+    fn generated_secret() {
+        let client: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let first = handshake(b"");
+        let second = handshake(b"");
+
+        let packet = first.challenge(client);
+        assert_eq!(Cookie::Valid(vec![]), first.verify(client, packet.clone()));
+        assert_eq!(Cookie::Invalid, second.verify(client, packet));
+    }
+}
@@ -0,0 +1,417 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! An ICE-lite agent (RFC 8445 section 2.5), which answers the STUN binding
+//! requests (RFC 8489) that clients send to check their paths to the proxy.
+
+use std::net::{IpAddr, SocketAddr};
+
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::{json, Value};
+use sha1::Sha1;
+
+use crate::config::Ice as IceConfig;
+
+/// The size in bytes of a STUN message header.
+const HEADER_SIZE: usize = 20;
+/// The bytes that follow the message type and length in a STUN header.
+const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+/// The size in bytes of the transaction id in a STUN header.
+const TRANSACTION_ID_SIZE: usize = 12;
+/// The size in bytes of a STUN attribute header.
+const ATTRIBUTE_HEADER_SIZE: usize = 4;
+/// The size in bytes of the value of a MESSAGE-INTEGRITY attribute.
+const MESSAGE_INTEGRITY_SIZE: usize = 20;
+/// The size in bytes of the value of a FINGERPRINT attribute.
+const FINGERPRINT_SIZE: usize = 4;
+/// The value that the CRC-32 of a message is XORed with in its FINGERPRINT.
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+
+const USERNAME: u16 = 0x0006;
+const MESSAGE_INTEGRITY: u16 = 0x0008;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const USE_CANDIDATE: u16 = 0x0025;
+const FINGERPRINT: u16 = 0x8028;
+
+/// The number of random bytes that a generated username fragment and
+/// password are encoded from.
+const GENERATED_UFRAG_SIZE: usize = 6;
+const GENERATED_PASSWORD_SIZE: usize = 18;
+
+/// The outcome of checking a received packet for an ICE connectivity check.
+#[derive(Debug, PartialEq)]
+pub(super) enum IceCheck {
+    /// The packet isn't a STUN message. Holds the packet.
+    NotStun(Vec<u8>),
+    /// The packet is a valid connectivity check. Holds the response to send
+    /// back, and whether the client nominated the path it was sent on.
+    Valid { response: Vec<u8>, nominated: bool },
+    /// The packet is a STUN message, but not a valid connectivity check.
+    Invalid,
+}
+
+/// Answers the connectivity checks that clients send to the proxy's
+/// candidates. As a lite agent, the proxy never sends checks itself, so it
+/// only needs its own credentials and candidates, which clients are given
+/// through the `/ice` admin endpoint.
+pub(crate) struct IceLite {
+    ufrag: String,
+    password: String,
+    candidates: Vec<SocketAddr>,
+}
+
+impl IceLite {
+    pub(crate) fn new(config: IceConfig) -> Self {
+        // Base64 only uses `ice-char`s, once its padding is removed.
+        let generate = |size| {
+            let bytes = (0..size).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+            base64::encode(bytes).trim_end_matches('=').to_string()
+        };
+        Self {
+            ufrag: if config.ufrag.is_empty() {
+                generate(GENERATED_UFRAG_SIZE)
+            } else {
+                config.ufrag
+            },
+            password: if config.password.is_empty() {
+                generate(GENERATED_PASSWORD_SIZE)
+            } else {
+                config.password
+            },
+            candidates: config.candidates,
+        }
+    }
+
+    /// Returns the ICE parameters that clients need to check their paths to
+    /// the proxy, with its candidates as SDP `candidate` attributes.
+    pub(crate) fn parameters(&self) -> Value {
+        let candidates = self
+            .candidates
+            .iter()
+            .enumerate()
+            .map(|(index, address)| {
+                // Type preference 126 for host candidates, and a local
+                // preference that follows the order of the candidates.
+                let priority = (126 << 24) | ((65535 - index as u32) << 8) | 255;
+                format!(
+                    "candidate:{} 1 udp {} {} {} typ host",
+                    index + 1,
+                    priority,
+                    address.ip(),
+                    address.port()
+                )
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "ice_lite": true,
+            "ufrag": self.ufrag,
+            "password": self.password,
+            "candidates": candidates,
+        })
+    }
+
+    /// Checks whether `packet`, received from `from`, is a connectivity
+    /// check, and if it's a valid one, returns the response to it.
+    pub(super) fn check(&self, from: SocketAddr, packet: Vec<u8>) -> IceCheck {
+        if !is_stun(&packet) {
+            return IceCheck::NotStun(packet);
+        }
+        if u16::from_be_bytes([packet[0], packet[1]]) != BINDING_REQUEST {
+            return IceCheck::Invalid;
+        }
+
+        let mut username = None;
+        let mut integrity = None;
+        let mut fingerprint = None;
+        let mut nominated = false;
+        let mut offset = HEADER_SIZE;
+        while offset < packet.len() {
+            let (kind, value) = match attribute(&packet, offset) {
+                Some(attribute) => attribute,
+                None => return IceCheck::Invalid,
+            };
+            match kind {
+                FINGERPRINT => fingerprint = Some((offset, value)),
+                // Only the fingerprint is checked after the message
+                // integrity, and nothing after the fingerprint.
+                _ if integrity.is_some() || fingerprint.is_some() => {}
+                USERNAME => username = Some(value),
+                MESSAGE_INTEGRITY => integrity = Some((offset, value)),
+                USE_CANDIDATE => nominated = true,
+                _ => {}
+            }
+            offset += ATTRIBUTE_HEADER_SIZE + padded(value.len());
+        }
+
+        // Connectivity checks carry a fingerprint as their last attribute,
+        // which tells them apart from other traffic.
+        match fingerprint {
+            Some((offset, value))
+                if offset + ATTRIBUTE_HEADER_SIZE + FINGERPRINT_SIZE == packet.len()
+                    && value == self::fingerprint(&packet[..offset]).to_be_bytes() => {}
+            _ => return IceCheck::Invalid,
+        }
+
+        // The username is the proxy's username fragment, then the client's.
+        let expected_username = format!("{}:", self.ufrag);
+        match username {
+            Some(username) if username.starts_with(expected_username.as_bytes()) => {}
+            _ => return IceCheck::Invalid,
+        }
+
+        let (offset, value) = match integrity {
+            Some(integrity) => integrity,
+            None => return IceCheck::Invalid,
+        };
+        // The integrity covers the message up to its attribute, with the
+        // length in the header as if the attribute were the last one.
+        let length = (offset + ATTRIBUTE_HEADER_SIZE + MESSAGE_INTEGRITY_SIZE - HEADER_SIZE) as u16;
+        let mac = self.mac(&[&packet[..2], &length.to_be_bytes(), &packet[4..offset]]);
+        if mac.verify(value).is_err() {
+            return IceCheck::Invalid;
+        }
+
+        IceCheck::Valid {
+            response: self.response(
+                from,
+                &packet[HEADER_SIZE - TRANSACTION_ID_SIZE..HEADER_SIZE],
+            ),
+            nominated,
+        }
+    }
+
+    /// Returns the successful response to the binding request with
+    /// `transaction_id`, which tells the client at `from` the address the
+    /// proxy received the request from.
+    fn response(&self, from: SocketAddr, transaction_id: &[u8]) -> Vec<u8> {
+        let mut response = Vec::with_capacity(HEADER_SIZE + 64);
+        response.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        response.extend_from_slice(&[0, 0]);
+        response.extend_from_slice(&MAGIC_COOKIE);
+        response.extend_from_slice(transaction_id);
+
+        // The address is XORed with the magic cookie followed by the
+        // transaction id, and the port with the start of the magic cookie.
+        let (family, address) = match from.ip() {
+            IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+        };
+        let mut value = vec![0, family];
+        value.extend_from_slice(&(from.port() ^ 0x2112).to_be_bytes());
+        value.extend(
+            address
+                .iter()
+                .zip(MAGIC_COOKIE.iter().chain(transaction_id))
+                .map(|(a, b)| a ^ b),
+        );
+        push_attribute(&mut response, XOR_MAPPED_ADDRESS, &value);
+
+        set_length(
+            &mut response,
+            ATTRIBUTE_HEADER_SIZE + MESSAGE_INTEGRITY_SIZE,
+        );
+        let integrity = self.integrity(&[&response]);
+        push_attribute(&mut response, MESSAGE_INTEGRITY, &integrity);
+        set_length(&mut response, ATTRIBUTE_HEADER_SIZE + FINGERPRINT_SIZE);
+        let fingerprint = fingerprint(&response);
+        push_attribute(&mut response, FINGERPRINT, &fingerprint.to_be_bytes());
+        response
+    }
+
+    /// Returns the HMAC-SHA1 of `parts`, keyed by the proxy's password.
+    fn integrity(&self, parts: &[&[u8]]) -> [u8; MESSAGE_INTEGRITY_SIZE] {
+        let mut integrity = [0; MESSAGE_INTEGRITY_SIZE];
+        integrity.copy_from_slice(&self.mac(parts).finalize().into_bytes());
+        integrity
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> Hmac<Sha1> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(self.password.as_bytes())
+            .expect("HMAC can take a key of any size");
+        for part in parts {
+            mac.update(part);
+        }
+        mac
+    }
+}
+
+/// Returns whether `packet` is a STUN message. Its first two bits are zero,
+/// it contains the magic cookie, and its length matches the one in its
+/// header.
+fn is_stun(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_SIZE
+        && packet[0] & 0xc0 == 0
+        && packet[4..8] == MAGIC_COOKIE
+        && usize::from(u16::from_be_bytes([packet[2], packet[3]])) == packet.len() - HEADER_SIZE
+        && packet.len() % 4 == 0
+}
+
+/// Returns the type and value of the attribute at `offset` in `message`, or
+/// `None` if it overruns the message.
+fn attribute(message: &[u8], offset: usize) -> Option<(u16, &[u8])> {
+    let header = message.get(offset..offset + ATTRIBUTE_HEADER_SIZE)?;
+    let attribute = u16::from_be_bytes([header[0], header[1]]);
+    let length = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    let start = offset + ATTRIBUTE_HEADER_SIZE;
+    let value = message.get(start..start + length)?;
+    Some((attribute, value))
+}
+
+/// Appends an attribute to `message`, padding its value to 4 bytes.
+fn push_attribute(message: &mut Vec<u8>, attribute: u16, value: &[u8]) {
+    message.extend_from_slice(&attribute.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    message.extend_from_slice(value);
+    message.resize(message.len() + padded(value.len()) - value.len(), 0);
+}
+
+/// Sets the length in the header of `message` to the length of its
+/// attributes so far, plus `extra` bytes of attributes still to be added.
+fn set_length(message: &mut [u8], extra: usize) {
+    let length = (message.len() - HEADER_SIZE + extra) as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+}
+
+/// Returns the FINGERPRINT of the message up to its fingerprint attribute.
+fn fingerprint(message: &[u8]) -> u32 {
+    crc32fast::hash(message) ^ FINGERPRINT_XOR
+}
+
+/// Returns `length` rounded up to a multiple of 4.
+fn padded(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{
+        attribute, fingerprint, IceCheck, IceLite, FINGERPRINT, MAGIC_COOKIE, MESSAGE_INTEGRITY,
+        XOR_MAPPED_ADDRESS,
+    };
+    use crate::config::Ice as IceConfig;
+
+    /// The sample request from RFC 5769 section 2.1, sent by the client
+    /// `h6vY` to the agent `evtj` with the password `VOkJxbRl1RmTxUk/WvJxBt`.
+    const SAMPLE_REQUEST: &str = "000100582112a442b7e7a701bc34d686fa87dfae80220010\
+                                  5354554e207465737420636c69656e74002400046e0001ff\
+                                  80290008932ff9b151263b36000600096576746a3a683676\
+                                  59202020000800149aeaa70cbfd8cb56781ef2b5b2d3f249\
+                                  c1b571a280280004e57a3bcf";
+
+    fn ice(ufrag: &str) -> IceLite {
+        IceLite::new(IceConfig {
+            ufrag: ufrag.into(),
+            password: "VOkJxbRl1RmTxUk/WvJxBt".into(),
+            candidates: vec!["203.0.113.1:7000".parse().unwrap()],
+        })
+    }
+
+    fn sample_request() -> Vec<u8> {
+        (0..SAMPLE_REQUEST.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&SAMPLE_REQUEST[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn check() {
+        let ice = ice("evtj");
+        let from: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let request = sample_request();
+
+        let response = match ice.check(from, request.clone()) {
+            IceCheck::Valid {
+                response,
+                nominated,
+            } => {
+                assert!(!nominated);
+                response
+            }
+            check => unreachable!("expected a valid check: got {:?}", check),
+        };
+
+        // A binding success response for the same transaction.
+        assert_eq!(0x0101, u16::from_be_bytes([response[0], response[1]]));
+        assert_eq!(request[4..20], response[4..20]);
+        let (kind, value) = attribute(&response, 20).unwrap();
+        assert_eq!(XOR_MAPPED_ADDRESS, kind);
+        assert_eq!([0x00, 0x01, 0xa1, 0x47], value[..4]);
+        let address = value[4..]
+            .iter()
+            .zip(MAGIC_COOKIE.iter())
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![192, 0, 2, 1], address);
+        let (kind, _) = attribute(&response, 32).unwrap();
+        assert_eq!(MESSAGE_INTEGRITY, kind);
+        let (kind, value) = attribute(&response, 56).unwrap();
+        assert_eq!(FINGERPRINT, kind);
+        assert_eq!(fingerprint(&response[..56]).to_be_bytes(), value);
+        assert_eq!(64, response.len());
+
+        // The response is itself a valid STUN message, but not a check.
+        assert_eq!(IceCheck::Invalid, ice.check(from, response));
+
+        // Checks for another agent are invalid.
+        assert_eq!(
+            IceCheck::Invalid,
+            self::ice("other").check(from, request.clone())
+        );
+
+        // As are checks that were tampered with.
+        let mut tampered = request.clone();
+        tampered[30] ^= 1;
+        assert_eq!(IceCheck::Invalid, ice.check(from, tampered));
+
+        // Other packets are left alone.
+        assert_eq!(
+            IceCheck::NotStun(b"hello".to_vec()),
+            ice.check(from, b"hello".to_vec())
+        );
+        let mut truncated = request;
+        truncated.truncate(40);
+        assert!(matches!(ice.check(from, truncated), IceCheck::NotStun(_)));
+    }
+
+    #[test]
+    fn parameters() {
+        let ice = IceLite::new(IceConfig {
+            ufrag: "".into(),
+            password: "".into(),
+            candidates: vec![
+                "203.0.113.1:7000".parse().unwrap(),
+                "[2001:db8::1]:7000".parse().unwrap(),
+            ],
+        });
+        let parameters = ice.parameters();
+
+        assert!(parameters["ice_lite"].as_bool().unwrap());
+        assert_eq!(8, parameters["ufrag"].as_str().unwrap().len());
+        assert_eq!(24, parameters["password"].as_str().unwrap().len());
+        assert_eq!(
+            serde_json::json!([
+                "candidate:1 1 udp 2130706431 203.0.113.1 7000 typ host",
+                "candidate:2 1 udp 2130706175 2001:db8::1 7000 typ host",
+            ]),
+            parameters["candidates"]
+        );
+    }
+}
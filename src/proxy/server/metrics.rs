@@ -14,29 +14,346 @@
  * limitations under the License.
  */
 
-use crate::metrics::{opts, CollectorExt};
+use crate::metrics::{histogram_opts, latency_buckets, opts, CollectorExt};
 use prometheus::core::{AtomicU64, GenericCounter};
-use prometheus::{IntCounterVec, Registry, Result as MetricsResult};
+use prometheus::{
+    Histogram, IntCounter, IntCounterVec, IntGauge, Registry, Result as MetricsResult,
+};
 
 #[derive(Clone)]
 pub struct Metrics {
     pub packets_dropped_no_endpoints: GenericCounter<AtomicU64>,
+    pub packets_dropped_oversized: GenericCounter<AtomicU64>,
+    pub packets_dropped_message_too_large: GenericCounter<AtomicU64>,
+    pub packets_dropped_buffer_full: GenericCounter<AtomicU64>,
+    pub packets_dropped_buffer_expired: GenericCounter<AtomicU64>,
+    pub packets_buffered_total: GenericCounter<AtomicU64>,
+    pub packets_dropped_session_rejected: GenericCounter<AtomicU64>,
+    pub connection_tracker_allowed: GenericCounter<AtomicU64>,
+    pub connection_tracker_rejected: GenericCounter<AtomicU64>,
+    pub connection_tracker_errors: GenericCounter<AtomicU64>,
+    pub packets_dropped_handshake_required: GenericCounter<AtomicU64>,
+    pub packets_dropped_invalid_cookie: GenericCounter<AtomicU64>,
+    pub handshake_challenges_total: GenericCounter<AtomicU64>,
+    pub packets_dropped_compute_pool_full: GenericCounter<AtomicU64>,
+    pub read_delay_seconds: Histogram,
+    pub packets_shed_queue_full: GenericCounter<AtomicU64>,
+    pub packets_shed_queued: GenericCounter<AtomicU64>,
+    pub packets_shed_filtering: GenericCounter<AtomicU64>,
+    pub packets_shed_fair_queue_full: GenericCounter<AtomicU64>,
+    pub packets_dropped_first_packet_rejected: GenericCounter<AtomicU64>,
+    pub client_version_sessions_total: IntCounterVec,
+    pub packets_dropped_invalid_connection_id: GenericCounter<AtomicU64>,
+    pub packets_dropped_invalid_ice_check: GenericCounter<AtomicU64>,
+    pub ice_checks_total: IntCounter,
+    pub packets_dropped_banned: GenericCounter<AtomicU64>,
+    pub packets_dropped_invalid_relay_envelope: GenericCounter<AtomicU64>,
+    pub packets_dropped_no_relay_endpoint: GenericCounter<AtomicU64>,
+    pub bans_local: IntCounter,
+    pub bans_peer: IntCounter,
+    pub bans_admin: IntCounter,
+    pub ban_gossip_invalid_total: IntCounter,
+    pub active_bans: IntGauge,
+    pub bursts_total: IntCounter,
+    pub burst_webhook_errors_total: IntCounter,
+    pub packets_dropped_standby: GenericCounter<AtomicU64>,
+    pub standby_active: IntGauge,
+    pub standby_transitions_total: IntCounter,
+    pub standby_heartbeats_invalid_total: IntCounter,
+    pub standby_syncs_sent: GenericCounter<AtomicU64>,
+    pub standby_syncs_failed: GenericCounter<AtomicU64>,
+    pub standby_syncs_received: GenericCounter<AtomicU64>,
+    pub standby_syncs_invalid: GenericCounter<AtomicU64>,
+    pub tunnels_total: IntCounter,
+    pub tunnels_rejected_total: IntCounter,
+    pub active_tunnels: IntGauge,
+    pub connect_udp_accepted: GenericCounter<AtomicU64>,
+    pub connect_udp_rejected: GenericCounter<AtomicU64>,
+    pub active_connect_udp_streams: IntGauge,
+    pub socks5_associations_accepted: GenericCounter<AtomicU64>,
+    pub socks5_associations_rejected: GenericCounter<AtomicU64>,
+    pub socks5_associations_limited: GenericCounter<AtomicU64>,
+    pub active_socks5_associations: IntGauge,
+    pub resident_memory_bytes: IntGauge,
+    pub open_fds: IntGauge,
+    pub max_fds: IntGauge,
+    pub open_sockets: IntGauge,
+    pub threads: IntGauge,
 }
 
 impl Metrics {
     pub fn new(registry: &Registry) -> MetricsResult<Self> {
         let subsystem = "proxy";
+        let packets_dropped_total = IntCounterVec::new(
+            opts(
+                "packets_dropped_total",
+                subsystem,
+                "Total number of packets dropped by the proxy",
+            ),
+            &["reason"],
+        )?
+        .register_if_not_exists(registry)?;
+        let packets_buffered_total = IntCounter::with_opts(opts(
+            "packets_buffered_total",
+            subsystem,
+            "Total number of packets held by the failover buffer while there were no endpoints",
+        ))?
+        .register_if_not_exists(registry)?;
+        let connection_tracker_requests_total = IntCounterVec::new(
+            opts(
+                "connection_tracker_requests_total",
+                subsystem,
+                "Total number of requests to the connection tracker to admit a session",
+            ),
+            &["result"],
+        )?
+        .register_if_not_exists(registry)?;
+        let handshake_challenges_total = IntCounter::with_opts(opts(
+            "handshake_challenges_total",
+            subsystem,
+            "Total number of handshake challenges sent to clients without a session",
+        ))?
+        .register_if_not_exists(registry)?;
+        let packets_shed_total = IntCounterVec::new(
+            opts(
+                "packets_shed_total",
+                subsystem,
+                "Total number of packets dropped because the proxy was overloaded",
+            ),
+            &["reason"],
+        )?
+        .register_if_not_exists(registry)?;
+        let connect_udp_requests_total = IntCounterVec::new(
+            opts(
+                "connect_udp_requests_total",
+                subsystem,
+                "Total number of UDP proxying requests received by the proxy",
+            ),
+            &["result"],
+        )?
+        .register_if_not_exists(registry)?;
+        let socks5_associations_total = IntCounterVec::new(
+            opts(
+                "socks5_associations_total",
+                subsystem,
+                "Total number of SOCKS5 UDP associations requested from the proxy",
+            ),
+            &["result"],
+        )?
+        .register_if_not_exists(registry)?;
+        let bans_total = IntCounterVec::new(
+            opts(
+                "bans_total",
+                subsystem,
+                "Total number of clients banned by the proxy, its peers or operators",
+            ),
+            &["source"],
+        )?
+        .register_if_not_exists(registry)?;
+        let standby_syncs_total = IntCounterVec::new(
+            opts(
+                "standby_syncs_total",
+                subsystem,
+                "Total number of state replications between the proxies of an active/standby pair",
+            ),
+            &["result"],
+        )?
+        .register_if_not_exists(registry)?;
         Ok(Self {
-            packets_dropped_no_endpoints: IntCounterVec::new(
+            packets_dropped_no_endpoints: packets_dropped_total
+                .get_metric_with_label_values(&["NoConfiguredEndpoints"])?,
+            packets_dropped_oversized: packets_dropped_total
+                .get_metric_with_label_values(&["OversizedPacket"])?,
+            packets_dropped_message_too_large: packets_dropped_total
+                .get_metric_with_label_values(&["MessageTooLarge"])?,
+            packets_dropped_buffer_full: packets_dropped_total
+                .get_metric_with_label_values(&["FailoverBufferFull"])?,
+            packets_dropped_buffer_expired: packets_dropped_total
+                .get_metric_with_label_values(&["FailoverBufferExpired"])?,
+            packets_buffered_total,
+            packets_dropped_session_rejected: packets_dropped_total
+                .get_metric_with_label_values(&["SessionRejected"])?,
+            connection_tracker_allowed: connection_tracker_requests_total
+                .get_metric_with_label_values(&["Allowed"])?,
+            connection_tracker_rejected: connection_tracker_requests_total
+                .get_metric_with_label_values(&["Rejected"])?,
+            connection_tracker_errors: connection_tracker_requests_total
+                .get_metric_with_label_values(&["Error"])?,
+            packets_dropped_handshake_required: packets_dropped_total
+                .get_metric_with_label_values(&["HandshakeRequired"])?,
+            packets_dropped_invalid_cookie: packets_dropped_total
+                .get_metric_with_label_values(&["InvalidCookie"])?,
+            handshake_challenges_total,
+            packets_dropped_compute_pool_full: packets_dropped_total
+                .get_metric_with_label_values(&["ComputePoolFull"])?,
+            read_delay_seconds: Histogram::with_opts(histogram_opts(
+                "read_delay_seconds",
+                subsystem,
+                "Seconds between a packet being received from a downstream client and the filter chain processing it",
+                Some(latency_buckets(&[
+                    0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+                ])),
+            ))?
+            .register_if_not_exists(registry)?,
+            packets_shed_queue_full: packets_shed_total
+                .get_metric_with_label_values(&["QueueFull"])?,
+            packets_shed_queued: packets_shed_total
+                .get_metric_with_label_values(&["DeadlineExceededQueued"])?,
+            packets_shed_filtering: packets_shed_total
+                .get_metric_with_label_values(&["DeadlineExceededFiltering"])?,
+            packets_shed_fair_queue_full: packets_shed_total
+                .get_metric_with_label_values(&["FairQueueFull"])?,
+            packets_dropped_first_packet_rejected: packets_dropped_total
+                .get_metric_with_label_values(&["FirstPacketRejected"])?,
+            packets_dropped_invalid_connection_id: packets_dropped_total
+                .get_metric_with_label_values(&["InvalidConnectionId"])?,
+            packets_dropped_invalid_ice_check: packets_dropped_total
+                .get_metric_with_label_values(&["InvalidIceCheck"])?,
+            ice_checks_total: IntCounter::with_opts(opts(
+                "ice_checks_total",
+                subsystem,
+                "Total number of ICE connectivity checks answered by the proxy",
+            ))?
+            .register_if_not_exists(registry)?,
+            packets_dropped_banned: packets_dropped_total
+                .get_metric_with_label_values(&["Banned"])?,
+            packets_dropped_invalid_relay_envelope: packets_dropped_total
+                .get_metric_with_label_values(&["InvalidRelayEnvelope"])?,
+            packets_dropped_no_relay_endpoint: packets_dropped_total
+                .get_metric_with_label_values(&["NoRelayEndpoint"])?,
+            bans_local: bans_total.get_metric_with_label_values(&["Local"])?,
+            bans_peer: bans_total.get_metric_with_label_values(&["Peer"])?,
+            bans_admin: bans_total.get_metric_with_label_values(&["Admin"])?,
+            ban_gossip_invalid_total: IntCounter::with_opts(opts(
+                "ban_gossip_invalid_total",
+                subsystem,
+                "Total number of bans received from peers that were invalid or expired",
+            ))?
+            .register_if_not_exists(registry)?,
+            active_bans: IntGauge::with_opts(opts(
+                "active_bans",
+                subsystem,
+                "Number of clients currently banned",
+            ))?
+            .register_if_not_exists(registry)?,
+            client_version_sessions_total: IntCounterVec::new(
                 opts(
-                    "packets_dropped_total",
+                    "client_version_sessions_total",
                     subsystem,
-                    "Total number of packets dropped by the proxy",
+                    "Total number of sessions created by the version of their client's build. \
+                     labels: client_version.",
                 ),
-                &["reason"],
+                &["client_version"],
             )?
-            .register_if_not_exists(registry)?
-            .get_metric_with_label_values(&["NoConfiguredEndpoints"])?,
+            .register_if_not_exists(registry)?,
+            bursts_total: IntCounter::with_opts(opts(
+                "bursts_total",
+                subsystem,
+                "Total number of bursts of packets sent by clients",
+            ))?
+            .register_if_not_exists(registry)?,
+            burst_webhook_errors_total: IntCounter::with_opts(opts(
+                "burst_webhook_errors_total",
+                subsystem,
+                "Total number of burst events that couldn't be sent to the webhook",
+            ))?
+            .register_if_not_exists(registry)?,
+            packets_dropped_standby: packets_dropped_total
+                .get_metric_with_label_values(&["Standby"])?,
+            standby_active: IntGauge::with_opts(opts(
+                "standby_active",
+                subsystem,
+                "Whether the proxy is the active proxy of its active/standby pair",
+            ))?
+            .register_if_not_exists(registry)?,
+            standby_transitions_total: IntCounter::with_opts(opts(
+                "standby_transitions_total",
+                subsystem,
+                "Total number of times the proxy switched between being active and standby",
+            ))?
+            .register_if_not_exists(registry)?,
+            standby_heartbeats_invalid_total: IntCounter::with_opts(opts(
+                "standby_heartbeats_invalid_total",
+                subsystem,
+                "Total number of heartbeats received from the peer proxy that were invalid",
+            ))?
+            .register_if_not_exists(registry)?,
+            standby_syncs_sent: standby_syncs_total.get_metric_with_label_values(&["Sent"])?,
+            standby_syncs_failed: standby_syncs_total.get_metric_with_label_values(&["Failed"])?,
+            standby_syncs_received: standby_syncs_total
+                .get_metric_with_label_values(&["Received"])?,
+            standby_syncs_invalid: standby_syncs_total
+                .get_metric_with_label_values(&["Invalid"])?,
+            tunnels_total: IntCounter::with_opts(opts(
+                "tunnels_total",
+                subsystem,
+                "Total number of tunnels accepted from peer proxies",
+            ))?
+            .register_if_not_exists(registry)?,
+            tunnels_rejected_total: IntCounter::with_opts(opts(
+                "tunnels_rejected_total",
+                subsystem,
+                "Total number of tunnels from peer proxies that were rejected",
+            ))?
+            .register_if_not_exists(registry)?,
+            active_tunnels: IntGauge::with_opts(opts(
+                "active_tunnels",
+                subsystem,
+                "Number of tunnels from peer proxies currently open",
+            ))?
+            .register_if_not_exists(registry)?,
+            connect_udp_accepted: connect_udp_requests_total
+                .get_metric_with_label_values(&["Accepted"])?,
+            connect_udp_rejected: connect_udp_requests_total
+                .get_metric_with_label_values(&["Rejected"])?,
+            active_connect_udp_streams: IntGauge::with_opts(opts(
+                "active_connect_udp_streams",
+                subsystem,
+                "Number of UDP proxying streams currently open",
+            ))?
+            .register_if_not_exists(registry)?,
+            socks5_associations_accepted: socks5_associations_total
+                .get_metric_with_label_values(&["Accepted"])?,
+            socks5_associations_rejected: socks5_associations_total
+                .get_metric_with_label_values(&["Rejected"])?,
+            socks5_associations_limited: socks5_associations_total
+                .get_metric_with_label_values(&["LimitReached"])?,
+            active_socks5_associations: IntGauge::with_opts(opts(
+                "active_socks5_associations",
+                subsystem,
+                "Number of SOCKS5 UDP associations currently open",
+            ))?
+            .register_if_not_exists(registry)?,
+            resident_memory_bytes: IntGauge::with_opts(opts(
+                "resident_memory_bytes",
+                subsystem,
+                "Resident memory used by the proxy, in bytes",
+            ))?
+            .register_if_not_exists(registry)?,
+            open_fds: IntGauge::with_opts(opts(
+                "open_fds",
+                subsystem,
+                "Number of file descriptors the proxy has open",
+            ))?
+            .register_if_not_exists(registry)?,
+            max_fds: IntGauge::with_opts(opts(
+                "max_fds",
+                subsystem,
+                "Maximum number of file descriptors the proxy can have open",
+            ))?
+            .register_if_not_exists(registry)?,
+            open_sockets: IntGauge::with_opts(opts(
+                "open_sockets",
+                subsystem,
+                "Number of sockets the proxy has open",
+            ))?
+            .register_if_not_exists(registry)?,
+            threads: IntGauge::with_opts(opts(
+                "threads",
+                subsystem,
+                "Number of threads the proxy is running",
+            ))?
+            .register_if_not_exists(registry)?,
         })
     }
 }
@@ -0,0 +1,226 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::FailoverBuffer;
+use crate::proxy::server::metrics::Metrics;
+
+/// Holds packets received while there are no endpoints available to forward
+/// them to, so that they can be forwarded once endpoints become available.
+pub(super) struct PacketBuffer {
+    max_delay: Duration,
+    max_packets: usize,
+    metrics: Metrics,
+    /// The total number of packets held, to check for an empty buffer
+    /// without taking the lock.
+    len: AtomicUsize,
+    packets: Mutex<HashMap<SocketAddr, VecDeque<(Instant, Vec<u8>)>>>,
+}
+
+impl PacketBuffer {
+    pub(super) fn new(config: FailoverBuffer, metrics: Metrics) -> Self {
+        Self {
+            max_delay: config.max_delay,
+            max_packets: config.max_packets,
+            metrics,
+            len: AtomicUsize::new(0),
+            packets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether no packets are held.
+    pub(super) fn is_empty(&self) -> bool {
+        self.len.load(Ordering::Relaxed) == 0
+    }
+
+    /// Holds `packet` received from `from`. If the maximum number of packets
+    /// is already held for `from`, its oldest packet is dropped.
+    pub(super) fn push(&self, from: SocketAddr, packet: Vec<u8>) {
+        let mut packets = self.packets.lock();
+        let client_packets = packets.entry(from).or_insert_with(VecDeque::new);
+        if client_packets.len() >= self.max_packets {
+            client_packets.pop_front();
+            self.metrics.packets_dropped_buffer_full.inc();
+        } else {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+        client_packets.push_back((Instant::now(), packet));
+        self.metrics.packets_buffered_total.inc();
+    }
+
+    /// Removes and returns the packets held for `from` that have not expired,
+    /// oldest first.
+    pub(super) fn take(&self, from: SocketAddr) -> Vec<Vec<u8>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+
+        let client_packets = {
+            let mut packets = self.packets.lock();
+            match packets.remove(&from) {
+                Some(client_packets) => {
+                    self.len.fetch_sub(client_packets.len(), Ordering::Relaxed);
+                    client_packets
+                }
+                None => return Vec::new(),
+            }
+        };
+        self.unexpired(client_packets, Instant::now())
+    }
+
+    /// Removes and returns all packets held that have not expired, oldest
+    /// first for each client.
+    pub(super) fn take_all(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        // The count is reset under the lock, so that packets pushed while
+        // the buffer is drained are still counted.
+        let packets = {
+            let mut packets = self.packets.lock();
+            self.len.store(0, Ordering::Relaxed);
+            std::mem::take(&mut *packets)
+        };
+
+        let now = Instant::now();
+        packets
+            .into_iter()
+            .flat_map(|(from, client_packets)| {
+                self.unexpired(client_packets, now)
+                    .into_iter()
+                    .map(move |packet| (from, packet))
+            })
+            .collect()
+    }
+
+    /// Drops all packets held for longer than the maximum delay.
+    pub(super) fn expire(&self) {
+        let now = Instant::now();
+        let mut packets = self.packets.lock();
+        let mut expired = 0;
+        packets.retain(|_, client_packets| {
+            while let Some((received_at, _)) = client_packets.front() {
+                if now.duration_since(*received_at) < self.max_delay {
+                    break;
+                }
+                client_packets.pop_front();
+                expired += 1;
+            }
+            !client_packets.is_empty()
+        });
+
+        self.len.fetch_sub(expired, Ordering::Relaxed);
+        self.metrics
+            .packets_dropped_buffer_expired
+            .inc_by(expired as u64);
+    }
+
+    fn unexpired(
+        &self,
+        client_packets: VecDeque<(Instant, Vec<u8>)>,
+        now: Instant,
+    ) -> Vec<Vec<u8>> {
+        let total = client_packets.len();
+        let packets = client_packets
+            .into_iter()
+            .filter(|(received_at, _)| now.duration_since(*received_at) < self.max_delay)
+            .map(|(_, packet)| packet)
+            .collect::<Vec<_>>();
+
+        self.metrics
+            .packets_dropped_buffer_expired
+            .inc_by((total - packets.len()) as u64);
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use prometheus::Registry;
+
+    use super::PacketBuffer;
+    use crate::config::FailoverBuffer;
+    use crate::proxy::server::metrics::Metrics;
+
+    fn packet_buffer(max_delay: Duration, max_packets: usize) -> PacketBuffer {
+        PacketBuffer::new(
+            FailoverBuffer {
+                max_delay,
+                max_packets,
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn push_and_take() {
+        let buffer = packet_buffer(Duration::from_secs(60), 10);
+        let from = "127.0.0.1:8080".parse().unwrap();
+        let other = "127.0.0.1:8081".parse().unwrap();
+
+        assert!(buffer.is_empty());
+        buffer.push(from, b"a".to_vec());
+        buffer.push(from, b"b".to_vec());
+        buffer.push(other, b"c".to_vec());
+        assert!(!buffer.is_empty());
+        assert_eq!(3, buffer.metrics.packets_buffered_total.get());
+
+        assert_eq!(vec![b"a".to_vec(), b"b".to_vec()], buffer.take(from));
+        assert!(buffer.take(from).is_empty());
+        assert!(!buffer.is_empty());
+
+        assert_eq!(vec![(other, b"c".to_vec())], buffer.take_all());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn push_full() {
+        let buffer = packet_buffer(Duration::from_secs(60), 2);
+        let from = "127.0.0.1:8080".parse().unwrap();
+
+        buffer.push(from, b"a".to_vec());
+        buffer.push(from, b"b".to_vec());
+        buffer.push(from, b"c".to_vec());
+
+        assert_eq!(1, buffer.metrics.packets_dropped_buffer_full.get());
+        assert_eq!(vec![b"b".to_vec(), b"c".to_vec()], buffer.take(from));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn expire() {
+        let buffer = packet_buffer(Duration::from_millis(10), 10);
+        let from = "127.0.0.1:8080".parse().unwrap();
+
+        buffer.push(from, b"a".to_vec());
+        std::thread::sleep(Duration::from_millis(20));
+        buffer.push(from, b"b".to_vec());
+
+        buffer.expire();
+        assert_eq!(1, buffer.metrics.packets_dropped_buffer_expired.get());
+        assert!(!buffer.is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(buffer.take(from).is_empty());
+        assert_eq!(2, buffer.metrics.packets_dropped_buffer_expired.get());
+        assert!(buffer.is_empty());
+    }
+}
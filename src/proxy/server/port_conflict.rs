@@ -0,0 +1,526 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Detects another proxy listening on the same port. A proxy replies to
+//! requests sent to its port over loopback, which tells it apart from other
+//! processes using the port, and stops if a proxy starting on the same port
+//! asks to take the port over.
+//!
+//! Requests and replies are signed with HMAC-SHA256 using the secret that
+//! the proxies share, and requests carry the time they were sent at and a
+//! nonce that the proxy remembers while they're recent, so that other
+//! processes on the host can't stop the proxy, nor replay a request to it.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Duration, Instant};
+
+/// Prefixes every request and reply, so that they aren't mistaken for
+/// client traffic.
+const MAGIC: &[u8] = b"\0QUILKIN_PORT_CONFLICT\0";
+
+const KIND_PROBE: u8 = 1;
+const KIND_TAKE_OVER: u8 = 2;
+const KIND_REPLY: u8 = 3;
+
+/// The length of a request following the magic: its kind, nonce, pid and the
+/// time it was sent at.
+const REQUEST_LEN: usize = 1 + 8 + 4 + 8;
+
+/// The size in bytes of the signature ending every request and reply.
+const SIGNATURE_SIZE: usize = 32;
+
+/// How long after it was sent a request is answered, which also allows for
+/// this much clock skew.
+const MAX_REQUEST_AGE_MS: u64 = 2_000;
+
+/// How many recent requests are remembered. Only proxies sharing the secret
+/// can send requests, so this many are never expected within
+/// [`MAX_REQUEST_AGE_MS`].
+const MAX_RECENT_REQUESTS: usize = 64;
+
+/// How long to wait for a reply to a request.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How many times to ask the other proxy to stop. The kernel picks which of
+/// the sockets sharing a port receives a packet from its source address, so
+/// a request may reach the proxy taking over the port instead, and each
+/// attempt is sent from a new port.
+const TAKE_OVER_ATTEMPTS: usize = 8;
+
+/// What a request asks of the proxy listening on the port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Kind {
+    /// Identify itself.
+    Probe,
+    /// Identify itself and stop, as another proxy is taking over the port.
+    TakeOver,
+}
+
+/// A request from a proxy starting on the same port.
+#[derive(Debug, PartialEq)]
+pub(super) struct Request {
+    pub(super) kind: Kind,
+    pub(super) nonce: u64,
+    pub(super) pid: u32,
+    /// When the request was sent, in milliseconds since the UNIX epoch.
+    pub(super) sent_at: u64,
+}
+
+impl Request {
+    fn new(kind: Kind) -> Self {
+        Self {
+            kind,
+            nonce: rand::random(),
+            pid: std::process::id(),
+            sent_at: unix_time_millis(),
+        }
+    }
+
+    /// Parses `packet` as a request signed with `secret`, if it is one.
+    /// Requests are only accepted over loopback, so that a remote client
+    /// can't stop the proxy, and only while they're recent.
+    pub(super) fn parse(from: SocketAddr, packet: &[u8], secret: &[u8]) -> Option<Self> {
+        if !from.ip().is_loopback() {
+            return None;
+        }
+        let body = verify(packet, secret)?;
+        if body.len() != REQUEST_LEN {
+            return None;
+        }
+        let kind = match body[0] {
+            KIND_PROBE => Kind::Probe,
+            KIND_TAKE_OVER => Kind::TakeOver,
+            _ => return None,
+        };
+        let sent_at = u64::from_be_bytes(body[13..21].try_into().ok()?);
+        let now = unix_time_millis();
+        if now.saturating_sub(sent_at) > MAX_REQUEST_AGE_MS
+            || sent_at.saturating_sub(now) > MAX_REQUEST_AGE_MS
+        {
+            return None;
+        }
+        Some(Self {
+            kind,
+            nonce: u64::from_be_bytes(body[1..9].try_into().ok()?),
+            pid: u32::from_be_bytes(body[9..13].try_into().ok()?),
+            sent_at,
+        })
+    }
+
+    /// Returns whether the request was sent by this process, which happens
+    /// when a proxy taking over a port receives its own request.
+    pub(super) fn is_own(&self) -> bool {
+        self.pid == std::process::id()
+    }
+
+    pub(super) fn encode(&self, secret: &[u8]) -> Vec<u8> {
+        let kind = match self.kind {
+            Kind::Probe => KIND_PROBE,
+            Kind::TakeOver => KIND_TAKE_OVER,
+        };
+        let mut packet = MAGIC.to_vec();
+        packet.push(kind);
+        packet.extend_from_slice(&self.nonce.to_be_bytes());
+        packet.extend_from_slice(&self.pid.to_be_bytes());
+        packet.extend_from_slice(&self.sent_at.to_be_bytes());
+        sign(packet, secret)
+    }
+
+    /// Returns the reply to the request from the proxy `id`, including
+    /// whether it is stopping. The id is only included if the proxies share
+    /// a secret, as otherwise any process on the host could ask for it.
+    pub(super) fn reply(&self, id: &str, stopping: bool, secret: &[u8]) -> Vec<u8> {
+        let mut packet = MAGIC.to_vec();
+        packet.push(KIND_REPLY);
+        packet.extend_from_slice(&self.nonce.to_be_bytes());
+        packet.extend_from_slice(&std::process::id().to_be_bytes());
+        packet.push(stopping as u8);
+        if !secret.is_empty() {
+            packet.extend_from_slice(id.as_bytes());
+        }
+        sign(packet, secret)
+    }
+}
+
+/// The requests answered while they're recent enough to be accepted, so that
+/// they can't be replayed in the meantime.
+#[derive(Default)]
+pub(super) struct RecentRequests {
+    /// The nonce and send time of each request, oldest first.
+    requests: VecDeque<(u64, u64)>,
+}
+
+impl RecentRequests {
+    /// Returns whether `request` should be answered, i.e. it wasn't seen
+    /// before, remembering it if so. Requests are also refused while
+    /// [`MAX_RECENT_REQUESTS`] recent requests are remembered.
+    pub(super) fn insert(&mut self, request: &Request) -> bool {
+        let now = unix_time_millis();
+        self.requests
+            .retain(|(_, sent_at)| now.saturating_sub(*sent_at) <= MAX_REQUEST_AGE_MS);
+        if self.requests.len() >= MAX_RECENT_REQUESTS
+            || self
+                .requests
+                .iter()
+                .any(|(nonce, _)| *nonce == request.nonce)
+        {
+            return false;
+        }
+        self.requests.push_back((request.nonce, request.sent_at));
+        true
+    }
+}
+
+/// A proxy that replied to a request.
+#[derive(Debug, PartialEq)]
+pub(super) struct Peer {
+    /// The proxy's id, which is empty unless the proxies share a secret.
+    pub(super) id: String,
+    pub(super) pid: u32,
+    /// Whether the proxy is stopping, as it was asked to by a request to
+    /// take over its port.
+    pub(super) stopping: bool,
+}
+
+impl Peer {
+    /// Parses `packet` as the reply to the request with `nonce`, signed with
+    /// `secret`, if it is.
+    pub(super) fn parse(packet: &[u8], nonce: u64, secret: &[u8]) -> Option<Self> {
+        let body = verify(packet, secret)?;
+        if body.len() < 14 || body[0] != KIND_REPLY {
+            return None;
+        }
+        if u64::from_be_bytes(body[1..9].try_into().ok()?) != nonce {
+            return None;
+        }
+        Some(Self {
+            id: String::from_utf8_lossy(&body[14..]).into_owned(),
+            pid: u32::from_be_bytes(body[9..13].try_into().ok()?),
+            stopping: body[13] != 0,
+        })
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.id.is_empty() {
+            write!(f, "pid: {}", self.pid)
+        } else {
+            write!(f, "id: {}, pid: {}", self.id, self.pid)
+        }
+    }
+}
+
+/// Returns `packet` followed by its signature with `secret`.
+fn sign(mut packet: Vec<u8>, secret: &[u8]) -> Vec<u8> {
+    let signature = mac(&packet, secret).finalize().into_bytes();
+    packet.extend_from_slice(&signature);
+    packet
+}
+
+/// Returns the body of `packet` following the magic, if it starts with the
+/// magic and is signed with `secret`.
+fn verify<'a>(packet: &'a [u8], secret: &[u8]) -> Option<&'a [u8]> {
+    if !packet.starts_with(MAGIC) || packet.len() < MAGIC.len() + SIGNATURE_SIZE {
+        return None;
+    }
+    let (signed, signature) = packet.split_at(packet.len() - SIGNATURE_SIZE);
+    mac(signed, secret).verify(signature).ok()?;
+    Some(&signed[MAGIC.len()..])
+}
+
+fn mac(packet: &[u8], secret: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take a key of any size");
+    mac.update(packet);
+    mac
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch.
+fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Asks the process listening on `port` to identify itself, returning it if
+/// it is a proxy sharing `secret`.
+pub(super) async fn probe(port: u16, secret: &[u8]) -> io::Result<Option<Peer>> {
+    send(port, Request::new(Kind::Probe), secret).await
+}
+
+/// Asks the proxy listening on `port` alongside this one to stop, returning
+/// it once it replies.
+pub(super) async fn take_over(port: u16, secret: &[u8]) -> io::Result<Option<Peer>> {
+    for _ in 0..TAKE_OVER_ATTEMPTS {
+        if let Some(peer) = send(port, Request::new(Kind::TakeOver), secret).await? {
+            return Ok(Some(peer));
+        }
+    }
+    Ok(None)
+}
+
+/// Sends `request` to `port` over loopback, and waits for a reply.
+async fn send(port: u16, request: Request, secret: &[u8]) -> io::Result<Option<Peer>> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    socket.connect((Ipv4Addr::LOCALHOST, port)).await?;
+    socket.send(&request.encode(secret)).await?;
+
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    let mut buf = [0; 512];
+    loop {
+        let size = match timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(Ok(size)) => size,
+            // Nothing is listening on the port.
+            Ok(Err(err)) if err.kind() == io::ErrorKind::ConnectionRefused => return Ok(None),
+            Ok(Err(err)) => return Err(err),
+            // Whatever is listening on the port isn't a proxy.
+            Err(_) => return Ok(None),
+        };
+        if let Some(peer) = Peer::parse(&buf[..size], request.nonce, secret) {
+            return Ok(Some(peer));
+        }
+    }
+}
+
+/// Binds a socket to `addr` with `SO_REUSEPORT` set, so that it can listen
+/// on the same port as another proxy while taking the port over.
+#[cfg(target_os = "linux")]
+pub(super) fn bind_reuse_port(addr: SocketAddrV4) -> io::Result<std::net::UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+
+    // Safe since the descriptor is owned by `socket` as soon as it's created,
+    // so it's closed on error, and `enable` and `sockaddr` outlive the calls
+    // they're passed to.
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = std::net::UdpSocket::from_raw_fd(fd);
+
+        let enable: libc::c_int = 1;
+        if libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let sockaddr = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: addr.port().to_be(),
+            sin_addr: libc::in_addr {
+                s_addr: u32::from(*addr.ip()).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+        if libc::bind(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn bind_reuse_port(_: SocketAddrV4) -> io::Result<std::net::UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "taking over the port is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+
+    use super::{
+        probe, take_over, unix_time_millis, Kind, Peer, RecentRequests, Request,
+        MAX_RECENT_REQUESTS, MAX_REQUEST_AGE_MS,
+    };
+
+    const SECRET: &[u8] = b"secret";
+
+    /// Replies to requests on a new socket as the proxy `id` would, honouring
+    /// requests to take over its port.
+    async fn spawn_peer(id: &'static str) -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (size, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Request::parse(from, &buf[..size], SECRET).unwrap();
+                let stopping = request.kind == Kind::TakeOver;
+                socket
+                    .send_to(&request.reply(id, stopping, SECRET), from)
+                    .await
+                    .unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn parse_request() {
+        let loopback: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let remote: SocketAddr = "10.0.0.1:7000".parse().unwrap();
+        let request = Request {
+            kind: Kind::TakeOver,
+            nonce: 42,
+            pid: 1234,
+            sent_at: unix_time_millis(),
+        };
+        let packet = request.encode(SECRET);
+
+        assert_eq!(
+            Some(&request),
+            Request::parse(loopback, &packet, SECRET).as_ref()
+        );
+        assert!(!request.is_own());
+        assert_eq!(None, Request::parse(remote, &packet, SECRET));
+        assert_eq!(None, Request::parse(loopback, b"hello", SECRET));
+        assert_eq!(
+            None,
+            Request::parse(loopback, &packet[..packet.len() - 1], SECRET)
+        );
+        // Requests signed with another secret are ignored.
+        assert_eq!(None, Request::parse(loopback, &packet, b"other"));
+
+        // As are old requests, so that they can't be replayed.
+        let old = Request {
+            sent_at: request.sent_at - 60_000,
+            ..request
+        };
+        assert_eq!(None, Request::parse(loopback, &old.encode(SECRET), SECRET));
+    }
+
+    #[test]
+    fn recent_requests() {
+        let mut recent = RecentRequests::default();
+        let request = Request::new(Kind::TakeOver);
+
+        // A request is only answered once.
+        assert!(recent.insert(&request));
+        assert!(!recent.insert(&request));
+
+        // Requests are forgotten once they're too old to be accepted anyway.
+        recent.requests[0].1 -= MAX_REQUEST_AGE_MS + 1;
+        assert!(recent.insert(&request));
+
+        for _ in 1..MAX_RECENT_REQUESTS {
+            assert!(recent.insert(&Request::new(Kind::Probe)));
+        }
+        assert!(!recent.insert(&Request::new(Kind::Probe)));
+    }
+
+    #[test]
+    fn parse_reply() {
+        let request = Request::new(Kind::Probe);
+        assert!(request.is_own());
+        let reply = request.reply("proxy-1", false, SECRET);
+
+        assert_eq!(
+            Some(Peer {
+                id: "proxy-1".into(),
+                pid: std::process::id(),
+                stopping: false,
+            }),
+            Peer::parse(&reply, request.nonce, SECRET)
+        );
+        assert_eq!(
+            None,
+            Peer::parse(&reply, request.nonce.wrapping_add(1), SECRET)
+        );
+        assert_eq!(None, Peer::parse(&reply, request.nonce, b"other"));
+        assert_eq!(
+            None,
+            Peer::parse(&request.encode(SECRET), request.nonce, SECRET)
+        );
+
+        // Without a secret, the proxy's id isn't shared.
+        let reply = request.reply("proxy-1", false, b"");
+        let peer = Peer::parse(&reply, request.nonce, b"").unwrap();
+        assert_eq!("", peer.id);
+        assert_eq!(format!("pid: {}", std::process::id()), peer.to_string());
+    }
+
+    #[tokio::test]
+    async fn probe_peer() {
+        let port = spawn_peer("proxy-1").await;
+        let peer = probe(port, SECRET).await.unwrap().unwrap();
+        assert_eq!("proxy-1", peer.id);
+        assert!(!peer.stopping);
+
+        let peer = take_over(port, SECRET).await.unwrap().unwrap();
+        assert_eq!("proxy-1", peer.id);
+        assert!(peer.stopping);
+    }
+
+    #[tokio::test]
+    async fn probe_other_process() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        assert_eq!(None, probe(port, SECRET).await.unwrap());
+        drop(socket);
+    }
+
+    #[tokio::test]
+    async fn probe_unused_port() {
+        let port = {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.local_addr().unwrap().port()
+        };
+        assert_eq!(None, probe(port, SECRET).await.unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bind_reuse_port() {
+        let socket = super::bind_reuse_port("0.0.0.0:0".parse().unwrap()).unwrap();
+        let addr = match socket.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            addr => panic!("unexpected address {}", addr),
+        };
+        let other = super::bind_reuse_port(addr).unwrap();
+        assert_eq!(addr.port(), other.local_addr().unwrap().port());
+
+        // A socket bound without SO_REUSEPORT can't share the port.
+        assert!(std::net::UdpSocket::bind(addr).is_err());
+    }
+}
@@ -0,0 +1,541 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt::{self, Display, Formatter};
+#[cfg(target_os = "linux")]
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+use slog::{error, info, warn, Logger};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::config::{ManagementServer, PortConflictPolicy, Proxy};
+use crate::proxy::builder::{ValidatedConfig, ValidatedSource};
+
+use super::port_conflict::{self, Peer};
+
+/// How long to wait for a connection to a management server.
+const MANAGEMENT_SERVER_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The number of concurrent sessions that the limit on open file descriptors
+/// is checked against when the proxy's resource limits don't bound the
+/// number of files it opens. Each session holds a socket.
+#[cfg(unix)]
+const DEFAULT_EXPECTED_SESSIONS: libc::rlim_t = 4096;
+
+/// The segment size that support for UDP generic segmentation offload is
+/// probed with.
+#[cfg(target_os = "linux")]
+const GSO_PROBE_SEGMENT_SIZE: libc::c_int = 1200;
+
+/// The outcome of a single preflight check.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Status {
+    /// The check succeeded.
+    Pass,
+    /// The check found a problem that the proxy can run with.
+    Warn,
+    /// The check found a problem that prevents the proxy from running.
+    Fail,
+}
+
+/// The result of a single preflight check.
+#[derive(Debug)]
+pub(super) struct Check {
+    pub(super) name: &'static str,
+    pub(super) status: Status,
+    pub(super) detail: String,
+}
+
+impl Check {
+    pub(super) fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// The results of all preflight checks run before the proxy starts.
+#[derive(Debug)]
+pub(super) struct Report {
+    pub(super) checks: Vec<Check>,
+}
+
+impl Report {
+    /// Returns the checks that prevent the proxy from running.
+    pub(super) fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.checks
+            .iter()
+            .filter(|check| check.status == Status::Fail)
+    }
+
+    /// Logs the result of each check.
+    pub(super) fn log(&self, log: &Logger) {
+        for check in &self.checks {
+            match check.status {
+                Status::Pass => info!(
+                    log,
+                    "Preflight check passed";
+                    "check" => check.name,
+                    "detail" => &check.detail
+                ),
+                Status::Warn => warn!(
+                    log,
+                    "Preflight check raised a warning";
+                    "check" => check.name,
+                    "detail" => &check.detail
+                ),
+                Status::Fail => error!(
+                    log,
+                    "Preflight check failed";
+                    "check" => check.name,
+                    "detail" => &check.detail
+                ),
+            }
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let failures = self
+            .failures()
+            .map(|check| format!("{}: {}", check.name, check.detail))
+            .collect::<Vec<_>>();
+        write!(f, "{}", failures.join(", "))
+    }
+}
+
+/// Runs all preflight checks against the provided config. The listening port
+/// is only checked if `check_listen_port` is set, as it is already bound when
+/// the socket is passed in by systemd. `peer` is the proxy found listening on
+/// the port, if any.
+pub(super) async fn run(
+    config: &ValidatedConfig,
+    check_listen_port: bool,
+    peer: Option<&Peer>,
+) -> Report {
+    let mut checks = vec![];
+    if check_listen_port {
+        let policy = config.proxy.port_conflict_policy;
+        checks.push(check_port_conflict(config.proxy.port, policy, peer));
+        checks.push(check_port(
+            config.proxy.port,
+            policy == PortConflictPolicy::TakeOver,
+        ));
+    }
+
+    checks.extend(check_environment(&config.proxy));
+
+    if let ValidatedSource::Dynamic {
+        management_servers, ..
+    } = &config.source
+    {
+        checks.push(check_management_servers(management_servers).await);
+    }
+
+    Report { checks }
+}
+
+/// Runs the checks of the operating system's limits and support for the
+/// socket options that the proxy uses, which are shared with `quilkin doctor`.
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(super) fn check_environment(proxy: &Proxy) -> Vec<Check> {
+    let mut checks = vec![];
+    #[cfg(unix)]
+    checks.push(check_nofile(proxy));
+    #[cfg(target_os = "linux")]
+    checks.push(check_reuseport(proxy.port_conflict_policy));
+    #[cfg(target_os = "linux")]
+    checks.push(check_gso());
+    checks
+}
+
+/// Checks that no other proxy is listening on the proxy's port, unless the
+/// proxy is set to take the port over.
+pub(super) fn check_port_conflict(
+    port: u16,
+    policy: PortConflictPolicy,
+    peer: Option<&Peer>,
+) -> Check {
+    let name = "port_conflict";
+    let peer = match peer {
+        Some(peer) => peer,
+        None => {
+            return Check::new(
+                name,
+                Status::Pass,
+                format!("no other proxy is listening on port {}", port),
+            )
+        }
+    };
+
+    match policy {
+        PortConflictPolicy::Fail => Check::new(
+            name,
+            Status::Fail,
+            format!(
+                "another proxy ({}) is listening on port {}; stop it, or set \
+                 proxy.port_conflict_policy to TAKE_OVER on both proxies to take the port over",
+                peer, port
+            ),
+        ),
+        PortConflictPolicy::TakeOver => Check::new(
+            name,
+            Status::Pass,
+            format!(
+                "another proxy ({}) is listening on port {} and will be taken over",
+                peer, port
+            ),
+        ),
+    }
+}
+
+/// Checks that the proxy's listening port can be bound to, alongside another
+/// proxy if `reuse_port` is set.
+pub(super) fn check_port(port: u16, reuse_port: bool) -> Check {
+    let name = "listen_port";
+    let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
+    let bound = if reuse_port {
+        port_conflict::bind_reuse_port(addr)
+    } else {
+        UdpSocket::bind(addr)
+    };
+    match bound {
+        Ok(_) => Check::new(name, Status::Pass, format!("port {} is available", port)),
+        Err(err) => Check::new(
+            name,
+            Status::Fail,
+            format!(
+                "port {} cannot be bound to ({}); check that no other process is using it",
+                port, err
+            ),
+        ),
+    }
+}
+
+/// Checks that the limit on open file descriptors allows for as many open
+/// files as the proxy is configured to use.
+#[cfg(unix)]
+pub(super) fn check_nofile(proxy: &Proxy) -> Check {
+    let name = "nofile_limit";
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    // Safe since `limit` is a valid rlimit struct that outlives the call.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Check::new(
+            name,
+            Status::Warn,
+            format!(
+                "failed to read the open file limit: {}",
+                std::io::Error::last_os_error()
+            ),
+        );
+    }
+
+    let soft_limit = limit.rlim_cur;
+    let (required, reason) = required_nofile(proxy);
+    if soft_limit < required {
+        Check::new(
+            name,
+            Status::Warn,
+            format!(
+                "the open file limit is {}, but {} needs {} open files; \
+                 consider raising it (e.g `ulimit -n {}`)",
+                soft_limit, reason, required, required
+            ),
+        )
+    } else {
+        Check::new(
+            name,
+            Status::Pass,
+            format!("the open file limit is {}", soft_limit),
+        )
+    }
+}
+
+/// Returns the number of open files that the proxy needs with its config,
+/// along with what the number is derived from. Sessions are shed past
+/// `max_open_fds`, so the limit has to allow for that many files for the
+/// resource limits to take effect. Otherwise the proxy needs a file for each
+/// of the sessions it expects, and for each SOCKS5 connection.
+#[cfg(unix)]
+fn required_nofile(proxy: &Proxy) -> (libc::rlim_t, String) {
+    let max_open_fds = proxy.resource_limits.and_then(|limits| limits.max_open_fds);
+    if let Some(max_open_fds) = max_open_fds {
+        return (
+            max_open_fds as libc::rlim_t,
+            "proxy.resource_limits.max_open_fds".into(),
+        );
+    }
+
+    match &proxy.socks5 {
+        Some(socks5) => (
+            DEFAULT_EXPECTED_SESSIONS + socks5.max_associations as libc::rlim_t,
+            format!(
+                "{} sessions with proxy.socks5.max_associations connections",
+                DEFAULT_EXPECTED_SESSIONS
+            ),
+        ),
+        None => (
+            DEFAULT_EXPECTED_SESSIONS,
+            format!("{} sessions", DEFAULT_EXPECTED_SESSIONS),
+        ),
+    }
+}
+
+/// Checks that the kernel supports `SO_REUSEPORT`, which socket activation
+/// needs to pass the same port to several proxies, and which the proxy needs
+/// to take over the port of another proxy.
+#[cfg(target_os = "linux")]
+pub(super) fn check_reuseport(policy: PortConflictPolicy) -> Check {
+    let name = "reuseport";
+    match probe_socket_option(libc::SOL_SOCKET, libc::SO_REUSEPORT, 1) {
+        Ok(()) => Check::new(name, Status::Pass, "SO_REUSEPORT is supported"),
+        Err(err) if policy == PortConflictPolicy::TakeOver => Check::new(
+            name,
+            Status::Fail,
+            format!(
+                "SO_REUSEPORT is not supported ({}), which proxy.port_conflict_policy \
+                 TAKE_OVER needs to share the port with another proxy",
+                err
+            ),
+        ),
+        Err(err) => Check::new(
+            name,
+            Status::Warn,
+            format!(
+                "SO_REUSEPORT is not supported ({}), so several proxies can't share a port",
+                err
+            ),
+        ),
+    }
+}
+
+/// Checks that the kernel supports UDP generic segmentation offload
+/// (`UDP_SEGMENT`), which lets a batch of packets to the same address be
+/// handed to the kernel with a single system call.
+#[cfg(target_os = "linux")]
+pub(super) fn check_gso() -> Check {
+    let name = "gso";
+    match probe_socket_option(libc::SOL_UDP, libc::UDP_SEGMENT, GSO_PROBE_SEGMENT_SIZE) {
+        Ok(()) => Check::new(
+            name,
+            Status::Pass,
+            "UDP generic segmentation offload is supported",
+        ),
+        Err(err) => Check::new(
+            name,
+            Status::Warn,
+            format!(
+                "UDP generic segmentation offload is not supported ({}), \
+                 it needs Linux 4.18 or later",
+                err
+            ),
+        ),
+    }
+}
+
+/// Sets a socket option on a new UDP socket, to find out whether the kernel
+/// supports it.
+#[cfg(target_os = "linux")]
+fn probe_socket_option(
+    level: libc::c_int,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // Safe since the socket is only used within this function, and `value`
+    // outlives the call to setsockopt.
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = libc::setsockopt(
+            fd,
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+        let result = if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        };
+        libc::close(fd);
+        result
+    }
+}
+
+/// Checks that at least one management server accepts connections.
+async fn check_management_servers(management_servers: &[ManagementServer]) -> Check {
+    let name = "management_servers";
+    let mut unreachable = vec![];
+    for server in management_servers {
+        match connect(&server.address).await {
+            Ok(()) => {
+                return Check::new(
+                    name,
+                    Status::Pass,
+                    format!("management server {} is reachable", server.address),
+                )
+            }
+            Err(err) => unreachable.push(format!("{} ({})", server.address, err)),
+        }
+    }
+
+    Check::new(
+        name,
+        Status::Warn,
+        format!(
+            "no management server is reachable, the proxy will keep retrying: {}",
+            unreachable.join(", ")
+        ),
+    )
+}
+
+/// Opens a TCP connection to the host and port of `address`.
+pub(super) async fn connect(address: &str) -> Result<(), String> {
+    let uri = address
+        .parse::<hyper::Uri>()
+        .map_err(|err| err.to_string())?;
+    let host = uri.host().ok_or_else(|| "missing host".to_string())?;
+    let port = uri.port_u16().unwrap_or_else(|| match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+
+    timeout(
+        MANAGEMENT_SERVER_CONNECT_TIMEOUT,
+        TcpStream::connect((host, port)),
+    )
+    .await
+    .map_err(|_| "timed out".to_string())?
+    .map(|_| ())
+    .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use tokio::net::TcpListener;
+
+    use super::{check_management_servers, check_port, check_port_conflict, Peer, Status};
+    use crate::config::{ManagementServer, PortConflictPolicy};
+
+    #[test]
+    fn check_port_available() {
+        let port = {
+            let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+            socket.local_addr().unwrap().port()
+        };
+        assert_eq!(Status::Pass, check_port(port, false).status);
+    }
+
+    #[test]
+    fn check_port_in_use() {
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        assert_eq!(Status::Fail, check_port(port, false).status);
+        // A port can only be shared if every socket bound to it allows it.
+        assert_eq!(Status::Fail, check_port(port, true).status);
+    }
+
+    #[test]
+    fn check_port_conflicts() {
+        let peer = Peer {
+            id: "proxy-1".into(),
+            pid: 1234,
+            stopping: false,
+        };
+        assert_eq!(
+            Status::Pass,
+            check_port_conflict(7000, PortConflictPolicy::Fail, None).status
+        );
+
+        let check = check_port_conflict(7000, PortConflictPolicy::Fail, Some(&peer));
+        assert_eq!(Status::Fail, check.status);
+        assert!(check.detail.contains("proxy-1"), "{}", check.detail);
+
+        assert_eq!(
+            Status::Pass,
+            check_port_conflict(7000, PortConflictPolicy::TakeOver, Some(&peer)).status
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn required_nofile() {
+        use crate::config::{Proxy, ResourceLimits, Socks5};
+
+        let mut proxy = Proxy::default();
+        assert_eq!(4096, super::required_nofile(&proxy).0);
+
+        proxy.socks5 = Some(Socks5 {
+            port: 1080,
+            max_associations: 64,
+        });
+        assert_eq!(4160, super::required_nofile(&proxy).0);
+
+        // The resource limits bound the files the proxy opens.
+        proxy.resource_limits = Some(ResourceLimits {
+            max_memory_bytes: None,
+            max_open_fds: Some(100_000),
+            check_interval: std::time::Duration::from_secs(5),
+        });
+        let (required, reason) = super::required_nofile(&proxy);
+        assert_eq!(100_000, required);
+        assert_eq!("proxy.resource_limits.max_open_fds", reason);
+    }
+
+    #[tokio::test]
+    async fn check_management_servers_reachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let check = check_management_servers(&[
+            ManagementServer {
+                address: "http://127.0.0.1:1".into(),
+                token: None,
+            },
+            ManagementServer {
+                address: format!("http://127.0.0.1:{}", port),
+                token: None,
+            },
+        ])
+        .await;
+        assert_eq!(Status::Pass, check.status);
+    }
+
+    #[tokio::test]
+    async fn check_management_servers_unreachable() {
+        let check = check_management_servers(&[ManagementServer {
+            address: "http://127.0.0.1:1".into(),
+            token: None,
+        }])
+        .await;
+        assert_eq!(Status::Warn, check.status);
+    }
+}
@@ -0,0 +1,108 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::VecDeque;
+
+use crate::filters::Priority;
+use crate::proxy::sessions::Packet;
+
+/// The most packets that [`PriorityQueues`] are filled up to. Once reached,
+/// packets are left in the channel they come from until some have been sent,
+/// so that the channel keeps applying backpressure.
+const MAX_QUEUED_PACKETS: usize = 1024;
+
+/// Holds the packets waiting to be sent to clients in a queue per
+/// [`Priority`]. Packets are taken from the highest priority queue that
+/// isn't empty, in the order they were added to it.
+#[derive(Default)]
+pub(super) struct PriorityQueues {
+    queues: [VecDeque<Packet>; 3],
+    len: usize,
+}
+
+impl PriorityQueues {
+    pub(super) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(super) fn is_full(&self) -> bool {
+        self.len >= MAX_QUEUED_PACKETS
+    }
+
+    pub(super) fn push(&mut self, packet: Packet) {
+        self.len += 1;
+        self.queues[index(packet.priority())].push_back(packet);
+    }
+
+    /// Removes and returns the packet to send next, if any.
+    pub(super) fn pop(&mut self) -> Option<Packet> {
+        let packet = self.queues.iter_mut().find_map(VecDeque::pop_front)?;
+        self.len -= 1;
+        Some(packet)
+    }
+}
+
+/// Returns the index of the queue for `priority`, highest priority first.
+fn index(priority: Priority) -> usize {
+    match priority {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filters::Priority;
+    use crate::proxy::sessions::Packet;
+
+    use super::{PriorityQueues, MAX_QUEUED_PACKETS};
+
+    #[test]
+    fn priority_order() {
+        let dest = "127.0.0.1:7000".parse().unwrap();
+        let mut queues = PriorityQueues::default();
+        assert!(queues.is_empty());
+        for (contents, priority) in vec![
+            ("low", Priority::Low),
+            ("normal 1", Priority::Normal),
+            ("high", Priority::High),
+            ("normal 2", Priority::Normal),
+        ] {
+            queues.push(Packet::new(dest, contents.into()).with_priority(priority));
+        }
+
+        let mut sent = vec![];
+        while let Some(packet) = queues.pop() {
+            sent.push(String::from_utf8(packet.contents().clone()).unwrap());
+        }
+        assert_eq!(vec!["high", "normal 1", "normal 2", "low"], sent);
+        assert!(queues.is_empty());
+    }
+
+    #[test]
+    fn full() {
+        let dest = "127.0.0.1:7000".parse().unwrap();
+        let mut queues = PriorityQueues::default();
+        for _ in 0..MAX_QUEUED_PACKETS {
+            assert!(!queues.is_full());
+            queues.push(Packet::new(dest, vec![]));
+        }
+        assert!(queues.is_full());
+        queues.pop();
+        assert!(!queues.is_full());
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Receives packets along with the time at which the kernel received them,
+//! rather than the time at which the receiving task got scheduled.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use tokio::net::UdpSocket;
+
+/// Enables kernel receive timestamps (`SO_TIMESTAMPNS`) on `socket`.
+#[cfg(target_os = "linux")]
+pub(super) fn enable(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let enabled: libc::c_int = 1;
+    // Safe since `enabled` is a valid c_int that outlives the call, and its
+    // size is passed along with it.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn enable(_: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "kernel receive timestamps are only supported on Linux",
+    ))
+}
+
+/// Receives a packet into `buf`, returning its size, its sender and the time
+/// it was received. The kernel's receive timestamp is used if timestamps
+/// were enabled with [`enable`], otherwise the time the packet was read.
+#[cfg(target_os = "linux")]
+pub(super) async fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SystemTime)> {
+    use std::os::unix::io::AsRawFd;
+
+    use tokio::io::Interest;
+
+    loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || recv_msg(socket.as_raw_fd(), buf)) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) async fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SystemTime)> {
+    let (size, addr) = socket.recv_from(buf).await?;
+    Ok((size, addr, SystemTime::now()))
+}
+
+/// Calls `recvmsg` on `fd`, reading the receive timestamp from the control
+/// messages if there is one.
+#[cfg(target_os = "linux")]
+fn recv_msg(
+    fd: std::os::unix::io::RawFd,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SystemTime)> {
+    use std::mem;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // Safe since all of these are plain C structs, for which all zeroes is
+    // a valid value.
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    // Use u64s so that the buffer is aligned for `cmsghdr`.
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    // Safe since `msg` only points to buffers that outlive the call, along
+    // with their sizes.
+    let size = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut received_at = None;
+    // Safe since the kernel filled in `msg`'s control messages, which are
+    // only read within the bounds that the CMSG macros check.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET
+                && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS
+            {
+                let timestamp =
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                received_at = Some(
+                    UNIX_EPOCH + Duration::new(timestamp.tv_sec as u64, timestamp.tv_nsec as u32),
+                );
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((
+        size as usize,
+        to_socket_addr(&addr)?,
+        received_at.unwrap_or_else(SystemTime::now),
+    ))
+}
+
+/// Converts an address filled in by `recvmsg` to a [`SocketAddr`].
+#[cfg(target_os = "linux")]
+fn to_socket_addr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // Safe since the address family says that `addr` holds a
+            // sockaddr_in, which sockaddr_storage is large enough for.
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // Safe since the address family says that `addr` holds a
+            // sockaddr_in6, which sockaddr_storage is large enough for.
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("received a packet from unsupported address family {}", family),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::{Duration, SystemTime};
+
+    use tokio::net::UdpSocket;
+
+    use super::{enable, recv_from};
+
+    #[tokio::test]
+    async fn recv_with_timestamp() {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        if cfg!(target_os = "linux") {
+            enable(&socket).unwrap();
+        }
+        let sender = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+
+        let sent_at = SystemTime::now();
+        sender
+            .send_to(b"hello", socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        // Delay reading the packet, which shouldn't affect when it was
+        // received according to the kernel.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut buf = vec![0; 1024];
+        let (size, from, received_at) = recv_from(&socket, &mut buf).await.unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+        assert_eq!(sender.local_addr().unwrap(), from);
+        if cfg!(target_os = "linux") {
+            let delay = received_at.duration_since(sent_at).unwrap_or_default();
+            assert!(delay < Duration::from_millis(100), "delay: {:?}", delay);
+        }
+    }
+}
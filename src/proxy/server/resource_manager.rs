@@ -14,20 +14,30 @@
  * limitations under the License.
  */
 
+use crate::audit_log::AuditLog;
 use crate::cluster::cluster_manager::{ClusterManager, InitializeError, SharedClusterManager};
-use crate::config::{Endpoints, ManagementServer};
+use crate::cluster::mdns::MdnsBrowser;
+use crate::config::{
+    EndpointUpdateGuard, Endpoints, Failover, FilterUpdateFailurePolicy, ManagementServer, Mdns,
+};
+use crate::faults::FaultInjector;
 use crate::filters::{
     manager::{FilterManager, ListenerManagerArgs, SharedFilterManager},
     FilterChain, FilterRegistry,
 };
+use crate::proxy::builder::ValidatedStartup;
+use crate::secret::SecretProviders;
+use crate::supervisor::Supervisor;
 use crate::xds::ads_client::{
     AdsClient, ClusterUpdate, ExecutionResult, UPDATES_CHANNEL_BUFFER_SIZE,
 };
+use crate::xds::telemetry::TelemetryReporter;
 use prometheus::Registry;
 use slog::{debug, o, warn, Logger};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{self, Instant};
 
 /// Contains resource managers for fixed cluster/filter etc resources.
 pub(super) struct StaticResourceManagers {
@@ -54,6 +64,38 @@ impl StaticResourceManagers {
             filter_manager: FilterManager::fixed(filter_chain),
         })
     }
+
+    /// Returns resource managers like [`StaticResourceManagers::new`], but
+    /// whose endpoints also include those discovered with mDNS.
+    pub(super) fn with_mdns(
+        base_logger: Logger,
+        metrics_registry: &Registry,
+        endpoints: Option<Endpoints>,
+        filter_chain: Arc<FilterChain>,
+        mdns: &Mdns,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> Result<StaticResourceManagers, InitializeError> {
+        let browser = MdnsBrowser::new(&base_logger, metrics_registry, mdns, endpoints)
+            .map_err(|err| InitializeError::Message(format!("{:?}", err)))?;
+        let (cluster_updates_tx, cluster_updates_rx) =
+            DynamicResourceManagers::cluster_updates_channel();
+        let cluster_manager = ClusterManager::dynamic(
+            base_logger,
+            metrics_registry,
+            browser.cluster_update(),
+            None,
+            None,
+            cluster_updates_rx,
+            shutdown_rx.clone(),
+        )
+        .map_err(|err| InitializeError::Message(format!("{:?}", err)))?;
+        tokio::spawn(browser.run(cluster_updates_tx, shutdown_rx));
+
+        Ok(Self {
+            cluster_manager,
+            filter_manager: FilterManager::fixed(filter_chain),
+        })
+    }
 }
 
 /// Contains arguments to the `spawn_ads_client` function.
@@ -65,16 +107,30 @@ struct SpawnAdsClient {
     cluster_updates_tx: mpsc::Sender<ClusterUpdate>,
     listener_manager_args: ListenerManagerArgs,
     execution_result_tx: oneshot::Sender<ExecutionResult>,
+    secret_providers: SecretProviders,
+    audit_log: Option<AuditLog>,
+    faults: Option<Arc<FaultInjector>>,
+    telemetry: Option<TelemetryReporter>,
+    supervisor: Supervisor,
     shutdown_rx: watch::Receiver<()>,
 }
 
 impl DynamicResourceManagers {
+    #[allow(clippy::too_many_arguments)]
     pub(super) async fn new(
         base_logger: Logger,
         xds_node_id: String,
         metrics_registry: Registry,
         filter_registry: FilterRegistry,
         management_servers: Vec<ManagementServer>,
+        startup: &ValidatedStartup,
+        failover: Option<Failover>,
+        endpoint_update_guard: Option<EndpointUpdateGuard>,
+        filter_update_failure: FilterUpdateFailurePolicy,
+        audit_log: Option<AuditLog>,
+        faults: Option<Arc<FaultInjector>>,
+        telemetry: Option<TelemetryReporter>,
+        supervisor: Supervisor,
         mut shutdown_rx: watch::Receiver<()>,
     ) -> Result<DynamicResourceManagers, InitializeError> {
         let log = base_logger.new(o!("source" => "server::DynamicResourceManager"));
@@ -83,13 +139,16 @@ impl DynamicResourceManagers {
         let (filter_chain_updates_tx, mut filter_chain_updates_rx) =
             Self::filter_chain_updates_channel();
 
+        let secret_providers = filter_registry.secret_providers().clone();
         let listener_manager_args = ListenerManagerArgs::new(
             metrics_registry.clone(),
             filter_registry,
             filter_chain_updates_tx,
-        );
+        )
+        .with_filter_update_failure(filter_update_failure);
 
-        let (execution_result_tx, execution_result_rx) = oneshot::channel::<ExecutionResult>();
+        let (execution_result_tx, mut execution_result_rx) =
+            oneshot::channel::<ExecutionResult>();
         Self::spawn_ads_client(SpawnAdsClient {
             log: log.clone(),
             metrics_registry: metrics_registry.clone(),
@@ -98,38 +157,89 @@ impl DynamicResourceManagers {
             cluster_updates_tx,
             listener_manager_args,
             execution_result_tx,
+            secret_providers,
+            audit_log,
+            faults,
+            telemetry,
+            supervisor,
             shutdown_rx: shutdown_rx.clone(),
         })?;
 
         // Initial cluster warming - wait to receive the initial LDS and CDS resources
-        // from the XDS server before we start receiving any traffic.
+        // from the XDS server before we start receiving any traffic. Unless the startup
+        // policy is to wait indefinitely, we only wait until the startup timeout.
+        let deadline = match startup {
+            ValidatedStartup::Wait => None,
+            ValidatedStartup::Exit { timeout } | ValidatedStartup::Fallback { timeout, .. } => {
+                Some(Instant::now() + *timeout)
+            }
+        };
+
         debug!(log, "Waiting to receive initial cluster update.");
-        let (cluster_update, execution_result_rx) = Self::receive_update(
+        let cluster_update = Self::receive_initial_update(
+            deadline,
             &mut cluster_updates_rx,
-            execution_result_rx,
+            &mut execution_result_rx,
             &mut shutdown_rx,
         )
         .await?;
-        debug!(log, "Received initial cluster update.");
 
         debug!(log, "Waiting to receive initial filter chain update.");
-        let (filter_chain_update, execution_result_rx) = Self::receive_update(
+        let filter_chain_update = Self::receive_initial_update(
+            deadline,
             &mut filter_chain_updates_rx,
-            execution_result_rx,
+            &mut execution_result_rx,
             &mut shutdown_rx,
         )
         .await?;
-        debug!(log, "Received initial filter chain update.");
 
-        let cluster_manager = ClusterManager::dynamic(
-            base_logger.new(o!("source" => "ClusterManager")),
-            &metrics_registry,
-            cluster_update,
-            cluster_updates_rx,
-            shutdown_rx.clone(),
-        )
+        let cluster_manager = match cluster_update {
+            Some(cluster_update) => {
+                debug!(log, "Received initial cluster update.");
+                ClusterManager::dynamic(
+                    base_logger.new(o!("source" => "ClusterManager")),
+                    &metrics_registry,
+                    cluster_update,
+                    failover,
+                    endpoint_update_guard,
+                    cluster_updates_rx,
+                    shutdown_rx.clone(),
+                )
+            }
+            None => {
+                let (_, endpoints) = Self::fallback(startup)?;
+                warn!(
+                    log,
+                    "Initial cluster update was not received in time, using fallback endpoints until it is."
+                );
+                ClusterManager::dynamic_with_fallback(
+                    base_logger.new(o!("source" => "ClusterManager")),
+                    &metrics_registry,
+                    endpoints.clone(),
+                    failover,
+                    endpoint_update_guard,
+                    cluster_updates_rx,
+                    shutdown_rx.clone(),
+                )
+            }
+        }
         .map_err(|err| InitializeError::Message(format!("{:?}", err)))?;
 
+        let filter_chain_update = match filter_chain_update {
+            Some(filter_chain_update) => {
+                debug!(log, "Received initial filter chain update.");
+                filter_chain_update
+            }
+            None => {
+                let (filter_chain, _) = Self::fallback(startup)?;
+                warn!(
+                    log,
+                    "Initial filter chain update was not received in time, using fallback filters until it is."
+                );
+                filter_chain.clone()
+            }
+        };
+
         let filter_manager = FilterManager::dynamic(
             base_logger.new(o!("source" => "FilterManager")),
             filter_chain_update,
@@ -144,6 +254,28 @@ impl DynamicResourceManagers {
         })
     }
 
+    // Returns the fallback configuration to use in place of an initial update that
+    // was not received before the startup timeout, or an error if the startup
+    // policy does not allow for one.
+    fn fallback(
+        startup: &ValidatedStartup,
+    ) -> Result<(&Arc<FilterChain>, &Endpoints), InitializeError> {
+        match startup {
+            ValidatedStartup::Fallback {
+                filter_chain,
+                endpoints,
+                ..
+            } => Ok((filter_chain, endpoints)),
+            ValidatedStartup::Exit { timeout } => Err(InitializeError::Message(format!(
+                "failed to receive initial update within the startup timeout of {:?}",
+                timeout
+            ))),
+            ValidatedStartup::Wait => Err(InitializeError::Message(
+                "failed to receive initial update".into(),
+            )),
+        }
+    }
+
     // Spawns a task that runs an ADS client.
     // Cluster and Filter updates from the client
     // as well as execution result after termination are sent on the passed-in channels.
@@ -156,51 +288,87 @@ impl DynamicResourceManagers {
             cluster_updates_tx,
             listener_manager_args,
             execution_result_tx,
+            secret_providers,
+            audit_log,
+            faults,
+            telemetry,
+            supervisor,
             shutdown_rx,
         } = args;
 
-        let client = AdsClient::new(log.clone(), &metrics_registry).map_err(|err| {
+        let client = AdsClient::new(
+            log.clone(),
+            &metrics_registry,
+            secret_providers,
+            audit_log,
+            faults,
+            telemetry,
+        )
+        .map_err(|err| {
             InitializeError::Message(format!("failed to initialize xDS client: {:?}", err))
         })?;
+        // The client is restarted from scratch if it panics.
+        let client = supervisor.spawn("xds_client", move || {
+            client.clone().run(
+                node_id.clone(),
+                management_servers.clone(),
+                cluster_updates_tx.clone(),
+                listener_manager_args.clone(),
+                shutdown_rx.clone(),
+            )
+        });
         tokio::spawn(async move {
-            let result = client
-                .run(
-                    node_id,
-                    management_servers,
-                    cluster_updates_tx,
-                    listener_manager_args,
-                    shutdown_rx,
-                )
-                .await;
-            execution_result_tx
-                .send(result)
-                .map_err(|_err| warn!(log, "Failed to send ADS client execution result on channel"))
-                .ok();
+            if let Ok(Some(result)) = client.await {
+                execution_result_tx
+                    .send(result)
+                    .map_err(|_err| {
+                        warn!(log, "Failed to send ADS client execution result on channel")
+                    })
+                    .ok();
+            }
         });
 
         Ok(())
     }
 
+    // Waits until it receives an update from the given channel or `deadline`
+    // passes, returning `None` in the latter case.
+    async fn receive_initial_update<T>(
+        deadline: Option<Instant>,
+        updates_rx: &mut mpsc::Receiver<T>,
+        execution_result_rx: &mut oneshot::Receiver<ExecutionResult>,
+        shutdown_rx: &mut watch::Receiver<()>,
+    ) -> Result<Option<T>, InitializeError> {
+        let update = Self::receive_update(updates_rx, execution_result_rx, shutdown_rx);
+        match deadline {
+            Some(deadline) => match time::timeout_at(deadline, update).await {
+                Ok(result) => result.map(Some),
+                Err(_elapsed) => Ok(None),
+            },
+            None => update.await.map(Some),
+        }
+    }
+
     // Waits until it receives a cluster update from the given channel.
     // This also takes in the execution result receiver - while we're waiting for
     // an update, if the client exits prematurely, we return its execution error.
     async fn receive_update<T>(
         updates_rx: &mut mpsc::Receiver<T>,
-        execution_result_rx: oneshot::Receiver<ExecutionResult>,
+        execution_result_rx: &mut oneshot::Receiver<ExecutionResult>,
         shutdown_rx: &mut watch::Receiver<()>,
-    ) -> Result<(T, oneshot::Receiver<ExecutionResult>), InitializeError> {
+    ) -> Result<T, InitializeError> {
         tokio::select! {
             update = updates_rx.recv() => {
                 match update {
                     Some(update) => {
-                        Ok((update, execution_result_rx))
+                        Ok(update)
                     }
                     None => {
                         // Sender has dropped (the client exited prematurely) - so we can't
                         // initialize properly.
                         // Check the client's execution result if exiting was due to some root cause
                         // error and return that error if so. Otherwise return a generic error.
-                        if let Ok(Ok(Err(execution_error))) = tokio::time::timeout(Duration::from_millis(1000), execution_result_rx).await {
+                        if let Ok(Ok(Err(execution_error))) = time::timeout(Duration::from_millis(1000), execution_result_rx).await {
                             Err(InitializeError::Message(format!("failed to receive initial update: {:?}", execution_error)))
                         } else {
                             Err(InitializeError::Message("failed to receive initial update: sender dropped the channel".into()))
@@ -230,11 +398,16 @@ mod tests {
 
     use super::DynamicResourceManagers;
     use crate::cluster::cluster_manager::InitializeError;
-    use crate::config::ManagementServer;
-    use crate::filters::{manager::ListenerManagerArgs, FilterRegistry};
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, ManagementServer};
+    use crate::filters::{manager::ListenerManagerArgs, FilterChain, FilterRegistry};
+    use crate::proxy::builder::ValidatedStartup;
+    use crate::secret::SecretProviders;
+    use crate::supervisor::Supervisor;
     use crate::test_utils::logger;
     use crate::xds::ads_client::ExecutionError;
 
+    use std::sync::Arc;
     use std::time::Duration;
 
     use crate::proxy::server::resource_manager::SpawnAdsClient;
@@ -242,19 +415,19 @@ mod tests {
     use tokio::sync::mpsc;
     use tokio::sync::oneshot;
     use tokio::sync::watch;
-    use tokio::time;
+    use tokio::time::{self, Instant};
 
     #[tokio::test]
     async fn dynamic_resource_manager_receive_update() {
         let (updates_tx, mut updates_rx) = mpsc::channel(10);
         let (_shutdown_tx, mut shutdown_rx) = watch::channel(());
-        let (_execution_tx, execution_result_rx) = oneshot::channel();
+        let (_execution_tx, mut execution_result_rx) = oneshot::channel();
 
         updates_tx.send(42).await.unwrap();
 
-        let (result, _) = DynamicResourceManagers::receive_update(
+        let result = DynamicResourceManagers::receive_update(
             &mut updates_rx,
-            execution_result_rx,
+            &mut execution_result_rx,
             &mut shutdown_rx,
         )
         .await
@@ -263,12 +436,72 @@ mod tests {
         assert_eq!(42, result);
     }
 
+    #[tokio::test]
+    async fn dynamic_resource_manager_receive_initial_update() {
+        let (updates_tx, mut updates_rx) = mpsc::channel(10);
+        let (_shutdown_tx, mut shutdown_rx) = watch::channel(());
+        let (_execution_tx, mut execution_result_rx) = oneshot::channel();
+
+        updates_tx.send(42).await.unwrap();
+
+        let result = DynamicResourceManagers::receive_initial_update(
+            Some(Instant::now() + Duration::from_secs(5)),
+            &mut updates_rx,
+            &mut execution_result_rx,
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(Some(42), result);
+    }
+
+    #[tokio::test]
+    async fn dynamic_resource_manager_receive_initial_update_deadline() {
+        // If no update is received before the deadline, return None.
+        let (_updates_tx, mut updates_rx) = mpsc::channel::<usize>(10);
+        let (_shutdown_tx, mut shutdown_rx) = watch::channel(());
+        let (_execution_tx, mut execution_result_rx) = oneshot::channel();
+
+        let result = DynamicResourceManagers::receive_initial_update(
+            Some(Instant::now() + Duration::from_millis(10)),
+            &mut updates_rx,
+            &mut execution_result_rx,
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(None, result);
+    }
+
+    #[test]
+    fn dynamic_resource_manager_fallback() {
+        let registry = Registry::default();
+        let fallback = ValidatedStartup::Fallback {
+            timeout: Duration::from_secs(1),
+            filter_chain: Arc::new(FilterChain::new(vec![], &registry).unwrap()),
+            endpoints: Endpoints::new(vec![Endpoint::from_address(
+                "127.0.0.1:80".parse().unwrap(),
+            )])
+            .unwrap(),
+        };
+        let (_, endpoints) = DynamicResourceManagers::fallback(&fallback).unwrap();
+        assert_eq!(1, endpoints.as_ref().len());
+
+        let exit = ValidatedStartup::Exit {
+            timeout: Duration::from_secs(1),
+        };
+        assert!(DynamicResourceManagers::fallback(&exit).is_err());
+        assert!(DynamicResourceManagers::fallback(&ValidatedStartup::Wait).is_err());
+    }
+
     #[tokio::test]
     async fn dynamic_resource_manager_shutdown_task_on_system_shutdown() {
         // If a shutdown is triggered, shutdown the task.
         let (_updates_tx, mut updates_rx) = mpsc::channel::<usize>(10);
         let (shutdown_tx, mut shutdown_rx) = watch::channel(());
-        let (_execution_tx, execution_result_rx) = oneshot::channel();
+        let (_execution_tx, mut execution_result_rx) = oneshot::channel();
 
         // Send a shutdown signal.
         shutdown_tx.send(()).unwrap();
@@ -276,7 +509,7 @@ mod tests {
         // We should exit with an error.
         let result = DynamicResourceManagers::receive_update(
             &mut updates_rx,
-            execution_result_rx,
+            &mut execution_result_rx,
             &mut shutdown_rx,
         )
         .await;
@@ -289,7 +522,7 @@ mod tests {
         // since we can never receive an update after that.
         let (updates_tx, mut updates_rx) = mpsc::channel::<usize>(10);
         let (_shutdown_tx, mut shutdown_rx) = watch::channel(());
-        let (_execution_tx, execution_result_rx) = oneshot::channel();
+        let (_execution_tx, mut execution_result_rx) = oneshot::channel();
 
         // Drop the sender half.
         drop(updates_tx);
@@ -297,7 +530,7 @@ mod tests {
         // We should exit with an error since we now can never receive an update.
         let result = DynamicResourceManagers::receive_update(
             &mut updates_rx,
-            execution_result_rx,
+            &mut execution_result_rx,
             &mut shutdown_rx,
         )
         .await;
@@ -311,7 +544,7 @@ mod tests {
         // return it.
         let (updates_tx, mut updates_rx) = mpsc::channel::<usize>(10);
         let (_shutdown_tx, mut shutdown_rx) = watch::channel(());
-        let (execution_result_tx, execution_result_rx) = oneshot::channel();
+        let (execution_result_tx, mut execution_result_rx) = oneshot::channel();
 
         // Leave an error ExecutionResult before dropping the updates channel.
         execution_result_tx
@@ -325,7 +558,7 @@ mod tests {
         // for an execution result error and return that instead.
         match DynamicResourceManagers::receive_update(
             &mut updates_rx,
-            execution_result_rx,
+            &mut execution_result_rx,
             &mut shutdown_rx,
         )
         .await
@@ -350,6 +583,7 @@ mod tests {
             node_id: "id".into(),
             management_servers: vec![ManagementServer {
                 address: "invalid-address".into(),
+                token: None,
             }],
             cluster_updates_tx,
             listener_manager_args: ListenerManagerArgs::new(
@@ -358,6 +592,11 @@ mod tests {
                 filter_chain_updates_tx,
             ),
             execution_result_tx,
+            secret_providers: SecretProviders::default(),
+            audit_log: None,
+            faults: None,
+            telemetry: None,
+            supervisor: Supervisor::new(&logger(), &Registry::default()).unwrap(),
             shutdown_rx,
         })
         .unwrap();
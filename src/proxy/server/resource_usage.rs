@@ -0,0 +1,332 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use slog::{warn, Logger};
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+
+use crate::config::{default_resource_check_interval, ResourceLimits};
+use crate::proxy::sessions::metrics::Metrics as SessionMetrics;
+use crate::proxy::sessions::session_manager::SessionManager;
+
+use super::metrics::Metrics as ProxyMetrics;
+
+/// One in this many sessions is shed each time a limit is found to be
+/// exceeded. Shedding in steps gives the freed resources time to be returned
+/// to the operating system before more sessions are shed.
+const SHED_FRACTION: usize = 10;
+
+/// The proxy's own use of resources, as read from the operating system.
+/// Usage that can't be read on the current platform is `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(super) struct Usage {
+    pub(super) resident_memory_bytes: Option<u64>,
+    pub(super) open_fds: Option<u64>,
+    pub(super) open_sockets: Option<u64>,
+    pub(super) threads: Option<u64>,
+}
+
+impl Usage {
+    /// Reads the proxy's current usage from `/proc`.
+    #[cfg(target_os = "linux")]
+    pub(super) fn read() -> Self {
+        use std::fs;
+
+        let resident_memory_bytes = fs::read_to_string("/proc/self/statm")
+            .ok()
+            .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<u64>().ok())
+            .map(|pages| {
+                // Safe since sysconf has no preconditions.
+                let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+                pages * page_size.max(0) as u64
+            });
+
+        let fds = fs::read_dir("/proc/self/fd").ok().map(|entries| {
+            entries
+                .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+                .collect::<Vec<_>>()
+        });
+        // Reading the directory opens a descriptor of its own, which is
+        // listed along with the proxy's.
+        let open_fds = fds.as_ref().map(|fds| fds.len().saturating_sub(1) as u64);
+        let open_sockets = fds.as_ref().map(|fds| {
+            fds.iter()
+                .filter(|target| target.to_string_lossy().starts_with("socket:"))
+                .count() as u64
+        });
+
+        let threads = fs::read_to_string("/proc/self/status").ok().and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Threads:"))?
+                .trim()
+                .parse::<u64>()
+                .ok()
+        });
+
+        Self {
+            resident_memory_bytes,
+            open_fds,
+            open_sockets,
+            threads,
+        }
+    }
+
+    /// Usage can only be read on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn read() -> Self {
+        Self::default()
+    }
+
+    /// Returns the name of a limit in `limits` that the usage exceeds, if
+    /// any.
+    pub(super) fn exceeded(&self, limits: &ResourceLimits) -> Option<&'static str> {
+        let exceeds = |usage: Option<u64>, limit: Option<u64>| match (usage, limit) {
+            (Some(usage), Some(limit)) => usage > limit,
+            _ => false,
+        };
+        if exceeds(self.resident_memory_bytes, limits.max_memory_bytes) {
+            Some("max_memory_bytes")
+        } else if exceeds(self.open_fds, limits.max_open_fds) {
+            Some("max_open_fds")
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the soft limit on open file descriptors, if it can be read.
+#[cfg(unix)]
+fn max_fds() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safe since `limit` is a valid rlimit struct that outlives the call.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    Some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+fn max_fds() -> Option<u64> {
+    None
+}
+
+/// Reports the proxy's resource usage as metrics, and sheds sessions while
+/// the usage exceeds its limits.
+pub(super) struct ResourceMonitor {
+    pub(super) log: Logger,
+    pub(super) proxy_metrics: ProxyMetrics,
+    pub(super) session_metrics: SessionMetrics,
+    pub(super) session_manager: SessionManager,
+    pub(super) limits: Option<ResourceLimits>,
+}
+
+impl ResourceMonitor {
+    /// Spawns a task that checks the proxy's resource usage at the limits'
+    /// check interval, until shutdown.
+    pub(super) fn run(self, mut shutdown_rx: watch::Receiver<()>) {
+        if let Some(max_fds) = max_fds() {
+            self.proxy_metrics.max_fds.set(max_fds as i64);
+        }
+        let interval = self
+            .limits
+            .map(|limits| limits.check_interval)
+            .unwrap_or_else(default_resource_check_interval);
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => self.check(Usage::read()).await,
+                    _ = shutdown_rx.changed() => return,
+                }
+            }
+        });
+    }
+
+    /// Reports `usage`, and sheds sessions if it exceeds a limit.
+    async fn check(&self, usage: Usage) {
+        let gauges = [
+            (&self.proxy_metrics.resident_memory_bytes, usage.resident_memory_bytes),
+            (&self.proxy_metrics.open_fds, usage.open_fds),
+            (&self.proxy_metrics.open_sockets, usage.open_sockets),
+            (&self.proxy_metrics.threads, usage.threads),
+        ];
+        for (gauge, value) in std::array::IntoIter::new(gauges) {
+            if let Some(value) = value {
+                gauge.set(value as i64);
+            }
+        }
+
+        let limit = match self.limits.and_then(|limits| usage.exceeded(&limits)) {
+            Some(limit) => limit,
+            None => return,
+        };
+        let count = self.session_manager.get_sessions().await.len();
+        let shed = self
+            .session_manager
+            .shed_idlest((count / SHED_FRACTION).max(1))
+            .await;
+        self.session_metrics
+            .sessions_expired_resource_limit
+            .inc_by(shed as u64);
+        warn!(self.log, "Shedding sessions as a resource limit is exceeded";
+            "limit" => limit, "usage" => ?usage, "sessions_shed" => shed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use prometheus::Registry;
+    use tokio::sync::{mpsc, watch};
+    use tokio::time::Duration;
+
+    use super::{ResourceMonitor, Usage};
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, ResourceLimits, UpstreamSocket};
+    use crate::filters::{manager::FilterManager, FilterChain};
+    use crate::proxy::server::metrics::Metrics as ProxyMetrics;
+    use crate::proxy::sessions::metrics::Metrics as SessionMetrics;
+    use crate::proxy::sessions::session_manager::SessionManager;
+    use crate::proxy::sessions::{Packet, PacketSizeLimit, Session, SessionArgs, SessionKey};
+    use crate::test_utils::TestHelper;
+
+    fn limits(max_memory_bytes: Option<u64>, max_open_fds: Option<u64>) -> ResourceLimits {
+        ResourceLimits {
+            max_memory_bytes,
+            max_open_fds,
+            check_interval: Duration::from_secs(1),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn read_usage() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let usage = Usage::read();
+        assert!(usage.resident_memory_bytes.unwrap() > 0);
+        assert!(usage.open_fds.unwrap() > 0);
+        assert!(usage.open_sockets.unwrap() > 0);
+        assert!(usage.open_sockets <= usage.open_fds);
+        assert!(usage.threads.unwrap() > 0);
+        drop(socket);
+    }
+
+    #[test]
+    fn exceeded() {
+        let usage = Usage {
+            resident_memory_bytes: Some(1000),
+            open_fds: Some(100),
+            open_sockets: Some(50),
+            threads: Some(4),
+        };
+        assert_eq!(None, usage.exceeded(&limits(None, None)));
+        assert_eq!(None, usage.exceeded(&limits(Some(1000), Some(100))));
+        assert_eq!(
+            Some("max_memory_bytes"),
+            usage.exceeded(&limits(Some(999), None))
+        );
+        assert_eq!(Some("max_open_fds"), usage.exceeded(&limits(None, Some(99))));
+        // Usage that can't be read never exceeds a limit.
+        assert_eq!(None, Usage::default().exceeded(&limits(Some(0), Some(0))));
+    }
+
+    #[tokio::test]
+    async fn shed_sessions() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let to: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(to)]).unwrap(),
+        )
+        .unwrap();
+        let session_metrics = SessionMetrics::new(&registry).unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            session_metrics.clone(),
+            cluster_manager,
+            None,
+            shutdown_rx,
+        );
+        let (send, _recv) = mpsc::channel::<Packet>(1);
+        let mut sessions = HashMap::new();
+        for port in 7002..7004 {
+            let from = SocketAddr::from(([127, 0, 0, 1], port));
+            let session = Session::new(
+                &t.log,
+                SessionArgs {
+                    metrics: session_metrics.clone(),
+                    filter_manager: FilterManager::fixed(Arc::new(
+                        FilterChain::new(vec![], &registry).unwrap(),
+                    )),
+                    from,
+                    client_key: None,
+                    connection_id_header: None,
+                    dest: Endpoint::from_address(to),
+                    sender: send.clone(),
+                    ttl: Duration::from_secs(10),
+                    packet_size_limit: PacketSizeLimit::default(),
+                    compute_pool: None,
+                    upstream_socket: UpstreamSocket::default(),
+                    tunnel: None,
+                    response_only_endpoints: None,
+                    endpoint_health: None,
+                    tap: None,
+                    faults: None,
+                    supervisor: None,
+                    send_queue_size: None,
+                },
+            )
+            .await
+            .unwrap();
+            sessions.insert(SessionKey::from((from, to)), session);
+        }
+        session_manager.get_sessions_mut().await.extend(sessions);
+
+        let monitor = ResourceMonitor {
+            log: t.log.clone(),
+            proxy_metrics: ProxyMetrics::new(&registry).unwrap(),
+            session_metrics: session_metrics.clone(),
+            session_manager: session_manager.clone(),
+            limits: Some(limits(None, Some(100))),
+        };
+        let usage = Usage {
+            open_fds: Some(100),
+            ..Usage::default()
+        };
+        monitor.check(usage).await;
+        assert_eq!(100, monitor.proxy_metrics.open_fds.get());
+        assert_eq!(2, session_manager.get_sessions().await.len());
+
+        // At least one session is shed each time a limit is exceeded.
+        let usage = Usage {
+            open_fds: Some(101),
+            ..Usage::default()
+        };
+        monitor.check(usage).await;
+        assert_eq!(1, session_manager.get_sessions().await.len());
+        assert_eq!(1, session_metrics.sessions_expired_resource_limit.get());
+    }
+}
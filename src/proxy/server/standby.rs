@@ -0,0 +1,569 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Coordinates a pair of proxies, one active and one standby, in the manner
+//! of VRRP: the proxies send each other heartbeats, the active proxy
+//! replicates its state to the standby, and the standby takes over once the
+//! active proxy's heartbeats stop.
+
+use std::convert::TryInto;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use slog::{debug, info, warn, Logger};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant};
+
+use crate::config::Standby as StandbyConfig;
+use crate::proxy::server::metrics::Metrics;
+use crate::proxy::server::state::{Snapshot, StateTransfer};
+
+/// The bytes that a heartbeat starts with.
+const PREFIX: &[u8] = b"QUILKIN_STANDBY";
+/// The size in bytes of the signature of a heartbeat or of replicated state.
+const SIGNATURE_SIZE: usize = 32;
+/// The size in bytes of a heartbeat: the prefix, the sender's id and
+/// sequence number, its priority, whether it's active and the signature.
+const HEARTBEAT_SIZE: usize = PREFIX.len() + 8 + 8 + 1 + 1 + SIGNATURE_SIZE;
+/// The largest replicated state accepted from the peer, in bytes.
+const MAX_STATE_SIZE: usize = 64 * 1024 * 1024;
+
+/// A heartbeat sent between the proxies of a pair.
+#[derive(Debug, PartialEq)]
+struct Heartbeat {
+    /// The id the sender picked at random when it started, which breaks
+    /// ties between proxies with the same priority.
+    id: u64,
+    /// Increases with each heartbeat the sender sends, even across
+    /// restarts, so that heartbeats can't be replayed.
+    sequence: u64,
+    priority: u8,
+    active: bool,
+}
+
+/// A change of the proxy's role, in response to a heartbeat from its peer.
+#[derive(Debug, PartialEq)]
+enum Transition {
+    BecomeActive,
+    BecomeStandby,
+}
+
+/// What the proxy knows about its peer.
+struct Peer {
+    /// The sequence number of the last valid heartbeat received from the
+    /// peer.
+    sequence: u64,
+    /// When the peer last said it was active, or when the proxy started or
+    /// became the standby if the peer hasn't since.
+    active_at: Instant,
+    /// The state last replicated from the peer, which is restored when the
+    /// proxy becomes active.
+    snapshot: Option<Snapshot>,
+}
+
+/// One of an active/standby pair of proxies. Only the active proxy
+/// processes packets from clients. The proxies start as the standby, and a
+/// standby becomes active once it hasn't received a heartbeat from an
+/// active peer for the failover timeout, restoring the peer's sessions from
+/// the state it last replicated. If both proxies are active at once, e.g.
+/// after a network partition heals, the one with the lower priority becomes
+/// the standby, removing its sessions.
+///
+/// Heartbeats are sent over UDP, every heartbeat interval, and are the
+/// prefix, the sender's id and sequence number, its priority, whether it's
+/// active, and an HMAC-SHA256 signature of all of them. The active proxy
+/// replicates its state over TCP, every sync interval, as its length, the
+/// state as JSON, and a signature of the state.
+pub(crate) struct Standby {
+    log: Logger,
+    socket: UdpSocket,
+    listener: TcpListener,
+    config: StandbyConfig,
+    id: u64,
+    /// The sequence number of the next heartbeat, which starts from the time
+    /// the proxy started so that it keeps increasing across restarts.
+    sequence: AtomicU64,
+    active: AtomicBool,
+    peer: Mutex<Peer>,
+    metrics: Metrics,
+}
+
+impl Standby {
+    /// Returns a new Standby, which sends and receives heartbeats on `socket`
+    /// and receives state from the peer on `listener`.
+    pub(crate) fn new(
+        log: Logger,
+        config: StandbyConfig,
+        socket: UdpSocket,
+        listener: TcpListener,
+        metrics: Metrics,
+    ) -> Self {
+        metrics.standby_active.set(0);
+        Self {
+            log,
+            socket,
+            listener,
+            config,
+            id: rand::random(),
+            sequence: AtomicU64::new(unix_time_millis()),
+            active: AtomicBool::new(false),
+            peer: Mutex::new(Peer {
+                sequence: 0,
+                active_at: Instant::now(),
+                snapshot: None,
+            }),
+            metrics,
+        }
+    }
+
+    /// Returns whether the proxy is the active proxy of the pair.
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Returns the proxy's role and the state replicated from its peer as
+    /// JSON.
+    pub(crate) fn status(&self) -> Value {
+        let replicated_sessions = self
+            .peer
+            .lock()
+            .snapshot
+            .as_ref()
+            .map_or(0, |snapshot| snapshot.sessions.len());
+        json!({
+            "active": self.is_active(),
+            "priority": self.config.priority,
+            "peer": self.config.peer.to_string(),
+            "replicated_sessions": replicated_sessions,
+        })
+    }
+
+    /// Exchanges heartbeats with the peer and replicates state in the
+    /// background, until shutdown.
+    pub(super) fn run(
+        self: Arc<Self>,
+        state_transfer: StateTransfer,
+        shutdown_rx: watch::Receiver<()>,
+    ) {
+        // Heartbeats are sent and received by a single task, so that the
+        // proxy's role only changes in one place.
+        let standby = self.clone();
+        let transfer = state_transfer.clone();
+        let mut shutdown = shutdown_rx.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(standby.config.heartbeat_interval);
+            let mut buf = [0; HEARTBEAT_SIZE];
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        if !standby.is_active() && standby.failover_due(Instant::now()) {
+                            standby.transition(Transition::BecomeActive, &transfer).await;
+                        }
+                        standby.send_heartbeat().await;
+                    }
+                    result = standby.socket.recv_from(&mut buf) => match result {
+                        Ok((size, from)) => match standby.decode(&buf[..size]) {
+                            Some(heartbeat) => {
+                                if let Some(transition) = standby.received(heartbeat) {
+                                    standby.transition(transition, &transfer).await;
+                                }
+                            }
+                            None => {
+                                standby.metrics.standby_heartbeats_invalid_total.inc();
+                                debug!(standby.log, "Received an invalid heartbeat";
+                                    "from" => %from);
+                            }
+                        },
+                        Err(err) => {
+                            warn!(standby.log, "Failed to receive heartbeat"; "error" => %err);
+                        }
+                    },
+                    _ = shutdown.changed() => return,
+                }
+            }
+        });
+
+        let standby = self.clone();
+        let mut shutdown = shutdown_rx.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = standby.accept_state() => {}
+                    _ = shutdown.changed() => return,
+                }
+            }
+        });
+
+        let mut shutdown = shutdown_rx;
+        tokio::spawn(async move {
+            let mut interval = time::interval(self.config.sync_interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        if self.is_active() {
+                            let snapshot = state_transfer.export().await;
+                            self.send_state(&snapshot).await;
+                        }
+                    }
+                    _ = shutdown.changed() => return,
+                }
+            }
+        });
+    }
+
+    /// Returns whether the standby has gone long enough without a heartbeat
+    /// from an active peer to become active at `now`. As with VRRP, the
+    /// lower the proxy's priority the longer it waits, so that of two
+    /// proxies that start at once the one with the higher priority becomes
+    /// active.
+    fn failover_due(&self, now: Instant) -> bool {
+        let skew = self.config.heartbeat_interval * u32::from(u8::MAX - self.config.priority) / 256;
+        now.duration_since(self.peer.lock().active_at) >= self.config.failover_timeout + skew
+    }
+
+    /// Returns the change of the proxy's role that `heartbeat` calls for,
+    /// if any.
+    fn received(&self, heartbeat: Heartbeat) -> Option<Transition> {
+        {
+            let mut peer = self.peer.lock();
+            if heartbeat.id == self.id || heartbeat.sequence <= peer.sequence {
+                self.metrics.standby_heartbeats_invalid_total.inc();
+                return None;
+            }
+            peer.sequence = heartbeat.sequence;
+            if heartbeat.active {
+                peer.active_at = Instant::now();
+            }
+        }
+        if !heartbeat.active {
+            return None;
+        }
+
+        let active = self.is_active();
+        if active && (heartbeat.priority, heartbeat.id) > (self.config.priority, self.id) {
+            Some(Transition::BecomeStandby)
+        } else if !active && self.config.preempt && self.config.priority > heartbeat.priority {
+            Some(Transition::BecomeActive)
+        } else {
+            None
+        }
+    }
+
+    /// Changes the proxy's role. A proxy becoming active restores the state
+    /// last replicated from its peer before it processes packets, and one
+    /// becoming the standby removes its sessions, so that the endpoints'
+    /// packets aren't sent to clients by both proxies.
+    async fn transition(&self, transition: Transition, state_transfer: &StateTransfer) {
+        match transition {
+            Transition::BecomeActive => {
+                let snapshot = self.peer.lock().snapshot.take();
+                match snapshot {
+                    Some(snapshot) => match state_transfer.import(snapshot).await {
+                        Ok(summary) => info!(self.log, "Restored the peer's sessions";
+                            "sessions" => summary.sessions_imported),
+                        Err(err) => warn!(self.log, "Failed to restore the peer's state";
+                            "error" => %err),
+                    },
+                    None => info!(self.log, "There is no state from the peer to restore"),
+                }
+                self.active.store(true, Ordering::Relaxed);
+                self.metrics.standby_active.set(1);
+                info!(self.log, "Became the active proxy"; "peer" => %self.config.peer);
+            }
+            Transition::BecomeStandby => {
+                self.active.store(false, Ordering::Relaxed);
+                self.metrics.standby_active.set(0);
+                self.peer.lock().active_at = Instant::now();
+                let removed = state_transfer.remove_sessions().await;
+                info!(self.log, "Became the standby proxy as the peer outranks it";
+                    "peer" => %self.config.peer, "sessions_removed" => removed);
+            }
+        }
+        self.metrics.standby_transitions_total.inc();
+    }
+
+    async fn send_heartbeat(&self) {
+        let heartbeat = self.encode(&Heartbeat {
+            id: self.id,
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            priority: self.config.priority,
+            active: self.is_active(),
+        });
+        if let Err(err) = self.socket.send_to(&heartbeat, self.config.peer).await {
+            debug!(self.log, "Failed to send heartbeat";
+                "peer" => %self.config.peer, "error" => %err);
+        }
+    }
+
+    fn encode(&self, heartbeat: &Heartbeat) -> Vec<u8> {
+        let mut encoded = PREFIX.to_vec();
+        encoded.extend_from_slice(&heartbeat.id.to_be_bytes());
+        encoded.extend_from_slice(&heartbeat.sequence.to_be_bytes());
+        encoded.push(heartbeat.priority);
+        encoded.push(heartbeat.active as u8);
+        let signature = self.mac(&encoded).finalize().into_bytes();
+        encoded.extend_from_slice(&signature);
+        encoded
+    }
+
+    /// Returns the heartbeat in `encoded`, if it's valid.
+    fn decode(&self, encoded: &[u8]) -> Option<Heartbeat> {
+        if encoded.len() != HEARTBEAT_SIZE || !encoded.starts_with(PREFIX) {
+            return None;
+        }
+        let (signed, signature) = encoded.split_at(encoded.len() - SIGNATURE_SIZE);
+        self.mac(signed).verify(signature).ok()?;
+
+        let fields = &signed[PREFIX.len()..];
+        Some(Heartbeat {
+            id: u64::from_be_bytes(fields[..8].try_into().ok()?),
+            sequence: u64::from_be_bytes(fields[8..16].try_into().ok()?),
+            priority: fields[16],
+            active: fields[17] != 0,
+        })
+    }
+
+    /// Replicates `snapshot` to the peer.
+    async fn send_state(&self, snapshot: &Snapshot) {
+        let send = async {
+            let state = serde_json::to_vec(snapshot)?;
+            let signature = self.mac(&state).finalize().into_bytes();
+            let mut stream = TcpStream::connect(self.config.peer).await?;
+            stream
+                .write_all(&(state.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(&state).await?;
+            stream.write_all(&signature).await?;
+            stream.shutdown().await
+        };
+        let result = match time::timeout(self.config.sync_interval, send).await {
+            Ok(result) => result,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        };
+        match result {
+            Ok(()) => self.metrics.standby_syncs_sent.inc(),
+            Err(err) => {
+                self.metrics.standby_syncs_failed.inc();
+                debug!(self.log, "Failed to replicate state to the peer";
+                    "peer" => %self.config.peer, "error" => %err);
+            }
+        }
+    }
+
+    /// Accepts a connection from the peer and keeps the state it
+    /// replicates, unless the proxy is active.
+    async fn accept_state(&self) {
+        let (mut stream, from) = match self.listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(self.log, "Failed to accept a connection from the peer"; "error" => %err);
+                return;
+            }
+        };
+        let receive = async {
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).await?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len > MAX_STATE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "state is too large",
+                ));
+            }
+            let mut buf = vec![0; len + SIGNATURE_SIZE];
+            stream.read_exact(&mut buf).await?;
+            Ok(buf)
+        };
+        let snapshot = match time::timeout(self.config.failover_timeout, receive).await {
+            Ok(Ok(buf)) => self.verify_state(&buf),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(_) => Err("timed out".into()),
+        };
+        match snapshot {
+            Ok(snapshot) => {
+                self.metrics.standby_syncs_received.inc();
+                if !self.is_active() {
+                    self.peer.lock().snapshot = Some(snapshot);
+                }
+            }
+            Err(err) => {
+                self.metrics.standby_syncs_invalid.inc();
+                debug!(self.log, "Received invalid state"; "from" => %from, "error" => err);
+            }
+        }
+    }
+
+    /// Returns the state in `buf`, followed by its signature, if it's valid.
+    fn verify_state(&self, buf: &[u8]) -> Result<Snapshot, String> {
+        let (state, signature) = buf.split_at(buf.len() - SIGNATURE_SIZE);
+        self.mac(state)
+            .verify(signature)
+            .map_err(|_| "invalid signature".to_string())?;
+        serde_json::from_slice(state).map_err(|err| err.to_string())
+    }
+
+    fn mac(&self, message: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.config.secret)
+            .expect("HMAC can take a key of any size");
+        mac.update(message);
+        mac
+    }
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch.
+fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use prometheus::Registry;
+    use tokio::net::{TcpListener, UdpSocket};
+    use tokio::time::{self, Duration, Instant};
+
+    use super::{Heartbeat, Standby, Transition};
+    use crate::config::Standby as StandbyConfig;
+    use crate::proxy::server::metrics::Metrics;
+    use crate::proxy::server::state::{SessionState, Snapshot};
+    use crate::test_utils::logger;
+
+    async fn standby(secret: &[u8], priority: u8, preempt: bool) -> Standby {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Standby::new(
+            logger(),
+            StandbyConfig {
+                port: 0,
+                // The proxies in these tests are their own peer.
+                peer: listener.local_addr().unwrap(),
+                secret: secret.to_vec(),
+                priority,
+                preempt,
+                heartbeat_interval: Duration::from_millis(10),
+                failover_timeout: Duration::from_millis(50),
+                sync_interval: Duration::from_secs(1),
+            },
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            listener,
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    fn heartbeat(sequence: u64, priority: u8, active: bool) -> Heartbeat {
+        Heartbeat {
+            id: 42,
+            sequence,
+            priority,
+            active,
+        }
+    }
+
+    #[tokio::test]
+    async fn decode() {
+        let standby = standby(b"secret", 100, true).await;
+        let encoded = standby.encode(&heartbeat(7, 200, true));
+        assert_eq!(Some(heartbeat(7, 200, true)), standby.decode(&encoded));
+
+        // Heartbeats signed with another secret are invalid.
+        let other = self::standby(b"other", 100, true).await;
+        assert_eq!(None, other.decode(&encoded));
+        // As are heartbeats that were tampered with.
+        let mut tampered = encoded.clone();
+        tampered[super::PREFIX.len() + 16] = 255;
+        assert_eq!(None, standby.decode(&tampered));
+        assert_eq!(None, standby.decode(super::PREFIX));
+    }
+
+    #[tokio::test]
+    async fn received() {
+        let standby = standby(b"secret", 100, true).await;
+
+        // A standby with a higher priority than the active peer preempts it.
+        assert_eq!(None, standby.received(heartbeat(1, 50, false)));
+        assert_eq!(
+            Some(Transition::BecomeActive),
+            standby.received(heartbeat(2, 50, true))
+        );
+        // Replayed heartbeats are ignored.
+        assert_eq!(None, standby.received(heartbeat(2, 50, true)));
+        assert_eq!(1, standby.metrics.standby_heartbeats_invalid_total.get());
+        assert_eq!(None, standby.received(heartbeat(3, 200, true)));
+
+        // An active proxy becomes the standby of an active peer that
+        // outranks it.
+        standby.active.store(true, Ordering::Relaxed);
+        assert_eq!(None, standby.received(heartbeat(4, 50, true)));
+        assert_eq!(
+            Some(Transition::BecomeStandby),
+            standby.received(heartbeat(5, 200, true))
+        );
+
+        let standby = self::standby(b"secret", 100, false).await;
+        assert_eq!(None, standby.received(heartbeat(1, 50, true)));
+    }
+
+    #[tokio::test]
+    async fn failover_due() {
+        let standby = standby(b"secret", 255, true).await;
+        assert!(!standby.failover_due(Instant::now()));
+
+        time::sleep(Duration::from_millis(60)).await;
+        assert!(standby.failover_due(Instant::now()));
+
+        // Heartbeats from an active peer hold off the failover.
+        standby.received(heartbeat(1, 100, true));
+        assert!(!standby.failover_due(Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn replicate_state() {
+        let standby = standby(b"secret", 100, true).await;
+        let snapshot = Snapshot {
+            sessions: vec![SessionState {
+                client: "127.0.0.1:7000".parse().unwrap(),
+                client_id: None,
+                endpoint: "127.0.0.1:7001".parse().unwrap(),
+                expires_in: Duration::from_secs(60),
+            }],
+            ..Snapshot::default()
+        };
+
+        tokio::join!(standby.send_state(&snapshot), standby.accept_state());
+        assert_eq!(1, standby.metrics.standby_syncs_sent.get());
+        assert_eq!(1, standby.metrics.standby_syncs_received.get());
+        let replicated = standby.peer.lock().snapshot.take().unwrap();
+        assert_eq!(snapshot.sessions, replicated.sessions);
+
+        // State signed with another secret is rejected.
+        let mut other = self::standby(b"other", 100, true).await;
+        other.config.peer = standby.listener.local_addr().unwrap();
+        tokio::join!(other.send_state(&snapshot), standby.accept_state());
+        assert_eq!(1, standby.metrics.standby_syncs_invalid.get());
+        assert!(standby.peer.lock().snapshot.is_none());
+    }
+}
@@ -0,0 +1,395 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Exports the state of a running proxy, so that it can be imported into a
+//! new instance that replaces it without disrupting its clients.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use slog::{error, info};
+
+use crate::filters::{Error as FilterError, Filter, FilterChain};
+use crate::proxy::server::connection_tracker::ExportedAdmission;
+use crate::proxy::server::ProcessDownstreamReceiveConfig;
+use crate::proxy::sessions::{ClientKey, Session, SessionKey};
+
+/// A snapshot of the state of a proxy.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// The proxy's sessions.
+    #[serde(default)]
+    pub sessions: Vec<SessionState>,
+    /// The connection tracker's cached decisions to admit clients.
+    #[serde(default)]
+    pub admissions: Vec<ExportedAdmission>,
+    /// The state of the filters in the filter chain, if any have state.
+    #[serde(default)]
+    pub filters: Option<serde_json::Value>,
+}
+
+/// A session of the proxy, as exported in a [`Snapshot`].
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SessionState {
+    pub client: SocketAddr,
+    /// The token or connection id that the client is identified by, base64
+    /// encoded, if it isn't identified by its address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    pub endpoint: SocketAddr,
+    /// How long until the session expires unless it receives a packet.
+    #[serde(with = "humantime_serde")]
+    pub expires_in: Duration,
+}
+
+/// The outcome of importing a [`Snapshot`].
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ImportSummary {
+    /// The number of sessions created.
+    pub sessions_imported: usize,
+    /// The number of sessions that weren't created, as the proxy already
+    /// has them or their endpoint isn't one of the proxy's endpoints.
+    pub sessions_skipped: usize,
+    /// The number of decisions to admit clients that were cached.
+    pub admissions_imported: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("failed to import connection tracker decisions: invalid token: {}", .0)]
+    InvalidToken(base64::DecodeError),
+    #[error("failed to import sessions: invalid client id: {}", .0)]
+    InvalidClientId(base64::DecodeError),
+    #[error("failed to import filter state: {}", .0)]
+    Filters(FilterError),
+}
+
+/// Exports and imports the state of a running proxy.
+#[derive(Clone)]
+pub struct StateTransfer(Arc<ProcessDownstreamReceiveConfig>);
+
+impl StateTransfer {
+    pub(super) fn new(config: ProcessDownstreamReceiveConfig) -> Self {
+        Self(Arc::new(config))
+    }
+
+    /// Returns a snapshot of the proxy's current state.
+    pub async fn export(&self) -> Snapshot {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let sessions = self
+            .0
+            .session_manager
+            .get_sessions()
+            .await
+            .values()
+            .map(|session| {
+                let key = session.key();
+                SessionState {
+                    client: session.client(),
+                    client_id: key.client.id().map(base64::encode),
+                    endpoint: key.endpoint,
+                    expires_in: Duration::from_secs(session.expiration().saturating_sub(now)),
+                }
+            })
+            .collect();
+
+        Snapshot {
+            sessions,
+            admissions: self
+                .0
+                .connection_tracker
+                .as_ref()
+                .map(|connection_tracker| connection_tracker.export())
+                .unwrap_or_default(),
+            filters: self.filter_chain().export_state(),
+        }
+    }
+
+    /// Restores the state in `snapshot`, which was exported by another
+    /// proxy. Sessions are created for clients as if they had completed the
+    /// handshake (if enabled) and been admitted by the connection tracker
+    /// (if enabled).
+    pub async fn import(&self, snapshot: Snapshot) -> Result<ImportSummary, ImportError> {
+        let args = &self.0;
+        let mut summary = ImportSummary::default();
+
+        if let Some(connection_tracker) = &args.connection_tracker {
+            summary.admissions_imported = connection_tracker
+                .import(snapshot.admissions)
+                .map_err(ImportError::InvalidToken)?;
+        }
+        if let Some(state) = snapshot.filters {
+            self.filter_chain()
+                .import_state(state)
+                .map_err(ImportError::Filters)?;
+        }
+
+        let endpoints = args.cluster_manager.read().get_all_endpoints();
+        let mut sessions = args.session_manager.get_sessions_mut().await;
+        for SessionState {
+            client,
+            client_id,
+            endpoint,
+            expires_in,
+        } in snapshot.sessions
+        {
+            // Sessions are keyed by the client's id only if this proxy keys
+            // sessions the same way as the one they were exported from.
+            let client_key = match (client_id, &args.session_key) {
+                (Some(id), Some(source)) => {
+                    let id = base64::decode(id).map_err(ImportError::InvalidClientId)?;
+                    ClientKey::from_id(source.kind, client, id)
+                }
+                (Some(id), None) if args.connection_ids.is_some() => {
+                    let id = base64::decode(id).map_err(ImportError::InvalidClientId)?;
+                    ClientKey::ConnectionId(id)
+                }
+                _ => ClientKey::Address(client),
+            };
+            let endpoint = endpoints
+                .as_ref()
+                .and_then(|endpoints| endpoints.iter().find(|e| e.address == endpoint));
+            let endpoint = match endpoint {
+                Some(endpoint) => endpoint,
+                None => {
+                    summary.sessions_skipped += 1;
+                    continue;
+                }
+            };
+            let key = SessionKey {
+                client: client_key.clone(),
+                endpoint: endpoint.address,
+            };
+            if sessions.contains_key(&key) {
+                summary.sessions_skipped += 1;
+                continue;
+            }
+
+            let session_args =
+                args.session_args(client, client_key, endpoint.clone(), expires_in);
+            match Session::new(&args.log, session_args).await {
+                Ok(session) => {
+                    let key = session.key();
+                    args.client_drops
+                        .register(key.client.clone(), session.drop_reasons());
+                    sessions.insert(key, session);
+                    args.update_peak_active_sessions();
+                    summary.sessions_imported += 1;
+                }
+                Err(err) => {
+                    error!(args.log, "Failed to import session";
+                        "client" => client, "error" => %err);
+                    summary.sessions_skipped += 1;
+                }
+            }
+        }
+
+        info!(args.log, "Imported proxy state";
+            "sessions_imported" => summary.sessions_imported,
+            "sessions_skipped" => summary.sessions_skipped,
+            "admissions_imported" => summary.admissions_imported);
+        Ok(summary)
+    }
+
+    /// Removes all of the proxy's sessions, returning how many there were.
+    pub(super) async fn remove_sessions(&self) -> usize {
+        let mut sessions = self.0.session_manager.get_sessions_mut().await;
+        let removed = sessions.len();
+        sessions.clear();
+        removed
+    }
+
+    fn filter_chain(&self) -> Arc<FilterChain> {
+        self.0.filter_manager.read().get_filter_chain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+    use tokio::sync::{mpsc, watch};
+
+    use super::{ImportSummary, SessionState, Snapshot, StateTransfer};
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, SessionKeyKind, SessionKeySource};
+    use crate::proxy::server::tests::receive_config;
+    use crate::proxy::server::ProcessDownstreamReceiveConfig;
+    use crate::proxy::sessions::metrics::Metrics as SessionMetrics;
+    use crate::proxy::sessions::session_manager::SessionManager;
+    use crate::proxy::sessions::{ClientKey, Packet, SessionKey};
+    use crate::test_utils::TestHelper;
+
+    fn state_transfer(
+        t: &TestHelper,
+        shutdown_rx: watch::Receiver<()>,
+        session_key: Option<SessionKeySource>,
+    ) -> StateTransfer {
+        let registry = Registry::default();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(
+                "127.0.0.1:7001".parse().unwrap(),
+            )])
+            .unwrap(),
+        )
+        .unwrap();
+        let session_manager = SessionManager::new(
+            t.log.clone(),
+            SessionMetrics::new(&registry).unwrap(),
+            cluster_manager.clone(),
+            None,
+            shutdown_rx.clone(),
+        );
+        let (send_packets, _) = mpsc::channel::<Packet>(1);
+        StateTransfer::new(ProcessDownstreamReceiveConfig {
+            session_key,
+            ..receive_config(
+                t,
+                &registry,
+                cluster_manager,
+                session_manager,
+                send_packets,
+                shutdown_rx,
+            )
+        })
+    }
+
+    #[tokio::test]
+    async fn export_import() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let blue = state_transfer(&t, shutdown_rx.clone(), None);
+        let green = state_transfer(&t, shutdown_rx, None);
+
+        let session = SessionState {
+            client: "127.0.0.1:7000".parse().unwrap(),
+            client_id: None,
+            endpoint: "127.0.0.1:7001".parse().unwrap(),
+            expires_in: Duration::from_secs(30),
+        };
+        let unknown_endpoint = SessionState {
+            client: "127.0.0.1:7000".parse().unwrap(),
+            client_id: None,
+            endpoint: "127.0.0.1:7002".parse().unwrap(),
+            expires_in: Duration::from_secs(30),
+        };
+        let summary = blue
+            .import(Snapshot {
+                sessions: vec![session, unknown_endpoint],
+                ..Snapshot::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            ImportSummary {
+                sessions_imported: 1,
+                sessions_skipped: 1,
+                admissions_imported: 0,
+            },
+            summary
+        );
+
+        // The snapshot is passed through JSON as it would be by the admin
+        // server.
+        let snapshot = serde_json::to_string(&blue.export().await).unwrap();
+        let snapshot = serde_json::from_str::<Snapshot>(&snapshot).unwrap();
+        assert_eq!(1, snapshot.sessions.len());
+        assert_eq!(
+            "127.0.0.1:7000".parse::<SocketAddr>().unwrap(),
+            snapshot.sessions[0].client
+        );
+        assert!(snapshot.sessions[0].expires_in <= Duration::from_secs(30));
+        assert!(snapshot.filters.is_none());
+
+        let summary = green.import(snapshot).await.unwrap();
+        assert_eq!(1, summary.sessions_imported);
+        let sessions = green.0.session_manager.get_sessions().await;
+        assert!(sessions.contains_key(&SessionKey::from((
+            "127.0.0.1:7000".parse().unwrap(),
+            "127.0.0.1:7001".parse().unwrap()
+        ))));
+        drop(sessions);
+
+        // Sessions that already exist aren't created again.
+        let summary = green.import(green.export().await).await.unwrap();
+        assert_eq!(0, summary.sessions_imported);
+        assert_eq!(1, summary.sessions_skipped);
+    }
+
+    #[tokio::test]
+    async fn import_client_id() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let blue = state_transfer(
+            &t,
+            shutdown_rx.clone(),
+            Some(SessionKeySource {
+                kind: SessionKeyKind::ConnectionId,
+                metadata_key: "quilkin.dev/captured_bytes".into(),
+            }),
+        );
+        let green = state_transfer(&t, shutdown_rx, None);
+
+        let client = "127.0.0.1:7000".parse().unwrap();
+        let endpoint = "127.0.0.1:7001".parse().unwrap();
+        let snapshot = || Snapshot {
+            sessions: vec![SessionState {
+                client,
+                client_id: Some(base64::encode("abc")),
+                endpoint,
+                expires_in: Duration::from_secs(30),
+            }],
+            ..Snapshot::default()
+        };
+
+        // The session is keyed by its client's id if the proxy keys sessions
+        // by connection ids...
+        assert_eq!(1, blue.import(snapshot()).await.unwrap().sessions_imported);
+        assert!(blue
+            .0
+            .session_manager
+            .get_sessions()
+            .await
+            .contains_key(&SessionKey {
+                client: ClientKey::ConnectionId(b"abc".to_vec()),
+                endpoint,
+            }));
+        let exported = blue.export().await;
+        assert_eq!(Some(base64::encode("abc")), exported.sessions[0].client_id);
+
+        // ...otherwise by its client's address.
+        assert_eq!(1, green.import(snapshot()).await.unwrap().sessions_imported);
+        assert!(green
+            .0
+            .session_manager
+            .get_sessions()
+            .await
+            .contains_key(&SessionKey::from((client, endpoint))));
+
+        let mut invalid = snapshot();
+        invalid.sessions[0].client_id = Some("!".into());
+        assert!(blue.import(invalid).await.is_err());
+    }
+}
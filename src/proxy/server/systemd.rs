@@ -0,0 +1,182 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Integration with systemd: readiness and watchdog notifications, and the
+//! socket passed to the proxy by socket activation. Each of these only takes
+//! effect if systemd set the environment variables that enable it.
+
+use std::env;
+use std::io;
+use std::process;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+/// Sends `state`, e.g. `READY=1`, to the service manager. Returns whether a
+/// notification was sent, which is only the case if the proxy was started
+/// with `NOTIFY_SOCKET` set.
+#[cfg(unix)]
+pub(super) fn notify(state: &str) -> io::Result<bool> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    if path.as_bytes().starts_with(b"@") {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "notify sockets in the abstract namespace are not supported",
+        ));
+    }
+    UnixDatagram::unbound()?.send_to(state.as_bytes(), path)?;
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub(super) fn notify(_: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Returns how often the service manager expects a `WATCHDOG=1` notification,
+/// if the watchdog is enabled for this process.
+pub(super) fn watchdog_interval() -> Option<Duration> {
+    if !for_this_process(env::var("WATCHDOG_PID").ok(), false) {
+        return None;
+    }
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|&usec| usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Returns the socket passed to this process by socket activation, if any.
+/// Only a single UDP socket can be passed.
+#[cfg(unix)]
+pub(super) fn listen_socket() -> io::Result<Option<UdpSocket>> {
+    use std::os::unix::io::{FromRawFd, RawFd};
+
+    /// The first file descriptor passed by socket activation.
+    const LISTEN_FDS_START: RawFd = 3;
+
+    if !for_this_process(env::var("LISTEN_PID").ok(), true) {
+        return Ok(None);
+    }
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<u32>().ok())
+        .unwrap_or(0);
+    // Don't pass the sockets on to any process started by the proxy.
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    match fds {
+        0 => return Ok(None),
+        1 => {}
+        fds => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected a single socket from socket activation, got {}", fds),
+            ))
+        }
+    }
+
+    if socket_type(LISTEN_FDS_START)? != libc::SOCK_DGRAM {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the socket passed by socket activation is not a UDP socket",
+        ));
+    }
+    // Safe since socket activation hands the file descriptor over to this
+    // process, and it is only taken once as the variables are removed.
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(LISTEN_FDS_START) };
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket).map(Some)
+}
+
+#[cfg(not(unix))]
+pub(super) fn listen_socket() -> io::Result<Option<UdpSocket>> {
+    Ok(None)
+}
+
+/// Returns the type of the socket `fd`, e.g. `SOCK_DGRAM`.
+#[cfg(unix)]
+fn socket_type(fd: std::os::unix::io::RawFd) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // Safe since `value` and `len` are valid and outlive the call.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Returns whether a variable naming the process it is meant for, such as
+/// `LISTEN_PID`, names this process. `required` is whether the variable must
+/// be set.
+fn for_this_process(pid: Option<String>, required: bool) -> bool {
+    match pid {
+        Some(pid) => pid.parse::<u32>().ok() == Some(process::id()),
+        None => !required,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::for_this_process;
+
+    #[test]
+    fn this_process() {
+        assert!(for_this_process(Some(process::id().to_string()), true));
+        assert!(!for_this_process(Some((process::id() + 1).to_string()), true));
+        assert!(!for_this_process(Some("invalid".into()), false));
+        assert!(for_this_process(None, false));
+        assert!(!for_this_process(None, true));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notify() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join(format!("quilkin-notify-{}", process::id()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        let sent = super::notify("READY=1");
+        std::env::remove_var("NOTIFY_SOCKET");
+        assert!(sent.unwrap());
+
+        let mut buf = [0; 64];
+        let size = socket.recv(&mut buf).unwrap();
+        assert_eq!(b"READY=1", &buf[..size]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!super::notify("READY=1").unwrap());
+    }
+}
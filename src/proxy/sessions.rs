@@ -14,10 +14,20 @@
  * limitations under the License.
  */
 
-pub use session::{Packet, Session};
+pub(crate) use drop_reasons::ClientDrops;
+pub use packet_rates::{PacketCounts, SIZE_BUCKETS as PACKET_SIZE_BUCKETS};
+pub(crate) use packet_size_limit::is_message_too_large;
+pub use packet_size_limit::PacketSizeLimit;
+pub use session::{Packet, Session, SessionArgs};
+pub use session_key::{ClientKey, SessionKey};
 pub use session_manager::SESSION_TIMEOUT_SECONDS;
 
+mod drop_reasons;
 pub(crate) mod error;
 pub(crate) mod metrics;
+mod packet_rates;
+mod packet_size_limit;
 mod session;
+mod session_key;
 pub(crate) mod session_manager;
+mod upstream_socket;
@@ -0,0 +1,176 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::filters::DropReason;
+use crate::proxy::sessions::ClientKey;
+
+/// How many of the reasons the most packets were dropped for are reported
+/// for a session.
+pub const TOP_DROP_REASONS: usize = 5;
+
+/// The fewest clients that the clients whose sessions have all closed are
+/// removed at.
+const MIN_PRUNED_CLIENTS: usize = 1024;
+
+/// Counts the packets dropped by the filter chain for a session by the
+/// reason they were dropped for.
+#[derive(Default)]
+pub struct DropReasons(Mutex<HashMap<DropReason, u64>>);
+
+impl DropReasons {
+    /// Counts a packet dropped for `reason`.
+    pub fn record(&self, reason: DropReason) {
+        *self.0.lock().entry(reason).or_insert(0) += 1;
+    }
+
+    /// Returns the [`TOP_DROP_REASONS`] reasons the most packets were
+    /// dropped for along with how many were, most frequent first.
+    pub fn top(&self) -> Vec<(DropReason, u64)> {
+        let mut reasons = self
+            .0
+            .lock()
+            .iter()
+            .map(|(reason, count)| (reason.clone(), *count))
+            .collect::<Vec<_>>();
+        reasons.sort_by(|(a, a_count), (b, b_count)| {
+            b_count
+                .cmp(a_count)
+                .then_with(|| a.filter.cmp(&b.filter))
+                .then_with(|| a.code.cmp(b.code))
+        });
+        reasons.truncate(TOP_DROP_REASONS);
+        reasons
+    }
+}
+
+/// The drop reasons of the sessions of each client, so that the packets the
+/// filter chain drops can be counted against the sessions of their client
+/// without looking them up in the session map.
+#[derive(Default)]
+pub(crate) struct ClientDrops {
+    clients: RwLock<HashMap<ClientKey, Vec<Weak<DropReasons>>>>,
+    /// The number of clients left after the clients whose sessions have all
+    /// closed were last removed.
+    pruned_len: AtomicUsize,
+}
+
+impl ClientDrops {
+    /// Counts the dropped packets of `client` against `drop_reasons`, those
+    /// of one of its sessions, until the session closes.
+    pub(crate) fn register(&self, client: ClientKey, drop_reasons: &Arc<DropReasons>) {
+        let mut clients = self.clients.write();
+        // The clients whose sessions have all closed are removed once the
+        // number of clients has doubled since they were last removed.
+        let pruned_len = self.pruned_len.load(Ordering::Relaxed);
+        if clients.len() >= 2 * pruned_len.max(MIN_PRUNED_CLIENTS) {
+            clients.retain(|_, sessions| {
+                sessions.retain(|session| session.strong_count() > 0);
+                !sessions.is_empty()
+            });
+            self.pruned_len.store(clients.len(), Ordering::Relaxed);
+        }
+        let sessions = clients.entry(client).or_default();
+        sessions.retain(|session| session.strong_count() > 0);
+        sessions.push(Arc::downgrade(drop_reasons));
+    }
+
+    /// Counts a packet of `client` dropped for `reason` against each of its
+    /// sessions, returning whether it has any.
+    pub(crate) fn record(&self, client: &ClientKey, reason: &DropReason) -> bool {
+        let clients = self.clients.read();
+        let mut found = false;
+        for drop_reasons in clients
+            .get(client)
+            .into_iter()
+            .flatten()
+            .filter_map(Weak::upgrade)
+        {
+            drop_reasons.record(reason.clone());
+            found = true;
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{ClientDrops, DropReasons, TOP_DROP_REASONS};
+    use crate::filters::DropReason;
+    use crate::proxy::sessions::ClientKey;
+
+    fn reason(filter: &str, code: &'static str) -> DropReason {
+        DropReason {
+            filter: filter.into(),
+            code,
+        }
+    }
+
+    #[test]
+    fn top() {
+        let drop_reasons = DropReasons::default();
+        assert!(drop_reasons.top().is_empty());
+
+        drop_reasons.record(reason("a", "Rejected"));
+        drop_reasons.record(reason("b", "Rejected"));
+        drop_reasons.record(reason("b", "Rejected"));
+        drop_reasons.record(reason("b", "Invalid"));
+        assert_eq!(
+            vec![
+                (reason("b", "Rejected"), 2),
+                (reason("a", "Rejected"), 1),
+                (reason("b", "Invalid"), 1),
+            ],
+            drop_reasons.top()
+        );
+
+        for i in 0..TOP_DROP_REASONS {
+            drop_reasons.record(reason(&i.to_string(), "Rejected"));
+        }
+        assert_eq!(TOP_DROP_REASONS, drop_reasons.top().len());
+        assert_eq!((reason("b", "Rejected"), 2), drop_reasons.top()[0]);
+    }
+
+    #[test]
+    fn client_drops() {
+        let client_drops = ClientDrops::default();
+        let client = ClientKey::Address("127.0.0.1:7000".parse().unwrap());
+        let other = ClientKey::Address("127.0.0.1:7001".parse().unwrap());
+        let (first, second) = (Arc::default(), Arc::default());
+        client_drops.register(client.clone(), &first);
+        client_drops.register(client.clone(), &second);
+
+        // Drops are counted against each of the client's sessions.
+        assert!(client_drops.record(&client, &reason("a", "Rejected")));
+        assert!(!client_drops.record(&other, &reason("a", "Rejected")));
+        assert_eq!(vec![(reason("a", "Rejected"), 1)], first.top());
+        assert_eq!(vec![(reason("a", "Rejected"), 1)], second.top());
+
+        // Until they close.
+        drop(first);
+        assert!(client_drops.record(&client, &reason("a", "Rejected")));
+        assert_eq!(vec![(reason("a", "Rejected"), 2)], second.top());
+        drop(second);
+        assert!(!client_drops.record(&client, &reason("a", "Rejected")));
+    }
+}
@@ -19,6 +19,7 @@ use std::fmt::{self, Display, Formatter};
 #[derive(Debug)]
 pub enum Error {
     BindUdpSocket(tokio::io::Error),
+    ConfigureUdpSocket(std::io::Error),
     SendToDst(std::io::Error),
     UpdateSessionExpiration(String),
 }
@@ -29,6 +30,9 @@ impl Display for Error {
             Error::BindUdpSocket(inner) => {
                 write!(f, "failed to bind to UDP socket on address: {}", inner)
             }
+            Error::ConfigureUdpSocket(inner) => {
+                write!(f, "failed to configure UDP socket: {}", inner)
+            }
             Error::SendToDst(inner) => write!(
                 f,
                 "failed to send a packet to the destination address: {}",
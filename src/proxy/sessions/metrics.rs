@@ -14,27 +14,132 @@
  * limitations under the License.
  */
 
-use crate::metrics::{histogram_opts, opts, CollectorExt};
+use std::io;
+use std::net::SocketAddr;
+
+use crate::metrics::{
+    histogram_opts, latency_buckets, opts, packet_size_buckets, CollectorExt,
+};
 use prometheus::core::{AtomicI64, AtomicU64, GenericCounter, GenericGauge};
-use prometheus::{Histogram, IntCounter, IntGauge, Registry, Result as MetricsResult};
+use prometheus::{
+    Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry,
+    Result as MetricsResult,
+};
+
+use super::packet_size_limit::is_message_too_large;
+
+/// The label of the metrics of the clients in an experiment's variant.
+const VARIANT_LABEL: &str = "variant";
 
 #[derive(Clone)]
 pub struct Metrics {
     pub active_sessions: GenericGauge<AtomicI64>,
+    pub peak_active_sessions: GenericGauge<AtomicI64>,
     pub sessions_total: GenericCounter<AtomicU64>,
+    pub sessions_rebound_total: GenericCounter<AtomicU64>,
+    pub sessions_expired_ttl: GenericCounter<AtomicU64>,
+    pub sessions_expired_endpoint_removed: GenericCounter<AtomicU64>,
+    pub sessions_expired_error: GenericCounter<AtomicU64>,
+    pub sessions_expired_no_response: GenericCounter<AtomicU64>,
+    pub sessions_expired_resource_limit: GenericCounter<AtomicU64>,
+    pub sessions_expired_admin: GenericCounter<AtomicU64>,
     pub rx_bytes_total: GenericCounter<AtomicU64>,
     pub tx_bytes_total: GenericCounter<AtomicU64>,
     pub rx_packets_total: GenericCounter<AtomicU64>,
+    pub rx_response_only_packets_total: GenericCounter<AtomicU64>,
     pub tx_packets_total: GenericCounter<AtomicU64>,
     pub rx_errors_total: GenericCounter<AtomicU64>,
     pub tx_errors_total: GenericCounter<AtomicU64>,
+    pub tx_message_too_large_total: IntCounterVec,
+    pub tx_queue_full_total: GenericCounter<AtomicU64>,
+    pub packets_dropped_compute_pool_full: GenericCounter<AtomicU64>,
     pub packets_dropped_total: GenericCounter<AtomicU64>,
+    pub upstream_packets_oversized_total: GenericCounter<AtomicU64>,
+    pub downstream_packets_oversized_total: GenericCounter<AtomicU64>,
     pub duration_secs: Histogram,
+    pub rx_packet_size_bytes: Histogram,
+    pub tx_packet_size_bytes: Histogram,
+    pub recv_buffer_bytes: GenericGauge<AtomicI64>,
+    pub recv_buffer_compactions_total: GenericCounter<AtomicU64>,
+    pub map_lookup_duration_seconds: Histogram,
+    pub map_insert_duration_seconds: Histogram,
+    pub map_prune_duration_seconds: Histogram,
+    pub idle_downstream_sessions: GenericGauge<AtomicI64>,
+    pub idle_upstream_sessions: GenericGauge<AtomicI64>,
+    /// How long the packets of the session's client take to be read. Both it
+    /// and `packets_dropped_total` are labelled with the experiment variant
+    /// of the session's client, which is empty if it isn't in one.
+    pub read_latency_seconds: Histogram,
+    packets_dropped_by_variant: IntCounterVec,
+    read_latency_seconds_by_variant: HistogramVec,
 }
 
 impl Metrics {
     pub fn new(registry: &Registry) -> MetricsResult<Self> {
         let subsystem = "session";
+        let packets_oversized_total = IntCounterVec::new(
+            opts(
+                "packets_oversized_total",
+                subsystem,
+                "Total number of packets exceeding the maximum packet size, as received or after \
+                 filtering",
+            ),
+            &["direction"],
+        )?
+        .register_if_not_exists(registry)?;
+        let expired_total = IntCounterVec::new(
+            opts(
+                "expired_total",
+                subsystem,
+                "Total number of sessions removed. labels: reason.",
+            ),
+            &["reason"],
+        )?
+        .register_if_not_exists(registry)?;
+        let map_operation_duration_seconds = HistogramVec::new(
+            histogram_opts(
+                "map_operation_duration_seconds",
+                subsystem,
+                "Seconds taken by an operation on the session map, including waiting for its lock. labels: operation.",
+                Some(latency_buckets(&[
+                    0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01,
+                    0.05, 0.1, 0.5, 1.0,
+                ])),
+            ),
+            &["operation"],
+        )?
+        .register_if_not_exists(registry)?;
+        let idle = IntGaugeVec::new(
+            opts(
+                "idle",
+                subsystem,
+                "Number of sessions that have not received a packet recently. labels: direction.",
+            ),
+            &["direction"],
+        )?
+        .register_if_not_exists(registry)?;
+        let packets_dropped_by_variant = IntCounterVec::new(
+            opts(
+                "packets_dropped_total",
+                subsystem,
+                "Total number of dropped packets. labels: variant.",
+            ),
+            &[VARIANT_LABEL],
+        )?
+        .register_if_not_exists(registry)?;
+        let read_latency_seconds_by_variant = HistogramVec::new(
+            histogram_opts(
+                "read_latency_seconds",
+                subsystem,
+                "Seconds between a packet being received from a downstream client and the filter \
+                 chain finishing reading it. labels: variant.",
+                Some(latency_buckets(&[
+                    0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+                ])),
+            ),
+            &[VARIANT_LABEL],
+        )?
+        .register_if_not_exists(registry)?;
         Ok(Self {
             active_sessions: IntGauge::with_opts(opts(
                 "active",
@@ -42,12 +147,33 @@ impl Metrics {
                 "Number of sessions currently active",
             ))?
             .register_if_not_exists(registry)?,
+            peak_active_sessions: IntGauge::with_opts(opts(
+                "peak_active",
+                subsystem,
+                "Highest number of sessions that have been active at the same time",
+            ))?
+            .register_if_not_exists(registry)?,
             sessions_total: IntCounter::with_opts(opts(
                 "total",
                 subsystem,
                 "Total number of established sessions",
             ))?
             .register_if_not_exists(registry)?,
+            sessions_rebound_total: IntCounter::with_opts(opts(
+                "rebound_total",
+                subsystem,
+                "Total number of times a session's client address changed",
+            ))?
+            .register_if_not_exists(registry)?,
+            sessions_expired_ttl: expired_total.get_metric_with_label_values(&["TTL"])?,
+            sessions_expired_endpoint_removed: expired_total
+                .get_metric_with_label_values(&["EndpointRemoved"])?,
+            sessions_expired_error: expired_total.get_metric_with_label_values(&["Error"])?,
+            sessions_expired_no_response: expired_total
+                .get_metric_with_label_values(&["NoResponse"])?,
+            sessions_expired_resource_limit: expired_total
+                .get_metric_with_label_values(&["ResourceLimit"])?,
+            sessions_expired_admin: expired_total.get_metric_with_label_values(&["Admin"])?,
             rx_bytes_total: IntCounter::with_opts(opts(
                 "rx_bytes_total",
                 subsystem,
@@ -66,18 +192,24 @@ impl Metrics {
                 "Total number of packets received",
             ))?
             .register_if_not_exists(registry)?,
-            tx_packets_total: IntCounter::with_opts(opts(
-                "tx_packets_total",
+            rx_response_only_packets_total: IntCounter::with_opts(opts(
+                "rx_response_only_packets_total",
                 subsystem,
-                "Total number of packets sent",
+                "Total number of packets received from response-only endpoints",
             ))?
             .register_if_not_exists(registry)?,
-            packets_dropped_total: IntCounter::with_opts(opts(
-                "packets_dropped_total",
+            tx_packets_total: IntCounter::with_opts(opts(
+                "tx_packets_total",
                 subsystem,
-                "Total number of dropped packets",
+                "Total number of packets sent",
             ))?
             .register_if_not_exists(registry)?,
+            packets_dropped_total: packets_dropped_by_variant
+                .get_metric_with_label_values(&[""])?,
+            upstream_packets_oversized_total: packets_oversized_total
+                .get_metric_with_label_values(&["upstream"])?,
+            downstream_packets_oversized_total: packets_oversized_total
+                .get_metric_with_label_values(&["downstream"])?,
             rx_errors_total: IntCounter::with_opts(opts(
                 "rx_errors_total",
                 subsystem,
@@ -90,6 +222,29 @@ impl Metrics {
                 "Total number of errors encountered while sending a packet",
             ))?
             .register_if_not_exists(registry)?,
+            tx_message_too_large_total: IntCounterVec::new(
+                opts(
+                    "tx_message_too_large_total",
+                    subsystem,
+                    "Total number of packets that couldn't be sent to an endpoint as they were \
+                     too large for the path to it. labels: endpoint.",
+                ),
+                &["endpoint"],
+            )?
+            .register_if_not_exists(registry)?,
+            tx_queue_full_total: IntCounter::with_opts(opts(
+                "tx_queue_full_total",
+                subsystem,
+                "Total number of packets dropped as their session's send queue was full",
+            ))?
+            .register_if_not_exists(registry)?,
+            packets_dropped_compute_pool_full: IntCounter::with_opts(opts(
+                "packets_dropped_compute_pool_full_total",
+                subsystem,
+                "Total number of packets received from an endpoint that were dropped as the \
+                 compute pool's queue was full",
+            ))?
+            .register_if_not_exists(registry)?,
             duration_secs: Histogram::with_opts(histogram_opts(
                 "duration_secs",
                 subsystem,
@@ -99,6 +254,83 @@ impl Metrics {
                 ]),
             ))?
             .register_if_not_exists(registry)?,
+            rx_packet_size_bytes: Histogram::with_opts(histogram_opts(
+                "rx_packet_size_bytes",
+                subsystem,
+                "Size in bytes of packets received",
+                Some(packet_size_buckets()),
+            ))?
+            .register_if_not_exists(registry)?,
+            tx_packet_size_bytes: Histogram::with_opts(histogram_opts(
+                "tx_packet_size_bytes",
+                subsystem,
+                "Size in bytes of packets sent",
+                Some(packet_size_buckets()),
+            ))?
+            .register_if_not_exists(registry)?,
+            recv_buffer_bytes: IntGauge::with_opts(opts(
+                "recv_buffer_bytes",
+                subsystem,
+                "Total size in bytes of the receive buffers held by sessions",
+            ))?
+            .register_if_not_exists(registry)?,
+            recv_buffer_compactions_total: IntCounter::with_opts(opts(
+                "recv_buffer_compactions_total",
+                subsystem,
+                "Total number of receive buffers released by idle sessions",
+            ))?
+            .register_if_not_exists(registry)?,
+            map_lookup_duration_seconds: map_operation_duration_seconds
+                .get_metric_with_label_values(&["lookup"])?,
+            map_insert_duration_seconds: map_operation_duration_seconds
+                .get_metric_with_label_values(&["insert"])?,
+            map_prune_duration_seconds: map_operation_duration_seconds
+                .get_metric_with_label_values(&["prune"])?,
+            idle_downstream_sessions: idle.get_metric_with_label_values(&["downstream"])?,
+            idle_upstream_sessions: idle.get_metric_with_label_values(&["upstream"])?,
+            read_latency_seconds: read_latency_seconds_by_variant
+                .get_metric_with_label_values(&[""])?,
+            packets_dropped_by_variant,
+            read_latency_seconds_by_variant,
         })
     }
+
+    /// Returns the metrics of the sessions of clients in the experiment's
+    /// variant named `variant`, or of clients that aren't in an experiment
+    /// if `None`.
+    pub fn for_variant(&self, variant: Option<&str>) -> Self {
+        let variant = match variant {
+            Some(variant) => variant,
+            None => return self.clone(),
+        };
+        Self {
+            packets_dropped_total: self
+                .packets_dropped_by_variant
+                .with_label_values(&[variant]),
+            read_latency_seconds: self.read_latency(Some(variant)),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the histogram of the read latency of the clients of
+    /// `variant`, or of the clients outside experiments if `None`.
+    pub fn read_latency(&self, variant: Option<&str>) -> Histogram {
+        match variant {
+            Some(variant) => self
+                .read_latency_seconds_by_variant
+                .with_label_values(&[variant]),
+            None => self.read_latency_seconds.clone(),
+        }
+    }
+
+    /// Records an error sending a packet to the endpoint at `endpoint`,
+    /// counting it against the endpoint if the packet was too large.
+    pub fn record_tx_error(&self, endpoint: SocketAddr, err: &io::Error) {
+        self.tx_errors_total.inc();
+        if is_message_too_large(err) {
+            self.tx_message_too_large_total
+                .with_label_values(&[&endpoint.to_string()])
+                .inc();
+        }
+    }
 }
@@ -0,0 +1,143 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The upper bounds, in bytes, of the packet size buckets. Packets larger
+/// than the last bound are counted in a final bucket.
+pub const SIZE_BUCKETS: [usize; 6] = [64, 128, 256, 512, 1024, 1500];
+
+/// Counts the packets a session receives in one direction, and their
+/// sizes, over the lifetime of the session. Rates over a period are worked
+/// out from the difference between two [`PacketCounts`] taken at either end
+/// of it.
+#[derive(Default)]
+pub struct PacketRates {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    squared_bytes: AtomicU64,
+    sizes: [AtomicU64; SIZE_BUCKETS.len() + 1],
+}
+
+/// The counts of a [`PacketRates`] at a point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PacketCounts {
+    pub packets: u64,
+    pub bytes: u64,
+    /// The sum of the squares of the packets' sizes, from which the
+    /// variance of the sizes is worked out.
+    pub squared_bytes: u64,
+    /// The number of packets in each size bucket of [`SIZE_BUCKETS`].
+    pub sizes: [u64; SIZE_BUCKETS.len() + 1],
+}
+
+impl PacketRates {
+    /// Counts a packet of `size` bytes.
+    pub fn record(&self, size: usize) {
+        let size = size as u64;
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        self.squared_bytes.fetch_add(size * size, Ordering::Relaxed);
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|bound| size <= *bound as u64)
+            .unwrap_or(SIZE_BUCKETS.len());
+        self.sizes[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current counts.
+    pub fn counts(&self) -> PacketCounts {
+        let mut sizes = [0; SIZE_BUCKETS.len() + 1];
+        for (count, bucket) in sizes.iter_mut().zip(self.sizes.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        PacketCounts {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            squared_bytes: self.squared_bytes.load(Ordering::Relaxed),
+            sizes,
+        }
+    }
+}
+
+impl PacketCounts {
+    /// Returns the packets counted since `earlier` was taken.
+    pub fn since(&self, earlier: &PacketCounts) -> PacketCounts {
+        let mut sizes = self.sizes;
+        for (count, earlier) in sizes.iter_mut().zip(earlier.sizes.iter()) {
+            *count = count.wrapping_sub(*earlier);
+        }
+        PacketCounts {
+            packets: self.packets.wrapping_sub(earlier.packets),
+            bytes: self.bytes.wrapping_sub(earlier.bytes),
+            squared_bytes: self.squared_bytes.wrapping_sub(earlier.squared_bytes),
+            sizes,
+        }
+    }
+
+    /// Returns the mean size of the packets in bytes, or 0 if there are
+    /// none.
+    pub fn mean_size(&self) -> f64 {
+        if self.packets == 0 {
+            return 0.0;
+        }
+        self.bytes as f64 / self.packets as f64
+    }
+
+    /// Returns the standard deviation of the sizes of the packets in bytes,
+    /// or 0 if there are none.
+    pub fn size_stddev(&self) -> f64 {
+        if self.packets == 0 {
+            return 0.0;
+        }
+        let mean = self.mean_size();
+        let variance = self.squared_bytes as f64 / self.packets as f64 - mean * mean;
+        variance.max(0.0).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PacketCounts, PacketRates, SIZE_BUCKETS};
+
+    #[test]
+    fn counts() {
+        let rates = PacketRates::default();
+        assert_eq!(PacketCounts::default(), rates.counts());
+        assert_eq!(0.0, rates.counts().mean_size());
+        assert_eq!(0.0, rates.counts().size_stddev());
+
+        rates.record(10);
+        rates.record(30);
+        let earlier = rates.counts();
+        assert_eq!(2, earlier.packets);
+        assert_eq!(40, earlier.bytes);
+        assert_eq!(20.0, earlier.mean_size());
+        assert_eq!(10.0, earlier.size_stddev());
+        assert_eq!(2, earlier.sizes[0]);
+
+        rates.record(100);
+        rates.record(2000);
+        let since = rates.counts().since(&earlier);
+        assert_eq!(2, since.packets);
+        assert_eq!(2100, since.bytes);
+        assert_eq!(1050.0, since.mean_size());
+        assert_eq!(950.0, since.size_stddev());
+        assert_eq!(0, since.sizes[0]);
+        assert_eq!(1, since.sizes[1]);
+        assert_eq!(1, since.sizes[SIZE_BUCKETS.len()]);
+    }
+}
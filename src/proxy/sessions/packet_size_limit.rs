@@ -0,0 +1,194 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+
+use prometheus::core::{AtomicU64, GenericCounter};
+
+use crate::config::{OversizedPacketPolicy, DEFAULT_MAX_PACKET_SIZE, MAX_DATAGRAM_SIZE};
+
+/// Enforces the maximum size of packets, both as they are received and as
+/// they are produced by the filter chain before they are sent.
+#[derive(Clone, Copy, Debug)]
+pub struct PacketSizeLimit {
+    max_size: usize,
+    policy: OversizedPacketPolicy,
+}
+
+impl PacketSizeLimit {
+    pub fn new(max_size: usize, policy: OversizedPacketPolicy) -> Self {
+        Self { max_size, policy }
+    }
+
+    /// Applies the oversized packet policy to `contents` if it exceeds the
+    /// maximum size, incrementing `oversized_total` when it does. Returns
+    /// `None` if the packet should be dropped.
+    pub fn apply(
+        &self,
+        mut contents: Vec<u8>,
+        oversized_total: &GenericCounter<AtomicU64>,
+    ) -> Option<Vec<u8>> {
+        let len = self.limit_len(contents.len(), oversized_total)?;
+        contents.truncate(len);
+        Some(contents)
+    }
+
+    /// Like [`PacketSizeLimit::apply`], but for a packet of `len` bytes.
+    /// Returns how many bytes of the packet to keep.
+    pub fn limit_len(
+        &self,
+        len: usize,
+        oversized_total: &GenericCounter<AtomicU64>,
+    ) -> Option<usize> {
+        if len <= self.max_size {
+            return Some(len);
+        }
+
+        oversized_total.inc();
+        match self.policy {
+            OversizedPacketPolicy::Drop => None,
+            OversizedPacketPolicy::Truncate => Some(self.max_size),
+            OversizedPacketPolicy::Send => Some(len),
+        }
+    }
+
+    /// Returns the size of the buffers that packets are received into. They
+    /// hold one byte more than the maximum size, so that larger packets are
+    /// detected rather than silently truncated by the kernel, unless
+    /// oversized packets are sent as they are.
+    pub fn recv_buffer_size(&self) -> usize {
+        match self.policy {
+            OversizedPacketPolicy::Send => MAX_DATAGRAM_SIZE,
+            _ => (self.max_size + 1).min(MAX_DATAGRAM_SIZE),
+        }
+    }
+}
+
+/// Returns whether `err` is the error returned when sending a packet that is
+/// too large for the socket or the path to its destination (`EMSGSIZE`).
+#[cfg(unix)]
+pub(crate) fn is_message_too_large(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+/// Windows returns `WSAEMSGSIZE` instead.
+#[cfg(not(unix))]
+pub(crate) fn is_message_too_large(err: &io::Error) -> bool {
+    cfg!(windows) && err.raw_os_error() == Some(10040)
+}
+
+impl Default for PacketSizeLimit {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PACKET_SIZE, OversizedPacketPolicy::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::IntCounter;
+
+    use super::PacketSizeLimit;
+    use crate::config::{OversizedPacketPolicy, MAX_DATAGRAM_SIZE};
+
+    #[test]
+    fn apply() {
+        struct Case {
+            policy: OversizedPacketPolicy,
+            contents: Vec<u8>,
+            expected: Option<Vec<u8>>,
+            expected_oversized: u64,
+        }
+
+        let cases = vec![
+            Case {
+                policy: OversizedPacketPolicy::Drop,
+                contents: vec![1, 2, 3],
+                expected: Some(vec![1, 2, 3]),
+                expected_oversized: 0,
+            },
+            Case {
+                policy: OversizedPacketPolicy::Drop,
+                contents: vec![1, 2, 3, 4, 5],
+                expected: None,
+                expected_oversized: 1,
+            },
+            Case {
+                policy: OversizedPacketPolicy::Truncate,
+                contents: vec![1, 2, 3, 4, 5],
+                expected: Some(vec![1, 2, 3]),
+                expected_oversized: 1,
+            },
+            Case {
+                policy: OversizedPacketPolicy::Send,
+                contents: vec![1, 2, 3, 4, 5],
+                expected: Some(vec![1, 2, 3, 4, 5]),
+                expected_oversized: 1,
+            },
+        ];
+
+        for case in cases {
+            let oversized_total = IntCounter::new("oversized", "oversized").unwrap();
+            let limit = PacketSizeLimit::new(3, case.policy);
+
+            assert_eq!(
+                case.expected,
+                limit.apply(case.contents, &oversized_total),
+                "policy: {:?}",
+                case.policy
+            );
+            assert_eq!(case.expected_oversized, oversized_total.get());
+        }
+    }
+
+    #[test]
+    fn recv_buffer_size() {
+        let limit = PacketSizeLimit::new(1200, OversizedPacketPolicy::Drop);
+        assert_eq!(1201, limit.recv_buffer_size());
+        let limit = PacketSizeLimit::new(1200, OversizedPacketPolicy::Truncate);
+        assert_eq!(1201, limit.recv_buffer_size());
+        let limit = PacketSizeLimit::new(1200, OversizedPacketPolicy::Send);
+        assert_eq!(MAX_DATAGRAM_SIZE, limit.recv_buffer_size());
+        let limit = PacketSizeLimit::new(MAX_DATAGRAM_SIZE, OversizedPacketPolicy::Drop);
+        assert_eq!(MAX_DATAGRAM_SIZE, limit.recv_buffer_size());
+    }
+
+    #[test]
+    fn limit_len() {
+        let oversized_total = IntCounter::new("oversized", "oversized").unwrap();
+        let limit = PacketSizeLimit::new(3, OversizedPacketPolicy::Truncate);
+        assert_eq!(Some(2), limit.limit_len(2, &oversized_total));
+        assert_eq!(Some(3), limit.limit_len(4, &oversized_total));
+        assert_eq!(1, oversized_total.get());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn message_too_large() {
+        use super::is_message_too_large;
+        use std::io;
+
+        assert!(is_message_too_large(&io::Error::from_raw_os_error(
+            libc::EMSGSIZE
+        )));
+        assert!(!is_message_too_large(&io::Error::from_raw_os_error(
+            libc::ECONNREFUSED
+        )));
+        assert!(!is_message_too_large(&io::Error::new(
+            io::ErrorKind::Other,
+            "other"
+        )));
+    }
+}
@@ -14,25 +14,43 @@
  * limitations under the License.
  */
 
+use std::future::Future;
+use std::io;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use slog::{debug, error, o, trace, warn, Logger};
+use parking_lot::RwLock;
+use slog::{debug, error, info, o, trace, warn, Logger};
 use tokio::net::UdpSocket;
 use tokio::select;
-use tokio::sync::{mpsc, watch};
-use tokio::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time::{self, Duration, Instant};
 
-use crate::cluster::Endpoint;
-use crate::filters::{manager::SharedFilterManager, Filter, WriteContext};
+use crate::cluster::{Endpoint, EndpointHealth};
+use crate::config::{Endpoints, UpstreamSocket};
+use crate::faults::FaultInjector;
+use crate::filters::{manager::SharedFilterManager, DropReason, Priority, WriteContext};
+use crate::proxy::relay;
+use crate::proxy::sessions::drop_reasons::DropReasons;
 use crate::proxy::sessions::error::Error;
 use crate::proxy::sessions::metrics::Metrics;
+use crate::proxy::sessions::packet_rates::PacketRates;
+use crate::proxy::sessions::{
+    upstream_socket, ClientKey, PacketCounts, PacketSizeLimit, SessionKey,
+};
+use crate::proxy::tunnel::Connector as TunnelConnector;
+use crate::proxy::{ComputePool, Scheduler, Tap, TapDirection};
+use crate::supervisor::Supervisor;
 use crate::utils::debug;
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// How long a session can go without receiving a packet before its receive
+/// buffer is released.
+const RECV_BUFFER_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Session encapsulates a UDP stream session
 pub struct Session {
     log: Logger,
@@ -40,17 +58,170 @@ pub struct Session {
     filter_manager: SharedFilterManager,
     /// created_at is time at which the session was created
     created_at: Instant,
-    socket: Arc<UdpSocket>,
+    /// How packets are sent to dest.
+    upstream: Upstream,
     /// dest is where to send data to
     dest: Endpoint,
-    /// from is the original sender
-    from: SocketAddr,
+    /// from is the client's current address, which changes if the client
+    /// is keyed by something other than its address and moves.
+    from: Arc<RwLock<SocketAddr>>,
+    /// What the session's client is identified by.
+    client_key: ClientKey,
+    /// The header carrying the client's connection id, which is sent at the
+    /// start of the packets sent to the client until it sends the id back.
+    connection_id_header: Arc<RwLock<Option<Vec<u8>>>>,
+    /// Further downstream addresses that write filters can send packets to,
+    /// e.g. those of spectators.
+    downstreams: Arc<RwLock<Vec<SocketAddr>>>,
     /// The time at which the session is considered expired and can be removed.
     expiration: Arc<AtomicU64>,
+    /// Set once the session's socket or tunnel has failed, so that it is removed.
+    failed: Arc<AtomicBool>,
+    /// The unix time in milliseconds at which a packet was last received
+    /// from the client, or 0 if none has been.
+    last_received_downstream: Arc<AtomicU64>,
+    /// The unix time in milliseconds at which a packet was last received
+    /// from the endpoint, or 0 if none has been.
+    last_received_upstream: Arc<AtomicU64>,
+    /// Limits the size of packets produced by the filter chain.
+    packet_size_limit: PacketSizeLimit,
+    /// Runs filter chains containing heavy filters, if enabled.
+    compute_pool: Option<Arc<ComputePool>>,
+    /// The endpoints that packets are accepted from but never sent to, if
+    /// any.
+    response_only_endpoints: Option<Arc<Endpoints>>,
+    /// Tracks the health of dest from the packets sent to and received from
+    /// it, if enabled.
+    endpoint_health: Option<EndpointHealth>,
+    /// Counts the packets dropped by the filter chain by reason.
+    drop_reasons: Arc<DropReasons>,
+    /// Counts the packets received from the client.
+    downstream_rates: PacketRates,
+    /// Counts the packets received from the endpoint.
+    upstream_rates: Arc<PacketRates>,
+    /// Streams copies of the session's packets to the watchers of the tap
+    /// service, if enabled.
+    tap: Option<Arc<Tap>>,
+    /// Injects faults into the session, if enabled.
+    faults: Option<Arc<FaultInjector>>,
+    /// The queue of packets that a single task sends to dest in order, if
+    /// sends are ordered.
+    send_queue: Option<mpsc::Sender<Vec<u8>>>,
     /// a channel to broadcast on if we are shutting down this Session
     shutdown_tx: watch::Sender<()>,
 }
 
+/// Contains the arguments to create a new [`Session`].
+pub struct SessionArgs {
+    pub metrics: Metrics,
+    pub filter_manager: SharedFilterManager,
+    /// The address of the client the session is created for.
+    pub from: SocketAddr,
+    /// What the client is identified by, if not by its address `from`.
+    pub client_key: Option<ClientKey>,
+    /// If set, the header carrying the connection id issued to the client,
+    /// which is sent at the start of the packets sent to the client until
+    /// the session is told that the client sent the id back.
+    pub connection_id_header: Option<Vec<u8>>,
+    /// The endpoint the session sends packets to.
+    pub dest: Endpoint,
+    /// The channel that packets for the client are sent on.
+    pub sender: mpsc::Sender<Packet>,
+    pub ttl: Duration,
+    pub packet_size_limit: PacketSizeLimit,
+    /// Runs filter chains containing heavy filters, if enabled.
+    pub compute_pool: Option<Arc<ComputePool>>,
+    /// Options for the socket that packets are sent to `dest` from.
+    pub upstream_socket: UpstreamSocket,
+    /// If set, packets are sent to `dest` through a tunnel to a peer proxy
+    /// rather than over UDP.
+    pub tunnel: Option<Arc<TunnelConnector>>,
+    /// The endpoints that packets are accepted from but never sent to, if
+    /// any. Packets received from them are attributed to them, rather than
+    /// to `dest`.
+    pub response_only_endpoints: Option<Arc<Endpoints>>,
+    /// If set, the outcome of sending packets to `dest`, and the packets
+    /// received from it, are recorded to track its health.
+    pub endpoint_health: Option<EndpointHealth>,
+    /// If set, copies of the packets received from `dest` are streamed to
+    /// the watchers of the tap service.
+    pub tap: Option<Arc<Tap>>,
+    /// If set, faults are injected into the session, to test how the proxy
+    /// recovers from them.
+    pub faults: Option<Arc<FaultInjector>>,
+    /// If set, the session's receive loop is restarted if it panics.
+    pub supervisor: Option<Supervisor>,
+    /// If set, packets are queued, up to this many at a time, for a single
+    /// task to send to `dest` in the order they were queued.
+    pub send_queue_size: Option<usize>,
+}
+
+/// How a session sends packets to its endpoint.
+#[derive(Clone)]
+enum Upstream {
+    /// Over UDP, from the session's socket.
+    Socket(Arc<UdpSocket>),
+    /// Through a tunnel to a peer proxy.
+    Tunnel(mpsc::Sender<Vec<u8>>),
+}
+
+impl Upstream {
+    /// Sends `buf` to `dest`, returning the number of bytes sent.
+    async fn send_to(&self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        match self {
+            Upstream::Socket(socket) => socket.send_to(buf, dest).await,
+            // Packets are dropped rather than waiting for a tunnel that
+            // can't keep up.
+            Upstream::Tunnel(packets) => match packets.try_send(buf.to_vec()) {
+                Ok(()) => Ok(buf.len()),
+                Err(mpsc::error::TrySendError::Full(_)) => Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "the tunnel's queue is full",
+                )),
+                Err(mpsc::error::TrySendError::Closed(_)) => Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the tunnel is closed",
+                )),
+            },
+        }
+    }
+}
+
+/// How a session receives packets from its endpoint.
+enum Receiver {
+    /// Over UDP, on the session's socket.
+    Socket(Arc<UdpSocket>),
+    /// Through a tunnel to a peer proxy.
+    Tunnel(mpsc::Receiver<Vec<u8>>),
+}
+
+/// The state that a session's receive loop runs with. It's cloned each time
+/// the loop is restarted.
+#[derive(Clone)]
+struct RecvLoop {
+    log: Logger,
+    from: Arc<RwLock<SocketAddr>>,
+    connection_id_header: Arc<RwLock<Option<Vec<u8>>>>,
+    expiration: Arc<AtomicU64>,
+    failed: Arc<AtomicBool>,
+    last_received_upstream: Arc<AtomicU64>,
+    filter_manager: SharedFilterManager,
+    downstreams: Arc<RwLock<Vec<SocketAddr>>>,
+    endpoint: Endpoint,
+    metrics: Metrics,
+    packet_size_limit: PacketSizeLimit,
+    compute_pool: Option<Arc<ComputePool>>,
+    response_only_endpoints: Option<Arc<Endpoints>>,
+    endpoint_health: Option<EndpointHealth>,
+    drop_reasons: Arc<DropReasons>,
+    upstream_rates: Arc<PacketRates>,
+    tap: Option<Arc<Tap>>,
+    faults: Option<Arc<FaultInjector>>,
+    ttl: Duration,
+    sender: mpsc::Sender<Packet>,
+    shutdown_rx: watch::Receiver<()>,
+}
+
 /// ReceivedPacketContext contains state needed to process a received packet.
 struct ReceivedPacketContext<'a> {
     packet: &'a [u8],
@@ -58,17 +229,50 @@ struct ReceivedPacketContext<'a> {
     endpoint: &'a Endpoint,
     from: SocketAddr,
     to: SocketAddr,
+    /// The header to send at the start of the packets sent to `to`, if any.
+    connection_id_header: Option<Vec<u8>>,
+    downstreams: &'a RwLock<Vec<SocketAddr>>,
+    packet_size_limit: PacketSizeLimit,
+    compute_pool: Option<Arc<ComputePool>>,
+    drop_reasons: &'a DropReasons,
+    tap: Option<&'a Tap>,
+    faults: Option<&'a FaultInjector>,
 }
 
 /// Packet represents a packet that needs to go somewhere
 pub struct Packet {
     dest: SocketAddr,
     contents: Vec<u8>,
+    delay: Duration,
+    priority: Priority,
 }
 
 impl Packet {
     pub fn new(dest: SocketAddr, contents: Vec<u8>) -> Packet {
-        Packet { dest, contents }
+        Packet {
+            dest,
+            contents,
+            delay: Duration::from_secs(0),
+            priority: Priority::default(),
+        }
+    }
+
+    /// Sets how long to wait before sending the packet.
+    pub fn with_delay(self, delay: Duration) -> Packet {
+        Packet { delay, ..self }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Sets the priority with which the packet is sent.
+    pub fn with_priority(self, priority: Priority) -> Packet {
+        Packet { priority, ..self }
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
     }
 
     pub fn dest(&self) -> SocketAddr {
@@ -83,94 +287,114 @@ impl Packet {
 impl Session {
     /// new creates a new Session, and starts the process of receiving udp sockets
     /// from its ephemeral port from endpoint(s)
-    pub async fn new(
-        base: &Logger,
-        metrics: Metrics,
-        filter_manager: SharedFilterManager,
-        from: SocketAddr,
-        dest: Endpoint,
-        sender: mpsc::Sender<Packet>,
-        ttl: Duration,
-    ) -> Result<Self> {
+    pub async fn new(base: &Logger, args: SessionArgs) -> Result<Self> {
+        let SessionArgs {
+            metrics,
+            filter_manager,
+            from,
+            client_key,
+            connection_id_header,
+            dest,
+            sender,
+            ttl,
+            packet_size_limit,
+            compute_pool,
+            upstream_socket,
+            tunnel,
+            response_only_endpoints,
+            endpoint_health,
+            tap,
+            faults,
+            supervisor,
+            send_queue_size,
+        } = args;
         let log = base
             .new(o!("source" => "proxy::Session", "from" => from, "dest_address" => dest.address));
-        let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0);
-        let socket = Arc::new(UdpSocket::bind(addr).await.map_err(Error::BindUdpSocket)?);
+        let (upstream, receiver) = match tunnel {
+            Some(tunnel) => {
+                let (packets_tx, packets_rx) = tunnel.open(&log, dest.address);
+                (Upstream::Tunnel(packets_tx), Receiver::Tunnel(packets_rx))
+            }
+            None => {
+                let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0);
+                let socket = UdpSocket::bind(addr).await.map_err(Error::BindUdpSocket)?;
+                upstream_socket::configure(&socket, upstream_socket)
+                    .map_err(Error::ConfigureUdpSocket)?;
+                let socket = Arc::new(socket);
+                (Upstream::Socket(socket.clone()), Receiver::Socket(socket))
+            }
+        };
         let (shutdown_tx, shutdown_rx) = watch::channel::<()>(());
 
         let expiration = Arc::new(AtomicU64::new(0));
         Self::do_update_expiration(&expiration, ttl)?;
 
-        let s = Session {
+        let mut s = Session {
             metrics,
             log,
             filter_manager,
-            socket: socket.clone(),
-            from,
+            upstream,
+            from: Arc::new(RwLock::new(from)),
+            client_key: client_key.unwrap_or(ClientKey::Address(from)),
+            connection_id_header: Arc::new(RwLock::new(connection_id_header)),
+            downstreams: Arc::new(RwLock::new(Vec::new())),
             dest,
             created_at: Instant::now(),
             expiration,
+            failed: Arc::new(AtomicBool::new(false)),
+            last_received_downstream: Arc::new(AtomicU64::new(0)),
+            last_received_upstream: Arc::new(AtomicU64::new(0)),
+            packet_size_limit,
+            compute_pool,
+            response_only_endpoints,
+            endpoint_health,
+            drop_reasons: Arc::new(DropReasons::default()),
+            downstream_rates: PacketRates::default(),
+            upstream_rates: Arc::new(PacketRates::default()),
+            tap,
+            faults,
+            send_queue: None,
             shutdown_tx,
         };
+        s.send_queue = send_queue_size.map(|queue_size| s.spawn_send_queue(queue_size));
         debug!(s.log, "Session created");
 
         s.metrics.sessions_total.inc();
         s.metrics.active_sessions.inc();
-        s.run(ttl, socket, sender, shutdown_rx);
+        s.recv_loop(ttl, sender, shutdown_rx).spawn(receiver, supervisor);
         Ok(s)
     }
 
-    /// run starts processing received udp packets on its UdpSocket
-    fn run(
+    /// Returns the state that the session's receive loop runs with.
+    fn recv_loop(
         &self,
         ttl: Duration,
-        socket: Arc<UdpSocket>,
-        mut sender: mpsc::Sender<Packet>,
-        mut shutdown_rx: watch::Receiver<()>,
-    ) {
-        let log = self.log.clone();
-        let from = self.from;
-        let expiration = self.expiration.clone();
-        let filter_manager = self.filter_manager.clone();
-        let endpoint = self.dest.clone();
-        let metrics = self.metrics.clone();
-        tokio::spawn(async move {
-            let mut buf: Vec<u8> = vec![0; 65535];
-            loop {
-                debug!(log, "Awaiting incoming packet");
-                select! {
-                    received = socket.recv_from(&mut buf) => {
-                        match received {
-                            Err(err) => {
-                                metrics.rx_errors_total.inc();
-                                error!(log, "Error receiving packet"; "error" => %err);
-                            },
-                            Ok((size, recv_addr)) => {
-                                metrics.rx_bytes_total.inc_by(size as u64);
-                                metrics.rx_packets_total.inc();
-                                Session::process_recv_packet(
-                                    &log,
-                                    &metrics,
-                                    &mut sender,
-                                    &expiration,
-                                    ttl,
-                                    ReceivedPacketContext {
-                                        filter_manager: filter_manager.clone(),
-                                        packet: &buf[..size],
-                                        endpoint: &endpoint,
-                                        from: recv_addr,
-                                        to: from,
-                                    }).await
-                            }
-                        };
-                    }
-                    _ = shutdown_rx.changed() => {
-                        debug!(log, "Closing Session");
-                        return;
-                    }
-                };
-            }
-        });
+        sender: mpsc::Sender<Packet>,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> RecvLoop {
+        RecvLoop {
+            log: self.log.clone(),
+            from: self.from.clone(),
+            connection_id_header: self.connection_id_header.clone(),
+            expiration: self.expiration.clone(),
+            failed: self.failed.clone(),
+            last_received_upstream: self.last_received_upstream.clone(),
+            filter_manager: self.filter_manager.clone(),
+            downstreams: self.downstreams.clone(),
+            endpoint: self.dest.clone(),
+            metrics: self.metrics.clone(),
+            packet_size_limit: self.packet_size_limit,
+            compute_pool: self.compute_pool.clone(),
+            response_only_endpoints: self.response_only_endpoints.clone(),
+            endpoint_health: self.endpoint_health.clone(),
+            drop_reasons: self.drop_reasons.clone(),
+            upstream_rates: self.upstream_rates.clone(),
+            tap: self.tap.clone(),
+            faults: self.faults.clone(),
+            ttl,
+            sender,
+            shutdown_rx,
+        }
     }
 
     /// expiration returns the current expiration Instant value
@@ -178,9 +402,150 @@ impl Session {
         self.expiration.load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// Returns whether the session's socket has failed.
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Returns how long ago the session was created.
+    pub fn age(&self) -> Duration {
+        self.created_at.elapsed()
+    }
+
+    /// Returns when the session last received a packet from its client.
+    pub fn last_received_downstream(&self) -> Option<SystemTime> {
+        load_time(&self.last_received_downstream)
+    }
+
+    /// Returns when the session last received a packet from its endpoint.
+    pub fn last_received_upstream(&self) -> Option<SystemTime> {
+        load_time(&self.last_received_upstream)
+    }
+
+    /// Returns how long the session has gone without receiving a packet from
+    /// its client, or since it was created if it never has.
+    pub fn downstream_idle(&self) -> Duration {
+        self.idle_since(self.last_received_downstream())
+    }
+
+    /// Returns how long the session has gone without receiving a packet from
+    /// its endpoint, or since it was created if it never has.
+    pub fn upstream_idle(&self) -> Duration {
+        self.idle_since(self.last_received_upstream())
+    }
+
+    fn idle_since(&self, last_received: Option<SystemTime>) -> Duration {
+        match last_received {
+            Some(time) => SystemTime::now().duration_since(time).unwrap_or_default(),
+            None => self.age(),
+        }
+    }
+
+    /// Counts a packet from the session's client that was dropped by the
+    /// filter chain for `reason`.
+    pub fn record_drop(&self, reason: DropReason) {
+        self.drop_reasons.record(reason);
+    }
+
+    /// Returns the counts of the packets of the session dropped by the filter
+    /// chain, by the reason they were dropped for.
+    pub(crate) fn drop_reasons(&self) -> &Arc<DropReasons> {
+        &self.drop_reasons
+    }
+
+    /// Returns the reasons the most packets were dropped by the filter chain
+    /// for in either direction, along with how many were, most frequent
+    /// first.
+    pub fn top_drop_reasons(&self) -> Vec<(DropReason, u64)> {
+        self.drop_reasons.top()
+    }
+
+    /// Returns the counts of the packets received from the session's client
+    /// so far.
+    pub fn downstream_packet_counts(&self) -> PacketCounts {
+        self.downstream_rates.counts()
+    }
+
+    /// Returns the counts of the packets received from the session's
+    /// endpoint so far.
+    pub fn upstream_packet_counts(&self) -> PacketCounts {
+        self.upstream_rates.counts()
+    }
+
+    /// Registers `address` as a further downstream of the session, which
+    /// write filters can send packets to. Returns whether it wasn't already
+    /// one.
+    pub fn add_downstream(&self, address: SocketAddr) -> bool {
+        let mut downstreams = self.downstreams.write();
+        if address == self.client() || downstreams.contains(&address) {
+            return false;
+        }
+        downstreams.push(address);
+        true
+    }
+
+    /// Unregisters `address` as a downstream of the session. Returns whether
+    /// it was one.
+    pub fn remove_downstream(&self, address: SocketAddr) -> bool {
+        let mut downstreams = self.downstreams.write();
+        let len = downstreams.len();
+        downstreams.retain(|downstream| *downstream != address);
+        downstreams.len() != len
+    }
+
+    /// Returns the further downstream addresses registered on the session.
+    pub fn downstreams(&self) -> Vec<SocketAddr> {
+        self.downstreams.read().clone()
+    }
+
     /// key returns the key to be used for this session in a SessionMap
-    pub fn key(&self) -> (SocketAddr, SocketAddr) {
-        (self.from, self.dest.address)
+    pub fn key(&self) -> SessionKey {
+        SessionKey {
+            client: self.client_key.clone(),
+            endpoint: self.dest.address,
+        }
+    }
+
+    /// Returns the client's current address.
+    pub fn client(&self) -> SocketAddr {
+        *self.from.read()
+    }
+
+    /// Counts the session as removed through the admin server.
+    pub fn record_admin_removal(&self) {
+        self.metrics.sessions_expired_admin.inc();
+    }
+
+    /// Moves the session to the client's new `address`, so that packets
+    /// for the client are sent there. Returns whether the address changed.
+    pub fn rebind(&self, address: SocketAddr) -> bool {
+        // Only take the write lock when the address changed, as this is
+        // called for every packet from the client.
+        if self.client() == address {
+            return false;
+        }
+        let mut from = self.from.write();
+        if *from == address {
+            return false;
+        }
+        debug!(self.log, "Session rebound to new client address";
+            "previous" => *from, "address" => address);
+        *from = address;
+        self.metrics.sessions_rebound_total.inc();
+        true
+    }
+
+    /// Stops sending the client's connection id at the start of the packets
+    /// sent to it, once the client has sent the id back.
+    pub fn confirm_connection_id(&self) {
+        // Only take the write lock while the id is still being sent, as
+        // this is called for every packet carrying the id.
+        if self.connection_id_header.read().is_none() {
+            return;
+        }
+        if self.connection_id_header.write().take().is_some() {
+            debug!(self.log, "Client confirmed its connection id");
+        }
     }
 
     /// process_recv_packet processes a packet that is received by this session.
@@ -198,6 +563,13 @@ impl Session {
             endpoint,
             from,
             to,
+            connection_id_header,
+            downstreams,
+            packet_size_limit,
+            compute_pool,
+            drop_reasons,
+            tap,
+            faults,
         } = packet_ctx;
 
         trace!(log, "Received packet"; "from" => from,
@@ -212,15 +584,90 @@ impl Session {
             let filter_manager_guard = filter_manager.read();
             filter_manager_guard.get_filter_chain()
         };
-        if let Some(response) =
-            filter_chain.write(WriteContext::new(endpoint, from, to, packet.to_vec()))
-        {
-            if let Err(err) = sender.send(Packet::new(to, response.contents)).await {
+        let downstreams = downstreams.read().clone();
+        let tapped = tap.and_then(|tap| tap.select(TapDirection::Write, to, packet));
+        if let Some(tapped) = &tapped {
+            tapped.pre_filter(Some(endpoint.address), packet);
+        }
+        if let Some(faults) = faults {
+            faults.delay_filters();
+        }
+        let response = match compute_pool {
+            Some(compute_pool) if compute_pool.is_heavy(&filter_chain) => {
+                let endpoint = endpoint.clone();
+                let packet = packet.to_vec();
+                let downstreams = downstreams.clone();
+                let write = move || {
+                    let mut ctx = WriteContext::new(&endpoint, from, to, packet);
+                    ctx.downstreams = &downstreams;
+                    filter_chain.try_write(ctx)
+                };
+                match compute_pool.run(write).await {
+                    Some(response) => response,
+                    None => {
+                        metrics.packets_dropped_compute_pool_full.inc();
+                        return;
+                    }
+                }
+            }
+            _ => {
+                let mut ctx = WriteContext::new(endpoint, from, to, packet.to_vec());
+                ctx.downstreams = &downstreams;
+                filter_chain.try_write(ctx)
+            }
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(reason) => {
+                metrics.packets_dropped_total.inc();
+                if let Some(tapped) = &tapped {
+                    tapped.dropped(Some(endpoint.address), &reason);
+                }
+                drop_reasons.record(reason);
+                return;
+            }
+        };
+
+        for response in response.into_packets() {
+            // Packets are only sent to the session's downstreams, so that a
+            // filter can't have the proxy send packets to arbitrary addresses.
+            if response.to != to && !downstreams.contains(&response.to) {
+                metrics.packets_dropped_total.inc();
+                debug!(log, "Dropping packet for an address that isn't a downstream";
+                    "to" => response.to);
+                continue;
+            }
+
+            let contents = match packet_size_limit
+                .apply(response.contents, &metrics.downstream_packets_oversized_total)
+            {
+                Some(contents) => contents,
+                None => {
+                    metrics.packets_dropped_total.inc();
+                    continue;
+                }
+            };
+            if let Some(tapped) = &tapped {
+                tapped.post_filter(Some(endpoint.address), &contents);
+            }
+            // Only the client is sent its connection id, not the other
+            // downstreams.
+            let contents = match &connection_id_header {
+                Some(header) if response.to == to => {
+                    let mut packet = header.clone();
+                    packet.extend_from_slice(&contents);
+                    packet
+                }
+                _ => contents,
+            };
+
+            let packet = Packet::new(response.to, contents)
+                .with_delay(response.delay)
+                .with_priority(response.priority);
+            if let Err(err) = sender.send(packet).await {
                 metrics.rx_errors_total.inc();
                 error!(log, "Error sending packet to channel"; "error" => %err);
             }
-        } else {
-            metrics.packets_dropped_total.inc();
         }
     }
 
@@ -252,29 +699,415 @@ impl Session {
         Ok(())
     }
 
-    /// Sends a packet to the Session's dest.
+    /// Spawns the task that sends the packets queued by [`Session::send`]
+    /// to dest one at a time, in the order they were queued. The task exits
+    /// once the session, and with it the queue's sender, is dropped.
+    fn spawn_send_queue(&self, queue_size: usize) -> mpsc::Sender<Vec<u8>> {
+        let (packets_tx, mut packets_rx) = mpsc::channel::<Vec<u8>>(queue_size);
+        let log = self.log.clone();
+        let metrics = self.metrics.clone();
+        let upstream = self.upstream.clone();
+        let dest = self.dest.address;
+        let endpoint_health = self.endpoint_health.clone();
+        let faults = self.faults.clone();
+        tokio::spawn(async move {
+            while let Some(packet) = packets_rx.recv().await {
+                let result = send_from_task(
+                    &upstream,
+                    dest,
+                    &packet,
+                    &metrics,
+                    endpoint_health.as_ref(),
+                    faults.as_deref(),
+                )
+                .await;
+                if let Err(err) = result {
+                    error!(log, "Error sending queued packet"; "error" => %err);
+                }
+            }
+        });
+        packets_tx
+    }
+
+    /// Sends a packet to the Session's dest. If sends are ordered, the
+    /// packet is queued instead, and `None` is returned.
     pub async fn send(&self, buf: &[u8]) -> Result<Option<usize>> {
         trace!(self.log, "Sending packet";
         "dest_address" => &self.dest.address,
         "contents" => debug::bytes_to_string(buf));
 
-        self.do_send(buf)
-            .await
+        store_now(&self.last_received_downstream);
+        self.downstream_rates.record(buf.len());
+        if let Some(send_queue) = &self.send_queue {
+            return self.queue(send_queue, buf);
+        }
+        let result = self.do_send(buf).await;
+        if let Some(health) = &self.endpoint_health {
+            health.record_send(self.dest.address, result.is_ok());
+        }
+        result
             .map(|size| {
                 self.metrics.tx_packets_total.inc();
                 self.metrics.tx_bytes_total.inc_by(size as u64);
+                self.metrics.tx_packet_size_bytes.observe(size as f64);
                 Some(size)
             })
             .map_err(|err| {
-                self.metrics.tx_errors_total.inc();
+                self.metrics.record_tx_error(self.dest.address, &err);
                 Error::SendToDst(err)
             })
     }
 
-    /// Sends `buf` to the session's destination address. On success, returns
-    /// the number of bytes written.
+    /// Queues `buf` for the session's send queue task to send to dest. The
+    /// packet is dropped if the queue is full.
+    fn queue(&self, send_queue: &mpsc::Sender<Vec<u8>>, buf: &[u8]) -> Result<Option<usize>> {
+        let packet = match &self.dest.relay_token {
+            Some(token) => relay::seal(token, buf),
+            None => buf.to_vec(),
+        };
+        send_queue.try_send(packet).map(|()| None).map_err(|err| {
+            let err = match err {
+                mpsc::error::TrySendError::Full(_) => {
+                    self.metrics.tx_queue_full_total.inc();
+                    io::Error::new(io::ErrorKind::WouldBlock, "the send queue is full")
+                }
+                mpsc::error::TrySendError::Closed(_) => {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "the send queue is closed")
+                }
+            };
+            Error::SendToDst(err)
+        })
+    }
+
+    /// Sends `packet` to the session's dest once `delay` has elapsed.
+    pub fn send_after(&self, scheduler: &Scheduler, delay: Duration, packet: Vec<u8>) {
+        trace!(self.log, "Scheduling packet";
+        "dest_address" => &self.dest.address,
+        "delay" => ?delay,
+        "contents" => debug::bytes_to_string(&packet));
+
+        store_now(&self.last_received_downstream);
+        self.downstream_rates.record(packet.len());
+        let packet = match &self.dest.relay_token {
+            Some(token) => relay::seal(token, &packet),
+            None => packet,
+        };
+        let log = self.log.clone();
+        let metrics = self.metrics.clone();
+        let upstream = self.upstream.clone();
+        let dest = self.dest.address;
+        let endpoint_health = self.endpoint_health.clone();
+        let faults = self.faults.clone();
+        scheduler.schedule(delay, async move {
+            let result = send_from_task(
+                &upstream,
+                dest,
+                &packet,
+                &metrics,
+                endpoint_health.as_ref(),
+                faults.as_deref(),
+            )
+            .await;
+            if let Err(err) = result {
+                error!(log, "Error sending delayed packet"; "error" => %err);
+            }
+        });
+    }
+
+    /// Sends `buf` to the session's destination address, wrapped in an
+    /// envelope if the destination is a relay. On success, returns the number
+    /// of bytes written.
     pub async fn do_send(&self, buf: &[u8]) -> std::result::Result<usize, std::io::Error> {
-        self.socket.send_to(buf, &self.dest.address).await
+        if let Some(faults) = &self.faults {
+            faults.send()?;
+        }
+        match &self.dest.relay_token {
+            Some(token) => {
+                let sealed = relay::seal(token, buf);
+                self.upstream.send_to(&sealed, self.dest.address).await
+            }
+            None => self.upstream.send_to(buf, self.dest.address).await,
+        }
+    }
+}
+
+impl RecvLoop {
+    /// Spawns the loop receiving packets from `receiver`. If `supervisor`
+    /// is set, the loop is restarted if it panics.
+    fn spawn(self, receiver: Receiver, supervisor: Option<Supervisor>) {
+        match receiver {
+            Receiver::Socket(socket) => {
+                Self::supervise(supervisor, move || self.clone().run(socket.clone()))
+            }
+            Receiver::Tunnel(packets) => {
+                // The tunnel's receiver is shared by each restart of the loop.
+                let packets = Arc::new(Mutex::new(packets));
+                Self::supervise(supervisor, move || self.clone().run_tunnel(packets.clone()))
+            }
+        }
+    }
+
+    /// Spawns the task returned by `make_task`, through `supervisor` if set.
+    fn supervise<F, Fut>(supervisor: Option<Supervisor>, mut make_task: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        match supervisor {
+            Some(supervisor) => {
+                supervisor.spawn("session", make_task);
+            }
+            None => {
+                tokio::spawn(make_task());
+            }
+        }
+    }
+
+    /// run starts processing received udp packets on its UdpSocket.
+    /// The receive buffer is only allocated while packets are being received
+    /// and is released once the session has been idle for
+    /// `RECV_BUFFER_IDLE_TIMEOUT`.
+    async fn run(self, socket: Arc<UdpSocket>) {
+        let RecvLoop {
+            log,
+            from,
+            connection_id_header,
+            expiration,
+            failed,
+            last_received_upstream,
+            filter_manager,
+            downstreams,
+            endpoint,
+            metrics,
+            packet_size_limit,
+            compute_pool,
+            response_only_endpoints,
+            endpoint_health,
+            drop_reasons,
+            upstream_rates,
+            tap,
+            faults,
+            ttl,
+            mut sender,
+            mut shutdown_rx,
+        } = self;
+        let mut buf: Vec<u8> = Vec::new();
+        let mut last_received = Instant::now();
+        let mut compaction_interval = time::interval(RECV_BUFFER_IDLE_TIMEOUT);
+        loop {
+            debug!(log, "Awaiting incoming packet");
+            select! {
+                readable = socket.readable() => {
+                    if let Err(err) = readable {
+                        // The socket can't be used anymore, so stop
+                        // receiving and let the session be removed.
+                        metrics.rx_errors_total.inc();
+                        metrics.recv_buffer_bytes.sub(buf.len() as i64);
+                        failed.store(true, Ordering::Relaxed);
+                        error!(log, "Closing session after error waiting for packet"; "error" => %err);
+                        return;
+                    }
+
+                    if buf.is_empty() {
+                        buf = vec![0; packet_size_limit.recv_buffer_size()];
+                        metrics.recv_buffer_bytes.add(buf.len() as i64);
+                    }
+
+                    match socket.try_recv_from(&mut buf) {
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                        Err(err) => {
+                            metrics.rx_errors_total.inc();
+                            error!(log, "Error receiving packet"; "error" => %err);
+                        },
+                        Ok((size, recv_addr)) => {
+                            last_received = Instant::now();
+                            let response_only = response_only_endpoints
+                                .as_ref()
+                                .and_then(|endpoints| {
+                                    endpoints.as_ref().iter().find(|e| e.address == recv_addr)
+                                });
+                            match response_only {
+                                // A packet from a response-only endpoint isn't a response
+                                // from the session's endpoint.
+                                Some(_) => metrics.rx_response_only_packets_total.inc(),
+                                None => {
+                                    store_now(&last_received_upstream);
+                                    upstream_rates.record(size);
+                                    if let Some(health) = &endpoint_health {
+                                        health.record_response(endpoint.address);
+                                    }
+                                }
+                            }
+                            metrics.rx_bytes_total.inc_by(size as u64);
+                            metrics.rx_packets_total.inc();
+                            metrics.rx_packet_size_bytes.observe(size as f64);
+                            let size = match packet_size_limit.limit_len(
+                                size,
+                                &metrics.downstream_packets_oversized_total,
+                            ) {
+                                Some(size) => size,
+                                None => {
+                                    metrics.packets_dropped_total.inc();
+                                    continue;
+                                }
+                            };
+                            Session::process_recv_packet(
+                                &log,
+                                &metrics,
+                                &mut sender,
+                                &expiration,
+                                ttl,
+                                ReceivedPacketContext {
+                                    filter_manager: filter_manager.clone(),
+                                    packet: &buf[..size],
+                                    endpoint: response_only.unwrap_or(&endpoint),
+                                    from: recv_addr,
+                                    to: *from.read(),
+                                    connection_id_header: connection_id_header.read().clone(),
+                                    downstreams: &downstreams,
+                                    packet_size_limit,
+                                    compute_pool: compute_pool.clone(),
+                                    drop_reasons: &drop_reasons,
+                                    tap: tap.as_deref(),
+                                    faults: faults.as_deref(),
+                                }).await
+                        }
+                    };
+                }
+                _ = compaction_interval.tick() => {
+                    if !buf.is_empty() && last_received.elapsed() >= RECV_BUFFER_IDLE_TIMEOUT {
+                        metrics.recv_buffer_bytes.sub(buf.len() as i64);
+                        metrics.recv_buffer_compactions_total.inc();
+                        buf = Vec::new();
+                        debug!(log, "Released receive buffer of idle session");
+                    }
+                }
+                _ = shutdown_rx.changed() => {
+                    metrics.recv_buffer_bytes.sub(buf.len() as i64);
+                    debug!(log, "Closing Session");
+                    return;
+                }
+            };
+        }
+    }
+
+    /// Processes the packets received through the session's tunnel. The
+    /// session fails once the tunnel is closed.
+    async fn run_tunnel(self, packets: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>) {
+        let RecvLoop {
+            log,
+            from,
+            connection_id_header,
+            expiration,
+            failed,
+            last_received_upstream,
+            filter_manager,
+            downstreams,
+            endpoint,
+            metrics,
+            packet_size_limit,
+            compute_pool,
+            endpoint_health,
+            drop_reasons,
+            upstream_rates,
+            tap,
+            faults,
+            ttl,
+            mut sender,
+            mut shutdown_rx,
+            ..
+        } = self;
+        let mut packets = packets.lock().await;
+        loop {
+            select! {
+                packet = packets.recv() => {
+                    let packet = match packet {
+                        Some(packet) => packet,
+                        None => {
+                            failed.store(true, Ordering::Relaxed);
+                            error!(log, "Closing session after its tunnel was closed");
+                            return;
+                        }
+                    };
+                    store_now(&last_received_upstream);
+                    upstream_rates.record(packet.len());
+                    if let Some(health) = &endpoint_health {
+                        health.record_response(endpoint.address);
+                    }
+                    metrics.rx_bytes_total.inc_by(packet.len() as u64);
+                    metrics.rx_packets_total.inc();
+                    metrics.rx_packet_size_bytes.observe(packet.len() as f64);
+                    Session::process_recv_packet(
+                        &log,
+                        &metrics,
+                        &mut sender,
+                        &expiration,
+                        ttl,
+                        ReceivedPacketContext {
+                            filter_manager: filter_manager.clone(),
+                            packet: &packet,
+                            endpoint: &endpoint,
+                            from: endpoint.address,
+                            to: *from.read(),
+                            connection_id_header: connection_id_header.read().clone(),
+                            downstreams: &downstreams,
+                            packet_size_limit,
+                            compute_pool: compute_pool.clone(),
+                            drop_reasons: &drop_reasons,
+                            tap: tap.as_deref(),
+                            faults: faults.as_deref(),
+                        }).await
+                }
+                _ = shutdown_rx.changed() => {
+                    debug!(log, "Closing Session");
+                    return;
+                }
+            };
+        }
+    }
+}
+
+/// Sends `packet` to `dest` on behalf of a session, from a task other than
+/// the one that received it, recording the outcome in the session's
+/// `metrics` and `endpoint_health`.
+async fn send_from_task(
+    upstream: &Upstream,
+    dest: SocketAddr,
+    packet: &[u8],
+    metrics: &Metrics,
+    endpoint_health: Option<&EndpointHealth>,
+    faults: Option<&FaultInjector>,
+) -> io::Result<usize> {
+    let result = match faults.map(FaultInjector::send) {
+        Some(Err(err)) => Err(err),
+        _ => upstream.send_to(packet, dest).await,
+    };
+    if let Some(health) = endpoint_health {
+        health.record_send(dest, result.is_ok());
+    }
+    match &result {
+        Ok(size) => {
+            metrics.tx_packets_total.inc();
+            metrics.tx_bytes_total.inc_by(*size as u64);
+            metrics.tx_packet_size_bytes.observe(*size as f64);
+        }
+        Err(err) => metrics.record_tx_error(dest, err),
+    }
+    result
+}
+
+/// Stores the current unix time in milliseconds in `time`.
+fn store_now(time: &AtomicU64) {
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        time.store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Returns the time stored in `time` by [`store_now`], if any.
+fn load_time(time: &AtomicU64) -> Option<SystemTime> {
+    match time.load(Ordering::Relaxed) {
+        0 => None,
+        millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
     }
 }
 
@@ -289,28 +1122,50 @@ impl Drop for Session {
             warn!(self.log, "Error sending session shutdown signal"; "error" => error.to_string());
         }
 
-        debug!(self.log, "Session closed"; "from" => self.from, "dest_address" => &self.dest.address);
+        let drop_reasons = self.top_drop_reasons();
+        if drop_reasons.is_empty() {
+            debug!(self.log, "Session closed";
+                "from" => self.client(),
+                "dest_address" => &self.dest.address);
+        } else {
+            let drop_reasons = drop_reasons
+                .iter()
+                .map(|(reason, count)| format!("{} ({})", reason, count))
+                .collect::<Vec<_>>()
+                .join(", ");
+            info!(self.log, "Session closed with packets dropped by filters";
+                "from" => self.client(),
+                "dest_address" => &self.dest.address,
+                "drop_reasons" => drop_reasons);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::SocketAddr;
     use std::str::from_utf8;
     use std::sync::atomic::{AtomicU64, Ordering};
     use std::sync::Arc;
     use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-    use super::{Metrics, Packet, Session};
+    use super::{
+        DropReasons, Metrics, Packet, PacketSizeLimit, Session, SessionArgs, UpstreamSocket,
+    };
 
+    use parking_lot::RwLock;
     use prometheus::Registry;
     use tokio::time::timeout;
 
-    use crate::filters::FilterChain;
+    use crate::filters::{drop_packet, Filter, FilterChain, WriteContext, WriteResponse};
+    use crate::proxy::sessions::ClientKey;
     use crate::test_utils::{new_test_chain, TestHelper};
 
     use crate::cluster::Endpoint;
     use crate::filters::manager::FilterManager;
+    use crate::config::{ComputePool as ComputePoolConfig, Endpoints};
     use crate::proxy::sessions::session::ReceivedPacketContext;
+    use crate::proxy::ComputePool;
     use tokio::sync::mpsc;
 
     #[tokio::test]
@@ -324,12 +1179,28 @@ mod tests {
 
         let sess = Session::new(
             &t.log,
-            Metrics::new(&registry).unwrap(),
-            FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
-            addr,
-            endpoint,
-            send_packet,
-            Duration::from_secs(20),
+            SessionArgs {
+                metrics: Metrics::new(&registry).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: addr,
+                client_key: None,
+                connection_id_header: None,
+                dest: endpoint,
+                sender: send_packet,
+                ttl: Duration::from_secs(20),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
         )
         .await
         .unwrap();
@@ -350,6 +1221,9 @@ mod tests {
             socket.send_to(&buf[..size], &recv_addr).await.unwrap();
         });
 
+        assert!(sess.last_received_downstream().is_none());
+        assert!(sess.last_received_upstream().is_none());
+
         sess.send(b"hello").await.unwrap();
 
         let packet = recv_packet
@@ -358,6 +1232,96 @@ mod tests {
             .expect("Should receive a packet 'hello'");
         assert_eq!(String::from("hello").into_bytes(), packet.contents);
         assert_eq!(addr, packet.dest);
+
+        let last_received_downstream = sess.last_received_downstream().unwrap();
+        let last_received_upstream = sess.last_received_upstream().unwrap();
+        assert!(last_received_downstream <= last_received_upstream);
+        assert!(sess.upstream_idle() < Duration::from_secs(1));
+        assert_eq!(1, sess.downstream_packet_counts().packets);
+        assert_eq!(5, sess.downstream_packet_counts().bytes);
+        assert_eq!(1, sess.upstream_packet_counts().packets);
+
+        let spectator: SocketAddr = "127.0.0.1:89".parse().unwrap();
+        assert!(!sess.add_downstream(addr));
+        assert!(sess.add_downstream(spectator));
+        assert!(!sess.add_downstream(spectator));
+        assert_eq!(vec![spectator], sess.downstreams());
+        assert!(sess.remove_downstream(spectator));
+        assert!(!sess.remove_downstream(spectator));
+        assert!(sess.downstreams().is_empty());
+    }
+
+    #[tokio::test]
+    async fn session_response_only_endpoint() {
+        // Replaces each packet with the address of the endpoint that it is
+        // attributed to.
+        struct EndpointAddress;
+        impl Filter for EndpointAddress {
+            fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+                ctx.contents = ctx.endpoint.address.to_string().into_bytes();
+                Some(ctx.into())
+            }
+        }
+
+        let t = TestHelper::default();
+        let socket = t.create_socket().await;
+        let addr = socket.local_addr().unwrap();
+        let broadcaster = t.create_socket().await;
+        // Packets from the broadcaster arrive from the loopback address,
+        // rather than the unspecified address it is bound to.
+        let broadcaster_addr =
+            SocketAddr::from(([127, 0, 0, 1], broadcaster.local_addr().unwrap().port()));
+        let (send_packet, mut recv_packet) = mpsc::channel::<Packet>(5);
+        let registry = Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> =
+            vec![("EndpointAddress".into(), Box::new(EndpointAddress))];
+
+        let sess = Session::new(
+            &t.log,
+            SessionArgs {
+                metrics: Metrics::new(&registry).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(filters, &registry).unwrap(),
+                )),
+                from: addr,
+                client_key: None,
+                connection_id_header: None,
+                dest: Endpoint::from_address(addr),
+                sender: send_packet,
+                ttl: Duration::from_secs(20),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: Some(Arc::new(
+                    Endpoints::new(vec![Endpoint::from_address(broadcaster_addr)]).unwrap(),
+                )),
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        // The broadcaster learns the session's address from the endpoint.
+        sess.send(b"hello").await.unwrap();
+        let mut buf = vec![0; 1024];
+        let (_, session_addr) = socket.recv_from(&mut buf).await.unwrap();
+        broadcaster
+            .send_to(b"broadcast", &session_addr)
+            .await
+            .unwrap();
+
+        let packet = timeout(Duration::from_secs(5), recv_packet.recv())
+            .await
+            .unwrap()
+            .expect("Should receive the broadcast packet");
+        assert_eq!(broadcaster_addr.to_string().into_bytes(), packet.contents);
+        assert_eq!(1, sess.metrics.rx_response_only_packets_total.get());
+        assert!(sess.last_received_upstream().is_none());
     }
 
     #[tokio::test]
@@ -374,12 +1338,28 @@ mod tests {
 
         let session = Session::new(
             &t.log,
-            Metrics::new(&Registry::default()).unwrap(),
-            FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
-            addr,
-            endpoint.clone(),
-            sender,
-            Duration::from_millis(1000),
+            SessionArgs {
+                metrics: Metrics::new(&Registry::default()).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: addr,
+                client_key: None,
+                connection_id_header: None,
+                dest: endpoint.clone(),
+                sender,
+                ttl: Duration::from_millis(1000),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
         )
         .await
         .unwrap();
@@ -387,6 +1367,98 @@ mod tests {
         assert_eq!(msg, ep.packet_rx.await.unwrap());
     }
 
+    #[tokio::test]
+    async fn session_send_to_relay() {
+        let t = TestHelper::default();
+        let (sender, _) = mpsc::channel::<Packet>(1);
+        let ep = t.open_socket_and_recv_single_packet().await;
+        let addr = ep.socket.local_addr().unwrap();
+        let endpoint = Endpoint {
+            relay_token: Some(b"abc".to_vec()),
+            ..Endpoint::from_address(addr)
+        };
+        let registry = Registry::default();
+
+        let session = Session::new(
+            &t.log,
+            SessionArgs {
+                metrics: Metrics::new(&Registry::default()).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: addr,
+                client_key: None,
+                connection_id_header: None,
+                dest: endpoint,
+                sender,
+                ttl: Duration::from_millis(1000),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
+        )
+        .await
+        .unwrap();
+        session.send(b"hello").await.unwrap();
+        assert_eq!("QUILKIN_RELAY\u{3}abchello", ep.packet_rx.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn session_send_queued() {
+        let mut t = TestHelper::default();
+        let (sender, _) = mpsc::channel::<Packet>(1);
+        let (mut packet_rx, socket) = t.open_socket_and_recv_multiple_packets().await;
+        let addr = socket.local_addr().unwrap();
+        let registry = Registry::default();
+
+        let session = Session::new(
+            &t.log,
+            SessionArgs {
+                metrics: Metrics::new(&Registry::default()).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: addr,
+                client_key: None,
+                connection_id_header: None,
+                dest: Endpoint::from_address(addr),
+                sender,
+                ttl: Duration::from_millis(1000),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: Some(8),
+            },
+        )
+        .await
+        .unwrap();
+
+        // Queued packets aren't sent by `send`, so no size is returned.
+        for msg in &["one", "two", "three"] {
+            assert_eq!(None, session.send(msg.as_bytes()).await.unwrap());
+        }
+        for msg in &["one", "two", "three"] {
+            let received = timeout(Duration::from_secs(5), packet_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(*msg, received);
+        }
+    }
+
     #[tokio::test]
     async fn process_recv_packet() {
         let t = TestHelper::default();
@@ -418,6 +1490,13 @@ mod tests {
                 endpoint: &endpoint,
                 from: endpoint.address,
                 to: dest,
+                connection_id_header: None,
+                downstreams: &RwLock::default(),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                drop_reasons: &DropReasons::default(),
+                tap: None,
+                faults: None,
             },
         )
         .await;
@@ -452,6 +1531,13 @@ mod tests {
                 endpoint: &endpoint,
                 from: endpoint.address,
                 to: dest,
+                connection_id_header: None,
+                downstreams: &RwLock::default(),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                drop_reasons: &DropReasons::default(),
+                tap: None,
+                faults: None,
             },
         )
         .await;
@@ -468,6 +1554,216 @@ mod tests {
         assert_eq!(dest, p.dest);
     }
 
+    #[tokio::test]
+    async fn process_recv_packet_compute_pool() {
+        let t = TestHelper::default();
+        let registry = Registry::default();
+
+        let chain = new_test_chain(&registry);
+        let endpoint = Endpoint::from_address("127.0.1.1:80".parse().unwrap());
+        let dest = "127.0.0.1:88".parse().unwrap();
+        let (mut sender, mut receiver) = mpsc::channel::<Packet>(10);
+        let compute_pool = ComputePool::new(ComputePoolConfig {
+            heavy_filters: vec!["TestFilter".into()],
+            threads: 1,
+            queue_size: 1,
+        });
+
+        let msg = "hello";
+        Session::process_recv_packet(
+            &t.log,
+            &Metrics::new(&registry).unwrap(),
+            &mut sender,
+            &Arc::new(AtomicU64::new(0)),
+            Duration::from_secs(10),
+            ReceivedPacketContext {
+                filter_manager: FilterManager::fixed(chain),
+                packet: msg.as_bytes(),
+                endpoint: &endpoint,
+                from: endpoint.address,
+                to: dest,
+                connection_id_header: None,
+                downstreams: &RwLock::default(),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: Some(Arc::new(compute_pool)),
+                drop_reasons: &DropReasons::default(),
+                tap: None,
+                faults: None,
+            },
+        )
+        .await;
+
+        let p = timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .expect("Should receive a packet")
+            .unwrap();
+        assert_eq!(
+            format!("{}:our:{}:{}", msg, endpoint.address, dest),
+            from_utf8(p.contents.as_slice()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn process_recv_packet_compute_pool_full() {
+        let t = TestHelper::default();
+        let registry = Registry::default();
+
+        let chain = new_test_chain(&registry);
+        let endpoint = Endpoint::from_address("127.0.1.1:80".parse().unwrap());
+        let (mut sender, mut receiver) = mpsc::channel::<Packet>(10);
+        let metrics = Metrics::new(&registry).unwrap();
+        // A pool without threads, whose queue is filled by another packet.
+        let compute_pool = Arc::new(ComputePool::new(ComputePoolConfig {
+            heavy_filters: vec!["TestFilter".into()],
+            threads: 0,
+            queue_size: 1,
+        }));
+        tokio::spawn({
+            let compute_pool = compute_pool.clone();
+            async move { compute_pool.run(|| ()).await }
+        });
+        tokio::task::yield_now().await;
+
+        Session::process_recv_packet(
+            &t.log,
+            &metrics,
+            &mut sender,
+            &Arc::new(AtomicU64::new(0)),
+            Duration::from_secs(10),
+            ReceivedPacketContext {
+                filter_manager: FilterManager::fixed(chain),
+                packet: b"hello",
+                endpoint: &endpoint,
+                from: endpoint.address,
+                to: "127.0.0.1:88".parse().unwrap(),
+                connection_id_header: None,
+                downstreams: &RwLock::default(),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: Some(compute_pool),
+                drop_reasons: &DropReasons::default(),
+                tap: None,
+                faults: None,
+            },
+        )
+        .await;
+
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(1, metrics.packets_dropped_compute_pool_full.get());
+        assert_eq!(0, metrics.packets_dropped_total.get());
+    }
+
+    #[tokio::test]
+    async fn process_recv_packet_drop_reason() {
+        struct Reject;
+        impl Filter for Reject {
+            fn write(&self, _: WriteContext) -> Option<WriteResponse> {
+                drop_packet("Rejected")
+            }
+        }
+
+        let t = TestHelper::default();
+        let registry = Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> = vec![("Reject".into(), Box::new(Reject))];
+        let chain = Arc::new(FilterChain::new(filters, &registry).unwrap());
+        let endpoint = Endpoint::from_address("127.0.1.1:80".parse().unwrap());
+        let (mut sender, mut receiver) = mpsc::channel::<Packet>(10);
+        let metrics = Metrics::new(&registry).unwrap();
+        let drop_reasons = DropReasons::default();
+
+        for _ in 0..2 {
+            Session::process_recv_packet(
+                &t.log,
+                &metrics,
+                &mut sender,
+                &Arc::new(AtomicU64::new(0)),
+                Duration::from_secs(10),
+                ReceivedPacketContext {
+                    filter_manager: FilterManager::fixed(chain.clone()),
+                    packet: b"hello",
+                    endpoint: &endpoint,
+                    from: endpoint.address,
+                    to: "127.0.0.1:88".parse().unwrap(),
+                    connection_id_header: None,
+                    downstreams: &RwLock::default(),
+                    packet_size_limit: PacketSizeLimit::default(),
+                    compute_pool: None,
+                    drop_reasons: &drop_reasons,
+                    tap: None,
+                    faults: None,
+                },
+            )
+            .await;
+        }
+
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(2, metrics.packets_dropped_total.get());
+        let top = drop_reasons.top();
+        assert_eq!(1, top.len());
+        assert_eq!("Reject", top[0].0.filter);
+        assert_eq!("Rejected", top[0].0.code);
+        assert_eq!(2, top[0].1);
+    }
+
+    #[tokio::test]
+    async fn process_recv_packet_downstreams() {
+        /// Duplicates each packet to the address in its contents.
+        struct Duplicate;
+        impl Filter for Duplicate {
+            fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+                let to = std::str::from_utf8(&ctx.contents).ok()?.parse().ok()?;
+                let copy = WriteContext::new(ctx.endpoint, ctx.from, to, ctx.contents.clone());
+                let mut response: WriteResponse = ctx.into();
+                response.additional.push(copy.into());
+                Some(response)
+            }
+        }
+
+        let t = TestHelper::default();
+        let registry = Registry::default();
+        let filters: Vec<(String, Box<dyn Filter>)> =
+            vec![("Duplicate".into(), Box::new(Duplicate))];
+        let chain = Arc::new(FilterChain::new(filters, &registry).unwrap());
+        let endpoint = Endpoint::from_address("127.0.1.1:80".parse().unwrap());
+        let dest: SocketAddr = "127.0.0.1:88".parse().unwrap();
+        let spectator: SocketAddr = "127.0.0.1:89".parse().unwrap();
+        let (mut sender, mut receiver) = mpsc::channel::<Packet>(10);
+        let metrics = Metrics::new(&registry).unwrap();
+        let downstreams = RwLock::new(vec![spectator]);
+
+        for packet in &["127.0.0.1:89", "127.0.0.1:90"] {
+            Session::process_recv_packet(
+                &t.log,
+                &metrics,
+                &mut sender,
+                &Arc::new(AtomicU64::new(0)),
+                Duration::from_secs(10),
+                ReceivedPacketContext {
+                    filter_manager: FilterManager::fixed(chain.clone()),
+                    packet: packet.as_bytes(),
+                    endpoint: &endpoint,
+                    from: endpoint.address,
+                    to: dest,
+                    connection_id_header: None,
+                    downstreams: &downstreams,
+                    packet_size_limit: PacketSizeLimit::default(),
+                    compute_pool: None,
+                    drop_reasons: &DropReasons::default(),
+                    tap: None,
+                    faults: None,
+                },
+            )
+            .await;
+        }
+
+        // The copy sent to an address that isn't a downstream is dropped.
+        let mut dests = vec![];
+        while let Ok(packet) = receiver.try_recv() {
+            dests.push(packet.dest);
+        }
+        assert_eq!(vec![dest, spectator, dest], dests);
+        assert_eq!(1, metrics.packets_dropped_total.get());
+    }
+
     #[tokio::test]
     async fn session_new_metrics() {
         let t = TestHelper::default();
@@ -479,12 +1775,28 @@ mod tests {
 
         let session = Session::new(
             &t.log,
-            Metrics::new(&Registry::default()).unwrap(),
-            FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
-            addr,
-            endpoint,
-            send_packet,
-            Duration::from_secs(10),
+            SessionArgs {
+                metrics: Metrics::new(&Registry::default()).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: addr,
+                client_key: None,
+                connection_id_header: None,
+                dest: endpoint,
+                sender: send_packet,
+                ttl: Duration::from_secs(10),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
         )
         .await
         .unwrap();
@@ -503,12 +1815,28 @@ mod tests {
         let registry = Registry::default();
         let session = Session::new(
             &t.log,
-            Metrics::new(&registry).unwrap(),
-            FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
-            addr,
-            Endpoint::from_address(addr),
-            sender,
-            Duration::from_secs(10),
+            SessionArgs {
+                metrics: Metrics::new(&registry).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: addr,
+                client_key: None,
+                connection_id_header: None,
+                dest: Endpoint::from_address(addr),
+                sender,
+                ttl: Duration::from_secs(10),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
         )
         .await
         .unwrap();
@@ -516,9 +1844,230 @@ mod tests {
         endpoint.packet_rx.await.unwrap();
 
         assert_eq!(session.metrics.tx_bytes_total.get(), 5);
+        assert_eq!(session.metrics.tx_packet_size_bytes.get_sample_sum(), 5.0);
         assert_eq!(session.metrics.tx_packets_total.get(), 1);
     }
 
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn send_too_large_metrics() {
+        let t = TestHelper::default();
+
+        let (sender, _) = mpsc::channel::<Packet>(1);
+        let endpoint = t.open_socket_and_recv_single_packet().await;
+        let addr = endpoint.socket.local_addr().unwrap();
+        let registry = Registry::default();
+        let session = Session::new(
+            &t.log,
+            SessionArgs {
+                metrics: Metrics::new(&registry).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: addr,
+                client_key: None,
+                connection_id_header: None,
+                dest: Endpoint::from_address(addr),
+                sender,
+                ttl: Duration::from_secs(10),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        // A packet larger than an IPv4 datagram can carry is rejected with
+        // EMSGSIZE, and counted against the endpoint.
+        assert!(session.send(&[0; 65508]).await.is_err());
+        assert_eq!(1, session.metrics.tx_errors_total.get());
+        assert_eq!(
+            1,
+            session
+                .metrics
+                .tx_message_too_large_total
+                .with_label_values(&[&addr.to_string()])
+                .get()
+        );
+    }
+
+    #[tokio::test]
+    async fn recv_buffer_metrics() {
+        let mut t = TestHelper::default();
+        let echo_addr = t.run_echo_server().await;
+        let (send_packet, mut recv_packet) = mpsc::channel::<Packet>(5);
+        let registry = Registry::default();
+        let session = Session::new(
+            &t.log,
+            SessionArgs {
+                metrics: Metrics::new(&registry).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: "127.0.0.1:7000".parse().unwrap(),
+                client_key: None,
+                connection_id_header: None,
+                dest: Endpoint::from_address(echo_addr),
+                sender: send_packet,
+                ttl: Duration::from_secs(10),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        // No buffer is allocated until a packet is received.
+        assert_eq!(session.metrics.recv_buffer_bytes.get(), 0);
+
+        session.send(b"hello").await.unwrap();
+        let packet = timeout(Duration::from_secs(5), recv_packet.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"hello", packet.contents().as_slice());
+        assert_eq!(
+            session.metrics.recv_buffer_bytes.get(),
+            PacketSizeLimit::default().recv_buffer_size() as i64
+        );
+
+        // The buffer is released once the session is closed.
+        let metrics = session.metrics.clone();
+        drop(session);
+        timeout(Duration::from_secs(5), async {
+            while metrics.recv_buffer_bytes.get() != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rebind() {
+        let mut t = TestHelper::default();
+        let echo_addr = t.run_echo_server().await;
+        let (send_packet, mut recv_packet) = mpsc::channel::<Packet>(5);
+        let registry = Registry::default();
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let moved: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let session = Session::new(
+            &t.log,
+            SessionArgs {
+                metrics: Metrics::new(&registry).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from,
+                client_key: Some(ClientKey::ConnectionId(b"abc".to_vec())),
+                connection_id_header: None,
+                dest: Endpoint::from_address(echo_addr),
+                sender: send_packet,
+                ttl: Duration::from_secs(10),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(ClientKey::ConnectionId(b"abc".to_vec()), session.key().client);
+
+        session.send(b"hello").await.unwrap();
+        let packet = timeout(Duration::from_secs(5), recv_packet.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, packet.dest());
+
+        // Responses are sent to the client's new address once it moves.
+        assert!(!session.rebind(from));
+        assert!(session.rebind(moved));
+        assert_eq!(moved, session.client());
+        assert_eq!(1, session.metrics.sessions_rebound_total.get());
+
+        session.send(b"hello").await.unwrap();
+        let packet = timeout(Duration::from_secs(5), recv_packet.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(moved, packet.dest());
+    }
+
+    #[tokio::test]
+    async fn connection_id_header() {
+        let mut t = TestHelper::default();
+        let echo_addr = t.run_echo_server().await;
+        let (send_packet, mut recv_packet) = mpsc::channel::<Packet>(5);
+        let registry = Registry::default();
+        let session = Session::new(
+            &t.log,
+            SessionArgs {
+                metrics: Metrics::new(&registry).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: "127.0.0.1:7000".parse().unwrap(),
+                client_key: Some(ClientKey::ConnectionId(b"abc".to_vec())),
+                connection_id_header: Some(b"CIDabc".to_vec()),
+                dest: Endpoint::from_address(echo_addr),
+                sender: send_packet,
+                ttl: Duration::from_secs(10),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        // The client is sent its connection id until it sends it back.
+        session.send(b"hello").await.unwrap();
+        let packet = timeout(Duration::from_secs(5), recv_packet.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"CIDabchello", packet.contents().as_slice());
+
+        session.confirm_connection_id();
+        session.send(b"hello").await.unwrap();
+        let packet = timeout(Duration::from_secs(5), recv_packet.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"hello", packet.contents().as_slice());
+    }
+
     #[tokio::test]
     async fn session_drop_metrics() {
         let t = TestHelper::default();
@@ -528,12 +2077,28 @@ mod tests {
         let registry = Registry::default();
         let session = Session::new(
             &t.log,
-            Metrics::new(&registry).unwrap(),
-            FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
-            addr,
-            Endpoint::from_address(addr),
-            send_packet,
-            Duration::from_secs(10),
+            SessionArgs {
+                metrics: Metrics::new(&registry).unwrap(),
+                filter_manager: FilterManager::fixed(Arc::new(
+                    FilterChain::new(vec![], &registry).unwrap(),
+                )),
+                from: addr,
+                client_key: None,
+                connection_id_header: None,
+                dest: Endpoint::from_address(addr),
+                sender: send_packet,
+                ttl: Duration::from_secs(10),
+                packet_size_limit: PacketSizeLimit::default(),
+                compute_pool: None,
+                upstream_socket: UpstreamSocket::default(),
+                tunnel: None,
+                response_only_endpoints: None,
+                endpoint_health: None,
+                tap: None,
+                faults: None,
+                supervisor: None,
+                send_queue_size: None,
+            },
         )
         .await
         .unwrap();
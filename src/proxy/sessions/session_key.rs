@@ -0,0 +1,171 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::config::{SessionKeyKind, SessionKeySource};
+
+/// Identifies the client that a session is for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ClientKey {
+    /// The client's address.
+    Address(SocketAddr),
+    /// The client's IP address and a token in its packets.
+    IpAndToken(IpAddr, Vec<u8>),
+    /// A connection id in the client's packets.
+    ConnectionId(Vec<u8>),
+}
+
+impl ClientKey {
+    /// Returns the key of the client at `from` whose packet left `metadata`
+    /// after being processed by the filter chain. The client is keyed by its
+    /// address unless `source` is set and the metadata holds the token or
+    /// connection id it refers to.
+    pub(crate) fn new(
+        source: Option<&SessionKeySource>,
+        from: SocketAddr,
+        metadata: &HashMap<Arc<String>, Box<dyn Any + Send>>,
+    ) -> Self {
+        let source = match source {
+            Some(source) => source,
+            None => return ClientKey::Address(from),
+        };
+        match metadata
+            .get(&source.metadata_key)
+            .and_then(|value| value.downcast_ref::<Vec<u8>>())
+        {
+            Some(id) => Self::from_id(source.kind, from, id.clone()),
+            None => ClientKey::Address(from),
+        }
+    }
+
+    /// Returns the key of the client at `from` that sent the token or
+    /// connection id `id`.
+    pub(crate) fn from_id(kind: SessionKeyKind, from: SocketAddr, id: Vec<u8>) -> Self {
+        match kind {
+            SessionKeyKind::IpAndToken => ClientKey::IpAndToken(from.ip(), id),
+            SessionKeyKind::ConnectionId => ClientKey::ConnectionId(id),
+        }
+    }
+
+    /// Returns the token or connection id that the client is keyed by, if
+    /// it isn't keyed by its address.
+    pub fn id(&self) -> Option<&[u8]> {
+        match self {
+            ClientKey::Address(_) => None,
+            ClientKey::IpAndToken(_, id) | ClientKey::ConnectionId(id) => Some(id),
+        }
+    }
+}
+
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientKey::Address(address) => write!(f, "{}", address),
+            ClientKey::IpAndToken(ip, token) => write!(f, "{}/{}", ip, base64::encode(token)),
+            ClientKey::ConnectionId(id) => write!(f, "{}", base64::encode(id)),
+        }
+    }
+}
+
+/// The key of a session in the session map: the client it is for and the
+/// address of the endpoint it sends packets to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionKey {
+    pub client: ClientKey,
+    pub endpoint: SocketAddr,
+}
+
+impl From<(SocketAddr, SocketAddr)> for SessionKey {
+    /// Returns the key of the session between the client at the first
+    /// address and the endpoint at the second.
+    fn from((client, endpoint): (SocketAddr, SocketAddr)) -> Self {
+        SessionKey {
+            client: ClientKey::Address(client),
+            endpoint,
+        }
+    }
+}
+
+impl fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}:{})", self.client, self.endpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use super::ClientKey;
+    use crate::config::{SessionKeyKind, SessionKeySource};
+
+    fn metadata(id: Option<Vec<u8>>) -> HashMap<Arc<String>, Box<dyn Any + Send>> {
+        let mut metadata = HashMap::new();
+        if let Some(id) = id {
+            metadata.insert(
+                Arc::new("quilkin.dev/captured_bytes".into()),
+                Box::new(id) as Box<dyn Any + Send>,
+            );
+        }
+        metadata
+    }
+
+    #[test]
+    fn new() {
+        let from: SocketAddr = "127.0.0.1:7777".parse().unwrap();
+        let source = |kind| SessionKeySource {
+            kind,
+            metadata_key: "quilkin.dev/captured_bytes".into(),
+        };
+
+        assert_eq!(
+            ClientKey::Address(from),
+            ClientKey::new(None, from, &metadata(Some(b"abc".to_vec())))
+        );
+        assert_eq!(
+            ClientKey::Address(from),
+            ClientKey::new(
+                Some(&source(SessionKeyKind::ConnectionId)),
+                from,
+                &metadata(None),
+            )
+        );
+        assert_eq!(
+            ClientKey::ConnectionId(b"abc".to_vec()),
+            ClientKey::new(
+                Some(&source(SessionKeyKind::ConnectionId)),
+                from,
+                &metadata(Some(b"abc".to_vec())),
+            )
+        );
+        assert_eq!(
+            ClientKey::IpAndToken(from.ip(), b"abc".to_vec()),
+            ClientKey::new(
+                Some(&source(SessionKeyKind::IpAndToken)),
+                from,
+                &metadata(Some(b"abc".to_vec())),
+            )
+        );
+    }
+}
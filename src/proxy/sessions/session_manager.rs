@@ -14,18 +14,21 @@
  *  limitations under the License.
  */
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use prometheus::IntCounter;
 use slog::{debug, warn, Logger};
 use tokio::sync::{watch, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use crate::proxy::sessions::Session;
+use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::proxy::sessions::metrics::Metrics;
+use crate::proxy::sessions::{Session, SessionKey};
 
-// Tracks current sessions keyed by key (source_address,destination_address) pair.
-type SessionsMap = HashMap<(SocketAddr, SocketAddr), Session>;
+// Tracks current sessions keyed by their client and endpoint.
+type SessionsMap = HashMap<SessionKey, Session>;
 type Sessions = Arc<RwLock<SessionsMap>>;
 
 /// SESSION_TIMEOUT_SECONDS is the default session timeout.
@@ -34,15 +37,37 @@ pub const SESSION_TIMEOUT_SECONDS: u64 = 60;
 /// SESSION_EXPIRY_POLL_INTERVAL is the default interval to check for expired sessions.
 const SESSION_EXPIRY_POLL_INTERVAL: u64 = 60;
 
+/// How long a session can go without receiving a packet in a direction before
+/// it is counted as idle in that direction.
+const SESSION_IDLE_THRESHOLD: Duration = Duration::from_secs(10);
+
 #[derive(Clone)]
 pub struct SessionManager(Sessions);
 
 impl SessionManager {
-    pub fn new(log: Logger, shutdown_rx: watch::Receiver<()>) -> Self {
-        let poll_interval = Duration::from_secs(SESSION_EXPIRY_POLL_INTERVAL);
+    pub fn new(
+        log: Logger,
+        metrics: Metrics,
+        cluster_manager: SharedClusterManager,
+        first_response_timeout: Option<Duration>,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> Self {
+        // Check often enough that unresponsive sessions don't outlive
+        // `first_response_timeout` by much more than the timeout itself.
+        let poll_interval = first_response_timeout
+            .unwrap_or_else(|| Duration::from_secs(SESSION_EXPIRY_POLL_INTERVAL))
+            .min(Duration::from_secs(SESSION_EXPIRY_POLL_INTERVAL));
         let sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
 
-        Self::run_prune_sessions(log.clone(), sessions.clone(), poll_interval, shutdown_rx);
+        Self::run_prune_sessions(
+            log.clone(),
+            sessions.clone(),
+            cluster_manager,
+            metrics,
+            first_response_timeout,
+            poll_interval,
+            shutdown_rx,
+        );
 
         Self(sessions)
     }
@@ -55,6 +80,42 @@ impl SessionManager {
         self.0.write().await
     }
 
+    /// Removes up to `count` sessions, those that have gone the longest
+    /// without receiving a packet in either direction first, and the oldest
+    /// first among those that are as idle. Returns the number of sessions
+    /// removed.
+    pub async fn shed_idlest(&self, count: usize) -> usize {
+        let mut sessions = self.0.write().await;
+        let mut candidates = sessions
+            .iter()
+            .map(|(key, session)| {
+                let idle = session.downstream_idle().min(session.upstream_idle());
+                (idle, session.age(), key.clone())
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_unstable_by(|a, b| b.cmp(a));
+        candidates
+            .into_iter()
+            .take(count)
+            .filter(|(_, _, key)| sessions.remove(key).is_some())
+            .count()
+    }
+
+    /// Removes the sessions of the client at `client`, returning the number
+    /// of sessions removed.
+    pub async fn remove_client(&self, client: SocketAddr) -> usize {
+        let mut removed = 0;
+        self.0.write().await.retain(|_, session| {
+            if session.client() != client {
+                return true;
+            }
+            session.record_admin_removal();
+            removed += 1;
+            false
+        });
+        removed
+    }
+
     /// run_prune_sessions starts the timer for pruning sessions and runs prune_sessions every
     /// SESSION_TIMEOUT_SECONDS, via a tokio::spawn, i.e. it's non-blocking.
     /// Pruning will occur ~ every interval period. So the timeout expiration may sometimes
@@ -62,12 +123,16 @@ impl SessionManager {
     fn run_prune_sessions(
         log: Logger,
         mut sessions: Sessions,
+        cluster_manager: SharedClusterManager,
+        metrics: Metrics,
+        first_response_timeout: Option<Duration>,
         poll_interval: Duration,
         mut shutdown_rx: watch::Receiver<()>,
     ) {
         let mut interval = tokio::time::interval(poll_interval);
 
         tokio::spawn(async move {
+            let mut known_endpoints = HashSet::new();
             loop {
                 tokio::select! {
                     _ = shutdown_rx.changed() => {
@@ -76,44 +141,142 @@ impl SessionManager {
                     }
                     _ = interval.tick() => {
                         debug!(log, "Attempting to Prune Sessions");
-                        Self::prune_sessions(&log, &mut sessions).await;
-
+                        Self::prune_sessions(
+                            &log,
+                            &mut sessions,
+                            &cluster_manager,
+                            &mut known_endpoints,
+                            &metrics,
+                            first_response_timeout,
+                        )
+                        .await;
                     }
                 }
             }
         });
     }
 
-    /// Removes expired [`Session`]s from `sessions`. This should be run
-    /// regularly such as on a time interval. This will only write lock
-    /// `sessions` if it first finds expired sessions.
-    async fn prune_sessions(log: &Logger, sessions: &mut Sessions) {
+    /// Removes expired [`Session`]s from `sessions`, along with sessions
+    /// whose endpoint has been removed from the cluster since the previous
+    /// prune or whose socket has failed, or whose endpoint hasn't responded
+    /// within `first_response_timeout`. This should be run regularly such as
+    /// on a time interval. This will only write lock `sessions` if it first
+    /// finds sessions to remove. Also updates the number of idle sessions in
+    /// each direction.
+    ///
+    /// `known_endpoints` holds the cluster's endpoints as of the previous
+    /// prune. Sessions to addresses that were never in the cluster, such as
+    /// those of the Shadow filter, aren't removed for that reason, and
+    /// neither are any sessions while the cluster's endpoints are unknown,
+    /// e.g before the first update from the management server.
+    async fn prune_sessions(
+        log: &Logger,
+        sessions: &mut Sessions,
+        cluster_manager: &SharedClusterManager,
+        known_endpoints: &mut HashSet<SocketAddr>,
+        metrics: &Metrics,
+        first_response_timeout: Option<Duration>,
+    ) {
+        let _timer = metrics.map_prune_duration_seconds.start_timer();
         let now = if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
             now.as_secs()
         } else {
             warn!(log, "Failed to get current time when pruning sessions");
             return;
         };
+        let removed_endpoints = match cluster_manager.read().get_all_endpoints() {
+            Some(endpoints) => {
+                let endpoints = endpoints
+                    .iter()
+                    .map(|endpoint| endpoint.address)
+                    .collect::<HashSet<_>>();
+                let removed = known_endpoints
+                    .difference(&endpoints)
+                    .copied()
+                    .collect::<HashSet<_>>();
+                *known_endpoints = endpoints;
+                removed
+            }
+            None => HashSet::new(),
+        };
 
-        let expired_keys = (*sessions.read().await)
-            .iter()
-            .filter(|(_, session)| session.expiration() <= now)
-            .count();
+        let mut expired_keys = 0;
+        let mut idle_downstream = 0;
+        let mut idle_upstream = 0;
+        for session in (*sessions.read().await).values() {
+            if expired_counter(
+                metrics,
+                session,
+                now,
+                &removed_endpoints,
+                first_response_timeout,
+            )
+            .is_some()
+            {
+                expired_keys += 1;
+                continue;
+            }
+            if session.downstream_idle() >= SESSION_IDLE_THRESHOLD {
+                idle_downstream += 1;
+            }
+            if session.upstream_idle() >= SESSION_IDLE_THRESHOLD {
+                idle_upstream += 1;
+            }
+        }
+        metrics.idle_downstream_sessions.set(idle_downstream);
+        metrics.idle_upstream_sessions.set(idle_upstream);
 
         if expired_keys != 0 {
             // Go over the whole sessions map again in case anything expired
             // since acquiring the write lock.
-            sessions
-                .write()
-                .await
-                .retain(|_, session| session.expiration() > now);
+            sessions.write().await.retain(|_, session| {
+                match expired_counter(
+                    metrics,
+                    session,
+                    now,
+                    &removed_endpoints,
+                    first_response_timeout,
+                ) {
+                    Some(counter) => {
+                        counter.inc();
+                        false
+                    }
+                    None => true,
+                }
+            });
         }
     }
 }
 
+/// Returns the counter for the reason `session` should be removed, or `None`
+/// if it should be kept.
+fn expired_counter<'a>(
+    metrics: &'a Metrics,
+    session: &Session,
+    now: u64,
+    removed_endpoints: &HashSet<SocketAddr>,
+    first_response_timeout: Option<Duration>,
+) -> Option<&'a IntCounter> {
+    let unresponsive = || match first_response_timeout {
+        Some(timeout) => session.last_received_upstream().is_none() && session.age() >= timeout,
+        None => false,
+    };
+    if session.failed() {
+        Some(&metrics.sessions_expired_error)
+    } else if removed_endpoints.contains(&session.key().endpoint) {
+        Some(&metrics.sessions_expired_endpoint_removed)
+    } else if unresponsive() {
+        Some(&metrics.sessions_expired_no_response)
+    } else if session.expiration() <= now {
+        Some(&metrics.sessions_expired_ttl)
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
     use std::net::SocketAddr;
     use std::ops::Add;
     use std::sync::Arc;
@@ -122,11 +285,12 @@ mod tests {
     use prometheus::Registry;
     use tokio::sync::{mpsc, watch, RwLock};
 
-    use crate::cluster::Endpoint;
+    use crate::cluster::{cluster_manager::ClusterManager, Endpoint};
+    use crate::config::{Endpoints, UpstreamSocket};
     use crate::filters::{manager::FilterManager, FilterChain};
     use crate::proxy::sessions::metrics::Metrics;
     use crate::proxy::sessions::session_manager::Sessions;
-    use crate::proxy::sessions::{Packet, Session};
+    use crate::proxy::sessions::{Packet, PacketSizeLimit, Session, SessionArgs, SessionKey};
     use crate::test_utils::TestHelper;
 
     use super::SessionManager;
@@ -147,29 +311,50 @@ mod tests {
 
         //let config = Arc::new(config_with_dummy_endpoint().build());
         //let server = Builder::from(config).validate().unwrap().build();
+        let registry = Registry::default();
         SessionManager::run_prune_sessions(
             t.log.clone(),
             sessions.clone(),
+            ClusterManager::fixed(&registry, Endpoints::new(vec![endpoint.clone()]).unwrap())
+                .unwrap(),
+            Metrics::new(&registry).unwrap(),
+            None,
             poll_interval,
             shutdown_rx,
         );
 
-        let key = (from, to);
+        let key = SessionKey::from((from, to));
 
         // Insert key.
         {
             let registry = Registry::default();
             let mut sessions = sessions.write().await;
             sessions.insert(
-                key,
+                key.clone(),
                 Session::new(
                     &t.log,
-                    Metrics::new(&registry).unwrap(),
-                    FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
-                    from,
-                    endpoint.clone(),
-                    send,
-                    ttl,
+                    SessionArgs {
+                        metrics: Metrics::new(&registry).unwrap(),
+                        filter_manager: FilterManager::fixed(Arc::new(
+                            FilterChain::new(vec![], &registry).unwrap(),
+                        )),
+                        from,
+                        client_key: None,
+                        connection_id_header: None,
+                        dest: endpoint.clone(),
+                        sender: send,
+                        ttl,
+                        packet_size_limit: PacketSizeLimit::default(),
+                        compute_pool: None,
+                        upstream_socket: UpstreamSocket::default(),
+                        tunnel: None,
+                        response_only_endpoints: None,
+                        endpoint_health: None,
+                        tap: None,
+                        faults: None,
+                        supervisor: None,
+                        send_queue_size: None,
+                    },
                 )
                 .await
                 .unwrap(),
@@ -215,22 +400,44 @@ mod tests {
         let (send, _recv) = mpsc::channel::<Packet>(1);
         let endpoint = Endpoint::from_address(to);
 
-        let key = (from, to);
+        let key = SessionKey::from((from, to));
         let ttl = Duration::from_secs(1);
+        let registry = Registry::default();
+        let metrics = Metrics::new(&registry).unwrap();
+        let cluster_manager =
+            ClusterManager::fixed(&registry, Endpoints::new(vec![endpoint.clone()]).unwrap())
+                .unwrap();
+        let mut known_endpoints = HashSet::new();
 
         {
             let registry = Registry::default();
             let mut sessions = sessions.write().await;
             sessions.insert(
-                key,
+                key.clone(),
                 Session::new(
                     &t.log,
-                    Metrics::new(&registry).unwrap(),
-                    FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
-                    from,
-                    endpoint.clone(),
-                    send,
-                    ttl,
+                    SessionArgs {
+                        metrics: Metrics::new(&registry).unwrap(),
+                        filter_manager: FilterManager::fixed(Arc::new(
+                            FilterChain::new(vec![], &registry).unwrap(),
+                        )),
+                        from,
+                        client_key: None,
+                        connection_id_header: None,
+                        dest: endpoint.clone(),
+                        sender: send,
+                        ttl,
+                        packet_size_limit: PacketSizeLimit::default(),
+                        compute_pool: None,
+                        upstream_socket: UpstreamSocket::default(),
+                        tunnel: None,
+                        response_only_endpoints: None,
+                        endpoint_health: None,
+                        tap: None,
+                        faults: None,
+                        supervisor: None,
+                        send_queue_size: None,
+                    },
                 )
                 .await
                 .unwrap(),
@@ -245,7 +452,15 @@ mod tests {
         }
 
         // session map should be the same since, we haven't passed expiry
-        SessionManager::prune_sessions(&t.log, &mut sessions).await;
+        SessionManager::prune_sessions(
+            &t.log,
+            &mut sessions,
+            &cluster_manager,
+            &mut known_endpoints,
+            &metrics,
+            None,
+        )
+        .await;
         {
             let map = sessions.read().await;
             assert!(map.contains_key(&key));
@@ -255,7 +470,15 @@ mod tests {
         // Wait until the key has expired.
         tokio::time::sleep_until(tokio::time::Instant::now().add(ttl)).await;
 
-        SessionManager::prune_sessions(&t.log, &mut sessions).await;
+        SessionManager::prune_sessions(
+            &t.log,
+            &mut sessions,
+            &cluster_manager,
+            &mut known_endpoints,
+            &metrics,
+            None,
+        )
+        .await;
         {
             let map = sessions.read().await;
             assert!(
@@ -264,5 +487,296 @@ mod tests {
             );
             assert_eq!(0, map.len(), "len should be 0, bit is {}", map.len());
         }
+        assert_eq!(2, metrics.map_prune_duration_seconds.get_sample_count());
+        assert_eq!(1, metrics.sessions_expired_ttl.get());
+    }
+
+    #[tokio::test]
+    async fn shed_idlest() {
+        let t = TestHelper::default();
+        let session_manager = SessionManager(Arc::new(RwLock::new(HashMap::new())));
+        let to: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let (send, _recv) = mpsc::channel::<Packet>(1);
+        let registry = Registry::default();
+
+        // Sessions that have never received a packet are as idle as they
+        // are old, so the sessions created first are shed first.
+        let clients = (7002..7005)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect::<Vec<_>>();
+        for from in &clients {
+            let session = Session::new(
+                &t.log,
+                SessionArgs {
+                    metrics: Metrics::new(&registry).unwrap(),
+                    filter_manager: FilterManager::fixed(Arc::new(
+                        FilterChain::new(vec![], &registry).unwrap(),
+                    )),
+                    from: *from,
+                    client_key: None,
+                    connection_id_header: None,
+                    dest: Endpoint::from_address(to),
+                    sender: send.clone(),
+                    ttl: Duration::from_secs(10),
+                    packet_size_limit: PacketSizeLimit::default(),
+                    compute_pool: None,
+                    upstream_socket: UpstreamSocket::default(),
+                    tunnel: None,
+                    response_only_endpoints: None,
+                    endpoint_health: None,
+                    tap: None,
+                    faults: None,
+                    supervisor: None,
+                    send_queue_size: None,
+                },
+            )
+            .await
+            .unwrap();
+            session_manager
+                .get_sessions_mut()
+                .await
+                .insert(SessionKey::from((*from, to)), session);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(2, session_manager.shed_idlest(2).await);
+        {
+            let sessions = session_manager.get_sessions().await;
+            assert_eq!(1, sessions.len());
+            assert!(sessions.contains_key(&SessionKey::from((clients[2], to))));
+        }
+        assert_eq!(1, session_manager.shed_idlest(2).await);
+        assert!(session_manager.get_sessions().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn prune_sessions_endpoint_removed() {
+        let t = TestHelper::default();
+        let mut sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let to: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:7002".parse().unwrap();
+        let (send, _recv) = mpsc::channel::<Packet>(1);
+
+        let registry = Registry::default();
+        let metrics = Metrics::new(&registry).unwrap();
+        // The cluster no longer contains the session's endpoint, which it
+        // did when sessions were last pruned.
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(other)]).unwrap(),
+        )
+        .unwrap();
+        let mut known_endpoints = vec![to, other].into_iter().collect::<HashSet<_>>();
+        sessions.write().await.insert(
+            SessionKey::from((from, to)),
+            Session::new(
+                &t.log,
+                SessionArgs {
+                    metrics: metrics.clone(),
+                    filter_manager: FilterManager::fixed(Arc::new(
+                        FilterChain::new(vec![], &registry).unwrap(),
+                    )),
+                    from,
+                    client_key: None,
+                    connection_id_header: None,
+                    dest: Endpoint::from_address(to),
+                    sender: send,
+                    ttl: Duration::from_secs(60),
+                    packet_size_limit: PacketSizeLimit::default(),
+                    compute_pool: None,
+                    upstream_socket: UpstreamSocket::default(),
+                    tunnel: None,
+                    response_only_endpoints: None,
+                    endpoint_health: None,
+                    tap: None,
+                    faults: None,
+                    supervisor: None,
+                    send_queue_size: None,
+                },
+            )
+            .await
+            .unwrap(),
+        );
+
+        SessionManager::prune_sessions(
+            &t.log,
+            &mut sessions,
+            &cluster_manager,
+            &mut known_endpoints,
+            &metrics,
+            None,
+        )
+        .await;
+        assert!(sessions.read().await.is_empty());
+        assert_eq!(1, metrics.sessions_expired_endpoint_removed.get());
+        assert_eq!(0, metrics.sessions_expired_ttl.get());
+        assert_eq!(0, metrics.active_sessions.get());
+        assert_eq!(
+            vec![other].into_iter().collect::<HashSet<_>>(),
+            known_endpoints
+        );
+    }
+
+    #[tokio::test]
+    async fn prune_sessions_keeps_shadow_sessions() {
+        let t = TestHelper::default();
+        let mut sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let to: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        // The Shadow filter's endpoint, which isn't in the cluster.
+        let shadow: SocketAddr = "127.0.0.1:7002".parse().unwrap();
+        let (send, _recv) = mpsc::channel::<Packet>(1);
+
+        let registry = Registry::default();
+        let metrics = Metrics::new(&registry).unwrap();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(to)]).unwrap(),
+        )
+        .unwrap();
+        let mut known_endpoints = HashSet::new();
+        for &dest in &[to, shadow] {
+            let session = Session::new(
+                &t.log,
+                SessionArgs {
+                    metrics: metrics.clone(),
+                    filter_manager: FilterManager::fixed(Arc::new(
+                        FilterChain::new(vec![], &registry).unwrap(),
+                    )),
+                    from,
+                    client_key: None,
+                    connection_id_header: None,
+                    dest: Endpoint::from_address(dest),
+                    sender: send.clone(),
+                    ttl: Duration::from_secs(60),
+                    packet_size_limit: PacketSizeLimit::default(),
+                    compute_pool: None,
+                    upstream_socket: UpstreamSocket::default(),
+                    tunnel: None,
+                    response_only_endpoints: None,
+                    endpoint_health: None,
+                    tap: None,
+                    faults: None,
+                    supervisor: None,
+                    send_queue_size: None,
+                },
+            )
+            .await
+            .unwrap();
+            sessions
+                .write()
+                .await
+                .insert(SessionKey::from((from, dest)), session);
+        }
+
+        // Neither the first prune, which learns the cluster's endpoints, nor
+        // later ones remove the shadow session.
+        for _ in 0..2 {
+            SessionManager::prune_sessions(
+                &t.log,
+                &mut sessions,
+                &cluster_manager,
+                &mut known_endpoints,
+                &metrics,
+                None,
+            )
+            .await;
+            let map = sessions.read().await;
+            assert_eq!(2, map.len());
+            assert!(map.contains_key(&SessionKey::from((from, shadow))));
+        }
+        assert_eq!(0, metrics.sessions_expired_endpoint_removed.get());
+    }
+
+    #[tokio::test]
+    async fn prune_sessions_no_response() {
+        let mut t = TestHelper::default();
+        let mut sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let responsive = t.run_echo_server().await;
+        // A socket that receives packets but never responds to them.
+        let unresponsive_socket = t.create_socket().await;
+        let unresponsive = unresponsive_socket.local_addr().unwrap();
+        let (send, mut recv) = mpsc::channel::<Packet>(1);
+
+        let registry = Registry::default();
+        let metrics = Metrics::new(&registry).unwrap();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![
+                Endpoint::from_address(responsive),
+                Endpoint::from_address(unresponsive),
+            ])
+            .unwrap(),
+        )
+        .unwrap();
+        for &to in &[responsive, unresponsive] {
+            let session = Session::new(
+                &t.log,
+                SessionArgs {
+                    metrics: metrics.clone(),
+                    filter_manager: FilterManager::fixed(Arc::new(
+                        FilterChain::new(vec![], &registry).unwrap(),
+                    )),
+                    from,
+                    client_key: None,
+                    connection_id_header: None,
+                    dest: Endpoint::from_address(to),
+                    sender: send.clone(),
+                    ttl: Duration::from_secs(60),
+                    packet_size_limit: PacketSizeLimit::default(),
+                    compute_pool: None,
+                    upstream_socket: UpstreamSocket::default(),
+                    tunnel: None,
+                    response_only_endpoints: None,
+                    endpoint_health: None,
+                    tap: None,
+                    faults: None,
+                    supervisor: None,
+                    send_queue_size: None,
+                },
+            )
+            .await
+            .unwrap();
+            session.send(b"hello").await.unwrap();
+            sessions
+                .write()
+                .await
+                .insert(SessionKey::from((from, to)), session);
+        }
+        // Wait for the response from the echo server.
+        recv.recv().await.unwrap();
+
+        let first_response_timeout = Some(Duration::from_millis(50));
+        let mut known_endpoints = HashSet::new();
+        SessionManager::prune_sessions(
+            &t.log,
+            &mut sessions,
+            &cluster_manager,
+            &mut known_endpoints,
+            &metrics,
+            first_response_timeout,
+        )
+        .await;
+        assert_eq!(2, sessions.read().await.len());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        SessionManager::prune_sessions(
+            &t.log,
+            &mut sessions,
+            &cluster_manager,
+            &mut known_endpoints,
+            &metrics,
+            first_response_timeout,
+        )
+        .await;
+        {
+            let map = sessions.read().await;
+            assert_eq!(1, map.len());
+            assert!(map.contains_key(&SessionKey::from((from, responsive))));
+        }
+        assert_eq!(1, metrics.sessions_expired_no_response.get());
+        assert_eq!(0, metrics.sessions_expired_ttl.get());
     }
 }
@@ -0,0 +1,133 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+
+use tokio::net::UdpSocket;
+
+use crate::config::UpstreamSocket;
+
+/// Applies the configured `options` to a session's socket.
+pub(super) fn configure(socket: &UdpSocket, options: UpstreamSocket) -> io::Result<()> {
+    if let Some(ttl) = options.ttl {
+        socket.set_ttl(ttl)?;
+    }
+    if let Some(dont_fragment) = options.dont_fragment {
+        set_dont_fragment(socket, dont_fragment)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, dont_fragment: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Path MTU discovery sets the don't fragment bit on all packets, while
+    // disabling it clears the bit and lets routers fragment packets.
+    let value: libc::c_int = if dont_fragment {
+        libc::IP_PMTUDISC_DO
+    } else {
+        libc::IP_PMTUDISC_DONT
+    };
+
+    // Safe since `value` is a valid c_int that outlives the call, and its
+    // size is passed along with it.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_: &UdpSocket, _: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "setting the don't fragment bit is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use tokio::net::UdpSocket;
+
+    use super::configure;
+    use crate::config::UpstreamSocket;
+
+    #[tokio::test]
+    async fn configure_ttl() {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        configure(
+            &socket,
+            UpstreamSocket {
+                ttl: Some(32),
+                dont_fragment: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(32, socket.ttl().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn configure_dont_fragment() {
+        use std::os::unix::io::AsRawFd;
+
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        for &(dont_fragment, expected) in &[
+            (true, libc::IP_PMTUDISC_DO),
+            (false, libc::IP_PMTUDISC_DONT),
+        ] {
+            configure(
+                &socket,
+                UpstreamSocket {
+                    ttl: None,
+                    dont_fragment: Some(dont_fragment),
+                },
+            )
+            .unwrap();
+
+            let mut value: libc::c_int = -1;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // Safe since `value` and `len` are valid and outlive the call.
+            let result = unsafe {
+                libc::getsockopt(
+                    socket.as_raw_fd(),
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                    &mut value as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(0, result);
+            assert_eq!(expected, value);
+        }
+    }
+}
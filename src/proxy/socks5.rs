@@ -0,0 +1,509 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Accepts UDP associations from SOCKS5 clients, as specified by RFC 1928,
+//! so that tooling and network stacks that send UDP through a SOCKS5 proxy
+//! can reach the proxy.
+//!
+//! A client connects over TCP, negotiates no authentication and sends a
+//! UDP ASSOCIATE request. The reply holds the address of a UDP relay socket
+//! that the client then sends its datagrams to, each prefixed with a SOCKS5
+//! UDP header. Whatever their destination, the payloads are handed to the
+//! proxy's workers as if it had received them over UDP from the address the
+//! client connected from, so that they go through the filter chain to
+//! endpoints like any other packet. The association lasts as long as the TCP
+//! connection.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use slog::{debug, o, warn, Logger};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::select;
+use tokio::sync::{watch, Semaphore};
+use tokio::time::{self, Duration, Instant};
+
+use crate::config::MAX_DATAGRAM_SIZE;
+use crate::proxy::relayed::RelayedClients;
+use crate::proxy::server::metrics::Metrics as ProxyMetrics;
+
+/// The version of the protocol.
+const VERSION: u8 = 0x05;
+
+/// The authentication method for clients that don't authenticate.
+const NO_AUTHENTICATION: u8 = 0x00;
+
+/// The method chosen when none of the client's methods are supported.
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+/// The command requesting a UDP association.
+const UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN_NAME: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// How long a client has to request an association after connecting.
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts SOCKS5 UDP associations, handing the payloads of each to the
+/// proxy's workers.
+pub(crate) struct Listener {
+    log: Logger,
+    clients: RelayedClients,
+    metrics: ProxyMetrics,
+    /// How long an association can go without a datagram from the client
+    /// before it is closed.
+    idle_timeout: Duration,
+    /// Limits the number of connections open at once, including those still
+    /// negotiating an association.
+    associations: Arc<Semaphore>,
+}
+
+impl Listener {
+    pub(crate) fn new(
+        log: Logger,
+        clients: RelayedClients,
+        metrics: ProxyMetrics,
+        idle_timeout: Duration,
+        max_associations: usize,
+    ) -> Self {
+        Self {
+            log,
+            clients,
+            metrics,
+            idle_timeout,
+            associations: Arc::new(Semaphore::new(max_associations)),
+        }
+    }
+
+    /// Accepts connections on `listener` in the background until
+    /// `shutdown_rx` is notified.
+    pub(crate) fn run(self, listener: TcpListener, mut shutdown_rx: watch::Receiver<()>) {
+        let listener_ref = Arc::new(self);
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, from)) => {
+                            listener_ref.clone().accept(stream, from, shutdown_rx.clone())
+                        }
+                        Err(err) => {
+                            warn!(listener_ref.log, "Failed to accept connection"; "error" => %err)
+                        }
+                    },
+                    _ = shutdown_rx.changed() => return,
+                }
+            }
+        });
+    }
+
+    /// Serves the association requested over `stream` in the background, or
+    /// closes the connection if too many are already open.
+    fn accept(
+        self: Arc<Self>,
+        stream: TcpStream,
+        from: SocketAddr,
+        shutdown_rx: watch::Receiver<()>,
+    ) {
+        let permit = match self.associations.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                self.metrics.socks5_associations_limited.inc();
+                debug!(self.log, "Too many SOCKS5 associations are open"; "socks5_from" => from);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            let log = self.log.new(o!("socks5_from" => from));
+            if let Err(err) = self.associate(&log, stream, from, shutdown_rx).await {
+                debug!(log, "SOCKS5 association failed"; "error" => %err);
+            }
+            drop(permit);
+        });
+    }
+
+    async fn associate(
+        &self,
+        log: &Logger,
+        mut stream: TcpStream,
+        client: SocketAddr,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> io::Result<()> {
+        let relay = match time::timeout(NEGOTIATION_TIMEOUT, negotiate(&mut stream)).await {
+            Ok(Ok(relay)) => relay,
+            Ok(Err(err)) => {
+                self.metrics.socks5_associations_rejected.inc();
+                return Err(err);
+            }
+            Err(_) => {
+                self.metrics.socks5_associations_rejected.inc();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the negotiation timed out",
+                ));
+            }
+        };
+        self.metrics.socks5_associations_accepted.inc();
+        debug!(log, "Accepted SOCKS5 association"; "relay" => ?relay.local_addr().ok());
+
+        self.metrics.active_socks5_associations.inc();
+        let result = self.relay(log, stream, relay, client, shutdown_rx).await;
+        self.metrics.active_socks5_associations.dec();
+        result
+    }
+
+    /// Relays datagrams between the client at `client`, as it connected
+    /// from, and the proxy until either the client closes the connection or
+    /// goes idle, or `shutdown_rx` is notified.
+    async fn relay(
+        &self,
+        log: &Logger,
+        mut stream: TcpStream,
+        relay: UdpSocket,
+        client: SocketAddr,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) -> io::Result<()> {
+        // The proxy sees the client's datagrams as coming from the address
+        // it connected from, which is unique to the association.
+        let (association, mut responses) = self.clients.register(client);
+
+        // The proxy's packets are relayed to wherever the client last sent a
+        // datagram from, prefixed with that datagram's header so that they
+        // appear to come from the destination the client sent it to.
+        let mut last_datagram: Option<(SocketAddr, Vec<u8>)> = None;
+        let mut client_buf = vec![0; MAX_DATAGRAM_SIZE];
+        let mut control_buf = [0; 64];
+        let idle = time::sleep(self.idle_timeout);
+        tokio::pin!(idle);
+        loop {
+            select! {
+                received = relay.recv_from(&mut client_buf) => {
+                    let (size, from) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            debug!(log, "Error receiving datagram from client"; "error" => %err);
+                            continue;
+                        }
+                    };
+                    // Only the host that requested the association can use it.
+                    if from.ip() != client.ip() {
+                        continue;
+                    }
+                    let header_len = match udp_header_len(&client_buf[..size]) {
+                        Some(header_len) => header_len,
+                        None => {
+                            debug!(log, "Dropping invalid or fragmented datagram"; "from" => from);
+                            continue;
+                        }
+                    };
+                    idle.as_mut().reset(Instant::now() + self.idle_timeout);
+                    last_datagram = Some((from, client_buf[..header_len].to_vec()));
+                    if !association.send(client_buf[header_len..size].to_vec()).await {
+                        return Ok(());
+                    }
+                }
+                Some(packet) = responses.recv() => {
+                    if let Some((address, header)) = &last_datagram {
+                        let mut datagram = Vec::with_capacity(header.len() + packet.len());
+                        datagram.extend_from_slice(header);
+                        datagram.extend_from_slice(&packet);
+                        if let Err(err) = relay.send_to(&datagram, *address).await {
+                            debug!(log, "Error sending datagram to client"; "error" => %err);
+                        }
+                    }
+                }
+                // Nothing else is expected over the connection, which the
+                // client closes to end the association.
+                read = stream.read(&mut control_buf) => match read {
+                    Ok(0) => return Ok(()),
+                    Ok(_) => {}
+                    Err(err) => return Err(err),
+                },
+                _ = &mut idle => return Ok(()),
+                _ = shutdown_rx.changed() => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Negotiates a UDP association with the client, returning the relay socket
+/// it sends datagrams to.
+async fn negotiate(stream: &mut TcpStream) -> io::Result<UdpSocket> {
+    // The greeting lists the authentication methods the client supports.
+    if stream.read_u8().await? != VERSION {
+        return Err(invalid_data("unsupported SOCKS version"));
+    }
+    let mut methods = vec![0; usize::from(stream.read_u8().await?)];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(invalid_data("the client requires authentication"));
+    }
+    stream.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    // The request's version, command, a reserved byte and the address type.
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        return Err(invalid_data("unsupported SOCKS version"));
+    }
+    if request[1] != UDP_ASSOCIATE {
+        stream.write_all(&reply(REPLY_COMMAND_NOT_SUPPORTED, None)).await?;
+        return Err(invalid_data("unsupported command"));
+    }
+    let address_len = match request[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN_NAME => usize::from(stream.read_u8().await?),
+        _ => {
+            stream.write_all(&reply(REPLY_ADDRESS_TYPE_NOT_SUPPORTED, None)).await?;
+            return Err(invalid_data("unsupported address type"));
+        }
+    };
+    // The address the client will send datagrams from is often unknown to
+    // it, so it is ignored in favour of the client's IP address.
+    let mut address = vec![0; address_len + 2];
+    stream.read_exact(&mut address).await?;
+
+    // The relay is bound to the address the client connected to, so that the
+    // client can reach it at the address in the reply.
+    let relay = UdpSocket::bind(SocketAddr::new(stream.local_addr()?.ip(), 0)).await?;
+    stream.write_all(&reply(REPLY_SUCCEEDED, Some(relay.local_addr()?))).await?;
+    Ok(relay)
+}
+
+/// Returns a reply to a request, holding `address` if the request succeeded.
+fn reply(code: u8, address: Option<SocketAddr>) -> Vec<u8> {
+    let address = address.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    let mut reply = vec![VERSION, code, 0x00];
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            reply.push(ATYP_IPV4);
+            reply.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            reply.push(ATYP_IPV6);
+            reply.extend_from_slice(&ip.octets());
+        }
+    }
+    reply.extend_from_slice(&address.port().to_be_bytes());
+    reply
+}
+
+/// Returns the length of the header of a datagram from the client, or `None`
+/// if the header is invalid or the datagram is a fragment.
+fn udp_header_len(datagram: &[u8]) -> Option<usize> {
+    // Two reserved bytes, the fragment number, the address type, the
+    // destination address and the destination port.
+    let (fragment, address_type) = (*datagram.get(2)?, *datagram.get(3)?);
+    // Reassembling fragments is optional, and isn't supported.
+    if fragment != 0 {
+        return None;
+    }
+    let address_len = match address_type {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN_NAME => 1 + usize::from(*datagram.get(4)?),
+        _ => return None,
+    };
+    let len = 4 + address_len + 2;
+    if datagram.len() < len {
+        return None;
+    }
+    Some(len)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use prometheus::Registry;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use tokio::sync::mpsc;
+    use tokio::time::{timeout, Duration};
+
+    use super::{udp_header_len, Listener};
+    use crate::proxy::relayed::RelayedClients;
+    use crate::proxy::server::metrics::Metrics as ProxyMetrics;
+    use crate::test_utils::TestHelper;
+
+    /// Runs a listener allowing `max_associations`, whose packets are echoed
+    /// back by the proxy. Returns the listener's address and metrics, and the
+    /// addresses that the proxy received packets from.
+    async fn run_listener(
+        t: &mut TestHelper,
+        max_associations: usize,
+    ) -> (
+        SocketAddr,
+        ProxyMetrics,
+        mpsc::UnboundedReceiver<SocketAddr>,
+    ) {
+        let metrics = ProxyMetrics::new(&Registry::default()).unwrap();
+        let (clients, mut packets) = RelayedClients::new();
+        let (from_tx, from_rx) = mpsc::unbounded_channel();
+        let echo = clients.clone();
+        tokio::spawn(async move {
+            while let Some((from, contents, _)) = packets.recv().await {
+                echo.deliver(from, &contents);
+                let _ = from_tx.send(from);
+            }
+        });
+
+        let listener = Listener::new(
+            t.log.clone(),
+            clients,
+            metrics.clone(),
+            Duration::from_secs(5),
+            max_associations,
+        );
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        listener.run(socket, t.get_shutdown_subscriber().await);
+        (address, metrics, from_rx)
+    }
+
+    #[test]
+    fn header_len() {
+        assert_eq!(Some(10), udp_header_len(&[0, 0, 0, 1, 127, 0, 0, 1, 0, 80, 1]));
+        assert_eq!(Some(22), udp_header_len(&[&[0, 0, 0, 4][..], &[0; 18]].concat()));
+        assert_eq!(
+            Some(18),
+            udp_header_len(&[&[0, 0, 0, 3, 11][..], b"example.com", &[0, 80]].concat())
+        );
+        // Fragments aren't supported.
+        assert_eq!(None, udp_header_len(&[0, 0, 1, 1, 127, 0, 0, 1, 0, 80, 1]));
+        // Unknown address types, and truncated headers.
+        assert_eq!(None, udp_header_len(&[0, 0, 0, 2, 127, 0, 0, 1, 0, 80, 1]));
+        assert_eq!(None, udp_header_len(&[0, 0, 0, 1, 127, 0, 0, 1, 0]));
+    }
+
+    #[tokio::test]
+    async fn udp_associate() {
+        let mut t = TestHelper::default();
+        let (address, metrics, mut received_from) = run_listener(&mut t, 16).await;
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!([5, 0], method);
+
+        stream
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut reply = [0; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!([5, 0, 0, 1, 127, 0, 0, 1], reply[..8]);
+        let relay = SocketAddr::from(([127, 0, 0, 1], u16::from_be_bytes([reply[8], reply[9]])));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let header = [0, 0, 0, 1, 192, 0, 2, 6, 0x1e, 0x61];
+        for packet in &["hello", "world"] {
+            let datagram = [&header[..], packet.as_bytes()].concat();
+            socket.send_to(&datagram, relay).await.unwrap();
+            let mut buf = vec![0; 1024];
+            let (size, from) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(relay, from);
+            assert_eq!(datagram, buf[..size].to_vec());
+            // The proxy sees the packets as coming from the client.
+            assert_eq!(
+                stream.local_addr().unwrap(),
+                received_from.recv().await.unwrap()
+            );
+        }
+        assert_eq!(1, metrics.socks5_associations_accepted.get());
+        assert_eq!(1, metrics.active_socks5_associations.get());
+    }
+
+    #[tokio::test]
+    async fn limit_associations() {
+        let mut t = TestHelper::default();
+        let (address, metrics, _) = run_listener(&mut t, 1).await;
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!([5, 0], method);
+
+        // Connections beyond the limit are closed straight away.
+        let mut other = TcpStream::connect(address).await.unwrap();
+        assert_eq!(0, other.read(&mut method).await.unwrap());
+        assert_eq!(1, metrics.socks5_associations_limited.get());
+
+        // Until an association ends.
+        drop(stream);
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let mut stream = TcpStream::connect(address).await.unwrap();
+                stream.write_all(&[5, 1, 0]).await.unwrap();
+                if stream.read_exact(&mut method).await.is_ok() {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_unsupported_requests() {
+        let mut t = TestHelper::default();
+        let (address, metrics, _) = run_listener(&mut t, 16).await;
+
+        // Only connecting without authentication is supported.
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&[5, 1, 2]).await.unwrap();
+        let mut method = [0; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!([5, 0xff], method);
+        assert_eq!(0, stream.read(&mut method).await.unwrap());
+
+        // As is only the UDP ASSOCIATE command.
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!([5, 0], method);
+        stream
+            .write_all(&[5, 1, 0, 1, 192, 0, 2, 6, 0, 80])
+            .await
+            .unwrap();
+        let mut reply = [0; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(7, reply[1]);
+
+        assert_eq!(0, stream.read(&mut reply).await.unwrap());
+        assert_eq!(2, metrics.socks5_associations_rejected.get());
+        assert_eq!(0, metrics.socks5_associations_accepted.get());
+    }
+}
@@ -0,0 +1,621 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tunnels the packets of sessions through TCP connections, optionally
+//! secured with TLS, to a peer proxy that forwards them to endpoints over
+//! UDP. This lets clients on networks that block UDP reach game servers.
+//!
+//! Each tunnel carries the packets of a single session. It starts with
+//! [`MAGIC`] followed by a frame containing the endpoint's address, and
+//! every frame after that contains a packet. A frame is the length of its
+//! contents as a big endian u16, followed by the contents.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::Arc;
+
+use slog::{debug, o, warn, Logger};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Duration};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{ClientConfig, NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::webpki::{DNSName, DNSNameRef};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::config::{ListenerTls, TunnelListener, TunnelPeer, MAX_DATAGRAM_SIZE};
+use crate::proxy::server::metrics::Metrics as ProxyMetrics;
+
+/// The bytes that a tunnel starts with.
+const MAGIC: &[u8] = b"QUILKIN_TUNNEL_V1";
+
+/// The maximum number of packets waiting to go through a tunnel in either
+/// direction.
+const QUEUE_SIZE: usize = 1024;
+
+/// How long a peer has to start a tunnel after connecting, including the
+/// TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection that a tunnel runs over, secured with TLS or not.
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// Splits `address` into its host and port, or returns `None` if it isn't a
+/// host followed by a port.
+pub(crate) fn split_host_port(address: &str) -> Option<(&str, u16)> {
+    let index = address.rfind(':')?;
+    let host = address[..index]
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = address[index + 1..].parse().ok()?;
+    if host.is_empty() {
+        None
+    } else {
+        Some((host, port))
+    }
+}
+
+/// Opens tunnels to the peer proxy for sessions.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    address: String,
+    tls: Option<(TlsConnector, DNSName)>,
+    connect_timeout: Duration,
+}
+
+impl Connector {
+    pub(crate) fn new(config: &TunnelPeer) -> io::Result<Self> {
+        let tls = match &config.tls {
+            Some(tls) => {
+                let mut client_config = ClientConfig::new();
+                match &tls.ca_certificate {
+                    Some(path) => {
+                        let (added, _) = client_config
+                            .root_store
+                            .add_pem_file(&mut open(path)?)
+                            .map_err(|()| invalid_pem(path))?;
+                        if added == 0 {
+                            return Err(invalid_pem(path));
+                        }
+                    }
+                    None => client_config
+                        .root_store
+                        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+                }
+
+                let server_name = match &tls.server_name {
+                    Some(server_name) => server_name.as_str(),
+                    None => split_host_port(&config.address)
+                        .map(|(host, _)| host)
+                        .unwrap_or_default(),
+                };
+                let server_name = DNSNameRef::try_from_ascii_str(server_name)
+                    .map_err(|_| {
+                        invalid_data(format!("{} is not a valid DNS name", server_name))
+                    })?
+                    .to_owned();
+
+                Some((TlsConnector::from(Arc::new(client_config)), server_name))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            address: config.address.clone(),
+            tls,
+            connect_timeout: config.connect_timeout,
+        })
+    }
+
+    /// Opens a tunnel to `dest` in the background, returning the sender of
+    /// packets to the endpoint and the receiver of packets from it. Packets
+    /// sent before the tunnel is established are queued. The receiver is
+    /// closed once the tunnel is, including if it couldn't be established.
+    pub(crate) fn open(
+        &self,
+        log: &Logger,
+        dest: SocketAddr,
+    ) -> (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) {
+        let log = log.new(o!("tunnel_peer" => self.address.clone()));
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(QUEUE_SIZE);
+        let (incoming_tx, incoming_rx) = mpsc::channel(QUEUE_SIZE);
+        let connector = self.clone();
+        tokio::spawn(async move {
+            let stream = match time::timeout(connector.connect_timeout, connector.connect(dest))
+                .await
+            {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    warn!(log, "Failed to open tunnel"; "error" => %err);
+                    return;
+                }
+                Err(_) => {
+                    warn!(log, "Timed out opening tunnel");
+                    return;
+                }
+            };
+            debug!(log, "Opened tunnel");
+
+            let (reader, writer) = tokio::io::split(stream);
+            let write_log = log.clone();
+            tokio::spawn(async move {
+                if let Err(err) = write_frames(writer, outgoing_rx).await {
+                    debug!(write_log, "Error writing to tunnel"; "error" => %err);
+                }
+            });
+            match read_frames(reader, incoming_tx).await {
+                Ok(()) => debug!(log, "Tunnel closed"),
+                Err(err) => warn!(log, "Tunnel closed with an error"; "error" => %err),
+            }
+        });
+        (outgoing_tx, incoming_rx)
+    }
+
+    async fn connect(&self, dest: SocketAddr) -> io::Result<Box<dyn Stream>> {
+        let stream = TcpStream::connect(&self.address).await?;
+        stream.set_nodelay(true)?;
+        let mut stream: Box<dyn Stream> = match &self.tls {
+            Some((connector, server_name)) => {
+                Box::new(connector.connect(server_name.as_ref(), stream).await?)
+            }
+            None => Box::new(stream),
+        };
+        stream.write_all(MAGIC).await?;
+        write_frame(&mut stream, dest.to_string().as_bytes()).await?;
+        stream.flush().await?;
+        Ok(stream)
+    }
+}
+
+/// Accepts tunnels from peer proxies and forwards their packets to
+/// endpoints over UDP.
+pub(crate) struct Listener {
+    log: Logger,
+    acceptor: Option<TlsAcceptor>,
+    cluster_manager: SharedClusterManager,
+    metrics: ProxyMetrics,
+    /// How long a tunnel can go without a packet from the peer before it
+    /// is closed.
+    idle_timeout: Duration,
+}
+
+impl Listener {
+    pub(crate) fn new(
+        log: Logger,
+        config: &TunnelListener,
+        cluster_manager: SharedClusterManager,
+        metrics: ProxyMetrics,
+        idle_timeout: Duration,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            log,
+            acceptor: config.tls.as_ref().map(tls_acceptor).transpose()?,
+            cluster_manager,
+            metrics,
+            idle_timeout,
+        })
+    }
+
+    /// Accepts tunnels on `listener` in the background until `shutdown_rx`
+    /// is notified.
+    pub(crate) fn run(self, listener: TcpListener, mut shutdown_rx: watch::Receiver<()>) {
+        let listener_ref = Arc::new(self);
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    accepted = listener.accept() => {
+                        let (stream, from) = match accepted {
+                            Ok(accepted) => accepted,
+                            Err(err) => {
+                                warn!(listener_ref.log, "Failed to accept tunnel"; "error" => %err);
+                                continue;
+                            }
+                        };
+                        let listener_ref = listener_ref.clone();
+                        let shutdown_rx = shutdown_rx.clone();
+                        tokio::spawn(async move {
+                            listener_ref.handle(stream, from, shutdown_rx).await
+                        });
+                    }
+                    _ = shutdown_rx.changed() => return,
+                }
+            }
+        });
+    }
+
+    async fn handle(&self, stream: TcpStream, from: SocketAddr, shutdown_rx: watch::Receiver<()>) {
+        let log = self.log.new(o!("tunnel_from" => from));
+        let (stream, dest) = match time::timeout(HANDSHAKE_TIMEOUT, self.accept(stream)).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(err)) => {
+                self.metrics.tunnels_rejected_total.inc();
+                debug!(log, "Rejected invalid tunnel"; "error" => %err);
+                return;
+            }
+            Err(_) => {
+                self.metrics.tunnels_rejected_total.inc();
+                debug!(log, "Rejected tunnel that was not started in time");
+                return;
+            }
+        };
+
+        // Otherwise, anyone able to open a tunnel could use the proxy to send
+        // packets anywhere.
+        if !self.is_endpoint(dest) {
+            self.metrics.tunnels_rejected_total.inc();
+            warn!(log, "Rejected tunnel to an address that is not an endpoint"; "dest" => dest);
+            return;
+        }
+
+        debug!(log, "Accepted tunnel"; "dest" => dest);
+        self.metrics.tunnels_total.inc();
+        self.metrics.active_tunnels.inc();
+        match self.forward(&log, stream, dest, shutdown_rx).await {
+            Ok(()) => debug!(log, "Tunnel closed"; "dest" => dest),
+            Err(err) => debug!(log, "Tunnel closed with an error"; "dest" => dest, "error" => %err),
+        }
+        self.metrics.active_tunnels.dec();
+    }
+
+    /// Completes the TLS handshake if enabled, and reads the start of the
+    /// tunnel, returning the address of the endpoint that it is for.
+    async fn accept(&self, stream: TcpStream) -> io::Result<(Box<dyn Stream>, SocketAddr)> {
+        stream.set_nodelay(true)?;
+        let mut stream: Box<dyn Stream> = match &self.acceptor {
+            Some(acceptor) => Box::new(acceptor.accept(stream).await?),
+            None => Box::new(stream),
+        };
+
+        let mut magic = vec![0; MAGIC.len()];
+        stream.read_exact(&mut magic).await?;
+        if magic != MAGIC {
+            return Err(invalid_data("the connection is not a tunnel"));
+        }
+        let dest = read_frame(&mut stream)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let dest = std::str::from_utf8(&dest)
+            .ok()
+            .and_then(|dest| dest.parse().ok())
+            .ok_or_else(|| invalid_data("invalid endpoint address"))?;
+        Ok((stream, dest))
+    }
+
+    /// Returns whether `address` is the address of an endpoint.
+    fn is_endpoint(&self, address: SocketAddr) -> bool {
+        self.cluster_manager
+            .read()
+            .get_all_endpoints()
+            .map(|endpoints| endpoints.iter().any(|endpoint| endpoint.address == address))
+            .unwrap_or(false)
+    }
+
+    /// Forwards packets between the tunnel and `dest` until either the peer
+    /// closes the tunnel or goes idle, or `shutdown_rx` is notified.
+    async fn forward(
+        &self,
+        log: &Logger,
+        stream: Box<dyn Stream>,
+        dest: SocketAddr,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) -> io::Result<()> {
+        let socket = Arc::new(UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?);
+        socket.connect(dest).await?;
+        let (mut reader, mut writer) = tokio::io::split(stream);
+
+        // Packets from the peer are forwarded on a task of their own, since
+        // waiting for a frame can't be cancelled without losing its start.
+        let send_socket = socket.clone();
+        let send_log = log.clone();
+        let idle_timeout = self.idle_timeout;
+        let mut from_peer = tokio::spawn(async move {
+            loop {
+                let packet = match time::timeout(idle_timeout, read_frame(&mut reader)).await {
+                    Ok(Ok(Some(packet))) => packet,
+                    Ok(Ok(None)) | Err(_) => return Ok(()),
+                    Ok(Err(err)) => return Err(err),
+                };
+                // The endpoint may be briefly unreachable, which shouldn't
+                // close the tunnel.
+                if let Err(err) = send_socket.send(&packet).await {
+                    debug!(send_log, "Error sending packet to endpoint"; "error" => %err);
+                }
+            }
+        });
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let result = loop {
+            select! {
+                received = socket.recv(&mut buf) => {
+                    let size = match received {
+                        Ok(size) => size,
+                        Err(err) => {
+                            debug!(log, "Error receiving packet from endpoint"; "error" => %err);
+                            continue;
+                        }
+                    };
+                    let written = async {
+                        write_frame(&mut writer, &buf[..size]).await?;
+                        writer.flush().await
+                    };
+                    if let Err(err) = written.await {
+                        break Err(err);
+                    }
+                }
+                result = &mut from_peer => {
+                    break result
+                        .unwrap_or_else(|err| Err(io::Error::new(io::ErrorKind::Other, err)));
+                }
+                _ = shutdown_rx.changed() => break Ok(()),
+            }
+        };
+        from_peer.abort();
+        result
+    }
+}
+
+/// Returns an acceptor securing connections with the certificate in
+/// `config`.
+pub(crate) fn tls_acceptor(config: &ListenerTls) -> io::Result<TlsAcceptor> {
+    let certificates = pemfile::certs(&mut open(&config.certificate)?)
+        .map_err(|()| invalid_pem(&config.certificate))?;
+    if certificates.is_empty() {
+        return Err(invalid_pem(&config.certificate));
+    }
+    let private_key = read_private_key(&config.private_key)?;
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(certificates, private_key)
+        .map_err(|err| invalid_data(err.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Writes a frame containing `contents`.
+async fn write_frame<W: AsyncWrite + Unpin + ?Sized>(
+    writer: &mut W,
+    contents: &[u8],
+) -> io::Result<()> {
+    let len = u16::try_from(contents.len())
+        .map_err(|_| invalid_data("the packet is too large to be tunnelled"))?;
+    let mut frame = Vec::with_capacity(2 + contents.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(contents);
+    writer.write_all(&frame).await
+}
+
+/// Reads a frame, returning its contents or `None` if the connection was
+/// closed.
+async fn read_frame<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut contents = vec![0; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut contents).await?;
+    Ok(Some(contents))
+}
+
+/// Writes the packets received on `packets` as frames until it is closed,
+/// then closes the connection.
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut packets: mpsc::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    while let Some(packet) = packets.recv().await {
+        write_frame(&mut writer, &packet).await?;
+        // Packets that are already waiting are flushed together.
+        while let Ok(packet) = packets.try_recv() {
+            write_frame(&mut writer, &packet).await?;
+        }
+        writer.flush().await?;
+    }
+    writer.shutdown().await
+}
+
+/// Sends the packets read from frames on `packets` until either the
+/// connection or `packets` is closed.
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    packets: mpsc::Sender<Vec<u8>>,
+) -> io::Result<()> {
+    while let Some(packet) = read_frame(&mut reader).await? {
+        if packets.send(packet).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn read_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut keys =
+        pemfile::pkcs8_private_keys(&mut open(path)?).map_err(|()| invalid_pem(path))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(|()| invalid_pem(path))?;
+    }
+    keys.pop().ok_or_else(|| invalid_pem(path))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path).map(BufReader::new).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to open {}: {}", path.display(), err),
+        )
+    })
+}
+
+fn invalid_pem(path: &Path) -> io::Error {
+    invalid_data(format!("{} does not contain valid PEM data", path.display()))
+}
+
+fn invalid_data(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    use prometheus::Registry;
+    use tokio::net::TcpListener;
+    use tokio::time::{timeout, Duration};
+
+    use super::{split_host_port, Connector, Listener};
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, ListenerTls, TunnelListener, TunnelPeer, TunnelPeerTls};
+    use crate::proxy::server::metrics::Metrics as ProxyMetrics;
+    use crate::test_utils::TestHelper;
+
+    fn cert_path(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "tests", "certs", name]
+            .iter()
+            .collect()
+    }
+
+    /// Runs a tunnel listener forwarding to `endpoint`, returning its
+    /// address and metrics.
+    async fn run_listener(
+        t: &mut TestHelper,
+        endpoint: SocketAddr,
+        tls: Option<ListenerTls>,
+    ) -> (SocketAddr, ProxyMetrics) {
+        let registry = Registry::default();
+        let metrics = ProxyMetrics::new(&registry).unwrap();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(endpoint)]).unwrap(),
+        )
+        .unwrap();
+        let listener = Listener::new(
+            t.log.clone(),
+            &TunnelListener { port: 0, tls },
+            cluster_manager,
+            metrics.clone(),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        listener.run(socket, t.get_shutdown_subscriber().await);
+        (address, metrics)
+    }
+
+    #[test]
+    fn split_address() {
+        assert_eq!(
+            Some(("relay.example.com", 443)),
+            split_host_port("relay.example.com:443")
+        );
+        assert_eq!(Some(("::1", 7443)), split_host_port("[::1]:7443"));
+        assert_eq!(None, split_host_port("relay.example.com"));
+        assert_eq!(None, split_host_port(":443"));
+        assert_eq!(None, split_host_port("relay.example.com:http"));
+    }
+
+    #[tokio::test]
+    async fn tunnel() {
+        let mut t = TestHelper::default();
+        let endpoint = t.run_echo_server().await;
+        let (address, metrics) = run_listener(&mut t, endpoint, None).await;
+
+        let connector = Connector::new(&TunnelPeer {
+            address: address.to_string(),
+            tls: None,
+            connect_timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        let (tx, mut rx) = connector.open(&t.log, endpoint);
+        for packet in &["hello", "world"] {
+            tx.send(packet.as_bytes().to_vec()).await.unwrap();
+            let echoed = timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(packet.as_bytes(), &echoed[..]);
+        }
+        assert_eq!(1, metrics.tunnels_total.get());
+        assert_eq!(1, metrics.active_tunnels.get());
+    }
+
+    #[tokio::test]
+    async fn tunnel_tls() {
+        let mut t = TestHelper::default();
+        let endpoint = t.run_echo_server().await;
+        let (address, _) = run_listener(
+            &mut t,
+            endpoint,
+            Some(ListenerTls {
+                certificate: cert_path("cert.pem"),
+                private_key: cert_path("key.pem"),
+            }),
+        )
+        .await;
+
+        let connector = Connector::new(&TunnelPeer {
+            address: address.to_string(),
+            tls: Some(TunnelPeerTls {
+                server_name: Some("localhost".into()),
+                ca_certificate: Some(cert_path("ca.pem")),
+            }),
+            connect_timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        let (tx, mut rx) = connector.open(&t.log, endpoint);
+        tx.send(b"hello".to_vec()).await.unwrap();
+        let echoed = timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"hello", &echoed[..]);
+    }
+
+    #[tokio::test]
+    async fn reject_unknown_endpoint() {
+        let mut t = TestHelper::default();
+        let endpoint = t.run_echo_server().await;
+        let other_endpoint = t.run_echo_server().await;
+        let (address, metrics) = run_listener(&mut t, endpoint, None).await;
+
+        let connector = Connector::new(&TunnelPeer {
+            address: address.to_string(),
+            tls: None,
+            connect_timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        let (tx, mut rx) = connector.open(&t.log, other_endpoint);
+        tx.send(b"hello".to_vec()).await.unwrap();
+        // The tunnel is closed without any packets going through it.
+        assert_eq!(
+            None,
+            timeout(Duration::from_secs(5), rx.recv()).await.unwrap()
+        );
+        assert_eq!(1, metrics.tunnels_rejected_total.get());
+        assert_eq!(0, metrics.tunnels_total.get());
+    }
+}
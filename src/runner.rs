@@ -14,46 +14,41 @@
  * limitations under the License.
  */
 
-use std::{fs::File, sync::Arc};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use clap::App;
-use slog::{info, o};
+use clap::{App, ArgMatches, SubCommand};
+use slog::{info, o, Logger};
 use tokio::{signal, sync::watch};
 
 use crate::{
-    config::Config,
+    config::{Config, ConfigKey, ProfileError, KEY_ENV, KEY_FILE_ENV},
     filters::{DynFilterFactory, FilterRegistry, FilterSet},
-    proxy::{logger, Builder},
+    load,
+    proxy::{logger, version, Builder},
+    test_server::{self, TestServer},
 };
 
 #[cfg(doc)]
 use crate::filters::FilterFactory;
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
+#[cfg(windows)]
+mod service;
+
 const CONFIG_FILE: &str = "quilkin.yaml";
 
 pub type Error = Box<dyn std::error::Error>;
 
-#[cfg(debug_assertions)]
-fn version() -> String {
-    format!("{}+debug", VERSION)
-}
-
-#[cfg(not(debug_assertions))]
-fn version() -> String {
-    VERSION.into()
-}
-
 /// Start and run a proxy. Any passed in [`FilterFactory`]s are included
 /// alongside the default filter factories.
 pub async fn run(
     filter_factories: impl IntoIterator<Item = DynFilterFactory>,
 ) -> Result<(), Error> {
     let version = version();
-    let base_logger = logger();
-    let log = base_logger.new(o!("source" => "run"));
-
-    let matches = App::new(clap::crate_name!())
+    let app = App::new(clap::crate_name!())
         .version(version.as_str())
         .about(clap::crate_description!())
         .arg(
@@ -64,7 +59,42 @@ pub async fn run(
                 .help("The yaml configuration file")
                 .takes_value(true),
         )
-        .get_matches();
+        .subcommand(test_server_command())
+        .subcommand(load_command())
+        .subcommand(doctor_command())
+        .subcommand(list_filters_command())
+        .subcommand(encrypt_config_command());
+    #[cfg(windows)]
+    let app = app.arg(service::arg());
+    let matches = app.get_matches();
+
+    #[cfg(windows)]
+    let base_logger = if matches.is_present(service::ARG) {
+        service::logger()?
+    } else {
+        logger()
+    };
+    #[cfg(not(windows))]
+    let base_logger = logger();
+    let log = base_logger.new(o!("source" => "run"));
+
+    if let Some(matches) = matches.subcommand_matches("test-server") {
+        return run_test_server(&base_logger, matches).await;
+    }
+    if let Some(matches) = matches.subcommand_matches("load") {
+        return run_load(&base_logger, matches).await;
+    }
+    if let Some(matches) = matches.subcommand_matches("encrypt-config") {
+        return run_encrypt_config(matches);
+    }
+
+    let filter_registry = FilterRegistry::new(FilterSet::default_with(
+        &log,
+        filter_factories.into_iter(),
+    ));
+    if let Some(matches) = matches.subcommand_matches("list-filters") {
+        return run_list_filters(&filter_registry, matches);
+    }
 
     let config_env = std::env::var("QUILKIN_FILENAME").ok();
     let config_path = matches
@@ -76,25 +106,38 @@ pub async fn run(
         // Path wll always be `Some` here.
         .map(Option::unwrap)?;
 
+    if let Some(matches) = matches.subcommand_matches("doctor") {
+        return run_doctor(base_logger, &config_path, filter_registry, matches).await;
+    }
+
     info!(log, "Starting Quilkin"; "version" => version);
 
-    let config = File::open(&config_path)
-        .or_else(|_| get_config_file())
-        .map_err(Error::from)
-        .and_then(|file| Config::from_reader(file).map_err(Error::from))
-        .map(Arc::new)?;
+    let config = load_config(&config_path)?;
 
     info!(log, "Found configuration file"; "path" => config_path.display());
 
     let server = Builder::from(config)
         .with_log(base_logger)
-        .with_filter_registry(FilterRegistry::new(FilterSet::default_with(
-            &log,
-            filter_factories.into_iter(),
-        )))
+        .with_filter_registry(filter_registry)
         .validate()?
         .build();
 
+    #[cfg(windows)]
+    if matches.is_present(service::ARG) {
+        return service::run(log, server).map_err(Error::from);
+    }
+
+    if let Err(err) = server.run(shutdown_signal()).await {
+        info!(log, "Shutting down with error"; "error" => %err);
+        Err(Error::from(err))
+    } else {
+        info!(log, "Shutting down");
+        Ok(())
+    }
+}
+
+/// Returns a receiver that is sent a value once the process is interrupted.
+fn shutdown_signal() -> watch::Receiver<()> {
     let (shutdown_tx, shutdown_rx) = watch::channel::<()>(());
     tokio::spawn(async move {
         // Don't unwrap in order to ensure that we execute
@@ -102,22 +145,267 @@ pub async fn run(
         signal::ctrl_c().await.ok();
         shutdown_tx.send(()).ok();
     });
+    shutdown_rx
+}
 
-    if let Err(err) = server.run(shutdown_rx).await {
-        info!(log, "Shutting down with error"; "error" => %err);
-        Err(Error::from(err))
-    } else {
-        info!(log, "Shutting down");
-        Ok(())
+fn test_server_command<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("test-server")
+        .about("Runs a UDP server that echoes or discards the packets it receives, for testing proxy configurations")
+        .arg(
+            clap::Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .value_name("PORT")
+                .help("The port to listen on")
+                .default_value("8000"),
+        )
+        .arg(
+            clap::Arg::with_name("mode")
+                .long("mode")
+                .value_name("MODE")
+                .help("Whether to echo received packets back to their sender, or discard them")
+                .possible_values(&["echo", "sink"])
+                .default_value("echo"),
+        )
+        .arg(
+            clap::Arg::with_name("latency")
+                .long("latency")
+                .value_name("DURATION")
+                .help("How long to wait before echoing a packet, e.g. 50ms")
+                .default_value("0s"),
+        )
+        .arg(
+            clap::Arg::with_name("loss")
+                .long("loss")
+                .value_name("PROBABILITY")
+                .help("The probability, between 0 and 1, that a received packet is dropped")
+                .default_value("0"),
+        )
+        .arg(
+            clap::Arg::with_name("validate-prefix")
+                .long("validate-prefix")
+                .value_name("PREFIX")
+                .help("Drop and count as invalid packets that don't start with PREFIX")
+                .takes_value(true),
+        )
+}
+
+async fn run_test_server(base_logger: &Logger, matches: &ArgMatches<'_>) -> Result<(), Error> {
+    let loss = matches.value_of("loss").unwrap_or_default().parse::<f64>()?;
+    if !(0.0..=1.0).contains(&loss) {
+        return Err(format!("loss must be between 0 and 1, got {}", loss).into());
     }
+    let config = test_server::Config {
+        port: matches.value_of("port").unwrap_or_default().parse()?,
+        mode: match matches.value_of("mode") {
+            Some("sink") => test_server::Mode::Sink,
+            _ => test_server::Mode::Echo,
+        },
+        latency: humantime_serde::re::humantime::parse_duration(
+            matches.value_of("latency").unwrap_or_default(),
+        )?,
+        loss,
+        validate_prefix: matches
+            .value_of("validate-prefix")
+            .map(|prefix| prefix.as_bytes().to_vec()),
+    };
+
+    TestServer::bind(base_logger, config)
+        .await?
+        .run(shutdown_signal())
+        .await?;
+    Ok(())
 }
 
-fn get_config_file() -> Result<File, std::io::Error> {
-    std::fs::File::open("./quilkin.yaml").or_else(|error| {
-        if cfg!(unix) {
-            std::fs::File::open("/etc/quilkin/quilkin.yaml")
-        } else {
-            Err(error)
+fn load_command<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("load")
+        .about("Simulates many clients sending packets to a proxy, and reports packet loss and latency")
+        .arg(
+            clap::Arg::with_name("target")
+                .long("target")
+                .value_name("ADDRESS")
+                .help("The address of the proxy to send packets to, e.g. 127.0.0.1:7000")
+                .required(true),
+        )
+        .arg(
+            clap::Arg::with_name("pps")
+                .long("pps")
+                .value_name("N")
+                .help("The number of packets to send per second, across all sessions")
+                .default_value("1000"),
+        )
+        .arg(
+            clap::Arg::with_name("size")
+                .long("size")
+                .value_name("BYTES")
+                .help("The size of each packet")
+                .default_value("64"),
+        )
+        .arg(
+            clap::Arg::with_name("sessions")
+                .long("sessions")
+                .value_name("N")
+                .help("The number of clients sending packets at the same time")
+                .default_value("10"),
+        )
+        .arg(
+            clap::Arg::with_name("duration")
+                .long("duration")
+                .value_name("DURATION")
+                .help("How long to send packets for, e.g. 1m")
+                .default_value("10s"),
+        )
+        .arg(
+            clap::Arg::with_name("session-lifetime")
+                .long("session-lifetime")
+                .value_name("DURATION")
+                .help("How long each client sends packets before being replaced by a new client, or 0s to never replace clients")
+                .default_value("30s"),
+        )
+}
+
+async fn run_load(base_logger: &Logger, matches: &ArgMatches<'_>) -> Result<(), Error> {
+    let parse_duration = |name: &str| {
+        humantime_serde::re::humantime::parse_duration(matches.value_of(name).unwrap_or_default())
+    };
+    let config = load::Config {
+        target: matches.value_of("target").unwrap_or_default().parse()?,
+        packets_per_second: matches.value_of("pps").unwrap_or_default().parse()?,
+        packet_size: matches.value_of("size").unwrap_or_default().parse()?,
+        sessions: matches.value_of("sessions").unwrap_or_default().parse()?,
+        duration: parse_duration("duration")?,
+        session_lifetime: parse_duration("session-lifetime")?,
+    };
+    if config.packets_per_second == 0 || config.sessions == 0 {
+        return Err("pps and sessions must be greater than 0".into());
+    }
+    if config.packet_size < load::HEADER_SIZE {
+        return Err(format!("size must be at least {} bytes", load::HEADER_SIZE).into());
+    }
+
+    let report = load::run(base_logger, config, shutdown_signal()).await?;
+    print!("{}", report);
+    Ok(())
+}
+
+fn doctor_command<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("doctor")
+        .about("Checks the configuration, kernel settings, management servers and endpoints, and reports any problems")
+        .arg(
+            clap::Arg::with_name("endpoints")
+                .long("endpoints")
+                .value_name("N")
+                .help("The number of static endpoints, picked at random, to send a probe packet to")
+                .default_value("5"),
+        )
+}
+
+async fn run_doctor(
+    base_logger: Logger,
+    config_path: &Path,
+    filter_registry: FilterRegistry,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Error> {
+    let endpoint_sample = matches.value_of("endpoints").unwrap_or_default().parse()?;
+    let server = Builder::from(load_config(config_path)?)
+        .with_log(base_logger)
+        .with_filter_registry(filter_registry)
+        .validate()?
+        .build();
+
+    let report = server.doctor(endpoint_sample).await;
+    print!("{}", report);
+    match report.failures() {
+        0 => Ok(()),
+        failures => Err(format!("{} checks failed", failures).into()),
+    }
+}
+
+fn list_filters_command<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("list-filters")
+        .about("Lists the filters compiled into this binary, which configurations can refer to")
+        .arg(
+            clap::Arg::with_name("json")
+                .long("json")
+                .help("Print each filter's deprecated names and configuration schema as JSON"),
+        )
+}
+
+fn run_list_filters(
+    filter_registry: &FilterRegistry,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Error> {
+    let filters = filter_registry.filters();
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&filters)?);
+    } else {
+        for filter in filters {
+            println!("{}", filter.name);
         }
-    })
+    }
+    Ok(())
+}
+
+fn encrypt_config_command<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("encrypt-config")
+        .about("Encrypts a configuration file with the key given by the environment")
+        .arg(
+            clap::Arg::with_name("input")
+                .long("input")
+                .value_name("FILE")
+                .help("The configuration file to encrypt")
+                .required(true),
+        )
+        .arg(
+            clap::Arg::with_name("output")
+                .long("output")
+                .value_name("FILE")
+                .help("The file to write the encrypted configuration to")
+                .required(true),
+        )
+}
+
+fn run_encrypt_config(matches: &ArgMatches<'_>) -> Result<(), Error> {
+    let key = ConfigKey::from_env()?
+        .ok_or_else(|| format!("no config key, set {} or {}", KEY_ENV, KEY_FILE_ENV))?;
+    let config = std::fs::read(matches.value_of("input").unwrap_or_default())?;
+    std::fs::write(
+        matches.value_of("output").unwrap_or_default(),
+        key.encrypt(&config)?,
+    )?;
+    Ok(())
+}
+
+/// Reads the config from `path`, falling back to the default locations if
+/// there is no file at `path`. Errors in the file, or in the base profiles
+/// it extends, are returned rather than falling back.
+fn load_config(path: &Path) -> Result<Arc<Config>, Error> {
+    let config = match Config::from_file(path) {
+        Err(ProfileError::Read {
+            path: missing,
+            source,
+        }) if missing == path && source.kind() == ErrorKind::NotFound => match get_config_file() {
+            Some(path) => Config::from_file(&path),
+            None => Err(ProfileError::Read {
+                path: missing,
+                source,
+            }),
+        },
+        result => result,
+    }?;
+    Ok(Arc::new(config))
+}
+
+fn get_config_file() -> Option<PathBuf> {
+    let path = Path::new("./quilkin.yaml");
+    if path.exists() {
+        return Some(path.into());
+    }
+
+    let path = Path::new("/etc/quilkin/quilkin.yaml");
+    if cfg!(unix) && path.exists() {
+        Some(path.into())
+    } else {
+        None
+    }
 }
@@ -0,0 +1,218 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Runs the proxy as a Windows service, logging to the Windows event log.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Write};
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::time::Duration;
+
+use parking_lot::{const_mutex, Mutex};
+use slog::{error, info, o, Drain, Logger, OwnedKVList, Record, KV};
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use winapi::um::winbase::{DeregisterEventSource, RegisterEventSourceW, ReportEventW};
+use winapi::um::winnt::{
+    EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, HANDLE,
+};
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+    ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use crate::proxy::Server;
+
+/// The name of the command line flag that runs the proxy as a service.
+pub(super) const ARG: &str = "service";
+/// The name of the service, which is also the event log source.
+const SERVICE_NAME: &str = "quilkin";
+
+/// The proxy to run once the service control manager starts the service.
+/// The service's entry point can't take any arguments, so the proxy is
+/// handed over through here.
+static SERVICE: Mutex<Option<(Logger, Server, Handle)>> = const_mutex(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Returns the command line flag that runs the proxy as a service.
+pub(super) fn arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name(ARG)
+        .long(ARG)
+        .help("Run as a Windows service, logging to the Windows event log")
+}
+
+/// Returns a logger that writes to the Windows event log.
+pub(super) fn logger() -> io::Result<Logger> {
+    let drain = EventLog::new(SERVICE_NAME)?.ignore_res();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    Ok(Logger::root(drain, o!()))
+}
+
+/// Runs `server` as a Windows service, until the service is stopped. This
+/// blocks the current thread, which the service control manager uses to
+/// dispatch service controls.
+pub(super) fn run(log: Logger, server: Server) -> windows_service::Result<()> {
+    *SERVICE.lock() = Some((log, server, Handle::current()));
+    tokio::task::block_in_place(|| service_dispatcher::start(SERVICE_NAME, ffi_service_main))
+}
+
+/// The entry point of the service, which the service control manager calls
+/// on a thread of its own.
+fn service_main(_: Vec<OsString>) {
+    if let Some((log, server, runtime)) = SERVICE.lock().take() {
+        if let Err(err) = run_service(&log, server, runtime) {
+            error!(log, "Windows service failed"; "error" => %err);
+        }
+    }
+}
+
+fn run_service(log: &Logger, server: Server, runtime: Handle) -> windows_service::Result<()> {
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                shutdown_tx.send(()).ok();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+
+    status_handle.set_service_status(status(ServiceState::Running, ServiceExitCode::Win32(0)))?;
+    info!(log, "Running as a Windows service");
+    let exit_code = match runtime.block_on(server.run(shutdown_rx)) {
+        Ok(()) => {
+            info!(log, "Shutting down");
+            ServiceExitCode::Win32(0)
+        }
+        Err(err) => {
+            info!(log, "Shutting down with error"; "error" => %err);
+            ServiceExitCode::ServiceSpecific(1)
+        }
+    };
+    status_handle.set_service_status(status(ServiceState::Stopped, exit_code))
+}
+
+/// Returns the status reported to the service control manager in `state`.
+fn status(state: ServiceState, exit_code: ServiceExitCode) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: if state == ServiceState::Running {
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+        } else {
+            ServiceControlAccept::empty()
+        },
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::from_secs(0),
+        process_id: None,
+    }
+}
+
+/// A [`Drain`] that writes each log record to the Windows event log, as the
+/// message followed by its key value pairs.
+struct EventLog {
+    handle: HANDLE,
+}
+
+// The handle can be used from any thread.
+unsafe impl Send for EventLog {}
+
+impl EventLog {
+    /// Opens the event log for `source`. Messages are only displayed nicely
+    /// if the source is registered, e.g. with PowerShell's `New-EventLog`.
+    fn new(source: &str) -> io::Result<Self> {
+        let source = to_wide(source);
+        // Safe since `source` is a null terminated string that outlives the
+        // call.
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { handle })
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        // Safe since the handle was opened by `new` and is only closed here.
+        unsafe {
+            DeregisterEventSource(self.handle);
+        }
+    }
+}
+
+impl Drain for EventLog {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let mut message = Message(record.msg().to_string());
+        record
+            .kv()
+            .serialize(record, &mut message)
+            .and_then(|()| values.serialize(record, &mut message))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+
+        let event_type = match record.level() {
+            slog::Level::Critical | slog::Level::Error => EVENTLOG_ERROR_TYPE,
+            slog::Level::Warning => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = to_wide(&message.0);
+        let mut strings = [message.as_ptr()];
+        // Safe since `strings` holds a single null terminated string, and
+        // both outlive the call.
+        let result = unsafe {
+            ReportEventW(
+                self.handle,
+                event_type,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_mut_ptr(),
+                ptr::null_mut(),
+            )
+        };
+        if result == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Collects the key value pairs of a log record into a message.
+struct Message(String);
+
+impl slog::Serializer for Message {
+    fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments) -> slog::Result {
+        write!(self.0, " {}={}", key, value)?;
+        Ok(())
+    }
+}
+
+/// Returns `value` as a null terminated UTF-16 string.
+fn to_wide(value: &str) -> Vec<u16> {
+    OsStr::new(value).encode_wide().chain(Some(0)).collect()
+}
@@ -0,0 +1,231 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Secrets, such as signing keys and tokens, which are read from a
+//! [`SecretProvider`] when they are used rather than written in the
+//! configuration.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+/// A boxed [`SecretProvider`].
+pub type DynSecretProvider = Box<dyn SecretProvider>;
+
+/// A reference to a secret held by a [`SecretProvider`].
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SecretRef {
+    /// The [`SecretProvider::name`] of the provider holding the secret.
+    pub provider: String,
+    /// Identifies the secret within the provider, e.g the path of a file.
+    pub key: String,
+}
+
+/// An error that occurred when reading a secret.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum SecretError {
+    #[error("secret provider `{}` not found", .0)]
+    ProviderNotFound(String),
+    #[error("secret `{}` not found", .0)]
+    NotFound(String),
+    #[error("failed to read secret `{}`: {}", key, reason)]
+    Unavailable { key: String, reason: String },
+}
+
+/// Reads secrets from a store, e.g a file system or a secret manager.
+/// Providers beyond the ones in this crate are registered through
+/// [`SecretProviders::default_with`].
+pub trait SecretProvider: Send + Sync {
+    /// The name that [`SecretRef`]s refer to the provider by, e.g `vault`.
+    fn name(&self) -> &'static str;
+
+    /// Returns the current value of the secret identified by `key`. This is
+    /// called each time the secret is used, e.g when a filter is created or
+    /// a management server is connected to, so rotated secrets are picked up
+    /// without restarting the proxy.
+    fn get(&self, key: &str) -> Result<Vec<u8>, SecretError>;
+}
+
+/// Reads a secret from the file at the path given by its key, e.g a secret
+/// mounted into the proxy's container. The file's contents are used as is.
+pub struct FileProvider;
+
+impl SecretProvider for FileProvider {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, SecretError> {
+        std::fs::read(key).map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => SecretError::NotFound(key.into()),
+            _ => SecretError::Unavailable {
+                key: key.into(),
+                reason: err.to_string(),
+            },
+        })
+    }
+}
+
+/// Reads a secret from the environment variable named by its key.
+pub struct EnvProvider;
+
+impl SecretProvider for EnvProvider {
+    fn name(&self) -> &'static str {
+        "env"
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, SecretError> {
+        std::env::var_os(key)
+            .map(|value| value.to_string_lossy().into_owned().into_bytes())
+            .ok_or_else(|| SecretError::NotFound(key.into()))
+    }
+}
+
+/// The [`SecretProvider`]s that secrets can be read from, by name.
+///
+/// **Note:** Cloning [`SecretProviders`] is shallow, clones refer to the
+/// same providers.
+#[derive(Clone)]
+pub struct SecretProviders {
+    providers: Arc<HashMap<&'static str, DynSecretProvider>>,
+}
+
+impl SecretProviders {
+    /// Returns the providers in `providers` in addition to the defaults,
+    /// which are [`FileProvider`] and [`EnvProvider`]. A provider in
+    /// `providers` overrides a default with the same name.
+    pub fn default_with(providers: impl IntoIterator<Item = DynSecretProvider>) -> Self {
+        Self::with(
+            std::array::IntoIter::new([
+                Box::new(FileProvider) as DynSecretProvider,
+                Box::new(EnvProvider),
+            ])
+            .chain(providers),
+        )
+    }
+
+    /// Returns the providers in `providers`, without any defaults.
+    pub fn with(providers: impl IntoIterator<Item = DynSecretProvider>) -> Self {
+        Self {
+            providers: Arc::new(
+                providers
+                    .into_iter()
+                    .map(|provider| (provider.name(), provider))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Returns whether there is a provider named `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.providers.contains_key(name)
+    }
+
+    /// Returns the current value of `secret`.
+    pub fn get(&self, secret: &SecretRef) -> Result<Vec<u8>, SecretError> {
+        self.providers
+            .get(secret.provider.as_str())
+            .ok_or_else(|| SecretError::ProviderNotFound(secret.provider.clone()))?
+            .get(&secret.key)
+    }
+}
+
+impl Default for SecretProviders {
+    fn default() -> Self {
+        Self::default_with(Option::into_iter(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DynSecretProvider, SecretError, SecretProvider, SecretProviders, SecretRef};
+
+    fn secret(provider: &str, key: &str) -> SecretRef {
+        SecretRef {
+            provider: provider.into(),
+            key: key.into(),
+        }
+    }
+
+    struct StaticProvider;
+
+    impl SecretProvider for StaticProvider {
+        fn name(&self) -> &'static str {
+            "static"
+        }
+
+        fn get(&self, key: &str) -> Result<Vec<u8>, SecretError> {
+            Ok(format!("value of {}", key).into_bytes())
+        }
+    }
+
+    #[test]
+    fn file_provider() {
+        let path = std::env::temp_dir().join(format!("quilkin-secret-{}", std::process::id()));
+        std::fs::write(&path, b"file secret").unwrap();
+        let providers = SecretProviders::default();
+
+        let value = providers.get(&secret("file", path.to_str().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(b"file secret".to_vec(), value.unwrap());
+        assert_eq!(
+            SecretError::NotFound(path.to_str().unwrap().into()),
+            providers
+                .get(&secret("file", path.to_str().unwrap()))
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn env_provider() {
+        std::env::set_var("QUILKIN_TEST_SECRET", "env secret");
+        let providers = SecretProviders::default();
+
+        assert_eq!(
+            b"env secret".to_vec(),
+            providers
+                .get(&secret("env", "QUILKIN_TEST_SECRET"))
+                .unwrap()
+        );
+        assert_eq!(
+            SecretError::NotFound("QUILKIN_TEST_MISSING_SECRET".into()),
+            providers
+                .get(&secret("env", "QUILKIN_TEST_MISSING_SECRET"))
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn external_provider() {
+        let providers =
+            SecretProviders::default_with(vec![Box::new(StaticProvider) as DynSecretProvider]);
+        assert!(providers.contains("static"));
+        assert!(providers.contains("file"));
+        assert_eq!(
+            b"value of key".to_vec(),
+            providers.get(&secret("static", "key")).unwrap()
+        );
+
+        let providers = SecretProviders::with(vec![Box::new(StaticProvider) as DynSecretProvider]);
+        assert!(!providers.contains("file"));
+        assert_eq!(
+            SecretError::ProviderNotFound("vault".into()),
+            providers.get(&secret("vault", "key")).unwrap_err()
+        );
+    }
+}
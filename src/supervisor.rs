@@ -0,0 +1,150 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::any::Any;
+use std::future::Future;
+
+use prometheus::{IntCounterVec, Registry, Result as MetricsResult};
+use slog::{error, o, Logger};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
+
+use crate::metrics::{opts, CollectorExt};
+
+/// How long a task is restarted after the first time it panics.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// The longest a task is restarted after. The backoff of a task that ran
+/// for at least this long before panicking starts over.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Spawns tasks that are restarted, with an exponential backoff, whenever
+/// they panic rather than being silently lost. Each panic is logged along
+/// with the task it happened in, and counted by the
+/// `quilkin_task_panics_total` metric.
+///
+/// **Note:** Cloning [`Supervisor`] is shallow, clones count panics with the
+/// same metric.
+#[derive(Clone)]
+pub(crate) struct Supervisor {
+    log: Logger,
+    panics_total: IntCounterVec,
+}
+
+impl Supervisor {
+    pub(crate) fn new(base: &Logger, registry: &Registry) -> MetricsResult<Self> {
+        Ok(Self {
+            log: base.new(o!("source" => "Supervisor")),
+            panics_total: IntCounterVec::new(
+                opts(
+                    "panics_total",
+                    "task",
+                    "Total number of panics of supervised tasks, which are then restarted",
+                ),
+                &["task"],
+            )?
+            .register_if_not_exists(registry)?,
+        })
+    }
+
+    /// Spawns the future returned by `make_task`, calling it again for each
+    /// restart of the task. The returned handle resolves to the task's
+    /// output once it completes without panicking, or `None` if it was
+    /// cancelled.
+    pub(crate) fn spawn<F, Fut>(
+        &self,
+        task: &'static str,
+        mut make_task: F,
+    ) -> JoinHandle<Option<Fut::Output>>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let log = self.log.clone();
+        let panics_total = self.panics_total.with_label_values(&[task]);
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let started_at = Instant::now();
+                let panic = match tokio::spawn(make_task()).await {
+                    Ok(output) => return Some(output),
+                    Err(err) if err.is_panic() => err.into_panic(),
+                    Err(_) => return None,
+                };
+                if started_at.elapsed() >= MAX_BACKOFF {
+                    backoff = INITIAL_BACKOFF;
+                }
+
+                panics_total.inc();
+                error!(log, "Restarting task after it panicked";
+                    "task" => task, "panic" => panic_message(&*panic), "backoff" => ?backoff);
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        })
+    }
+}
+
+/// Returns the message that a task panicked with.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<unknown>")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use prometheus::Registry;
+    use tokio::time::{self, Duration, Instant};
+
+    use super::{Supervisor, INITIAL_BACKOFF};
+    use crate::test_utils::logger;
+
+    #[tokio::test]
+    async fn spawn() {
+        time::pause();
+        let supervisor = Supervisor::new(&logger(), &Registry::default()).unwrap();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let started_at = Instant::now();
+        let task = supervisor.spawn("test", {
+            let runs = runs.clone();
+            move || {
+                let runs = runs.clone();
+                async move {
+                    if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                        panic!("test panic");
+                    }
+                    "done"
+                }
+            }
+        });
+
+        assert_eq!(Some("done"), task.await.unwrap());
+        assert_eq!(3, runs.load(Ordering::SeqCst));
+        assert_eq!(
+            2,
+            supervisor.panics_total.with_label_values(&["test"]).get()
+        );
+        // The second restart backs off for twice as long as the first.
+        assert!(started_at.elapsed() >= INITIAL_BACKOFF * 3);
+        assert!(started_at.elapsed() < INITIAL_BACKOFF * 3 + Duration::from_millis(10));
+    }
+}
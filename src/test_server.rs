@@ -0,0 +1,282 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A UDP server to run proxy configurations against in integration and load
+//! tests, which echoes or discards the packets it receives.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use slog::{error, info, o, Logger};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::time;
+
+/// How often the server logs its [`Stats`].
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What the server does with the packets it receives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Send each packet back to its sender.
+    Echo,
+    /// Discard each packet.
+    Sink,
+}
+
+/// Configures a [`TestServer`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// The port to listen on. If 0, a random port is used.
+    pub port: u16,
+    pub mode: Mode,
+    /// How long to wait before echoing a packet.
+    pub latency: Duration,
+    /// The probability, between 0 and 1, that a received packet is dropped.
+    pub loss: f64,
+    /// If set, packets that don't start with these bytes are counted as
+    /// invalid and dropped.
+    pub validate_prefix: Option<Vec<u8>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 0,
+            mode: Mode::Echo,
+            latency: Duration::from_secs(0),
+            loss: 0.0,
+            validate_prefix: None,
+        }
+    }
+}
+
+/// Counts the packets handled by a [`TestServer`].
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// The number of packets received.
+    pub received: AtomicU64,
+    /// The number of packets echoed.
+    pub echoed: AtomicU64,
+    /// The number of packets dropped to simulate packet loss.
+    pub lost: AtomicU64,
+    /// The number of packets that failed validation.
+    pub invalid: AtomicU64,
+}
+
+/// A UDP server that echoes or discards the packets it receives, optionally
+/// injecting latency and packet loss.
+pub struct TestServer {
+    log: Logger,
+    config: Config,
+    socket: Arc<UdpSocket>,
+    stats: Arc<Stats>,
+}
+
+impl TestServer {
+    /// Binds the server's socket.
+    pub async fn bind(base: &Logger, config: Config) -> io::Result<Self> {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), config.port);
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Self {
+            log: base.new(o!("source" => "TestServer")),
+            config,
+            socket: Arc::new(socket),
+            stats: Arc::new(Stats::default()),
+        })
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Returns the server's packet counts.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Handles received packets until a value is sent on `shutdown_rx`.
+    pub async fn run(self, mut shutdown_rx: watch::Receiver<()>) -> io::Result<()> {
+        info!(self.log, "Starting test server";
+            "address" => self.local_addr()?,
+            "mode" => ?self.config.mode,
+            "latency" => ?self.config.latency,
+            "loss" => self.config.loss);
+
+        let mut stats_interval = time::interval(STATS_LOG_INTERVAL);
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => {
+                    let (size, from) = received?;
+                    self.handle_packet(buf[..size].to_vec(), from);
+                }
+                _ = stats_interval.tick() => {
+                    self.log_stats();
+                }
+                _ = shutdown_rx.changed() => {
+                    self.log_stats();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle_packet(&self, packet: Vec<u8>, from: SocketAddr) {
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(prefix) = &self.config.validate_prefix {
+            if !packet.starts_with(prefix) {
+                self.stats.invalid.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        if self.config.loss > 0.0 && rand::random::<f64>() < self.config.loss {
+            self.stats.lost.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if self.config.mode == Mode::Sink {
+            return;
+        }
+
+        let log = self.log.clone();
+        let socket = self.socket.clone();
+        let stats = self.stats.clone();
+        let latency = self.config.latency;
+        tokio::spawn(async move {
+            if latency > Duration::from_secs(0) {
+                time::sleep(latency).await;
+            }
+            match socket.send_to(&packet, from).await {
+                Ok(_) => {
+                    stats.echoed.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(err) => error!(log, "Failed to echo packet"; "to" => from, "error" => %err),
+            }
+        });
+    }
+
+    fn log_stats(&self) {
+        info!(self.log, "Test server stats";
+            "received" => self.stats.received.load(Ordering::Relaxed),
+            "echoed" => self.stats.echoed.load(Ordering::Relaxed),
+            "lost" => self.stats.lost.load(Ordering::Relaxed),
+            "invalid" => self.stats.invalid.load(Ordering::Relaxed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    use tokio::sync::watch;
+    use tokio::time::timeout;
+
+    use super::{Config, Mode, TestServer};
+    use crate::test_utils::{logger, TestHelper};
+
+    /// Runs a test server, returning its address, its stats and the sender
+    /// that shuts it down.
+    async fn run_test_server(
+        config: Config,
+    ) -> (SocketAddr, std::sync::Arc<super::Stats>, watch::Sender<()>) {
+        let server = TestServer::bind(&logger(), config).await.unwrap();
+        let addr = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            server.local_addr().unwrap().port(),
+        );
+        let stats = server.stats();
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(server.run(shutdown_rx));
+        (addr, stats, shutdown_tx)
+    }
+
+    #[tokio::test]
+    async fn echo() {
+        let t = TestHelper::default();
+        let (addr, stats, _shutdown_tx) = run_test_server(Config {
+            latency: Duration::from_millis(100),
+            ..Config::default()
+        })
+        .await;
+
+        let socket = t.create_socket().await;
+        let start = Instant::now();
+        socket.send_to(b"hello", addr).await.unwrap();
+        let mut buf = vec![0; 1024];
+        let (size, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .expect("should receive a packet")
+            .unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(1, stats.received.load(Ordering::Relaxed));
+        assert_eq!(1, stats.echoed.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn sink() {
+        let t = TestHelper::default();
+        let (addr, stats, _shutdown_tx) = run_test_server(Config {
+            mode: Mode::Sink,
+            ..Config::default()
+        })
+        .await;
+
+        let socket = t.create_socket().await;
+        socket.send_to(b"hello", addr).await.unwrap();
+        let mut buf = vec![0; 1024];
+        assert!(
+            timeout(Duration::from_millis(200), socket.recv_from(&mut buf))
+                .await
+                .is_err(),
+            "should not receive a packet"
+        );
+        assert_eq!(1, stats.received.load(Ordering::Relaxed));
+        assert_eq!(0, stats.echoed.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn loss_and_validation() {
+        let t = TestHelper::default();
+        let (addr, stats, _shutdown_tx) = run_test_server(Config {
+            loss: 1.0,
+            validate_prefix: Some(b"QUILKIN".to_vec()),
+            ..Config::default()
+        })
+        .await;
+
+        let socket = t.create_socket().await;
+        socket.send_to(b"hello", addr).await.unwrap();
+        socket.send_to(b"QUILKIN hello", addr).await.unwrap();
+        let mut buf = vec![0; 1024];
+        assert!(
+            timeout(Duration::from_millis(200), socket.recv_from(&mut buf))
+                .await
+                .is_err(),
+            "should not receive a packet"
+        );
+        assert_eq!(2, stats.received.load(Ordering::Relaxed));
+        assert_eq!(1, stats.invalid.load(Ordering::Relaxed));
+        assert_eq!(1, stats.lost.load(Ordering::Relaxed));
+    }
+}
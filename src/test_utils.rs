@@ -29,6 +29,9 @@ use crate::config::{Builder as ConfigBuilder, Config, EndPoint, Endpoints};
 use crate::filters::{prelude::*, DynFilterFactory, FilterChain, FilterRegistry, FilterSet};
 use crate::proxy::{Builder, PendingValidation};
 
+#[cfg(feature = "testing")]
+pub mod golden;
+
 pub struct TestFilterFactory {}
 impl FilterFactory for TestFilterFactory {
     fn name(&self) -> &'static str {
@@ -243,7 +246,7 @@ impl TestHelper {
     }
 
     /// Returns a receiver subscribed to the helper's shutdown event.
-    async fn get_shutdown_subscriber(&mut self) -> watch::Receiver<()> {
+    pub async fn get_shutdown_subscriber(&mut self) -> watch::Receiver<()> {
         // If this is the first call, then we set up the channel first.
         match self.shutdown_ch {
             Some((_, ref rx)) => rx.clone(),
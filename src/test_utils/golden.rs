@@ -0,0 +1,265 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Golden file tests for filter chains. A case defines a filter chain in
+//! YAML along with a corpus of packets, which are run through the chain.
+//! What the chain outputs is compared against the case's golden file, so
+//! that changes to the wire format of filters (e.g compression framing) are
+//! caught across upgrades.
+//!
+//! The golden file of `case.yaml` is `case.golden.yaml`, next to it. Set the
+//! `QUILKIN_UPDATE_GOLDEN` environment variable to write the golden files
+//! from the current output rather than compare against them.
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use base64_serde::base64_serde_type;
+use prometheus::Registry;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Endpoint;
+use crate::config::{Endpoints, Filter as FilterConfig};
+use crate::filters::{prelude::*, FilterChain, FilterRegistry, FilterSet};
+use crate::test_utils::logger;
+
+base64_serde_type!(Base64Standard, base64::STANDARD);
+
+/// The environment variable that, if set, updates golden files rather than
+/// comparing against them.
+pub const UPDATE_ENV: &str = "QUILKIN_UPDATE_GOLDEN";
+
+/// The address that read packets are received from, and write packets are
+/// sent to.
+pub const CLIENT: &str = "127.0.0.1:7000";
+
+/// The address of the endpoint that read packets are sent to, and write
+/// packets are received from.
+pub const ENDPOINT: &str = "127.0.0.1:7001";
+
+/// A filter chain and the corpus of packets to run through it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Case {
+    pub filters: Vec<FilterConfig>,
+    pub packets: Vec<Packet>,
+}
+
+/// Which way a packet travels through the filter chain.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Direction {
+    /// From the client to the endpoint, through `Filter::read`.
+    Read,
+    /// From the endpoint to the client, through `Filter::write`.
+    Write,
+}
+
+/// A packet of a case's corpus.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Packet {
+    pub direction: Direction,
+    #[serde(with = "Base64Standard")]
+    pub contents: Vec<u8>,
+}
+
+/// What the filter chain output for a packet of the corpus.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Output {
+    pub direction: Direction,
+    /// Whether the filter chain dropped the packet.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dropped: bool,
+    /// The contents of the packet, or empty if it was dropped.
+    #[serde(default, with = "Base64Standard", skip_serializing_if = "Vec::is_empty")]
+    pub contents: Vec<u8>,
+}
+
+/// Runs golden file cases through filter chains created from a
+/// [`FilterRegistry`], so that cases can use custom filters.
+pub struct Harness {
+    filter_registry: FilterRegistry,
+}
+
+impl Default for Harness {
+    /// Returns a harness for cases using the default filters.
+    fn default() -> Self {
+        Self::new(FilterRegistry::new(FilterSet::default(&logger())))
+    }
+}
+
+impl Harness {
+    pub fn new(filter_registry: FilterRegistry) -> Self {
+        Self { filter_registry }
+    }
+
+    /// Runs each packet of `case` through its filter chain, in order, and
+    /// returns what the chain output for each.
+    pub fn run(&self, case: &Case) -> Result<Vec<Output>, Error> {
+        let chain = FilterChain::try_create(
+            case.filters.clone(),
+            &self.filter_registry,
+            &Registry::default(),
+        )?;
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let endpoint = Endpoint::from_address(ENDPOINT.parse().unwrap());
+
+        Ok(case
+            .packets
+            .iter()
+            .map(|packet| {
+                let contents = packet.contents.clone();
+                let output = match packet.direction {
+                    Direction::Read => {
+                        let endpoints = Endpoints::new(vec![endpoint.clone()]).unwrap();
+                        chain
+                            .read(ReadContext::new(endpoints.into(), client, contents))
+                            .map(|response| response.contents)
+                    }
+                    Direction::Write => chain
+                        .write(WriteContext::new(
+                            &endpoint,
+                            endpoint.address,
+                            client,
+                            contents,
+                        ))
+                        .map(|response| response.contents),
+                };
+                Output {
+                    direction: packet.direction,
+                    dropped: output.is_none(),
+                    contents: output.unwrap_or_default(),
+                }
+            })
+            .collect())
+    }
+
+    /// Runs the case at `path` and asserts that its output matches its
+    /// golden file, or writes the golden file if [`UPDATE_ENV`] is set.
+    pub fn assert_golden(&self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let case: Case = serde_yaml::from_str(&read(path))
+            .unwrap_or_else(|err| panic!("failed to parse case {}: {}", path.display(), err));
+        let output = self
+            .run(&case)
+            .unwrap_or_else(|err| panic!("failed to run case {}: {}", path.display(), err));
+
+        let golden_path = golden_path(path);
+        if std::env::var_os(UPDATE_ENV).is_some() {
+            let golden = serde_yaml::to_string(&output).unwrap();
+            fs::write(&golden_path, golden)
+                .unwrap_or_else(|err| panic!("failed to write {}: {}", golden_path.display(), err));
+            return;
+        }
+
+        let golden: Vec<Output> = serde_yaml::from_str(&read(&golden_path))
+            .unwrap_or_else(|err| panic!("failed to parse {}: {}", golden_path.display(), err));
+        assert_eq!(
+            serde_yaml::to_string(&golden).unwrap(),
+            serde_yaml::to_string(&output).unwrap(),
+            "the output of {} doesn't match {}; if the change is expected, set {} to update it",
+            path.display(),
+            golden_path.display(),
+            UPDATE_ENV
+        );
+    }
+
+    /// Runs every case in the directory at `path`, as with
+    /// [`Harness::assert_golden`].
+    pub fn assert_golden_dir(&self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let mut cases = fs::read_dir(path)
+            .unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err))
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| is_case(path))
+            .collect::<Vec<_>>();
+        cases.sort();
+        assert!(!cases.is_empty(), "no cases found in {}", path.display());
+        for case in cases {
+            self.assert_golden(case);
+        }
+    }
+}
+
+fn read(path: &Path) -> String {
+    fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err))
+}
+
+/// Returns whether `path` is a case, rather than a golden file.
+fn is_case(path: &Path) -> bool {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    name.ends_with(".yaml") && !name.ends_with(".golden.yaml")
+}
+
+/// Returns the path of the golden file of the case at `path`.
+fn golden_path(path: &Path) -> PathBuf {
+    path.with_extension("golden.yaml")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{golden_path, is_case, Case, Direction, Harness, Output};
+
+    #[test]
+    fn paths() {
+        assert!(is_case(Path::new("golden/compress.yaml")));
+        assert!(!is_case(Path::new("golden/compress.golden.yaml")));
+        assert!(!is_case(Path::new("golden/README.md")));
+        assert_eq!(
+            PathBuf::from("golden/compress.golden.yaml"),
+            golden_path(Path::new("golden/compress.yaml"))
+        );
+    }
+
+    #[test]
+    fn run() {
+        let yaml = "
+filters:
+  - name: quilkin.extensions.filters.concatenate_bytes.v1beta1.ConcatenateBytes
+    config:
+      on_read: APPEND
+      on_write: PREPEND
+      bytes: YWJj # abc
+packets:
+  - direction: READ
+    contents: aGVsbG8= # hello
+  - direction: WRITE
+    contents: aGVsbG8=
+";
+        let case: Case = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            vec![
+                Output {
+                    direction: Direction::Read,
+                    dropped: false,
+                    contents: b"helloabc".to_vec(),
+                },
+                Output {
+                    direction: Direction::Write,
+                    dropped: false,
+                    contents: b"abchello".to_vec(),
+                },
+            ],
+            Harness::default().run(&case).unwrap()
+        );
+    }
+}
@@ -105,6 +105,15 @@ mod envoy {
     }
 }
 
+pub(crate) mod udpa {
+    pub mod r#type {
+        pub mod v1 {
+            #![doc(hidden)]
+            tonic::include_proto!("udpa.r#type.v1");
+        }
+    }
+}
+
 mod google {
     pub mod rpc {
         #![doc(hidden)]
@@ -117,8 +126,14 @@ const CLUSTER_TYPE: &str = "type.googleapis.com/envoy.config.cluster.v3.Cluster"
 const LISTENER_TYPE: &str = "type.googleapis.com/envoy.config.listener.v3.Listener";
 
 pub(crate) mod ads_client;
+mod client;
 pub(crate) mod cluster;
+mod diff;
 pub(crate) mod error;
 pub(crate) mod listener;
 pub(crate) mod metadata;
 mod metrics;
+pub(crate) mod telemetry;
+
+pub use ads_client::{ClusterUpdate, ExecutionError, ExecutionResult};
+pub use client::{Client, Error, Updates};
@@ -15,6 +15,7 @@
  */
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::xds::google::rpc::Status as GrpcStatus;
 use backoff::{backoff::Backoff, exponential::ExponentialBackoff, Clock, SystemClock};
@@ -26,13 +27,17 @@ use tokio::{
 };
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{
-    transport::{channel::Channel as TonicChannel, Error as TonicError},
+    metadata::{Ascii, MetadataValue},
+    transport::{channel::Channel as TonicChannel, Endpoint as TonicEndpoint, Error as TonicError},
     Request,
 };
 
+use crate::audit_log::{Action, AuditLog, Outcome, Record};
 use crate::cluster::Cluster;
 use crate::config::ManagementServer;
+use crate::faults::FaultInjector;
 use crate::filters::manager::ListenerManagerArgs;
+use crate::secret::{SecretError, SecretProviders, SecretRef};
 use crate::xds::cluster::ClusterManager;
 use crate::xds::envoy::config::core::v3::Node;
 use crate::xds::envoy::service::discovery::v3::{
@@ -40,14 +45,25 @@ use crate::xds::envoy::service::discovery::v3::{
 };
 use crate::xds::listener::ListenerManager;
 use crate::xds::metrics::Metrics;
+use crate::xds::telemetry::TelemetryReporter;
 use crate::xds::{CLUSTER_TYPE, ENDPOINT_TYPE, LISTENER_TYPE};
 use prometheus::core::{AtomicU64, GenericGauge};
 use tokio::sync::mpsc::error::SendError;
 
 /// AdsClient is a client that can talk to an XDS server using the ADS protocol.
+#[derive(Clone)]
 pub(crate) struct AdsClient {
     log: Logger,
     metrics: Metrics,
+    /// The providers that management server tokens are read from.
+    secret_providers: SecretProviders,
+    /// Records the updates accepted and rejected by the proxy, if enabled.
+    audit_log: Option<AuditLog>,
+    /// Drops connections to management servers, if enabled.
+    faults: Option<Arc<FaultInjector>>,
+    /// Reports a summary of the proxy's metrics to the management server
+    /// it's connected to, if enabled.
+    telemetry: Option<TelemetryReporter>,
 }
 
 /// Contains the components that handle XDS responses for supported resources.
@@ -67,7 +83,11 @@ impl ResourceHandlers {
 struct RpcSessionArgs<'a> {
     log: Logger,
     metrics: Metrics,
+    audit_log: Option<AuditLog>,
+    faults: Option<Arc<FaultInjector>>,
+    telemetry: Option<TelemetryReporter>,
     server_addr: String,
+    authorization: Option<MetadataValue<Ascii>>,
     node_id: String,
     resource_handlers: ResourceHandlers,
     backoff: ExponentialBackoff<SystemClock>,
@@ -117,10 +137,24 @@ pub type ExecutionResult = Result<(), ExecutionError>;
 pub const UPDATES_CHANNEL_BUFFER_SIZE: usize = 1;
 
 impl AdsClient {
-    pub fn new(base_logger: Logger, metrics_registry: &Registry) -> MetricsResult<Self> {
+    pub fn new(
+        base_logger: Logger,
+        metrics_registry: &Registry,
+        secret_providers: SecretProviders,
+        audit_log: Option<AuditLog>,
+        faults: Option<Arc<FaultInjector>>,
+        telemetry: Option<TelemetryReporter>,
+    ) -> MetricsResult<Self> {
         let log = base_logger.new(o!("source" => "xds::AdsClient"));
         let metrics = Metrics::new(metrics_registry)?;
-        Ok(Self { log, metrics })
+        Ok(Self {
+            log,
+            metrics,
+            secret_providers,
+            audit_log,
+            faults,
+            telemetry,
+        })
     }
     /// Continuously tracks CDS and EDS resources on an ADS server,
     /// sending summarized cluster updates on the provided channel.
@@ -135,13 +169,25 @@ impl AdsClient {
         let mut backoff = ExponentialBackoff::<SystemClock>::default();
         let log = self.log;
         let metrics = self.metrics;
+        let secret_providers = self.secret_providers;
+        let audit_log = self.audit_log;
+        let faults = self.faults;
+        let telemetry = self.telemetry;
 
         let (discovery_req_tx, mut discovery_req_rx) =
             mpsc::channel::<DiscoveryRequest>(UPDATES_CHANNEL_BUFFER_SIZE);
-        let cluster_manager =
-            ClusterManager::new(log.clone(), cluster_updates_tx, discovery_req_tx.clone());
-        let listener_manager =
-            ListenerManager::new(log.clone(), listener_manager_args, discovery_req_tx);
+        let cluster_manager = ClusterManager::new(
+            log.clone(),
+            metrics.clone(),
+            cluster_updates_tx,
+            discovery_req_tx.clone(),
+        );
+        let listener_manager = ListenerManager::new(
+            log.clone(),
+            metrics.clone(),
+            listener_manager_args,
+            discovery_req_tx,
+        );
 
         let mut resource_handlers = ResourceHandlers {
             cluster_manager,
@@ -156,21 +202,41 @@ impl AdsClient {
             resource_handlers.on_reconnect();
 
             // Pick a server to talk to.
-            let server_addr = {
-                let server_addr = management_servers
+            let (server_addr, token) = {
+                let (server_addr, token) = management_servers
                     .get(next_server_index % management_servers.len())
-                    .map(|server| server.address.clone())
+                    .map(|server| (server.address.clone(), server.token.clone()))
                     // We have previously validated that a config provides at least one
                     // server address so this default value shouldn't be necessary.
-                    .unwrap_or_else(|| "127.0.0.1:18000".into());
+                    .unwrap_or_else(|| ("127.0.0.1:18000".into(), None));
                 next_server_index += 1;
-                server_addr
+                (server_addr, token)
+            };
+
+            // The token is read for each connection, so that a rotated token
+            // is used from the next reconnect onwards.
+            let authorization = match Self::authorization(&secret_providers, token.as_ref()) {
+                Ok(authorization) => authorization,
+                Err(err) => {
+                    error!(
+                        log,
+                        "Unable to read the XDS server's token";
+                        "address" => server_addr,
+                        "error" => %err
+                    );
+                    Self::backoff(&log, &mut backoff).await?;
+                    continue;
+                }
             };
 
             let args = RpcSessionArgs {
                 log: log.clone(),
                 metrics: metrics.clone(),
+                audit_log: audit_log.clone(),
+                faults: faults.clone(),
+                telemetry: telemetry.clone(),
                 server_addr: server_addr.clone(),
+                authorization,
                 node_id: node_id.clone(),
                 resource_handlers,
                 backoff,
@@ -230,15 +296,23 @@ impl AdsClient {
         let RpcSessionArgs {
             log,
             metrics,
+            audit_log,
+            faults,
+            telemetry,
             server_addr,
+            authorization,
             node_id,
             resource_handlers,
             backoff,
             discovery_req_rx,
             shutdown_rx,
         } = args;
-        let client = match AggregatedDiscoveryServiceClient::connect(server_addr).await {
-            Ok(client) => client,
+        let channel = match TonicEndpoint::new(server_addr.clone()) {
+            Ok(endpoint) => endpoint.connect().await,
+            Err(err) => Err(err),
+        };
+        let channel = match channel {
+            Ok(channel) => channel,
             Err(err) => {
                 return Err(RpcSessionError::InitialConnect(
                     resource_handlers,
@@ -247,6 +321,11 @@ impl AdsClient {
                 ))
             }
         };
+        let client = AggregatedDiscoveryServiceClient::new(channel.clone());
+        // Telemetry is reported over the same connection for as long as the
+        // session lasts.
+        let _telemetry = telemetry
+            .map(|telemetry| telemetry.spawn(channel, authorization.clone(), node_id.clone()));
 
         let (mut rpc_tx, rpc_rx) = mpsc::channel::<DiscoveryRequest>(UPDATES_CHANNEL_BUFFER_SIZE);
 
@@ -255,9 +334,11 @@ impl AdsClient {
             log.clone(),
             metrics.clone(),
             client,
+            authorization,
             rpc_rx,
             resource_handlers,
             backoff,
+            faults,
             shutdown_rx,
         );
 
@@ -276,6 +357,7 @@ impl AdsClient {
 
                 req = discovery_req_rx.recv() => {
                     if let Some(req) = req {
+                    Self::audit_update(&audit_log, &server_addr, &req);
                     Self::send_discovery_request(&log, &metrics, req, &mut rpc_tx)
                         .await
                         .map_err(|err| RpcSessionError::NonRecoverable(
@@ -350,20 +432,24 @@ impl AdsClient {
     }
 
     // Spawns a task that runs a receive loop.
+    #[allow(clippy::too_many_arguments)]
     fn run_receive_loop(
         log: Logger,
         metrics: Metrics,
         mut client: AggregatedDiscoveryServiceClient<TonicChannel>,
+        authorization: Option<MetadataValue<Ascii>>,
         rpc_rx: mpsc::Receiver<DiscoveryRequest>,
         mut resource_handlers: ResourceHandlers,
         mut backoff: ExponentialBackoff<SystemClock>,
+        faults: Option<Arc<FaultInjector>>,
         mut shutdown_rx: watch::Receiver<()>,
     ) -> JoinHandle<RpcSessionResult> {
         tokio::spawn(async move {
-            let mut response_stream = match client
-                .stream_aggregated_resources(Request::new(ReceiverStream::new(rpc_rx)))
-                .await
-            {
+            let mut request = Request::new(ReceiverStream::new(rpc_rx));
+            if let Some(authorization) = authorization {
+                request.metadata_mut().insert("authorization", authorization);
+            }
+            let mut response_stream = match client.stream_aggregated_resources(request).await {
                 Ok(response) => response.into_inner(),
                 Err(err) => return Err(RpcSessionError::Receive(resource_handlers, backoff, err)),
             };
@@ -386,6 +472,13 @@ impl AdsClient {
 
             // We are now connected to the server.
             let _connected_state = ConnectionState::connected(metrics.connected_state);
+            let disconnect = async {
+                match &faults {
+                    Some(faults) => faults.xds_disconnect().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::pin!(disconnect);
 
             loop {
                 tokio::select! {
@@ -417,6 +510,12 @@ impl AdsClient {
                         }
                     }
 
+                    _ = &mut disconnect => {
+                        warn!(log, "Exiting receive loop - injected disconnect");
+                        let status = tonic::Status::unavailable("injected fault: disconnect");
+                        return Err(RpcSessionError::Receive(resource_handlers, backoff, status))
+                    }
+
                     _ = shutdown_rx.changed() => {
                         info!(log, "Exiting receive loop - received shutdown signal");
                         return Ok(resource_handlers)
@@ -444,6 +543,51 @@ impl AdsClient {
         req_tx.send(req).await
     }
 
+    /// Records the proxy's response to an update from the management server
+    /// at `server_addr`, i.e whether it accepted or rejected the update, in
+    /// the audit log if enabled.
+    fn audit_update(audit_log: &Option<AuditLog>, server_addr: &str, req: &DiscoveryRequest) {
+        let audit_log = match audit_log {
+            // Requests without a nonce aren't a response to an update, e.g
+            // they subscribe to resources.
+            Some(audit_log) if !req.response_nonce.is_empty() => audit_log,
+            _ => return,
+        };
+        let (outcome, detail) = match &req.error_detail {
+            Some(status) => (Outcome::Failed, status.message.clone()),
+            None => (Outcome::Succeeded, format!("version {}", req.version_info)),
+        };
+        audit_log.record(Record {
+            actor: server_addr,
+            action: Action::XdsUpdate,
+            target: &req.type_url,
+            outcome,
+            detail: Some(&detail),
+        });
+    }
+
+    /// Returns the `authorization` metadata that authenticates the proxy
+    /// with `token`, if it has one.
+    fn authorization(
+        secret_providers: &SecretProviders,
+        token: Option<&SecretRef>,
+    ) -> Result<Option<MetadataValue<Ascii>>, SecretError> {
+        let token = match token {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let invalid = |reason: &str| SecretError::Unavailable {
+            key: token.key.clone(),
+            reason: reason.into(),
+        };
+        let value = String::from_utf8(secret_providers.get(token)?)
+            .map_err(|_| invalid("the token is not valid UTF-8"))?;
+        // Tokens read from files usually end with a newline.
+        MetadataValue::from_str(&format!("Bearer {}", value.trim()))
+            .map(Some)
+            .map_err(|_| invalid("the token contains characters that can't be sent as metadata"))
+    }
+
     async fn backoff<C: Clock>(
         log: &Logger,
         backoff: &mut ExponentialBackoff<C>,
@@ -497,9 +641,11 @@ pub(super) async fn send_discovery_req(
 #[cfg(test)]
 mod tests {
     use super::AdsClient;
-    use crate::config::ManagementServer;
+    use crate::audit_log::AuditLog;
+    use crate::config::{AuditLog as AuditLogConfig, ManagementServer};
     use crate::filters::FilterRegistry;
     use crate::proxy::logger;
+    use crate::secret::{SecretError, SecretProviders, SecretRef};
     use crate::xds::ads_client::ListenerManagerArgs;
     use crate::xds::envoy::service::discovery::v3::DiscoveryRequest;
     use crate::xds::google::rpc::Status as GrpcStatus;
@@ -518,10 +664,20 @@ mod tests {
         let (_shutdown_tx, shutdown_rx) = watch::channel::<()>(());
         let (cluster_updates_tx, _) = mpsc::channel(10);
         let (filter_chain_updates_tx, _) = mpsc::channel(10);
-        let run = AdsClient::new(logger(), &Registry::default()).unwrap().run(
+        let run = AdsClient::new(
+            logger(),
+            &Registry::default(),
+            SecretProviders::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap()
+        .run(
             "test-id".into(),
             vec![ManagementServer {
                 address: "localhost:18000".into(),
+                token: None,
             }],
             cluster_updates_tx,
             ListenerManagerArgs::new(
@@ -576,4 +732,72 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn authorization() {
+        std::env::set_var("QUILKIN_TEST_XDS_TOKEN", "token\n");
+        let providers = SecretProviders::default();
+        let token = |key: &str| SecretRef {
+            provider: "env".into(),
+            key: key.into(),
+        };
+
+        assert_eq!(None, AdsClient::authorization(&providers, None).unwrap());
+        assert_eq!(
+            "Bearer token",
+            AdsClient::authorization(&providers, Some(&token("QUILKIN_TEST_XDS_TOKEN")))
+                .unwrap()
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+        assert_eq!(
+            SecretError::NotFound("QUILKIN_TEST_MISSING_XDS_TOKEN".into()),
+            AdsClient::authorization(&providers, Some(&token("QUILKIN_TEST_MISSING_XDS_TOKEN")))
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn audit_update() {
+        let path = std::env::temp_dir().join(format!("quilkin-xds-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = AuditLogConfig {
+            file: Some(path.clone()),
+            syslog: None,
+        };
+        let audit_log = Some(AuditLog::new(logger(), &config).unwrap());
+
+        let request = |response_nonce: &str, error_message: Option<&str>| DiscoveryRequest {
+            version_info: "101".into(),
+            response_nonce: response_nonce.into(),
+            type_url: CLUSTER_TYPE.into(),
+            resource_names: vec![],
+            node: None,
+            error_detail: error_message.map(|message| GrpcStatus {
+                code: 2,
+                message: message.into(),
+                details: vec![],
+            }),
+        };
+        // Subscriptions aren't recorded.
+        AdsClient::audit_update(&audit_log, "xds:18000", &request("", None));
+        AdsClient::audit_update(&audit_log, "xds:18000", &request("nonce-101", None));
+        AdsClient::audit_update(&audit_log, "xds:18000", &request("nonce-102", Some("Boo!")));
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records = contents
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(2, records.len());
+        assert_eq!("xds:18000", records[0]["actor"]);
+        assert_eq!("XdsUpdate", records[0]["action"]);
+        assert_eq!(CLUSTER_TYPE, records[0]["target"]);
+        assert_eq!("Succeeded", records[0]["outcome"]);
+        assert_eq!("version 101", records[0]["detail"]);
+        assert_eq!("Failed", records[1]["outcome"]);
+        assert_eq!("Boo!", records[1]["detail"]);
+    }
 }
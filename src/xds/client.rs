@@ -0,0 +1,226 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use prometheus::{Error as MetricsError, Registry};
+use slog::{o, Logger};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::config::ManagementServer;
+use crate::filters::{manager::ListenerManagerArgs, FilterChain, FilterRegistry, FilterSet};
+use crate::xds::ads_client::{
+    AdsClient, ClusterUpdate, ExecutionResult, UPDATES_CHANNEL_BUFFER_SIZE,
+};
+
+/// A standalone xDS client, which watches the clusters and filter chain that
+/// a set of management servers provide without running a proxy. This lets
+/// other services consume the same resources as the proxies they manage.
+///
+/// ```no_run
+/// # async fn run() {
+/// let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+/// let servers = vec![quilkin::config::ManagementServer {
+///     address: "http://127.0.0.1:18000".into(),
+///     token: None,
+/// }];
+/// let mut updates = quilkin::xds::Client::new(quilkin::proxy::logger(), "dashboard", servers)
+///     .spawn(shutdown_rx)
+///     .unwrap();
+/// while updates.clusters.changed().await.is_ok() {
+///     println!("{:?}", *updates.clusters.borrow());
+/// }
+/// # }
+/// ```
+pub struct Client {
+    log: Logger,
+    node_id: String,
+    management_servers: Vec<ManagementServer>,
+    metrics_registry: Registry,
+    filter_registry: Option<FilterRegistry>,
+}
+
+/// The resources received by a [`Client`], each holding `None` until the
+/// first update for it is received.
+pub struct Updates {
+    /// The latest snapshot of all clusters and their endpoints, by cluster
+    /// name.
+    pub clusters: watch::Receiver<Option<ClusterUpdate>>,
+    /// The filter chain created from the latest listener resource.
+    pub filter_chain: watch::Receiver<Option<Arc<FilterChain>>>,
+    /// Completes once the client stops, after shutdown or an error it can't
+    /// recover from.
+    pub result: JoinHandle<ExecutionResult>,
+}
+
+/// An error returned when a [`Client`] can't be started.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("at least one management server is required")]
+    NoManagementServers,
+    #[error("failed to register metrics: {}", .0)]
+    Metrics(MetricsError),
+}
+
+impl From<MetricsError> for Error {
+    fn from(error: MetricsError) -> Self {
+        Self::Metrics(error)
+    }
+}
+
+impl Client {
+    /// Returns a client that identifies itself to `management_servers` as
+    /// `node_id`, connecting to the next server whenever it loses its
+    /// connection.
+    pub fn new(
+        base_logger: Logger,
+        node_id: impl Into<String>,
+        management_servers: Vec<ManagementServer>,
+    ) -> Self {
+        Self {
+            log: base_logger.new(o!("source" => "xds::Client")),
+            node_id: node_id.into(),
+            management_servers,
+            metrics_registry: Registry::default(),
+            filter_registry: None,
+        }
+    }
+
+    /// Registers the client's metrics, and those of the filters it creates,
+    /// in `metrics_registry` instead of a registry of its own.
+    pub fn with_metrics_registry(self, metrics_registry: Registry) -> Self {
+        Self {
+            metrics_registry,
+            ..self
+        }
+    }
+
+    /// Creates the filters in listener resources from `filter_registry`
+    /// instead of from the [default filters](FilterSet::default).
+    pub fn with_filter_registry(self, filter_registry: FilterRegistry) -> Self {
+        Self {
+            filter_registry: Some(filter_registry),
+            ..self
+        }
+    }
+
+    /// Starts the client in the background, watching resources until
+    /// `shutdown_rx` is signalled.
+    pub fn spawn(self, shutdown_rx: watch::Receiver<()>) -> Result<Updates, Error> {
+        if self.management_servers.is_empty() {
+            return Err(Error::NoManagementServers);
+        }
+        let filter_registry = self
+            .filter_registry
+            .unwrap_or_else(|| FilterRegistry::new(FilterSet::default(&self.log)));
+
+        let client = AdsClient::new(
+            self.log.clone(),
+            &self.metrics_registry,
+            filter_registry.secret_providers().clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        let (cluster_updates_tx, cluster_updates_rx) = mpsc::channel(UPDATES_CHANNEL_BUFFER_SIZE);
+        let (filter_chain_updates_tx, filter_chain_updates_rx) =
+            mpsc::channel(UPDATES_CHANNEL_BUFFER_SIZE);
+        let listener_manager_args = ListenerManagerArgs::new(
+            self.metrics_registry,
+            filter_registry,
+            filter_chain_updates_tx,
+        );
+
+        let result = tokio::spawn(client.run(
+            self.node_id,
+            self.management_servers,
+            cluster_updates_tx,
+            listener_manager_args,
+            shutdown_rx,
+        ));
+
+        Ok(Updates {
+            clusters: forward(cluster_updates_rx),
+            filter_chain: forward(filter_chain_updates_rx),
+            result,
+        })
+    }
+}
+
+/// Publishes each update received on `updates_rx` on the returned watch
+/// channel, until the client stops sending updates.
+fn forward<T>(mut updates_rx: mpsc::Receiver<T>) -> watch::Receiver<Option<T>>
+where
+    T: Send + Sync + 'static,
+{
+    let (watch_tx, watch_rx) = watch::channel(None);
+    tokio::spawn(async move {
+        while let Some(update) = updates_rx.recv().await {
+            // Updates keep being received even if nobody is watching, so
+            // that the client isn't held up.
+            let _ = watch_tx.send(Some(update));
+        }
+    });
+    watch_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::watch;
+    use tokio::time;
+
+    use super::{Client, Error};
+    use crate::config::ManagementServer;
+    use crate::test_utils::logger;
+
+    #[tokio::test]
+    async fn spawn_requires_management_servers() {
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        match Client::new(logger(), "id", vec![]).spawn(shutdown_rx) {
+            Err(Error::NoManagementServers) => {}
+            Err(err) => unreachable!("{:?}", err),
+            Ok(_) => unreachable!("client started without management servers"),
+        }
+    }
+
+    #[tokio::test]
+    async fn spawn_returns_execution_error() {
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let updates = Client::new(
+            logger(),
+            "id",
+            vec![ManagementServer {
+                address: "invalid-address".into(),
+                token: None,
+            }],
+        )
+        .spawn(shutdown_rx)
+        .unwrap();
+
+        let err = time::timeout(Duration::from_secs(5), updates.result)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert!(format!("{:?}", err).to_lowercase().contains("invalid url"));
+        assert!(updates.clusters.borrow().is_none());
+        assert!(updates.filter_chain.borrow().is_none());
+    }
+}
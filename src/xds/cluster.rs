@@ -16,20 +16,24 @@
 
 use crate::cluster::{
     Cluster as ProxyCluster, ClusterLocalities, Endpoint, Locality, LocalityEndpoints,
+    DEFAULT_WEIGHT,
 };
 use crate::xds::envoy::config::cluster::v3::{cluster, Cluster};
-use crate::xds::envoy::config::core::v3::{address, socket_address};
+use crate::xds::envoy::config::core::v3::{address, socket_address, HealthStatus};
 use crate::xds::envoy::config::endpoint::v3::{lb_endpoint, ClusterLoadAssignment};
 use crate::xds::envoy::service::discovery::v3::{DiscoveryRequest, DiscoveryResponse};
 use crate::xds::metadata;
 use crate::xds::{CLUSTER_TYPE, ENDPOINT_TYPE};
 
 use crate::xds::ads_client::send_discovery_req;
+use crate::xds::diff::{self, Diff};
 use crate::xds::error::Error;
+use crate::xds::metrics::Metrics;
 use bytes::Bytes;
 use prost::Message;
-use slog::{debug, o, warn, Logger};
-use std::collections::{HashMap, HashSet};
+use slog::{debug, info, o, warn, Logger};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::net::SocketAddr;
 use tokio::sync::mpsc;
 
@@ -40,6 +44,8 @@ use tokio::sync::mpsc;
 pub(crate) struct ClusterManager {
     log: Logger,
 
+    metrics: Metrics,
+
     // Send discovery requests ACKs/NACKs to the server.
     discovery_req_tx: mpsc::Sender<DiscoveryRequest>,
 
@@ -49,12 +55,29 @@ pub(crate) struct ClusterManager {
     // Tracks each cluster's endpoints and localities.
     clusters: HashMap<String, ProxyCluster>,
 
+    // The endpoints in the last cluster set sent downstream.
+    sent_endpoints: BTreeSet<ClusterEndpoint>,
+
     // Tracks the (version, nonce) state for EDS request/response.
     // This is used to make spontaneous EDS requests to
     // subscribe to the latest cluster set anytime the set changes.
     last_seen_cluster_load_assignment_version: Option<(String, String)>,
 }
 
+/// An endpoint of a cluster, as tracked to report the endpoints added and
+/// removed by updates.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ClusterEndpoint {
+    cluster: String,
+    address: SocketAddr,
+}
+
+impl fmt::Display for ClusterEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.cluster, self.address)
+    }
+}
+
 impl ClusterManager {
     /// Creates a new [`ClusterManager`].
     /// Cluster updates are sent on the provided channel. DiscoveryRequest
@@ -62,14 +85,17 @@ impl ClusterManager {
     /// server.
     pub(in crate::xds) fn new(
         base: Logger,
+        metrics: Metrics,
         cluster_updates_tx: mpsc::Sender<HashMap<String, ProxyCluster>>,
         discovery_req_tx: mpsc::Sender<DiscoveryRequest>,
     ) -> Self {
         ClusterManager {
             log: base.new(o!("source" => "xds::ClusterManager")),
+            metrics,
             discovery_req_tx,
             cluster_updates_tx,
             clusters: HashMap::new(),
+            sent_endpoints: BTreeSet::new(),
             last_seen_cluster_load_assignment_version: None,
         }
     }
@@ -85,7 +111,7 @@ impl ClusterManager {
         );
 
         let error_message = self
-            .process_cluster_response(response.resources)
+            .process_cluster_response(&response.version_info, response.resources)
             .await
             .err()
             .map(|err| err.message);
@@ -96,6 +122,7 @@ impl ClusterManager {
 
     async fn process_cluster_response(
         &mut self,
+        version_info: &str,
         resources: Vec<prost_types::Any>,
     ) -> Result<(), Error> {
         let mut temp_cluster_set = HashMap::new();
@@ -134,7 +161,7 @@ impl ClusterManager {
         std::mem::swap(&mut temp_cluster_set, &mut self.clusters);
 
         // Send the new cluster set downstream.
-        self.send_cluster_update().await;
+        self.send_cluster_update(version_info).await;
 
         // If we have any added/removed clusters, we need to update our ClusterLoadAssignment watch.
         // This also handles deletion - if a previously existing cluster wasn't returned in a response,
@@ -173,7 +200,7 @@ impl ClusterManager {
             Some((response.version_info.clone(), response.nonce.clone()));
 
         let error_message = self
-            .process_cluster_load_assignment_response(response.resources)
+            .process_cluster_load_assignment_response(&response.version_info, response.resources)
             .await
             .err()
             .map(|err| err.message);
@@ -188,6 +215,7 @@ impl ClusterManager {
 
     async fn process_cluster_load_assignment_response(
         &mut self,
+        version_info: &str,
         resources: Vec<prost_types::Any>,
     ) -> Result<(), Error> {
         for resource in resources {
@@ -217,13 +245,15 @@ impl ClusterManager {
         }
 
         // Send any cluster update downstream.
-        self.send_cluster_update().await;
+        self.send_cluster_update(version_info).await;
 
         Ok(())
     }
 
     // Send the current cluster state downstream.
-    async fn send_cluster_update(&mut self) {
+    async fn send_cluster_update(&mut self, version_info: &str) {
+        self.log_endpoint_changes(version_info);
+
         self.cluster_updates_tx
             .send(self.clusters.clone())
             .await
@@ -237,6 +267,52 @@ impl ClusterManager {
             .ok();
     }
 
+    // Log and count the endpoints added and removed since the last cluster
+    // state sent downstream, so that operators can tell what an update did.
+    fn log_endpoint_changes(&mut self, version_info: &str) {
+        let endpoints = self
+            .clusters
+            .iter()
+            .flat_map(|(name, cluster)| {
+                cluster
+                    .localities
+                    .values()
+                    .flat_map(|locality| locality.endpoints.iter())
+                    .map(move |endpoint| ClusterEndpoint {
+                        cluster: name.clone(),
+                        address: endpoint.address,
+                    })
+            })
+            .collect::<BTreeSet<_>>();
+
+        let changes = Diff::new(&self.sent_endpoints, &endpoints);
+        if changes.is_empty() {
+            debug!(
+                self.log,
+                "Cluster update did not change any endpoints";
+                "version" => version_info
+            );
+        } else {
+            info!(
+                self.log,
+                "Cluster update changed endpoints";
+                "version" => version_info,
+                "added" => changes.added.len(),
+                "removed" => changes.removed.len(),
+                "added_endpoints" => diff::join(&changes.added),
+                "removed_endpoints" => diff::join(&changes.removed)
+            );
+            self.metrics
+                .endpoints_added_total
+                .inc_by(changes.added.len() as u64);
+            self.metrics
+                .endpoints_removed_total
+                .inc_by(changes.removed.len() as u64);
+        }
+
+        self.sent_endpoints = endpoints;
+    }
+
     /// Parses a ClusterLoadAssignment response into the endpoint
     /// components that we're interested in.
     fn process_cluster_load_assignment(
@@ -253,15 +329,17 @@ impl ClusterManager {
 
             // Extract components of the endpoint that we care about.
             let mut processed_endpoints = vec![];
-            for (host_identifier, metadata) in
+            for (host_identifier, metadata, weight) in
                 lb_locality
                     .lb_endpoints
                     .into_iter()
+                    .filter(|lb_endpoint| is_healthy(lb_endpoint.health_status))
                     .filter_map(|lb_endpoint| {
                         let metadata = lb_endpoint.metadata;
+                        let weight = lb_endpoint.load_balancing_weight;
                         lb_endpoint
                             .host_identifier
-                            .map(|host_identifier| (host_identifier, metadata))
+                            .map(|host_identifier| (host_identifier, metadata, weight))
                     })
             {
                 let endpoint = match host_identifier {
@@ -308,27 +386,33 @@ impl ClusterManager {
                     })?;
 
                 // Extract any metadata associated with the endpoint.
-                let (metadata, tokens) = if let Some(metadata) = metadata {
-                    let (metadata, tokens) =
+                let (metadata, tokens, relay_token) = if let Some(metadata) = metadata {
+                    let (metadata, tokens, relay_token) =
                         metadata::parse_endpoint_metadata(metadata).map_err(Error::new)?;
-                    (Some(metadata), tokens)
+                    (Some(metadata), tokens, relay_token)
                 } else {
-                    (None, Default::default())
+                    (None, Default::default(), None)
                 };
 
-                processed_endpoints.push((address, tokens, metadata));
+                processed_endpoints.push((address, tokens, metadata, weight, relay_token));
             }
 
             let mut endpoints = vec![];
-            for ((addr, port), tokens, metadata) in processed_endpoints.into_iter() {
-                endpoints.push(Endpoint::new(
+            for ((addr, port), tokens, metadata, weight, relay_token) in processed_endpoints {
+                let endpoint = Endpoint::new(
                     // We only support IP addresses so anything else is an error.
                     addr.parse::<std::net::IpAddr>()
                         .map_err(|err| Error::new(format!("invalid ip address: {}", err)))
                         .map(|ip_addr| SocketAddr::new(ip_addr, port))?,
                     tokens,
                     metadata,
-                ));
+                );
+                endpoints.push(Endpoint {
+                    // A weight of 0 isn't valid, so it's treated as unset.
+                    weight: weight.filter(|weight| *weight > 0).unwrap_or(DEFAULT_WEIGHT),
+                    relay_token,
+                    ..endpoint
+                });
             }
 
             existing_endpoints.insert(locality, LocalityEndpoints { endpoints });
@@ -400,21 +484,31 @@ impl ClusterManager {
     }
 }
 
+/// Returns whether an endpoint with the given health status should receive
+/// traffic. Endpoints of unknown health are assumed to be healthy.
+fn is_healthy(health_status: i32) -> bool {
+    health_status != HealthStatus::Unhealthy as i32
+        && health_status != HealthStatus::Draining as i32
+        && health_status != HealthStatus::Timeout as i32
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{ClusterManager, ProxyCluster};
+    use super::{ClusterManager, ProxyCluster, DEFAULT_WEIGHT};
     use crate::cluster::Endpoint as ProxyEndpoint;
     use crate::test_utils::logger;
     use crate::xds::envoy::config::cluster::v3::{cluster::ClusterDiscoveryType, Cluster};
     use crate::xds::envoy::config::core::v3::{
-        address, socket_address::PortSpecifier, Address, Metadata, SocketAddress,
+        address, socket_address::PortSpecifier, Address, HealthStatus, Metadata, SocketAddress,
     };
     use crate::xds::envoy::config::endpoint::v3::{
         lb_endpoint::HostIdentifier, ClusterLoadAssignment, Endpoint, LbEndpoint,
         LocalityLbEndpoints,
     };
     use crate::xds::envoy::service::discovery::v3::{DiscoveryRequest, DiscoveryResponse};
+    use crate::xds::metrics::Metrics;
     use crate::xds::{CLUSTER_TYPE, ENDPOINT_TYPE};
+    use prometheus::Registry;
     use prost::Message;
     use prost_types::value::Kind;
     use prost_types::Struct as ProstStruct;
@@ -432,7 +526,7 @@ mod tests {
 
         let (cluster_updates_tx, _) = mpsc::channel::<ClusterState>(100);
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel::<DiscoveryRequest>(100);
-        let mut cm = ClusterManager::new(logger(), cluster_updates_tx, discovery_req_tx);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
 
         let initial_names = vec!["a".into()];
         cm.on_cluster_response(cluster_discovery_response("1", "2", initial_names.clone()))
@@ -474,7 +568,7 @@ mod tests {
 
         let (cluster_updates_tx, _) = mpsc::channel::<ClusterState>(100);
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel::<DiscoveryRequest>(100);
-        let mut cm = ClusterManager::new(logger(), cluster_updates_tx, discovery_req_tx);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
 
         let names = vec!["a".into(), "b".into()];
         cm.on_cluster_response(cluster_discovery_response("3", "6", names.clone()))
@@ -560,7 +654,7 @@ mod tests {
 
         let (cluster_updates_tx, _) = mpsc::channel::<ClusterState>(100);
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel::<DiscoveryRequest>(100);
-        let mut cm = ClusterManager::new(logger(), cluster_updates_tx, discovery_req_tx);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
 
         let names = vec!["a".into(), "b".into()];
         cm.on_cluster_response(cluster_discovery_response("3", "6", names.clone()))
@@ -596,7 +690,7 @@ mod tests {
 
         let (cluster_updates_tx, _) = mpsc::channel::<ClusterState>(100);
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel::<DiscoveryRequest>(100);
-        let mut cm = ClusterManager::new(logger(), cluster_updates_tx, discovery_req_tx);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
 
         let initial_names = vec!["a".into()];
         cm.on_cluster_response(cluster_discovery_response("1", "2", initial_names.clone()))
@@ -627,7 +721,7 @@ mod tests {
 
         let (cluster_updates_tx, _) = mpsc::channel::<ClusterState>(100);
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel::<DiscoveryRequest>(100);
-        let mut cm = ClusterManager::new(logger(), cluster_updates_tx, discovery_req_tx);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
 
         cm.on_cluster_response(cluster_discovery_response(
             "1",
@@ -677,7 +771,7 @@ mod tests {
 
         let (cluster_updates_tx, mut cluster_updates_rx) = mpsc::channel::<ClusterState>(100);
         let (discovery_req_tx, _) = mpsc::channel::<DiscoveryRequest>(100);
-        let mut cm = ClusterManager::new(logger(), cluster_updates_tx, discovery_req_tx);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
 
         cm.on_cluster_response(cluster_discovery_response(
             "1",
@@ -740,7 +834,7 @@ mod tests {
 
         let (cluster_updates_tx, mut cluster_updates_rx) = mpsc::channel::<ClusterState>(100);
         let (discovery_req_tx, _) = mpsc::channel::<DiscoveryRequest>(100);
-        let mut cm = ClusterManager::new(logger(), cluster_updates_tx, discovery_req_tx);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
 
         cm.on_cluster_response(cluster_discovery_response(
             "1",
@@ -796,7 +890,7 @@ mod tests {
 
         let (cluster_updates_tx, mut cluster_updates_rx) = mpsc::channel::<ClusterState>(100);
         let (discovery_req_tx, _) = mpsc::channel::<DiscoveryRequest>(100);
-        let mut cm = ClusterManager::new(logger(), cluster_updates_tx, discovery_req_tx);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
 
         cm.on_cluster_response(cluster_discovery_response_with_update(
             "1",
@@ -854,7 +948,157 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn unhealthy_endpoints() {
+        // Test that we leave out endpoints that the server reports as unhealthy.
+
+        let (cluster_updates_tx, mut cluster_updates_rx) = mpsc::channel::<ClusterState>(100);
+        let (discovery_req_tx, _) = mpsc::channel::<DiscoveryRequest>(100);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
+
+        cm.on_cluster_response(cluster_discovery_response_with_update(
+            "1",
+            "2",
+            vec!["a".into()],
+            |mut cluster| {
+                if let Some(assignment) = cluster.load_assignment.as_mut() {
+                    let lb_endpoints = &mut assignment.endpoints[0].lb_endpoints;
+                    lb_endpoints[0].health_status = HealthStatus::Healthy as i32;
+                    for (port, health_status) in vec![
+                        (2021, HealthStatus::Unhealthy),
+                        (2022, HealthStatus::Draining),
+                        (2023, HealthStatus::Timeout),
+                    ] {
+                        let mut lb_endpoint = lb_endpoints[0].clone();
+                        lb_endpoint.health_status = health_status as i32;
+                        if let Some(HostIdentifier::Endpoint(endpoint)) =
+                            lb_endpoint.host_identifier.as_mut()
+                        {
+                            endpoint.address = Some(Address {
+                                address: Some(address::Address::SocketAddress(SocketAddress {
+                                    protocol: 1,
+                                    address: "127.0.0.1".into(),
+                                    resolver_name: "".into(),
+                                    ipv4_compat: true,
+                                    port_specifier: Some(PortSpecifier::PortValue(port)),
+                                })),
+                            });
+                        }
+                        lb_endpoints.push(lb_endpoint);
+                    }
+                };
+
+                cluster
+            },
+        ))
+        .await;
+
+        let cluster_state = cluster_updates_rx.recv().await.unwrap();
+        let cluster = cluster_state.get("a").unwrap();
+        assert_eq!(1, cluster.localities.get(&None).unwrap().endpoints.len());
+        assert_cluster_has_lone_static_address(cluster, "127.0.0.1:2020");
+    }
+
+    #[tokio::test]
+    async fn endpoint_weights() {
+        // Test that endpoint weights are kept, and default when unset.
+
+        let (cluster_updates_tx, mut cluster_updates_rx) = mpsc::channel::<ClusterState>(100);
+        let (discovery_req_tx, _) = mpsc::channel::<DiscoveryRequest>(100);
+        let mut cm = ClusterManager::new(logger(), metrics(), cluster_updates_tx, discovery_req_tx);
+
+        cm.on_cluster_response(cluster_discovery_response_with_update(
+            "1",
+            "2",
+            vec!["a".into(), "b".into()],
+            |mut cluster| {
+                if cluster.name == "a" {
+                    if let Some(assignment) = cluster.load_assignment.as_mut() {
+                        assignment.endpoints[0].lb_endpoints[0].load_balancing_weight = Some(5);
+                    }
+                }
+                cluster
+            },
+        ))
+        .await;
+
+        let cluster_state = cluster_updates_rx.recv().await.unwrap();
+        let weight = |name: &str| {
+            let endpoints = &cluster_state.get(name).unwrap().localities[&None].endpoints;
+            endpoints[0].weight
+        };
+        assert_eq!(5, weight("a"));
+        assert_eq!(DEFAULT_WEIGHT, weight("b"));
+    }
+
+    #[tokio::test]
+    async fn endpoint_changes_metrics() {
+        // Test that we count the endpoints added and removed by updates.
+
+        let (cluster_updates_tx, mut cluster_updates_rx) = mpsc::channel::<ClusterState>(100);
+        let (discovery_req_tx, _) = mpsc::channel::<DiscoveryRequest>(100);
+        let metrics = metrics();
+        let mut cm = ClusterManager::new(
+            logger(),
+            metrics.clone(),
+            cluster_updates_tx,
+            discovery_req_tx,
+        );
+
+        cm.on_cluster_response(cluster_discovery_response(
+            "1",
+            "2",
+            vec!["a".into(), "b".into()],
+        ))
+        .await;
+        cluster_updates_rx.recv().await.unwrap();
+        assert_eq!(2, metrics.endpoints_added_total.get());
+        assert_eq!(0, metrics.endpoints_removed_total.get());
+
+        // Move cluster b's endpoint.
+        cm.on_cluster_load_assignment_response(endpoint_discovery_response_with_update(
+            "3",
+            "4",
+            vec!["a".into(), "b".into()],
+            |mut assignment| {
+                if &assignment.cluster_name == "b" {
+                    if let Some(HostIdentifier::Endpoint(endpoint)) =
+                        assignment.endpoints[0].lb_endpoints[0].host_identifier.as_mut()
+                    {
+                        if let Some(address::Address::SocketAddress(address)) = endpoint
+                            .address
+                            .as_mut()
+                            .and_then(|address| address.address.as_mut())
+                        {
+                            address.port_specifier = Some(PortSpecifier::PortValue(4040));
+                        }
+                    }
+                }
+                assignment
+            },
+        ))
+        .await;
+        cluster_updates_rx.recv().await.unwrap();
+        assert_eq!(3, metrics.endpoints_added_total.get());
+        assert_eq!(1, metrics.endpoints_removed_total.get());
+
+        // Nothing changes if the same endpoints are sent again.
+        cm.on_cluster_load_assignment_response(endpoint_discovery_response(
+            "5",
+            "6",
+            vec!["a".into()],
+        ))
+        .await;
+        cluster_updates_rx.recv().await.unwrap();
+        assert_eq!(3, metrics.endpoints_added_total.get());
+        assert_eq!(1, metrics.endpoints_removed_total.get());
+    }
+
     // Test Helpers
+    fn metrics() -> Metrics {
+        Metrics::new(&Registry::default()).unwrap()
+    }
+
     fn create_endpoint_resource(cluster_name: &str) -> ClusterLoadAssignment {
         ClusterLoadAssignment {
             cluster_name: cluster_name.into(),
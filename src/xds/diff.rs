@@ -0,0 +1,104 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use sha2::{Digest, Sha256};
+
+/// The resources added and removed by an update.
+#[derive(Debug, PartialEq)]
+pub(in crate::xds) struct Diff<'a, T> {
+    pub added: Vec<&'a T>,
+    pub removed: Vec<&'a T>,
+}
+
+impl<'a, T: Ord> Diff<'a, T> {
+    /// Returns the resources in `new` but not in `old`, and those in `old`
+    /// but not in `new`, in order.
+    pub fn new(old: &'a BTreeSet<T>, new: &'a BTreeSet<T>) -> Self {
+        Self {
+            added: new.difference(old).collect(),
+            removed: old.difference(new).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Formats resources as a comma separated list for logging.
+pub(in crate::xds) fn join<T: Display>(resources: &[&T]) -> String {
+    resources
+        .iter()
+        .map(|resource| resource.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns a digest of a filter's configuration, which is logged in place
+/// of the configuration itself since it may contain secrets.
+pub(in crate::xds) fn redact(config: &prost_types::Any) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(config.type_url.as_bytes());
+    hasher.update(&config.value);
+    let digest = hasher.finalize();
+    format!(
+        "sha256:{}",
+        digest[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{join, redact, Diff};
+
+    #[test]
+    fn diff() {
+        let old = vec![1, 2, 3].into_iter().collect::<BTreeSet<_>>();
+        let new = vec![5, 3, 4, 2].into_iter().collect::<BTreeSet<_>>();
+
+        let diff = Diff::new(&old, &new);
+        assert_eq!(vec![&4, &5], diff.added);
+        assert_eq!(vec![&1], diff.removed);
+        assert_eq!("4, 5", join(&diff.added));
+
+        assert!(Diff::new(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn redact_config() {
+        let config = prost_types::Any {
+            type_url: "filter".into(),
+            value: b"secret".to_vec(),
+        };
+        let redacted = redact(&config);
+        assert!(redacted.starts_with("sha256:"));
+        assert_eq!(23, redacted.len());
+
+        let other = prost_types::Any {
+            type_url: "filter".into(),
+            value: b"other".to_vec(),
+        };
+        assert_ne!(redacted, redact(&other));
+    }
+}
@@ -14,31 +14,44 @@
  * limitations under the License.
  */
 
+use crate::config::FilterUpdateFailurePolicy;
 use crate::filters::{
-    manager::ListenerManagerArgs, CreateFilterArgs, FilterChain as ProxyFilterChain, FilterRegistry,
+    manager::ListenerManagerArgs, CreateFilterArgs, Filter, FilterChain as ProxyFilterChain,
+    FilterRegistry,
 };
+use crate::proxy::contain_panics;
 use crate::xds::envoy::config::listener::v3::{
-    filter::ConfigType as LdsConfigType, FilterChain, Listener,
+    filter::ConfigType as LdsConfigType, Filter as LdsFilter, FilterChain, Listener,
 };
 use crate::xds::envoy::service::discovery::v3::{DiscoveryRequest, DiscoveryResponse};
 use crate::xds::error::Error;
+use crate::xds::metrics::Metrics;
 use crate::xds::LISTENER_TYPE;
 
+use std::collections::BTreeSet;
+use std::fmt;
 use std::sync::Arc;
 
 use crate::xds::ads_client::send_discovery_req;
+use crate::xds::diff::{self, Diff};
 use bytes::Bytes;
 use prometheus::Registry;
 use prost::Message;
-use slog::{debug, warn, Logger};
+use slog::{debug, error, info, warn, Logger};
 use tokio::sync::mpsc;
 
 /// Tracks FilterChain resources on the LDS DiscoveryResponses and
 /// instantiates a corresponding proxy filter chain and exposes it
-/// to the caller whenever the filter chain changes.
+/// to the caller whenever the filter chain changes. A filter chain is only
+/// exposed once all of its filters have been created and have processed a
+/// test packet without panicking, otherwise the update is rejected. Once a
+/// filter chain has been exposed, updates with filters that fail to be
+/// created can instead be exposed without those filters.
 pub(crate) struct ListenerManager {
     log: Logger,
 
+    metrics: Metrics,
+
     metrics_registry: Registry,
 
     // Registry to lookup filter factories by name.
@@ -49,20 +62,72 @@ pub(crate) struct ListenerManager {
 
     // Sends listener state updates to the caller.
     filter_chain_updates_tx: mpsc::Sender<Arc<ProxyFilterChain>>,
+
+    // The filters in the last filter chain sent to the caller.
+    sent_filters: Vec<ChainFilter>,
+
+    // Whether a filter chain has been sent to the caller.
+    sent_chain: bool,
+
+    // What is done with updates in which a filter fails to be created.
+    filter_update_failure: FilterUpdateFailurePolicy,
+}
+
+/// A filter chain created from a listener update.
+struct ListenerUpdate {
+    filter_chain: ProxyFilterChain,
+    // The filters in the chain.
+    filters: Vec<ChainFilter>,
+    // The names of the filters left out of the chain as they failed to be
+    // created.
+    bypassed: Vec<String>,
+}
+
+impl ListenerUpdate {
+    fn empty(metrics_registry: &Registry) -> Result<Self, Error> {
+        Ok(ListenerUpdate {
+            filter_chain: ProxyFilterChain::new(vec![], metrics_registry)?,
+            filters: vec![],
+            bypassed: vec![],
+        })
+    }
+}
+
+/// A filter in a filter chain, as tracked to report the filters added and
+/// removed by updates.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ChainFilter {
+    name: String,
+    // A digest of the filter's configuration, if it has one.
+    config: Option<String>,
+}
+
+impl fmt::Display for ChainFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.config {
+            Some(config) => write!(f, "{} ({})", self.name, config),
+            None => write!(f, "{}", self.name),
+        }
+    }
 }
 
 impl ListenerManager {
     pub(in crate::xds) fn new(
         log: Logger,
+        metrics: Metrics,
         args: ListenerManagerArgs,
         discovery_req_tx: mpsc::Sender<DiscoveryRequest>,
     ) -> Self {
         ListenerManager {
             log,
+            metrics,
             metrics_registry: args.metrics_registry,
             filter_registry: args.filter_registry,
             discovery_req_tx,
             filter_chain_updates_tx: args.filter_chain_updates_tx,
+            sent_filters: vec![],
+            sent_chain: false,
+            filter_update_failure: args.filter_update_failure,
         }
     }
 
@@ -80,9 +145,14 @@ impl ListenerManager {
             .map_err(|err| err.message);
 
         let error_message = match result {
-            Ok(filter_chain) => {
+            Ok(update) => {
+                self.log_filter_changes(&response.version_info, update.filters);
+                self.metrics
+                    .filters_bypassed
+                    .set(update.bypassed.len() as i64);
+                self.sent_chain = true;
                 self.filter_chain_updates_tx
-                    .send(Arc::new(filter_chain))
+                    .send(Arc::new(update.filter_chain))
                     .await
                     .map_err(|err| {
                         warn!(self.log, "Failed to send filter chain update on channel");
@@ -108,12 +178,47 @@ impl ListenerManager {
         .await;
     }
 
+    // Log and count the filters added and removed since the last filter
+    // chain sent to the caller, so that operators can tell what an update did.
+    fn log_filter_changes(&mut self, version_info: &str, filters: Vec<ChainFilter>) {
+        if filters == self.sent_filters {
+            debug!(
+                self.log,
+                "Listener update did not change the filter chain";
+                "version" => version_info
+            );
+            return;
+        }
+
+        let sent_filters = self.sent_filters.iter().collect::<BTreeSet<_>>();
+        let new_filters = filters.iter().collect::<BTreeSet<_>>();
+        let changes = Diff::new(&sent_filters, &new_filters);
+        info!(
+            self.log,
+            "Listener update changed the filter chain";
+            "version" => version_info,
+            "added" => changes.added.len(),
+            "removed" => changes.removed.len(),
+            "added_filters" => diff::join(&changes.added),
+            "removed_filters" => diff::join(&changes.removed),
+            "filter_chain" => diff::join(&filters.iter().collect::<Vec<_>>())
+        );
+        self.metrics
+            .filters_added_total
+            .inc_by(changes.added.len() as u64);
+        self.metrics
+            .filters_removed_total
+            .inc_by(changes.removed.len() as u64);
+
+        self.sent_filters = filters;
+    }
+
     async fn process_listener_response(
         &mut self,
         mut resources: Vec<prost_types::Any>,
-    ) -> Result<ProxyFilterChain, Error> {
+    ) -> Result<ListenerUpdate, Error> {
         let resource = match resources.len() {
-            0 => return Ok(ProxyFilterChain::new(vec![], &self.metrics_registry)?),
+            0 => return ListenerUpdate::empty(&self.metrics_registry),
             1 => resources.swap_remove(0),
             n => {
                 return Err(Error::new(format!(
@@ -127,7 +232,7 @@ impl ListenerManager {
             .map_err(|err| Error::new(format!("listener decode error: {}", err.to_string())))?;
 
         let lds_filter_chain = match listener.filter_chains.len() {
-            0 => return Ok(ProxyFilterChain::new(vec![], &self.metrics_registry)?),
+            0 => return ListenerUpdate::empty(&self.metrics_registry),
             1 => listener.filter_chains.swap_remove(0),
             n => {
                 return Err(Error::new(format!(
@@ -140,35 +245,90 @@ impl ListenerManager {
         self.process_filter_chain(lds_filter_chain)
     }
 
-    fn process_filter_chain(
-        &self,
-        lds_filter_chain: FilterChain,
-    ) -> Result<ProxyFilterChain, Error> {
+    fn process_filter_chain(&self, lds_filter_chain: FilterChain) -> Result<ListenerUpdate, Error> {
+        // Filters that fail to be created are only bypassed once a filter
+        // chain is in use, so that a proxy never starts without them.
+        let bypass =
+            self.sent_chain && self.filter_update_failure == FilterUpdateFailurePolicy::Bypass;
+
         let mut filters = vec![];
+        let mut chain_filters = vec![];
+        let mut bypassed = vec![];
         for filter in lds_filter_chain.filters {
-            let config = filter
-                .config_type
-                .map(|config| match config {
-                    LdsConfigType::TypedConfig(config) => Ok(config),
-                    invalid => Err(Error::new(format!(
-                        "unsupported filter.config_type: {:?}",
-                        invalid
-                    ))),
-                })
-                .transpose()?;
-            let create_filter_args =
-                CreateFilterArgs::dynamic(self.metrics_registry.clone(), config);
-
-            let name = filter.name;
-            let filter = self
-                .filter_registry
-                .get(&name, create_filter_args)
-                .map_err(|err| Error::new(format!("{}", err)))?;
+            let name = filter.name.clone();
+            let (name, filter, redacted_config) = match self.create_filter(filter) {
+                Ok(created) => created,
+                Err(err) if bypass => {
+                    error!(
+                        self.log,
+                        "Bypassing a filter that failed to be created";
+                        "filter" => &name,
+                        "error" => &err.message
+                    );
+                    bypassed.push(name);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
 
+            chain_filters.push(ChainFilter {
+                name: name.clone(),
+                config: redacted_config,
+            });
             filters.push((name, filter));
         }
 
-        Ok(ProxyFilterChain::new(filters, &self.metrics_registry)?)
+        // Warm the new filter chain up before it replaces the current one,
+        // so that an update with a filter that fails on packets is rejected
+        // rather than dropping every packet.
+        let filter_chain = ProxyFilterChain::new(filters, &self.metrics_registry)?;
+        filter_chain.self_test()?;
+        Ok(ListenerUpdate {
+            filter_chain,
+            filters: chain_filters,
+            bypassed,
+        })
+    }
+
+    // Creates a filter of a filter chain, returning it along with its name
+    // and a digest of its configuration.
+    fn create_filter(
+        &self,
+        filter: LdsFilter,
+    ) -> Result<(String, Box<dyn Filter>, Option<String>), Error> {
+        let config = filter
+            .config_type
+            .map(|config| match config {
+                LdsConfigType::TypedConfig(config) => Ok(config),
+                invalid => Err(Error::new(format!(
+                    "unsupported filter.config_type: {:?}",
+                    invalid
+                ))),
+            })
+            .transpose()?;
+        let redacted_config = config.as_ref().map(diff::redact);
+        let create_filter_args = CreateFilterArgs::dynamic(self.metrics_registry.clone(), config);
+
+        let name = filter.name;
+        let filter = contain_panics(|| self.filter_registry.get(&name, create_filter_args))
+            .map_err(|message| {
+                Error::new(format!("filter {} panicked on creation: {}", name, message))
+            })?
+            .map_err(|err| Error::new(format!("{}", err)))?;
+
+        let name = match self.filter_registry.replacement_for(&name) {
+            Some(replacement) => {
+                warn!(
+                    self.log,
+                    "Filter name is deprecated and will be removed in a future release";
+                    "filter" => &name,
+                    "replacement" => replacement
+                );
+                replacement.into()
+            }
+            None => name,
+        };
+        Ok((name, filter, redacted_config))
     }
 
     // Send a DiscoveryRequest ACK/NACK back to the server for the given version and nonce.
@@ -206,8 +366,9 @@ mod tests {
     use std::time::Duration;
 
     use crate::cluster::Endpoint;
-    use crate::config::{Endpoints, UpstreamEndpoints};
+    use crate::config::{Endpoints, FilterUpdateFailurePolicy, UpstreamEndpoints};
     use crate::filters::{ConvertProtoConfigError, DynFilterFactory, FilterRegistry, FilterSet};
+    use crate::xds::metrics::Metrics;
     use crate::xds::LISTENER_TYPE;
     use prometheus::Registry;
     use prost::Message;
@@ -237,6 +398,9 @@ mod tests {
 
     impl Filter for Append {
         fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+            if self.value.as_ref().unwrap() == "panic" {
+                panic!("panic requested");
+            }
             ctx.contents = format!(
                 "{}{}",
                 String::from_utf8(ctx.contents).unwrap(),
@@ -266,6 +430,9 @@ mod tests {
                 .map(|config| config.deserialize::<Append, ProtoAppend>(self.name()))
                 .transpose()?
                 .unwrap();
+            if filter.value.as_ref().unwrap() == "panic-on-create" {
+                panic!("panic on create requested");
+            }
             if filter.value.as_ref().unwrap() == "reject" {
                 Err(Error::FieldInvalid {
                     field: "value".into(),
@@ -288,6 +455,7 @@ mod tests {
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel(10);
         let mut manager = ListenerManager::new(
             logger(),
+            metrics(),
             ListenerManagerArgs::new(
                 Registry::default(),
                 filter_registry,
@@ -396,6 +564,7 @@ mod tests {
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel(10);
         let mut manager = ListenerManager::new(
             logger(),
+            metrics(),
             ListenerManagerArgs::new(
                 Registry::default(),
                 filter_registry,
@@ -503,10 +672,11 @@ mod tests {
         // Test that the manager returns NACK DiscoveryRequests for updates it failed to process.
 
         let filter_registry = new_registry();
-        let (filter_chain_updates_tx, _filter_chain_updates_rx) = mpsc::channel(10);
+        let (filter_chain_updates_tx, mut filter_chain_updates_rx) = mpsc::channel(10);
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel(10);
         let mut manager = ListenerManager::new(
             logger(),
+            metrics(),
             ListenerManagerArgs::new(
                 Registry::default(),
                 filter_registry,
@@ -536,6 +706,19 @@ mod tests {
                 }])],
                 "reject requested",
             ),
+            (
+                // The filter panics when it is created.
+                vec![create_lds_filter_chain(vec![append_filter("panic-on-create")])],
+                "filter filter.append panicked on creation: panic on create requested",
+            ),
+            (
+                // The filter panics on packets, so fails its self-test.
+                vec![create_lds_filter_chain(vec![
+                    append_filter("world"),
+                    append_filter("panic"),
+                ])],
+                "filter filter.append panicked on a test packet: panic requested",
+            ),
             (
                 // Filter does not exist in the filter registry.
                 vec![create_lds_filter_chain(vec![LdsFilter {
@@ -603,7 +786,13 @@ mod tests {
                 discovery_req,
             );
 
-            assert!(error_detail.message.contains(error_message));
+            assert!(
+                error_detail.message.contains(error_message),
+                "{}",
+                error_detail.message
+            );
+            // The current filter chain is kept.
+            assert!(filter_chain_updates_rx.try_recv().is_err());
         }
     }
 
@@ -615,6 +804,7 @@ mod tests {
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel(10);
         let mut manager = ListenerManager::new(
             logger(),
+            metrics(),
             ListenerManagerArgs::new(
                 Registry::default(),
                 FilterRegistry::new(FilterSet::default(&logger())),
@@ -675,6 +865,174 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn listener_manager_filter_changes_metrics() {
+        // Test that the filters added and removed by updates are counted.
+
+        let (filter_chain_updates_tx, _filter_chain_updates_rx) = mpsc::channel(10);
+        let (discovery_req_tx, _discovery_req_rx) = mpsc::channel(10);
+        let metrics = metrics();
+        let mut manager = ListenerManager::new(
+            logger(),
+            metrics.clone(),
+            ListenerManagerArgs::new(
+                Registry::default(),
+                new_registry(),
+                filter_chain_updates_tx,
+            ),
+            discovery_req_tx,
+        );
+
+        let updates = vec![
+            (vec!["a", "b"], (2, 0)),
+            // Unchanged.
+            (vec!["a", "b"], (2, 0)),
+            // A reconfigured filter is both removed and added.
+            (vec!["a", "c"], (3, 1)),
+            (vec![], (3, 3)),
+        ];
+        for (i, (values, (added, removed))) in updates.into_iter().enumerate() {
+            let filters = values
+                .into_iter()
+                .map(|value| LdsFilter {
+                    name: APPEND_TYPE_URL.into(),
+                    config_type: Some(ConfigType::TypedConfig({
+                        let mut buf = vec![];
+                        ProtoAppend {
+                            value: Some(value.into()),
+                        }
+                        .encode(&mut buf)
+                        .unwrap();
+                        prost_types::Any {
+                            type_url: APPEND_TYPE_URL.into(),
+                            value: buf,
+                        }
+                    })),
+                })
+                .collect();
+            let lds_listener = create_lds_listener(
+                "test-listener".into(),
+                vec![create_lds_filter_chain(filters)],
+            );
+            let mut buf = vec![];
+            lds_listener.encode(&mut buf).unwrap();
+
+            manager
+                .on_listener_response(DiscoveryResponse {
+                    version_info: format!("test-version-{}", i),
+                    resources: vec![prost_types::Any {
+                        type_url: LISTENER_TYPE.into(),
+                        value: buf,
+                    }],
+                    canary: false,
+                    type_url: LISTENER_TYPE.into(),
+                    nonce: "test-nonce".into(),
+                    control_plane: None,
+                })
+                .await;
+
+            assert_eq!(added, metrics.filters_added_total.get(), "update {}", i);
+            assert_eq!(removed, metrics.filters_removed_total.get(), "update {}", i);
+        }
+    }
+
+    #[tokio::test]
+    async fn listener_manager_bypass_failed_filters() {
+        // Test that filters which fail to be created are bypassed under the
+        // bypass policy, but only once a filter chain has been applied.
+
+        let (filter_chain_updates_tx, mut filter_chain_updates_rx) = mpsc::channel(10);
+        let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel(10);
+        let metrics = metrics();
+        let mut manager = ListenerManager::new(
+            logger(),
+            metrics.clone(),
+            ListenerManagerArgs::new(Registry::default(), new_registry(), filter_chain_updates_tx)
+                .with_filter_update_failure(FilterUpdateFailurePolicy::Bypass),
+            discovery_req_tx,
+        );
+
+        let updates = vec![
+            // The initial filter chain is rejected.
+            (vec!["reject", "!"], None),
+            (vec!["world"], Some("hello-world")),
+            (vec!["world", "reject", "!"], Some("hello-world!")),
+        ];
+        for (i, (values, expected)) in updates.into_iter().enumerate() {
+            let filters = values.into_iter().map(append_filter).collect();
+            let lds_listener = create_lds_listener(
+                "test-listener".into(),
+                vec![create_lds_filter_chain(filters)],
+            );
+            let mut buf = vec![];
+            lds_listener.encode(&mut buf).unwrap();
+
+            manager
+                .on_listener_response(DiscoveryResponse {
+                    version_info: format!("test-version-{}", i),
+                    resources: vec![prost_types::Any {
+                        type_url: LISTENER_TYPE.into(),
+                        value: buf,
+                    }],
+                    canary: false,
+                    type_url: LISTENER_TYPE.into(),
+                    nonce: "test-nonce".into(),
+                    control_plane: None,
+                })
+                .await;
+
+            let discovery_req = time::timeout(Duration::from_secs(5), discovery_req_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let expected = match expected {
+                Some(expected) => expected,
+                None => {
+                    assert!(discovery_req.error_detail.is_some(), "update {}", i);
+                    assert!(filter_chain_updates_rx.try_recv().is_err());
+                    continue;
+                }
+            };
+            assert!(discovery_req.error_detail.is_none(), "update {}", i);
+
+            let filter_chain = filter_chain_updates_rx.try_recv().unwrap();
+            let response = filter_chain
+                .read(ReadContext::new(
+                    UpstreamEndpoints::from(
+                        Endpoints::new(vec![Endpoint::from_address(
+                            "127.0.0.1:8080".parse().unwrap(),
+                        )])
+                        .unwrap(),
+                    ),
+                    "127.0.0.1:8081".parse().unwrap(),
+                    "hello-".into(),
+                ))
+                .unwrap();
+            assert_eq!(expected, String::from_utf8(response.contents).unwrap());
+        }
+        assert_eq!(1, metrics.filters_bypassed.get());
+    }
+
+    fn metrics() -> Metrics {
+        Metrics::new(&Registry::default()).unwrap()
+    }
+
+    fn append_filter(value: &str) -> LdsFilter {
+        let mut buf = vec![];
+        ProtoAppend {
+            value: Some(value.into()),
+        }
+        .encode(&mut buf)
+        .unwrap();
+        LdsFilter {
+            name: APPEND_TYPE_URL.into(),
+            config_type: Some(ConfigType::TypedConfig(prost_types::Any {
+                type_url: APPEND_TYPE_URL.into(),
+                value: buf,
+            })),
+        }
+    }
+
     #[allow(deprecated)]
     fn create_lds_filter_chain(filters: Vec<LdsFilter>) -> LdsFilterChain {
         LdsFilterChain {
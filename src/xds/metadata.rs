@@ -16,7 +16,7 @@
 
 use std::collections::{BTreeMap, HashSet};
 
-use crate::config::extract_endpoint_tokens;
+use crate::config::{extract_endpoint_relay_token, extract_endpoint_tokens};
 use crate::xds::envoy::config::core::v3::Metadata;
 use prost_types::value::Kind;
 use prost_types::Value as ProstValue;
@@ -27,10 +27,11 @@ use serde_json::Number as JSONNumber;
 /// Converts an XDS Metadata object into endpoint specific values and JSON values.
 pub fn parse_endpoint_metadata(
     metadata: Metadata,
-) -> Result<(JSONValue, HashSet<Vec<u8>>), String> {
+) -> Result<(JSONValue, HashSet<Vec<u8>>, Option<Vec<u8>>), String> {
     let mut metadata = to_json_map(metadata)?;
+    let relay_token = extract_endpoint_relay_token(&mut metadata)?;
     let tokens = extract_endpoint_tokens(&mut metadata)?;
-    Ok((JSONValue::Object(metadata), tokens))
+    Ok((JSONValue::Object(metadata), tokens, relay_token))
 }
 
 /// Converts an XDS Metadata object into an equivalent JSON map.
@@ -317,7 +318,7 @@ mod tests {
             .collect(),
         };
 
-        let (metadata, tokens) = parse_endpoint_metadata(metadata).unwrap();
+        let (metadata, tokens, relay_token) = parse_endpoint_metadata(metadata).unwrap();
 
         assert_eq!(metadata, expected);
         assert_eq!(
@@ -326,6 +327,7 @@ mod tests {
                 .into_iter()
                 .collect::<HashSet<_>>()
         );
+        assert_eq!(relay_token, None);
     }
 
     #[test]
@@ -17,7 +17,7 @@
 use crate::metrics::{opts, CollectorExt};
 use prometheus::core::{AtomicU64, GenericCounter, GenericGauge};
 use prometheus::Result as MetricsResult;
-use prometheus::{IntCounter, Registry};
+use prometheus::{IntCounter, IntGauge, Registry};
 
 #[derive(Clone)]
 pub struct Metrics {
@@ -26,6 +26,11 @@ pub struct Metrics {
     pub update_success_total: GenericCounter<AtomicU64>,
     pub update_failure_total: GenericCounter<AtomicU64>,
     pub requests_total: GenericCounter<AtomicU64>,
+    pub endpoints_added_total: IntCounter,
+    pub endpoints_removed_total: IntCounter,
+    pub filters_added_total: IntCounter,
+    pub filters_removed_total: IntCounter,
+    pub filters_bypassed: IntGauge,
 }
 
 impl Metrics {
@@ -51,6 +56,26 @@ impl Metrics {
                 opts("requests_total", subsystem, "Total number of discovery requests made to the xDS management server."),
             )?
                 .register_if_not_exists(registry)?,
+            endpoints_added_total: IntCounter::with_opts(
+                opts("endpoints_added_total", subsystem, "Total number of endpoints added by updates from the xDS management server."),
+            )?
+                .register_if_not_exists(registry)?,
+            endpoints_removed_total: IntCounter::with_opts(
+                opts("endpoints_removed_total", subsystem, "Total number of endpoints removed by updates from the xDS management server."),
+            )?
+                .register_if_not_exists(registry)?,
+            filters_added_total: IntCounter::with_opts(
+                opts("filters_added_total", subsystem, "Total number of filters added to the filter chain by updates from the xDS management server."),
+            )?
+                .register_if_not_exists(registry)?,
+            filters_removed_total: IntCounter::with_opts(
+                opts("filters_removed_total", subsystem, "Total number of filters removed from the filter chain by updates from the xDS management server."),
+            )?
+                .register_if_not_exists(registry)?,
+            filters_bypassed: IntGauge::with_opts(
+                opts("filters_bypassed", subsystem, "Number of filters left out of the current filter chain as they failed to be created."),
+            )?
+                .register_if_not_exists(registry)?,
         })
     }
 }
@@ -0,0 +1,449 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reports a summary of the proxy's metrics to the management server that
+//! it's connected to, over its xDS connection, so that proxies that can't be
+//! scraped, such as those on players' machines, can still be monitored.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use parking_lot::Mutex;
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use slog::{debug, o, warn, Logger};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::channel::Channel as TonicChannel;
+use tonic::{Code, Request};
+
+use crate::config::Telemetry as TelemetryConfig;
+use crate::proxy::Metrics;
+
+crate::include_proto!("quilkin.proxy.telemetry.v1alpha1");
+use self::quilkin::proxy::telemetry::v1alpha1::{
+    telemetry_client::TelemetryClient, Counter, Label, Summary, TelemetryReport,
+};
+
+/// The number of reports that can wait to be sent. Reports aren't made
+/// while the stream is full, so a slow server delays reports rather than
+/// losing them.
+const REPORT_BUFFER_SIZE: usize = 1;
+
+/// Reports a summary of the proxy's metrics every interval.
+#[derive(Clone)]
+pub(crate) struct TelemetryReporter {
+    log: Logger,
+    metrics: Metrics,
+    config: TelemetryConfig,
+    /// The values of the reported metrics as of the last report. They are
+    /// kept across connections, so that each increase is only reported
+    /// once.
+    previous: Arc<Mutex<Snapshot>>,
+}
+
+/// The values of metrics at a point in time.
+struct Snapshot {
+    taken_at: Instant,
+    values: HashMap<MetricKey, Value>,
+}
+
+/// Identifies a metric by the name of its family and its labels.
+type MetricKey = (String, Vec<(String, String)>);
+
+/// The value of a counter or histogram.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Counter(f64),
+    /// The count and sum of the values recorded by a histogram, and the
+    /// cumulative count of each of its buckets by upper bound.
+    Histogram {
+        count: u64,
+        sum: f64,
+        buckets: Vec<(f64, u64)>,
+    },
+}
+
+/// Stops reporting once dropped, e.g when the xDS connection is lost.
+pub(crate) struct ReportTask(JoinHandle<()>);
+
+impl Drop for ReportTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl TelemetryReporter {
+    pub(crate) fn new(base: &Logger, metrics: Metrics, config: TelemetryConfig) -> Self {
+        Self {
+            log: base.new(o!("source" => "xds::TelemetryReporter")),
+            metrics,
+            config,
+            previous: Arc::new(Mutex::new(Snapshot {
+                taken_at: Instant::now(),
+                values: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Spawns a task that streams a report to the management server behind
+    /// `channel` every interval, identifying the proxy as `node_id`.
+    pub(crate) fn spawn(
+        &self,
+        channel: TonicChannel,
+        authorization: Option<MetadataValue<Ascii>>,
+        node_id: String,
+    ) -> ReportTask {
+        let reporter = self.clone();
+        ReportTask(tokio::spawn(async move {
+            let (reports, reports_rx) = mpsc::channel(REPORT_BUFFER_SIZE);
+            let mut request = Request::new(ReceiverStream::new(reports_rx));
+            if let Some(authorization) = authorization {
+                request
+                    .metadata_mut()
+                    .insert("authorization", authorization);
+            }
+            let mut client = TelemetryClient::new(channel);
+
+            let interval = reporter.config.interval;
+            let send_reports = async {
+                let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+                loop {
+                    ticks.tick().await;
+                    if reports.send(reporter.report(&node_id)).await.is_err() {
+                        return;
+                    }
+                }
+            };
+            tokio::select! {
+                result = client.report(request) => match result {
+                    Ok(_) => debug!(reporter.log, "Management server closed the telemetry stream"),
+                    Err(status) if status.code() == Code::Unimplemented => {
+                        warn!(reporter.log, "Management server doesn't support telemetry")
+                    }
+                    Err(status) => {
+                        warn!(reporter.log, "Failed to report telemetry"; "status" => %status)
+                    }
+                },
+                _ = send_reports => {}
+            }
+        }))
+    }
+
+    /// Returns a report of how the reported metrics changed since the
+    /// previous report.
+    fn report(&self, node_id: &str) -> TelemetryReport {
+        let mut previous = self.previous.lock();
+        let now = Instant::now();
+        let period = now.duration_since(previous.taken_at);
+
+        let mut values = HashMap::new();
+        let mut counters = vec![];
+        let mut summaries = vec![];
+        for family in self.metrics.gather(&self.config.metrics) {
+            for metric in family.get_metric() {
+                let value = match Value::new(&family, metric) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let labels = metric
+                    .get_label()
+                    .iter()
+                    .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+                    .collect::<Vec<_>>();
+                let key = (family.get_name().to_string(), labels);
+                let earlier = previous.values.get(&key);
+                match value.since(earlier) {
+                    Some(Value::Counter(increase)) => counters.push(Counter {
+                        name: key.0.clone(),
+                        labels: proto_labels(&key.1),
+                        increase,
+                    }),
+                    Some(Value::Histogram {
+                        count,
+                        sum,
+                        buckets,
+                    }) => summaries.push(Summary {
+                        name: key.0.clone(),
+                        labels: proto_labels(&key.1),
+                        count,
+                        sum,
+                        p50: quantile(0.5, count, &buckets),
+                        p90: quantile(0.9, count, &buckets),
+                        p99: quantile(0.99, count, &buckets),
+                    }),
+                    None => {}
+                }
+                values.insert(key, value);
+            }
+        }
+        *previous = Snapshot {
+            taken_at: now,
+            values,
+        };
+
+        TelemetryReport {
+            node_id: node_id.into(),
+            timestamp: Some(SystemTime::now().into()),
+            period: Some(period.into()),
+            counters,
+            summaries,
+        }
+    }
+}
+
+impl Value {
+    /// Returns the value of `metric`, or `None` if it's neither a counter
+    /// nor a histogram.
+    fn new(family: &MetricFamily, metric: &Metric) -> Option<Self> {
+        match family.get_field_type() {
+            MetricType::COUNTER => Some(Self::Counter(metric.get_counter().get_value())),
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                Some(Self::Histogram {
+                    count: histogram.get_sample_count(),
+                    sum: histogram.get_sample_sum(),
+                    buckets: histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                        .collect(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns how the value changed since it was `earlier`, which is
+    /// `None` for a metric that wasn't reported before, or `None` if it
+    /// didn't increase.
+    fn since(&self, earlier: Option<&Value>) -> Option<Value> {
+        match (self, earlier) {
+            (Self::Counter(value), Some(Self::Counter(earlier))) => {
+                Some(Self::Counter(value - earlier)).filter(|_| value > earlier)
+            }
+            (Self::Counter(value), _) => Some(Self::Counter(*value)).filter(|_| *value > 0.0),
+            (
+                Self::Histogram {
+                    count,
+                    sum,
+                    buckets,
+                },
+                Some(Self::Histogram {
+                    count: earlier_count,
+                    sum: earlier_sum,
+                    buckets: earlier_buckets,
+                }),
+            ) if buckets.len() == earlier_buckets.len() => Some(Self::Histogram {
+                count: count.saturating_sub(*earlier_count),
+                sum: sum - earlier_sum,
+                buckets: buckets
+                    .iter()
+                    .zip(earlier_buckets)
+                    .map(|((bound, count), (_, earlier))| (*bound, count.saturating_sub(*earlier)))
+                    .collect(),
+            })
+            .filter(|_| count > earlier_count),
+            (Self::Histogram { count, .. }, _) => Some(self.clone()).filter(|_| *count > 0),
+        }
+    }
+}
+
+fn proto_labels(labels: &[(String, String)]) -> Vec<Label> {
+    labels
+        .iter()
+        .map(|(name, value)| Label {
+            name: name.clone(),
+            value: value.clone(),
+        })
+        .collect()
+}
+
+/// Returns an estimate of the `q` quantile of `count` values, given the
+/// cumulative count of the values in each of their histogram's `buckets`,
+/// by interpolating within the bucket the quantile falls into. Values above
+/// the last bucket are only known to be above its bound, which is returned.
+fn quantile(q: f64, count: u64, buckets: &[(f64, u64)]) -> f64 {
+    let rank = q * count as f64;
+    let (mut lower_bound, mut lower_count) = (0.0, 0);
+    for &(bound, cumulative) in buckets {
+        if cumulative as f64 >= rank {
+            let in_bucket = cumulative.saturating_sub(lower_count);
+            if in_bucket == 0 {
+                return bound;
+            }
+            let position = (rank - lower_count as f64) / in_bucket as f64;
+            return lower_bound + (bound - lower_bound) * position;
+        }
+        lower_bound = bound;
+        lower_count = cumulative;
+    }
+    lower_bound
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry};
+    use tokio::sync::mpsc;
+    use tokio_stream::StreamExt;
+    use tonic::transport::{Channel, Server};
+    use tonic::{Request, Response, Status, Streaming};
+
+    use super::quilkin::proxy::telemetry::v1alpha1::{
+        telemetry_server::{Telemetry, TelemetryServer},
+        ReportResponse, TelemetryReport,
+    };
+    use super::{quantile, TelemetryReporter};
+    use crate::config::Telemetry as TelemetryConfig;
+    use crate::proxy::Metrics;
+    use crate::test_utils::logger;
+
+    fn reporter(registry: Registry, metrics: Vec<String>) -> TelemetryReporter {
+        TelemetryReporter::new(
+            &logger(),
+            Metrics::new(&logger(), registry),
+            TelemetryConfig {
+                interval: Duration::from_millis(10),
+                metrics,
+            },
+        )
+    }
+
+    #[test]
+    fn report() {
+        let registry = Registry::default();
+        let packets = IntCounterVec::new(Opts::new("packets_total", "Packets"), &["kind"]).unwrap();
+        registry.register(Box::new(packets.clone())).unwrap();
+        let latency = Histogram::with_opts(
+            HistogramOpts::new("latency_seconds", "Latency").buckets(vec![0.1, 1.0, 10.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        let other = IntCounter::new("other_total", "Other").unwrap();
+        registry.register(Box::new(other.clone())).unwrap();
+        let reporter = reporter(registry, vec!["packets_*".into(), "latency_*".into()]);
+
+        packets.with_label_values(&["read"]).inc_by(3);
+        packets.with_label_values(&["write"]).inc();
+        latency.observe(0.5);
+        other.inc();
+        let report = reporter.report("proxy");
+        assert_eq!("proxy", report.node_id);
+        assert_eq!(2, report.counters.len());
+        assert_eq!(3.0, report.counters[0].increase);
+        assert_eq!("kind", report.counters[0].labels[0].name);
+        assert_eq!("read", report.counters[0].labels[0].value);
+        assert_eq!(1, report.summaries.len());
+        assert_eq!(1, report.summaries[0].count);
+
+        // Only what changed since the previous report is reported.
+        packets.with_label_values(&["read"]).inc();
+        for _ in 0..10 {
+            latency.observe(5.0);
+        }
+        let report = reporter.report("proxy");
+        assert_eq!(1, report.counters.len());
+        assert_eq!(1.0, report.counters[0].increase);
+        let summary = &report.summaries[0];
+        assert_eq!(10, summary.count);
+        assert_eq!(50.0, summary.sum);
+        assert_eq!(5.5, summary.p50);
+
+        let report = reporter.report("proxy");
+        assert!(report.counters.is_empty());
+        assert!(report.summaries.is_empty());
+    }
+
+    #[test]
+    fn quantiles() {
+        let buckets = vec![(1.0, 10), (2.0, 20), (4.0, 20)];
+        assert_eq!(1.0, quantile(0.5, 20, &buckets));
+        assert_eq!(1.5, quantile(0.75, 20, &buckets));
+        assert_eq!(0.5, quantile(0.25, 20, &buckets));
+        // Values above the last bucket are only known to be above it.
+        assert_eq!(4.0, quantile(0.99, 40, &buckets));
+    }
+
+    /// A management server that forwards the reports it receives.
+    struct Collector {
+        reports: mpsc::UnboundedSender<TelemetryReport>,
+    }
+
+    #[tonic::async_trait]
+    impl Telemetry for Collector {
+        async fn report(
+            &self,
+            request: Request<Streaming<TelemetryReport>>,
+        ) -> Result<Response<ReportResponse>, Status> {
+            let mut stream = request.into_inner();
+            while let Some(report) = stream.next().await {
+                self.reports.send(report?).ok();
+            }
+            Ok(Response::new(ReportResponse {}))
+        }
+    }
+
+    #[tokio::test]
+    async fn spawn() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let (reports_tx, mut reports) = mpsc::unbounded_channel();
+        tokio::spawn(
+            Server::builder()
+                .add_service(TelemetryServer::new(Collector {
+                    reports: reports_tx,
+                }))
+                .serve(addr),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let registry = Registry::default();
+        let counter = IntCounter::new("packets_total", "Packets").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let channel = Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let task = reporter(registry, vec![]).spawn(channel, None, "proxy".into());
+
+        let report = tokio::time::timeout(Duration::from_secs(5), reports.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!("proxy", report.node_id);
+        assert!(report
+            .counters
+            .iter()
+            .any(|counter| counter.name == "packets_total" && counter.increase == 1.0));
+
+        // Reports stop once the task is dropped.
+        drop(task);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        while reports.try_recv().is_ok() {}
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(reports.try_recv().is_err());
+    }
+}
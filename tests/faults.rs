@@ -0,0 +1,129 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+extern crate quilkin;
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use tokio::time::{timeout, Duration, Instant};
+
+    use quilkin::config::Config;
+    use quilkin::test_utils::TestHelper;
+
+    /// Returns the config of a proxy on `port` forwarding to `echo`, with
+    /// `proxy` appended to its `proxy` section.
+    fn config(port: u16, echo: SocketAddr, proxy: &str) -> Config {
+        let yaml = format!(
+            "
+version: v1alpha1
+proxy:
+  port: {}
+{}
+static:
+  endpoints:
+    - address: {}
+",
+            port, proxy, echo
+        );
+        Config::from_reader(yaml.as_bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_filters_are_shed() {
+        let mut t = TestHelper::default();
+        let echo = t.run_echo_server().await;
+
+        // Packets take longer to filter than their deadline allows, so they
+        // are dropped rather than forwarded late.
+        let server_port = 12359;
+        t.run_server_with_config(config(
+            server_port,
+            echo,
+            "
+  packet_deadline: 50ms
+  faults:
+    filter_delay: 100ms",
+        ));
+
+        let socket = t.create_socket().await;
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), server_port);
+        let mut buf = vec![0; 1024];
+        socket.send_to(b"hello", &server_addr).await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(500), socket.recv_from(&mut buf))
+                .await
+                .is_err(),
+            "should not receive a response"
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_filters_delay_packets() {
+        let mut t = TestHelper::default();
+        let echo = t.run_echo_server().await;
+
+        let server_port = 12360;
+        t.run_server_with_config(config(
+            server_port,
+            echo,
+            "
+  faults:
+    filter_delay: 50ms",
+        ));
+
+        let socket = t.create_socket().await;
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), server_port);
+        let mut buf = vec![0; 1024];
+        let start = Instant::now();
+        socket.send_to(b"hello", &server_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .expect("should receive a response")
+            .unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+        // The packet is delayed on its way to the endpoint and on its way
+        // back.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn send_failures() {
+        let mut t = TestHelper::default();
+        let echo = t.run_echo_server().await;
+
+        let server_port = 12361;
+        t.run_server_with_config(config(
+            server_port,
+            echo,
+            "
+  faults:
+    send_failure_rate: 1",
+        ));
+
+        let socket = t.create_socket().await;
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), server_port);
+        let mut buf = vec![0; 1024];
+        socket.send_to(b"hello", &server_addr).await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(500), socket.recv_from(&mut buf))
+                .await
+                .is_err(),
+            "should not receive a response"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+extern crate quilkin;
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use tokio::time::{timeout, Duration};
+
+    use quilkin::config::Config;
+    use quilkin::test_utils::TestHelper;
+
+    #[tokio::test]
+    async fn handshake() {
+        let mut t = TestHelper::default();
+        let echo = t.run_echo_server().await;
+
+        let server_port = 12358;
+        let yaml = format!(
+            "
+version: v1alpha1
+proxy:
+  port: {}
+  handshake:
+    secret: c2VjcmV0
+static:
+  endpoints:
+    - address: {}
+",
+            server_port, echo
+        );
+        let server_config = Config::from_reader(yaml.as_bytes()).unwrap();
+        t.run_server_with_config(server_config);
+
+        let socket = t.create_socket().await;
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), server_port);
+        let mut buf = vec![0; 1024];
+
+        // A packet smaller than the challenge is dropped without a response.
+        socket.send_to(b"hello", &server_addr).await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(500), socket.recv_from(&mut buf))
+                .await
+                .is_err(),
+            "should not receive a challenge"
+        );
+
+        // A large enough packet is answered with a challenge.
+        socket.send_to(&[b'a'; 64], &server_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .expect("should receive a challenge")
+            .unwrap();
+        let challenge = buf[..size].to_vec();
+        assert!(challenge.starts_with(b"QUILKIN_COOKIE"));
+
+        // Echoing the challenge creates a session.
+        let mut packet = challenge;
+        packet.extend_from_slice(b"hello");
+        socket.send_to(&packet, &server_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .expect("should receive a response")
+            .unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+
+        // Once the session exists, packets no longer need a cookie.
+        socket.send_to(b"world", &server_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .expect("should receive a response")
+            .unwrap();
+        assert_eq!(b"world", &buf[..size]);
+    }
+}
@@ -32,7 +32,9 @@ mod tests {
             .with_port(server_port)
             .with_static(vec![], vec![EndPoint::new("127.0.0.1:0".parse().unwrap())])
             .with_admin(Admin {
-                address: "[::]:9093".parse().unwrap(),
+                address: Some("[::]:9093".parse().unwrap()),
+                local_socket: None,
+                tap_address: None,
             })
             .build();
         t.run_server_with_builder(ProxyBuilder::from(Arc::new(server_config)));
@@ -29,6 +29,7 @@ mod tests {
 
         let yaml = "
 policy: ROUND_ROBIN
+rebalance: true
 ";
         let selected_endpoint = Arc::new(Mutex::new(None::<SocketAddr>));
 
@@ -40,7 +40,9 @@ mod tests {
             .with_port(server_port)
             .with_static(vec![], vec![EndPoint::new(echo)])
             .with_admin(Admin {
-                address: "[::]:9092".parse().unwrap(),
+                address: Some("[::]:9092".parse().unwrap()),
+                local_socket: None,
+                tap_address: None,
             })
             .build();
         t.run_server_with_builder(Builder::from(Arc::new(server_config)));
@@ -116,9 +116,9 @@ mod quilkin_proto {
     pub mod extensions {
         pub mod filters {
             pub mod concatenate_bytes {
-                pub mod v1alpha1 {
+                pub mod v1beta1 {
                     #![doc(hidden)]
-                    tonic::include_proto!("quilkin.extensions.filters.concatenate_bytes.v1alpha1");
+                    tonic::include_proto!("quilkin.extensions.filters.concatenate_bytes.v1beta1");
                 }
             }
         }
@@ -147,14 +147,15 @@ mod tests {
     use super::envoy::service::discovery::v3::{
         DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
     };
-    use super::quilkin_proto::extensions::filters::concatenate_bytes::v1alpha1::ConcatenateBytes;
-    use super::quilkin_proto::extensions::filters::concatenate_bytes::v1alpha1::concatenate_bytes::{
+    use super::quilkin_proto::extensions::filters::concatenate_bytes::v1beta1::ConcatenateBytes;
+    use super::quilkin_proto::extensions::filters::concatenate_bytes::v1beta1::concatenate_bytes::{
         Strategy, StrategyValue,
     };
 
-    use quilkin::config::Config;
+    use quilkin::config::{Config, ManagementServer};
     use quilkin::proxy::Builder;
     use quilkin::test_utils::{logger, TestHelper};
+    use quilkin::xds::Client;
 
     use prost::Message;
     use slog::{info, o, Logger};
@@ -342,12 +343,104 @@ dynamic:
         }
     }
 
+    #[tokio::test]
+    async fn standalone_client_receives_updates() {
+        let t = TestHelper::default();
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let (discovery_response_tx, discovery_response_rx) = mpsc::channel(1);
+
+        let mut control_plane_shutdown_rx = shutdown_rx.clone();
+        let log = t.log.new(o!("source" => "control-plane"));
+        tokio::spawn(async move {
+            let server = ADSServer::new(ControlPlane {
+                source_discovery_response_rx: tokio::sync::Mutex::new(Some(discovery_response_rx)),
+                log,
+                shutdown_rx: control_plane_shutdown_rx.clone(),
+            });
+            let server = Server::builder().add_service(server);
+            server
+                .serve_with_shutdown("0.0.0.0:23457".parse().unwrap(), async move {
+                    let _: Result<(), _> = control_plane_shutdown_rx.changed().await;
+                })
+                .await
+                .unwrap();
+        });
+
+        let mut updates = Client::new(
+            logger(),
+            "test-client",
+            vec![ManagementServer {
+                address: "http://127.0.0.1:23457".into(),
+                token: None,
+            }],
+        )
+        .spawn(shutdown_rx)
+        .unwrap();
+
+        let endpoint_addr = "127.0.0.1:26000".parse().unwrap();
+        discovery_response_tx
+            .send(Ok(cluster_discovery_response(
+                "cluster-1".into(),
+                "1",
+                "1",
+                endpoint_addr,
+            )))
+            .await
+            .unwrap();
+        time::timeout(Duration::from_secs(10), updates.clusters.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        {
+            let clusters = updates.clusters.borrow();
+            let cluster = clusters.as_ref().unwrap().get("cluster-1").unwrap();
+            let addresses = cluster
+                .localities
+                .values()
+                .flat_map(|locality| locality.endpoints.iter())
+                .map(|endpoint| endpoint.address)
+                .collect::<Vec<_>>();
+            assert_eq!(vec![endpoint_addr], addresses);
+        }
+
+        discovery_response_tx
+            .send(Ok(concat_listener_discovery_response(
+                "1",
+                "1",
+                vec![b"b".to_vec()],
+            )))
+            .await
+            .unwrap();
+        time::timeout(Duration::from_secs(10), updates.filter_chain.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            vec!["quilkin.extensions.filters.concatenate_bytes.v1beta1.ConcatenateBytes"],
+            updates
+                .filter_chain
+                .borrow()
+                .as_ref()
+                .unwrap()
+                .names()
+                .collect::<Vec<_>>()
+        );
+
+        shutdown_tx.send(()).unwrap();
+        time::timeout(Duration::from_secs(10), updates.result)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
     fn concat_listener_discovery_response(
         version_info: &str,
         nonce: &str,
         bytes: Vec<Vec<u8>>,
     ) -> DiscoveryResponse {
-        let filter_name = "quilkin.extensions.filters.concatenate_bytes.v1alpha1.ConcatenateBytes";
+        let filter_name = "quilkin.extensions.filters.concatenate_bytes.v1beta1.ConcatenateBytes";
         let filters = bytes
             .into_iter()
             .map(|value| LdsFilter {
@@ -360,6 +453,7 @@ dynamic:
                             value: Strategy::Append as i32,
                         }),
                         bytes: value,
+                        validate: None,
                     }
                     .encode(&mut buf)
                     .unwrap();